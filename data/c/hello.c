@@ -0,0 +1,21 @@
+// Example C program, embedded in the kernel by adding "hello.c" to the
+// programs in build.toml
+
+#include <angstros_syscall.h>
+
+static unsigned long length(const char *s) {
+    unsigned long n = 0;
+    while (s[n]) {
+        n++;
+    }
+    return n;
+}
+
+static void log_message(const char *msg) {
+    angstros_syscall(ANGSTROS_SYS_LOG, (uint64_t)msg, length(msg));
+}
+
+int main(void) {
+    log_message("Hello kernel from C!");
+    return 0;
+}
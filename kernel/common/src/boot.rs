@@ -1,19 +1,30 @@
 //! Code relevant to booting (mostly shared between bootloader and kernel).
 
+use core::arch::x86_64::_rdtsc;
 use uefi::{
-    proto::console::gop::{GraphicsOutput, ModeInfo},
+    proto::console::gop::{self, GraphicsOutput},
     table::{boot::MemoryDescriptor, Runtime, SystemTable},
 };
+use x86_64::PhysAddr;
 
 /// Offset memory mapping information
 pub mod offset {
-    use x86_64::VirtAddr;
+    use x86_64::{PhysAddr, VirtAddr};
 
     /// Index of page table offset entry
     pub const PAGE_TABLE_INDEX: usize = 1;
+    /// Number of consecutive page table entries reserved for the mapping
+    pub const PAGE_TABLE_ENTRIES: usize = 4;
+    /// Largest amount of physical memory that can be mapped
+    pub const MAX_SIZE: u64 = (PAGE_TABLE_ENTRIES as u64) << 39;
     /// Offset of kernal mapping
     pub const VIRT_ADDR: VirtAddr = VirtAddr::new_truncate((PAGE_TABLE_INDEX as u64) << 39);
     pub const USIZE: usize = VIRT_ADDR.as_u64() as usize;
+
+    /// Virtual address of `addr` in the offset mapping
+    pub fn phys_to_virt(addr: PhysAddr) -> VirtAddr {
+        VIRT_ADDR + addr.as_u64()
+    }
 }
 
 /// Expected signature of the kernel entry point
@@ -21,35 +32,124 @@ pub type KernelMain = unsafe extern "C" fn(&'static BootInfo) -> !;
 
 /// The information provided by the boot stub
 pub struct BootInfo {
-    /// Access to UEFI system table. Note that this struct contains various
-    /// pointers that assume they are identity mapped, which may not be the case
-    /// in the kernel page table provided by the bootloader.
-    pub uefi_system_table: SystemTable<Runtime>,
+    /// Access to UEFI system table, if booted through the UEFI stub. Note that
+    /// this struct contains various pointers that assume they are identity
+    /// mapped, which may not be the case in the kernel page table provided by
+    /// the bootloader.
+    pub uefi_system_table: Option<SystemTable<Runtime>>,
     pub memory_map: MemoryMap,
-    /// Access to frame buffer of UEFI graphics output protocol
-    pub fb: Option<FrameBuffer>,
+    /// Frame buffer of UEFI graphics output protocol, if available
+    pub fb: Option<FramebufferInfo>,
+    /// Options passed when loading the UEFI stub
+    pub cmdline: CommandLine,
+    /// Physical address of the SMBIOS entry point structure, if provided by
+    /// the firmware
+    pub smbios: Option<PhysAddr>,
+    /// Physical address of the ACPI root system description pointer, if
+    /// provided by the firmware
+    pub rsdp: Option<PhysAddr>,
+    /// Offset the kernel is loaded at from its linked addresses, see
+    /// [`ElfInfo::offset`](crate::elf::ElfInfo::offset)
+    pub kernel_offset: u64,
+    /// Time stamps of the boot phases before the kernel was entered
+    pub times: BootTimes,
 }
 
 unsafe impl Send for BootInfo {}
 unsafe impl Sync for BootInfo {}
 
-/// UEFI frame buffer
+/// Values of the time stamp counter at the boot phases of the bootloader, or
+/// zero for phases it does not have
+#[derive(Copy, Clone, Debug, Default)]
+pub struct BootTimes {
+    /// Entry of the bootloader
+    pub loader_entry: u64,
+    /// Return from ExitBootServices
+    pub exit_boot_services: u64,
+    /// Jump to the kernel entry point
+    pub handoff: u64,
+}
+
+/// Current value of the time stamp counter
+pub fn timestamp() -> u64 {
+    unsafe { _rdtsc() }
+}
+
+/// Maximum length of the command line in bytes
+const CMDLINE_SIZE: usize = 256;
+
+/// Kernel command line, copied so it doesn't depend on UEFI memory
+#[derive(Copy, Clone)]
+pub struct CommandLine {
+    buf: [u8; CMDLINE_SIZE],
+    len: usize,
+}
+
+impl CommandLine {
+    /// Copy `s`, truncated to [`CMDLINE_SIZE`] bytes at a character boundary
+    pub fn new(s: &str) -> Self {
+        let mut len = s.len().min(CMDLINE_SIZE);
+        while !s.is_char_boundary(len) {
+            len -= 1;
+        }
+        let mut buf = [0; CMDLINE_SIZE];
+        buf[..len].copy_from_slice(&s.as_bytes()[..len]);
+        Self { buf, len }
+    }
+
+    pub fn as_str(&self) -> &str {
+        // Only constructed from a valid string cut at a character boundary
+        unsafe { core::str::from_utf8_unchecked(&self.buf[..self.len]) }
+    }
+}
+
+/// Layout of pixels in the frame buffer
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub enum PixelFormat {
+    Rgb,
+    Bgr,
+    /// Format described by UEFI pixel masks, which are not retained
+    Bitmask,
+}
+
+/// Snapshot of the frame buffer set up by UEFI
 ///
-/// This exists to make it possible to get access to the pointer without a
-/// mutable reference, and to bundle the mode information.
-pub struct FrameBuffer {
-    pub ptr: *mut u8,
+/// This is independent of UEFI boot services so the kernel doesn't need to
+/// consult the graphics output protocol at runtime.
+#[derive(Copy, Clone, Debug)]
+pub struct FramebufferInfo {
+    /// Physical address of the first byte of the frame buffer
+    pub phys_addr: PhysAddr,
+    /// Size in bytes
     pub size: usize,
-    pub info: ModeInfo,
+    /// Horizontal and vertical resolution in pixels
+    pub resolution: (usize, usize),
+    /// Number of pixels per scan line
+    pub stride: usize,
+    pub format: PixelFormat,
 }
 
-impl FrameBuffer {
-    pub fn new(gop: &mut GraphicsOutput, offset: usize) -> Self {
+impl FramebufferInfo {
+    /// Take snapshot of the current mode of the graphics output protocol
+    ///
+    /// Returns [`None`] if the mode does not have a frame buffer. The frame
+    /// buffer is assumed to be identity mapped.
+    pub fn new(gop: &mut GraphicsOutput) -> Option<Self> {
         let info = gop.current_mode_info();
+        let format = match info.pixel_format() {
+            gop::PixelFormat::Rgb => PixelFormat::Rgb,
+            gop::PixelFormat::Bgr => PixelFormat::Bgr,
+            gop::PixelFormat::Bitmask => PixelFormat::Bitmask,
+            gop::PixelFormat::BltOnly => return None,
+        };
         let mut fb = gop.frame_buffer();
-        let ptr = fb.as_mut_ptr().wrapping_add(offset);
-        let size = fb.size();
-        Self { ptr, size, info }
+        Some(Self {
+            phys_addr: PhysAddr::new(fb.as_mut_ptr() as u64),
+            size: fb.size(),
+            resolution: info.resolution(),
+            stride: info.stride(),
+            format,
+        })
     }
 }
 
@@ -98,3 +198,38 @@ impl Iterator for MemoryMap {
 }
 
 impl ExactSizeIterator for MemoryMap {}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use core::{mem, ptr};
+    use uefi::table::boot::MemoryType;
+
+    #[test]
+    fn memory_map_stride() {
+        // Firmware may report descriptors larger than the UEFI structure
+        const STRIDE: usize = 48;
+        assert!(STRIDE > mem::size_of::<MemoryDescriptor>());
+
+        #[repr(C, align(8))]
+        struct Buffer([u8; 3 * STRIDE]);
+        let buf = Box::leak(Box::new(Buffer([0xff; 3 * STRIDE])));
+        for i in 0..3 {
+            let mut desc = MemoryDescriptor::default();
+            desc.ty = MemoryType::CONVENTIONAL;
+            desc.phys_start = 0x1000 * i as u64;
+            desc.page_count = i as u64 + 1;
+            let ptr = buf.0[i * STRIDE..].as_mut_ptr() as *mut MemoryDescriptor;
+            unsafe { ptr::write(ptr, desc) };
+        }
+
+        let map = unsafe { MemoryMap::new(buf.0.as_ptr(), STRIDE, 3) };
+        assert_eq!(map.len(), 3);
+        let descs = map.collect::<Vec<_>>();
+        for (i, desc) in descs.iter().enumerate() {
+            assert_eq!(desc.ty, MemoryType::CONVENTIONAL);
+            assert_eq!(desc.phys_start, 0x1000 * i as u64);
+            assert_eq!(desc.page_count, i as u64 + 1);
+        }
+    }
+}
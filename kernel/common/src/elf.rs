@@ -1,11 +1,11 @@
 //! Helpers for dealing with the kernel ELF.
 
-use crate::boot::offset;
-use core::ptr;
+use crate::{boot::offset, boot_error, error::BootError, temp_map::TempMap};
+use core::slice;
 use x86_64::{
     structures::paging::{
-        FrameAllocator, FrameDeallocator, Mapper, Page, PageTableFlags, PhysFrame, Size4KiB,
-        Translate,
+        page::PageRangeInclusive, FrameAllocator, Mapper, Page, PageTableFlags, PhysFrame,
+        Size4KiB, Translate,
     },
     PhysAddr, VirtAddr,
 };
@@ -32,11 +32,68 @@ impl<const N: usize> Elf<N> {
     /// Parse ELF using [`xmas-elf`].
     ///
     /// The `user` parameter indicates whether the ELF is meant for userspace.
-    pub fn info(&self, user: bool) -> Result<ElfInfo, &'static str> {
-        Ok(ElfInfo {
-            elf: ElfFile::new(&(self.0).0)?,
-            user,
-        })
+    pub fn info(&self, user: bool) -> Result<ElfInfo, BootError> {
+        ElfInfo::new(&(self.0).0, user)
+    }
+}
+
+/// [`Elf`] of any size, to keep ELFs of different sizes together.
+pub trait AnyElf: Sync {
+    /// See [`Elf::info`].
+    fn info(&self, user: bool) -> Result<ElfInfo, BootError>;
+}
+
+impl<const N: usize> AnyElf for Elf<N> {
+    fn info(&self, user: bool) -> Result<ElfInfo, BootError> {
+        Elf::info(self, user)
+    }
+}
+
+/// Loadable segment of a user executable, whose pages are mapped on demand
+#[derive(Copy, Clone, Debug)]
+pub struct Segment<'a> {
+    /// Pages spanned by the segment
+    pub pages: PageRangeInclusive,
+    pub flags: PageTableFlags,
+    /// Address the segment starts at
+    start: VirtAddr,
+    /// Contents of the segment in the file
+    file: &'a [u8],
+    mem_size: u64,
+}
+
+impl Segment<'_> {
+    /// Address of the contents of `page` in the loading address space, if the
+    /// page holds nothing but contents of the file
+    ///
+    /// Such pages are mapped to the frames of the file itself. The other pages
+    /// need a fresh frame filled by [`Segment::fill`].
+    pub fn shared(&self, page: Page) -> Option<VirtAddr> {
+        if page < self.pages.start || page > self.pages.end {
+            return None;
+        }
+        let file_size = self.file.len() as u64;
+        if file_size != self.mem_size && page >= Page::containing_address(self.start + file_size) {
+            return None;
+        }
+        let index = page - self.pages.start;
+        Some(VirtAddr::from_ptr(self.file.as_ptr()).align_down(4096u64) + index * 4096)
+    }
+
+    /// Fill `bytes` with the contents of `page`, copying from the file and
+    /// zeroing everything else
+    pub fn fill(&self, page: Page, bytes: &mut [u8]) {
+        bytes.fill(0);
+        let page_start = page.start_address();
+        let page_end = page_start + bytes.len() as u64;
+        let start = self.start.max(page_start);
+        let end = (self.start + self.file.len() as u64).min(page_end);
+        if start >= end {
+            return;
+        }
+        let src = (start - self.start) as usize..(end - self.start) as usize;
+        let offset = (start - page_start) as usize;
+        bytes[offset..offset + src.len()].copy_from_slice(&self.file[src]);
     }
 }
 
@@ -44,17 +101,54 @@ impl<const N: usize> Elf<N> {
 pub struct ElfInfo<'a> {
     elf: ElfFile<'a>,
     user: bool,
+    /// Offset of PIE binaries chosen instead of the default one
+    offset: Option<u64>,
 }
 
 impl<'a> ElfInfo<'a> {
+    /// Parse ELF in `bytes`, which should be aligned like [`Elf`] does
+    ///
+    /// The `user` parameter indicates whether the ELF is meant for userspace.
+    pub fn new(bytes: &'a [u8], user: bool) -> Result<Self, BootError> {
+        Ok(Self {
+            elf: ElfFile::new(bytes)?,
+            user,
+            offset: None,
+        })
+    }
+
+    /// Load at `offset` from the linked addresses instead of the default
+    /// [`offset`](Self::offset), which requires a PIE binary
+    ///
+    /// Relocations and the entry point take the offset into account.
+    pub fn with_offset(self, offset: u64) -> Result<Self, BootError> {
+        if !self.pie() {
+            return Err("Only PIE binaries can be relocated".into());
+        }
+        if offset % 4096 != 0 {
+            return Err(boot_error!("Offset not page-aligned", "{:#x}", offset));
+        }
+        Ok(Self {
+            offset: Some(offset),
+            ..self
+        })
+    }
+
+    /// Whether the binary is position-independent
+    pub fn pie(&self) -> bool {
+        self.elf.header.pt2.type_().as_type() == header::Type::SharedObject
+    }
+
     /// Obtain the entry point as encoded in the ELF header
     pub fn entry_point(&self) -> u64 {
         self.elf.header.pt2.entry_point() + self.offset()
     }
 
-    /// Determine ELF offset for PIE binaries
-    fn offset(&self) -> u64 {
-        if self.elf.header.pt2.type_().as_type() == header::Type::SharedObject {
+    /// Determine ELF offset for PIE binaries, see [`with_offset`](Self::with_offset)
+    pub fn offset(&self) -> u64 {
+        if let Some(offset) = self.offset {
+            offset
+        } else if self.pie() {
             if self.user {
                 0x100000
             } else {
@@ -68,43 +162,104 @@ impl<'a> ElfInfo<'a> {
     /// Setup page table mappings based on desired ELF mappings
     ///
     /// Only supports very rudimentary ELF features
-    pub fn setup_mappings<M, A>(&self, map: &mut M, all: &mut A) -> Result<(), &'static str>
+    pub fn setup_mappings<M, A>(&self, map: &mut M, all: &mut A) -> Result<(), BootError>
     where
         M: Mapper<Size4KiB> + Translate,
         A: FrameAllocator<Size4KiB>,
     {
         log::info!("Setting up ELF mappings...");
-        for header in self.elf.program_iter() {
-            match header.get_type()? {
+        for (i, header) in self.elf.program_iter().enumerate() {
+            match header
+                .get_type()
+                .map_err(|e| boot_error!(e, "segment {}", i))?
+            {
                 Type::Load => {
-                    self.load_segment(&header, map, all)?;
+                    self.load_segment(i, &header, map, all)?;
                 }
                 ty => {
                     log::debug!("Skipping section of type {:?}", ty);
                 }
             }
         }
-        for header in self.elf.section_iter() {
-            match header.get_data(&self.elf)? {
-                SectionData::Rela64(list) => {
-                    self.relocate(list, map)?;
-                }
-                SectionData::Rel64(_) | SectionData::Rel32(_) | SectionData::Rela32(_) => {
-                    log::warn!("Relocation section skipped (not implemented)");
-                }
-                _ => {}
+        self.relocate_with(|virt| {
+            let phys = map.translate_addr(virt).ok_or("Relocation not mapped")?;
+            let mut virt = VirtAddr::new(phys.as_u64());
+            if self.user {
+                virt += offset::USIZE;
             }
+            Ok(virt.as_mut_ptr::<u64>())
+        })
+    }
+
+    /// Pages and page table flags of the non-empty loadable segments
+    pub fn segments(&self) -> impl Iterator<Item = (PageRangeInclusive, PageTableFlags)> + '_ {
+        self.elf
+            .program_iter()
+            .filter(|header| matches!(header.get_type(), Ok(Type::Load)) && header.mem_size() != 0)
+            .map(move |header| (self.segment_pages(&header), self.segment_flags(&header)))
+    }
+
+    /// Descriptors of the non-empty loadable segments, to map their pages on
+    /// demand
+    pub fn segment_descriptors(&self) -> impl Iterator<Item = Result<Segment<'a>, BootError>> + '_ {
+        self.elf
+            .program_iter()
+            .enumerate()
+            .filter(|(_, header)| {
+                matches!(header.get_type(), Ok(Type::Load)) && header.mem_size() != 0
+            })
+            .map(move |(i, header)| {
+                let start = header.offset() as usize;
+                let file = self
+                    .elf
+                    .input
+                    .get(start..start + header.file_size() as usize)
+                    .ok_or_else(|| {
+                        boot_error!("Segment outside of file", "segment {} at {:#x}", i, start)
+                    })?;
+                Ok(Segment {
+                    pages: self.segment_pages(&header),
+                    flags: self.segment_flags(&header),
+                    start: VirtAddr::new(header.virtual_addr()) + self.offset(),
+                    file,
+                    mem_size: header.mem_size(),
+                })
+            })
+    }
+
+    /// Pages spanned by a non-empty loadable segment
+    fn segment_pages(&self, header: &ProgramHeader) -> PageRangeInclusive {
+        let virt_start = VirtAddr::new(header.virtual_addr()) + self.offset();
+        let virt_end = virt_start + header.mem_size() - 1u64;
+        Page::range_inclusive(
+            Page::containing_address(virt_start),
+            Page::containing_address(virt_end),
+        )
+    }
+
+    /// Page table flags of a loadable segment
+    fn segment_flags(&self, header: &ProgramHeader) -> PageTableFlags {
+        let mut flags = PageTableFlags::PRESENT;
+        if self.user {
+            flags |= PageTableFlags::USER_ACCESSIBLE;
         }
-        Ok(())
+        if header.flags().is_write() {
+            flags |= PageTableFlags::WRITABLE;
+        }
+        if !header.flags().is_execute() {
+            flags |= PageTableFlags::NO_EXECUTE;
+        }
+        flags
     }
 
-    /// Map loadable segment of the executable as requested
+    /// Map loadable segment `index` of the executable as requested
     fn load_segment<M, A>(
         &self,
+        index: usize,
         header: &ProgramHeader,
         map: &mut M,
         all: &mut A,
-    ) -> Result<(), &'static str>
+    ) -> Result<(), BootError>
     where
         M: Mapper<Size4KiB> + Translate,
         A: FrameAllocator<Size4KiB>,
@@ -114,25 +269,14 @@ impl<'a> ElfInfo<'a> {
         if virt_len == 0 {
             return Ok(());
         }
-        let flags = {
-            let mut flags = PageTableFlags::PRESENT;
-            if self.user {
-                flags |= PageTableFlags::USER_ACCESSIBLE;
-            }
-            if header.flags().is_write() {
-                flags |= PageTableFlags::WRITABLE;
-            }
-            if !header.flags().is_execute() {
-                flags |= PageTableFlags::NO_EXECUTE;
-            }
-            flags
-        };
+        let flags = self.segment_flags(header);
         let virt_start = VirtAddr::new(header.virtual_addr()) + self.offset();
         let virt_end = virt_start + virt_len - 1u64;
         let elf_virt =
             VirtAddr::from_ptr(self.elf.input as *const _ as *const u8) + header.offset();
         let phys_start = if self.user {
-            map.translate_addr(elf_virt).ok_or("Elf not mapped")?
+            map.translate_addr(elf_virt)
+                .ok_or_else(|| boot_error!("Elf not mapped", "{:?}", elf_virt))?
         } else {
             PhysAddr::new(elf_virt.as_u64())
         };
@@ -144,10 +288,7 @@ impl<'a> ElfInfo<'a> {
             phys_start,
             phys_end
         );
-        let mut page_range = Page::range_inclusive(
-            Page::containing_address(virt_start),
-            Page::containing_address(virt_end),
-        );
+        let mut page_range = self.segment_pages(header);
         let frame_range = PhysFrame::range_inclusive(
             PhysFrame::containing_address(phys_start),
             PhysFrame::containing_address(phys_end),
@@ -160,36 +301,47 @@ impl<'a> ElfInfo<'a> {
             page_range.end = new_start - 1;
             let new_range = Page::range_inclusive(new_start, old_end);
             for (i, page) in new_range.enumerate() {
-                let frame = all.allocate_frame().ok_or("No frame allocated")?;
+                let frame = all
+                    .allocate_frame()
+                    .ok_or_else(|| boot_error!("No frame allocated", "segment {}", index))?;
                 log::trace!("Mapping {:?} to fresh {:?}", page, frame);
                 unsafe { map.map_to(page, frame, flags, all) }
                     .map_err(|e| {
                         log::error!("{:?}", e);
-                        "Mapping error"
+                        boot_error!("Mapping error", "{:?} of segment {}", page, index)
                     })?
                     .ignore();
-                // Copy data from ELF to first fresh frame
-                let zero_start = if i == 0 {
-                    let phys_start = phys_start.max(frame_range.end.start_address());
-                    let offset = phys_start - phys_start.align_down(4096u64);
-                    let count = phys_end - phys_start + 1;
-                    let fresh_start = frame.start_address() + offset;
-                    log::trace!(
-                        "Copying {} bytes from {:?} to {:?}",
-                        count,
-                        phys_start,
-                        fresh_start,
-                    );
-                    let src = phys_start.as_u64() as *const u8;
-                    let dst = fresh_start.as_u64() as *mut u8;
-                    unsafe { ptr::copy_nonoverlapping(src, dst, count as usize) };
-                    offset + count
-                } else {
-                    0
+                // Copy data from ELF to first fresh frame and zero the rest
+                let fill = |bytes: &mut [u8]| {
+                    let zero_start = if i == 0 {
+                        let copy_start = phys_start.max(frame_range.end.start_address());
+                        let offset = (copy_start - copy_start.align_down(4096u64)) as usize;
+                        let count = (phys_end - copy_start + 1) as usize;
+                        log::trace!(
+                            "Copying {} bytes from {:?} to {:?}",
+                            count,
+                            copy_start,
+                            frame
+                        );
+                        let src = elf_virt + (copy_start - phys_start);
+                        let src = unsafe { slice::from_raw_parts(src.as_ptr::<u8>(), count) };
+                        bytes[offset..offset + count].copy_from_slice(src);
+                        offset + count
+                    } else {
+                        0
+                    };
+                    bytes[zero_start..].fill(0);
                 };
-                // Zero memory using current identity mapping
-                let frame_ptr = (frame.start_address().as_u64() + zero_start) as *mut u8;
-                unsafe { ptr::write_bytes(frame_ptr, 0, 4096 - zero_start as usize) };
+                if self.user {
+                    let mut temp = unsafe { TempMap::new(map, frame, all) }
+                        .map_err(|e| boot_error!(e, "{:?} of segment {}", frame, index))?;
+                    fill(temp.bytes_mut());
+                } else {
+                    // The UEFI stub fills an inactive page table, but runs
+                    // with physical memory identity mapped
+                    let ptr = frame.start_address().as_u64() as *mut u8;
+                    fill(unsafe { slice::from_raw_parts_mut(ptr, 4096) });
+                }
             }
         }
         // Map directly to ELF as loaded in static variable
@@ -198,20 +350,43 @@ impl<'a> ElfInfo<'a> {
             unsafe { map.map_to(page, frame, flags, all) }
                 .map_err(|e| {
                     log::error!("{:?}", e);
-                    "Mapping error"
+                    boot_error!("Mapping error", "{:?} of segment {}", page, index)
                 })?
                 .ignore();
         }
         Ok(())
     }
 
+    /// Performs the relocations of all Rela sections, writing through the
+    /// pointer `target` returns for each relocated virtual address
+    pub fn relocate_with<F>(&self, mut target: F) -> Result<(), BootError>
+    where
+        F: FnMut(VirtAddr) -> Result<*mut u64, &'static str>,
+    {
+        for (i, header) in self.elf.section_iter().enumerate() {
+            match header
+                .get_data(&self.elf)
+                .map_err(|e| boot_error!(e, "section {}", i))?
+            {
+                SectionData::Rela64(list) => {
+                    self.relocate(list, &mut target)?;
+                }
+                SectionData::Rel64(_) | SectionData::Rel32(_) | SectionData::Rela32(_) => {
+                    log::warn!("Relocation section skipped (not implemented)");
+                }
+                _ => {}
+            }
+        }
+        Ok(())
+    }
+
     /// Performs relocations as described by Rela entries
     ///
     /// Does not check whether these relocations are valid (well-aligned, in
     /// bounds of the ELF etc.).
-    fn relocate<M>(&self, list: &[Rela<u64>], map: &mut M) -> Result<(), &'static str>
+    fn relocate<F>(&self, list: &[Rela<u64>], target: &mut F) -> Result<(), BootError>
     where
-        M: Mapper<Size4KiB> + Translate,
+        F: FnMut(VirtAddr) -> Result<*mut u64, &'static str>,
     {
         log::debug!("Fixing {} ELF relocations", list.len());
         let offset = VirtAddr::new(self.offset());
@@ -219,103 +394,101 @@ impl<'a> ElfInfo<'a> {
             match rela.get_type() {
                 8 => {
                     // R_X86_64_RELATIVE (Adjust by program base)
-                    let ptr = {
-                        let virt_base = offset + rela.get_offset();
-                        let phys = map
-                            .translate_addr(virt_base)
-                            .ok_or("Relocation not mapped")?;
-                        let mut virt = VirtAddr::new(phys.as_u64());
-                        if self.user {
-                            virt += offset::USIZE;
-                        }
-                        virt.as_mut_ptr::<u64>()
-                    };
+                    let virt = offset + rela.get_offset();
+                    let ptr =
+                        target(virt).map_err(|e| boot_error!(e, "relocation at {:?}", virt))?;
                     // Base + Addend
                     let value = offset + rela.get_addend();
                     unsafe { ptr.write(value.as_u64()) };
                 }
                 n => {
-                    log::warn!("Relocation type {} not handled", n);
-                    return Err("Unimplemented relocation type encountered");
+                    return Err(boot_error!(
+                        "Unimplemented relocation type encountered",
+                        "type {} at {:#x}",
+                        n,
+                        rela.get_offset()
+                    ));
                 }
             }
         }
         Ok(())
     }
+}
 
-    /// Remove page table mappings
-    ///
-    /// Does not remove non-level-4 page table entries.
-    pub fn remove_mappings<M, A>(&self, map: &mut M, all: &mut A) -> Result<(), &'static str>
-    where
-        M: Mapper<Size4KiB> + Translate,
-        A: FrameDeallocator<Size4KiB>,
-    {
-        log::info!("Removing up ELF mappings...");
-        for header in self.elf.program_iter() {
-            match header.get_type()? {
-                Type::Load => {
-                    self.unload_segment(&header, map, all)?;
-                }
-                ty => {
-                    log::debug!("Skipping section of type {:?}", ty);
-                }
-            }
-        }
-        Ok(())
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const ENTRY: u64 = 0x1234;
+
+    /// ELF64 header of type `ty` without program and section headers
+    fn header(ty: u16) -> [u8; 64] {
+        let mut bytes = [0; 64];
+        bytes[..8].copy_from_slice(&[0x7f, b'E', b'L', b'F', 2, 1, 1, 0]);
+        bytes[16..18].copy_from_slice(&ty.to_le_bytes());
+        bytes[18..20].copy_from_slice(&0x3eu16.to_le_bytes());
+        bytes[20..24].copy_from_slice(&1u32.to_le_bytes());
+        bytes[24..32].copy_from_slice(&ENTRY.to_le_bytes());
+        bytes[52..54].copy_from_slice(&64u16.to_le_bytes());
+        bytes[54..56].copy_from_slice(&56u16.to_le_bytes());
+        bytes[58..60].copy_from_slice(&64u16.to_le_bytes());
+        bytes
     }
 
-    /// Unload loadable segment of the executable as requested
-    fn unload_segment<M, A>(
-        &self,
-        header: &ProgramHeader,
-        map: &mut M,
-        all: &mut A,
-    ) -> Result<(), &'static str>
-    where
-        M: Mapper<Size4KiB> + Translate,
-        A: FrameDeallocator<Size4KiB>,
-    {
-        let virt_len = header.mem_size();
-        let phys_len = header.file_size();
-        if virt_len == 0 {
-            return Ok(());
-        }
-        let virt_start = VirtAddr::new(header.virtual_addr()) + self.offset();
-        let virt_end = virt_start + virt_len - 1u64;
-        log::debug!("Unmapping {:?}..{:?}", virt_start, virt_end,);
-        let mut page_range = Page::range_inclusive(
-            Page::containing_address(virt_start),
-            Page::containing_address(virt_end),
-        );
-        if virt_len > phys_len {
-            // Instead of mapping to the last ELF frame, map to fresh frame
-            // Other extraneous virtual memory is also backed by fresh frames
-            let new_start = Page::containing_address(virt_start + phys_len - 1u64);
-            let old_end = page_range.end;
-            page_range.end = new_start - 1;
-            let new_range = Page::range_inclusive(new_start, old_end);
-            for page in new_range {
-                log::trace!("Unmapping {:?}", page);
-                let (frame, flush) = map.unmap(page).map_err(|e| {
-                    log::error!("{:?}", e);
-                    "Mapping error"
-                })?;
-                flush.flush();
-                unsafe { all.deallocate_frame(frame) };
-            }
-        }
-        // Map directly to ELF as loaded in static variable
-        for page in page_range {
-            log::trace!("Unmapping {:?}", page);
-            map.unmap(page)
-                .map_err(|e| {
-                    log::error!("{:?}", e);
-                    "Mapping error"
-                })?
-                .1
-                .flush();
-        }
-        Ok(())
+    #[test]
+    fn entry_point() {
+        // Executables are loaded where they are linked
+        let elf = Elf::new(header(2));
+        assert_eq!(elf.info(true).unwrap().entry_point(), ENTRY);
+        assert_eq!(elf.info(false).unwrap().entry_point(), ENTRY);
+
+        // PIE binaries are offset
+        let elf = Elf::new(header(3));
+        assert_eq!(elf.info(true).unwrap().entry_point(), ENTRY + 0x100000);
+        assert_eq!(elf.info(false).unwrap().entry_point(), ENTRY + 0x200000);
+    }
+
+    #[test]
+    fn with_offset() {
+        let elf = Elf::new(header(3));
+        let info = elf.info(false).unwrap().with_offset(0x4000_0000).unwrap();
+        assert_eq!(info.entry_point(), ENTRY + 0x4000_0000);
+        assert!(elf.info(false).unwrap().with_offset(0x123).is_err());
+
+        // Executables cannot be moved
+        let elf = Elf::new(header(2));
+        assert!(elf.info(false).unwrap().with_offset(0x4000_0000).is_err());
+    }
+
+    #[test]
+    fn segment() {
+        static FILE: [u8; 0x1000] = [0xaa; 0x1000];
+        let start = VirtAddr::new(0x1000_0800);
+        let page = Page::containing_address(start);
+        let segment = Segment {
+            pages: Page::range_inclusive(page, page + 2),
+            flags: PageTableFlags::PRESENT,
+            start,
+            file: &FILE,
+            mem_size: 0x2000,
+        };
+        // Only the first page holds nothing but file contents
+        let file = VirtAddr::from_ptr(FILE.as_ptr());
+        assert_eq!(segment.shared(page), Some(file.align_down(4096u64)));
+        assert_eq!(segment.shared(page + 1), None);
+        assert_eq!(segment.shared(page + 3), None);
+
+        // The file ends halfway the second page, after which it is zeroed
+        let mut bytes = [0x55; 0x1000];
+        segment.fill(page + 1, &mut bytes);
+        assert!(bytes[..0x800].iter().all(|&b| b == 0xaa));
+        assert!(bytes[0x800..].iter().all(|&b| b == 0));
+        segment.fill(page + 2, &mut bytes);
+        assert!(bytes.iter().all(|&b| b == 0));
+    }
+
+    #[test]
+    fn invalid_magic() {
+        assert!(Elf::new([0; 64]).info(true).is_err());
     }
 }
@@ -0,0 +1,139 @@
+//! Errors of boot code that carry context
+//!
+//! A bare message like "Mapping error" does not tell which page failed to be
+//! mapped. A [`BootError`] keeps a static message next to a context, such as
+//! an address or segment index, formatted into a fixed buffer as there may not
+//! be a heap yet. Create one with the [`boot_error!`](crate::boot_error) macro.
+
+use core::{
+    fmt::{self, Write},
+    str,
+};
+
+/// Maximum length of the context in bytes, beyond which it is truncated
+const CONTEXT_LEN: usize = 64;
+
+/// Error of boot code, with a message and optional formatted context
+#[derive(Clone)]
+pub struct BootError {
+    message: &'static str,
+    context: [u8; CONTEXT_LEN],
+    len: usize,
+}
+
+impl BootError {
+    /// Error without context
+    pub const fn new(message: &'static str) -> Self {
+        Self {
+            message,
+            context: [0; CONTEXT_LEN],
+            len: 0,
+        }
+    }
+
+    /// Error with `args` formatted as context, see [`boot_error!`](crate::boot_error)
+    pub fn with_context(message: &'static str, args: fmt::Arguments) -> Self {
+        let mut error = Self::new(message);
+        // Only fails when truncated, in which case the start is kept
+        let _ = error.write_fmt(args);
+        error
+    }
+
+    /// Message describing what went wrong
+    pub fn message(&self) -> &'static str {
+        self.message
+    }
+
+    /// Context of the error, or an empty string if there is none
+    pub fn context(&self) -> &str {
+        // Truncation only happens on character boundaries
+        str::from_utf8(&self.context[..self.len]).unwrap_or("")
+    }
+
+    /// Log the error including its context, keeping only the message for
+    /// callers that return bare messages
+    pub fn logged(self) -> &'static str {
+        log::error!("{}", self);
+        self.message
+    }
+}
+
+impl Write for BootError {
+    fn write_str(&mut self, s: &str) -> fmt::Result {
+        let mut len = s.len().min(CONTEXT_LEN - self.len);
+        while !s.is_char_boundary(len) {
+            len -= 1;
+        }
+        self.context[self.len..self.len + len].copy_from_slice(&s.as_bytes()[..len]);
+        self.len += len;
+        if len < s.len() {
+            Err(fmt::Error)
+        } else {
+            Ok(())
+        }
+    }
+}
+
+impl From<&'static str> for BootError {
+    fn from(message: &'static str) -> Self {
+        Self::new(message)
+    }
+}
+
+impl fmt::Display for BootError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.write_str(self.message)?;
+        if self.len > 0 {
+            write!(f, " ({})", self.context())?;
+        }
+        Ok(())
+    }
+}
+
+impl fmt::Debug for BootError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.debug_struct("BootError")
+            .field("message", &self.message)
+            .field("context", &self.context())
+            .finish()
+    }
+}
+
+/// Create a [`BootError`] with `message` and context formatted like
+/// [`format_args!`]
+#[macro_export]
+macro_rules! boot_error {
+    ($message:expr, $($arg:tt)*) => {
+        $crate::error::BootError::with_context($message, format_args!($($arg)*))
+    };
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn display() {
+        let error = boot_error!("Mapping error", "page {:#x} of segment {}", 0x1000, 2);
+        assert_eq!(error.context(), "page 0x1000 of segment 2");
+        assert_eq!(
+            format!("{}", error),
+            "Mapping error (page 0x1000 of segment 2)"
+        );
+        let error = BootError::from("Out of memory");
+        assert_eq!(error.context(), "");
+        assert_eq!(format!("{}", error), "Out of memory");
+    }
+
+    #[test]
+    fn truncated() {
+        let error = boot_error!("Too long", "{:x<70}", "é");
+        assert_eq!(error.context().len(), CONTEXT_LEN);
+        assert!(error.context().starts_with("éxxx"));
+        // Multi-byte characters are not split
+        let error = boot_error!("Too long", "{:é<40}", "");
+        assert_eq!(error.context().len(), CONTEXT_LEN);
+        let error = boot_error!("Too long", "x{:é<40}", "");
+        assert_eq!(error.context().len(), CONTEXT_LEN - 1);
+    }
+}
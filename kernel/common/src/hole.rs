@@ -0,0 +1,112 @@
+//! Arithmetic of free blocks of memory, as used by the linked list allocator
+//! of the kernel
+
+use x86_64::VirtAddr;
+
+/// Describes a free block of memory based on its starting address and size.
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub struct Hole {
+    pub addr: VirtAddr,
+    pub size: u64,
+}
+
+impl Hole {
+    pub fn new(addr: VirtAddr, size: u64) -> Self {
+        Self { addr, size }
+    }
+
+    pub fn start_addr(self) -> VirtAddr {
+        self.addr
+    }
+
+    pub fn end_addr(self) -> VirtAddr {
+        self.start_addr() + self.size
+    }
+
+    /// Determine if and how an allocation of `size` bytes aligned to `align`
+    /// can fit in a [`Hole`]
+    ///
+    /// If the allocation cannot fit, [`None`] is returned, otherwise a
+    /// [`VirtAddr`] is returned for where the allocation would fit, along with
+    /// up to two holes that fill the remaining space of the hole. It is
+    /// guaranteed that the optional first hole's location is the same as
+    /// `self` and that the optional second hole's location is after the
+    /// allocation. Holes smaller than `min_size` cannot be kept track of, so
+    /// the allocation does not fit if it would leave one.
+    pub fn fit(
+        self,
+        size: u64,
+        align: u64,
+        min_size: u64,
+    ) -> Option<(Option<Self>, VirtAddr, Option<Self>)> {
+        // Calculate placement of new allocation
+        let start = self.start_addr().align_up(align);
+        let end = start + size;
+        if end > self.end_addr() {
+            return None;
+        }
+
+        // Calculate placements and necessity of holes before and after
+        let excess_before = start - self.start_addr();
+        let before = if excess_before == 0 {
+            None
+        } else if excess_before < min_size {
+            return None;
+        } else {
+            Some(Self::new(self.start_addr(), excess_before))
+        };
+
+        let excess_after = self.end_addr() - end;
+        let after = if excess_after == 0 {
+            None
+        } else if excess_after < min_size {
+            return None;
+        } else {
+            Some(Self::new(end, excess_after))
+        };
+
+        Some((before, start, after))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn hole(addr: u64, size: u64) -> Hole {
+        Hole::new(VirtAddr::new(addr), size)
+    }
+
+    #[test]
+    fn exact_fit() {
+        let fit = hole(0x1000, 64).fit(64, 8, 16);
+        assert_eq!(fit, Some((None, VirtAddr::new(0x1000), None)));
+    }
+
+    #[test]
+    fn remainder_after() {
+        let fit = hole(0x1000, 64).fit(32, 8, 16);
+        assert_eq!(
+            fit,
+            Some((None, VirtAddr::new(0x1000), Some(hole(0x1020, 32))))
+        );
+    }
+
+    #[test]
+    fn alignment_leaves_hole_before() {
+        let fit = hole(0x1010, 0x100).fit(0x10, 0x100, 16);
+        assert_eq!(
+            fit,
+            Some((Some(hole(0x1010, 0xf0)), VirtAddr::new(0x1100), None))
+        );
+    }
+
+    #[test]
+    fn no_fit() {
+        // Too large
+        assert_eq!(hole(0x1000, 64).fit(72, 8, 16), None);
+        // Remainder too small to keep track of
+        assert_eq!(hole(0x1000, 40).fit(32, 8, 16), None);
+        assert_eq!(hole(0x1008, 64).fit(32, 16, 16), None);
+    }
+}
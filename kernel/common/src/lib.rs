@@ -1,12 +1,18 @@
 //! Boot code shared between different crates (e.g. the UEFI stub and the
 //! kernel).
 
-#![no_std]
+#![cfg_attr(not(any(test, feature = "std")), no_std)]
 
 pub mod boot;
 pub mod elf;
+pub mod error;
+pub mod hole;
 pub mod logger;
+pub mod mmio;
+pub mod paging;
 pub mod serial;
+pub mod temp_map;
+pub mod tlb;
 
 use core::panic::PanicInfo;
 use log::LevelFilter;
@@ -16,13 +22,13 @@ use x86_64::instructions;
 /// Initialize all relevant structures before use
 ///
 /// Initializes the serial port and logger.
-pub fn init(log_filter: LevelFilter) -> Result<(), &'static str> {
+pub fn init(log_filter: LevelFilter, log_format: logger::Format) -> Result<(), &'static str> {
     serial::init();
-    logger::init(log_filter).map_err(|_| "Could not initialize logger")?;
+    logger::init(log_filter, log_format).map_err(|_| "Could not initialize logger")?;
     Ok(())
 }
 
-/// Print the panic information via SERIAL1 and halt the CPU indefinitely.
+/// Print the panic information to the serial console and halt the CPU indefinitely.
 pub fn panic_handler(info: &PanicInfo) -> ! {
     println!();
     println!(
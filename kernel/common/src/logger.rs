@@ -1,19 +1,200 @@
 //! Simple logger implementation
 
 use crate::println;
+use core::{
+    fmt::{self, Arguments, Write},
+    sync::atomic::{AtomicU32, AtomicU64, Ordering},
+};
 use log::{Level, LevelFilter, Log, Metadata, Record, SetLoggerError};
 use owo_colors::{AnsiColors, OwoColorize};
-use spin::Once;
+use spin::{Mutex, Once};
+use x86_64::instructions::interrupts;
 
 static LOGGER: Once<Logger> = Once::new();
 
+/// Source of the time in milliseconds used for rate limiting
+static CLOCK: Once<fn() -> u64> = Once::new();
+
+/// Where messages are written without color besides the serial port
+static SCREEN: Once<fn(Arguments)> = Once::new();
+
+/// Where messages are sent without color to be collected elsewhere
+static REMOTE: Once<fn(Arguments)> = Once::new();
+
+/// Length of the window in which the number of messages is limited
+const RATE_LIMIT_INTERVAL_MS: u64 = 1000;
+/// Maximum number of messages per window over all call sites
+const GLOBAL_BURST: u32 = 100;
+
+/// Limit of all messages, protecting the serial console against log storms
+static THROTTLE: RateLimit = RateLimit::new(GLOBAL_BURST);
+
+/// Limit of the number of messages per [`RATE_LIMIT_INTERVAL_MS`]
+///
+/// Nothing is limited until a clock is set with [`set_clock`]. Used by
+/// [`log_rate_limited`](crate::log_rate_limited) for individual call sites.
+pub struct RateLimit {
+    burst: u32,
+    window_start: AtomicU64,
+    count: AtomicU32,
+    suppressed: AtomicU32,
+}
+
+impl RateLimit {
+    pub const fn new(burst: u32) -> Self {
+        Self {
+            burst,
+            window_start: AtomicU64::new(0),
+            count: AtomicU32::new(0),
+            suppressed: AtomicU32::new(0),
+        }
+    }
+
+    /// Count a message and determine whether it may be logged
+    ///
+    /// Returns the number of messages suppressed since the last one that was
+    /// allowed, or [`None`] if this message should be suppressed as well.
+    pub fn check(&self) -> Option<u32> {
+        let now = match CLOCK.get() {
+            Some(clock) => clock(),
+            None => return Some(0),
+        };
+        let start = self.window_start.load(Ordering::Relaxed);
+        if now.wrapping_sub(start) >= RATE_LIMIT_INTERVAL_MS
+            && self
+                .window_start
+                .compare_exchange(start, now, Ordering::Relaxed, Ordering::Relaxed)
+                .is_ok()
+        {
+            self.count.store(0, Ordering::Relaxed);
+        }
+        if self.count.fetch_add(1, Ordering::Relaxed) < self.burst {
+            Some(self.suppressed.swap(0, Ordering::Relaxed))
+        } else {
+            self.suppressed.fetch_add(1, Ordering::Relaxed);
+            None
+        }
+    }
+}
+
+/// Log a message, unless more than `$burst` messages were logged at this call
+/// site in the current window
+///
+/// The number of suppressed messages is logged with the next message that is
+/// allowed.
+#[macro_export]
+macro_rules! log_rate_limited {
+    ($burst:expr, $level:expr, $($arg:tt)+) => {{
+        static LIMIT: $crate::logger::RateLimit = $crate::logger::RateLimit::new($burst);
+        if let Some(suppressed) = LIMIT.check() {
+            if suppressed > 0 {
+                log::log!($level, "Suppressed {} similar messages", suppressed);
+            }
+            log::log!($level, $($arg)+);
+        }
+    }};
+}
+
+/// Number of bytes of recent log messages that are kept
+const HISTORY_SIZE: usize = 4096;
+
+static HISTORY: Mutex<History> = Mutex::new(History {
+    buf: [0; HISTORY_SIZE],
+    start: 0,
+    len: 0,
+});
+
+/// Ring buffer of recent log messages, overwriting the oldest bytes when full
+struct History {
+    buf: [u8; HISTORY_SIZE],
+    start: usize,
+    len: usize,
+}
+
+impl Write for History {
+    fn write_str(&mut self, s: &str) -> fmt::Result {
+        for &byte in s.as_bytes() {
+            self.buf[(self.start + self.len) % HISTORY_SIZE] = byte;
+            if self.len == HISTORY_SIZE {
+                self.start = (self.start + 1) % HISTORY_SIZE;
+            } else {
+                self.len += 1;
+            }
+        }
+        Ok(())
+    }
+}
+
+/// Formatting of log messages
+#[derive(Copy, Clone, Debug)]
+pub struct Format {
+    /// Color the level of messages
+    pub color: bool,
+    /// Prefix messages with their target, usually the module they come from
+    pub target: bool,
+}
+
+/// Single formatted log message
+///
+/// The level is padded to a fixed width, and continuation lines of multi-line
+/// messages are indented to line up with the first line.
+struct Line<'a> {
+    level: Level,
+    target: &'a str,
+    args: Arguments<'a>,
+    format: Format,
+}
+
+impl fmt::Display for Line<'_> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        if self.format.color {
+            let color = match self.level {
+                Level::Error => AnsiColors::Red,
+                Level::Warn => AnsiColors::Yellow,
+                Level::Info => AnsiColors::Green,
+                Level::Debug => AnsiColors::Cyan,
+                Level::Trace => AnsiColors::Magenta,
+            };
+            write!(f, "{:<5} ", self.level.color(color))?;
+        } else {
+            write!(f, "{:<5} ", self.level)?;
+        }
+        let mut indent = 6;
+        if self.format.target {
+            write!(f, "{}: ", self.target)?;
+            indent += self.target.len() + 2;
+        }
+        write!(Indented { inner: f, indent }, "{}", self.args)
+    }
+}
+
+/// Writer indenting every line but the first by `indent` spaces
+struct Indented<W> {
+    inner: W,
+    indent: usize,
+}
+
+impl<W: Write> Write for Indented<W> {
+    fn write_str(&mut self, s: &str) -> fmt::Result {
+        let mut lines = s.split('\n');
+        if let Some(first) = lines.next() {
+            self.inner.write_str(first)?;
+        }
+        for line in lines {
+            write!(self.inner, "\n{:indent$}{}", "", line, indent = self.indent)?;
+        }
+        Ok(())
+    }
+}
+
 struct Logger {
     level: LevelFilter,
+    format: Format,
 }
 
 impl Logger {
-    fn new(level: LevelFilter) -> Self {
-        Self { level }
+    fn new(level: LevelFilter, format: Format) -> Self {
+        Self { level, format }
     }
 
     fn init(&'static self) -> Result<(), SetLoggerError> {
@@ -25,27 +206,134 @@ impl Logger {
 
 impl Log for Logger {
     fn enabled(&self, metadata: &Metadata) -> bool {
-        metadata.level() <= self.level
+        // The maximum level may be changed after initialization
+        metadata.level() <= log::max_level()
     }
 
     fn log(&self, record: &Record) {
         if self.enabled(record.metadata()) {
-            let level = record.level();
-            let level = level.color(match level {
-                Level::Error => AnsiColors::Red,
-                Level::Warn => AnsiColors::Yellow,
-                Level::Info => AnsiColors::Green,
-                Level::Debug => AnsiColors::Cyan,
-                Level::Trace => AnsiColors::Magenta,
-            });
-            println!("{} {}", level, record.args());
+            match THROTTLE.check() {
+                Some(0) => {}
+                Some(suppressed) => self.write(
+                    Level::Warn,
+                    module_path!(),
+                    format_args!("Suppressed {} messages during log storm", suppressed),
+                ),
+                None => return,
+            }
+            self.write(record.level(), record.target(), *record.args());
         }
     }
 
     fn flush(&self) {}
 }
 
+impl Logger {
+    /// Print a message and keep it in the history, without color
+    fn write(&self, level: Level, target: &str, args: Arguments) {
+        let mut line = Line {
+            level,
+            target,
+            args,
+            format: self.format,
+        };
+        println!("{}", line);
+        line.format.color = false;
+        interrupts::without_interrupts(|| {
+            let _ = writeln!(HISTORY.lock(), "{}", line);
+        });
+        if let Some(screen) = SCREEN.get() {
+            screen(format_args!("{}\n", line));
+        }
+        if let Some(remote) = REMOTE.get() {
+            remote(format_args!("{}", line));
+        }
+    }
+}
+
+/// Use `clock`, returning the time in milliseconds, to rate limit messages
+pub fn set_clock(clock: fn() -> u64) {
+    CLOCK.call_once(|| clock);
+}
+
+/// Also write messages to the screen with `screen`
+pub fn set_screen(screen: fn(Arguments)) {
+    SCREEN.call_once(|| screen);
+}
+
+/// Also send messages elsewhere with `remote`, which should not log itself
+pub fn set_remote(remote: fn(Arguments)) {
+    REMOTE.call_once(|| remote);
+}
+
+/// Run `f` on recent log messages, passed as two consecutive parts
+///
+/// Returns [`None`] if the messages are being written, e.g. when called from a
+/// panic that occurred during logging.
+pub fn with_history<F: FnOnce(&[u8], &[u8]) -> T, T>(f: F) -> Option<T> {
+    interrupts::without_interrupts(|| {
+        let history = HISTORY.try_lock()?;
+        let end = history.start + history.len;
+        if end <= HISTORY_SIZE {
+            Some(f(&history.buf[history.start..end], &[]))
+        } else {
+            Some(f(
+                &history.buf[history.start..],
+                &history.buf[..end - HISTORY_SIZE],
+            ))
+        }
+    })
+}
+
 // Should be called only once; subsequent calls will panic
-pub fn init(level: LevelFilter) -> Result<(), SetLoggerError> {
-    LOGGER.call_once(|| Logger::new(level)).init()
+pub fn init(level: LevelFilter, format: Format) -> Result<(), SetLoggerError> {
+    LOGGER.call_once(|| Logger::new(level, format)).init()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn line(target: bool, args: Arguments) -> String {
+        let line = Line {
+            level: Level::Warn,
+            target: "kernel::test",
+            args,
+            format: Format {
+                color: false,
+                target,
+            },
+        };
+        format!("{}", line)
+    }
+
+    #[test]
+    fn line_format() {
+        assert_eq!(line(false, format_args!("a")), "WARN  a");
+        assert_eq!(line(true, format_args!("a")), "WARN  kernel::test: a");
+    }
+
+    #[test]
+    fn line_indent() {
+        assert_eq!(line(false, format_args!("a\nb")), "WARN  a\n      b");
+        assert_eq!(
+            line(true, format_args!("{}\n{}", 1, 2)),
+            "WARN  kernel::test: 1\n                    2"
+        );
+    }
+
+    #[test]
+    fn history_wraps() {
+        let mut history = History {
+            buf: [0; HISTORY_SIZE],
+            start: 0,
+            len: 0,
+        };
+        history.write_str(&"x".repeat(HISTORY_SIZE - 1)).unwrap();
+        assert_eq!((history.start, history.len), (0, HISTORY_SIZE - 1));
+        history.write_str("abc").unwrap();
+        assert_eq!((history.start, history.len), (2, HISTORY_SIZE));
+        assert_eq!(&history.buf[..2], b"bc");
+        assert_eq!(history.buf[HISTORY_SIZE - 1], b'a');
+    }
 }
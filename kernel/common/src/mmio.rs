@@ -0,0 +1,96 @@
+//! Memory-mapped device registers
+//!
+//! A block of registers is described by a `#[repr(C)]` structure of
+//! [`ReadOnly`], [`WriteOnly`] and [`ReadWrite`] fields, with [`Reserved`]
+//! fields for the gaps between them, and accessed through a reference obtained
+//! with [`block`]. Every access is volatile and has the width of the field, so
+//! drivers need no pointer arithmetic or `unsafe` beyond locating the block.
+
+use core::{cell::UnsafeCell, ptr};
+use x86_64::VirtAddr;
+
+/// Register that can only be read
+#[repr(transparent)]
+pub struct ReadOnly<T: Copy>(UnsafeCell<T>);
+
+impl<T: Copy> ReadOnly<T> {
+    pub fn read(&self) -> T {
+        unsafe { ptr::read_volatile(self.0.get()) }
+    }
+}
+
+/// Register that can only be written
+#[repr(transparent)]
+pub struct WriteOnly<T: Copy>(UnsafeCell<T>);
+
+impl<T: Copy> WriteOnly<T> {
+    pub fn write(&self, value: T) {
+        unsafe { ptr::write_volatile(self.0.get(), value) }
+    }
+}
+
+/// Register that can be read and written
+#[repr(transparent)]
+pub struct ReadWrite<T: Copy>(UnsafeCell<T>);
+
+impl<T: Copy> ReadWrite<T> {
+    pub fn read(&self) -> T {
+        unsafe { ptr::read_volatile(self.0.get()) }
+    }
+
+    pub fn write(&self, value: T) {
+        unsafe { ptr::write_volatile(self.0.get(), value) }
+    }
+
+    /// Write the result of `f` on the current value back
+    pub fn update<F: FnOnce(T) -> T>(&self, f: F) {
+        self.write(f(self.read()));
+    }
+}
+
+/// `N` bytes between registers, which are never accessed
+#[repr(C)]
+pub struct Reserved<const N: usize>([u8; N]);
+
+/// Register block `T` at `addr`
+///
+/// # Safety
+/// The registers described by `T` should be mapped at `addr` for as long as
+/// the reference is used, with caching disabled if the device requires it.
+/// Registers are only accessed through the fields, so reading or writing one
+/// may have side effects.
+pub unsafe fn block<T>(addr: VirtAddr) -> &'static T {
+    &*addr.as_ptr()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use core::mem::size_of;
+
+    #[repr(C)]
+    struct Registers {
+        id: ReadOnly<u32>,
+        _reserved: Reserved<4>,
+        control: ReadWrite<u64>,
+        doorbell: WriteOnly<u16>,
+    }
+
+    #[test]
+    fn layout() {
+        assert_eq!(size_of::<Reserved<12>>(), 12);
+        assert_eq!(size_of::<Registers>(), 24);
+    }
+
+    #[test]
+    fn access() {
+        let mut memory = [7u64, 1, 0];
+        let addr = VirtAddr::from_ptr(memory.as_mut_ptr());
+        let registers = unsafe { block::<Registers>(addr) };
+        assert_eq!(registers.id.read(), 7);
+        registers.control.update(|value| value | 0b100);
+        assert_eq!(registers.control.read(), 0b101);
+        registers.doorbell.write(0xabcd);
+        assert_eq!(memory, [7, 0b101, 0xabcd]);
+    }
+}
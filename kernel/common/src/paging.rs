@@ -0,0 +1,99 @@
+//! Support for 5-level paging
+//!
+//! Firmware may enable 5-level paging (LA57), which cannot be disabled without
+//! leaving long mode. Only the lower 47 bits of the address space are used, so
+//! a 5-level hierarchy is set up as a PML5 whose first entry refers to the
+//! usual 4-level page table, on which all other code operates.
+
+use crate::boot::offset;
+use core::arch::x86_64::{__cpuid, __cpuid_count};
+use x86_64::{
+    registers::control::{Cr3, Cr4},
+    structures::paging::{PageTable, PageTableFlags, PhysFrame},
+    PhysAddr, VirtAddr,
+};
+
+/// Whether the CPU supports 5-level paging
+pub fn la57_supported() -> bool {
+    unsafe { __cpuid_count(7, 0) }.ecx & (1 << 16) != 0
+}
+
+/// Whether 5-level paging is enabled
+pub fn la57_enabled() -> bool {
+    Cr4::read_raw() & (1 << 12) != 0
+}
+
+/// Level 4 page table of the active page table hierarchy
+///
+/// # Safety
+/// Physical memory should be mapped at `offset`.
+pub unsafe fn active_level_4_table(offset: VirtAddr) -> PhysFrame {
+    let root = Cr3::read().0;
+    if !la57_enabled() {
+        return root;
+    }
+    let pml5 = &*(offset + root.start_address().as_u64()).as_ptr::<PageTable>();
+    pml5[0]
+        .frame()
+        .expect("Lower half not mapped by level 5 page table")
+}
+
+/// Initialize `pml5` to refer to the level 4 page table in `pml4`
+///
+/// The entry allows user access, which the CPU checks at every level; entries
+/// of the lower levels still keep processes out of kernel memory.
+pub fn wrap_level_4_table(pml5: &mut PageTable, pml4: PhysFrame) {
+    pml5.zero();
+    let flags =
+        PageTableFlags::PRESENT | PageTableFlags::WRITABLE | PageTableFlags::USER_ACCESSIBLE;
+    pml5[0].set_frame(pml4, flags);
+}
+
+/// Map all physical memory the CPU can address at the offset, up to
+/// [`offset::MAX_SIZE`], using the largest pages supported
+///
+/// Device memory lies outside the memory map, so the whole physical address
+/// space is mapped rather than the memory in the map. Page tables are accessed
+/// at `table_offset` plus their physical address, and new ones are taken from
+/// `new_table`, whose errors are passed on.
+pub fn map_offset<F, E>(pml4: &mut PageTable, table_offset: u64, mut new_table: F) -> Result<(), E>
+where
+    F: FnMut() -> Result<PhysFrame, E>,
+{
+    let phys_bits = unsafe { __cpuid(0x8000_0008) }.eax & 0xff;
+    let size = (1 << phys_bits).min(offset::MAX_SIZE);
+    let gib_pages = unsafe { __cpuid(0x8000_0001) }.edx & (1 << 26) != 0;
+    log::info!(
+        "Mapping {} GiB of physical memory with {} pages",
+        size >> 30,
+        if gib_pages { "1 GiB" } else { "2 MiB" }
+    );
+    let table =
+        |addr: PhysAddr| unsafe { &mut *((table_offset + addr.as_u64()) as *mut PageTable) };
+    let mut new_table = || -> Result<&'static mut PageTable, E> {
+        let table = table(new_table()?.start_address());
+        table.zero();
+        Ok(table)
+    };
+    let flags = PageTableFlags::PRESENT | PageTableFlags::WRITABLE;
+    for phys in (0..size).step_by(1 << 30) {
+        let entry = &mut pml4[offset::PAGE_TABLE_INDEX + (phys >> 39) as usize];
+        if entry.is_unused() {
+            let pdpt = new_table()?;
+            entry.set_addr(PhysAddr::new(pdpt as *mut _ as u64 - table_offset), flags);
+        }
+        let pdpt = table(entry.addr());
+        let entry = &mut pdpt[(phys >> 30) as usize % 512];
+        if gib_pages {
+            entry.set_addr(PhysAddr::new(phys), flags | PageTableFlags::HUGE_PAGE);
+        } else {
+            let pd = new_table()?;
+            for (i, pd_entry) in pd.iter_mut().enumerate() {
+                let addr = PhysAddr::new(phys + ((i as u64) << 21));
+                pd_entry.set_addr(addr, flags | PageTableFlags::HUGE_PAGE);
+            }
+            entry.set_addr(PhysAddr::new(pd as *mut _ as u64 - table_offset), flags);
+        }
+    }
+    Ok(())
+}
@@ -1,29 +1,151 @@
-//! Serial I/O port
+//! Serial console
+//!
+//! Output goes to a [`ConsoleBackend`], which is the 16550 UART at COM1 by
+//! default. Another backend can be selected at runtime with [`select`], or
+//! provided by a driver with [`set_backend`]. Any of them can be connected to a
+//! QEMU character device, such as a TCP socket.
 
-use core::fmt::{Arguments, Write};
+use core::{
+    fmt::{self, Arguments, Write},
+    str::FromStr,
+};
 use spin::Mutex;
 use uart_16550::SerialPort;
-use x86_64::instructions::interrupts;
+use x86_64::instructions::{interrupts, port::Port};
 
-static SERIAL1: Mutex<SerialPort> = Mutex::new(unsafe { SerialPort::new(0x3f8) });
+/// Transport of the console
+pub trait ConsoleBackend: Sync {
+    /// Prepare the device; called when the backend is selected
+    fn init(&self);
+    /// Write `s`, waiting while the device is busy
+    fn write_str(&self, s: &str);
+}
+
+/// 16550 UART at an I/O port
+pub struct Uart(Mutex<SerialPort>);
+
+impl Uart {
+    /// UART with its registers at `port`
+    ///
+    /// # Safety
+    /// The port should be a UART.
+    pub const unsafe fn new(port: u16) -> Self {
+        Self(Mutex::new(SerialPort::new(port)))
+    }
+}
+
+impl ConsoleBackend for Uart {
+    fn init(&self) {
+        self.0.lock().init();
+    }
+
+    fn write_str(&self, s: &str) {
+        // Writing to a UART cannot fail
+        let _ = self.0.lock().write_str(s);
+    }
+}
+
+/// QEMU debug console (`-debugcon`), an output-only port without setup
+pub struct Debugcon;
+
+impl ConsoleBackend for Debugcon {
+    fn init(&self) {}
+
+    fn write_str(&self, s: &str) {
+        let mut port = Port::<u8>::new(0xe9);
+        for byte in s.bytes() {
+            unsafe { port.write(byte) };
+        }
+    }
+}
+
+static COM1: Uart = unsafe { Uart::new(0x3f8) };
+static COM2: Uart = unsafe { Uart::new(0x2f8) };
+static DEBUGCON: Debugcon = Debugcon;
+
+static CONSOLE: Mutex<&'static dyn ConsoleBackend> = Mutex::new(&COM1);
+
+/// Backends that can be selected with [`select`]
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub enum Console {
+    Com1,
+    Com2,
+    Debugcon,
+    /// Virtio console, which its driver sets up with [`set_backend`] once the
+    /// device is found; output goes to COM1 until then
+    Virtio,
+}
+
+impl FromStr for Console {
+    type Err = &'static str;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "com1" => Ok(Console::Com1),
+            "com2" => Ok(Console::Com2),
+            "debugcon" => Ok(Console::Debugcon),
+            "virtio" => Ok(Console::Virtio),
+            _ => Err("Invalid console"),
+        }
+    }
+}
 
 /// Initialize serial devices. Should be called once before using any of the
 /// print  functions and macros that use serial ports, including indirectly
 /// (e.g. logging and panicking).
 pub fn init() {
-    SERIAL1.lock().init();
+    CONSOLE.lock().init();
+}
+
+/// Print to `backend` from now on, after initializing it
+pub fn set_backend(backend: &'static dyn ConsoleBackend) {
+    backend.init();
+    interrupts::without_interrupts(|| *CONSOLE.lock() = backend);
+}
+
+/// Print to the built-in backend `console` from now on
+///
+/// Does nothing for [`Console::Virtio`], which is not built in.
+pub fn select(console: Console) {
+    set_backend(match console {
+        Console::Com1 => &COM1,
+        Console::Com2 => &COM2,
+        Console::Debugcon => &DEBUGCON,
+        Console::Virtio => return,
+    });
 }
 
-/// Print and format to the `SERIAL1` port. Beforehand [`init`] should be called.
+/// Adapter to format to a backend
+struct Writer(&'static dyn ConsoleBackend);
+
+impl Write for Writer {
+    fn write_str(&mut self, s: &str) -> fmt::Result {
+        self.0.write_str(s);
+        Ok(())
+    }
+}
+
+/// Print and format to the console. Beforehand [`init`] should be called.
 pub fn print(args: Arguments) {
     interrupts::without_interrupts(|| {
-        SERIAL1
-            .lock()
+        let console = CONSOLE.lock();
+        Writer(*console)
             .write_fmt(args)
             .expect("Printing to serial failed");
     });
 }
 
+/// Print and format to COM1 without taking any lock
+///
+/// Meant for contexts such as non-maskable interrupts, which can interrupt a
+/// holder of the console lock. Output may interleave with that of [`print`]
+/// and ignores the selected backend.
+pub fn print_unlocked(args: Arguments) {
+    // Port writes are atomic per byte and COM1 was set up by [`init`]
+    let mut port = unsafe { SerialPort::new(0x3f8) };
+    let _ = port.write_fmt(args);
+}
+
 /// Format and print using [`print`] function.
 #[macro_export]
 macro_rules! print {
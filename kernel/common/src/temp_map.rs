@@ -0,0 +1,89 @@
+//! Temporary mappings of physical frames
+//!
+//! Code that needs to access the contents of an arbitrary frame should not
+//! assume physical memory is identity mapped. A [`TempMap`] maps the frame into
+//! a scratch window of the active page table for as long as it is alive.
+
+use crate::boot::offset;
+use core::{
+    slice,
+    sync::atomic::{AtomicBool, Ordering},
+};
+use x86_64::{
+    instructions::tlb,
+    structures::paging::{FrameAllocator, Mapper, Page, PageTableFlags, PhysFrame, Size4KiB},
+    VirtAddr,
+};
+
+/// Virtual address of the scratch window, just below the offset mapping
+const WINDOW: VirtAddr = VirtAddr::new_truncate(offset::VIRT_ADDR.as_u64() - 0x1000);
+
+/// Whether the scratch window is in use
+static IN_USE: AtomicBool = AtomicBool::new(false);
+
+/// Frame mapped into the scratch window, unmapped on drop
+pub struct TempMap<'a, M: Mapper<Size4KiB>> {
+    mapper: &'a mut M,
+    page: Page,
+}
+
+impl<'a, M: Mapper<Size4KiB>> TempMap<'a, M> {
+    /// Map `frame` into the scratch window
+    ///
+    /// Only one frame can be mapped at a time; an error is returned if the
+    /// window is already in use.
+    ///
+    /// # Safety
+    /// `mapper` should describe the active page table and `frame` should not be
+    /// accessed through other mappings while it is mapped.
+    pub unsafe fn new<A>(
+        mapper: &'a mut M,
+        frame: PhysFrame,
+        all: &mut A,
+    ) -> Result<Self, &'static str>
+    where
+        A: FrameAllocator<Size4KiB>,
+    {
+        if IN_USE.swap(true, Ordering::Acquire) {
+            return Err("Scratch window in use");
+        }
+        let page = Page::containing_address(WINDOW);
+        let flags = PageTableFlags::PRESENT | PageTableFlags::WRITABLE | PageTableFlags::NO_EXECUTE;
+        match mapper.map_to(page, frame, flags, all) {
+            Ok(flush) => flush.flush(),
+            Err(e) => {
+                log::error!("{:?}", e);
+                IN_USE.store(false, Ordering::Release);
+                return Err("Mapping error");
+            }
+        }
+        Ok(Self { mapper, page })
+    }
+
+    /// Contents of the mapped frame
+    pub fn bytes(&self) -> &[u8] {
+        let ptr = self.page.start_address().as_ptr();
+        unsafe { slice::from_raw_parts(ptr, Page::<Size4KiB>::SIZE as usize) }
+    }
+
+    /// Mutable contents of the mapped frame
+    pub fn bytes_mut(&mut self) -> &mut [u8] {
+        let ptr = self.page.start_address().as_mut_ptr();
+        unsafe { slice::from_raw_parts_mut(ptr, Page::<Size4KiB>::SIZE as usize) }
+    }
+}
+
+impl<M: Mapper<Size4KiB>> Drop for TempMap<'_, M> {
+    fn drop(&mut self) {
+        match self.mapper.unmap(self.page) {
+            Ok((_, flush)) => flush.flush(),
+            Err(e) => {
+                // Leave the window marked in use so the frame isn't exposed
+                log::error!("Could not unmap scratch window: {:?}", e);
+                tlb::flush(self.page.start_address());
+                return;
+            }
+        }
+        IN_USE.store(false, Ordering::Release);
+    }
+}
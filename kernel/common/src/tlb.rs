@@ -0,0 +1,92 @@
+//! Batched TLB invalidation
+//!
+//! Unmapping a range of pages one `invlpg` at a time is wasteful for large
+//! ranges, and once multiple CPUs share an address space every invalidation has
+//! to reach those CPUs as well. A [`Shootdown`] collects the pages whose
+//! translations became stale and invalidates them in one go.
+//!
+//! Only the bootstrap processor is running at the moment, so only the local TLB
+//! is invalidated. [`Shootdown::finish`] is the single place where remote CPUs
+//! need to be notified (by IPI) once they are brought up.
+
+use x86_64::{
+    instructions::tlb,
+    structures::paging::{mapper::MapperFlush, Page, Size4KiB},
+};
+
+/// Maximum number of pages that are invalidated individually; the whole TLB is
+/// flushed instead if more pages are queued.
+const MAX_PAGES: usize = 32;
+
+/// Batch of pending TLB invalidations
+///
+/// The invalidations are performed by [`Shootdown::finish`], or when the batch
+/// is dropped.
+pub struct Shootdown {
+    pages: [Option<Page>; MAX_PAGES],
+    len: usize,
+    flush_all: bool,
+}
+
+impl Shootdown {
+    pub const fn new() -> Self {
+        Self {
+            pages: [None; MAX_PAGES],
+            len: 0,
+            flush_all: false,
+        }
+    }
+
+    /// Queue invalidation of `page`, taking over the responsibility of `flush`
+    pub fn push(&mut self, page: Page, flush: MapperFlush<Size4KiB>) {
+        flush.ignore();
+        if self.flush_all {
+            return;
+        }
+        if self.len == MAX_PAGES {
+            self.flush_all = true;
+        } else {
+            self.pages[self.len] = Some(page);
+            self.len += 1;
+        }
+    }
+
+    /// Number of pages queued for invalidation
+    pub fn len(&self) -> usize {
+        self.len
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len == 0 && !self.flush_all
+    }
+
+    /// Perform all queued invalidations
+    pub fn finish(mut self) {
+        self.invalidate();
+    }
+
+    fn invalidate(&mut self) {
+        if self.flush_all {
+            log::trace!("Flushing entire TLB");
+            tlb::flush_all();
+        } else {
+            for page in self.pages[..self.len].iter().flatten() {
+                tlb::flush(page.start_address());
+            }
+        }
+        self.len = 0;
+        self.flush_all = false;
+    }
+}
+
+impl Default for Shootdown {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl Drop for Shootdown {
+    fn drop(&mut self) {
+        self.invalidate();
+    }
+}
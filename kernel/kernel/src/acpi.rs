@@ -0,0 +1,465 @@
+//! ACPI power management
+//!
+//! The fixed hardware described by the FADT is used directly: the power button
+//! raises a system control interrupt (SCI), which is turned into a shutdown
+//! request, and the soft off state is entered by writing its sleep type to the
+//! PM1 control registers. The sleep type comes from the `\_S5` package of the
+//! DSDT, decoded by the minimal [`aml`] subset. Events of the embedded
+//! controller described by the ECDT are queried on its general purpose event.
+//! Suspend to RAM is experimental, see [`sleep`].
+//!
+//! Without a full AML interpreter, power buttons implemented as control method
+//! devices and embedded controllers that are only described in the DSDT are
+//! not supported.
+
+pub mod aml;
+pub mod ec;
+pub mod sleep;
+
+use crate::{
+    devices::{self, Resource, State},
+    interrupts, shutdown, Init,
+};
+use alloc::{format, vec};
+use common::boot::offset;
+use core::{hint::spin_loop, slice};
+use ec::EmbeddedController;
+use spin::Once;
+use x86_64::{instructions::port::Port, PhysAddr};
+
+/// Size of the header common to all system description tables
+const HEADER_SIZE: usize = 36;
+/// Number of polls of the SCI enable bit after enabling ACPI mode
+const TIMEOUT: usize = 1_000_000;
+
+/// FADT field offsets
+mod fadt {
+    pub const FIRMWARE_CTRL: usize = 36;
+    pub const DSDT: usize = 40;
+    pub const SCI_INT: usize = 46;
+    pub const SMI_CMD: usize = 48;
+    pub const ACPI_ENABLE: usize = 52;
+    pub const PM1A_EVT_BLK: usize = 56;
+    pub const PM1B_EVT_BLK: usize = 60;
+    pub const PM1A_CNT_BLK: usize = 64;
+    pub const PM1B_CNT_BLK: usize = 68;
+    pub const GPE0_BLK: usize = 80;
+    pub const PM1_EVT_LEN: usize = 88;
+    pub const GPE0_BLK_LEN: usize = 92;
+    pub const FLAGS: usize = 112;
+    pub const X_FIRMWARE_CTRL: usize = 132;
+    pub const X_DSDT: usize = 140;
+
+    /// Flag set if the power button is a control method device
+    pub const PWR_BUTTON: u32 = 1 << 4;
+}
+
+/// PM1 register bits
+mod pm1 {
+    pub const PWRBTN: u16 = 1 << 8;
+    pub const WAK: u16 = 1 << 15;
+    pub const SCI_EN: u16 = 1 << 0;
+    pub const SLP_TYP_SHIFT: u16 = 10;
+    pub const SLP_TYP_MASK: u16 = 0b111 << SLP_TYP_SHIFT;
+    pub const SLP_EN: u16 = 1 << 13;
+}
+
+static ACPI: Once<Acpi> = Once::new();
+
+/// Fixed hardware registers and the devices found in the tables
+struct Acpi {
+    pm1a_event: u16,
+    pm1b_event: Option<u16>,
+    /// Length of each PM1 event block in bytes, half of it status registers
+    pm1_event_length: u16,
+    pm1a_control: u16,
+    pm1b_control: Option<u16>,
+    /// General purpose event block 0 and its length in bytes
+    gpe0: Option<(u16, u16)>,
+    /// Whether the power button is fixed hardware rather than a control method
+    /// device
+    fixed_power_button: bool,
+    /// Sleep types of the suspend to RAM state for PM1a and PM1b
+    s3: Option<(u16, u16)>,
+    /// Sleep types of the soft off state for PM1a and PM1b
+    s5: Option<(u16, u16)>,
+    /// Physical address of the firmware ACPI control structure
+    facs: Option<u64>,
+    ec: Option<EmbeddedController>,
+}
+
+impl Acpi {
+    fn pm1_event_blocks(&self) -> impl Iterator<Item = u16> {
+        core::iter::once(self.pm1a_event).chain(self.pm1b_event)
+    }
+
+    fn pm1_status(&self) -> u16 {
+        self.pm1_event_blocks()
+            .map(|block| unsafe { Port::<u16>::new(block).read() })
+            .fold(0, |status, block| status | block)
+    }
+
+    /// Clear PM1 status bits `bits`, which are cleared by writing ones
+    fn clear_pm1_status(&self, bits: u16) {
+        for block in self.pm1_event_blocks() {
+            unsafe { Port::<u16>::new(block).write(bits) };
+        }
+    }
+
+    fn set_pm1_enable(&self, bits: u16) {
+        for block in self.pm1_event_blocks() {
+            let enable = block + self.pm1_event_length / 2;
+            unsafe { Port::<u16>::new(enable).write(bits) };
+        }
+    }
+
+    /// Status and enable port of general purpose event `gpe`, and its bit
+    fn gpe_registers(&self, gpe: u8) -> Option<(u16, u16, u8)> {
+        let (block, length) = self.gpe0?;
+        let byte = gpe as u16 / 8;
+        if byte >= length / 2 {
+            return None;
+        }
+        Some((block + byte, block + length / 2 + byte, 1 << (gpe % 8)))
+    }
+
+    /// Disable all general purpose events but `gpe`
+    fn enable_only_gpe(&self, gpe: Option<u8>) {
+        let (block, length) = match self.gpe0 {
+            Some(gpe0) => gpe0,
+            None => return,
+        };
+        for byte in 0..length / 2 {
+            unsafe { Port::<u8>::new(block + length / 2 + byte).write(0) };
+        }
+        if let Some((status, enable, bit)) = gpe.and_then(|gpe| self.gpe_registers(gpe)) {
+            unsafe {
+                Port::<u8>::new(status).write(bit);
+                Port::<u8>::new(enable).write(bit);
+            }
+        }
+    }
+
+    /// Enable the events that are handled: the fixed power button and the
+    /// embedded controller
+    fn enable_events(&self) {
+        self.clear_pm1_status(pm1::PWRBTN);
+        self.set_pm1_enable(if self.fixed_power_button {
+            pm1::PWRBTN
+        } else {
+            0
+        });
+        self.enable_only_gpe(self.ec.as_ref().map(|ec| ec.gpe));
+    }
+
+    /// Write sleep types `types` for PM1a and PM1b to the control registers,
+    /// which enters the sleep state
+    fn enter_sleep_state(&self, (a, b): (u16, u16)) {
+        self.clear_pm1_status(pm1::WAK);
+        let controls = core::iter::once((self.pm1a_control, a))
+            .chain(self.pm1b_control.map(|control| (control, b)));
+        for (control, sleep_type) in controls {
+            let mut control = Port::<u16>::new(control);
+            unsafe {
+                let value = control.read() & !pm1::SLP_TYP_MASK;
+                control.write(value | sleep_type << pm1::SLP_TYP_SHIFT | pm1::SLP_EN);
+            }
+        }
+    }
+
+    /// Whether general purpose event `gpe` occurred, clearing it if so
+    fn take_gpe(&self, gpe: u8) -> bool {
+        let (status, _, bit) = match self.gpe_registers(gpe) {
+            Some(registers) => registers,
+            None => return false,
+        };
+        let mut status = Port::<u8>::new(status);
+        if unsafe { status.read() } & bit == 0 {
+            return false;
+        }
+        unsafe { status.write(bit) };
+        true
+    }
+}
+
+fn read_u8(bytes: &[u8], offset: usize) -> Option<u8> {
+    bytes.get(offset).copied()
+}
+
+fn read_u16(bytes: &[u8], offset: usize) -> Option<u16> {
+    let bytes = bytes.get(offset..offset + 2)?;
+    Some(u16::from_le_bytes([bytes[0], bytes[1]]))
+}
+
+fn read_u32(bytes: &[u8], offset: usize) -> Option<u32> {
+    let mut value = [0; 4];
+    value.copy_from_slice(bytes.get(offset..offset + 4)?);
+    Some(u32::from_le_bytes(value))
+}
+
+fn read_u64(bytes: &[u8], offset: usize) -> Option<u64> {
+    let mut value = [0; 8];
+    value.copy_from_slice(bytes.get(offset..offset + 8)?);
+    Some(u64::from_le_bytes(value))
+}
+
+fn checksum(bytes: &[u8]) -> bool {
+    bytes.iter().fold(0u8, |sum, &b| sum.wrapping_add(b)) == 0
+}
+
+/// Bytes of `length` at physical address `addr`
+///
+/// # Safety
+/// The memory should be firmware tables, which are never reused.
+unsafe fn phys_bytes(addr: u64, length: usize) -> Result<&'static [u8], &'static str> {
+    if addr + length as u64 > offset::MAX_SIZE {
+        return Err("ACPI table outside of mapped memory");
+    }
+    let ptr = offset::phys_to_virt(PhysAddr::new(addr)).as_ptr::<u8>();
+    Ok(slice::from_raw_parts(ptr, length))
+}
+
+/// System description table at `addr`, including the header
+fn table(addr: u64) -> Result<&'static [u8], &'static str> {
+    let header = unsafe { phys_bytes(addr, HEADER_SIZE)? };
+    let length = read_u32(header, 4).unwrap() as usize;
+    if length < HEADER_SIZE {
+        return Err("ACPI table too short");
+    }
+    let table = unsafe { phys_bytes(addr, length)? };
+    if !checksum(table) {
+        return Err("Invalid ACPI table checksum");
+    }
+    Ok(table)
+}
+
+/// Addresses of the tables listed in the root table of the RSDP at `rsdp`
+fn root_entries(rsdp: PhysAddr) -> Result<impl Iterator<Item = u64>, &'static str> {
+    let rsdp = unsafe { phys_bytes(rsdp.as_u64(), 36)? };
+    if &rsdp[0..8] != b"RSD PTR " || !checksum(&rsdp[0..20]) {
+        return Err("Invalid ACPI RSDP");
+    }
+    // Revision 2 and later have the 64-bit XSDT
+    let (root, entry_size) = if rsdp[15] >= 2 && checksum(rsdp) {
+        (read_u64(rsdp, 24).unwrap(), 8)
+    } else {
+        (read_u32(rsdp, 16).unwrap() as u64, 4)
+    };
+    let root = table(root)?;
+    let entries = root[HEADER_SIZE..].chunks_exact(entry_size);
+    Ok(entries.map(move |entry| match entry_size {
+        8 => read_u64(entry, 0).unwrap(),
+        _ => read_u32(entry, 0).unwrap() as u64,
+    }))
+}
+
+/// Table with `signature` listed in the root table
+fn find_table(rsdp: PhysAddr, signature: &[u8; 4]) -> Result<Option<&'static [u8]>, &'static str> {
+    for addr in root_entries(rsdp)? {
+        let header = unsafe { phys_bytes(addr, HEADER_SIZE)? };
+        if &header[0..4] == signature {
+            return table(addr).map(Some);
+        }
+    }
+    Ok(None)
+}
+
+/// Port of a generic address structure at `offset` in `table`, if it is in
+/// I/O space
+fn io_port(table: &[u8], offset: usize) -> Option<u16> {
+    const SYSTEM_IO: u8 = 1;
+    if read_u8(table, offset)? != SYSTEM_IO {
+        return None;
+    }
+    Some(read_u64(table, offset + 4)? as u16)
+}
+
+/// Sleep types for PM1a and PM1b of the sleep state package `name` in the
+/// definition block `dsdt`
+fn sleep_types(dsdt: &[u8], name: &[u8; 4]) -> Option<(u16, u16)> {
+    match aml::package(&dsdt[HEADER_SIZE..], name)? {
+        (types, 1) => Some((types[0] as u16, types[0] as u16)),
+        (types, found) if found >= 2 => Some((types[0] as u16, types[1] as u16)),
+        _ => None,
+    }
+}
+
+/// Embedded controller described by the ECDT, if any
+fn embedded_controller(rsdp: PhysAddr) -> Result<Option<EmbeddedController>, &'static str> {
+    let ecdt = match find_table(rsdp, b"ECDT")? {
+        Some(ecdt) => ecdt,
+        None => return Ok(None),
+    };
+    let command = io_port(ecdt, 36).ok_or("Embedded controller not in I/O space")?;
+    let data = io_port(ecdt, 48).ok_or("Embedded controller not in I/O space")?;
+    let gpe = read_u8(ecdt, 64).ok_or("ECDT too short")?;
+    Ok(Some(EmbeddedController::new(command, data, gpe)))
+}
+
+/// Switch from legacy mode to ACPI mode, in which events raise an SCI
+fn enable_acpi_mode(fadt: &[u8], pm1a_control: u16) -> Result<(), &'static str> {
+    let mut control = Port::<u16>::new(pm1a_control);
+    if unsafe { control.read() } & pm1::SCI_EN != 0 {
+        return Ok(());
+    }
+    let smi_command = read_u32(fadt, fadt::SMI_CMD).unwrap_or(0) as u16;
+    let enable = read_u8(fadt, fadt::ACPI_ENABLE).unwrap_or(0);
+    if smi_command == 0 || enable == 0 {
+        return Err("ACPI mode cannot be enabled");
+    }
+    unsafe { Port::<u8>::new(smi_command).write(enable) };
+    for _ in 0..TIMEOUT {
+        if unsafe { control.read() } & pm1::SCI_EN != 0 {
+            return Ok(());
+        }
+        spin_loop();
+    }
+    Err("ACPI mode not enabled by firmware")
+}
+
+fn setup(rsdp: PhysAddr) -> Result<(), &'static str> {
+    let fadt = find_table(rsdp, b"FACP")?.ok_or("No FADT")?;
+    let field = |offset| read_u32(fadt, offset).ok_or("FADT too short");
+    let optional_port = |offset| field(offset).map(|port| Some(port as u16).filter(|&p| p != 0));
+    let sci = read_u16(fadt, fadt::SCI_INT).ok_or("FADT too short")?;
+    let pm1a_event = field(fadt::PM1A_EVT_BLK)? as u16;
+    let pm1a_control = field(fadt::PM1A_CNT_BLK)? as u16;
+    let pm1_event_length = read_u8(fadt, fadt::PM1_EVT_LEN).ok_or("FADT too short")? as u16;
+    let gpe0 = optional_port(fadt::GPE0_BLK)?.map(|block| {
+        let length = read_u8(fadt, fadt::GPE0_BLK_LEN).unwrap_or(0) as u16;
+        (block, length)
+    });
+    let flags = field(fadt::FLAGS).unwrap_or(0);
+
+    let dsdt = match read_u64(fadt, fadt::X_DSDT).filter(|&addr| addr != 0) {
+        Some(addr) => addr,
+        None => field(fadt::DSDT)? as u64,
+    };
+    let dsdt = table(dsdt)?;
+    let s5 = sleep_types(dsdt, b"_S5_");
+    if s5.is_none() {
+        log::warn!("No soft off sleep type in DSDT");
+    }
+    let facs = match read_u64(fadt, fadt::X_FIRMWARE_CTRL).filter(|&addr| addr != 0) {
+        Some(addr) => Some(addr),
+        None => Some(field(fadt::FIRMWARE_CTRL)? as u64).filter(|&addr| addr != 0),
+    };
+    let ec = embedded_controller(rsdp).unwrap_or_else(|e| {
+        log::warn!("{}", e);
+        None
+    });
+
+    enable_acpi_mode(fadt, pm1a_control)?;
+    let acpi = ACPI.call_once(|| Acpi {
+        pm1a_event,
+        pm1b_event: optional_port(fadt::PM1B_EVT_BLK).unwrap_or(None),
+        pm1_event_length,
+        pm1a_control,
+        pm1b_control: optional_port(fadt::PM1B_CNT_BLK).unwrap_or(None),
+        gpe0,
+        fixed_power_button: flags & fadt::PWR_BUTTON == 0,
+        s3: sleep_types(dsdt, b"_S3_"),
+        s5,
+        facs,
+        ec,
+    });
+    if !acpi.fixed_power_button {
+        log::warn!("Power button is a control method device, which is not supported");
+    }
+    acpi.enable_events();
+
+    let resources = vec![
+        Resource::Irq(sci as u8),
+        Resource::Ports(pm1a_event, pm1_event_length),
+    ];
+    let id = devices::add("ACPI fixed hardware".into(), resources);
+    devices::bind(id, "acpi", State::Bound);
+    devices::on_resume(id, resume);
+    if let Some(ec) = &acpi.ec {
+        let name = format!("Embedded controller (GPE {})", ec.gpe);
+        let id = devices::add(name, vec![]);
+        devices::bind(id, "acpi-ec", State::Bound);
+    }
+
+    if sci != interrupts::SCI_IRQ as u16 {
+        return Err("SCI not on the supported interrupt line");
+    }
+    interrupts::unmask_irq(interrupts::SCI_IRQ);
+    log::info!(
+        "ACPI enabled, soft off {}, suspend to RAM {}, embedded controller {}",
+        if acpi.s5.is_some() {
+            "supported"
+        } else {
+            "unsupported"
+        },
+        if acpi.s3.is_some() && acpi.facs.is_some() {
+            "supported"
+        } else {
+            "unsupported"
+        },
+        if acpi.ec.is_some() {
+            "found"
+        } else {
+            "not found"
+        }
+    );
+    Ok(())
+}
+
+crate::initcall!(Arch, init);
+
+/// Take over power management from the firmware, if it provided ACPI tables
+pub fn init(init: &mut Init) {
+    if let Some(rsdp) = init.boot_info.rsdp {
+        if let Err(e) = setup(rsdp) {
+            log::warn!("ACPI unavailable: {}", e);
+        }
+    }
+}
+
+/// Enable the handled events again after a sleep state
+fn resume() {
+    if let Some(acpi) = ACPI.get() {
+        acpi.enable_events();
+    }
+}
+
+/// Handle the system control interrupt
+pub fn interrupt() {
+    let acpi = match ACPI.get() {
+        Some(acpi) => acpi,
+        None => return,
+    };
+    if acpi.pm1_status() & pm1::PWRBTN != 0 {
+        acpi.clear_pm1_status(pm1::PWRBTN);
+        log::info!("Power button pressed");
+        shutdown::request();
+    }
+    if let Some(ec) = &acpi.ec {
+        if acpi.take_gpe(ec.gpe) {
+            loop {
+                match ec.query() {
+                    Ok(Some(event)) => log::info!("Embedded controller event {:#04x}", event),
+                    Ok(None) => break,
+                    Err(e) => {
+                        log::warn!("{}", e);
+                        break;
+                    }
+                }
+            }
+        }
+    }
+}
+
+/// Enter the soft off state
+///
+/// Returns if ACPI or the sleep type of the state is unavailable.
+pub fn power_off() {
+    let acpi = match ACPI.get() {
+        Some(acpi) => acpi,
+        None => return,
+    };
+    if let Some(s5) = acpi.s5 {
+        acpi.enter_sleep_state(s5);
+    }
+}
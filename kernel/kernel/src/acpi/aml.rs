@@ -0,0 +1,114 @@
+//! Minimal subset of AML, the bytecode of the ACPI definition blocks
+//!
+//! Only named package objects with integer elements are decoded, which is
+//! enough to find the sleep types of the soft off state. Control methods are
+//! not executed.
+
+/// Opcodes and prefixes
+mod op {
+    pub const ZERO: u8 = 0x00;
+    pub const ONE: u8 = 0x01;
+    pub const NAME: u8 = 0x08;
+    pub const BYTE_PREFIX: u8 = 0x0a;
+    pub const WORD_PREFIX: u8 = 0x0b;
+    pub const DWORD_PREFIX: u8 = 0x0c;
+    pub const QWORD_PREFIX: u8 = 0x0e;
+    pub const PACKAGE: u8 = 0x12;
+    pub const ROOT_CHAR: u8 = b'\\';
+    pub const ONES: u8 = 0xff;
+}
+
+/// Decode a package length, returning the length and the number of bytes it
+/// was encoded in
+fn package_length(bytes: &[u8]) -> Option<(usize, usize)> {
+    let lead = *bytes.first()?;
+    let follow = (lead >> 6) as usize;
+    if follow == 0 {
+        return Some(((lead & 0x3f) as usize, 1));
+    }
+    let mut length = (lead & 0x0f) as usize;
+    for i in 0..follow {
+        length |= (*bytes.get(1 + i)? as usize) << (4 + 8 * i);
+    }
+    Some((length, 1 + follow))
+}
+
+/// Decode an integer constant, returning its value and encoded size
+fn integer(bytes: &[u8]) -> Option<(u64, usize)> {
+    let le = |size: usize| {
+        let value = bytes
+            .get(1..1 + size)?
+            .iter()
+            .rev()
+            .fold(0, |value, &b| value << 8 | b as u64);
+        Some((value, 1 + size))
+    };
+    match *bytes.first()? {
+        op::ZERO => Some((0, 1)),
+        op::ONE => Some((1, 1)),
+        op::ONES => Some((u64::MAX, 1)),
+        op::BYTE_PREFIX => le(1),
+        op::WORD_PREFIX => le(2),
+        op::DWORD_PREFIX => le(4),
+        op::QWORD_PREFIX => le(8),
+        _ => None,
+    }
+}
+
+/// Up to four integer elements of the package named `name` (a four character
+/// name segment) in the definition block `aml`, and their number
+///
+/// The name is searched for bytewise, so a package defined in a scope other
+/// than the root may be found as well. Elements that are not integer
+/// constants end the list.
+pub fn package(aml: &[u8], name: &[u8; 4]) -> Option<([u64; 4], usize)> {
+    // Opcode before the name, skipping a root prefix
+    let name_op = |i: usize| match aml[..i].last() {
+        Some(&op::ROOT_CHAR) => aml[..i - 1].last().copied(),
+        previous => previous.copied(),
+    };
+    let position = (0..aml.len().saturating_sub(3))
+        .find(|&i| &aml[i..i + 4] == name && name_op(i) == Some(op::NAME))?;
+    let rest = &aml[position + 4..];
+    if rest.first() != Some(&op::PACKAGE) {
+        return None;
+    }
+    let (length, encoded) = package_length(&rest[1..])?;
+    let package = rest.get(1 + encoded..1 + length)?;
+    let (&count, mut elements) = package.split_first()?;
+    let mut values = [0; 4];
+    let mut found = 0;
+    while found < values.len().min(count as usize) {
+        let (value, size) = match integer(elements) {
+            Some(element) => element,
+            None => break,
+        };
+        values[found] = value;
+        found += 1;
+        elements = &elements[size..];
+    }
+    Some((values, found))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test_case]
+    fn s5_package() {
+        // Name (\_S5, Package (0x04) { 0x05, Zero, Zero, Zero }) as compiled
+        // by iasl, after some unrelated bytes
+        let aml = [
+            0x5b, 0x80, 0x08, b'\\', b'_', b'S', b'5', b'_', 0x12, 0x07, 0x04, 0x0a, 0x05, 0x00,
+            0x00, 0x00,
+        ];
+        assert_eq!(package(&aml, b"_S5_"), Some(([5, 0, 0, 0], 4)));
+        assert_eq!(package(&aml, b"_S4_"), None);
+    }
+
+    #[test_case]
+    fn long_package_length() {
+        assert_eq!(package_length(&[0x41, 0x02]), Some((0x21, 2)));
+        assert_eq!(package_length(&[0x3f]), Some((0x3f, 1)));
+    }
+}
@@ -0,0 +1,64 @@
+//! Embedded controller described by the ECDT
+//!
+//! The embedded controller of laptops signals events (lid, battery, hotkeys)
+//! through a general purpose event. The event is identified with the query
+//! command, but handling it needs the `_Qxx` control method of the definition
+//! block, so queried events are only logged.
+
+use core::hint::spin_loop;
+use x86_64::instructions::port::Port;
+
+/// Number of status polls before a transaction is considered to have failed
+const TIMEOUT: usize = 100_000;
+
+/// Status register bits
+mod status {
+    pub const OUTPUT_FULL: u8 = 1 << 0;
+    pub const INPUT_FULL: u8 = 1 << 1;
+    pub const SCI_EVENT: u8 = 1 << 5;
+}
+
+/// Query command, which returns the number of a pending event
+const QUERY: u8 = 0x84;
+
+pub struct EmbeddedController {
+    command: u16,
+    data: u16,
+    /// General purpose event signaled by the controller
+    pub gpe: u8,
+}
+
+impl EmbeddedController {
+    pub fn new(command: u16, data: u16, gpe: u8) -> Self {
+        Self { command, data, gpe }
+    }
+
+    fn status(&self) -> u8 {
+        unsafe { Port::<u8>::new(self.command).read() }
+    }
+
+    /// Wait until status bit `mask` has value `set`
+    fn wait(&self, mask: u8, set: bool) -> Result<(), &'static str> {
+        for _ in 0..TIMEOUT {
+            if (self.status() & mask != 0) == set {
+                return Ok(());
+            }
+            spin_loop();
+        }
+        Err("Embedded controller timed out")
+    }
+
+    /// Number of the pending event, if any
+    pub fn query(&self) -> Result<Option<u8>, &'static str> {
+        if self.status() & status::SCI_EVENT == 0 {
+            return Ok(None);
+        }
+        self.wait(status::INPUT_FULL, false)?;
+        unsafe { Port::<u8>::new(self.command).write(QUERY) };
+        self.wait(status::OUTPUT_FULL, true)?;
+        match unsafe { Port::<u8>::new(self.data).read() } {
+            0 => Ok(None),
+            event => Ok(Some(event)),
+        }
+    }
+}
@@ -0,0 +1,427 @@
+//! Suspend to RAM (S3), which is experimental
+//!
+//! Memory stays powered while suspended, but the CPU and most devices are
+//! reset. Devices are stopped before sleeping and set up again with the hooks
+//! of the device registry; devices without such hooks are left running, so they
+//! keep working if sleeping fails, and are marked as failed after waking. The
+//! CPU state that is lost is saved. On wake the firmware enters the waking
+//! vector in the FACS in real mode. That is a trampoline copied to low memory,
+//! which switches to long mode using a copy of the kernel page table that
+//! identity maps the trampoline, continues in the offset mapping of physical
+//! memory, loads the kernel page table and returns to [`suspend`] through the
+//! registers saved by [`save_and_sleep`].
+//!
+//! The `_PTS` and `_WAK` control methods are not run without an AML
+//! interpreter; QEMU does not need them, but real machines may. Time spent
+//! asleep is not counted.
+
+use super::ACPI;
+use crate::{allocator::RegionFrameAllocator, devices, interrupts, time};
+use common::{boot::offset, paging};
+use core::{hint::spin_loop, ptr};
+use spin::Once;
+use x86_64::{
+    instructions,
+    registers::{
+        control::{Cr0, Cr3, Cr4},
+        model_specific::Msr,
+    },
+    structures::paging::{PageTable, PageTableFlags, PhysFrame},
+    PhysAddr,
+};
+
+/// Number of frames reserved for the trampoline and its page table
+const FRAMES: u64 = 5;
+/// Number of polls after entering the sleep state before giving up
+const TIMEOUT: usize = 10_000_000;
+
+const CR4_PAE: u64 = 1 << 5;
+const CR4_LA57: u64 = 1 << 12;
+const CR4_OSXSAVE: u64 = 1 << 18;
+const EFER: u32 = 0xc000_0080;
+const EFER_LME: u64 = 1 << 8;
+const EFER_NXE: u64 = 1 << 11;
+
+/// Model specific registers restored on wake: EFER, STAR, LSTAR, SFMASK, the
+/// FS and GS bases, the kernel GS base and PAT
+const MSRS: [u32; 8] = [
+    EFER,
+    0xc000_0081,
+    0xc000_0082,
+    0xc000_0084,
+    0xc000_0100,
+    0xc000_0101,
+    0xc000_0102,
+    0x277,
+];
+
+/// First of the [`FRAMES`] frames below 1 MiB reserved by [`reserve`]
+static LOW_MEMORY: Once<PhysFrame> = Once::new();
+
+/// Registers restored by the trampoline, followed by the address it continues
+/// at; offsets are used by [`save_and_sleep`]
+#[repr(C)]
+struct Registers {
+    rsp: u64,
+    rbx: u64,
+    rbp: u64,
+    resume: u64,
+}
+
+static mut REGISTERS: Registers = Registers {
+    rsp: 0,
+    rbx: 0,
+    rbp: 0,
+    resume: 0,
+};
+
+/// Data filled in at `acpi_wakeup_data` of the trampoline; offsets are used by
+/// the trampoline
+#[repr(C)]
+struct WakeupData {
+    /// Physical address of the temporary page table
+    cr3: u32,
+    /// Paging extensions used by the temporary page table
+    cr4: u32,
+    /// Long mode and no-execute enable
+    efer: u32,
+    _reserved: u32,
+    /// Virtual address of the offset mapping
+    offset: u64,
+    /// Physical address of the kernel page table
+    kernel_cr3: u64,
+    /// Virtual address of [`REGISTERS`]
+    registers: u64,
+}
+
+// Entered in real mode with CS at the trampoline, which is copied to a page
+// below 1 MiB. The far jump targets and GDT base are patched at runtime, as the
+// trampoline can be anywhere.
+global_asm!(
+    ".pushsection .rodata.acpi_wakeup, \"a\"",
+    ".global acpi_wakeup_start",
+    ".global acpi_wakeup_data",
+    ".global acpi_wakeup_end",
+    ".set WAKEUP_GDT, acpi_wakeup_gdt - acpi_wakeup_start",
+    ".set WAKEUP_GDTR, acpi_wakeup_gdtr - acpi_wakeup_start",
+    ".set WAKEUP_JUMP_32, acpi_wakeup_jump_32 - acpi_wakeup_start",
+    ".set WAKEUP_32, acpi_wakeup_32 - acpi_wakeup_start",
+    ".set WAKEUP_JUMP_64, acpi_wakeup_jump_64 - acpi_wakeup_start",
+    ".set WAKEUP_64, acpi_wakeup_64 - acpi_wakeup_start",
+    ".set WAKEUP_HIGH, acpi_wakeup_high - acpi_wakeup_start",
+    ".set WAKEUP_DATA, acpi_wakeup_data - acpi_wakeup_start",
+    ".balign 16",
+    ".code16",
+    "acpi_wakeup_start:",
+    "cli",
+    "cld",
+    "mov ax, cs",
+    "mov ds, ax",
+    // Physical address of the trampoline
+    "movzx ebx, ax",
+    "shl ebx, 4",
+    "lea eax, [ebx + WAKEUP_GDT]",
+    "mov dword ptr [WAKEUP_GDTR + 2], eax",
+    "lea eax, [ebx + WAKEUP_32]",
+    "mov dword ptr [WAKEUP_JUMP_32], eax",
+    "lgdt [WAKEUP_GDTR]",
+    "mov eax, cr0",
+    "or al, 1",
+    "mov cr0, eax",
+    // Far jump with 32-bit offset
+    ".byte 0x66, 0xea",
+    "acpi_wakeup_jump_32:",
+    ".long 0",
+    ".word 0x08",
+    ".code32",
+    "acpi_wakeup_32:",
+    "mov ax, 0x10",
+    "mov ds, ax",
+    "mov es, ax",
+    "mov ss, ax",
+    "mov eax, dword ptr [ebx + WAKEUP_DATA + 4]",
+    "mov cr4, eax",
+    "mov eax, dword ptr [ebx + WAKEUP_DATA]",
+    "mov cr3, eax",
+    "mov ecx, 0xc0000080",
+    "mov eax, dword ptr [ebx + WAKEUP_DATA + 8]",
+    "xor edx, edx",
+    "wrmsr",
+    "lea eax, [ebx + WAKEUP_64]",
+    "mov dword ptr [ebx + WAKEUP_JUMP_64], eax",
+    "mov eax, cr0",
+    "or eax, 0x80000001",
+    "mov cr0, eax",
+    // Far jump to the 64-bit code segment
+    ".byte 0xea",
+    "acpi_wakeup_jump_64:",
+    ".long 0",
+    ".word 0x18",
+    ".code64",
+    "acpi_wakeup_64:",
+    // Continue in the offset mapping, which the kernel page table has too
+    "mov ebx, ebx",
+    "add rbx, qword ptr [rbx + WAKEUP_DATA + 16]",
+    "lea rax, [rbx + WAKEUP_HIGH]",
+    "jmp rax",
+    "acpi_wakeup_high:",
+    "mov rax, qword ptr [rbx + WAKEUP_DATA + 24]",
+    "mov cr3, rax",
+    "mov rdi, qword ptr [rbx + WAKEUP_DATA + 32]",
+    "jmp qword ptr [rdi + 24]",
+    ".balign 8",
+    "acpi_wakeup_gdt:",
+    ".quad 0",
+    ".quad 0x00cf9a000000ffff",
+    ".quad 0x00cf92000000ffff",
+    ".quad 0x00af9a000000ffff",
+    "acpi_wakeup_gdtr:",
+    ".word 31",
+    ".long 0",
+    ".balign 8",
+    "acpi_wakeup_data:",
+    ".zero 40",
+    "acpi_wakeup_end:",
+    ".popsection",
+);
+
+extern "C" {
+    static acpi_wakeup_start: u8;
+    static acpi_wakeup_data: u8;
+    static acpi_wakeup_end: u8;
+}
+
+/// Reserve low memory for the trampoline and its page table
+///
+/// Memory below 1 MiB is only found in the first regions of the memory map, so
+/// this should be called before anything else allocates frames.
+pub fn reserve(frame_allocator: &mut RegionFrameAllocator) {
+    let mut frames = frame_allocator.allocate_contiguous(FRAMES);
+    // A waking vector of zero means there is none
+    if let Some(range) = frames {
+        if range.start.start_address().as_u64() == 0 {
+            frames = frame_allocator.allocate_contiguous(FRAMES);
+        }
+    }
+    match frames {
+        Some(range) if range.end.start_address().as_u64() <= 0x10_0000 => {
+            LOW_MEMORY.call_once(|| range.start);
+        }
+        _ => log::warn!("No memory below 1 MiB for the waking vector"),
+    }
+}
+
+/// Control and model specific registers that are lost while asleep
+struct Cpu {
+    cr0: u64,
+    cr4: u64,
+    xcr0: Option<u64>,
+    msrs: [u64; MSRS.len()],
+}
+
+impl Cpu {
+    fn save() -> Self {
+        let cr4 = Cr4::read_raw();
+        let xcr0 = if cr4 & CR4_OSXSAVE != 0 {
+            let (high, low): (u32, u32);
+            unsafe {
+                asm!("xgetbv", in("ecx") 0, out("eax") low, out("edx") high, options(nomem, nostack))
+            };
+            Some((high as u64) << 32 | low as u64)
+        } else {
+            None
+        };
+        let mut msrs = [0; MSRS.len()];
+        for (value, &msr) in msrs.iter_mut().zip(MSRS.iter()) {
+            *value = unsafe { Msr::new(msr).read() };
+        }
+        Self {
+            cr0: Cr0::read_raw(),
+            cr4,
+            xcr0,
+            msrs,
+        }
+    }
+
+    /// Restore the registers; the segments should not be reloaded afterwards,
+    /// as that clears the FS and GS bases
+    unsafe fn restore(&self) {
+        Cr0::write_raw(self.cr0);
+        Cr4::write_raw(self.cr4);
+        if let Some(xcr0) = self.xcr0 {
+            let (high, low) = ((xcr0 >> 32) as u32, xcr0 as u32);
+            asm!("xsetbv", in("ecx") 0, in("eax") low, in("edx") high, options(nomem, nostack));
+        }
+        for (&value, &msr) in self.msrs.iter().zip(MSRS.iter()) {
+            Msr::new(msr).write(value);
+        }
+    }
+}
+
+/// Copy the trampoline to `start` and make it the waking vector in the FACS at
+/// `facs`, building its page table in the frames after it
+///
+/// # Safety
+/// The frames should be reserved by [`reserve`].
+unsafe fn prepare(start: PhysFrame, facs: u64) -> Result<(), &'static str> {
+    let header = super::phys_bytes(facs, 64)?;
+    if &header[0..4] != b"FACS" {
+        return Err("Invalid FACS");
+    }
+    let facs_length = super::read_u32(header, 4).unwrap();
+
+    let table = |frame: PhysFrame| -> &'static mut PageTable {
+        &mut *offset::phys_to_virt(frame.start_address()).as_mut_ptr()
+    };
+    let flags = PageTableFlags::PRESENT | PageTableFlags::WRITABLE;
+    // The kernel page table, but with the first 2 MiB identity mapped in the
+    // first entry instead of the kernel image
+    let kernel = table(paging::active_level_4_table(offset::VIRT_ADDR));
+    let pml4 = table(start + 1);
+    *pml4 = kernel.clone();
+    let pdpt = table(start + 2);
+    pdpt.zero();
+    let pd = table(start + 3);
+    pd.zero();
+    pd[0].set_addr(PhysAddr::new(0), flags | PageTableFlags::HUGE_PAGE);
+    pdpt[0].set_frame(start + 3, flags);
+    pml4[0].set_frame(start + 2, flags);
+    let (root, cr4) = if paging::la57_enabled() {
+        paging::wrap_level_4_table(table(start + 4), start + 1);
+        (start + 4, CR4_PAE | CR4_LA57)
+    } else {
+        (start + 1, CR4_PAE)
+    };
+
+    let code = &acpi_wakeup_start as *const u8;
+    let length = &acpi_wakeup_end as *const u8 as usize - code as usize;
+    let data_offset = &acpi_wakeup_data as *const u8 as usize - code as usize;
+    let trampoline = offset::phys_to_virt(start.start_address()).as_mut_ptr::<u8>();
+    ptr::copy_nonoverlapping(code, trampoline, length);
+    let data = WakeupData {
+        cr3: root.start_address().as_u64() as u32,
+        cr4: cr4 as u32,
+        efer: (Msr::new(EFER).read() & (EFER_LME | EFER_NXE)) as u32,
+        _reserved: 0,
+        offset: offset::VIRT_ADDR.as_u64(),
+        kernel_cr3: Cr3::read().0.start_address().as_u64(),
+        registers: ptr::addr_of!(REGISTERS) as u64,
+    };
+    trampoline.add(data_offset).cast::<WakeupData>().write(data);
+
+    let facs = offset::phys_to_virt(PhysAddr::new(facs)).as_mut_ptr::<u8>();
+    let vector = start.start_address().as_u64() as u32;
+    facs.add(12).cast::<u32>().write_volatile(vector);
+    // The 64-bit vector takes precedence and is entered in protected mode
+    if facs_length >= 32 {
+        facs.add(24).cast::<u64>().write_volatile(0);
+    }
+    Ok(())
+}
+
+/// Enter the suspend to RAM state; returns if the system did not go to sleep
+extern "C" fn enter() {
+    let acpi = match ACPI.get() {
+        Some(acpi) => acpi,
+        None => return,
+    };
+    if let Some(s3) = acpi.s3 {
+        // Caches are not preserved
+        unsafe { asm!("wbinvd", options(nostack)) };
+        acpi.enter_sleep_state(s3);
+        for _ in 0..TIMEOUT {
+            spin_loop();
+        }
+    }
+}
+
+/// Save the registers the compiler cannot restore in [`REGISTERS`] and call
+/// [`enter`]; returns whether the system slept
+///
+/// The trampoline continues after the call as if [`enter`] returned, with the
+/// stack and the contents of this frame as they were.
+#[inline(never)]
+unsafe fn save_and_sleep() -> bool {
+    let slept: u64;
+    asm!(
+        "mov [rdi], rsp",
+        "mov [rdi + 8], rbx",
+        "mov [rdi + 16], rbp",
+        "lea rax, [rip + 2f]",
+        "mov [rdi + 24], rax",
+        "call {}",
+        "xor eax, eax",
+        "jmp 3f",
+        // Continued here by the trampoline, with REGISTERS in rdi
+        "2:",
+        "mov rsp, [rdi]",
+        "mov rbx, [rdi + 8]",
+        "mov rbp, [rdi + 16]",
+        "mov eax, 1",
+        "3:",
+        sym enter,
+        inout("rdi") ptr::addr_of_mut!(REGISTERS) => _,
+        out("rax") slept,
+        out("rcx") _,
+        out("rdx") _,
+        out("rsi") _,
+        out("r8") _,
+        out("r9") _,
+        out("r10") _,
+        out("r11") _,
+        out("r12") _,
+        out("r13") _,
+        out("r14") _,
+        out("r15") _,
+    );
+    slept != 0
+}
+
+/// Suspend to RAM until a wake event, such as the power button
+///
+/// Should only be called by the boot CPU, from the system call loop or the
+/// kernel main loop.
+pub fn suspend() -> Result<(), &'static str> {
+    let acpi = ACPI.get().ok_or("ACPI unavailable")?;
+    acpi.s3.ok_or("No suspend to RAM sleep type in DSDT")?;
+    let facs = acpi.facs.ok_or("No FACS")?;
+    let start = *LOW_MEMORY
+        .get()
+        .ok_or("No low memory for the waking vector")?;
+    unsafe { prepare(start, facs)? };
+
+    log::info!("Suspending to RAM");
+    devices::suspend();
+    instructions::interrupts::disable();
+    let cpu = Cpu::save();
+    let suspended = interrupts::suspend();
+    let slept = unsafe { save_and_sleep() };
+    unsafe { cpu.restore() };
+    if slept {
+        time::resume();
+    }
+    interrupts::resume(suspended);
+    devices::resume(slept);
+    if slept {
+        log::info!("Woke up from suspend to RAM");
+        Ok(())
+    } else {
+        Err("Suspend to RAM had no effect")
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use core::mem;
+
+    #[test_case]
+    fn trampoline_layout() {
+        let start = unsafe { &acpi_wakeup_start as *const u8 as usize };
+        let data = unsafe { &acpi_wakeup_data as *const u8 as usize };
+        let end = unsafe { &acpi_wakeup_end as *const u8 as usize };
+        assert!(end - start <= 4096);
+        assert_eq!((data - start) % 8, 0);
+        assert_eq!(end - data, mem::size_of::<WakeupData>());
+    }
+}
@@ -3,57 +3,303 @@
 //! This includes both frame allocators governing physical memory and "normal"
 //! allocators governing virtual memory.
 
-#[allow(dead_code)]
+mod buddy;
 mod bump;
 mod linked_list;
 mod region_frame;
+mod sanitizer;
 mod user_frame;
 
+pub use buddy::BuddyAllocator;
 pub use bump::BumpAllocator;
 pub use linked_list::LinkedListAllocator;
 pub use region_frame::RegionFrameAllocator;
+pub use sanitizer::Sanitizer;
 pub use user_frame::UserFrameAllocator;
 
-use crate::config::Allocator;
+use crate::{
+    config,
+    inject::{self, Site},
+    oom, trace,
+    vm::AddressSpace,
+};
+use core::{
+    alloc::{GlobalAlloc, Layout},
+    ptr,
+    str::FromStr,
+    sync::atomic::{AtomicU64, AtomicU8, Ordering},
+};
+use sys::{MemoryStats, TraceKind};
 use x86_64::{
     structures::paging::{
-        mapper::MapToError, FrameAllocator, Mapper, Page, PageTableFlags, Size4KiB,
+        FrameAllocator, FrameDeallocator, Page, PageSize, PageTableFlags, Size4KiB,
     },
     VirtAddr,
 };
 
 pub const HEAP_START: VirtAddr = VirtAddr::new_truncate(0o1_000_000_0000);
 pub const HEAP_SIZE: u64 = 0o1_000_0000;
+/// Size by which the heap grows when it is exhausted
+pub const HEAP_GROWTH: u64 = 0o1_000_000;
+/// Number of times the heap can grow
+///
+/// This reserve is mapped to frames up front, as growing happens inside the
+/// global allocator, which cannot reach the address space or the frame
+/// allocator (whose free list lives on the heap itself). Growing therefore
+/// saves no physical memory; it only lets the allocator manage a smaller heap
+/// until an allocation fails, which is then logged by the [`oom`] policy.
+pub const HEAP_GROWTH_STEPS: u64 = 4;
+
+/// Current end of the heap handed to the allocator
+static HEAP_END: AtomicU64 = AtomicU64::new(0);
+/// Bytes currently allocated on the heap
+static HEAP_USED: AtomicU64 = AtomicU64::new(0);
 
 /// Our global allocator
 #[global_allocator]
-pub static ALLOC: Allocator = Allocator::new();
+pub static ALLOC: Heap<Sanitizer<Allocators>> =
+    Heap(Sanitizer::new(Allocators::new(), config::SANITIZE_HEAP));
+
+/// Heap allocator implementations
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub enum Kind {
+    Bump,
+    LinkedList,
+    Buddy,
+}
+
+/// Parses names as used in the build configuration, with a space or hyphen
+/// between words
+impl FromStr for Kind {
+    type Err = &'static str;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "bump" => Ok(Kind::Bump),
+            "linked list" | "linked-list" => Ok(Kind::LinkedList),
+            "buddy" => Ok(Kind::Buddy),
+            _ => Err("Invalid allocator"),
+        }
+    }
+}
+
+/// Heap allocator that can be initialized and grown
+trait HeapAllocator: GlobalAlloc {
+    unsafe fn init(&self, heap_start: u64, heap_size: u64);
+    unsafe fn grow(&self, heap_start: u64, heap_size: u64) -> bool;
+}
 
-pub fn init<M, A>(mapper: &mut M, allocator: &mut A) -> Result<(), MapToError<Size4KiB>>
+macro_rules! impl_heap_allocator {
+    ($($allocator:ty),*) => {
+        $(
+            impl HeapAllocator for $allocator {
+                unsafe fn init(&self, heap_start: u64, heap_size: u64) {
+                    <$allocator>::init(self, heap_start, heap_size)
+                }
+
+                unsafe fn grow(&self, heap_start: u64, heap_size: u64) -> bool {
+                    <$allocator>::grow(self, heap_start, heap_size)
+                }
+            }
+        )*
+    };
+}
+
+impl_heap_allocator!(BumpAllocator, LinkedListAllocator, BuddyAllocator);
+
+/// All heap allocators, forwarding to the one selected when initializing the
+/// heap so allocators can be compared without rebuilding
+pub struct Allocators {
+    kind: AtomicU8,
+    bump: BumpAllocator,
+    linked_list: LinkedListAllocator,
+    buddy: BuddyAllocator,
+}
+
+impl Allocators {
+    const fn new() -> Self {
+        Self {
+            kind: AtomicU8::new(Kind::LinkedList as u8),
+            bump: BumpAllocator::new(),
+            linked_list: LinkedListAllocator::new(),
+            buddy: BuddyAllocator::new(),
+        }
+    }
+
+    fn selected(&self) -> &dyn HeapAllocator {
+        match self.kind.load(Ordering::Relaxed) {
+            x if x == Kind::Bump as u8 => &self.bump,
+            x if x == Kind::Buddy as u8 => &self.buddy,
+            _ => &self.linked_list,
+        }
+    }
+
+    /// Select the allocator of `kind` and give it the initial heap
+    ///
+    /// # Safety
+    /// See [`BumpAllocator::init`].
+    unsafe fn init(&self, kind: Kind, heap_start: u64, heap_size: u64) {
+        self.kind.store(kind as u8, Ordering::Relaxed);
+        self.selected().init(heap_start, heap_size);
+    }
+
+    /// # Safety
+    /// See [`BumpAllocator::grow`].
+    unsafe fn grow(&self, heap_start: u64, heap_size: u64) -> bool {
+        self.selected().grow(heap_start, heap_size)
+    }
+}
+
+unsafe impl GlobalAlloc for Allocators {
+    unsafe fn alloc(&self, layout: Layout) -> *mut u8 {
+        self.selected().alloc(layout)
+    }
+
+    unsafe fn dealloc(&self, ptr: *mut u8, layout: Layout) {
+        self.selected().dealloc(ptr, layout)
+    }
+
+    unsafe fn realloc(&self, ptr: *mut u8, layout: Layout, new_size: usize) -> *mut u8 {
+        self.selected().realloc(ptr, layout, new_size)
+    }
+}
+
+/// Allocator wrapper recording allocations and deallocations as trace events
+/// and in the heap usage, and retrying failed allocations after the [`oom`] policy freed memory
+///
+/// Injected failures, see [`inject`], skip the policy.
+pub struct Heap<A>(A);
+
+unsafe impl<A: GlobalAlloc> GlobalAlloc for Heap<A> {
+    unsafe fn alloc(&self, layout: Layout) -> *mut u8 {
+        trace::record(TraceKind::Alloc, layout.size() as u64);
+        if inject::fail(Site::Heap) {
+            return ptr::null_mut();
+        }
+        loop {
+            let ptr = self.0.alloc(layout);
+            if !ptr.is_null() {
+                HEAP_USED.fetch_add(layout.size() as u64, Ordering::Relaxed);
+                return ptr;
+            }
+            if !oom::handle(layout) {
+                return ptr;
+            }
+        }
+    }
+
+    unsafe fn dealloc(&self, ptr: *mut u8, layout: Layout) {
+        trace::record(TraceKind::Free, layout.size() as u64);
+        HEAP_USED.fetch_sub(layout.size() as u64, Ordering::Relaxed);
+        self.0.dealloc(ptr, layout)
+    }
+
+    unsafe fn realloc(&self, ptr: *mut u8, layout: Layout, new_size: usize) -> *mut u8 {
+        trace::record(TraceKind::Free, layout.size() as u64);
+        trace::record(TraceKind::Alloc, new_size as u64);
+        loop {
+            let new_ptr = self.0.realloc(ptr, layout, new_size);
+            if !new_ptr.is_null() {
+                HEAP_USED.fetch_add(new_size as u64, Ordering::Relaxed);
+                HEAP_USED.fetch_sub(layout.size() as u64, Ordering::Relaxed);
+                return new_ptr;
+            }
+            // The failed allocation is the new size, which `realloc` callers
+            // guarantee forms a valid layout with the old alignment
+            let new_layout = Layout::from_size_align_unchecked(new_size, layout.align());
+            if !oom::handle(new_layout) {
+                return new_ptr;
+            }
+        }
+    }
+}
+
+pub fn init<A>(address_space: &mut AddressSpace, allocator: &mut A) -> Result<(), &'static str>
 where
-    M: Mapper<Size4KiB>,
-    A: FrameAllocator<Size4KiB>,
+    A: FrameAllocator<Size4KiB> + FrameDeallocator<Size4KiB>,
 {
+    let heap_end = HEAP_START + HEAP_SIZE + HEAP_GROWTH * HEAP_GROWTH_STEPS;
+    let kind = config::allocator();
     log::debug!(
-        "Initializing heap at {:?}..{:?}",
+        "Initializing heap at {:?}..{:?}, reserve up to {:?}, using {:?} allocator",
         HEAP_START,
-        HEAP_START + HEAP_SIZE
+        HEAP_START + HEAP_SIZE,
+        heap_end,
+        kind
     );
-    for page in Page::range_inclusive(
+    let pages = Page::range(
         Page::containing_address(HEAP_START),
-        Page::containing_address(HEAP_START + (HEAP_SIZE - 1)),
-    ) {
-        let flags = PageTableFlags::PRESENT | PageTableFlags::WRITABLE;
-        let frame = allocator.allocate_frame().unwrap();
-        unsafe { mapper.map_to(page, frame, flags, allocator)? }.flush();
+        Page::containing_address(heap_end),
+    );
+    let flags = PageTableFlags::PRESENT | PageTableFlags::WRITABLE;
+    address_space.map_anonymous(pages, flags, None, allocator)?;
+    if config::SANITIZE_HEAP {
+        log::info!("Heap sanitizer enabled");
     }
-    unsafe { ALLOC.init(HEAP_START.as_u64(), HEAP_SIZE) };
+    unsafe { ALLOC.0.inner().init(kind, HEAP_START.as_u64(), HEAP_SIZE) };
+    HEAP_END.store((HEAP_START + HEAP_SIZE).as_u64(), Ordering::Relaxed);
     Ok(())
 }
 
+/// Grow the heap by [`HEAP_GROWTH`] into its reserve, which is already mapped
+/// (see [`HEAP_GROWTH_STEPS`])
+///
+/// Returns the new size of the heap, or [`None`] if the reserve is exhausted
+/// or the allocator cannot grow.
+pub fn grow() -> Option<u64> {
+    let limit = (HEAP_START + HEAP_SIZE + HEAP_GROWTH * HEAP_GROWTH_STEPS).as_u64();
+    let start = HEAP_END
+        .fetch_update(Ordering::Relaxed, Ordering::Relaxed, |end| {
+            Some(end + HEAP_GROWTH).filter(|&end| end <= limit)
+        })
+        .ok()?;
+    // The reserve was mapped by init and is handed out only once
+    if unsafe { ALLOC.0.inner().grow(start, HEAP_GROWTH) } {
+        Some(start + HEAP_GROWTH - HEAP_START.as_u64())
+    } else {
+        None
+    }
+}
+
+/// Use of physical memory, as seen by `frame_allocator`, and of the heap
+pub fn memory_stats(frame_allocator: &UserFrameAllocator<RegionFrameAllocator>) -> MemoryStats {
+    MemoryStats {
+        total: frame_allocator.total_frames() * Size4KiB::SIZE,
+        free: frame_allocator.free_frames() * Size4KiB::SIZE,
+        heap_size: HEAP_END.load(Ordering::Relaxed) - HEAP_START.as_u64(),
+        heap_used: HEAP_USED.load(Ordering::Relaxed),
+    }
+}
+
+/// Page-aligned memory of `N` bytes for an allocator under test to manage
+#[cfg(test)]
+#[repr(align(0x1000))]
+pub struct TestHeap<const N: usize>([u8; N]);
+
+#[cfg(test)]
+impl<const N: usize> TestHeap<N> {
+    pub const fn new() -> Self {
+        Self([0; N])
+    }
+
+    /// Address of the first byte
+    pub fn start(&mut self) -> u64 {
+        self.0.as_mut_ptr() as u64
+    }
+}
+
 #[cfg(test)]
 mod tests {
+    use super::*;
+    use crate::test::Rng;
     use alloc::boxed::Box;
+    use core::{ptr, slice};
+
+    const HEAP_SIZE: usize = 0x4000;
+    /// Maximum number of live allocations during [`fuzz`]
+    const SLOTS: usize = 32;
+    /// Seeds of the operation sequences of [`fuzz`]
+    const SEEDS: u64 = 4;
 
     #[test_case]
     fn boxed() {
@@ -61,4 +307,179 @@ mod tests {
         *boxed += 10;
         assert_eq!(*boxed, 20);
     }
+
+    #[test_case]
+    fn heap_used() {
+        let before = HEAP_USED.load(Ordering::Relaxed);
+        let boxed = Box::new([0u8; 100]);
+        assert_eq!(HEAP_USED.load(Ordering::Relaxed), before + 100);
+        drop(boxed);
+        assert_eq!(HEAP_USED.load(Ordering::Relaxed), before);
+    }
+
+    /// Check the guarantees of [`GlobalAlloc`] for `allocator`, initialized
+    /// with `buffer` as heap
+    fn conformance<A: HeapAllocator>(allocator: &A, buffer: &'static mut TestHeap<HEAP_SIZE>) {
+        let start = buffer.start();
+        let end = start + HEAP_SIZE as u64;
+        unsafe { allocator.init(start, HEAP_SIZE as u64) };
+
+        // Simultaneous allocations are aligned, within the heap and disjoint
+        let mut live = [(ptr::null_mut::<u8>(), Layout::new::<u8>()); 16];
+        for (i, slot) in live.iter_mut().enumerate() {
+            let layout = Layout::from_size_align(1 + 37 * i, 1 << (i % 8)).unwrap();
+            let ptr = unsafe { allocator.alloc(layout) };
+            assert!(!ptr.is_null());
+            assert_eq!(ptr as usize % layout.align(), 0);
+            assert!(ptr as u64 >= start && ptr as u64 + layout.size() as u64 <= end);
+            unsafe { ptr.write_bytes(i as u8, layout.size()) };
+            *slot = (ptr, layout);
+        }
+        for (i, &(ptr, layout)) in live.iter().enumerate() {
+            let bytes = unsafe { slice::from_raw_parts(ptr, layout.size()) };
+            assert!(bytes.iter().all(|&byte| byte == i as u8));
+            unsafe { allocator.dealloc(ptr, layout) };
+        }
+
+        // Reallocation keeps the contents when growing and shrinking
+        let layout = Layout::from_size_align(16, 8).unwrap();
+        unsafe {
+            let ptr = allocator.alloc(layout);
+            assert!(!ptr.is_null());
+            (0..16).for_each(|i| ptr.add(i).write(i as u8));
+            let ptr = allocator.realloc(ptr, layout, 300);
+            assert!(!ptr.is_null());
+            assert!((0..16).all(|i| ptr.add(i).read() == i as u8));
+            let layout = Layout::from_size_align(300, 8).unwrap();
+            let ptr = allocator.realloc(ptr, layout, 8);
+            assert!(!ptr.is_null());
+            assert!((0..8).all(|i| ptr.add(i).read() == i as u8));
+            allocator.dealloc(ptr, Layout::from_size_align(8, 8).unwrap());
+        }
+
+        // Freed memory is reused
+        let layout = Layout::from_size_align(HEAP_SIZE / 2, 8).unwrap();
+        unsafe {
+            let ptr = allocator.alloc(layout);
+            assert!(!ptr.is_null());
+            allocator.dealloc(ptr, layout);
+        }
+    }
+
+    /// Check that `ptr` is a valid placement of `layout` in the heap at
+    /// `start..end`, overlapping none of the `live` allocations but `slot`
+    fn check_placement(
+        live: &[Option<(*mut u8, Layout)>],
+        slot: usize,
+        ptr: *mut u8,
+        layout: Layout,
+        (start, end): (u64, u64),
+    ) {
+        let addr = ptr as u64;
+        let addr_end = addr + layout.size() as u64;
+        assert_eq!(addr % layout.align() as u64, 0);
+        assert!(addr >= start && addr_end <= end);
+        for (i, allocation) in live.iter().enumerate() {
+            if let Some((other, other_layout)) = *allocation {
+                let other = other as u64;
+                let other_end = other + other_layout.size() as u64;
+                assert!(i == slot || addr_end <= other || other_end <= addr);
+            }
+        }
+    }
+
+    /// Check the invariants of `allocator`, initialized with `buffer` as heap,
+    /// over a random sequence of allocations, deallocations and reallocations
+    ///
+    /// Live allocations are aligned, within the heap, disjoint and keep their
+    /// contents. Once everything is freed, free memory should be merged again
+    /// so the whole heap can be allocated at once.
+    fn fuzz<A: HeapAllocator>(allocator: &A, buffer: &'static mut TestHeap<HEAP_SIZE>, seed: u64) {
+        let start = buffer.start();
+        let heap = (start, start + HEAP_SIZE as u64);
+        unsafe { allocator.init(start, HEAP_SIZE as u64) };
+        let mut rng = Rng::new(seed);
+        let mut live: [Option<(*mut u8, Layout)>; SLOTS] = [None; SLOTS];
+        for _ in 0..2000 {
+            let slot = rng.below(SLOTS as u64) as usize;
+            let size = 1 + rng.below(512) as usize;
+            match live[slot] {
+                Some((ptr, layout)) => unsafe {
+                    let bytes = slice::from_raw_parts(ptr, layout.size());
+                    assert!(bytes.iter().all(|&byte| byte == slot as u8));
+                    if rng.below(2) == 0 {
+                        allocator.dealloc(ptr, layout);
+                        live[slot] = None;
+                        continue;
+                    }
+                    let ptr = allocator.realloc(ptr, layout, size);
+                    if ptr.is_null() {
+                        // The old allocation is left untouched
+                        continue;
+                    }
+                    let new_layout = Layout::from_size_align(size, layout.align()).unwrap();
+                    check_placement(&live, slot, ptr, new_layout, heap);
+                    let kept = layout.size().min(size);
+                    let bytes = slice::from_raw_parts(ptr, kept);
+                    assert!(bytes.iter().all(|&byte| byte == slot as u8));
+                    ptr.add(kept).write_bytes(slot as u8, size - kept);
+                    live[slot] = Some((ptr, new_layout));
+                },
+                None => {
+                    let align = 1 << rng.below(7);
+                    let layout = Layout::from_size_align(size, align).unwrap();
+                    let ptr = unsafe { allocator.alloc(layout) };
+                    if ptr.is_null() {
+                        continue;
+                    }
+                    check_placement(&live, slot, ptr, layout, heap);
+                    unsafe { ptr.write_bytes(slot as u8, size) };
+                    live[slot] = Some((ptr, layout));
+                }
+            }
+        }
+        for (ptr, layout) in live.iter().flatten() {
+            unsafe { allocator.dealloc(*ptr, *layout) };
+        }
+        let layout = Layout::from_size_align(HEAP_SIZE, 8).unwrap();
+        unsafe {
+            let ptr = allocator.alloc(layout);
+            assert_eq!(ptr as u64, start);
+            allocator.dealloc(ptr, layout);
+        }
+    }
+
+    #[test_case]
+    fn bump_conformance() {
+        static mut HEAP: TestHeap<HEAP_SIZE> = TestHeap::new();
+        conformance(&BumpAllocator::new(), unsafe { &mut HEAP });
+    }
+
+    #[test_case]
+    fn linked_list_conformance() {
+        static mut HEAP: TestHeap<HEAP_SIZE> = TestHeap::new();
+        conformance(&LinkedListAllocator::new(), unsafe { &mut HEAP });
+    }
+
+    #[test_case]
+    fn buddy_conformance() {
+        static mut HEAP: TestHeap<HEAP_SIZE> = TestHeap::new();
+        conformance(&BuddyAllocator::new(), unsafe { &mut HEAP });
+    }
+
+    #[test_case]
+    fn linked_list_fuzz() {
+        static mut HEAP: TestHeap<HEAP_SIZE> = TestHeap::new();
+        for seed in 0..SEEDS {
+            fuzz(&LinkedListAllocator::new(), unsafe { &mut HEAP }, seed);
+        }
+    }
+
+    #[test_case]
+    fn buddy_fuzz() {
+        static mut HEAP: TestHeap<HEAP_SIZE> = TestHeap::new();
+        for seed in 0..SEEDS {
+            fuzz(&BuddyAllocator::new(), unsafe { &mut HEAP }, seed);
+        }
+    }
 }
@@ -0,0 +1,195 @@
+//! A simple buddy allocator
+
+use core::{
+    alloc::{GlobalAlloc, Layout},
+    ptr,
+};
+use spin::Mutex;
+
+/// Size of the smallest block as a power of two, large enough for a link
+const MIN_ORDER: usize = 4;
+/// Size of the largest block as a power of two
+const MAX_ORDER: usize = 21;
+const ORDERS: usize = MAX_ORDER - MIN_ORDER + 1;
+
+/// Buddy allocator
+///
+/// Memory is divided into blocks with a power of two size, aligned to their
+/// size relative to the start of the heap. Allocations are rounded up to the
+/// next block size and take the smallest free block that fits, splitting larger
+/// blocks in halves (buddies) as needed. Freed blocks are merged with their
+/// buddy when it is free as well, which keeps external fragmentation low at
+/// the cost of up to half of each block being unused.
+pub struct BuddyAllocator(Mutex<Blocks>);
+
+/// Free lists of blocks per order
+struct Blocks {
+    start: u64,
+    /// Largest alignment guaranteed for blocks, following from `start`
+    max_align: u64,
+    /// Address of the first free block of each order, or zero if there is
+    /// none; each free block starts with the address of the next
+    free: [u64; ORDERS],
+}
+
+impl Blocks {
+    /// Add free block of `order` at `addr` to its list
+    unsafe fn push(&mut self, order: usize, addr: u64) {
+        *(addr as *mut u64) = self.free[order - MIN_ORDER];
+        self.free[order - MIN_ORDER] = addr;
+    }
+
+    /// Take a free block of `order` from its list
+    unsafe fn pop(&mut self, order: usize) -> Option<u64> {
+        let addr = self.free[order - MIN_ORDER];
+        if addr == 0 {
+            return None;
+        }
+        self.free[order - MIN_ORDER] = *(addr as *const u64);
+        Some(addr)
+    }
+
+    /// Take the free block of `order` at `addr` from its list, if it is there
+    unsafe fn remove(&mut self, order: usize, addr: u64) -> bool {
+        let mut link = &mut self.free[order - MIN_ORDER] as *mut u64;
+        while *link != 0 {
+            if *link == addr {
+                *link = *(addr as *const u64);
+                return true;
+            }
+            link = *link as *mut u64;
+        }
+        false
+    }
+
+    /// Split a free block into blocks of `order` and take one
+    unsafe fn allocate(&mut self, order: usize) -> Option<u64> {
+        let mut current = (order..=MAX_ORDER).find(|&order| self.free[order - MIN_ORDER] != 0)?;
+        let addr = self.pop(current)?;
+        while current > order {
+            current -= 1;
+            self.push(current, addr + (1 << current));
+        }
+        Some(addr)
+    }
+
+    /// Free the block of `order` at `addr`, merging it with free buddies
+    unsafe fn deallocate(&mut self, mut addr: u64, mut order: usize) {
+        while order < MAX_ORDER {
+            let buddy = self.start + ((addr - self.start) ^ (1 << order));
+            if !self.remove(order, buddy) {
+                break;
+            }
+            addr = addr.min(buddy);
+            order += 1;
+        }
+        self.push(order, addr);
+    }
+
+    /// Free `addr..end` as the largest aligned blocks that fit
+    unsafe fn add(&mut self, mut addr: u64, end: u64) {
+        while addr + (1 << MIN_ORDER) <= end {
+            let offset = addr - self.start;
+            let order = (MIN_ORDER..=MAX_ORDER)
+                .rev()
+                .find(|&order| offset % (1 << order) == 0 && addr + (1 << order) <= end)
+                .unwrap();
+            self.deallocate(addr, order);
+            addr += 1 << order;
+        }
+    }
+}
+
+impl BuddyAllocator {
+    pub const fn new() -> Self {
+        Self(Mutex::new(Blocks {
+            start: 0,
+            max_align: 0,
+            free: [0; ORDERS],
+        }))
+    }
+
+    /// # Safety
+    /// Safe iff virtual addresses `heap_start..heap_start+heap_size` are backed
+    /// by unused physical memory.
+    pub unsafe fn init(&self, heap_start: u64, heap_size: u64) {
+        let mut blocks = self.0.lock();
+        blocks.start = heap_start;
+        blocks.max_align = 1 << heap_start.trailing_zeros().min(MAX_ORDER as u32);
+        blocks.add(heap_start, heap_start + heap_size);
+    }
+
+    /// Add memory after the start of the heap, which is merged with adjacent
+    /// free blocks where possible
+    ///
+    /// Always succeeds and returns `true`.
+    ///
+    /// # Safety
+    /// Safe iff virtual addresses `heap_start..heap_start+heap_size` are backed
+    /// by unused physical memory.
+    pub unsafe fn grow(&self, heap_start: u64, heap_size: u64) -> bool {
+        self.0.lock().add(heap_start, heap_start + heap_size);
+        true
+    }
+
+    /// Order of the blocks used for `layout`, or [`None`] if it is too large
+    fn order(layout: Layout) -> Option<usize> {
+        let size = layout
+            .size()
+            .max(layout.align())
+            .max(1 << MIN_ORDER)
+            .next_power_of_two();
+        let order = size.trailing_zeros() as usize;
+        if order <= MAX_ORDER {
+            Some(order)
+        } else {
+            None
+        }
+    }
+}
+
+unsafe impl GlobalAlloc for BuddyAllocator {
+    unsafe fn alloc(&self, layout: Layout) -> *mut u8 {
+        log::trace!("Allocating {:?}", layout);
+        let mut blocks = self.0.lock();
+        if layout.align() as u64 > blocks.max_align {
+            return ptr::null_mut();
+        }
+        Self::order(layout)
+            .and_then(|order| blocks.allocate(order))
+            .map_or(ptr::null_mut(), |addr| addr as *mut u8)
+    }
+
+    unsafe fn dealloc(&self, ptr: *mut u8, layout: Layout) {
+        log::trace!("Deallocating {:?}", layout);
+        if let Some(order) = Self::order(layout) {
+            self.0.lock().deallocate(ptr as u64, order);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::allocator::TestHeap;
+
+    #[test_case]
+    fn split_and_merge() {
+        static mut HEAP: TestHeap<0x1000> = TestHeap::new();
+        let allocator = BuddyAllocator::new();
+        let start = unsafe { HEAP.start() };
+        unsafe { allocator.init(start, 0x1000) };
+        let small = Layout::from_size_align(24, 8).unwrap();
+        let large = Layout::from_size_align(0x1000, 8).unwrap();
+        unsafe {
+            let a = allocator.alloc(small);
+            let b = allocator.alloc(small);
+            assert_eq!(a as u64, start);
+            assert_eq!(b as u64, start + 32);
+            assert!(allocator.alloc(large).is_null());
+            allocator.dealloc(a, small);
+            allocator.dealloc(b, small);
+            assert_eq!(allocator.alloc(large) as u64, start);
+        }
+    }
+}
@@ -40,6 +40,25 @@ impl BumpAllocator {
         self.start.store(heap_start, Ordering::SeqCst);
     }
 
+    /// Add memory to the heap, which is only possible if it directly follows
+    /// the current heap
+    ///
+    /// Returns whether the heap was grown.
+    ///
+    /// # Safety
+    /// Safe iff virtual addresses `heap_start..heap_start+heap_size` are backed
+    /// by unused physical memory.
+    pub unsafe fn grow(&self, heap_start: u64, heap_size: u64) -> bool {
+        self.end
+            .compare_exchange(
+                heap_start,
+                heap_start + heap_size,
+                Ordering::Relaxed,
+                Ordering::Relaxed,
+            )
+            .is_ok()
+    }
+
     /// Allocate a certain layout
     ///
     /// The virtual address of the first byte of the layout is returned, or
@@ -125,4 +144,27 @@ unsafe impl GlobalAlloc for BumpAllocator {
     unsafe fn dealloc(&self, _ptr: *mut u8, _layout: Layout) {
         self.deallocate();
     }
+
+    /// Resize the most recent allocation in place, otherwise allocate, copy
+    /// and deallocate
+    unsafe fn realloc(&self, ptr: *mut u8, layout: Layout, new_size: usize) -> *mut u8 {
+        let old_end = ptr as u64 + layout.size() as u64;
+        let new_end = ptr as u64 + new_size as u64;
+        if new_end < self.end.load(Ordering::Relaxed)
+            && self
+                .next
+                .compare_exchange(old_end, new_end, Ordering::Relaxed, Ordering::Relaxed)
+                .is_ok()
+        {
+            log::trace!("Reallocated {:?} in place to {} bytes", layout, new_size);
+            return ptr;
+        }
+        let new_layout = Layout::from_size_align_unchecked(new_size, layout.align());
+        let new_ptr = self.alloc(new_layout);
+        if !new_ptr.is_null() {
+            ptr::copy_nonoverlapping(ptr, new_ptr, layout.size().min(new_size));
+            self.dealloc(ptr, layout);
+        }
+        new_ptr
+    }
 }
@@ -1,8 +1,8 @@
 //! Everything related to the linked list allocator
 
+use common::hole::Hole;
 use core::{
     alloc::{GlobalAlloc, Layout},
-    borrow::Borrow,
     fmt, mem, ptr,
 };
 use spin::{mutex::MutexGuard, Mutex};
@@ -29,87 +29,9 @@ impl From<Layout> for NodeLayout {
     }
 }
 
-/// Describes a free block of memory based on its starting address and size.
-#[derive(Copy, Clone, Debug)]
-struct Hole {
-    addr: VirtAddr,
-    size: u64,
-}
-
-impl Hole {
-    fn new(addr: VirtAddr, size: u64) -> Self {
-        Self { addr, size }
-    }
-
-    fn start_addr(self) -> VirtAddr {
-        self.addr
-    }
-
-    fn end_addr(self) -> VirtAddr {
-        self.start_addr() + self.size
-    }
-
-    /// Create [`Node`] as described by [`Hole`]
-    ///
-    /// The `next` field of the node is set to [`None`].
-    ///
-    /// # Panic
-    /// Panics if the hole is not lare enough to fit the node or if the hole is
-    /// not properly aligned to fit the node.
-    ///
-    /// # Safety
-    /// Starting from `hole.addr`, `hole.size` bytes need to be backed by
-    /// physical memory and ownership of that memory is transferred to the node.
-    unsafe fn to_static_node(self) -> &'static mut Node {
-        assert!(self.size >= Node::SIZE);
-        assert!(self.addr.is_aligned(Node::ALIGN));
-
-        let node = Node::new(self.size);
-        let node_ptr = self.addr.as_mut_ptr::<Node>();
-        node_ptr.write(node);
-        &mut *node_ptr
-    }
-
-    fn from_alloc(addr: VirtAddr, layout: NodeLayout) -> Self {
-        Self::new(addr, layout.size)
-    }
-
-    /// Determine if and how a [`NodeLayout`] can fit in a [`Hole`]
-    ///
-    /// If the layout cannot fit, [`None`] is returned, otherwise a [`VirtAddr`]
-    /// is returned for where the layout would fit, along with up to two holes
-    /// that fill the remaining space of the hole. It is guaranteed that the
-    /// optional first hole's location is the same as `self` and that the
-    /// optional second hole's location is after the layout allocation.
-    fn fit_alloc(self, layout: NodeLayout) -> Option<(Option<Self>, VirtAddr, Option<Self>)> {
-        // Calculate placement of new allocation
-        let start = self.start_addr().align_up(layout.align);
-        let end = start + layout.size;
-        if end > self.end_addr() {
-            return None;
-        }
-
-        // Calculate placements and necessity of holes before and after
-        let excess_before = start - self.start_addr();
-        let before = if excess_before == 0 {
-            None
-        } else if excess_before < Node::SIZE {
-            return None;
-        } else {
-            Some(Self::new(self.start_addr(), excess_before))
-        };
-
-        let excess_after = self.end_addr() - end;
-        let after = if excess_after == 0 {
-            None
-        } else if excess_after < Node::SIZE {
-            return None;
-        } else {
-            Some(Self::new(end, excess_after))
-        };
-
-        Some((before, start, after))
-    }
+/// Determine if and how a [`NodeLayout`] can fit in `hole`, see [`Hole::fit`]
+fn fit(hole: Hole, layout: NodeLayout) -> Option<(Option<Hole>, VirtAddr, Option<Hole>)> {
+    hole.fit(layout.size, layout.align, Node::SIZE)
 }
 
 /// Node in linked list of free memory regions
@@ -146,9 +68,35 @@ impl Node {
         self.start_addr() + self.size
     }
 
-    /// Convenience wrapper around [`Hole::fit_alloc`]
+    /// Free block of memory described by the node
+    fn hole(&self) -> Hole {
+        Hole::new(self.start_addr(), self.size)
+    }
+
+    /// Create [`Node`] as described by `hole`
+    ///
+    /// The `next` field of the node is set to [`None`].
+    ///
+    /// # Panic
+    /// Panics if the hole is not lare enough to fit the node or if the hole is
+    /// not properly aligned to fit the node.
+    ///
+    /// # Safety
+    /// Starting from `hole.addr`, `hole.size` bytes need to be backed by
+    /// physical memory and ownership of that memory is transferred to the node.
+    unsafe fn from_hole(hole: Hole) -> &'static mut Self {
+        assert!(hole.size >= Node::SIZE);
+        assert!(hole.addr.is_aligned(Node::ALIGN));
+
+        let node = Node::new(hole.size);
+        let node_ptr = hole.addr.as_mut_ptr::<Node>();
+        node_ptr.write(node);
+        &mut *node_ptr
+    }
+
+    /// Convenience wrapper around [`fit`]
     fn fit_alloc(&self, layout: NodeLayout) -> Option<(Option<Hole>, VirtAddr, Option<Hole>)> {
-        Hole::from(self).fit_alloc(layout)
+        fit(self.hole(), layout)
     }
 
     /// Insert [`Node`] in the linked list immediately after `self`
@@ -163,9 +111,9 @@ impl Node {
     /// Convenience wrapper around [`Node::insert`]
     ///
     /// Since the [`Hole`] needs to be converted to a [`Node`], the same
-    /// requirements hold as for [`Hole::to_static_node`].
+    /// requirements hold as for [`Node::from_hole`].
     unsafe fn insert_hole(&mut self, hole: Hole) {
-        self.insert(hole.to_static_node())
+        self.insert(Node::from_hole(hole))
     }
 
     /// Unlink the next node from the linked list
@@ -175,12 +123,37 @@ impl Node {
             node
         })
     }
-}
 
-impl<T: Borrow<Node>> From<T> for Hole {
-    fn from(node: T) -> Self {
-        let node = node.borrow();
-        Self::new(node.start_addr(), node.size)
+    /// Check the invariants of the linked list following `self`
+    ///
+    /// Nodes should be aligned, large enough to hold a node and sorted by
+    /// address. They should neither overlap nor touch, as adjacent nodes are
+    /// merged.
+    fn validate(&self) -> Result<(), &'static str> {
+        let mut prev: Option<&Node> = None;
+        let mut next = self.next.as_deref();
+        while let Some(node) = next {
+            if !node.start_addr().is_aligned(Node::ALIGN) {
+                return Err("Misaligned node");
+            }
+            if node.size < Node::SIZE {
+                return Err("Node too small");
+            }
+            if let Some(prev) = prev {
+                if node.start_addr() <= prev.start_addr() {
+                    return Err("Nodes not sorted");
+                }
+                if node.start_addr() < prev.end_addr() {
+                    return Err("Nodes overlap");
+                }
+                if node.start_addr() == prev.end_addr() {
+                    return Err("Adjacent nodes not merged");
+                }
+            }
+            prev = Some(node);
+            next = node.next.as_deref();
+        }
+        Ok(())
     }
 }
 
@@ -227,7 +200,7 @@ impl fmt::Debug for LinkedListAllocator {
         let mut head = self.head();
         let mut iter = NodeIter::new(&mut head);
         while let Some(region) = iter.current() {
-            list.entry(&Hole::from(region));
+            list.entry(&region.hole());
             iter.advance();
         }
         list.finish()
@@ -253,11 +226,29 @@ impl LinkedListAllocator {
         self.push(hole);
     }
 
+    /// Add memory to the heap, see [`LinkedListAllocator::init`]
+    ///
+    /// Always succeeds and returns `true`.
+    ///
+    /// # Safety
+    /// Same as [`LinkedListAllocator::init`].
+    pub unsafe fn grow(&self, heap_start: u64, heap_size: u64) -> bool {
+        self.init(heap_start, heap_size);
+        true
+    }
+
     /// Lock the heap and get the head node
     fn head(&self) -> MutexGuard<Node> {
         self.0.lock()
     }
 
+    /// Check the consistency of the list of free memory regions
+    ///
+    /// This is done after every change in debug builds.
+    pub fn validate(&self) -> Result<(), &'static str> {
+        self.head().validate()
+    }
+
     /// Push hole in linked list and merge with other nodes if possible
     unsafe fn push(&self, mut hole: Hole) {
         // Find region after which the hole whould be located
@@ -282,6 +273,7 @@ impl LinkedListAllocator {
             } else {
                 region.insert_hole(hole);
             }
+            debug_assert_eq!(head.validate(), Ok(()));
             return;
         }
         unreachable!();
@@ -307,6 +299,7 @@ impl LinkedListAllocator {
                     if let Some(after) = after {
                         unsafe { current.insert_hole(after) };
                     }
+                    debug_assert_eq!(head.validate(), Ok(()));
                     return Some(start);
                 }
             }
@@ -318,7 +311,7 @@ impl LinkedListAllocator {
     /// Deallocate memory and put it back into the linked list
     unsafe fn deallocate(&self, addr: VirtAddr, layout: NodeLayout) {
         log::trace!("Deallocating {:?}", layout);
-        let hole = Hole::from_alloc(addr, layout);
+        let hole = Hole::new(addr, layout.size);
         self.push(hole);
     }
 
@@ -332,13 +325,13 @@ impl LinkedListAllocator {
         layout: NodeLayout,
         new_size: u64,
     ) -> Option<VirtAddr> {
-        let mut hole = Hole::from_alloc(addr, layout);
+        let mut hole = Hole::new(addr, layout.size);
         let new_layout = Layout::from_size_align(new_size as usize, layout.align as usize)
             .unwrap()
             .into();
         // Small allocations may have been made larger due to NodeLayout
         // size/align requirements and may not require any actual work.
-        if let Some((before, start, after)) = hole.fit_alloc(new_layout) {
+        if let Some((before, start, after)) = fit(hole, new_layout) {
             // If after isn't None we will need to insert it into the list
             if after.is_none() {
                 assert!(before.is_none());
@@ -360,25 +353,27 @@ impl LinkedListAllocator {
                 // Found hole, simply grow or shrink if possible
                 if next.start_addr() == hole.end_addr() {
                     hole.size += next.size;
-                    if let Some((before, start, after)) = hole.fit_alloc(new_layout) {
+                    if let Some((before, start, after)) = fit(hole, new_layout) {
                         region.next = next.next.take();
                         assert!(before.is_none());
                         assert_eq!(addr, start);
                         if let Some(after) = after {
                             region.insert_hole(after);
                         }
+                        debug_assert_eq!(head.validate(), Ok(()));
                         return Some(addr);
                     }
                     hole.size -= next.size;
                 }
             } else {
                 // Allocation is at the very end, but shrinking might be possible
-                if let Some((before, start, after)) = hole.fit_alloc(new_layout) {
+                if let Some((before, start, after)) = fit(hole, new_layout) {
                     assert!(before.is_none());
                     assert_eq!(addr, start);
                     if let Some(after) = after {
                         region.insert_hole(after);
                     }
+                    debug_assert_eq!(head.validate(), Ok(()));
                     return Some(addr);
                 }
             }
@@ -423,3 +418,68 @@ unsafe impl GlobalAlloc for LinkedListAllocator {
             .unwrap_or(ptr::null_mut())
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{allocator::TestHeap, test::Rng};
+    use core::slice;
+
+    const HEAP_SIZE: usize = 0x4000;
+    /// Maximum number of live allocations
+    const SLOTS: usize = 32;
+
+    /// Free memory according to the linked list
+    fn free_size(allocator: &LinkedListAllocator) -> u64 {
+        let mut head = allocator.head();
+        let mut iter = NodeIter::new(&mut head);
+        let mut size = 0;
+        while let Some(node) = iter.current() {
+            size += node.size;
+            iter.advance();
+        }
+        size
+    }
+
+    /// Random allocations and deallocations, checked against a model of the
+    /// live allocations filled with a byte identifying them
+    #[test_case]
+    fn fuzz() {
+        static mut HEAP: TestHeap<HEAP_SIZE> = TestHeap::new();
+        let allocator = LinkedListAllocator::new();
+        let start = unsafe { HEAP.start() };
+        unsafe { allocator.init(start, HEAP_SIZE as u64) };
+        let mut rng = Rng::new(0x2545_f491_4f6c_dd1d);
+        let mut live: [Option<(*mut u8, Layout)>; SLOTS] = [None; SLOTS];
+        for _ in 0..2000 {
+            let slot = rng.next() as usize % SLOTS;
+            match live[slot].take() {
+                Some((ptr, layout)) => unsafe {
+                    let bytes = slice::from_raw_parts(ptr, layout.size());
+                    assert!(bytes.iter().all(|&byte| byte == slot as u8));
+                    allocator.dealloc(ptr, layout);
+                },
+                None => {
+                    let size = 1 + rng.next() as usize % 512;
+                    let align = 1 << (rng.next() % 7);
+                    let layout = Layout::from_size_align(size, align).unwrap();
+                    let ptr = unsafe { allocator.alloc(layout) };
+                    if ptr.is_null() {
+                        continue;
+                    }
+                    let addr = ptr as u64;
+                    assert_eq!(addr % align as u64, 0);
+                    assert!(addr >= start && addr + size as u64 <= start + HEAP_SIZE as u64);
+                    unsafe { ptr.write_bytes(slot as u8, size) };
+                    live[slot] = Some((ptr, layout));
+                }
+            }
+            assert_eq!(allocator.validate(), Ok(()));
+        }
+        for (ptr, layout) in live.iter().flatten() {
+            unsafe { allocator.dealloc(*ptr, *layout) };
+        }
+        assert_eq!(allocator.validate(), Ok(()));
+        assert_eq!(free_size(&allocator), HEAP_SIZE as u64);
+    }
+}
@@ -3,16 +3,31 @@
 use common::boot::MemoryMap;
 use uefi::table::boot::{MemoryDescriptor, MemoryType};
 use x86_64::{
-    structures::paging::{frame::PhysFrameRange, FrameAllocator, PageSize, PhysFrame, Size4KiB},
+    structures::paging::{
+        frame::PhysFrameRange, FrameAllocator, FrameDeallocator, PageSize, PhysFrame, Size4KiB,
+    },
     PhysAddr,
 };
 
+/// Maximum number of regions added after boot
+const MAX_ADDED: usize = 16;
+/// Maximum number of partial regions skipped by contiguous allocations
+const MAX_SKIPPED: usize = 16;
+
 /// Frame allocator based on memory regions
 ///
-/// Currently only allocates pages in regions marked conventional by UEFI.
+/// Allocates frames in regions marked conventional by UEFI, followed by those
+/// in regions added later with [`RegionFrameAllocator::add_region`].
 pub struct RegionFrameAllocator {
     frames: PhysFrameRange,
     regions: MemoryMap,
+    /// Complete memory map, to check added regions against
+    memory_map: MemoryMap,
+    added: [Option<PhysFrameRange>; MAX_ADDED],
+    /// Number of added regions that were switched to
+    added_used: usize,
+    /// Frames left in regions that were too small for a contiguous allocation
+    skipped: [Option<PhysFrameRange>; MAX_SKIPPED],
 }
 
 unsafe impl FrameAllocator<Size4KiB> for RegionFrameAllocator {
@@ -20,15 +35,32 @@ unsafe impl FrameAllocator<Size4KiB> for RegionFrameAllocator {
         // Switch to a new region if current one is out of frames
         self.frames.next().map_or_else(
             || {
-                // Only allocate if a new region exists; recursion should be
-                // limited as next_region skips regions without usable frames
-                self.next_region().and_then(|_| self.allocate_frame())
+                // Only allocate if a skipped or new region exists; recursion
+                // should be limited as neither is empty
+                self.next_skipped()
+                    .or_else(|| self.next_region())
+                    .and_then(|_| self.allocate_frame())
             },
             Some,
         )
     }
 }
 
+/// Only the most recently allocated frame of the current region can be given
+/// back, which is what rolling back a failed mapping during boot does. Other
+/// frames are leaked, as the allocator is handed to a
+/// [`UserFrameAllocator`](super::user_frame::UserFrameAllocator) before frames
+/// are freed otherwise.
+impl FrameDeallocator<Size4KiB> for RegionFrameAllocator {
+    unsafe fn deallocate_frame(&mut self, frame: PhysFrame) {
+        if frame + 1 == self.frames.start {
+            self.frames.start = frame;
+        } else {
+            log::warn!("Leaking {:?} deallocated during boot", frame);
+        }
+    }
+}
+
 fn region_to_frames<S>(region: &MemoryDescriptor) -> PhysFrameRange<S>
 where
     S: PageSize,
@@ -43,38 +75,232 @@ where
     )
 }
 
+/// Number of frames in the conventional regions of `regions`
+fn conventional_frames(regions: MemoryMap) -> u64 {
+    regions
+        .filter(|region| region.ty == MemoryType::CONVENTIONAL)
+        .map(|region| {
+            let frames = region_to_frames::<Size4KiB>(region);
+            frames.end - frames.start
+        })
+        .sum()
+}
+
 impl RegionFrameAllocator {
     pub fn new(memory_map: MemoryMap) -> Self {
         // This is just a dummy value
         let frame_zero = PhysFrame::containing_address(PhysAddr::new(0));
         let mut allocator = Self {
             frames: PhysFrame::range(frame_zero, frame_zero),
-            regions: memory_map,
+            regions: memory_map.clone(),
+            memory_map,
+            added: [None; MAX_ADDED],
+            added_used: 0,
+            skipped: [None; MAX_SKIPPED],
         };
         // Replace dummy value with the actual first usable frame
         allocator.next_region();
         allocator
     }
 
+    /// Allocate `count` physically contiguous frames
+    ///
+    /// Frames at the end of a region that are skipped because the region is
+    /// too small are used for later allocations of single frames. If no
+    /// region is large enough, nothing is skipped.
+    pub fn allocate_contiguous(&mut self, count: u64) -> Option<PhysFrameRange> {
+        let fits = |frames: PhysFrameRange| frames.end - frames.start >= count;
+        let available = fits(self.frames)
+            || self
+                .regions
+                .clone()
+                .filter(|region| region.ty == MemoryType::CONVENTIONAL)
+                .any(|region| fits(region_to_frames(region)))
+            || self.added[self.added_used..]
+                .iter()
+                .flatten()
+                .any(|&f| fits(f));
+        if !available {
+            return None;
+        }
+        while !fits(self.frames) {
+            self.skip();
+            self.next_region()?;
+        }
+        let start = self.frames.start;
+        self.frames.start += count;
+        Some(PhysFrame::range(start, start + count))
+    }
+
+    /// Make the frames in `frames` available for allocation after those in the
+    /// memory map
+    ///
+    /// Intended for memory that becomes usable after boot, such as reclaimed
+    /// ACPI memory or hot-added memory. Frames that may be in use are refused:
+    /// the region may only overlap memory map regions of type
+    /// [`MemoryType::ACPI_RECLAIM`], and not other added regions.
+    pub fn add_region(&mut self, frames: PhysFrameRange) -> Result<(), &'static str> {
+        if frames.is_empty() {
+            return Err("Empty region");
+        }
+        let overlaps = |other: PhysFrameRange| other.start < frames.end && frames.start < other.end;
+        let in_use = self.memory_map.clone().any(|region| {
+            region.ty != MemoryType::ACPI_RECLAIM && overlaps(region_to_frames(region))
+        });
+        if in_use {
+            return Err("Region overlaps memory that may be in use");
+        }
+        if self.added.iter().flatten().any(|&other| overlaps(other)) {
+            return Err("Region overlaps added region");
+        }
+        let slot = self
+            .added
+            .iter_mut()
+            .find(|slot| slot.is_none())
+            .ok_or("Too many added regions")?;
+        *slot = Some(frames);
+        log::info!("Added region {:?}..{:?}", frames.start, frames.end);
+        Ok(())
+    }
+
+    /// Number of frames in conventional and added regions
+    pub fn total_frames(&self) -> u64 {
+        let conventional = conventional_frames(self.memory_map.clone());
+        let added: u64 = self.added.iter().flatten().map(|f| f.end - f.start).sum();
+        conventional + added
+    }
+
+    /// Number of frames that have not been allocated yet
+    pub fn free_frames(&self) -> u64 {
+        let added: u64 = self.added[self.added_used..]
+            .iter()
+            .flatten()
+            .map(|f| f.end - f.start)
+            .sum();
+        let skipped: u64 = self.skipped.iter().flatten().map(|f| f.end - f.start).sum();
+        (self.frames.end - self.frames.start)
+            + conventional_frames(self.regions.clone())
+            + added
+            + skipped
+    }
+
+    /// Keep the frames left in the current region for later allocations
+    fn skip(&mut self) {
+        if self.frames.is_empty() {
+            return;
+        }
+        match self.skipped.iter_mut().find(|slot| slot.is_none()) {
+            Some(slot) => *slot = Some(self.frames),
+            None => log::warn!(
+                "Leaking skipped frames {:?}..{:?}",
+                self.frames.start,
+                self.frames.end
+            ),
+        }
+    }
+
+    /// Switch to frames skipped earlier, if any
+    fn next_skipped(&mut self) -> Option<PhysFrameRange> {
+        let frames = self.skipped.iter_mut().find_map(Option::take)?;
+        self.frames = frames;
+        Some(frames)
+    }
+
     /// Find next usable region containing at least one frame
     ///
-    /// Should only be called if all frames in the current region are exhausted.
+    /// Should only be called if all frames in the current region are exhausted
+    /// or skipped.
     /// Also updates list of frames with those in the newly found current region.
-    fn next_region(&mut self) -> Option<MemoryDescriptor> {
-        self.regions
+    fn next_region(&mut self) -> Option<PhysFrameRange> {
+        let region = self
+            .regions
             .by_ref()
             .find(|region| {
                 region.ty == MemoryType::CONVENTIONAL
                     && !region_to_frames::<Size4KiB>(region).is_empty()
             })
-            .map(|region| {
-                self.frames = region_to_frames(region);
-                log::trace!(
-                    "New region for allocations {:?}..{:?}",
-                    self.frames.start,
-                    self.frames.end
-                );
-                *region
-            })
+            .map(region_to_frames::<Size4KiB>);
+        let frames = match region {
+            Some(frames) => frames,
+            None => {
+                let frames = (*self.added.get(self.added_used)?)?;
+                self.added_used += 1;
+                frames
+            }
+        };
+        self.frames = frames;
+        log::trace!(
+            "New region for allocations {:?}..{:?}",
+            self.frames.start,
+            self.frames.end
+        );
+        Some(frames)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use alloc::vec::Vec;
+
+    #[test_case]
+    fn add_region() {
+        let region = |ty, phys_start, page_count| {
+            let mut region = MemoryDescriptor::default();
+            region.ty = ty;
+            region.phys_start = phys_start;
+            region.page_count = page_count;
+            region
+        };
+        let regions = Vec::leak(alloc::vec![
+            region(MemoryType::CONVENTIONAL, 0x10000, 1),
+            region(MemoryType::ACPI_RECLAIM, 0x20000, 2),
+            region(MemoryType::LOADER_DATA, 0x30000, 1),
+        ]);
+        let size = core::mem::size_of::<MemoryDescriptor>();
+        let memory_map = unsafe { MemoryMap::new(regions.as_ptr().cast(), size, regions.len()) };
+        let mut allocator = RegionFrameAllocator::new(memory_map);
+        let frames = |start: u64, count| {
+            let start = PhysFrame::containing_address(PhysAddr::new(start));
+            PhysFrame::range(start, start + count)
+        };
+        assert!(allocator.add_region(frames(0x10000, 1)).is_err());
+        assert!(allocator.add_region(frames(0x2f000, 2)).is_err());
+        allocator.add_region(frames(0x20000, 2)).unwrap();
+        assert!(allocator.add_region(frames(0x21000, 1)).is_err());
+        allocator.add_region(frames(0x100000, 1)).unwrap();
+        assert_eq!(allocator.total_frames(), 4);
+        assert_eq!(allocator.free_frames(), 4);
+        let allocated: Vec<_> = core::iter::from_fn(|| allocator.allocate_frame())
+            .map(|frame| frame.start_address().as_u64())
+            .collect();
+        assert_eq!(allocated, [0x10000, 0x20000, 0x21000, 0x100000]);
+        assert_eq!(allocator.free_frames(), 0);
+    }
+
+    /// Frames skipped by a contiguous allocation are allocated later, and a
+    /// failed one skips nothing
+    #[test_case]
+    fn allocate_contiguous() {
+        let region = |phys_start, page_count| {
+            let mut region = MemoryDescriptor::default();
+            region.ty = MemoryType::CONVENTIONAL;
+            region.phys_start = phys_start;
+            region.page_count = page_count;
+            region
+        };
+        let regions = Vec::leak(alloc::vec![region(0x10000, 2), region(0x20000, 4)]);
+        let size = core::mem::size_of::<MemoryDescriptor>();
+        let memory_map = unsafe { MemoryMap::new(regions.as_ptr().cast(), size, regions.len()) };
+        let mut allocator = RegionFrameAllocator::new(memory_map);
+        assert!(allocator.allocate_contiguous(5).is_none());
+        assert_eq!(allocator.free_frames(), 6);
+        let frames = allocator.allocate_contiguous(3).unwrap();
+        assert_eq!(frames.start.start_address().as_u64(), 0x20000);
+        assert_eq!(allocator.free_frames(), 3);
+        let allocated: Vec<_> = core::iter::from_fn(|| allocator.allocate_frame())
+            .map(|frame| frame.start_address().as_u64())
+            .collect();
+        assert_eq!(allocated, [0x23000, 0x10000, 0x11000]);
     }
 }
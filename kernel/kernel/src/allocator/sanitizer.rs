@@ -0,0 +1,371 @@
+//! Heap sanitizer, a lightweight address sanitizer for the heap
+//!
+//! When enabled with the `sanitize-heap` option of the build configuration,
+//! every block is surrounded by redzones filled with a known pattern, and its
+//! size and state are kept in a header right before it. Freed blocks are
+//! filled with another pattern and held in a quarantine before they are really
+//! freed, so they are not reused right away. Deallocation checks the header
+//! and redzones of the block, and the pattern of blocks leaving the quarantine,
+//! panicking on heap buffer overflows, double frees and writes after free.
+//!
+//! The header also holds the return addresses of the code that allocated and
+//! freed the block, found by following frame pointers like the backtraces of
+//! [`crate::crash_dump`]. Panics report them, so the misuse can be attributed
+//! to its source with `addr2line` on the kernel executable.
+
+use crate::crash_dump;
+use core::{
+    alloc::{GlobalAlloc, Layout},
+    fmt,
+    mem::size_of,
+    ptr, slice,
+};
+use spin::Mutex;
+
+/// Minimum size of the redzones before and after a block
+const REDZONE: usize = 32;
+/// Number of return addresses recorded on allocation and deallocation
+const CALLERS: usize = 8;
+/// Size of the header right before a block, following the redzone
+const HEADER: usize = size_of::<Header>();
+/// Minimum alignment of blocks, so their header is aligned
+const HEADER_ALIGN: usize = 16;
+/// Number of freed blocks held back
+const QUARANTINE: usize = 256;
+
+/// Patterns of redzones and freed blocks
+const REDZONE_BYTE: u8 = 0xfa;
+const FREED_BYTE: u8 = 0xfd;
+/// States of a block in its header
+const LIVE: u64 = 0x4c49_5645_4c49_5645;
+const FREED: u64 = 0x4652_4545_4652_4545;
+
+/// Header of a block
+#[repr(C)]
+#[derive(Copy, Clone)]
+struct Header {
+    size: u64,
+    state: u64,
+    /// Return addresses of the allocation, innermost first
+    allocated_at: [u64; CALLERS],
+    /// Return addresses of the deallocation, if the block was freed
+    freed_at: [u64; CALLERS],
+}
+
+/// Return addresses of the code calling the allocator, innermost first and
+/// padded with zeros
+#[inline(never)]
+fn callers() -> [u64; CALLERS] {
+    let (rsp, rbp): (u64, u64);
+    unsafe { asm!("mov {}, rsp; mov {}, rbp", out(reg) rsp, out(reg) rbp) };
+    let (addrs, len) = crash_dump::backtrace(rsp, rbp);
+    // The first return address is in the sanitizer itself
+    let addrs = &addrs[len.min(1)..len];
+    let mut callers = [0; CALLERS];
+    let len = addrs.len().min(CALLERS);
+    callers[..len].copy_from_slice(&addrs[..len]);
+    callers
+}
+
+/// Return addresses formatted for a report, if known
+struct Callers(Option<[u64; CALLERS]>);
+
+impl fmt::Display for Callers {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let addrs = match &self.0 {
+            Some(addrs) => addrs,
+            None => return write!(f, "unknown"),
+        };
+        for (i, addr) in addrs.iter().take_while(|&&addr| addr != 0).enumerate() {
+            if i > 0 {
+                write!(f, " ")?;
+            }
+            write!(f, "{:#x}", addr)?;
+        }
+        Ok(())
+    }
+}
+
+/// Block allocated from the inner allocator for a requested layout, with the
+/// offset of the requested block in it
+fn outer(layout: Layout) -> Option<(Layout, usize)> {
+    let align = layout.align().max(HEADER_ALIGN);
+    // Rounded up to the alignment, which is a power of two
+    let front = (REDZONE + HEADER + align - 1) & !(align - 1);
+    let size = front.checked_add(layout.size())?.checked_add(REDZONE)?;
+    Some((Layout::from_size_align(size, align).ok()?, front))
+}
+
+/// Header of the block at `ptr`
+unsafe fn header(ptr: *mut u8) -> *mut Header {
+    ptr.sub(HEADER).cast()
+}
+
+/// Panic with `error` about the block at `ptr` allocated for `layout`,
+/// reporting where it was allocated and freed if its header is intact
+///
+/// # Safety
+/// The header of the block should be readable.
+unsafe fn misuse(error: &str, ptr: *mut u8, layout: Layout) -> ! {
+    let header = header(ptr).read();
+    let allocated = header.state == LIVE || header.state == FREED;
+    let freed = header.state == FREED;
+    panic!(
+        "{} of {:?} at {:p}\nAllocated at {}\nFreed at {}\nDetected at {}",
+        error,
+        layout,
+        ptr,
+        Callers(Some(header.allocated_at).filter(|_| allocated)),
+        Callers(Some(header.freed_at).filter(|_| freed)),
+        Callers(Some(callers())),
+    );
+}
+
+/// Check the header and redzones of the block at `ptr` allocated for `layout`
+///
+/// # Safety
+/// `ptr` should have been returned by [`Sanitizer::alloc`].
+unsafe fn check(ptr: *mut u8, layout: Layout) -> Result<(), &'static str> {
+    let (outer, front) = outer(layout).ok_or("Invalid layout")?;
+    let Header { size, state, .. } = header(ptr).read();
+    match state {
+        LIVE => {}
+        FREED => return Err("Double free"),
+        _ => return Err("Free of unknown block"),
+    }
+    if size != layout.size() as u64 {
+        return Err("Free with wrong layout");
+    }
+    let base = ptr.sub(front);
+    let before = slice::from_raw_parts(base, front - HEADER);
+    let after = slice::from_raw_parts(ptr.add(layout.size()), outer.size() - front - layout.size());
+    if !before.iter().chain(after).all(|&byte| byte == REDZONE_BYTE) {
+        return Err("Heap buffer overflow");
+    }
+    Ok(())
+}
+
+/// Check that the quarantined block at `ptr` allocated for `layout` was not
+/// written to since it was freed
+///
+/// # Safety
+/// `ptr` should have been quarantined by [`Sanitizer::dealloc`].
+unsafe fn check_freed(ptr: *mut u8, layout: Layout) -> Result<(), &'static str> {
+    let state = header(ptr).read().state;
+    let bytes = slice::from_raw_parts(ptr, layout.size());
+    if state != FREED || !bytes.iter().all(|&byte| byte == FREED_BYTE) {
+        return Err("Heap use after free");
+    }
+    Ok(())
+}
+
+/// Freed blocks that are not reused yet, oldest first
+struct Quarantine {
+    blocks: [Option<(usize, Layout)>; QUARANTINE],
+    /// Slot of the oldest block
+    next: usize,
+}
+
+impl Quarantine {
+    const fn new() -> Self {
+        Self {
+            blocks: [None; QUARANTINE],
+            next: 0,
+        }
+    }
+
+    /// Add the block at `ptr`, returning the oldest block if it is full
+    fn push(&mut self, ptr: *mut u8, layout: Layout) -> Option<(*mut u8, Layout)> {
+        let slot = &mut self.blocks[self.next];
+        let evicted = slot.replace((ptr as usize, layout));
+        self.next = (self.next + 1) % QUARANTINE;
+        evicted.map(|(ptr, layout)| (ptr as *mut u8, layout))
+    }
+
+    /// Remove the oldest block, if any
+    fn pop(&mut self) -> Option<(*mut u8, Layout)> {
+        for _ in 0..QUARANTINE {
+            let slot = &mut self.blocks[self.next];
+            self.next = (self.next + 1) % QUARANTINE;
+            if let Some((ptr, layout)) = slot.take() {
+                return Some((ptr as *mut u8, layout));
+            }
+        }
+        None
+    }
+}
+
+/// Allocator wrapper sanitizing the heap of allocator `A` if enabled
+pub struct Sanitizer<A> {
+    inner: A,
+    enabled: bool,
+    quarantine: Mutex<Quarantine>,
+}
+
+impl<A> Sanitizer<A> {
+    pub const fn new(inner: A, enabled: bool) -> Self {
+        Self {
+            inner,
+            enabled,
+            quarantine: Mutex::new(Quarantine::new()),
+        }
+    }
+
+    pub fn inner(&self) -> &A {
+        &self.inner
+    }
+}
+
+impl<A: GlobalAlloc> Sanitizer<A> {
+    /// Really free the quarantined block at `ptr`, panicking if it was written
+    /// to since it was freed
+    unsafe fn release(&self, ptr: *mut u8, layout: Layout) {
+        if let Err(e) = check_freed(ptr, layout) {
+            misuse(e, ptr, layout);
+        }
+        let (outer, front) = outer(layout).unwrap();
+        self.inner.dealloc(ptr.sub(front), outer);
+    }
+
+    /// Really free all quarantined blocks, to make room for an allocation
+    unsafe fn flush(&self) -> bool {
+        let mut flushed = false;
+        loop {
+            let block = self.quarantine.lock().pop();
+            match block {
+                Some((ptr, layout)) => self.release(ptr, layout),
+                None => return flushed,
+            }
+            flushed = true;
+        }
+    }
+}
+
+unsafe impl<A: GlobalAlloc> GlobalAlloc for Sanitizer<A> {
+    unsafe fn alloc(&self, layout: Layout) -> *mut u8 {
+        if !self.enabled {
+            return self.inner.alloc(layout);
+        }
+        let (outer, front) = match outer(layout) {
+            Some(outer) => outer,
+            None => return ptr::null_mut(),
+        };
+        let mut base = self.inner.alloc(outer);
+        if base.is_null() && self.flush() {
+            base = self.inner.alloc(outer);
+        }
+        if base.is_null() {
+            return base;
+        }
+        base.write_bytes(REDZONE_BYTE, outer.size());
+        let ptr = base.add(front);
+        header(ptr).write(Header {
+            size: layout.size() as u64,
+            state: LIVE,
+            allocated_at: callers(),
+            freed_at: [0; CALLERS],
+        });
+        ptr
+    }
+
+    unsafe fn dealloc(&self, ptr: *mut u8, layout: Layout) {
+        if !self.enabled {
+            return self.inner.dealloc(ptr, layout);
+        }
+        if let Err(e) = check(ptr, layout) {
+            misuse(e, ptr, layout);
+        }
+        ptr.write_bytes(FREED_BYTE, layout.size());
+        let header = header(ptr);
+        (*header).state = FREED;
+        (*header).freed_at = callers();
+        // Released outside of the lock, as it may panic
+        let evicted = self.quarantine.lock().push(ptr, layout);
+        if let Some((ptr, layout)) = evicted {
+            self.release(ptr, layout);
+        }
+    }
+
+    unsafe fn realloc(&self, ptr: *mut u8, layout: Layout, new_size: usize) -> *mut u8 {
+        if !self.enabled {
+            return self.inner.realloc(ptr, layout, new_size);
+        }
+        // Always move the block, so stale pointers to it are caught
+        let new_layout = Layout::from_size_align_unchecked(new_size, layout.align());
+        let new_ptr = self.alloc(new_layout);
+        if !new_ptr.is_null() {
+            ptr::copy_nonoverlapping(ptr, new_ptr, layout.size().min(new_size));
+            self.dealloc(ptr, layout);
+        }
+        new_ptr
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::allocator::{LinkedListAllocator, TestHeap};
+
+    const HEAP_SIZE: usize = 0x4000;
+
+    #[test_case]
+    fn redzones() {
+        static mut BUFFER: TestHeap<HEAP_SIZE> = TestHeap::new();
+        let sanitizer = Sanitizer::new(LinkedListAllocator::new(), true);
+        unsafe {
+            let start = BUFFER.start();
+            sanitizer.inner().init(start, HEAP_SIZE as u64);
+
+            let layout = Layout::from_size_align(24, 8).unwrap();
+            let ptr = sanitizer.alloc(layout);
+            assert!(!ptr.is_null());
+            assert_eq!(ptr as usize % 8, 0);
+            assert!(check(ptr, layout).is_ok());
+            // Writing just past the block is caught
+            ptr.add(24).write(0);
+            assert_eq!(check(ptr, layout), Err("Heap buffer overflow"));
+            ptr.add(24).write(REDZONE_BYTE);
+            assert!(check(ptr, Layout::from_size_align(16, 8).unwrap()).is_err());
+
+            // Freed blocks are quarantined rather than reused
+            sanitizer.dealloc(ptr, layout);
+            assert_eq!(check(ptr, layout), Err("Double free"));
+            assert!(check_freed(ptr, layout).is_ok());
+            let other = sanitizer.alloc(layout);
+            assert_ne!(other, ptr);
+            ptr.write(0);
+            assert!(check_freed(ptr, layout).is_err());
+            ptr.write(FREED_BYTE);
+
+            // Reallocation moves the contents
+            other.write_bytes(7, 24);
+            let moved = sanitizer.realloc(other, layout, 100);
+            assert_ne!(moved, other);
+            assert!(slice::from_raw_parts(moved, 24).iter().all(|&b| b == 7));
+            sanitizer.dealloc(moved, Layout::from_size_align(100, 8).unwrap());
+            assert!(sanitizer.flush());
+        }
+    }
+
+    #[test_case]
+    fn records_callers() {
+        static mut BUFFER: TestHeap<HEAP_SIZE> = TestHeap::new();
+        let sanitizer = Sanitizer::new(LinkedListAllocator::new(), true);
+        unsafe {
+            let start = BUFFER.start();
+            sanitizer.inner().init(start, HEAP_SIZE as u64);
+
+            let layout = Layout::from_size_align(8, 64).unwrap();
+            let ptr = sanitizer.alloc(layout);
+            assert_eq!(ptr as usize % 64, 0);
+            let allocated_at = header(ptr).read().allocated_at;
+            assert_ne!(allocated_at[0], 0);
+            assert_eq!(header(ptr).read().freed_at, [0; CALLERS]);
+            sanitizer.dealloc(ptr, layout);
+            let freed = header(ptr).read();
+            assert_eq!(freed.allocated_at, allocated_at);
+            assert_ne!(freed.freed_at[0], 0);
+            assert!(sanitizer.flush());
+        }
+    }
+}
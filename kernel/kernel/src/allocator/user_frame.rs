@@ -1,6 +1,9 @@
+use super::RegionFrameAllocator;
+use crate::inject::{self, Site};
 use alloc::vec::Vec;
 use x86_64::structures::paging::{
-    frame::PhysFrameRangeInclusive, FrameAllocator, FrameDeallocator, PhysFrame, Size4KiB,
+    frame::{PhysFrameRange, PhysFrameRangeInclusive},
+    FrameAllocator, FrameDeallocator, PhysFrame, Size4KiB,
 };
 
 /// Frame allocator storing its own allocations for later deallocation
@@ -46,8 +49,36 @@ impl<A> UserFrameAllocator<A> {
     }
 }
 
+impl UserFrameAllocator<RegionFrameAllocator> {
+    /// Allocate `count` physically contiguous frames from the backing allocator
+    pub fn allocate_contiguous(&mut self, count: u64) -> Option<PhysFrameRange> {
+        self.backing.allocate_contiguous(count)
+    }
+
+    /// Number of frames available to the allocator, see
+    /// [`RegionFrameAllocator::total_frames`]
+    pub fn total_frames(&self) -> u64 {
+        self.backing.total_frames()
+    }
+
+    /// Number of frames that are not allocated, including freed ones
+    pub fn free_frames(&self) -> u64 {
+        let freed: u64 = self.free.iter().map(|f| f.end - f.start + 1).sum();
+        freed + self.backing.free_frames()
+    }
+
+    /// Make the frames in `frames` available, see
+    /// [`RegionFrameAllocator::add_region`]
+    pub fn add_region(&mut self, frames: PhysFrameRange) -> Result<(), &'static str> {
+        self.backing.add_region(frames)
+    }
+}
+
 unsafe impl<A: FrameAllocator<Size4KiB>> FrameAllocator<Size4KiB> for UserFrameAllocator<A> {
     fn allocate_frame(&mut self) -> Option<PhysFrame<Size4KiB>> {
+        if inject::fail(Site::Frame) {
+            return None;
+        }
         self.pop().or_else(|| self.backing.allocate_frame())
     }
 }
@@ -57,3 +88,73 @@ impl<A> FrameDeallocator<Size4KiB> for UserFrameAllocator<A> {
         self.push(frame)
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test::Rng;
+    use alloc::collections::BTreeSet;
+    use common::boot::MemoryMap;
+    use core::{iter, mem};
+    use uefi::table::boot::{MemoryDescriptor, MemoryType};
+
+    /// Random allocations and deallocations never hand out a frame that is in
+    /// use or not conventional memory, and every frame can be allocated again
+    /// once all are freed
+    #[test_case]
+    fn fuzz() {
+        let region = |ty, phys_start, page_count| {
+            let mut region = MemoryDescriptor::default();
+            region.ty = ty;
+            region.phys_start = phys_start;
+            region.page_count = page_count;
+            region
+        };
+        let regions: &'static [_] = Vec::leak(alloc::vec![
+            region(MemoryType::CONVENTIONAL, 0x100000, 16),
+            region(MemoryType::LOADER_DATA, 0x110000, 4),
+            region(MemoryType::CONVENTIONAL, 0x200000, 4),
+            region(MemoryType::CONVENTIONAL, 0x300000, 8),
+        ]);
+        let usable = |frame: PhysFrame| {
+            let addr = frame.start_address().as_u64();
+            regions.iter().any(|region| {
+                region.ty == MemoryType::CONVENTIONAL
+                    && addr >= region.phys_start
+                    && addr < region.phys_start + 4096 * region.page_count
+            })
+        };
+        let frames = 16 + 4 + 8;
+
+        for seed in 0..4 {
+            let size = mem::size_of::<MemoryDescriptor>();
+            let memory_map =
+                unsafe { MemoryMap::new(regions.as_ptr().cast(), size, regions.len()) };
+            let mut allocator = UserFrameAllocator::new(RegionFrameAllocator::new(memory_map));
+            let mut rng = Rng::new(seed);
+            let mut live = Vec::new();
+            for _ in 0..1000 {
+                if live.is_empty() || rng.below(2) == 0 {
+                    match allocator.allocate_frame() {
+                        Some(frame) => {
+                            assert!(usable(frame));
+                            assert!(!live.contains(&frame));
+                            live.push(frame);
+                        }
+                        // Only exhausted once every frame is in use
+                        None => assert_eq!(live.len(), frames),
+                    }
+                } else {
+                    let frame = live.swap_remove(rng.below(live.len() as u64) as usize);
+                    unsafe { allocator.deallocate_frame(frame) };
+                }
+            }
+            for frame in live.drain(..) {
+                unsafe { allocator.deallocate_frame(frame) };
+            }
+            let all: Vec<_> = iter::from_fn(|| allocator.allocate_frame()).collect();
+            assert_eq!(all.len(), frames);
+            assert_eq!(all.iter().collect::<BTreeSet<_>>().len(), frames);
+        }
+    }
+}
@@ -0,0 +1,118 @@
+//! Audit log of security-relevant operations
+//!
+//! Spawns, handle grants, signals, executable mappings and the use of devices
+//! through handles are recorded as an [`AuditRecord`] in a ring buffer,
+//! whether they were allowed or denied, overwriting the oldest record when
+//! full. Unlike [`trace`](crate::trace) the log is always on, as it is small
+//! and only written on operations a process is not expected to repeat often.
+//! Processes holding a handle to the audit log can drain it.
+
+use crate::trace;
+use core::mem::{self, MaybeUninit};
+use spin::Mutex;
+use sys::{AuditKind, AuditRecord};
+
+/// Number of records kept
+const CAPACITY: usize = 256;
+
+static LOG: Mutex<Ring> = Mutex::new(Ring::new());
+
+/// Ring buffer of records
+struct Ring {
+    records: [Option<AuditRecord>; CAPACITY],
+    start: usize,
+    len: usize,
+    /// Number of records overwritten since the last drain
+    dropped: u64,
+}
+
+impl Ring {
+    const fn new() -> Self {
+        Self {
+            records: [None; CAPACITY],
+            start: 0,
+            len: 0,
+            dropped: 0,
+        }
+    }
+
+    fn push(&mut self, record: AuditRecord) {
+        if self.len == CAPACITY {
+            self.records[self.start] = Some(record);
+            self.start = (self.start + 1) % CAPACITY;
+            self.dropped += 1;
+            return;
+        }
+        self.records[(self.start + self.len) % CAPACITY] = Some(record);
+        self.len += 1;
+    }
+
+    fn pop(&mut self) -> Option<AuditRecord> {
+        if self.len == 0 {
+            return None;
+        }
+        let i = self.start;
+        self.start = (i + 1) % CAPACITY;
+        self.len -= 1;
+        self.records[i].take()
+    }
+}
+
+/// Record an operation of process `pid`, or the kernel if zero
+pub fn record(kind: AuditKind, pid: u64, arg: u64, allowed: bool) {
+    LOG.lock().push(AuditRecord {
+        timestamp: trace::timestamp(),
+        kind,
+        allowed,
+        pid,
+        arg,
+    });
+}
+
+/// Move records into `buf`, oldest first, returning the number moved
+pub fn drain(buf: &mut [MaybeUninit<AuditRecord>]) -> usize {
+    let mut log = LOG.lock();
+    let dropped = mem::take(&mut log.dropped);
+    if dropped > 0 {
+        log::warn!("Dropped {} audit records as the log was full", dropped);
+    }
+    let mut len = 0;
+    while len < buf.len() {
+        match log.pop() {
+            Some(record) => {
+                buf[len].write(record);
+            }
+            None => break,
+        }
+        len += 1;
+    }
+    len
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn record(arg: u64) -> AuditRecord {
+        AuditRecord {
+            timestamp: arg,
+            kind: AuditKind::Spawn,
+            allowed: true,
+            pid: 1,
+            arg,
+        }
+    }
+
+    #[test_case]
+    fn ring_overwrites_oldest() {
+        // Too large for the stack
+        static RING: Mutex<Ring> = Mutex::new(Ring::new());
+        let mut ring = RING.lock();
+        for i in 0..CAPACITY as u64 + 2 {
+            ring.push(record(i));
+        }
+        assert_eq!(ring.dropped, 2);
+        assert_eq!(ring.pop(), Some(record(2)));
+        assert_eq!(ring.len, CAPACITY - 1);
+    }
+}
@@ -0,0 +1,100 @@
+//! Base64 encoding of binary data printed to the serial port
+//!
+//! Binary data such as screenshots and traces is printed between marker lines,
+//! which the xtask runner extracts from the serial output.
+
+use common::println;
+use core::{fmt, str};
+
+/// Number of base64 characters per line
+const LINE_LENGTH: usize = 76;
+
+const ALPHABET: &[u8; 64] = b"ABCDEFGHIJKLMNOPQRSTUVWXYZabcdefghijklmnopqrstuvwxyz0123456789+/";
+
+/// Encode up to three bytes as four base64 characters, including padding
+fn encode_block(block: &[u8]) -> [u8; 4] {
+    let mut bytes = [0; 3];
+    bytes[..block.len()].copy_from_slice(block);
+    let n = (bytes[0] as u32) << 16 | (bytes[1] as u32) << 8 | bytes[2] as u32;
+    let mut out = [b'='; 4];
+    for (i, c) in out.iter_mut().enumerate().take(block.len() + 1) {
+        *c = ALPHABET[(n >> (18 - 6 * i)) as usize & 0x3f];
+    }
+    out
+}
+
+/// Base64 encoder printing lines of fixed length to the serial port
+pub struct Encoder {
+    block: [u8; 3],
+    block_len: usize,
+    line: [u8; LINE_LENGTH],
+    line_len: usize,
+}
+
+impl Encoder {
+    pub fn new() -> Self {
+        Self {
+            block: [0; 3],
+            block_len: 0,
+            line: [0; LINE_LENGTH],
+            line_len: 0,
+        }
+    }
+
+    pub fn push(&mut self, byte: u8) {
+        self.block[self.block_len] = byte;
+        self.block_len += 1;
+        if self.block_len == 3 {
+            self.encode();
+        }
+    }
+
+    pub fn extend(&mut self, bytes: &[u8]) {
+        bytes.iter().for_each(|&byte| self.push(byte));
+    }
+
+    fn encode(&mut self) {
+        let chars = encode_block(&self.block[..self.block_len]);
+        self.line[self.line_len..self.line_len + 4].copy_from_slice(&chars);
+        self.line_len += 4;
+        self.block_len = 0;
+        if self.line_len == LINE_LENGTH {
+            self.print_line();
+        }
+    }
+
+    fn print_line(&mut self) {
+        // Base64 characters are always valid UTF-8
+        println!("{}", str::from_utf8(&self.line[..self.line_len]).unwrap());
+        self.line_len = 0;
+    }
+
+    /// Encode remaining bytes with padding and print the last line
+    pub fn finish(mut self) {
+        if self.block_len > 0 {
+            self.encode();
+        }
+        if self.line_len > 0 {
+            self.print_line();
+        }
+    }
+}
+
+impl fmt::Write for Encoder {
+    fn write_str(&mut self, s: &str) -> fmt::Result {
+        self.extend(s.as_bytes());
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::encode_block;
+
+    #[test_case]
+    fn base64_blocks() {
+        assert_eq!(&encode_block(b"Man"), b"TWFu");
+        assert_eq!(&encode_block(b"Ma"), b"TWE=");
+        assert_eq!(&encode_block(b"M"), b"TQ==");
+    }
+}
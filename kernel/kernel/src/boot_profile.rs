@@ -0,0 +1,152 @@
+//! Breakdown of the time spent booting
+//!
+//! The bootloader records the time stamp counter when it is entered, when it
+//! exits boot services and when it jumps to the kernel in [`BootTimes`]. The
+//! kernel adds a stamp with [`mark`] as every stage of its initialization
+//! finishes, and [`report`] logs how long each phase took at the end of it. As
+//! the first process is entered after initialization, [`user_entry`] logs that
+//! separately. All times count from the entry of the bootloader, or the kernel
+//! if the bootloader did not record it.
+
+use crate::{time, trace};
+use common::boot::BootTimes;
+use core::sync::atomic::{AtomicBool, AtomicU64, Ordering};
+use spin::Mutex;
+
+/// Maximum number of stamps of the kernel; later stamps are dropped
+const CAPACITY: usize = 16;
+
+static MARKS: Mutex<Marks> = Mutex::new(Marks::new());
+/// Start of the boot, once [`report`] has determined it
+static START: AtomicU64 = AtomicU64::new(0);
+static USER_ENTERED: AtomicBool = AtomicBool::new(false);
+
+/// Time stamp at the end of a phase
+#[derive(Copy, Clone, Debug)]
+struct Mark {
+    phase: &'static str,
+    tsc: u64,
+}
+
+struct Marks {
+    marks: [Mark; CAPACITY],
+    len: usize,
+}
+
+impl Marks {
+    const fn new() -> Self {
+        const EMPTY: Mark = Mark { phase: "", tsc: 0 };
+        Self {
+            marks: [EMPTY; CAPACITY],
+            len: 0,
+        }
+    }
+
+    fn push(&mut self, mark: Mark) {
+        if let Some(slot) = self.marks.get_mut(self.len) {
+            *slot = mark;
+            self.len += 1;
+        }
+    }
+
+    fn as_slice(&self) -> &[Mark] {
+        &self.marks[..self.len]
+    }
+}
+
+/// Phases of the bootloader, followed by those of the kernel in `kernel`,
+/// leaving out phases the bootloader did not record
+fn phases(times: &BootTimes, kernel: &[Mark]) -> Marks {
+    let mut marks = Marks::new();
+    let loader = [
+        ("Bootloader", times.exit_boot_services),
+        ("Bootloader after exiting boot services", times.handoff),
+    ];
+    for &(phase, tsc) in &loader {
+        if tsc != 0 {
+            marks.push(Mark { phase, tsc });
+        }
+    }
+    for &mark in kernel {
+        marks.push(mark);
+    }
+    marks
+}
+
+/// Record that kernel initialization phase `phase` finished now
+pub fn mark(phase: &'static str) {
+    MARKS.lock().push(Mark {
+        phase,
+        tsc: trace::timestamp(),
+    });
+}
+
+/// Log the duration of every phase so far; the time stamp counter should be
+/// calibrated
+pub fn report(times: &BootTimes) {
+    let kernel = MARKS.lock();
+    let marks = phases(times, kernel.as_slice());
+    let start = match (times.loader_entry, marks.as_slice().first()) {
+        (0, Some(first)) => first.tsc,
+        (0, None) => return,
+        (start, _) => start,
+    };
+    START.store(start, Ordering::Relaxed);
+    log::info!("Boot time breakdown:");
+    let mut previous = start;
+    for mark in marks.as_slice() {
+        log::info!(
+            "{:>8} us  {}",
+            time::nanoseconds(mark.tsc.saturating_sub(previous)) / 1000,
+            mark.phase
+        );
+        previous = mark.tsc;
+    }
+    log::info!(
+        "{:>8} us  Total",
+        time::nanoseconds(previous.saturating_sub(start)) / 1000
+    );
+}
+
+/// Log the time until the first process is entered; only the first call logs
+pub fn user_entry() {
+    if USER_ENTERED.swap(true, Ordering::Relaxed) {
+        return;
+    }
+    let start = START.load(Ordering::Relaxed);
+    if start == 0 {
+        return;
+    }
+    log::info!(
+        "First process entered {} us after boot",
+        time::nanoseconds(trace::timestamp().saturating_sub(start)) / 1000
+    );
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test_case]
+    fn phases() {
+        let kernel = [Mark {
+            phase: "Kernel",
+            tsc: 30,
+        }];
+        let times = BootTimes {
+            loader_entry: 10,
+            exit_boot_services: 0,
+            handoff: 20,
+        };
+        let marks = super::phases(&times, &kernel);
+        let tscs = marks.as_slice().iter().map(|mark| mark.tsc);
+        assert!(tscs.eq([20, 30].iter().copied()));
+
+        // Stamps beyond the capacity are dropped
+        let mut marks = Marks::new();
+        for tsc in 0..CAPACITY as u64 + 1 {
+            marks.push(Mark { phase: "", tsc });
+        }
+        assert_eq!(marks.as_slice().len(), CAPACITY);
+    }
+}
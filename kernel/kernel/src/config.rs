@@ -0,0 +1,268 @@
+//! Kernel configuration
+//!
+//! Defaults are set at compile time by the `kernel` section of the build
+//! configuration. They can be overridden on the kernel command line, which the
+//! UEFI stub takes from its load options, as space-separated `key=value` pairs
+//! using the keys of the build configuration, e.g. `log-level=debug trace=true`.
+//! Values containing spaces are written with hyphens, e.g.
+//! `allocator=linked-list`. The log format, the key authenticating the remote
+//! log and whether the heap is sanitized can only be set at compile time.
+
+use crate::{
+    allocator,
+    net::{self, Endpoint},
+};
+use common::serial::{self, Console};
+use log::LevelFilter;
+use spin::Once;
+
+/// Compile-time defaults generated from the build configuration
+mod defaults {
+    include!(concat!(env!("XTASK_OUT_DIR"), "/cfg_kernel.rs"));
+}
+
+pub use defaults::{LOG_FORMAT, REMOTE_LOG_KEY, SANITIZE_HEAP};
+
+static CONFIG: Once<Config> = Once::new();
+
+/// Options that can be set at runtime
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub struct Config {
+    pub log_level: LevelFilter,
+    pub allocator: allocator::Kind,
+    pub trace: bool,
+    pub profile: bool,
+    pub latency: bool,
+    pub crash_dump: bool,
+    pub core_dump: bool,
+    pub hide_physmap: bool,
+    pub strace: bool,
+    pub console: Console,
+    pub log_vt: u64,
+    pub user_vt: u64,
+    pub deterministic: bool,
+    pub fail_frame: u64,
+    pub fail_heap: u64,
+    pub fail_syscall: u64,
+    pub remote_log: Option<Endpoint>,
+    pub remote_log_source: [u8; 4],
+    pub test_stress: bool,
+}
+
+impl Config {
+    const DEFAULT: Self = Self {
+        log_level: defaults::LOG_LEVEL,
+        allocator: defaults::ALLOCATOR,
+        trace: defaults::TRACE,
+        profile: defaults::PROFILE,
+        latency: defaults::LATENCY,
+        crash_dump: defaults::CRASH_DUMP,
+        core_dump: defaults::CORE_DUMP,
+        hide_physmap: defaults::HIDE_PHYSMAP,
+        strace: defaults::STRACE,
+        console: defaults::CONSOLE,
+        log_vt: defaults::LOG_VT,
+        user_vt: defaults::USER_VT,
+        deterministic: defaults::DETERMINISTIC,
+        fail_frame: defaults::FAIL_FRAME,
+        fail_heap: defaults::FAIL_HEAP,
+        fail_syscall: defaults::FAIL_SYSCALL,
+        remote_log: defaults::REMOTE_LOG,
+        remote_log_source: defaults::REMOTE_LOG_SOURCE,
+        test_stress: defaults::TEST_STRESS,
+    };
+
+    /// Override option `key` with `value`
+    fn set(&mut self, key: &str, value: &str) -> Result<(), &'static str> {
+        let flag = || value.parse::<bool>().map_err(|_| "Expected true or false");
+        let number = || value.parse::<u64>().map_err(|_| "Expected a number");
+        match key {
+            "log-level" => self.log_level = value.parse().map_err(|_| "Invalid log level")?,
+            "allocator" => self.allocator = value.parse()?,
+            "trace" => self.trace = flag()?,
+            "profile" => self.profile = flag()?,
+            "latency" => self.latency = flag()?,
+            "crash-dump" => self.crash_dump = flag()?,
+            "core-dump" => self.core_dump = flag()?,
+            "hide-physmap" => self.hide_physmap = flag()?,
+            "strace" => self.strace = flag()?,
+            "console" => self.console = value.parse()?,
+            "log-vt" => self.log_vt = number()?,
+            "user-vt" => self.user_vt = number()?,
+            "deterministic" => self.deterministic = flag()?,
+            "fail-frame" => self.fail_frame = number()?,
+            "fail-heap" => self.fail_heap = number()?,
+            "fail-syscall" => self.fail_syscall = number()?,
+            "remote-log" if value == "off" => self.remote_log = None,
+            "remote-log" => self.remote_log = Some(value.parse()?),
+            "remote-log-source" => self.remote_log_source = net::parse_addr(value)?,
+            "test-stress" => self.test_stress = flag()?,
+            _ => return Err("Unknown option"),
+        }
+        Ok(())
+    }
+
+    /// Defaults overridden by the options in `cmdline`, skipping invalid ones
+    fn parse(cmdline: &str) -> Self {
+        let mut config = Self::DEFAULT;
+        for option in cmdline.split_whitespace() {
+            let result = match option.split_once('=') {
+                Some((key, value)) => config.set(key, value),
+                None => Err("Expected key=value"),
+            };
+            if let Err(e) = result {
+                log::warn!("Ignoring kernel option {:?}: {}", option, e);
+            }
+        }
+        config
+    }
+}
+
+/// Apply the options in `cmdline`
+///
+/// Should be called once, after initializing the logger; the options are
+/// fixed from then on.
+pub fn init(cmdline: &str) {
+    let config = CONFIG.call_once(|| Config::parse(cmdline));
+    log::set_max_level(config.log_level);
+    if config.console != Console::Com1 {
+        serial::select(config.console);
+    }
+    if *config != Config::DEFAULT {
+        log::info!("Configuration: {:?}", config);
+    }
+}
+
+/// Current configuration, or the defaults before [`init`]
+fn get() -> &'static Config {
+    CONFIG.get().unwrap_or(&Config::DEFAULT)
+}
+
+/// Console the log is printed to
+pub fn console() -> Console {
+    get().console
+}
+
+/// Virtual terminal the log is shown on, see [`crate::console`]
+pub fn log_vt() -> u64 {
+    get().log_vt
+}
+
+/// Virtual terminal messages of user processes are shown on, see
+/// [`crate::console`]
+pub fn user_vt() -> u64 {
+    get().user_vt
+}
+
+/// Whether boot is made reproducible, see [`crate::initcall::run`] and
+/// [`crate::interrupts::start_apic_timer`]
+pub fn deterministic() -> bool {
+    get().deterministic
+}
+
+/// Interval of injected frame allocation failures, see [`crate::inject`]
+pub fn fail_frame() -> u64 {
+    get().fail_frame
+}
+
+/// Interval of injected heap allocation failures, see [`crate::inject`]
+pub fn fail_heap() -> u64 {
+    get().fail_heap
+}
+
+/// Interval of injected system call failures, see [`crate::inject`]
+pub fn fail_syscall() -> u64 {
+    get().fail_syscall
+}
+
+/// Address and port the log is sent to, see [`crate::remote_log`]
+pub fn remote_log() -> Option<Endpoint> {
+    get().remote_log
+}
+
+/// Address the log is sent from, see [`crate::remote_log`]
+pub fn remote_log_source() -> [u8; 4] {
+    get().remote_log_source
+}
+
+/// Whether kernel tests run under stress, see [`crate::test`]
+#[cfg(test)]
+pub fn test_stress() -> bool {
+    get().test_stress
+}
+
+/// Heap allocator to initialize the heap with
+pub fn allocator() -> allocator::Kind {
+    get().allocator
+}
+
+/// Whether tracing is enabled, see [`crate::trace`]
+pub fn trace() -> bool {
+    get().trace
+}
+
+/// Whether profiling is enabled, see [`crate::profile`]
+pub fn profile() -> bool {
+    get().profile
+}
+
+/// Whether latency measurement is enabled, see [`crate::latency`]
+pub fn latency() -> bool {
+    get().latency
+}
+
+/// Whether crash dumps are enabled, see [`crate::crash_dump`]
+pub fn crash_dump() -> bool {
+    get().crash_dump
+}
+
+/// Whether core files of faulting processes are written, see
+/// [`crate::core_dump`]
+pub fn core_dump() -> bool {
+    get().core_dump
+}
+
+/// Whether processes run on a page table without the offset mapping, see
+/// [`crate::physmap`]
+pub fn hide_physmap() -> bool {
+    get().hide_physmap
+}
+
+/// Whether system calls of processes are logged, see [`crate::ptrace`]
+pub fn strace() -> bool {
+    get().strace
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test_case]
+    fn parse_overrides() {
+        let config = Config::parse(
+            "log-level=warn allocator=buddy trace=true  profile=false console=debugcon \
+             fail-syscall=3 remote-log=10.0.2.2:5140",
+        );
+        assert_eq!(config.log_level, LevelFilter::Warn);
+        assert_eq!(config.allocator, allocator::Kind::Buddy);
+        assert!(config.trace);
+        assert!(!config.profile);
+        assert_eq!(config.console, Console::Debugcon);
+        assert_eq!(config.fail_syscall, 3);
+        assert_eq!(
+            config.remote_log,
+            Some(Endpoint {
+                addr: [10, 0, 2, 2],
+                port: 5140
+            })
+        );
+        assert_eq!(config.latency, Config::DEFAULT.latency);
+    }
+
+    #[test_case]
+    fn parse_skips_invalid() {
+        let config =
+            Config::parse("trace=maybe unknown=1 latency log-level=loud remote-log=10.0.2.2");
+        assert_eq!(config, Config::DEFAULT);
+    }
+}
@@ -0,0 +1,445 @@
+//! Text console on the frame buffer, with virtual terminals
+//!
+//! While no process has access to the frame buffer, the kernel draws text on
+//! it. There are [`COUNT`] virtual terminals, of which one is shown at a time;
+//! Alt+F1 to Alt+F4 switch between them. Each keeps its last [`SCROLLBACK`]
+//! lines, which can be paged through with Shift+Page Up and Shift+Page Down.
+//! Ctrl+C interrupts the foreground process group of the shown terminal, see
+//! [`jobs`]. Keys used by the console are not delivered to processes, and the
+//! console ignores all other keys while it is hidden.
+//!
+//! The kernel log and messages of user processes are written to the terminals
+//! set by the `log-vt` and `user-vt` options, numbered from 1; 0 leaves them
+//! off screen. The kernel log is shown initially.
+//!
+//! Text is drawn with a [`Font`] loaded from the shared directory if there is
+//! one, or the built-in ASCII font otherwise.
+
+mod font;
+
+use crate::{
+    config,
+    drivers::virtio::gpu,
+    framebuffer::{self, cursor},
+    jobs,
+};
+use alloc::{collections::VecDeque, string::String, vec::Vec};
+use common::boot::{offset, FramebufferInfo, PixelFormat};
+use core::{
+    fmt::{self, Write},
+    ptr,
+};
+use font::Font;
+use spin::Mutex;
+use x86_64::instructions::interrupts;
+
+/// Number of virtual terminals
+pub const COUNT: usize = 4;
+/// Number of lines kept per virtual terminal
+const SCROLLBACK: usize = 1000;
+/// Number of columns a tab advances to a multiple of
+const TAB_WIDTH: usize = 8;
+
+/// Colors of the text, which are the same for RGB and BGR pixels
+const FOREGROUND: u32 = 0xaaaaaa;
+const BACKGROUND: u32 = 0x000000;
+
+/// USB HID usage ids of the keys used by the console; the function keys
+/// switch to the terminal with their number
+const F1: u8 = 0x3a;
+const F4: u8 = 0x3d;
+const PAGE_UP: u8 = 0x4b;
+const PAGE_DOWN: u8 = 0x4e;
+const KEY_C: u8 = 0x06;
+/// Usage id of the left control key; modifier bits map to consecutive ids
+const MODIFIER_USAGE: u8 = 0xe0;
+/// Modifier bits of the control, shift and alt keys on either side
+const CTRL: u8 = 1 << 0 | 1 << 4;
+const SHIFT: u8 = 1 << 1 | 1 << 5;
+const ALT: u8 = 1 << 2 | 1 << 6;
+
+static CONSOLE: Mutex<Option<Console>> = Mutex::new(None);
+
+/// Lines of a virtual terminal, of which the last one is written to
+#[derive(Default)]
+struct Vt {
+    lines: VecDeque<Vec<char>>,
+    /// Number of lines scrolled back from the last one
+    scroll: usize,
+    /// Width at which lines are wrapped
+    columns: usize,
+    /// Number of lines started, to tell how many lines a write added
+    started: u64,
+}
+
+impl Vt {
+    fn new_line(&mut self) {
+        if self.lines.len() == SCROLLBACK {
+            self.lines.pop_front();
+        }
+        self.lines.push_back(Vec::new());
+        self.started += 1;
+        // Keep showing the same lines when scrolled back
+        if self.scroll > 0 {
+            self.scroll = (self.scroll + 1).min(self.lines.len() - 1);
+        }
+    }
+
+    fn push(&mut self, c: char) {
+        match self.lines.back() {
+            Some(line) if line.len() < self.columns => {}
+            _ => self.new_line(),
+        }
+        self.lines.back_mut().unwrap().push(c);
+    }
+
+    /// Scroll back by `lines`, or forward if negative, keeping a screen of
+    /// `rows` lines filled
+    fn scroll_by(&mut self, lines: isize, rows: usize) {
+        let max = self.lines.len().saturating_sub(rows);
+        self.scroll = (self.scroll as isize + lines).clamp(0, max as isize) as usize;
+    }
+
+    /// Lines on a screen of `rows` lines, from the top
+    fn visible(&self, rows: usize) -> impl Iterator<Item = &[char]> {
+        let end = self.lines.len() - self.scroll;
+        let start = end.saturating_sub(rows);
+        self.lines.range(start..end).map(Vec::as_slice)
+    }
+}
+
+impl Write for Vt {
+    fn write_str(&mut self, s: &str) -> fmt::Result {
+        for c in s.chars() {
+            match c {
+                '\n' => self.new_line(),
+                '\r' => {}
+                // Backspace, within the last line
+                '\x08' => {
+                    if let Some(line) = self.lines.back_mut() {
+                        line.pop();
+                    }
+                }
+                '\t' => {
+                    let column = self.lines.back().map_or(0, Vec::len);
+                    for _ in column % TAB_WIDTH..TAB_WIDTH {
+                        self.push(' ');
+                    }
+                }
+                c if c.is_control() => self.push(char::REPLACEMENT_CHARACTER),
+                c => self.push(c),
+            }
+        }
+        Ok(())
+    }
+}
+
+/// Frame buffer the console draws on
+struct Screen {
+    /// Start of the frame buffer in the offset mapping
+    base: *mut u32,
+    stride: usize,
+    columns: usize,
+    rows: usize,
+}
+
+// Safe because the frame buffer is only accessed while holding the lock
+unsafe impl Send for Screen {}
+
+impl Screen {
+    fn new(fb: &FramebufferInfo, font: &Font) -> Option<Self> {
+        if fb.format == PixelFormat::Bitmask {
+            return None;
+        }
+        Some(Self {
+            base: offset::phys_to_virt(fb.phys_addr).as_mut_ptr(),
+            stride: fb.stride,
+            columns: fb.resolution.0 / font.width,
+            rows: fb.resolution.1 / font.height,
+        })
+    }
+
+    /// Draw `line` on `row`, clearing the rest of the row
+    fn draw_line(&self, font: &Font, row: usize, line: &[char]) {
+        let top = self.base.wrapping_add(row * font.height * self.stride);
+        for column in 0..self.columns {
+            let glyph = font.glyph(line.get(column).copied().unwrap_or(' '));
+            for y in 0..font.height {
+                let pixels = top.wrapping_add(y * self.stride + column * font.width);
+                for x in 0..font.width {
+                    let color = if glyph.pixel(x, y) {
+                        FOREGROUND
+                    } else {
+                        BACKGROUND
+                    };
+                    unsafe { ptr::write_volatile(pixels.wrapping_add(x), color) };
+                }
+            }
+        }
+    }
+
+    fn draw(&self, font: &Font, vt: &Vt) {
+        let mut lines = vt.visible(self.rows);
+        for row in 0..self.rows {
+            self.draw_line(font, row, lines.next().unwrap_or(&[]));
+        }
+    }
+}
+
+struct Console {
+    /// Frame buffer drawn on, or [`None`] while the console is hidden
+    screen: Option<Screen>,
+    font: Font,
+    vts: [Vt; COUNT],
+    /// Index of the terminal that is shown
+    active: usize,
+    /// Modifier keys that are held, in the bit order of HID reports
+    modifiers: u8,
+    /// Key whose press was used by the console, so its release is too
+    consumed: Option<u8>,
+}
+
+impl Console {
+    /// Draw the active terminal, if shown
+    fn redraw(&self) {
+        if let Some(screen) = &self.screen {
+            // The cursor would restore what was underneath it when moved
+            cursor::hide();
+            screen.draw(&self.font, &self.vts[self.active]);
+            flush();
+        }
+    }
+
+    fn write(&mut self, index: usize, args: fmt::Arguments) {
+        let vt = &mut self.vts[index];
+        let started = vt.started;
+        let _ = vt.write_fmt(args);
+        if index != self.active || vt.scroll > 0 {
+            return;
+        }
+        let screen = match &self.screen {
+            Some(screen) => screen,
+            None => return,
+        };
+        match vt.lines.back() {
+            // Only the last line changed
+            Some(line) if vt.started == started => {
+                cursor::hide();
+                let row = vt.lines.len().min(screen.rows) - 1;
+                screen.draw_line(&self.font, row, line);
+                flush();
+            }
+            _ => self.redraw(),
+        }
+    }
+
+    fn show(&mut self) {
+        let font = &self.font;
+        self.screen = framebuffer::current().and_then(|fb| Screen::new(&fb, font));
+        if let Some(screen) = &self.screen {
+            for vt in &mut self.vts {
+                vt.columns = screen.columns;
+            }
+        }
+        self.redraw();
+    }
+
+    /// Handle a key event, returning whether it was used by the console
+    fn key(&mut self, usage: u8, pressed: bool) -> bool {
+        if let Some(bit) = usage.checked_sub(MODIFIER_USAGE).filter(|bit| *bit < 8) {
+            if pressed {
+                self.modifiers |= 1 << bit;
+            } else {
+                self.modifiers &= !(1 << bit);
+            }
+            return false;
+        }
+        if !pressed {
+            let consumed = self.consumed == Some(usage);
+            if consumed {
+                self.consumed = None;
+            }
+            return consumed;
+        }
+        if usage == KEY_C && self.modifiers & CTRL != 0 && jobs::interrupt(self.active) {
+            self.consumed = Some(usage);
+            return true;
+        }
+        let rows = match &self.screen {
+            Some(screen) => screen.rows,
+            None => return false,
+        };
+        let (shift, alt) = (self.modifiers & SHIFT != 0, self.modifiers & ALT != 0);
+        match usage {
+            F1..=F4 if alt => self.active = (usage - F1) as usize,
+            PAGE_UP if shift => self.vts[self.active].scroll_by(rows as isize, rows),
+            PAGE_DOWN if shift => self.vts[self.active].scroll_by(-(rows as isize), rows),
+            _ => return false,
+        }
+        self.consumed = Some(usage);
+        self.redraw();
+        true
+    }
+}
+
+/// Display what was drawn when using a virtio GPU
+fn flush() {
+    if let Err(e) = gpu::try_flush() {
+        // Logging would draw again
+        common::println!("Failed to flush console: {}", e);
+    }
+}
+
+/// Run `f` on the console, if there is one
+///
+/// Interrupts are disabled so the console can also be used from interrupt
+/// handlers. Nothing happens if the console is already in use, e.g. when
+/// logging a panic that occurred while drawing.
+fn with_console<F: FnOnce(&mut Console) -> T, T>(f: F) -> Option<T> {
+    interrupts::without_interrupts(|| CONSOLE.try_lock()?.as_mut().map(f))
+}
+
+/// Index of virtual terminal `vt` as numbered in the configuration
+pub fn index(vt: u64) -> Option<usize> {
+    (vt as usize).checked_sub(1).filter(|index| *index < COUNT)
+}
+
+/// Set up the console on the current frame buffer and show the kernel log
+///
+/// Should be called after [`framebuffer::init`]. Log messages from before are
+/// taken from the log history.
+pub fn init() {
+    let fb = match framebuffer::current() {
+        Some(fb) => fb,
+        None => return,
+    };
+    if fb.format == PixelFormat::Bitmask {
+        log::warn!("Console not supported for bitmask pixel format");
+        return;
+    }
+    let font = Font::load(fb.resolution).unwrap_or_else(|e| {
+        log::debug!("Using built-in font: {}", e);
+        Font::builtin()
+    });
+    let mut console = Console {
+        screen: None,
+        vts: Default::default(),
+        active: index(config::log_vt()).unwrap_or(0),
+        modifiers: 0,
+        consumed: None,
+        font,
+    };
+    if let Some(log_vt) = index(config::log_vt()) {
+        let vt = &mut console.vts[log_vt];
+        vt.columns = fb.resolution.0 / console.font.width;
+        common::logger::with_history(|first, second| {
+            let _ = vt.write_str(&String::from_utf8_lossy(first));
+            let _ = vt.write_str(&String::from_utf8_lossy(second));
+        });
+    }
+    interrupts::without_interrupts(|| *CONSOLE.lock() = Some(console));
+    if index(config::log_vt()).is_some() {
+        common::logger::set_screen(log);
+    }
+    show();
+}
+
+/// Write to virtual terminal `vt`, numbered from 1
+///
+/// Does nothing if there is no such terminal.
+pub fn write(vt: u64, args: fmt::Arguments) {
+    if let Some(index) = index(vt) {
+        with_console(|console| console.write(index, args));
+    }
+}
+
+fn log(args: fmt::Arguments) {
+    write(config::log_vt(), args);
+}
+
+/// Write a message of a user process to its terminal
+///
+/// Messages are also logged, so nothing is written if the kernel log is
+/// attached to the same terminal.
+pub fn user_message(message: &str) {
+    if config::user_vt() != config::log_vt() {
+        write(config::user_vt(), format_args!("{}\n", message));
+    }
+}
+
+/// Echo input typed by a user process to its terminal
+pub fn echo(s: &str) {
+    write(config::user_vt(), format_args!("{}", s));
+}
+
+/// Stop drawing, e.g. because a process was granted the frame buffer
+pub fn hide() {
+    with_console(|console| console.screen = None);
+}
+
+/// Draw on the current frame buffer again
+pub fn show() {
+    with_console(Console::show);
+}
+
+/// Handle a key event, returning whether the console used it, in which case
+/// it should not be delivered to processes
+pub fn key(usage: u8, pressed: bool) -> bool {
+    with_console(|console| console.key(usage, pressed)).unwrap_or(false)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn vt(columns: usize, text: &str) -> Vt {
+        let mut vt = Vt {
+            columns,
+            ..Vt::default()
+        };
+        vt.write_str(text).unwrap();
+        vt
+    }
+
+    /// Lines on a screen of `rows` lines
+    fn visible(vt: &Vt, rows: usize) -> Vec<String> {
+        vt.visible(rows).map(|line| line.iter().collect()).collect()
+    }
+
+    #[test_case]
+    fn wraps_lines() {
+        let vt = vt(10, "abcdefghijkl\ng\th\u{e5}\x1b");
+        assert_eq!(visible(&vt, 10), ["abcdefghij", "kl", "g       h\u{e5}"]);
+        assert_eq!(vt.lines[3], ['\u{fffd}']);
+        assert_eq!(vt.started, 4);
+    }
+
+    #[test_case]
+    fn backspace() {
+        let vt = vt(10, "abc\x08\x08d\n\x08e");
+        assert_eq!(visible(&vt, 10), ["ad", "e"]);
+    }
+
+    #[test_case]
+    fn scrolls_back() {
+        let mut vt = vt(80, "1\n2\n3\n4\n5");
+        vt.scroll_by(2, 3);
+        assert_eq!(visible(&vt, 3), ["1", "2", "3"]);
+        // The view stays put while lines are added
+        vt.write_str("\n6").unwrap();
+        assert_eq!(visible(&vt, 3)[0], "1");
+        vt.scroll_by(-10, 3);
+        assert_eq!(vt.scroll, 0);
+        assert_eq!(visible(&vt, 3), ["4", "5", "6"]);
+    }
+
+    #[test_case]
+    fn limits_scrollback() {
+        let mut vt = vt(80, "");
+        for i in 0..SCROLLBACK + 5 {
+            writeln!(vt, "{}", i).unwrap();
+        }
+        assert_eq!(vt.lines.len(), SCROLLBACK);
+        assert_eq!(vt.lines[0], ['6']);
+    }
+}
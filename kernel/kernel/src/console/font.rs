@@ -0,0 +1,245 @@
+//! Fonts of the console
+//!
+//! A built-in 8x16 font covers ASCII. Larger fonts are loaded from [`PATH`] in
+//! the directory shared by the host, in the PC Screen Font 2 format used by
+//! Linux consoles. Such fonts map glyphs to the Unicode characters they
+//! represent, and characters without a glyph are drawn as U+FFFD, or `?` if
+//! the font lacks that too.
+
+use crate::drivers::virtio::ninep;
+use alloc::{collections::BTreeMap, vec::Vec};
+use core::{convert::TryInto, iter, str};
+use font8x8::legacy::BASIC_LEGACY;
+
+/// Path of the font in the shared directory
+const PATH: &str = "font.psf";
+/// Size of the largest font that is loaded
+const MAX_SIZE: usize = 1 << 20;
+
+const MAGIC: u32 = 0x864a_b572;
+const HEADER_SIZE: usize = 32;
+/// Flag set if the font has a table mapping Unicode characters to glyphs
+const HAS_UNICODE_TABLE: u32 = 1;
+/// Separators in the Unicode table
+const SEQUENCE_START: u8 = 0xfe;
+const GLYPH_END: u8 = 0xff;
+
+/// Bitmap font
+pub struct Font {
+    pub width: usize,
+    pub height: usize,
+    /// Number of bytes per row of a glyph
+    pitch: usize,
+    /// Rows of all glyphs, with the leftmost pixel in the highest bit
+    glyphs: Vec<u8>,
+    /// Glyph of each character, or [`None`] if glyphs are indexed by code
+    /// point
+    unicode: Option<BTreeMap<char, usize>>,
+    /// Glyph drawn for characters the font lacks
+    replacement: usize,
+}
+
+impl Font {
+    /// Font covering ASCII, with the rows of an 8x8 font doubled
+    pub fn builtin() -> Self {
+        let glyphs = BASIC_LEGACY
+            .iter()
+            .flat_map(|glyph| {
+                glyph
+                    .iter()
+                    .flat_map(|row| iter::repeat(row.reverse_bits()).take(2))
+            })
+            .collect();
+        let mut font = Self {
+            width: 8,
+            height: 16,
+            pitch: 1,
+            glyphs,
+            unicode: None,
+            replacement: 0,
+        };
+        font.replacement = font.index('?').unwrap_or(0);
+        font
+    }
+
+    /// Parse a font in the PC Screen Font 2 format, with glyphs that fit on a
+    /// screen of `resolution`
+    pub fn parse(data: &[u8], resolution: (usize, usize)) -> Result<Self, &'static str> {
+        let field = |i: usize| {
+            data.get(4 * i..4 * i + 4)
+                .map(|bytes| u32::from_le_bytes(bytes.try_into().unwrap()) as usize)
+                .ok_or("Font is truncated")
+        };
+        if field(0)? != MAGIC as usize {
+            return Err("Not a PC Screen Font 2");
+        }
+        let (header_size, flags, count) = (field(2)?, field(3)? as u32, field(4)?);
+        let (glyph_size, height, width) = (field(5)?, field(6)?, field(7)?);
+        let pitch = (width + 7) / 8;
+        if header_size < HEADER_SIZE
+            || width == 0
+            || height == 0
+            || count == 0
+            || glyph_size != pitch * height
+        {
+            return Err("Invalid font header");
+        }
+        if width > resolution.0 || height > resolution.1 {
+            return Err("Font is larger than the screen");
+        }
+        let glyphs_end = count
+            .checked_mul(glyph_size)
+            .and_then(|size| size.checked_add(header_size))
+            .filter(|end| *end <= data.len())
+            .ok_or("Font is truncated")?;
+        let unicode = if flags & HAS_UNICODE_TABLE != 0 {
+            Some(unicode_table(&data[glyphs_end..], count))
+        } else {
+            None
+        };
+        let mut font = Self {
+            width,
+            height,
+            pitch,
+            glyphs: data[header_size..glyphs_end].to_vec(),
+            unicode,
+            replacement: 0,
+        };
+        font.replacement = font
+            .index(char::REPLACEMENT_CHARACTER)
+            .or_else(|| font.index('?'))
+            .unwrap_or(0);
+        Ok(font)
+    }
+
+    /// Load the font from the shared directory, for a screen of `resolution`
+    pub fn load(resolution: (usize, usize)) -> Result<Self, &'static str> {
+        let mut data = Vec::new();
+        loop {
+            let start = data.len();
+            if start == MAX_SIZE {
+                return Err("Font is too large");
+            }
+            data.resize(start + 4096, 0);
+            let read = ninep::read(PATH, start as u64, &mut data[start..])?;
+            data.truncate(start + read);
+            if read < 4096 {
+                break;
+            }
+        }
+        Self::parse(&data, resolution)
+    }
+
+    fn index(&self, c: char) -> Option<usize> {
+        match &self.unicode {
+            Some(unicode) => unicode.get(&c).copied(),
+            None => Some(c as usize).filter(|i| *i < self.glyphs.len() / self.glyph_size()),
+        }
+    }
+
+    fn glyph_size(&self) -> usize {
+        self.pitch * self.height
+    }
+
+    /// Glyph of `c`, or the replacement glyph if the font lacks it
+    pub fn glyph(&self, c: char) -> Glyph {
+        let start = self.index(c).unwrap_or(self.replacement) * self.glyph_size();
+        Glyph {
+            rows: &self.glyphs[start..start + self.glyph_size()],
+            pitch: self.pitch,
+        }
+    }
+}
+
+pub struct Glyph<'a> {
+    rows: &'a [u8],
+    pitch: usize,
+}
+
+impl Glyph<'_> {
+    /// Whether pixel `(x, y)` is set
+    pub fn pixel(&self, x: usize, y: usize) -> bool {
+        self.rows[y * self.pitch + x / 8] & 0x80 >> (x % 8) != 0
+    }
+}
+
+/// Map the characters in the Unicode table `table` to the `count` glyphs
+///
+/// Every glyph has a list of UTF-8 encoded characters it represents, which
+/// may be followed by sequences of combining characters that are ignored.
+fn unicode_table(table: &[u8], count: usize) -> BTreeMap<char, usize> {
+    let mut map = BTreeMap::new();
+    let entries = table.split(|byte| *byte == GLYPH_END).take(count);
+    for (i, entry) in entries.enumerate() {
+        let chars = entry.split(|byte| *byte == SEQUENCE_START).next().unwrap();
+        if let Ok(chars) = str::from_utf8(chars) {
+            for c in chars.chars() {
+                map.entry(c).or_insert(i);
+            }
+        }
+    }
+    map
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Font of two 3x2 glyphs, mapping 'a' and 'å' to the first and U+FFFD to
+    /// the second
+    fn font() -> Vec<u8> {
+        let mut data = Vec::new();
+        for field in &[MAGIC, 0, 32, HAS_UNICODE_TABLE, 2, 2, 2, 3] {
+            data.extend_from_slice(&field.to_le_bytes());
+        }
+        data.extend_from_slice(&[0b1010_0000, 0b0100_0000, 0b1110_0000, 0]);
+        data.extend_from_slice("aå".as_bytes());
+        data.extend_from_slice(&[SEQUENCE_START, b'a', GLYPH_END]);
+        data.extend_from_slice("\u{fffd}".as_bytes());
+        data.push(GLYPH_END);
+        data
+    }
+
+    /// Resolution of the screen the test fonts are parsed for
+    const RESOLUTION: (usize, usize) = (640, 480);
+
+    #[test_case]
+    fn parse() {
+        let font = Font::parse(&font(), RESOLUTION).unwrap();
+        assert_eq!((font.width, font.height), (3, 2));
+        let (a, ring) = (font.glyph('a'), font.glyph('å'));
+        assert!(ring.pixel(0, 0) && !ring.pixel(1, 0));
+        assert!(a.pixel(1, 1) && !a.pixel(2, 1));
+        // Missing characters get the replacement glyph
+        let missing = font.glyph('b');
+        assert!(missing.pixel(1, 0) && !missing.pixel(0, 1));
+    }
+
+    #[test_case]
+    fn parse_invalid() {
+        let mut data = font();
+        assert!(Font::parse(&data[..34], RESOLUTION).is_err());
+        assert!(Font::parse(&data, (2, 480)).is_err());
+        assert!(Font::parse(&data, (640, 1)).is_err());
+        // No glyphs
+        data[16] = 0;
+        assert!(Font::parse(&data, RESOLUTION).is_err());
+        // Zero height, with a matching glyph size
+        let mut data = font();
+        data[20] = 0;
+        data[24] = 0;
+        assert!(Font::parse(&data, RESOLUTION).is_err());
+        data[0] = 0;
+        assert!(Font::parse(&data, RESOLUTION).is_err());
+        assert!(Font::parse(&[0; 8], RESOLUTION).is_err());
+    }
+
+    #[test_case]
+    fn builtin() {
+        let font = Font::builtin();
+        // The left and right edges of the middle of 'H' are set
+        assert!(font.glyph('H').pixel(0, 6) && font.glyph('H').pixel(5, 6));
+        assert!(!font.glyph(' ').pixel(0, 6));
+        assert_eq!(font.index('\u{e5}'), None);
+    }
+}
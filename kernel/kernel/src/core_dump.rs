@@ -0,0 +1,272 @@
+//! ELF core files of user processes killed by a fault
+//!
+//! A core file holds the registers of the process at the fault in an
+//! `NT_PRSTATUS` note, laid out like a Linux core file, and its writable
+//! memory in a loadable segment per mapping, so host tools such as `gdb
+//! <program> <core>` can inspect it. Frame buffer mappings are left out, as is
+//! memory beyond [`MAX_SIZE`].
+//!
+//! Core files are written to `cores/<pid>` in the directory shared by the host
+//! (see [`ninep`]), which should be writable and contain a `cores` directory.
+//! They are enabled with the `core-dump` option of the kernel configuration and
+//! complement the [`crash_dump`](crate::crash_dump) of the kernel itself.
+//!
+//! [`ninep`]: crate::drivers::virtio::ninep
+
+use crate::{
+    config,
+    drivers::virtio::ninep,
+    fault::{Exception, Report},
+    user::UserSlice,
+    vm::{AddressSpace, Backing},
+    Init,
+};
+use alloc::{format, vec::Vec};
+use x86_64::structures::paging::PageTableFlags;
+
+/// Maximum size of the memory in a core file in bytes
+const MAX_SIZE: usize = 4 << 20;
+/// Size of the ELF header
+const EHDR_SIZE: usize = 64;
+/// Size of a program header
+const PHDR_SIZE: usize = 56;
+/// Size of the `elf_prstatus` structure of x86-64 Linux
+const PRSTATUS_SIZE: usize = 336;
+/// Offset of the general purpose registers in `elf_prstatus`
+const PR_REG_OFFSET: usize = 112;
+/// Name of the note, padded to four bytes
+const NOTE_NAME: &[u8; 8] = b"CORE\0\0\0\0";
+/// Size of the name of the note, including the terminator
+const NOTE_NAME_SIZE: u32 = 5;
+
+const ET_CORE: u16 = 4;
+const EM_X86_64: u16 = 62;
+const PT_LOAD: u32 = 1;
+const PT_NOTE: u32 = 4;
+const NT_PRSTATUS: u32 = 1;
+const PF_X: u32 = 1;
+const PF_W: u32 = 2;
+const PF_R: u32 = 4;
+
+/// Memory of the process included in a core file
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+struct Region {
+    start: u64,
+    len: usize,
+    flags: PageTableFlags,
+}
+
+/// Writable memory mapped for process `pid`, up to [`MAX_SIZE`]
+fn regions(address_space: &AddressSpace, pid: u64) -> Vec<Region> {
+    let writable = PageTableFlags::PRESENT | PageTableFlags::WRITABLE;
+    let mut regions = Vec::new();
+    let mut size = 0;
+    for mapping in address_space.mappings() {
+        if mapping.pid != Some(pid)
+            || !mapping.flags.contains(writable)
+            || mapping.backing == Backing::FrameBuffer
+        {
+            continue;
+        }
+        let start = mapping.pages.start.start_address().as_u64();
+        let len = (mapping.pages.end - mapping.pages.start) as usize * 4096;
+        if size + len > MAX_SIZE {
+            log::warn!("Leaving {:#x} out of the core file, it is too large", start);
+            continue;
+        }
+        size += len;
+        regions.push(Region {
+            start,
+            len,
+            flags: mapping.flags,
+        });
+    }
+    regions.sort_unstable_by_key(|region| region.start);
+    regions
+}
+
+/// Signal Linux sends for `exception`
+fn signal(exception: Exception) -> u32 {
+    match exception {
+        Exception::DivideError => 8,
+        Exception::InvalidOpcode => 4,
+        Exception::GeneralProtection | Exception::PageFault => 11,
+    }
+}
+
+/// Append a program header to `core`
+fn program_header(core: &mut Vec<u8>, ty: u32, flags: u32, offset: usize, addr: u64, len: usize) {
+    let align: u64 = if ty == PT_LOAD { 4096 } else { 4 };
+    core.extend_from_slice(&ty.to_le_bytes());
+    core.extend_from_slice(&flags.to_le_bytes());
+    core.extend_from_slice(&(offset as u64).to_le_bytes());
+    core.extend_from_slice(&addr.to_le_bytes());
+    // Physical address
+    core.extend_from_slice(&0u64.to_le_bytes());
+    core.extend_from_slice(&(len as u64).to_le_bytes());
+    core.extend_from_slice(&(len as u64).to_le_bytes());
+    core.extend_from_slice(&align.to_le_bytes());
+}
+
+/// `elf_prstatus` describing the process of `report`
+fn prstatus(report: &Report) -> [u8; PRSTATUS_SIZE] {
+    let mut status = [0; PRSTATUS_SIZE];
+    let signal = signal(report.fault.exception);
+    // si_signo and pr_cursig
+    status[0..4].copy_from_slice(&signal.to_le_bytes());
+    status[12..14].copy_from_slice(&(signal as u16).to_le_bytes());
+    status[32..36].copy_from_slice(&(report.pid as u32).to_le_bytes());
+    let (r, fault) = (&report.registers, &report.fault);
+    // In the order of user_regs_struct, with orig_rax and the segment
+    // registers zero
+    let registers = [
+        r.r15,
+        r.r14,
+        r.r13,
+        r.r12,
+        r.rbp,
+        r.rbx,
+        r.r11,
+        r.r10,
+        r.r9,
+        r.r8,
+        r.rax,
+        r.rcx,
+        r.rdx,
+        r.rsi,
+        r.rdi,
+        0,
+        fault.rip,
+        0,
+        fault.rflags,
+        fault.rsp,
+    ];
+    for (i, value) in registers.iter().enumerate() {
+        let offset = PR_REG_OFFSET + i * 8;
+        status[offset..offset + 8].copy_from_slice(&value.to_le_bytes());
+    }
+    status
+}
+
+/// ELF header, program headers and note of the core file of `report`, padded
+/// to a page so the memory of `regions` can follow
+fn header(report: &Report, regions: &[Region]) -> Vec<u8> {
+    let phnum = 1 + regions.len();
+    let note_offset = EHDR_SIZE + phnum * PHDR_SIZE;
+    let note_size = 12 + NOTE_NAME.len() + PRSTATUS_SIZE;
+    let data_offset = (note_offset + note_size + 4095) / 4096 * 4096;
+    let mut core = Vec::with_capacity(data_offset);
+    // Identification: 64-bit, little-endian, version 1, System V ABI
+    core.extend_from_slice(b"\x7fELF\x02\x01\x01\0\0\0\0\0\0\0\0\0");
+    core.extend_from_slice(&ET_CORE.to_le_bytes());
+    core.extend_from_slice(&EM_X86_64.to_le_bytes());
+    core.extend_from_slice(&1u32.to_le_bytes());
+    // Entry point, program and section header offsets, flags
+    core.extend_from_slice(&0u64.to_le_bytes());
+    core.extend_from_slice(&(EHDR_SIZE as u64).to_le_bytes());
+    core.extend_from_slice(&0u64.to_le_bytes());
+    core.extend_from_slice(&0u32.to_le_bytes());
+    core.extend_from_slice(&(EHDR_SIZE as u16).to_le_bytes());
+    core.extend_from_slice(&(PHDR_SIZE as u16).to_le_bytes());
+    core.extend_from_slice(&(phnum as u16).to_le_bytes());
+    // No section headers
+    core.extend_from_slice(&[0; 6]);
+    program_header(&mut core, PT_NOTE, 0, note_offset, 0, note_size);
+    let mut offset = data_offset;
+    for region in regions {
+        let mut flags = PF_R;
+        if region.flags.contains(PageTableFlags::WRITABLE) {
+            flags |= PF_W;
+        }
+        if !region.flags.contains(PageTableFlags::NO_EXECUTE) {
+            flags |= PF_X;
+        }
+        program_header(&mut core, PT_LOAD, flags, offset, region.start, region.len);
+        offset += region.len;
+    }
+    core.extend_from_slice(&NOTE_NAME_SIZE.to_le_bytes());
+    core.extend_from_slice(&(PRSTATUS_SIZE as u32).to_le_bytes());
+    core.extend_from_slice(&NT_PRSTATUS.to_le_bytes());
+    core.extend_from_slice(NOTE_NAME);
+    core.extend_from_slice(&prstatus(report));
+    core.resize(data_offset, 0);
+    core
+}
+
+/// Write a core file of the process that caused the fault of `report`, if
+/// enabled
+pub fn write(init: &mut Init, report: &Report) {
+    if !config::core_dump() {
+        return;
+    }
+    let pid = report.pid;
+    let regions = regions(&init.address_space, pid);
+    let mut core = header(report, &regions);
+    for region in &regions {
+        let memory = UserSlice::new(region.start, region.len);
+        match memory.get(init, pid) {
+            Ok(bytes) => core.extend_from_slice(bytes),
+            Err(e) => {
+                log::warn!("Core file misses memory at {:#x}: {}", region.start, e);
+                core.resize(core.len() + region.len, 0);
+            }
+        }
+    }
+    let path = format!("cores/{}", pid);
+    match ninep::write(&path, &core) {
+        Ok(()) => log::info!("Wrote core file of process {} to {}", pid, path),
+        Err(e) => log::warn!("Failed to write core file of process {}: {}", pid, e),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::fault::{Fault, Registers, REGISTERS};
+    use x86_64::VirtAddr;
+
+    #[test_case]
+    fn layout() {
+        let report = Report {
+            pid: 7,
+            fault: Fault {
+                exception: Exception::PageFault,
+                error_code: Some(0x6),
+                address: Some(VirtAddr::new(0xdead_0000)),
+                rip: 0x20_1000,
+                rsp: 0x2ff8,
+                rflags: 0x202,
+            },
+            registers: Registers {
+                rax: 0x1234,
+                ..unsafe { REGISTERS }
+            },
+            stack: [None; 8],
+        };
+        let flags = PageTableFlags::PRESENT | PageTableFlags::WRITABLE | PageTableFlags::NO_EXECUTE;
+        let regions = [Region {
+            start: 0x2000,
+            len: 4096,
+            flags,
+        }];
+        let core = header(&report, &regions);
+        assert_eq!(core.len(), 4096);
+        assert_eq!(&core[..4], b"\x7fELF");
+        assert_eq!(core[16..18], ET_CORE.to_le_bytes());
+        assert_eq!(core[56..58], 2u16.to_le_bytes());
+        // The load segment follows the header, readable and writable
+        let phdr = &core[EHDR_SIZE + PHDR_SIZE..EHDR_SIZE + 2 * PHDR_SIZE];
+        assert_eq!(phdr[..8], [1, 0, 0, 0, 6, 0, 0, 0]);
+        assert_eq!(phdr[8..16], 4096u64.to_le_bytes());
+        assert_eq!(phdr[16..24], 0x2000u64.to_le_bytes());
+        let note = EHDR_SIZE + 2 * PHDR_SIZE;
+        assert_eq!(&core[note + 12..note + 17], b"CORE\0");
+        let status = &core[note + 20..note + 20 + PRSTATUS_SIZE];
+        assert_eq!(status[..4], 11u32.to_le_bytes());
+        assert_eq!(status[32..36], 7u32.to_le_bytes());
+        let rax = PR_REG_OFFSET + 10 * 8;
+        assert_eq!(status[rax..rax + 8], 0x1234u64.to_le_bytes());
+        let rip = PR_REG_OFFSET + 16 * 8;
+        assert_eq!(status[rip..rip + 8], 0x20_1000u64.to_le_bytes());
+    }
+}
@@ -0,0 +1,199 @@
+//! Crash dumps printed over the serial port on panic
+//!
+//! A dump contains the panic message, the offset the kernel is loaded at,
+//! registers, a backtrace, recent log messages, the memory map and the running
+//! process. It is encoded as base64 and printed between [`BEGIN`] and [`END`]
+//! marker lines; the xtask runner saves it and `cargo xtask crash-dump` decodes
+//! it. Dumps are enabled with the `crash-dump` option of the kernel
+//! configuration.
+//!
+//! Nothing is allocated, as the heap may be what caused the panic.
+
+use crate::{base64::Encoder, config, stack, threads};
+use common::{boot::BootInfo, logger, println};
+use core::{
+    fmt::{self, Write},
+    panic::PanicInfo,
+};
+use spin::Once;
+use x86_64::registers::{
+    control::{Cr0, Cr2, Cr3, Cr4},
+    rflags,
+};
+
+/// Line preceding the encoded dump
+pub const BEGIN: &str = "-----BEGIN CRASH DUMP-----";
+/// Line following the encoded dump
+pub const END: &str = "-----END CRASH DUMP-----";
+
+/// Start of a dump, identifying its format
+const MAGIC: &[u8; 4] = b"ACRD";
+/// Version of the dump format
+const VERSION: u32 = 1;
+
+/// Maximum length of the panic message in bytes
+const MESSAGE_SIZE: usize = 1024;
+/// Maximum number of return addresses in the backtrace
+const BACKTRACE_SIZE: usize = 32;
+
+/// Tags of sections of the dump
+mod section {
+    pub const MESSAGE: u32 = 1;
+    pub const REGISTERS: u32 = 2;
+    pub const BACKTRACE: u32 = 3;
+    pub const LOG: u32 = 4;
+    pub const MEMORY_MAP: u32 = 5;
+    pub const PROCESS: u32 = 6;
+    pub const KERNEL_OFFSET: u32 = 7;
+}
+
+static BOOT_INFO: Once<&'static BootInfo> = Once::new();
+
+/// Buffer of fixed size, truncating what is written to it
+struct Buffer {
+    buf: [u8; MESSAGE_SIZE],
+    len: usize,
+}
+
+impl Write for Buffer {
+    fn write_str(&mut self, s: &str) -> fmt::Result {
+        let len = s.len().min(MESSAGE_SIZE - self.len);
+        self.buf[self.len..self.len + len].copy_from_slice(&s.as_bytes()[..len]);
+        self.len += len;
+        Ok(())
+    }
+}
+
+/// Keep the boot information for crash dumps
+pub fn init(boot_info: &'static BootInfo) {
+    BOOT_INFO.call_once(|| boot_info);
+}
+
+/// Start a section of `len` bytes
+fn section(encoder: &mut Encoder, tag: u32, len: usize) {
+    encoder.extend(&tag.to_le_bytes());
+    encoder.extend(&(len as u32).to_le_bytes());
+}
+
+/// Return addresses found by following the chain of frame pointers
+///
+/// Frames are only followed upwards and within the bounds of the stack `rsp`
+/// points into, so garbage frame pointers don't cause another fault. Nothing
+/// is found on a stack of unknown bounds, like the one the kernel is entered
+/// on.
+pub fn backtrace(rsp: u64, rbp: u64) -> ([u64; BACKTRACE_SIZE], usize) {
+    let mut addrs = [0; BACKTRACE_SIZE];
+    let mut len = 0;
+    let end = match stack::bounds(rsp) {
+        Some(stack) => stack.end,
+        None => return (addrs, len),
+    };
+    let mut frame = rbp;
+    // Both the saved frame pointer and the return address should be on it
+    while len < BACKTRACE_SIZE && frame >= rsp && frame <= end - 16 && frame % 8 == 0 {
+        let (next, ret) = unsafe { (*(frame as *const u64), *(frame as *const u64).add(1)) };
+        if ret == 0 {
+            break;
+        }
+        addrs[len] = ret;
+        len += 1;
+        if next <= frame {
+            break;
+        }
+        frame = next;
+    }
+    (addrs, len)
+}
+
+/// Print a crash dump for the panic described by `info`
+pub fn write(info: &PanicInfo) {
+    if !config::crash_dump() {
+        return;
+    }
+    let (rsp, rbp): (u64, u64);
+    unsafe { asm!("mov {}, rsp; mov {}, rbp", out(reg) rsp, out(reg) rbp) };
+
+    println!("{}", BEGIN);
+    let mut encoder = Encoder::new();
+    encoder.extend(MAGIC);
+    encoder.extend(&VERSION.to_le_bytes());
+
+    let mut message = Buffer {
+        buf: [0; MESSAGE_SIZE],
+        len: 0,
+    };
+    let _ = write!(message, "{}", info);
+    section(&mut encoder, section::MESSAGE, message.len);
+    encoder.extend(&message.buf[..message.len]);
+
+    // Precedes the backtrace so it can be symbolized while decoding
+    if let Some(boot_info) = BOOT_INFO.get() {
+        section(&mut encoder, section::KERNEL_OFFSET, 8);
+        encoder.extend(&boot_info.kernel_offset.to_le_bytes());
+    }
+
+    let registers = [
+        rsp,
+        rbp,
+        rflags::read_raw(),
+        Cr0::read_raw(),
+        Cr2::read().as_u64(),
+        Cr3::read().0.start_address().as_u64(),
+        Cr4::read_raw(),
+    ];
+    section(&mut encoder, section::REGISTERS, registers.len() * 8);
+    registers
+        .iter()
+        .for_each(|register| encoder.extend(&register.to_le_bytes()));
+
+    let (addrs, len) = backtrace(rsp, rbp);
+    section(&mut encoder, section::BACKTRACE, len * 8);
+    addrs[..len]
+        .iter()
+        .for_each(|addr| encoder.extend(&addr.to_le_bytes()));
+
+    logger::with_history(|a, b| {
+        section(&mut encoder, section::LOG, a.len() + b.len());
+        encoder.extend(a);
+        encoder.extend(b);
+    });
+
+    if let Some(boot_info) = BOOT_INFO.get() {
+        let memory_map = &boot_info.memory_map;
+        section(
+            &mut encoder,
+            section::MEMORY_MAP,
+            memory_map.clone().count() * 24,
+        );
+        for descriptor in memory_map.clone() {
+            encoder.extend(&descriptor.phys_start.to_le_bytes());
+            encoder.extend(&descriptor.page_count.to_le_bytes());
+            encoder.extend(&descriptor.ty.0.to_le_bytes());
+            encoder.extend(&0u32.to_le_bytes());
+        }
+    }
+
+    section(&mut encoder, section::PROCESS, 8);
+    encoder.extend(&threads::current_pid().unwrap_or(0).to_le_bytes());
+
+    encoder.finish();
+    println!("{}", END);
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test_case]
+    fn backtrace_bounds() {
+        let (rsp, rbp): (u64, u64);
+        unsafe { asm!("mov {}, rsp; mov {}, rbp", out(reg) rsp, out(reg) rbp) };
+        assert!(backtrace(rsp, rbp).1 > 0);
+        // Frame pointers outside of the stack are not followed
+        let end = stack::bounds(rsp).unwrap().end;
+        assert_eq!(backtrace(rsp, end - 8).1, 0);
+        assert_eq!(backtrace(rsp, rsp - 16).1, 0);
+        // Nor are those on a stack of unknown bounds
+        assert_eq!(backtrace(0, rbp).1, 0);
+    }
+}
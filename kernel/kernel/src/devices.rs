@@ -0,0 +1,252 @@
+//! Registry of discovered devices
+//!
+//! Buses and the code setting up legacy devices record every device they find
+//! with [`add`], along with the resources it occupies. Drivers record whether
+//! they took a device with [`bind`], how to stop it with [`on_quiesce`] and
+//! how to restore it after a sleep state with [`on_resume`].
+//! The registry is listed with [`dump`] at the end of boot, as there is no
+//! other way to inspect it yet.
+//!
+//! Lookups vastly outnumber changes, so the registry is kept in an [`Rcu`].
+
+use crate::{drivers::pci, sync::Rcu};
+use alloc::{string::String, vec::Vec};
+use core::fmt;
+use x86_64::PhysAddr;
+
+static DEVICES: Rcu<Vec<Device>> = Rcu::new();
+
+/// Index of a device in the registry
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub struct Id(usize);
+
+/// Something a device occupies
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub enum Resource {
+    /// Configuration space of a PCI function
+    Pci(pci::Address),
+    /// Range of I/O ports, as first port and count
+    Ports(u16, u16),
+    /// Range of physical memory, as start address and size in bytes
+    Memory(PhysAddr, u64),
+    /// Legacy interrupt line
+    Irq(u8),
+}
+
+impl fmt::Display for Resource {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match *self {
+            Self::Pci(address) => write!(f, "pci {}", address),
+            Self::Ports(start, count) => {
+                write!(f, "ports {:#x}-{:#x}", start, start + (count - 1))
+            }
+            Self::Memory(start, size) => write!(
+                f,
+                "memory {:#x}-{:#x}",
+                start.as_u64(),
+                start.as_u64() + (size - 1)
+            ),
+            Self::Irq(irq) => write!(f, "irq {}", irq),
+        }
+    }
+}
+
+/// Whether a driver took a device
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub enum State {
+    /// No driver tried to use the device
+    Unbound,
+    /// The driver uses the device
+    Bound,
+    /// The driver tried but failed to set up the device
+    Failed,
+}
+
+#[derive(Clone)]
+pub struct Device {
+    pub name: String,
+    pub resources: Vec<Resource>,
+    pub driver: Option<&'static str>,
+    pub state: State,
+    /// Stops the device from using memory and raising interrupts
+    pub quiesce: Option<fn()>,
+    /// Sets the device up again after a sleep state, which resets it
+    pub resume: Option<fn()>,
+}
+
+impl fmt::Display for Device {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{} (", self.name)?;
+        for (i, resource) in self.resources.iter().enumerate() {
+            let separator = if i == 0 { "" } else { ", " };
+            write!(f, "{}{}", separator, resource)?;
+        }
+        write!(f, ")")?;
+        match (self.state, self.driver) {
+            (State::Bound, Some(driver)) => write!(f, " bound to {}", driver),
+            (State::Failed, Some(driver)) => write!(f, " failed in {}", driver),
+            _ => write!(f, " unbound"),
+        }
+    }
+}
+
+/// Record a device without a driver
+pub fn add(name: String, resources: Vec<Resource>) -> Id {
+    DEVICES.update(|devices| {
+        devices.push(Device {
+            name,
+            resources,
+            driver: None,
+            state: State::Unbound,
+            quiesce: None,
+            resume: None,
+        });
+        Id(devices.len() - 1)
+    })
+}
+
+/// Record that `driver` tried to take device `id`, ending up in `state`
+pub fn bind(id: Id, driver: &'static str, state: State) {
+    DEVICES.update(|devices| {
+        if let Some(device) = devices.get_mut(id.0) {
+            device.driver = Some(driver);
+            device.state = state;
+        }
+    });
+}
+
+/// Device of the PCI function at `address`
+pub fn pci(address: pci::Address) -> Option<Id> {
+    DEVICES
+        .read()?
+        .iter()
+        .position(|device| device.resources.contains(&Resource::Pci(address)))
+        .map(Id)
+}
+
+/// Record that `driver` tried to take the PCI function at `address`
+pub fn bind_pci(address: pci::Address, driver: &'static str, state: State) {
+    if let Some(id) = pci(address) {
+        bind(id, driver, state);
+    }
+}
+
+/// Have [`quiesce`] stop the PCI function at `address` by calling `quiesce`
+pub fn on_quiesce(address: pci::Address, quiesce: fn()) {
+    if let Some(id) = pci(address) {
+        DEVICES.update(|devices| {
+            if let Some(device) = devices.get_mut(id.0) {
+                device.quiesce = Some(quiesce);
+            }
+        });
+    }
+}
+
+/// Have [`resume`] set device `id` up again by calling `resume`
+pub fn on_resume(id: Id, resume: fn()) {
+    DEVICES.update(|devices| {
+        if let Some(device) = devices.get_mut(id.0) {
+            device.resume = Some(resume);
+        }
+    });
+}
+
+/// Stop all devices, in the reverse order of registration
+///
+/// Drivers should not be used afterwards, unless [`resume`] set their devices
+/// up again.
+pub fn quiesce() {
+    let hooks: Vec<_> = match DEVICES.read() {
+        Some(devices) => devices
+            .iter()
+            .filter_map(|device| Some((device.name.clone(), device.quiesce?)))
+            .collect(),
+        None => return,
+    };
+    for (name, quiesce) in hooks.into_iter().rev() {
+        log::debug!("Quiescing {}", name);
+        quiesce();
+    }
+}
+
+/// Stop the devices that [`resume`] can set up again before a sleep state, in
+/// the reverse order of registration
+///
+/// Other devices keep running, so they still work if the sleep state is not
+/// entered; the firmware powers them off if it is.
+pub fn suspend() {
+    let hooks: Vec<_> = match DEVICES.read() {
+        Some(devices) => devices
+            .iter()
+            .filter(|device| device.resume.is_some())
+            .filter_map(|device| Some((device.name.clone(), device.quiesce?)))
+            .collect(),
+        None => return,
+    };
+    for (name, quiesce) in hooks.into_iter().rev() {
+        log::debug!("Quiescing {}", name);
+        quiesce();
+    }
+}
+
+/// Set up devices again after [`suspend`], in the order of registration
+///
+/// If the system `slept`, devices that would have been stopped by [`quiesce`]
+/// but cannot be set up again lost their state, and are marked as failed.
+pub fn resume(slept: bool) {
+    // Hooks run after the update, as they may update the registry themselves
+    let hooks: Vec<_> = DEVICES.update(|devices| {
+        devices
+            .iter_mut()
+            .filter(|device| device.state == State::Bound)
+            .filter_map(|device| match (device.resume, device.quiesce) {
+                (Some(resume), _) => Some((device.name.clone(), resume)),
+                (None, Some(_)) if slept => {
+                    log::warn!("{} not restored after sleep", device.name);
+                    device.state = State::Failed;
+                    None
+                }
+                (None, _) => None,
+            })
+            .collect()
+    });
+    for (name, resume) in hooks {
+        log::debug!("Resuming {}", name);
+        resume();
+    }
+}
+
+/// Log all devices
+pub fn dump() {
+    let devices = DEVICES.read();
+    let devices = devices.as_deref().map_or(&[][..], Vec::as_slice);
+    log::info!("{} devices registered", devices.len());
+    for (i, device) in devices.iter().enumerate() {
+        log::debug!("Device {}: {}", i, device);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use alloc::{format, vec};
+
+    #[test_case]
+    fn display() {
+        let mut device = Device {
+            name: "PS/2 mouse".into(),
+            resources: vec![Resource::Ports(0x60, 1), Resource::Irq(12)],
+            driver: None,
+            state: State::Unbound,
+            quiesce: None,
+            resume: None,
+        };
+        assert_eq!(
+            format!("{}", device),
+            "PS/2 mouse (ports 0x60-0x60, irq 12) unbound"
+        );
+        device.driver = Some("ps2-mouse");
+        device.state = State::Failed;
+        assert!(format!("{}", device).ends_with(" failed in ps2-mouse"));
+    }
+}
@@ -0,0 +1,11 @@
+//! Device drivers
+//!
+//! Input, audio, memory balloon, virtio console and 9P drivers register
+//! themselves with [`crate::initcall!`]; other drivers are set up by the
+//! subsystems using them.
+
+pub mod ac97;
+pub mod pci;
+pub mod ps2;
+pub mod usb;
+pub mod virtio;
@@ -0,0 +1,218 @@
+//! AC'97 audio controller, as emulated by QEMU
+//!
+//! Only PCM output is supported, with 16-bit stereo samples at a fixed rate of
+//! [`sys::AUDIO_SAMPLE_RATE`]. Samples are copied into a ring of DMA buffers
+//! described by the buffer descriptor list of the PCM out channel.
+
+use super::pci::{self, Bar};
+use crate::{
+    devices::{self, State},
+    idle, Init,
+};
+use common::boot::offset;
+use core::{mem::size_of, ptr};
+use spin::Mutex;
+use x86_64::{instructions::port::Port, structures::paging::FrameAllocator, PhysAddr};
+
+/// PCI class and subclass of multimedia audio controllers
+const PCI_CLASS: (u8, u8) = (0x04, 0x01);
+
+/// Number of entries in the buffer descriptor list
+const BUFFERS: usize = 32;
+/// Number of samples in a single buffer occupying a frame
+const BUFFER_SAMPLES: usize = 4096 / size_of::<i16>();
+
+/// Mixer register offsets
+mod mixer {
+    pub const RESET: u16 = 0x00;
+    pub const MASTER_VOLUME: u16 = 0x02;
+    pub const PCM_OUT_VOLUME: u16 = 0x18;
+}
+
+/// Bus master register offsets
+mod bus {
+    /// Base of the PCM out channel registers
+    pub const PCM_OUT: u16 = 0x10;
+    pub const GLOBAL_CONTROL: u16 = 0x2c;
+
+    /// Offsets relative to a channel
+    pub const BDBAR: u16 = 0x00;
+    pub const CIV: u16 = 0x04;
+    pub const LVI: u16 = 0x05;
+    pub const STATUS: u16 = 0x06;
+    pub const CONTROL: u16 = 0x0b;
+
+    pub const STATUS_HALTED: u16 = 1 << 0;
+    pub const CONTROL_RUN: u8 = 1 << 0;
+    pub const CONTROL_RESET: u8 = 1 << 1;
+    pub const GLOBAL_COLD_RESET: u32 = 1 << 1;
+}
+
+static AC97: Mutex<Option<Ac97>> = Mutex::new(None);
+
+/// Entry of the buffer descriptor list
+#[repr(C)]
+#[derive(Copy, Clone)]
+struct Descriptor {
+    addr: u32,
+    samples: u16,
+    control: u16,
+}
+
+struct Ac97 {
+    channel: u16,
+    descriptors: PhysAddr,
+    buffers: [PhysAddr; BUFFERS],
+}
+
+impl Ac97 {
+    fn channel_u8(&self, offset: u16) -> Port<u8> {
+        Port::new(self.channel + offset)
+    }
+
+    fn halted(&self) -> bool {
+        let mut status = Port::<u16>::new(self.channel + bus::STATUS);
+        let status = unsafe { status.read() };
+        status & bus::STATUS_HALTED != 0
+    }
+
+    /// Reset the PCM out channel, which stops it and resets its indices
+    fn reset_channel(&self) {
+        let mut control = self.channel_u8(bus::CONTROL);
+        unsafe {
+            control.write(bus::CONTROL_RESET);
+            while control.read() & bus::CONTROL_RESET != 0 {
+                core::hint::spin_loop();
+            }
+            Port::<u32>::new(self.channel + bus::BDBAR).write(self.descriptors.as_u64() as u32);
+        }
+    }
+
+    /// Copy up to [`BUFFER_SAMPLES`] samples into the next buffer and queue it
+    ///
+    /// Blocks while all buffers are in use.
+    fn queue(&mut self, samples: &[i16]) {
+        let mut civ = self.channel_u8(bus::CIV);
+        let mut lvi = self.channel_u8(bus::LVI);
+        let (index, halted) = loop {
+            if self.halted() {
+                self.reset_channel();
+                break (0, true);
+            }
+            let next = (unsafe { lvi.read() } as usize + 1) % BUFFERS;
+            if next != unsafe { civ.read() } as usize {
+                break (next, false);
+            }
+            idle::wait();
+        };
+
+        let buffer = offset::phys_to_virt(self.buffers[index]).as_mut_ptr::<i16>();
+        let descriptor = Descriptor {
+            addr: self.buffers[index].as_u64() as u32,
+            samples: samples.len() as u16,
+            control: 0,
+        };
+        unsafe {
+            ptr::copy_nonoverlapping(samples.as_ptr(), buffer, samples.len());
+            offset::phys_to_virt(self.descriptors)
+                .as_mut_ptr::<Descriptor>()
+                .add(index)
+                .write_volatile(descriptor);
+            lvi.write(index as u8);
+            if halted {
+                self.channel_u8(bus::CONTROL).write(bus::CONTROL_RUN);
+            }
+        }
+    }
+}
+
+/// Allocate a frame below 4 GiB, as the controller only uses 32-bit addresses
+fn allocate(init: &mut Init) -> Result<PhysAddr, &'static str> {
+    let frame = init
+        .frame_allocator
+        .allocate_frame()
+        .ok_or("No frame for audio buffer")?;
+    let addr = frame.start_address();
+    if addr.as_u64() > u32::MAX as u64 {
+        return Err("Audio buffer not addressable");
+    }
+    Ok(addr)
+}
+
+fn setup(init: &mut Init, device: &pci::Device) -> Result<Ac97, &'static str> {
+    let (mixer, bus) = match (device.bar(0), device.bar(1)) {
+        (Some(Bar::Io(mixer)), Some(Bar::Io(bus))) => (mixer, bus),
+        _ => return Err("AC'97 registers not in I/O space"),
+    };
+    device.enable();
+    unsafe {
+        Port::<u32>::new(bus + bus::GLOBAL_CONTROL).write(bus::GLOBAL_COLD_RESET);
+        Port::<u16>::new(mixer + mixer::RESET).write(0);
+        // Maximum volume, unmuted
+        Port::<u16>::new(mixer + mixer::MASTER_VOLUME).write(0);
+        // Zero gain, unmuted
+        Port::<u16>::new(mixer + mixer::PCM_OUT_VOLUME).write(0x0808);
+    }
+
+    let descriptors = allocate(init)?;
+    let mut buffers = [PhysAddr::zero(); BUFFERS];
+    for buffer in &mut buffers {
+        *buffer = allocate(init)?;
+    }
+    let ac97 = Ac97 {
+        channel: bus + bus::PCM_OUT,
+        descriptors,
+        buffers,
+    };
+    ac97.reset_channel();
+    Ok(ac97)
+}
+
+crate::initcall!(Device, init);
+
+/// Set up the first audio controller, if any
+pub fn init(init: &mut Init) {
+    let device = match pci::devices()
+        .iter()
+        .find(|device| (device.class, device.subclass) == PCI_CLASS)
+    {
+        Some(device) => device,
+        None => return,
+    };
+    log::info!("AC'97 audio controller at {}", device.address);
+    match setup(init, device) {
+        Ok(ac97) => {
+            *AC97.lock() = Some(ac97);
+            devices::bind_pci(device.address, "ac97", State::Bound);
+            devices::on_quiesce(device.address, quiesce);
+        }
+        Err(e) => {
+            log::warn!("AC'97 audio controller unavailable: {}", e);
+            devices::bind_pci(device.address, "ac97", State::Failed);
+        }
+    }
+}
+
+/// Queue interleaved stereo samples for playback
+///
+/// Blocks until all samples are queued. Returns `false` if there is no audio
+/// device.
+pub fn submit(samples: &[i16]) -> bool {
+    let mut ac97 = AC97.lock();
+    let ac97 = match ac97.as_mut() {
+        Some(ac97) => ac97,
+        None => return false,
+    };
+    // Keep stereo pairs together in a buffer
+    for chunk in samples.chunks(BUFFER_SAMPLES) {
+        ac97.queue(chunk);
+    }
+    true
+}
+
+/// Stop playback on the audio controller, if any
+fn quiesce() {
+    if let Some(ac97) = AC97.lock().take() {
+        ac97.reset_channel();
+    }
+}
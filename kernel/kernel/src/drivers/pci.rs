@@ -0,0 +1,248 @@
+//! PCI bus enumeration using configuration space access mechanism #1
+
+use crate::devices::Resource;
+use alloc::{format, vec, vec::Vec};
+use common::boot::offset;
+use core::fmt;
+use spin::{Mutex, Once};
+use x86_64::{instructions::port::Port, PhysAddr};
+
+/// Address and data ports of the configuration space access mechanism
+static CONFIG: Mutex<(Port<u32>, Port<u32>)> = Mutex::new((Port::new(0xcf8), Port::new(0xcfc)));
+
+static DEVICES: Once<Vec<Device>> = Once::new();
+
+/// Location of a PCI function
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub struct Address {
+    pub bus: u8,
+    pub device: u8,
+    pub function: u8,
+}
+
+impl Address {
+    fn config_address(self, offset: u8) -> u32 {
+        1 << 31
+            | (self.bus as u32) << 16
+            | (self.device as u32) << 11
+            | (self.function as u32) << 8
+            | (offset & 0xfc) as u32
+    }
+
+    /// Read 32-bit register from configuration space; `offset` is rounded down
+    /// to a multiple of four
+    pub fn read(self, offset: u8) -> u32 {
+        let mut config = CONFIG.lock();
+        unsafe {
+            config.0.write(self.config_address(offset));
+            config.1.read()
+        }
+    }
+
+    /// Write 32-bit register to configuration space; `offset` is rounded down
+    /// to a multiple of four
+    pub fn write(self, offset: u8, value: u32) {
+        let mut config = CONFIG.lock();
+        unsafe {
+            config.0.write(self.config_address(offset));
+            config.1.write(value);
+        }
+    }
+
+    pub fn read_u16(self, offset: u8) -> u16 {
+        (self.read(offset) >> ((offset & 2) * 8)) as u16
+    }
+
+    pub fn read_u8(self, offset: u8) -> u8 {
+        (self.read(offset) >> ((offset & 3) * 8)) as u8
+    }
+}
+
+impl fmt::Display for Address {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{:02x}:{:02x}.{}", self.bus, self.device, self.function)
+    }
+}
+
+/// Base address register contents
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub enum Bar {
+    Memory(PhysAddr),
+    Io(u16),
+}
+
+/// PCI function found during enumeration
+#[derive(Copy, Clone, Debug)]
+pub struct Device {
+    pub address: Address,
+    pub vendor_id: u16,
+    pub device_id: u16,
+    pub class: u8,
+    pub subclass: u8,
+    pub prog_if: u8,
+}
+
+impl Device {
+    fn probe(address: Address) -> Option<Self> {
+        let id = address.read(0x00);
+        if id == 0xffff_ffff {
+            return None;
+        }
+        let class = address.read(0x08);
+        Some(Self {
+            address,
+            vendor_id: id as u16,
+            device_id: (id >> 16) as u16,
+            class: (class >> 24) as u8,
+            subclass: (class >> 16) as u8,
+            prog_if: (class >> 8) as u8,
+        })
+    }
+
+    /// Decode base address register `index`
+    ///
+    /// Returns [`None`] for unimplemented registers. The register following a
+    /// 64-bit memory register holds its upper half and should not be decoded
+    /// on its own.
+    pub fn bar(&self, index: u8) -> Option<Bar> {
+        let offset = 0x10 + 4 * index;
+        let low = self.address.read(offset);
+        if low & 1 == 1 {
+            let port = (low & !0b11) as u16;
+            return if port == 0 { None } else { Some(Bar::Io(port)) };
+        }
+        let mut addr = (low & !0b1111) as u64;
+        if (low >> 1) & 0b11 == 0b10 {
+            addr |= (self.address.read(offset + 4) as u64) << 32;
+        }
+        if addr == 0 {
+            None
+        } else {
+            Some(Bar::Memory(PhysAddr::new(addr)))
+        }
+    }
+
+    /// Enable I/O space, memory space and bus mastering
+    pub fn enable(&self) {
+        let command = self.address.read(0x04);
+        self.address.write(0x04, command | 0b111);
+    }
+
+    /// Route the first MSI-X vector to `message` (address and data) and enable
+    /// MSI-X, which also disables legacy interrupts
+    pub fn enable_msix(&self, (address, data): (u64, u32)) -> Result<(), &'static str> {
+        let (_, cap) = self
+            .capabilities()
+            .find(|&(id, _)| id == CAP_MSIX)
+            .ok_or("Device does not support MSI-X")?;
+        let table = self.address.read(cap + 4);
+        let bar = match self.bar(table as u8 & 0b111) {
+            Some(Bar::Memory(addr)) => addr,
+            _ => return Err("Invalid MSI-X table location"),
+        };
+        let entry = offset::phys_to_virt(bar + (table & !0b111) as u64).as_mut_ptr::<u32>();
+        unsafe {
+            entry.write_volatile(address as u32);
+            entry.add(1).write_volatile((address >> 32) as u32);
+            entry.add(2).write_volatile(data);
+            // Unmask vector
+            entry.add(3).write_volatile(0);
+        }
+        let command = self.address.read(0x04);
+        self.address.write(0x04, command | 1 << 10);
+        let control = self.address.read(cap);
+        self.address.write(cap, (control | 1 << 31) & !(1 << 30));
+        Ok(())
+    }
+
+    /// Iterate over the capability list as `(id, offset)` pairs
+    pub fn capabilities(&self) -> Capabilities {
+        let status = self.address.read_u16(0x06);
+        let next = if status & (1 << 4) != 0 {
+            self.address.read_u8(0x34) & 0xfc
+        } else {
+            0
+        };
+        Capabilities {
+            address: self.address,
+            next,
+        }
+    }
+}
+
+impl fmt::Display for Device {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(
+            f,
+            "{} {:04x}:{:04x} class {:02x}.{:02x}.{:02x}",
+            self.address, self.vendor_id, self.device_id, self.class, self.subclass, self.prog_if
+        )
+    }
+}
+
+/// Capability id of MSI-X
+const CAP_MSIX: u8 = 0x11;
+
+/// Iterator over the capabilities of a [`Device`]
+pub struct Capabilities {
+    address: Address,
+    next: u8,
+}
+
+impl Iterator for Capabilities {
+    type Item = (u8, u8);
+
+    fn next(&mut self) -> Option<Self::Item> {
+        if self.next == 0 {
+            return None;
+        }
+        let offset = self.next;
+        let header = self.address.read_u16(offset);
+        self.next = (header >> 8) as u8 & 0xfc;
+        Some((header as u8, offset))
+    }
+}
+
+/// Scan all buses for PCI functions
+fn enumerate() -> Vec<Device> {
+    let mut devices = Vec::new();
+    for bus in 0..=255 {
+        for device in 0..32 {
+            for function in 0..8 {
+                let address = Address {
+                    bus,
+                    device,
+                    function,
+                };
+                match Device::probe(address) {
+                    Some(found) => devices.push(found),
+                    None if function == 0 => break,
+                    None => continue,
+                }
+                // Only probe other functions of multi-function devices
+                if function == 0 && address.read_u8(0x0e) & 0x80 == 0 {
+                    break;
+                }
+            }
+        }
+    }
+    devices
+}
+
+crate::initcall!(Bus, |_| init());
+
+/// Enumerate PCI functions; should be called before [`devices`]
+pub fn init() {
+    let devices = DEVICES.call_once(enumerate);
+    log::info!("Found {} PCI functions", devices.len());
+    for device in devices {
+        log::debug!("PCI {}", device);
+        let name = format!("PCI {:04x}:{:04x}", device.vendor_id, device.device_id);
+        crate::devices::add(name, vec![Resource::Pci(device.address)]);
+    }
+}
+
+/// All PCI functions found by [`init`]
+pub fn devices() -> &'static [Device] {
+    DEVICES.get().map_or(&[][..], Vec::as_slice)
+}
@@ -0,0 +1,187 @@
+//! PS/2 controller (8042) and the devices attached to it
+
+pub mod keyboard;
+pub mod mouse;
+
+use crate::{
+    devices::{self, Resource, State},
+    ports::Ports,
+};
+use alloc::vec;
+use core::hint::spin_loop;
+use spin::Mutex;
+
+/// Number of status polls before an operation is considered to have failed
+const TIMEOUT: usize = 100_000;
+
+/// Status register bits
+mod status {
+    pub const OUTPUT_FULL: u8 = 1 << 0;
+    pub const INPUT_FULL: u8 = 1 << 1;
+    pub const AUX_DATA: u8 = 1 << 5;
+}
+
+/// Controller commands
+mod command {
+    pub const READ_CONFIG: u8 = 0x20;
+    pub const WRITE_CONFIG: u8 = 0x60;
+    pub const ENABLE_AUX: u8 = 0xa8;
+    pub const ENABLE_KEYBOARD: u8 = 0xae;
+    pub const WRITE_AUX: u8 = 0xd4;
+}
+
+/// Controller configuration byte bits
+mod config {
+    pub const KEYBOARD_IRQ: u8 = 1 << 0;
+    pub const AUX_IRQ: u8 = 1 << 1;
+    pub const KEYBOARD_CLOCK_DISABLED: u8 = 1 << 4;
+    pub const AUX_CLOCK_DISABLED: u8 = 1 << 5;
+    /// Translate scancodes of the keyboard to set 1
+    pub const TRANSLATION: u8 = 1 << 6;
+}
+
+/// Acknowledgement sent by devices after each command byte
+const ACK: u8 = 0xfa;
+
+static CONTROLLER: Mutex<Controller> = Mutex::new(Controller {
+    data: Ports::new("ps2", 0x60, 1),
+    control: Ports::new("ps2", 0x64, 1),
+});
+
+struct Controller {
+    data: Ports,
+    /// Status register when read, command register when written
+    control: Ports,
+}
+
+impl Controller {
+    fn claim(&self) -> Result<(), &'static str> {
+        self.data.claim()?;
+        self.control.claim()
+    }
+
+    fn status(&mut self) -> u8 {
+        unsafe { self.control.read(0) }
+    }
+
+    /// Wait until status bit `mask` has value `set`
+    fn wait(&mut self, mask: u8, set: bool) -> Result<(), &'static str> {
+        for _ in 0..TIMEOUT {
+            if (self.status() & mask != 0) == set {
+                return Ok(());
+            }
+            spin_loop();
+        }
+        Err("PS/2 controller timed out")
+    }
+
+    fn read(&mut self) -> Result<u8, &'static str> {
+        self.wait(status::OUTPUT_FULL, true)?;
+        Ok(unsafe { self.data.read(0) })
+    }
+
+    fn write(&mut self, data: u8) -> Result<(), &'static str> {
+        self.wait(status::INPUT_FULL, false)?;
+        unsafe { self.data.write(0, data) };
+        Ok(())
+    }
+
+    fn command(&mut self, command: u8) -> Result<(), &'static str> {
+        self.wait(status::INPUT_FULL, false)?;
+        unsafe { self.control.write(0, command) };
+        Ok(())
+    }
+
+    fn read_config(&mut self) -> Result<u8, &'static str> {
+        self.command(command::READ_CONFIG)?;
+        self.read()
+    }
+
+    fn write_config(&mut self, config: u8) -> Result<(), &'static str> {
+        self.command(command::WRITE_CONFIG)?;
+        self.write(config)
+    }
+
+    /// Send byte to the auxiliary (mouse) device and wait for acknowledgement
+    fn write_aux(&mut self, data: u8) -> Result<(), &'static str> {
+        self.command(command::WRITE_AUX)?;
+        self.write(data)?;
+        match self.read()? {
+            ACK => Ok(()),
+            _ => Err("PS/2 device did not acknowledge command"),
+        }
+    }
+
+    /// Read a pending byte from the auxiliary device without waiting
+    fn read_aux(&mut self) -> Option<u8> {
+        let status = self.status();
+        if status & status::OUTPUT_FULL != 0 && status & status::AUX_DATA != 0 {
+            Some(unsafe { self.data.read(0) })
+        } else {
+            None
+        }
+    }
+
+    /// Read a pending byte from the keyboard without waiting
+    fn read_keyboard(&mut self) -> Option<u8> {
+        let status = self.status();
+        if status & status::OUTPUT_FULL != 0 && status & status::AUX_DATA == 0 {
+            Some(unsafe { self.data.read(0) })
+        } else {
+            None
+        }
+    }
+}
+
+crate::initcall!(Platform, |_| init());
+
+/// Set up the devices attached to the controller
+pub fn init() {
+    let claimed = CONTROLLER.lock().claim();
+    let resources = vec![
+        Resource::Ports(0x60, 1),
+        Resource::Ports(0x64, 1),
+        Resource::Irq(1),
+    ];
+    let id = devices::add("PS/2 keyboard".into(), resources);
+    match claimed.and_then(|()| keyboard::init()) {
+        Ok(()) => {
+            devices::bind(id, "ps2-keyboard", State::Bound);
+            devices::on_resume(id, resume_keyboard);
+        }
+        Err(e) => {
+            log::warn!("PS/2 keyboard unavailable: {}", e);
+            devices::bind(id, "ps2-keyboard", State::Failed);
+        }
+    }
+    let resources = vec![
+        Resource::Ports(0x60, 1),
+        Resource::Ports(0x64, 1),
+        Resource::Irq(12),
+    ];
+    let id = devices::add("PS/2 mouse".into(), resources);
+    match claimed.and_then(|()| mouse::init()) {
+        Ok(()) => {
+            devices::bind(id, "ps2-mouse", State::Bound);
+            devices::on_resume(id, resume_mouse);
+        }
+        Err(e) => {
+            log::warn!("PS/2 mouse unavailable: {}", e);
+            devices::bind(id, "ps2-mouse", State::Failed);
+        }
+    }
+}
+
+/// Set the keyboard up again after a sleep state
+fn resume_keyboard() {
+    if let Err(e) = keyboard::init() {
+        log::warn!("PS/2 keyboard not restored: {}", e);
+    }
+}
+
+/// Set the mouse up again after a sleep state
+fn resume_mouse() {
+    if let Err(e) = mouse::init() {
+        log::warn!("PS/2 mouse not restored: {}", e);
+    }
+}
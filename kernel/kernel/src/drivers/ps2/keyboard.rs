@@ -0,0 +1,179 @@
+//! PS/2 keyboard
+//!
+//! The controller translates whatever the keyboard sends to scancode set 1,
+//! which is decoded to USB HID usage ids and delivered as input events, like
+//! keys of USB keyboards. Modifiers are keys like any other; the console and
+//! [`input::read_key`] keep track of them.
+
+use super::{command, config, CONTROLLER};
+use crate::input;
+use spin::Mutex;
+use sys::InputEvent;
+use x86_64::instructions::interrupts;
+
+/// Prefix of scancodes of extended keys
+const EXTENDED: u8 = 0xe0;
+/// Prefix of the scancode of the pause key, which has no break code
+const PAUSE: u8 = 0xe1;
+/// Number of bytes following [`PAUSE`]
+const PAUSE_LEN: u8 = 5;
+/// Bit set in break codes, sent when a key is released
+const RELEASED: u8 = 0x80;
+
+/// USB HID usage ids of the keys with scancodes 0x00 to 0x58, or zero for
+/// unused codes
+#[rustfmt::skip]
+const USAGES: [u8; 0x59] = [
+    0x00, 0x29, 0x1e, 0x1f, 0x20, 0x21, 0x22, 0x23, // -, Esc, 1-6
+    0x24, 0x25, 0x26, 0x27, 0x2d, 0x2e, 0x2a, 0x2b, // 7-0, -, =, Backspace, Tab
+    0x14, 0x1a, 0x08, 0x15, 0x17, 0x1c, 0x18, 0x0c, // Q-I
+    0x12, 0x13, 0x2f, 0x30, 0x28, 0xe0, 0x04, 0x16, // O, P, [, ], Enter, Ctrl, A, S
+    0x07, 0x09, 0x0a, 0x0b, 0x0d, 0x0e, 0x0f, 0x33, // D-L, ;
+    0x34, 0x35, 0xe1, 0x31, 0x1d, 0x1b, 0x06, 0x19, // ', `, Shift, \, Z-V
+    0x05, 0x11, 0x10, 0x36, 0x37, 0x38, 0xe5, 0x55, // B-M, ",", ., /, Shift, KP *
+    0xe2, 0x2c, 0x39, 0x3a, 0x3b, 0x3c, 0x3d, 0x3e, // Alt, Space, Caps Lock, F1-F5
+    0x3f, 0x40, 0x41, 0x42, 0x43, 0x53, 0x47, 0x5f, // F6-F10, Num Lock, Scroll Lock, KP 7
+    0x60, 0x61, 0x56, 0x5c, 0x5d, 0x5e, 0x57, 0x59, // KP 8, 9, -, 4-6, +, 1
+    0x5a, 0x5b, 0x62, 0x63, 0x00, 0x00, 0x64, 0x44, // KP 2, 3, 0, ., -, -, non-US \, F11
+    0x45,                                           // F12
+];
+
+/// USB HID usage id of the extended key with scancode `code`
+fn extended_usage(code: u8) -> Option<u8> {
+    let usage = match code {
+        0x1c => 0x58, // Keypad Enter
+        0x1d => 0xe4, // Right Ctrl
+        0x35 => 0x54, // Keypad /
+        0x38 => 0xe6, // Right Alt
+        0x47 => 0x4a, // Home
+        0x48 => 0x52, // Up
+        0x49 => 0x4b, // Page Up
+        0x4b => 0x50, // Left
+        0x4d => 0x4f, // Right
+        0x4f => 0x4d, // End
+        0x50 => 0x51, // Down
+        0x51 => 0x4e, // Page Down
+        0x52 => 0x49, // Insert
+        0x53 => 0x4c, // Delete
+        0x5b => 0xe3, // Left GUI
+        0x5c => 0xe7, // Right GUI
+        0x5d => 0x65, // Menu
+        // Including the fake shifts surrounding Print Screen
+        _ => return None,
+    };
+    Some(usage)
+}
+
+static KEYBOARD: Mutex<Decoder> = Mutex::new(Decoder::new());
+
+/// State of decoding scancodes that span several bytes
+struct Decoder {
+    extended: bool,
+    /// Bytes of the pause key still to be skipped
+    skip: u8,
+}
+
+impl Decoder {
+    const fn new() -> Self {
+        Self {
+            extended: false,
+            skip: 0,
+        }
+    }
+
+    /// Decode the next `byte` of scancode set 1, returning the usage id of the
+    /// key once complete and whether it was pressed
+    fn receive(&mut self, byte: u8) -> Option<(u8, bool)> {
+        if self.skip > 0 {
+            self.skip -= 1;
+            return None;
+        }
+        match byte {
+            EXTENDED => {
+                self.extended = true;
+                return None;
+            }
+            PAUSE => {
+                self.skip = PAUSE_LEN;
+                return None;
+            }
+            _ => {}
+        }
+        let code = byte & !RELEASED;
+        let usage = if core::mem::take(&mut self.extended) {
+            extended_usage(code)?
+        } else {
+            *USAGES.get(code as usize).filter(|&&usage| usage != 0)?
+        };
+        Some((usage, byte & RELEASED == 0))
+    }
+}
+
+fn setup() -> Result<(), &'static str> {
+    let mut controller = CONTROLLER.lock();
+    controller.command(command::ENABLE_KEYBOARD)?;
+    let cfg = controller.read_config()? & !config::KEYBOARD_CLOCK_DISABLED;
+    // Drop whatever was typed before
+    while controller.read_keyboard().is_some() {}
+    *KEYBOARD.lock() = Decoder::new();
+    controller.write_config(cfg | config::TRANSLATION | config::KEYBOARD_IRQ)
+}
+
+/// Enable the keyboard and its interrupt
+pub fn init() -> Result<(), &'static str> {
+    interrupts::without_interrupts(setup)
+}
+
+/// Handle the keyboard interrupt (IRQ 1)
+pub fn interrupt() {
+    let byte = match CONTROLLER.lock().read_keyboard() {
+        Some(byte) => byte,
+        None => return,
+    };
+    let key = KEYBOARD.lock().receive(byte);
+    if let Some((usage, pressed)) = key {
+        input::push(InputEvent::Key { usage, pressed });
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use alloc::vec::Vec;
+
+    fn decode(bytes: &[u8]) -> Vec<(u8, bool)> {
+        let mut decoder = Decoder::new();
+        bytes
+            .iter()
+            .filter_map(|&byte| decoder.receive(byte))
+            .collect()
+    }
+
+    #[test_case]
+    fn keys() {
+        // Shift+A, then Enter
+        let keys = decode(&[0x2a, 0x1e, 0x9e, 0xaa, 0x1c, 0x9c]);
+        let expected = [
+            (0xe1, true),
+            (0x04, true),
+            (0x04, false),
+            (0xe1, false),
+            (0x28, true),
+            (0x28, false),
+        ];
+        assert_eq!(keys, expected);
+    }
+
+    #[test_case]
+    fn extended_keys() {
+        // Right Ctrl, Up, and Print Screen with its fake shifts
+        let keys = decode(&[0xe0, 0x1d, 0xe0, 0x48, 0xe0, 0xc8, 0xe0, 0x2a, 0xe0, 0x37]);
+        assert_eq!(keys, [(0xe4, true), (0x52, true), (0x52, false)]);
+    }
+
+    #[test_case]
+    fn pause() {
+        let keys = decode(&[0xe1, 0x1d, 0x45, 0xe1, 0x9d, 0xc5, 0x39]);
+        assert_eq!(keys, [(0x2c, true)]);
+    }
+}
@@ -0,0 +1,119 @@
+//! PS/2 mouse, including the scroll wheel of the IntelliMouse extension
+//!
+//! Movement moves the kernel cursor and all changes are delivered as input
+//! events.
+
+use super::{command, config, CONTROLLER};
+use crate::input;
+use spin::Mutex;
+use x86_64::instructions::interrupts;
+
+/// Mouse commands
+mod cmd {
+    pub const GET_ID: u8 = 0xf2;
+    pub const SET_SAMPLE_RATE: u8 = 0xf3;
+    pub const ENABLE_REPORTING: u8 = 0xf4;
+    pub const SET_DEFAULTS: u8 = 0xf6;
+}
+
+/// Device id reported after enabling the IntelliMouse extension
+const INTELLIMOUSE_ID: u8 = 3;
+
+/// Bits of the first byte of a packet
+mod flags {
+    pub const BUTTONS: u8 = 0b111;
+    pub const ALWAYS_SET: u8 = 1 << 3;
+    pub const X_SIGN: u8 = 1 << 4;
+    pub const Y_SIGN: u8 = 1 << 5;
+    pub const OVERFLOW: u8 = 0b11 << 6;
+}
+
+static MOUSE: Mutex<Option<Mouse>> = Mutex::new(None);
+
+struct Mouse {
+    packet: [u8; 4],
+    len: usize,
+    /// Four with the IntelliMouse extension, three otherwise
+    packet_size: usize,
+    state: input::Mouse,
+}
+
+impl Mouse {
+    /// Add a byte to the current packet and handle it if it is complete
+    fn receive(&mut self, byte: u8) {
+        // Resynchronize if the first byte is obviously wrong
+        if self.len == 0 && byte & flags::ALWAYS_SET == 0 {
+            return;
+        }
+        self.packet[self.len] = byte;
+        self.len += 1;
+        if self.len == self.packet_size {
+            self.len = 0;
+            self.handle_packet();
+        }
+    }
+
+    fn handle_packet(&mut self) {
+        let [status, x, y, extra] = self.packet;
+        let (dx, dy) = if status & flags::OVERFLOW == 0 {
+            let dx = x as i32 - if status & flags::X_SIGN != 0 { 256 } else { 0 };
+            let dy = y as i32 - if status & flags::Y_SIGN != 0 { 256 } else { 0 };
+            // The mouse reports upward movement as positive
+            (dx, -dy)
+        } else {
+            (0, 0)
+        };
+        let scroll = if self.packet_size == 4 {
+            // Sign-extend the lower four bits
+            ((extra << 4) as i8 >> 4) as i32
+        } else {
+            0
+        };
+        self.state.report(dx, dy, status & flags::BUTTONS, scroll);
+    }
+}
+
+fn setup() -> Result<(), &'static str> {
+    let mut controller = CONTROLLER.lock();
+    controller.command(command::ENABLE_AUX)?;
+    // Keep the interrupt disabled while configuring the mouse, so replies are
+    // not consumed by the interrupt handler
+    let cfg = controller.read_config()? & !config::AUX_CLOCK_DISABLED & !config::AUX_IRQ;
+    controller.write_config(cfg)?;
+
+    controller.write_aux(cmd::SET_DEFAULTS)?;
+    // Magic sequence enabling the scroll wheel
+    for &rate in &[200, 100, 80] {
+        controller.write_aux(cmd::SET_SAMPLE_RATE)?;
+        controller.write_aux(rate)?;
+    }
+    controller.write_aux(cmd::GET_ID)?;
+    let id = controller.read()?;
+    let packet_size = if id == INTELLIMOUSE_ID { 4 } else { 3 };
+    log::info!("PS/2 mouse id {} ({}-byte packets)", id, packet_size);
+    controller.write_aux(cmd::ENABLE_REPORTING)?;
+
+    *MOUSE.lock() = Some(Mouse {
+        packet: [0; 4],
+        len: 0,
+        packet_size,
+        state: input::Mouse::new(),
+    });
+    controller.write_config(cfg | config::AUX_IRQ)
+}
+
+/// Detect and configure the mouse, and enable its interrupt
+pub fn init() -> Result<(), &'static str> {
+    interrupts::without_interrupts(setup)
+}
+
+/// Handle the mouse interrupt (IRQ 12)
+pub fn interrupt() {
+    let byte = match CONTROLLER.lock().read_aux() {
+        Some(byte) => byte,
+        None => return,
+    };
+    if let Some(mouse) = MOUSE.lock().as_mut() {
+        mouse.receive(byte);
+    }
+}
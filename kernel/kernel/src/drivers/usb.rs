@@ -0,0 +1,112 @@
+//! USB host controllers and device class drivers
+
+pub mod hid;
+pub mod xhci;
+
+use alloc::vec::Vec;
+
+/// Descriptor types
+pub mod descriptor {
+    pub const CONFIGURATION: u8 = 2;
+    pub const INTERFACE: u8 = 4;
+    pub const ENDPOINT: u8 = 5;
+}
+
+/// Setup stage of a control transfer
+#[derive(Copy, Clone, Debug)]
+pub struct SetupPacket {
+    pub request_type: u8,
+    pub request: u8,
+    pub value: u16,
+    pub index: u16,
+    pub length: u16,
+}
+
+impl SetupPacket {
+    /// Direction bit of `request_type` for device-to-host transfers
+    pub const DEVICE_TO_HOST: u8 = 0x80;
+
+    /// Standard GET_DESCRIPTOR request
+    pub fn get_descriptor(ty: u8, index: u8, length: u16) -> Self {
+        Self {
+            request_type: Self::DEVICE_TO_HOST,
+            request: 6,
+            value: (ty as u16) << 8 | index as u16,
+            index: 0,
+            length,
+        }
+    }
+
+    /// Standard SET_CONFIGURATION request
+    pub fn set_configuration(value: u8) -> Self {
+        Self {
+            request_type: 0,
+            request: 9,
+            value: value as u16,
+            index: 0,
+            length: 0,
+        }
+    }
+
+    /// Whether the data stage transfers data to the host
+    pub fn is_in(&self) -> bool {
+        self.request_type & Self::DEVICE_TO_HOST != 0
+    }
+
+    /// Packet as laid out on the bus
+    pub fn to_u64(self) -> u64 {
+        self.request_type as u64
+            | (self.request as u64) << 8
+            | (self.value as u64) << 16
+            | (self.index as u64) << 32
+            | (self.length as u64) << 48
+    }
+}
+
+/// Interrupt IN endpoint of a HID boot protocol interface
+#[derive(Copy, Clone, Debug)]
+pub struct BootInterface {
+    pub interface: u8,
+    pub kind: hid::Kind,
+    /// Endpoint number, without direction bit
+    pub endpoint: u8,
+    pub max_packet_size: u16,
+    pub interval: u8,
+}
+
+/// Find the value of the configuration and the HID boot protocol interfaces in
+/// a configuration descriptor, including the subordinate descriptors
+pub fn parse_configuration(data: &[u8]) -> (u8, Vec<BootInterface>) {
+    let mut value = 0;
+    let mut interfaces = Vec::new();
+    let mut current = None;
+    let mut rest = data;
+    while rest.len() >= 2 && rest[0] >= 2 && rest[0] as usize <= rest.len() {
+        let (desc, next) = rest.split_at(rest[0] as usize);
+        rest = next;
+        match desc[1] {
+            descriptor::CONFIGURATION if desc.len() >= 9 => value = desc[5],
+            descriptor::INTERFACE if desc.len() >= 9 => {
+                current = hid::Kind::from_interface(desc[5], desc[6], desc[7])
+                    .map(|kind| (desc[2], kind));
+            }
+            descriptor::ENDPOINT if desc.len() >= 7 => {
+                if let Some((interface, kind)) = current {
+                    // Only interested in the interrupt IN endpoint
+                    if desc[2] & 0x80 != 0 && desc[3] & 0b11 == 0b11 {
+                        interfaces.push(BootInterface {
+                            interface,
+                            kind,
+                            endpoint: desc[2] & 0xf,
+                            max_packet_size: u16::from_le_bytes([desc[4], desc[5]]) & 0x7ff,
+                            interval: desc[6],
+                        });
+                        current = None;
+                    }
+                }
+            }
+            _ => {}
+        }
+    }
+    (value, interfaces)
+}
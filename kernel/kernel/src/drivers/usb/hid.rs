@@ -0,0 +1,122 @@
+//! HID keyboards and mice using the boot protocol
+//!
+//! The boot protocol has fixed report formats, so no report descriptors need
+//! to be parsed.
+
+use super::SetupPacket;
+use crate::input;
+use sys::InputEvent;
+
+/// Usage id of the left control key; modifier bits map to consecutive ids
+const MODIFIER_USAGE: u8 = 0xe0;
+/// Usage id reported in all key slots when too many keys are pressed
+const ERROR_ROLL_OVER: u8 = 0x01;
+
+/// Kind of boot protocol device
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub enum Kind {
+    Keyboard,
+    Mouse,
+}
+
+impl Kind {
+    /// Kind of a HID boot interface with the given class codes
+    pub fn from_interface(class: u8, subclass: u8, protocol: u8) -> Option<Self> {
+        match (class, subclass, protocol) {
+            (3, 1, 1) => Some(Kind::Keyboard),
+            (3, 1, 2) => Some(Kind::Mouse),
+            _ => None,
+        }
+    }
+}
+
+/// Class-specific SET_PROTOCOL request selecting the boot protocol
+pub fn set_boot_protocol(interface: u8) -> SetupPacket {
+    SetupPacket {
+        request_type: 0x21,
+        request: 0x0b,
+        value: 0,
+        index: interface as u16,
+        length: 0,
+    }
+}
+
+/// Class-specific SET_IDLE request so reports are only sent on changes
+pub fn set_idle(interface: u8) -> SetupPacket {
+    SetupPacket {
+        request_type: 0x21,
+        request: 0x0a,
+        value: 0,
+        index: interface as u16,
+        length: 0,
+    }
+}
+
+/// Boot protocol device turning reports into input events
+pub enum Device {
+    Keyboard { previous: [u8; 8] },
+    Mouse(input::Mouse),
+}
+
+impl Device {
+    pub fn new(kind: Kind) -> Self {
+        match kind {
+            Kind::Keyboard => Device::Keyboard { previous: [0; 8] },
+            Kind::Mouse => Device::Mouse(input::Mouse::new()),
+        }
+    }
+
+    pub fn report(&mut self, data: &[u8]) {
+        match self {
+            Device::Keyboard { previous } => {
+                if data.len() < 8 || data[2] == ERROR_ROLL_OVER {
+                    return;
+                }
+                keyboard_report(previous, data);
+                previous.copy_from_slice(&data[..8]);
+            }
+            Device::Mouse(mouse) => {
+                if data.len() < 3 {
+                    return;
+                }
+                let dx = data[1] as i8 as i32;
+                let dy = data[2] as i8 as i32;
+                // The wheel reports movement away from the user as positive
+                let scroll = data.get(3).map_or(0, |&wheel| -(wheel as i8 as i32));
+                mouse.report(dx, dy, data[0], scroll);
+            }
+        }
+    }
+}
+
+/// Push events for the differences between two keyboard reports
+fn keyboard_report(previous: &[u8; 8], current: &[u8]) {
+    let changed = previous[0] ^ current[0];
+    for bit in 0..8 {
+        if changed & 1 << bit != 0 {
+            input::push(InputEvent::Key {
+                usage: MODIFIER_USAGE + bit,
+                pressed: current[0] & 1 << bit != 0,
+            });
+        }
+    }
+    let (old, new) = (&previous[2..8], &current[2..8]);
+    for &usage in old
+        .iter()
+        .filter(|usage| **usage != 0 && !new.contains(usage))
+    {
+        input::push(InputEvent::Key {
+            usage,
+            pressed: false,
+        });
+    }
+    for &usage in new
+        .iter()
+        .filter(|usage| **usage != 0 && !old.contains(usage))
+    {
+        input::push(InputEvent::Key {
+            usage,
+            pressed: true,
+        });
+    }
+}
@@ -0,0 +1,821 @@
+//! xHCI USB host controller
+//!
+//! Devices attached to root hub ports at initialization are enumerated by
+//! polling the event ring. Afterwards, the controller signals transfer events
+//! of HID boot protocol devices using MSI-X. Hubs and hot-plugging are not
+//! supported.
+
+use super::{hid, parse_configuration, BootInterface, SetupPacket};
+use crate::{
+    devices::{self, State},
+    drivers::pci::{self, Bar},
+    interrupts, Init,
+};
+use alloc::vec::Vec;
+use common::boot::offset;
+use core::{hint::spin_loop, ptr, slice};
+use spin::Mutex;
+use x86_64::{
+    instructions::interrupts::without_interrupts, structures::paging::FrameAllocator, PhysAddr,
+    VirtAddr,
+};
+
+/// PCI class, subclass and programming interface of xHCI controllers
+const PCI_CLASS: (u8, u8, u8) = (0x0c, 0x03, 0x30);
+
+/// Number of TRBs in a ring occupying a single frame
+const RING_SIZE: usize = 4096 / 16;
+/// Timer ticks to wait for the controller before giving up
+const TIMEOUT_TICKS: u64 = interrupts::TIMER_FREQUENCY as u64;
+
+/// TRB types
+mod trb {
+    pub const NORMAL: u32 = 1;
+    pub const SETUP_STAGE: u32 = 2;
+    pub const DATA_STAGE: u32 = 3;
+    pub const STATUS_STAGE: u32 = 4;
+    pub const LINK: u32 = 6;
+    pub const ENABLE_SLOT: u32 = 9;
+    pub const ADDRESS_DEVICE: u32 = 11;
+    pub const CONFIGURE_ENDPOINT: u32 = 12;
+    pub const TRANSFER_EVENT: u32 = 32;
+    pub const COMMAND_COMPLETION: u32 = 33;
+}
+
+/// Bits of the control field of TRBs
+mod control {
+    pub const CYCLE: u32 = 1 << 0;
+    pub const TOGGLE_CYCLE: u32 = 1 << 1;
+    pub const INTERRUPT_SHORT_PACKET: u32 = 1 << 2;
+    pub const INTERRUPT_ON_COMPLETION: u32 = 1 << 5;
+    pub const IMMEDIATE_DATA: u32 = 1 << 6;
+    pub const DIRECTION_IN: u32 = 1 << 16;
+}
+
+/// Completion codes
+mod completion {
+    pub const SUCCESS: u32 = 1;
+    pub const SHORT_PACKET: u32 = 13;
+}
+
+/// Bits of the port status and control register
+mod portsc {
+    pub const CONNECTED: u32 = 1 << 0;
+    pub const ENABLED: u32 = 1 << 1;
+    pub const RESET: u32 = 1 << 4;
+    pub const RESET_CHANGE: u32 = 1 << 21;
+    /// Bits that are cleared by writing one, including the enabled bit
+    pub const WRITE_CLEAR: u32 = 0x00fe_0002;
+}
+
+static XHCI: Mutex<Option<Xhci>> = Mutex::new(None);
+
+/// Transfer request block
+#[repr(C)]
+#[derive(Copy, Clone, Debug, Default)]
+struct Trb {
+    parameter: u64,
+    status: u32,
+    control: u32,
+}
+
+impl Trb {
+    fn new(ty: u32, parameter: u64, status: u32, control: u32) -> Self {
+        Self {
+            parameter,
+            status,
+            control: ty << 10 | control,
+        }
+    }
+
+    fn ty(&self) -> u32 {
+        (self.control >> 10) & 0x3f
+    }
+
+    fn completion_code(&self) -> u32 {
+        self.status >> 24
+    }
+
+    fn slot(&self) -> u8 {
+        (self.control >> 24) as u8
+    }
+}
+
+/// Allocate a zeroed frame, returning its physical and virtual address
+fn allocate(init: &mut Init) -> Result<(PhysAddr, VirtAddr), &'static str> {
+    let frame = init
+        .frame_allocator
+        .allocate_frame()
+        .ok_or("No frame for xHCI structure")?;
+    let phys = frame.start_address();
+    let virt = offset::phys_to_virt(phys);
+    unsafe { ptr::write_bytes(virt.as_mut_ptr::<u8>(), 0, 4096) };
+    Ok((phys, virt))
+}
+
+/// Producer ring (command or transfer ring) ending in a link TRB
+struct Ring {
+    base: PhysAddr,
+    trbs: *mut Trb,
+    index: usize,
+    cycle: bool,
+}
+
+impl Ring {
+    fn new(init: &mut Init) -> Result<Self, &'static str> {
+        let (base, virt) = allocate(init)?;
+        let trbs = virt.as_mut_ptr::<Trb>();
+        let link = Trb::new(trb::LINK, base.as_u64(), 0, control::TOGGLE_CYCLE);
+        unsafe { trbs.add(RING_SIZE - 1).write_volatile(link) };
+        Ok(Self {
+            base,
+            trbs,
+            index: 0,
+            cycle: true,
+        })
+    }
+
+    /// Address to program as dequeue pointer, including the cycle state
+    fn pointer(&self) -> u64 {
+        self.base.as_u64() | self.cycle as u64
+    }
+
+    /// Add TRB to the ring, returning its physical address
+    fn push(&mut self, mut trb: Trb) -> u64 {
+        trb.control = trb.control & !control::CYCLE | self.cycle as u32;
+        let addr = self.base.as_u64() + 16 * self.index as u64;
+        unsafe { self.trbs.add(self.index).write_volatile(trb) };
+        self.index += 1;
+        if self.index == RING_SIZE - 1 {
+            // Hand the link TRB to the controller and wrap around
+            unsafe {
+                let link = self.trbs.add(self.index);
+                let mut trb = link.read_volatile();
+                trb.control = trb.control & !control::CYCLE | self.cycle as u32;
+                link.write_volatile(trb);
+            }
+            self.index = 0;
+            self.cycle = !self.cycle;
+        }
+        addr
+    }
+}
+
+/// Consumer ring of events with a single segment
+struct EventRing {
+    base: PhysAddr,
+    trbs: *mut Trb,
+    index: usize,
+    cycle: bool,
+}
+
+impl EventRing {
+    fn pop(&mut self) -> Option<Trb> {
+        let trb = unsafe { self.trbs.add(self.index).read_volatile() };
+        if (trb.control & control::CYCLE != 0) != self.cycle {
+            return None;
+        }
+        self.index += 1;
+        if self.index == RING_SIZE {
+            self.index = 0;
+            self.cycle = !self.cycle;
+        }
+        Some(trb)
+    }
+
+    fn dequeue_pointer(&self) -> u64 {
+        self.base.as_u64() + 16 * self.index as u64
+    }
+}
+
+/// Memory-mapped register block
+#[derive(Copy, Clone)]
+struct Registers(VirtAddr);
+
+impl Registers {
+    fn read(self, offset: u64) -> u32 {
+        unsafe { (self.0 + offset).as_ptr::<u32>().read_volatile() }
+    }
+
+    fn write(self, offset: u64, value: u32) {
+        unsafe { (self.0 + offset).as_mut_ptr::<u32>().write_volatile(value) }
+    }
+
+    fn write64(self, offset: u64, value: u64) {
+        self.write(offset, value as u32);
+        self.write(offset + 4, (value >> 32) as u32);
+    }
+}
+
+/// Operational register offsets
+mod op {
+    pub const USBCMD: u64 = 0x00;
+    pub const USBSTS: u64 = 0x04;
+    pub const CRCR: u64 = 0x18;
+    pub const DCBAAP: u64 = 0x30;
+    pub const CONFIG: u64 = 0x38;
+    pub const PORTSC: u64 = 0x400;
+
+    pub const CMD_RUN: u32 = 1 << 0;
+    pub const CMD_RESET: u32 = 1 << 1;
+    pub const CMD_INTERRUPT_ENABLE: u32 = 1 << 2;
+    pub const STS_HALTED: u32 = 1 << 0;
+    pub const STS_EVENT_INTERRUPT: u32 = 1 << 3;
+    pub const STS_NOT_READY: u32 = 1 << 11;
+}
+
+/// Interrupter register offsets, relative to the first interrupter
+mod interrupter {
+    pub const IMAN: u64 = 0x00;
+    pub const ERSTSZ: u64 = 0x08;
+    pub const ERSTBA: u64 = 0x10;
+    pub const ERDP: u64 = 0x18;
+
+    pub const IMAN_PENDING: u32 = 1 << 0;
+    pub const IMAN_ENABLE: u32 = 1 << 1;
+    pub const ERDP_BUSY: u64 = 1 << 3;
+}
+
+/// Interrupt endpoint of a HID boot protocol interface
+struct Endpoint {
+    slot: u8,
+    /// Device context index
+    dci: u8,
+    ring: Ring,
+    buffer: PhysAddr,
+    max_packet_size: u16,
+    device: hid::Device,
+}
+
+struct Xhci {
+    op: Registers,
+    interrupter: Registers,
+    doorbells: Registers,
+    ports: u8,
+    /// Size of a context in bytes (32 or 64)
+    context_size: u64,
+    dcbaa: *mut u64,
+    commands: Ring,
+    events: EventRing,
+    endpoints: Vec<Endpoint>,
+}
+
+// Safe because the controller is only accessed while holding the lock
+unsafe impl Send for Xhci {}
+
+/// Wait until `f` returns true, giving up after [`TIMEOUT_TICKS`]
+fn wait(mut f: impl FnMut() -> bool) -> Result<(), &'static str> {
+    let start = interrupts::ticks();
+    while !f() {
+        if interrupts::ticks() - start > TIMEOUT_TICKS {
+            return Err("xHCI controller timed out");
+        }
+        spin_loop();
+    }
+    Ok(())
+}
+
+impl Xhci {
+    /// Reset the controller and set up the data structures shared with it
+    fn new(init: &mut Init, base: VirtAddr) -> Result<Self, &'static str> {
+        let cap = Registers(base);
+        let cap_length = cap.read(0x00) & 0xff;
+        let params1 = cap.read(0x04);
+        let params2 = cap.read(0x08);
+        let cc_params = cap.read(0x10);
+        let op = Registers(base + cap_length as u64);
+        let interrupter = Registers(base + (cap.read(0x18) & !0x1f) as u64 + 0x20);
+        let doorbells = Registers(base + (cap.read(0x14) & !0b11) as u64);
+
+        take_ownership(base, cc_params)?;
+        op.write(op::USBCMD, op.read(op::USBCMD) & !op::CMD_RUN);
+        wait(|| op.read(op::USBSTS) & op::STS_HALTED != 0)?;
+        op.write(op::USBCMD, op::CMD_RESET);
+        wait(|| {
+            op.read(op::USBCMD) & op::CMD_RESET == 0 && op.read(op::USBSTS) & op::STS_NOT_READY == 0
+        })?;
+
+        let slots = params1 & 0xff;
+        op.write(op::CONFIG, slots);
+        let (dcbaa_phys, dcbaa) = allocate(init)?;
+        let dcbaa = dcbaa.as_mut_ptr::<u64>();
+        let scratchpads = (params2 >> 27 & 0x1f) | (params2 >> 21 & 0x1f) << 5;
+        if scratchpads > 0 {
+            if scratchpads > 512 {
+                return Err("Too many xHCI scratchpad buffers");
+            }
+            let (array_phys, array) = allocate(init)?;
+            for i in 0..scratchpads as usize {
+                let (buffer, _) = allocate(init)?;
+                unsafe {
+                    array
+                        .as_mut_ptr::<u64>()
+                        .add(i)
+                        .write_volatile(buffer.as_u64())
+                };
+            }
+            unsafe { dcbaa.write_volatile(array_phys.as_u64()) };
+        }
+        op.write64(op::DCBAAP, dcbaa_phys.as_u64());
+
+        let commands = Ring::new(init)?;
+        op.write64(op::CRCR, commands.pointer());
+
+        let (events_phys, events_virt) = allocate(init)?;
+        let (table_phys, table) = allocate(init)?;
+        unsafe {
+            let table = table.as_mut_ptr::<u64>();
+            table.write_volatile(events_phys.as_u64());
+            table.add(1).write_volatile(RING_SIZE as u64);
+        }
+        interrupter.write(interrupter::ERSTSZ, 1);
+        interrupter.write64(interrupter::ERDP, events_phys.as_u64());
+        interrupter.write64(interrupter::ERSTBA, table_phys.as_u64());
+
+        op.write(op::USBCMD, op::CMD_RUN);
+        wait(|| op.read(op::USBSTS) & op::STS_HALTED == 0)?;
+
+        Ok(Self {
+            op,
+            interrupter,
+            doorbells,
+            ports: (params1 >> 24) as u8,
+            context_size: if cc_params & (1 << 2) != 0 { 64 } else { 32 },
+            dcbaa,
+            commands,
+            events: EventRing {
+                base: events_phys,
+                trbs: events_virt.as_mut_ptr(),
+                index: 0,
+                cycle: true,
+            },
+            endpoints: Vec::new(),
+        })
+    }
+
+    fn ring_doorbell(&self, slot: u8, target: u8) {
+        self.doorbells.write(4 * slot as u64, target as u32);
+    }
+
+    /// Poll the event ring until an event matching `f` arrives
+    ///
+    /// Other events are discarded.
+    fn wait_event(&mut self, f: impl Fn(&Trb) -> bool) -> Result<Trb, &'static str> {
+        let mut found = None;
+        wait(|| {
+            while let Some(event) = self.events.pop() {
+                if f(&event) {
+                    found = Some(event);
+                    return true;
+                }
+                log::trace!("Ignoring xHCI event {:?}", event);
+            }
+            false
+        })?;
+        self.interrupter.write64(
+            interrupter::ERDP,
+            self.events.dequeue_pointer() | interrupter::ERDP_BUSY,
+        );
+        found.ok_or("xHCI event missing")
+    }
+
+    /// Execute a command and return its completion event
+    fn command(&mut self, command: Trb) -> Result<Trb, &'static str> {
+        let addr = self.commands.push(command);
+        self.ring_doorbell(0, 0);
+        let event = self
+            .wait_event(|event| event.ty() == trb::COMMAND_COMPLETION && event.parameter == addr)?;
+        if event.completion_code() == completion::SUCCESS {
+            Ok(event)
+        } else {
+            log::debug!("xHCI command failed: {:?}", event);
+            Err("xHCI command failed")
+        }
+    }
+
+    /// Perform a control transfer on the default endpoint of `slot`
+    ///
+    /// The data stage uses `buffer`, which should be large enough.
+    fn control(
+        &mut self,
+        slot: u8,
+        ep0: &mut Ring,
+        setup: SetupPacket,
+        buffer: PhysAddr,
+    ) -> Result<(), &'static str> {
+        let has_data = setup.length > 0;
+        let transfer_type = match (has_data, setup.is_in()) {
+            (false, _) => 0,
+            (true, false) => 2,
+            (true, true) => 3,
+        };
+        ep0.push(Trb::new(
+            trb::SETUP_STAGE,
+            setup.to_u64(),
+            8,
+            control::IMMEDIATE_DATA | transfer_type << 16,
+        ));
+        if has_data {
+            let direction = if setup.is_in() {
+                control::DIRECTION_IN
+            } else {
+                0
+            };
+            ep0.push(Trb::new(
+                trb::DATA_STAGE,
+                buffer.as_u64(),
+                setup.length as u32,
+                direction,
+            ));
+        }
+        // Status stage is in the opposite direction of the data stage
+        let direction = if has_data && setup.is_in() {
+            0
+        } else {
+            control::DIRECTION_IN
+        };
+        let addr = ep0.push(Trb::new(
+            trb::STATUS_STAGE,
+            0,
+            0,
+            direction | control::INTERRUPT_ON_COMPLETION,
+        ));
+        self.ring_doorbell(slot, 1);
+        let event =
+            self.wait_event(|event| event.ty() == trb::TRANSFER_EVENT && event.parameter == addr)?;
+        match event.completion_code() {
+            completion::SUCCESS | completion::SHORT_PACKET => Ok(()),
+            _ => Err("USB control transfer failed"),
+        }
+    }
+
+    /// Reset port `port` (one-based) if necessary and return its speed
+    fn reset_port(&mut self, port: u8) -> Result<Option<u32>, &'static str> {
+        let offset = op::PORTSC + 0x10 * (port as u64 - 1);
+        let status = self.op.read(offset);
+        if status & portsc::CONNECTED == 0 {
+            return Ok(None);
+        }
+        // USB 3 ports are enabled automatically, USB 2 ports need a reset
+        if status & portsc::ENABLED == 0 {
+            let op = self.op;
+            op.write(offset, status & !portsc::WRITE_CLEAR | portsc::RESET);
+            wait(|| op.read(offset) & portsc::RESET_CHANGE != 0)?;
+            let status = op.read(offset);
+            op.write(offset, status & !portsc::WRITE_CLEAR | portsc::RESET_CHANGE);
+            if status & portsc::ENABLED == 0 {
+                return Err("USB port not enabled after reset");
+            }
+        }
+        Ok(Some((self.op.read(offset) >> 10) & 0xf))
+    }
+
+    /// Address the device on port `port` and set up its HID boot interfaces
+    fn setup_device(&mut self, init: &mut Init, port: u8, speed: u32) -> Result<(), &'static str> {
+        let id = self.command(Trb::new(trb::ENABLE_SLOT, 0, 0, 0))?.slot();
+        let (output_phys, _) = allocate(init)?;
+        unsafe {
+            self.dcbaa
+                .add(id as usize)
+                .write_volatile(output_phys.as_u64())
+        };
+        let (input_phys, input) = allocate(init)?;
+        let mut ep0 = Ring::new(init)?;
+        let (buffer_phys, buffer) = allocate(init)?;
+
+        let max_packet_size = match speed {
+            // Full and low speed; should be corrected using the device
+            // descriptor for full speed devices, but eight bytes always works
+            1 | 2 => 8,
+            3 => 64,
+            _ => 512,
+        };
+        let mut slot = Slot {
+            id,
+            speed,
+            port,
+            input: InputContext {
+                base: input,
+                phys: input_phys,
+                size: self.context_size,
+            },
+            context_entries: 1,
+        };
+        slot.prepare_input(0b11);
+        slot.input
+            .set_endpoint(1, 4, max_packet_size, 0, ep0.pointer());
+        self.command(Trb::new(
+            trb::ADDRESS_DEVICE,
+            input_phys.as_u64(),
+            0,
+            (id as u32) << 24,
+        ))?;
+
+        let mut setup = SetupPacket::get_descriptor(super::descriptor::CONFIGURATION, 0, 9);
+        self.control(id, &mut ep0, setup, buffer_phys)?;
+        let total = unsafe { buffer.as_ptr::<u16>().add(1).read_volatile() };
+        setup.length = total.min(4096);
+        self.control(id, &mut ep0, setup, buffer_phys)?;
+        let data = unsafe { slice::from_raw_parts(buffer.as_ptr::<u8>(), setup.length as usize) };
+        let (configuration, interfaces) = parse_configuration(data);
+        if interfaces.is_empty() {
+            log::debug!("USB device on port {} has no HID boot interfaces", port);
+            return Ok(());
+        }
+        self.control(
+            id,
+            &mut ep0,
+            SetupPacket::set_configuration(configuration),
+            buffer_phys,
+        )?;
+        for interface in interfaces {
+            self.setup_interface(init, &mut slot, &interface)?;
+            // Devices that fail to switch keep using the report protocol, which
+            // often has the same layout
+            let request = hid::set_boot_protocol(interface.interface);
+            if self.control(id, &mut ep0, request, buffer_phys).is_err() {
+                log::warn!("Failed to select boot protocol for {:?}", interface.kind);
+            }
+            // Optional request
+            let request = hid::set_idle(interface.interface);
+            let _ = self.control(id, &mut ep0, request, buffer_phys);
+            log::info!("USB {:?} on port {}", interface.kind, port);
+        }
+        Ok(())
+    }
+
+    /// Configure the interrupt endpoint of a HID interface
+    ///
+    /// Reports are requested after all devices are set up by [`Xhci::start`].
+    fn setup_interface(
+        &mut self,
+        init: &mut Init,
+        slot: &mut Slot,
+        interface: &BootInterface,
+    ) -> Result<(), &'static str> {
+        let dci = 2 * interface.endpoint + 1;
+        // Interval in units of 125 µs as power of two
+        let interval = if slot.speed >= 3 {
+            interface.interval.saturating_sub(1).min(15) as u32
+        } else {
+            let frames = (interface.interval.max(1) as u32 * 8).next_power_of_two();
+            frames.trailing_zeros().clamp(3, 10)
+        };
+        let ring = Ring::new(init)?;
+        slot.context_entries = slot.context_entries.max(dci as u32);
+        slot.prepare_input(1 | 1 << dci);
+        slot.input.set_endpoint(
+            dci as u64,
+            7,
+            interface.max_packet_size as u32,
+            interval,
+            ring.pointer(),
+        );
+        self.command(Trb::new(
+            trb::CONFIGURE_ENDPOINT,
+            slot.input.phys.as_u64(),
+            0,
+            (slot.id as u32) << 24,
+        ))?;
+        let (buffer, _) = allocate(init)?;
+        self.endpoints.push(Endpoint {
+            slot: slot.id,
+            dci,
+            ring,
+            buffer,
+            max_packet_size: interface.max_packet_size,
+            device: hid::Device::new(interface.kind),
+        });
+        Ok(())
+    }
+
+    /// Request the next report of an interrupt endpoint
+    fn queue_transfer(&self, endpoint: &mut Endpoint) {
+        endpoint.ring.push(Trb::new(
+            trb::NORMAL,
+            endpoint.buffer.as_u64(),
+            endpoint.max_packet_size as u32,
+            control::INTERRUPT_SHORT_PACKET | control::INTERRUPT_ON_COMPLETION,
+        ));
+        self.ring_doorbell(endpoint.slot, endpoint.dci);
+    }
+
+    /// Enable interrupts and request the first report of all endpoints
+    fn start(&mut self) {
+        self.interrupter.write(
+            interrupter::IMAN,
+            interrupter::IMAN_ENABLE | interrupter::IMAN_PENDING,
+        );
+        let command = self.op.read(op::USBCMD);
+        self.op
+            .write(op::USBCMD, command | op::CMD_INTERRUPT_ENABLE);
+        let mut endpoints = core::mem::take(&mut self.endpoints);
+        for endpoint in &mut endpoints {
+            self.queue_transfer(endpoint);
+        }
+        self.endpoints = endpoints;
+    }
+
+    /// Disable interrupts and halt the controller
+    fn stop(&mut self) -> Result<(), &'static str> {
+        self.interrupter
+            .write(interrupter::IMAN, interrupter::IMAN_PENDING);
+        let command = self.op.read(op::USBCMD);
+        self.op.write(
+            op::USBCMD,
+            command & !(op::CMD_RUN | op::CMD_INTERRUPT_ENABLE),
+        );
+        let op = &self.op;
+        wait(|| op.read(op::USBSTS) & op::STS_HALTED != 0)
+    }
+
+    /// Handle pending transfer events
+    fn handle_events(&mut self) {
+        self.op.write(op::USBSTS, op::STS_EVENT_INTERRUPT);
+        self.interrupter.write(
+            interrupter::IMAN,
+            interrupter::IMAN_ENABLE | interrupter::IMAN_PENDING,
+        );
+        while let Some(event) = self.events.pop() {
+            if event.ty() != trb::TRANSFER_EVENT {
+                log::trace!("Ignoring xHCI event {:?}", event);
+                continue;
+            }
+            let dci = ((event.control >> 16) & 0x1f) as u8;
+            let i = match self
+                .endpoints
+                .iter()
+                .position(|ep| ep.slot == event.slot() && ep.dci == dci)
+            {
+                Some(i) => i,
+                None => continue,
+            };
+            let mut endpoint = self.endpoints.swap_remove(i);
+            match event.completion_code() {
+                completion::SUCCESS | completion::SHORT_PACKET => {
+                    let remaining = (event.status & 0xff_ffff) as usize;
+                    let len = (endpoint.max_packet_size as usize).saturating_sub(remaining);
+                    let data = unsafe {
+                        slice::from_raw_parts(
+                            offset::phys_to_virt(endpoint.buffer).as_ptr::<u8>(),
+                            len,
+                        )
+                    };
+                    endpoint.device.report(data);
+                }
+                code => common::log_rate_limited!(
+                    10,
+                    log::Level::Warn,
+                    "USB transfer failed with code {}",
+                    code
+                ),
+            }
+            self.queue_transfer(&mut endpoint);
+            self.endpoints.push(endpoint);
+        }
+        self.interrupter.write64(
+            interrupter::ERDP,
+            self.events.dequeue_pointer() | interrupter::ERDP_BUSY,
+        );
+    }
+}
+
+/// Device slot being set up
+struct Slot {
+    id: u8,
+    speed: u32,
+    /// Root hub port number (one-based)
+    port: u8,
+    input: InputContext,
+    /// Highest device context index in use
+    context_entries: u32,
+}
+
+impl Slot {
+    /// Clear the input context and fill in the control and slot contexts
+    fn prepare_input(&self, add_flags: u32) {
+        self.input.clear();
+        self.input.set(0, 1, add_flags);
+        self.input
+            .set(1, 0, self.speed << 20 | self.context_entries << 27);
+        self.input.set(1, 1, (self.port as u32) << 16);
+    }
+}
+
+/// Input context used for address device and configure endpoint commands
+struct InputContext {
+    base: VirtAddr,
+    phys: PhysAddr,
+    size: u64,
+}
+
+impl InputContext {
+    /// Set dword `dword` of context `index`, where context zero is the input
+    /// control context and context one the slot context
+    fn set(&self, index: u64, dword: u64, value: u32) {
+        let addr = self.base + index * self.size + 4 * dword;
+        unsafe { addr.as_mut_ptr::<u32>().write_volatile(value) };
+    }
+
+    fn clear(&self) {
+        unsafe { ptr::write_bytes(self.base.as_mut_ptr::<u8>(), 0, 4096) };
+    }
+
+    /// Set up the context of endpoint `dci`
+    fn set_endpoint(&self, dci: u64, ty: u32, max_packet_size: u32, interval: u32, ring: u64) {
+        let index = dci + 1;
+        self.set(index, 0, interval << 16);
+        // Three retries on errors
+        self.set(index, 1, 3 << 1 | ty << 3 | max_packet_size << 16);
+        self.set(index, 2, ring as u32);
+        self.set(index, 3, (ring >> 32) as u32);
+        self.set(index, 4, max_packet_size << 16 | max_packet_size.min(8));
+    }
+}
+
+/// Perform the BIOS to OS handoff using the USB legacy support capability
+fn take_ownership(base: VirtAddr, cc_params: u32) -> Result<(), &'static str> {
+    let mut offset = ((cc_params >> 16) as u64) << 2;
+    while offset != 0 {
+        let regs = Registers(base + offset);
+        let cap = regs.read(0);
+        if cap & 0xff == 1 {
+            regs.write(0, cap | 1 << 24);
+            return wait(|| regs.read(0) & (1 << 16) == 0);
+        }
+        offset = match (cap >> 8) & 0xff {
+            0 => 0,
+            next => offset + ((next as u64) << 2),
+        };
+    }
+    Ok(())
+}
+
+fn setup(init: &mut Init, device: &pci::Device) -> Result<Xhci, &'static str> {
+    let base = match device.bar(0) {
+        Some(Bar::Memory(addr)) => offset::phys_to_virt(addr),
+        _ => return Err("xHCI registers not memory mapped"),
+    };
+    device.enable();
+    let mut xhci = Xhci::new(init, base)?;
+    for port in 1..=xhci.ports {
+        match xhci.reset_port(port) {
+            Ok(Some(speed)) => {
+                if let Err(e) = xhci.setup_device(init, port, speed) {
+                    log::warn!("Failed to set up USB device on port {}: {}", port, e);
+                }
+            }
+            Ok(None) => {}
+            Err(e) => log::warn!("Failed to reset USB port {}: {}", port, e),
+        }
+    }
+    device.enable_msix(interrupts::msi_message(interrupts::USB_INTERRUPT_ID))?;
+    Ok(xhci)
+}
+
+crate::initcall!(Device, init);
+
+/// Set up the first xHCI controller, if any, and its HID boot devices
+pub fn init(init: &mut Init) {
+    let device = match pci::devices()
+        .iter()
+        .find(|device| (device.class, device.subclass, device.prog_if) == PCI_CLASS)
+    {
+        Some(device) => device,
+        None => return,
+    };
+    log::info!("xHCI controller at {}", device.address);
+    match setup(init, device) {
+        // The interrupt handler needs the lock, so it should not interrupt
+        Ok(xhci) => {
+            without_interrupts(|| {
+                let mut guard = XHCI.lock();
+                *guard = Some(xhci);
+                guard.as_mut().unwrap().start();
+            });
+            devices::bind_pci(device.address, "xhci", State::Bound);
+            devices::on_quiesce(device.address, quiesce);
+        }
+        Err(e) => {
+            log::warn!("xHCI controller unavailable: {}", e);
+            devices::bind_pci(device.address, "xhci", State::Failed);
+        }
+    }
+}
+
+/// Halt the xHCI controller, if any
+fn quiesce() {
+    let xhci = without_interrupts(|| XHCI.lock().take());
+    if let Some(mut xhci) = xhci {
+        if let Err(e) = xhci.stop() {
+            log::warn!("Failed to halt xHCI controller: {}", e);
+        }
+    }
+}
+
+/// Handle the interrupt of the controller
+pub fn interrupt() {
+    if let Some(xhci) = XHCI.lock().as_mut() {
+        xhci.handle_events();
+    }
+}
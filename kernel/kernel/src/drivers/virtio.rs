@@ -0,0 +1,311 @@
+//! Virtio devices using the modern PCI transport
+//!
+//! Only the parts of the specification needed by the drivers in this module are
+//! implemented. Requests are performed synchronously by polling the used ring,
+//! so no interrupts are involved.
+
+pub mod balloon;
+pub mod console;
+pub mod gpu;
+pub mod net;
+pub mod ninep;
+
+use super::pci::{self, Bar, Device};
+use common::{
+    boot::offset,
+    mmio::{self, ReadOnly, ReadWrite},
+};
+use core::{
+    hint::spin_loop,
+    ptr,
+    sync::atomic::{fence, Ordering},
+};
+use x86_64::{
+    structures::paging::{FrameAllocator, Size4KiB},
+    PhysAddr, VirtAddr,
+};
+
+/// PCI vendor id of virtio devices
+const VENDOR_ID: u16 = 0x1af4;
+/// PCI device ids of modern devices are this value plus the device type
+const MODERN_DEVICE_ID: u16 = 0x1040;
+
+/// Feature bit indicating compliance with virtio 1.0 and later
+const F_VERSION_1: u64 = 1 << 32;
+
+/// Device status bits
+mod status {
+    pub const ACKNOWLEDGE: u8 = 1;
+    pub const DRIVER: u8 = 2;
+    pub const DRIVER_OK: u8 = 4;
+    pub const FEATURES_OK: u8 = 8;
+    pub const FAILED: u8 = 128;
+}
+
+/// Types of the vendor-specific PCI capabilities describing the transport
+mod cap {
+    pub const ID: u8 = 0x09;
+    pub const COMMON_CFG: u8 = 1;
+    pub const NOTIFY_CFG: u8 = 2;
+    pub const DEVICE_CFG: u8 = 4;
+}
+
+/// Common configuration structure
+#[repr(C)]
+struct CommonCfg {
+    device_feature_select: ReadWrite<u32>,
+    device_feature: ReadOnly<u32>,
+    driver_feature_select: ReadWrite<u32>,
+    driver_feature: ReadWrite<u32>,
+    msix_config: ReadWrite<u16>,
+    num_queues: ReadOnly<u16>,
+    device_status: ReadWrite<u8>,
+    config_generation: ReadOnly<u8>,
+    queue_select: ReadWrite<u16>,
+    queue_size: ReadWrite<u16>,
+    queue_msix_vector: ReadWrite<u16>,
+    queue_enable: ReadWrite<u16>,
+    queue_notify_off: ReadOnly<u16>,
+    queue_desc: ReadWrite<u64>,
+    queue_driver: ReadWrite<u64>,
+    queue_device: ReadWrite<u64>,
+}
+
+/// Virtio device type of a PCI function, if it is a virtio device
+fn device_type(device: &Device) -> Option<u16> {
+    if device.vendor_id != VENDOR_ID {
+        None
+    } else if device.device_id >= MODERN_DEVICE_ID {
+        Some(device.device_id - MODERN_DEVICE_ID)
+    } else {
+        // Transitional devices store the type as subsystem id
+        Some(device.address.read_u16(0x2e))
+    }
+}
+
+/// Find all virtio devices of type `ty`
+pub fn find(ty: u16) -> impl Iterator<Item = &'static Device> {
+    pci::devices()
+        .iter()
+        .filter(move |device| device_type(device) == Some(ty))
+}
+
+/// Configuration structures of a device
+pub struct Transport {
+    common: &'static CommonCfg,
+    notify: VirtAddr,
+    notify_multiplier: u32,
+    device: Option<VirtAddr>,
+}
+
+// Safe because the configuration structures are only accessed via `&mut self`
+unsafe impl Send for Transport {}
+
+impl Transport {
+    /// Locate the configuration structures of a device
+    ///
+    /// Also enables bus mastering for the device.
+    pub fn new(device: &Device) -> Result<Self, &'static str> {
+        let mut common = None;
+        let mut notify = None;
+        let mut config = None;
+        for (id, cap) in device.capabilities() {
+            if id != cap::ID {
+                continue;
+            }
+            let address = device.address;
+            let bar = match device.bar(address.read_u8(cap + 4)) {
+                Some(Bar::Memory(addr)) => addr,
+                _ => continue,
+            };
+            let addr = offset::phys_to_virt(bar + address.read(cap + 8) as u64);
+            match address.read_u8(cap + 3) {
+                cap::COMMON_CFG => common = Some(addr),
+                cap::NOTIFY_CFG => notify = Some((addr, address.read(cap + 16))),
+                cap::DEVICE_CFG => config = Some(addr),
+                _ => {}
+            }
+        }
+        let common = common.ok_or("Virtio common configuration missing")?;
+        let (notify, notify_multiplier) = notify.ok_or("Virtio notify configuration missing")?;
+        device.enable();
+        Ok(Self {
+            // Safe because the capability locates the structure in a memory BAR
+            common: unsafe { mmio::block(common) },
+            notify,
+            notify_multiplier,
+            device: config,
+        })
+    }
+
+    /// Reset the device and negotiate features
+    ///
+    /// Returns the accepted subset of `features`. The device should be set up
+    /// further (e.g. queues) before calling [`Transport::finish_init`].
+    pub fn init(&mut self, features: u64) -> Result<u64, &'static str> {
+        self.reset();
+        self.common
+            .device_status
+            .write(status::ACKNOWLEDGE | status::DRIVER);
+        let mut offered = 0;
+        for select in 0..2 {
+            self.common.device_feature_select.write(select);
+            offered |= (self.common.device_feature.read() as u64) << (32 * select);
+        }
+        let accepted = offered & (features | F_VERSION_1);
+        if accepted & F_VERSION_1 == 0 {
+            self.common.device_status.write(status::FAILED);
+            return Err("Virtio device does not support version 1");
+        }
+        for select in 0..2 {
+            self.common.driver_feature_select.write(select);
+            self.common
+                .driver_feature
+                .write((accepted >> (32 * select)) as u32);
+        }
+        let device_status = self.common.device_status.read() | status::FEATURES_OK;
+        self.common.device_status.write(device_status);
+        if self.common.device_status.read() & status::FEATURES_OK == 0 {
+            self.common.device_status.write(status::FAILED);
+            return Err("Virtio device rejected features");
+        }
+        Ok(accepted & !F_VERSION_1)
+    }
+
+    /// Reset the device, which stops it from using its queues
+    pub fn reset(&mut self) {
+        self.common.device_status.write(0);
+        while self.common.device_status.read() != 0 {
+            spin_loop();
+        }
+    }
+
+    /// Signal the device that the driver is ready
+    pub fn finish_init(&mut self) {
+        let device_status = self.common.device_status.read() | status::DRIVER_OK;
+        self.common.device_status.write(device_status);
+    }
+
+    /// Set up and enable queue `index`
+    pub fn queue<A>(&mut self, index: u16, all: &mut A) -> Result<Queue, &'static str>
+    where
+        A: FrameAllocator<Size4KiB>,
+    {
+        self.common.queue_select.write(index);
+        let size = self.common.queue_size.read().min(Queue::MAX_SIZE);
+        if size == 0 {
+            return Err("Virtio queue not available");
+        }
+        self.common.queue_size.write(size);
+        let frame = all.allocate_frame().ok_or("No frame for virtio queue")?;
+        let base = frame.start_address();
+        let virt = offset::phys_to_virt(base);
+        unsafe { ptr::write_bytes(virt.as_mut_ptr::<u8>(), 0, 4096) };
+        self.common.queue_desc.write(base.as_u64());
+        self.common
+            .queue_driver
+            .write(base.as_u64() + Queue::AVAIL_OFFSET);
+        self.common
+            .queue_device
+            .write(base.as_u64() + Queue::USED_OFFSET);
+        let notify_offset = self.common.queue_notify_off.read() as u64;
+        let notify = self.notify + notify_offset * self.notify_multiplier as u64;
+        self.common.queue_enable.write(1);
+        Ok(Queue {
+            index,
+            size,
+            desc: virt.as_mut_ptr(),
+            avail: (virt + Queue::AVAIL_OFFSET).as_mut_ptr(),
+            used: (virt + Queue::USED_OFFSET).as_mut_ptr(),
+            notify: notify.as_mut_ptr(),
+            next_avail: 0,
+            last_used: 0,
+        })
+    }
+
+    /// Pointer to the device-specific configuration structure, if any
+    pub fn device_config<T>(&self) -> Option<*mut T> {
+        self.device.map(VirtAddr::as_mut_ptr)
+    }
+}
+
+/// Entry of the descriptor table
+#[repr(C)]
+struct Descriptor {
+    addr: u64,
+    len: u32,
+    flags: u16,
+    next: u16,
+}
+
+/// Split virtqueue stored in a single frame
+///
+/// The frame contains the descriptor table, followed by the available and used
+/// rings at fixed offsets.
+pub struct Queue {
+    index: u16,
+    size: u16,
+    desc: *mut Descriptor,
+    /// Flags, index and ring of the available ring
+    avail: *mut u16,
+    /// Flags and index of the used ring, followed by its elements
+    used: *mut u16,
+    notify: *mut u16,
+    next_avail: u16,
+    last_used: u16,
+}
+
+// Safe because the rings are only accessed via `&mut self`
+unsafe impl Send for Queue {}
+
+impl Queue {
+    const MAX_SIZE: u16 = 64;
+    const AVAIL_OFFSET: u64 = 1024;
+    const USED_OFFSET: u64 = 2048;
+
+    const DESC_F_NEXT: u16 = 1;
+    const DESC_F_WRITE: u16 = 2;
+
+    /// Submit a chain of buffers and wait until the device has used it
+    ///
+    /// Buffers are described by physical address, length, and whether the
+    /// device may write to it. Returns the number of bytes written by the
+    /// device.
+    ///
+    /// # Safety
+    /// The buffers should be valid for the device to access as described until
+    /// this function returns.
+    pub unsafe fn submit(&mut self, buffers: &[(PhysAddr, u32, bool)]) -> u32 {
+        assert!(!buffers.is_empty() && buffers.len() <= self.size as usize);
+        for (i, &(addr, len, write)) in buffers.iter().enumerate() {
+            let mut flags = if write { Self::DESC_F_WRITE } else { 0 };
+            if i + 1 < buffers.len() {
+                flags |= Self::DESC_F_NEXT;
+            }
+            self.desc.add(i).write_volatile(Descriptor {
+                addr: addr.as_u64(),
+                len,
+                flags,
+                next: i as u16 + 1,
+            });
+        }
+        // Only a single chain is in flight, always starting at descriptor zero
+        let slot = self.next_avail % self.size;
+        self.avail.add(2 + slot as usize).write_volatile(0);
+        fence(Ordering::SeqCst);
+        self.next_avail = self.next_avail.wrapping_add(1);
+        self.avail.add(1).write_volatile(self.next_avail);
+        fence(Ordering::SeqCst);
+        self.notify.write_volatile(self.index);
+
+        while self.used.add(1).read_volatile() == self.last_used {
+            spin_loop();
+        }
+        fence(Ordering::SeqCst);
+        let slot = self.last_used % self.size;
+        self.last_used = self.last_used.wrapping_add(1);
+        // Skip flags/index and the id field of the used element
+        let len = self.used.add(2 + 4 * slot as usize + 2).cast::<u32>();
+        len.read_volatile()
+    }
+}
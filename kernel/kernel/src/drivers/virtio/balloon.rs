@@ -0,0 +1,199 @@
+//! Virtio memory balloon device
+//!
+//! The host asks for memory back by raising the number of pages it wants in the
+//! balloon. The driver inflates the balloon by taking frames from the frame
+//! allocator and telling the host their page numbers, after which the host may
+//! reuse them. Deflating tells the host the frames are used again and gives
+//! them back to the frame allocator. There are no interrupts, so changes of the
+//! target are picked up by [`poll`].
+
+use super::{find, Queue, Transport};
+use crate::{
+    devices::{self, State},
+    drivers::pci::Device,
+    Init,
+};
+use alloc::vec::Vec;
+use common::boot::offset;
+use core::{mem::size_of, ptr};
+use spin::Mutex;
+use x86_64::structures::paging::{FrameAllocator, FrameDeallocator, PhysFrame};
+
+/// Virtio device type of balloon devices
+const DEVICE_TYPE: u16 = 5;
+const INFLATE_QUEUE: u16 = 0;
+const DEFLATE_QUEUE: u16 = 1;
+/// Maximum number of page numbers per request, as many as fit in a frame
+const BATCH: usize = 4096 / size_of::<u32>();
+
+static BALLOON: Mutex<Option<Balloon>> = Mutex::new(None);
+
+/// Device-specific configuration structure
+#[repr(C)]
+struct Config {
+    /// Number of pages the host wants in the balloon
+    num_pages: u32,
+    /// Number of pages in the balloon, reported by the driver
+    actual: u32,
+}
+
+struct Balloon {
+    transport: Transport,
+    inflate: Queue,
+    deflate: Queue,
+    /// Frame holding the page numbers of a request
+    request: PhysFrame,
+    /// Frames given to the host
+    frames: Vec<PhysFrame>,
+}
+
+impl Balloon {
+    fn config(&self) -> *mut Config {
+        // Checked to exist in setup
+        self.transport.device_config().unwrap()
+    }
+
+    fn target(&self) -> usize {
+        unsafe { ptr::addr_of!((*self.config()).num_pages).read_volatile() as usize }
+    }
+
+    fn report(&self) {
+        let actual = self.frames.len() as u32;
+        unsafe { ptr::addr_of_mut!((*self.config()).actual).write_volatile(actual) };
+    }
+
+    /// Tell the host the page numbers of `frames` through the queue of
+    /// `inflate` or the deflate queue
+    fn send(&mut self, inflate: bool, frames: &[PhysFrame]) {
+        let addr = self.request.start_address();
+        let numbers = offset::phys_to_virt(addr).as_mut_ptr::<u32>();
+        for (i, frame) in frames.iter().enumerate() {
+            let number = (frame.start_address().as_u64() / 4096) as u32;
+            unsafe { numbers.add(i).write_volatile(number) };
+        }
+        let queue = if inflate {
+            &mut self.inflate
+        } else {
+            &mut self.deflate
+        };
+        let len = (frames.len() * size_of::<u32>()) as u32;
+        // The request frame is only used for this request
+        unsafe { queue.submit(&[(addr, len, false)]) };
+    }
+
+    /// Move up to [`BATCH`] frames towards the target, returning the change in
+    /// the number of frames in the balloon
+    fn adjust(&mut self, init: &mut Init) -> isize {
+        let target = self.target();
+        let current = self.frames.len();
+        if target > current {
+            let count = (target - current).min(BATCH);
+            let mut batch = Vec::with_capacity(count);
+            while batch.len() < count {
+                match init.frame_allocator.allocate_frame() {
+                    Some(frame) => batch.push(frame),
+                    None => break,
+                }
+            }
+            if batch.is_empty() {
+                return 0;
+            }
+            self.send(true, &batch);
+            self.frames.extend_from_slice(&batch);
+            self.report();
+            batch.len() as isize
+        } else if target < current {
+            let count = (current - target).min(BATCH);
+            let batch = self.frames.split_off(current - count);
+            self.send(false, &batch);
+            for frame in batch {
+                // The host no longer uses the frame after the deflate request
+                unsafe { init.frame_allocator.deallocate_frame(frame) };
+            }
+            self.report();
+            -(count as isize)
+        } else {
+            0
+        }
+    }
+}
+
+fn setup(init: &mut Init, device: &Device) -> Result<(), &'static str> {
+    let mut transport = Transport::new(device)?;
+    transport.init(0)?;
+    if transport.device_config::<Config>().is_none() {
+        return Err("Virtio balloon configuration missing");
+    }
+    let inflate = transport.queue(INFLATE_QUEUE, &mut init.frame_allocator)?;
+    let deflate = transport.queue(DEFLATE_QUEUE, &mut init.frame_allocator)?;
+    transport.finish_init();
+    let request = init
+        .frame_allocator
+        .allocate_frame()
+        .ok_or("No frame for virtio balloon requests")?;
+    *BALLOON.lock() = Some(Balloon {
+        transport,
+        inflate,
+        deflate,
+        request,
+        frames: Vec::new(),
+    });
+    Ok(())
+}
+
+crate::initcall!(Device, init);
+
+/// Set up the first virtio balloon, if any
+pub fn init(init: &mut Init) {
+    let device = match find(DEVICE_TYPE).next() {
+        Some(device) => device,
+        None => return,
+    };
+    log::info!("Virtio balloon at {}", device.address);
+    match setup(init, device) {
+        Ok(()) => {
+            devices::bind_pci(device.address, "virtio-balloon", State::Bound);
+            devices::on_quiesce(device.address, quiesce);
+            poll(init);
+        }
+        Err(e) => {
+            log::warn!("Virtio balloon unavailable: {}", e);
+            devices::bind_pci(device.address, "virtio-balloon", State::Failed);
+        }
+    }
+}
+
+/// Reset the virtio balloon, if any
+///
+/// The frames in the balloon are not given back, as the system is going down.
+fn quiesce() {
+    if let Some(mut balloon) = BALLOON.lock().take() {
+        balloon.transport.reset();
+    }
+}
+
+/// Inflate or deflate the balloon towards the size requested by the host
+///
+/// Should be called regularly; does nothing without a balloon device.
+pub fn poll(init: &mut Init) {
+    let mut guard = BALLOON.lock();
+    let balloon = match guard.as_mut() {
+        Some(balloon) => balloon,
+        None => return,
+    };
+    let mut change = 0;
+    loop {
+        match balloon.adjust(init) {
+            0 => break,
+            step => change += step,
+        }
+    }
+    if change != 0 {
+        log::info!(
+            "Balloon changed by {} to {} pages (target {})",
+            change,
+            balloon.frames.len(),
+            balloon.target()
+        );
+    }
+}
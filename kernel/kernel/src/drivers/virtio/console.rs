@@ -0,0 +1,121 @@
+//! Virtio console device
+//!
+//! Only the transmit queue of the first port is used, as an output-only
+//! [`ConsoleBackend`] selected with the `console=virtio` option. Unlike the
+//! 16550, which takes an I/O port access per byte, a whole write is handed to
+//! the host at once, so large trace dumps are printed much faster.
+
+use super::{find, Queue, Transport};
+use crate::{
+    config,
+    devices::{self, State},
+    drivers::pci::Device,
+    Init,
+};
+use common::{
+    boot::offset,
+    serial::{self, Console, ConsoleBackend},
+};
+use core::ptr;
+use spin::Mutex;
+use x86_64::structures::paging::{FrameAllocator, PhysFrame};
+
+/// Virtio device type of console devices
+const DEVICE_TYPE: u16 = 3;
+/// Transmit queue of port 0; without multiport support it is the only port
+const TRANSMIT_QUEUE: u16 = 1;
+/// Maximum number of bytes per request, as many as fit in a frame
+const BATCH: usize = 4096;
+
+static CONSOLE: Mutex<Option<VirtioConsole>> = Mutex::new(None);
+static BACKEND: Backend = Backend;
+
+struct VirtioConsole {
+    transport: Transport,
+    transmit: Queue,
+    /// Frame holding the bytes of a request
+    buffer: PhysFrame,
+}
+
+impl VirtioConsole {
+    fn write(&mut self, bytes: &[u8]) {
+        let addr = self.buffer.start_address();
+        let buffer = offset::phys_to_virt(addr).as_mut_ptr::<u8>();
+        for chunk in bytes.chunks(BATCH) {
+            unsafe { ptr::copy_nonoverlapping(chunk.as_ptr(), buffer, chunk.len()) };
+            // The buffer frame is only used for this request
+            unsafe { self.transmit.submit(&[(addr, chunk.len() as u32, false)]) };
+        }
+    }
+}
+
+/// Console backend writing to the virtio console, if any
+struct Backend;
+
+impl ConsoleBackend for Backend {
+    fn init(&self) {}
+
+    fn write_str(&self, s: &str) {
+        if let Some(console) = CONSOLE.lock().as_mut() {
+            console.write(s.as_bytes());
+        }
+    }
+}
+
+fn setup(init: &mut Init, device: &Device) -> Result<(), &'static str> {
+    let mut transport = Transport::new(device)?;
+    transport.init(0)?;
+    let transmit = transport.queue(TRANSMIT_QUEUE, &mut init.frame_allocator)?;
+    transport.finish_init();
+    let buffer = init
+        .frame_allocator
+        .allocate_frame()
+        .ok_or("No frame for virtio console output")?;
+    *CONSOLE.lock() = Some(VirtioConsole {
+        transport,
+        transmit,
+        buffer,
+    });
+    Ok(())
+}
+
+crate::initcall!(Device, init);
+
+/// Set up the first virtio console, if any, and print to it if selected
+pub fn init(init: &mut Init) {
+    let device = match find(DEVICE_TYPE).next() {
+        Some(device) => device,
+        None => {
+            if config::console() == Console::Virtio {
+                log::warn!("No virtio console, printing to COM1");
+            }
+            return;
+        }
+    };
+    log::info!("Virtio console at {}", device.address);
+    match setup(init, device) {
+        Ok(()) => {
+            devices::bind_pci(device.address, "virtio-console", State::Bound);
+            devices::on_quiesce(device.address, quiesce);
+            if config::console() == Console::Virtio {
+                serial::set_backend(&BACKEND);
+                log::info!("Printing to virtio console");
+            }
+        }
+        Err(e) => {
+            log::warn!("Virtio console unavailable: {}", e);
+            devices::bind_pci(device.address, "virtio-console", State::Failed);
+        }
+    }
+}
+
+/// Reset the virtio console, if any, printing to COM1 from then on if it was
+/// selected
+fn quiesce() {
+    if config::console() == Console::Virtio {
+        serial::select(Console::Com1);
+    }
+    if let Some(mut console) = CONSOLE.lock().take() {
+        console.transport.reset();
+    }
+}
@@ -0,0 +1,452 @@
+//! Virtio GPU device providing a 2D frame buffer with a configurable mode
+//!
+//! The frame buffer is a host resource backed by physically contiguous guest
+//! memory. Unlike the UEFI frame buffer, changes are only displayed after they
+//! are transferred to the host using [`flush`].
+
+use super::{find, Queue, Transport};
+use crate::{
+    devices::{self, State},
+    drivers::pci::Device,
+    Init,
+};
+use common::boot::{offset, FramebufferInfo, PixelFormat};
+use core::{convert::TryFrom, mem::size_of};
+use spin::Mutex;
+use x86_64::{
+    structures::paging::{frame::PhysFrameRange, FrameAllocator, FrameDeallocator, PhysFrame},
+    PhysAddr,
+};
+
+/// Virtio device type of GPU devices
+const DEVICE_TYPE: u16 = 16;
+/// Index of the control queue
+const CONTROL_QUEUE: u16 = 0;
+/// Scanout used for the frame buffer
+const SCANOUT: u32 = 0;
+/// Resolution used if the device does not report a preferred one
+const DEFAULT_RESOLUTION: (u32, u32) = (1024, 768);
+/// Largest resolution that is set, larger ones are clamped to it
+const MAX_RESOLUTION: (u32, u32) = (3840, 2160);
+/// Offset of the response in the command frame
+const RESPONSE_OFFSET: u64 = 2048;
+
+/// Pixel format with bytes in blue, green, red, unused order
+const FORMAT_B8G8R8X8_UNORM: u32 = 2;
+
+/// Command types
+mod cmd {
+    pub const GET_DISPLAY_INFO: u32 = 0x100;
+    pub const RESOURCE_CREATE_2D: u32 = 0x101;
+    pub const RESOURCE_UNREF: u32 = 0x102;
+    pub const SET_SCANOUT: u32 = 0x103;
+    pub const RESOURCE_FLUSH: u32 = 0x104;
+    pub const TRANSFER_TO_HOST_2D: u32 = 0x105;
+    pub const RESOURCE_ATTACH_BACKING: u32 = 0x106;
+    pub const RESOURCE_DETACH_BACKING: u32 = 0x107;
+}
+
+/// Response types
+mod resp {
+    pub const OK_NODATA: u32 = 0x1100;
+    pub const OK_DISPLAY_INFO: u32 = 0x1101;
+}
+
+static GPU: Mutex<Option<Gpu>> = Mutex::new(None);
+
+#[repr(C)]
+#[derive(Copy, Clone, Default)]
+struct Header {
+    ty: u32,
+    flags: u32,
+    fence_id: u64,
+    ctx_id: u32,
+    padding: u32,
+}
+
+impl Header {
+    fn new(ty: u32) -> Self {
+        Self {
+            ty,
+            ..Default::default()
+        }
+    }
+}
+
+#[repr(C)]
+#[derive(Copy, Clone, Default)]
+struct Rect {
+    x: u32,
+    y: u32,
+    width: u32,
+    height: u32,
+}
+
+impl Rect {
+    fn new((width, height): (u32, u32)) -> Self {
+        Self {
+            x: 0,
+            y: 0,
+            width,
+            height,
+        }
+    }
+}
+
+#[repr(C)]
+#[derive(Copy, Clone)]
+struct DisplayInfo {
+    header: Header,
+    modes: [DisplayMode; 16],
+}
+
+#[repr(C)]
+#[derive(Copy, Clone)]
+struct DisplayMode {
+    rect: Rect,
+    enabled: u32,
+    flags: u32,
+}
+
+#[repr(C)]
+struct ResourceCreate2d {
+    header: Header,
+    resource_id: u32,
+    format: u32,
+    width: u32,
+    height: u32,
+}
+
+#[repr(C)]
+struct ResourceUnref {
+    header: Header,
+    resource_id: u32,
+    padding: u32,
+}
+
+#[repr(C)]
+struct SetScanout {
+    header: Header,
+    rect: Rect,
+    scanout_id: u32,
+    resource_id: u32,
+}
+
+#[repr(C)]
+struct ResourceFlush {
+    header: Header,
+    rect: Rect,
+    resource_id: u32,
+    padding: u32,
+}
+
+#[repr(C)]
+struct TransferToHost2d {
+    header: Header,
+    rect: Rect,
+    offset: u64,
+    resource_id: u32,
+    padding: u32,
+}
+
+/// Attach a single contiguous range of memory to a resource
+#[repr(C)]
+struct ResourceAttachBacking {
+    header: Header,
+    resource_id: u32,
+    nr_entries: u32,
+    addr: u64,
+    length: u32,
+    padding: u32,
+}
+
+#[repr(C)]
+struct ResourceDetachBacking {
+    header: Header,
+    resource_id: u32,
+    padding: u32,
+}
+
+/// Host resource that is currently scanned out
+struct Resource {
+    id: u32,
+    resolution: (u32, u32),
+    frames: PhysFrameRange,
+}
+
+struct Gpu {
+    /// Kept so the device configuration stays owned by the driver
+    transport: Transport,
+    queue: Queue,
+    /// Frame holding the request and the response of a command
+    command: PhysFrame,
+    resource: Option<Resource>,
+    next_resource_id: u32,
+}
+
+impl Gpu {
+    /// Send `request` and return the type of the response
+    fn send<T>(&mut self, request: T, response_len: usize) -> u32 {
+        let request_addr = self.command.start_address();
+        let response_addr = request_addr + RESPONSE_OFFSET;
+        unsafe {
+            offset::phys_to_virt(request_addr)
+                .as_mut_ptr::<T>()
+                .write_volatile(request);
+            self.queue.submit(&[
+                (request_addr, size_of::<T>() as u32, false),
+                (response_addr, response_len as u32, true),
+            ]);
+            offset::phys_to_virt(response_addr)
+                .as_ptr::<Header>()
+                .read_volatile()
+                .ty
+        }
+    }
+
+    /// Send `request` that is answered without data
+    fn command<T>(&mut self, request: T) -> Result<(), &'static str> {
+        if self.send(request, size_of::<Header>()) == resp::OK_NODATA {
+            Ok(())
+        } else {
+            Err("Virtio GPU command failed")
+        }
+    }
+
+    /// Resolution of the first scanout, if it is enabled
+    fn preferred_resolution(&mut self) -> Option<(u32, u32)> {
+        let request = Header::new(cmd::GET_DISPLAY_INFO);
+        if self.send(request, size_of::<DisplayInfo>()) != resp::OK_DISPLAY_INFO {
+            return None;
+        }
+        let response_addr = self.command.start_address() + RESPONSE_OFFSET;
+        let info = unsafe {
+            offset::phys_to_virt(response_addr)
+                .as_ptr::<DisplayInfo>()
+                .read_volatile()
+        };
+        let mode = info.modes[SCANOUT as usize];
+        if mode.enabled != 0 && mode.rect.width != 0 && mode.rect.height != 0 {
+            Some((mode.rect.width, mode.rect.height))
+        } else {
+            None
+        }
+    }
+
+    /// Create a resource with the given resolution, clamped to
+    /// [`MAX_RESOLUTION`], and scan it out
+    ///
+    /// The previous resource is destroyed and its memory is freed.
+    fn set_mode(
+        &mut self,
+        init: &mut Init,
+        (width, height): (u32, u32),
+    ) -> Result<FramebufferInfo, &'static str> {
+        if width == 0 || height == 0 {
+            return Err("Invalid resolution");
+        }
+        let width = width.min(MAX_RESOLUTION.0);
+        let height = height.min(MAX_RESOLUTION.1);
+        let resolution = (width, height);
+        let size = width as u64 * height as u64 * 4;
+        let count = (size + 4095) / 4096;
+        let frames = init
+            .frame_allocator
+            .allocate_contiguous(count)
+            .ok_or("No memory for frame buffer")?;
+        let phys_addr = frames.start.start_address();
+        unsafe {
+            offset::phys_to_virt(phys_addr)
+                .as_mut_ptr::<u8>()
+                .write_bytes(0, size as usize);
+        }
+
+        let id = self.next_resource_id;
+        self.next_resource_id += 1;
+        let result = self.create_resource(id, resolution, phys_addr, size);
+        if let Err(e) = result {
+            // Partially created resources are not scanned out, see below
+            let _ = self.destroy_resource(id);
+            unsafe { free_frames(init, frames) };
+            return Err(e);
+        }
+        if let Some(old) = self.resource.replace(Resource {
+            id,
+            resolution,
+            frames,
+        }) {
+            if let Err(e) = self.destroy_resource(old.id) {
+                log::warn!("Failed to destroy virtio GPU resource {}: {}", old.id, e);
+            }
+            // Not scanned out or transferred anymore, so the device no longer
+            // accesses the memory even if destroying the resource failed
+            unsafe { free_frames(init, old.frames) };
+        }
+        log::info!("Virtio GPU mode set to {}x{}", width, height);
+        Ok(FramebufferInfo {
+            phys_addr,
+            size: size as usize,
+            resolution: (width as usize, height as usize),
+            stride: width as usize,
+            format: PixelFormat::Bgr,
+        })
+    }
+
+    /// Create resource `id` backed by memory at `addr` and scan it out
+    fn create_resource(
+        &mut self,
+        id: u32,
+        (width, height): (u32, u32),
+        addr: PhysAddr,
+        size: u64,
+    ) -> Result<(), &'static str> {
+        let length = u32::try_from(size).map_err(|_| "Frame buffer too large")?;
+        self.command(ResourceCreate2d {
+            header: Header::new(cmd::RESOURCE_CREATE_2D),
+            resource_id: id,
+            format: FORMAT_B8G8R8X8_UNORM,
+            width,
+            height,
+        })?;
+        self.command(ResourceAttachBacking {
+            header: Header::new(cmd::RESOURCE_ATTACH_BACKING),
+            resource_id: id,
+            nr_entries: 1,
+            addr: addr.as_u64(),
+            length,
+            padding: 0,
+        })?;
+        self.command(SetScanout {
+            header: Header::new(cmd::SET_SCANOUT),
+            rect: Rect::new((width, height)),
+            scanout_id: SCANOUT,
+            resource_id: id,
+        })
+    }
+
+    /// Detach the backing of resource `id` and destroy it
+    fn destroy_resource(&mut self, id: u32) -> Result<(), &'static str> {
+        self.command(ResourceDetachBacking {
+            header: Header::new(cmd::RESOURCE_DETACH_BACKING),
+            resource_id: id,
+            padding: 0,
+        })?;
+        self.command(ResourceUnref {
+            header: Header::new(cmd::RESOURCE_UNREF),
+            resource_id: id,
+            padding: 0,
+        })
+    }
+
+    /// Transfer the frame buffer to the host and display it
+    fn flush(&mut self) -> Result<(), &'static str> {
+        let (id, resolution) = match &self.resource {
+            Some(resource) => (resource.id, resource.resolution),
+            None => return Ok(()),
+        };
+        self.command(TransferToHost2d {
+            header: Header::new(cmd::TRANSFER_TO_HOST_2D),
+            rect: Rect::new(resolution),
+            offset: 0,
+            resource_id: id,
+            padding: 0,
+        })?;
+        self.command(ResourceFlush {
+            header: Header::new(cmd::RESOURCE_FLUSH),
+            rect: Rect::new(resolution),
+            resource_id: id,
+            padding: 0,
+        })
+    }
+}
+
+/// # Safety
+/// Frames should not be in use anymore.
+unsafe fn free_frames(init: &mut Init, frames: PhysFrameRange) {
+    for frame in frames {
+        init.frame_allocator.deallocate_frame(frame);
+    }
+}
+
+fn setup(init: &mut Init, device: &Device) -> Result<FramebufferInfo, &'static str> {
+    let mut transport = Transport::new(device)?;
+    transport.init(0)?;
+    let queue = transport.queue(CONTROL_QUEUE, &mut init.frame_allocator)?;
+    transport.finish_init();
+    let command = init
+        .frame_allocator
+        .allocate_frame()
+        .ok_or("No frame for virtio GPU commands")?;
+    let mut gpu = Gpu {
+        transport,
+        queue,
+        command,
+        resource: None,
+        next_resource_id: 1,
+    };
+    let resolution = gpu.preferred_resolution().unwrap_or(DEFAULT_RESOLUTION);
+    let fb = gpu.set_mode(init, resolution)?;
+    gpu.flush()?;
+    *GPU.lock() = Some(gpu);
+    Ok(fb)
+}
+
+/// Set up the first virtio GPU, if any, in its preferred mode
+///
+/// Returns the frame buffer of the device, which replaces the one provided by
+/// UEFI.
+pub fn init(init: &mut Init) -> Option<FramebufferInfo> {
+    let device = find(DEVICE_TYPE).next()?;
+    log::info!("Virtio GPU at {}", device.address);
+    match setup(init, device) {
+        Ok(fb) => {
+            devices::bind_pci(device.address, "virtio-gpu", State::Bound);
+            devices::on_quiesce(device.address, quiesce);
+            Some(fb)
+        }
+        Err(e) => {
+            log::warn!("Virtio GPU unavailable: {}", e);
+            devices::bind_pci(device.address, "virtio-gpu", State::Failed);
+            None
+        }
+    }
+}
+
+/// Reset the virtio GPU, if any, which stops scanning out the frame buffer
+fn quiesce() {
+    if let Some(mut gpu) = GPU.lock().take() {
+        gpu.transport.reset();
+    }
+}
+
+/// Whether a virtio GPU is in use
+pub fn available() -> bool {
+    GPU.lock().is_some()
+}
+
+/// Change the resolution of the frame buffer
+///
+/// The previous frame buffer is freed, so it should no longer be mapped.
+pub fn set_mode(init: &mut Init, resolution: (u32, u32)) -> Result<FramebufferInfo, &'static str> {
+    GPU.lock()
+        .as_mut()
+        .ok_or("No virtio GPU")?
+        .set_mode(init, resolution)
+}
+
+/// Display the contents of the frame buffer; does nothing without a virtio GPU
+pub fn flush() -> Result<(), &'static str> {
+    match GPU.lock().as_mut() {
+        Some(gpu) => gpu.flush(),
+        None => Ok(()),
+    }
+}
+
+/// Like [`flush`], but does nothing if the GPU is in use, e.g. by the code
+/// that is logging a message drawn by [`crate::console`]
+pub fn try_flush() -> Result<(), &'static str> {
+    match GPU.try_lock().as_deref_mut() {
+        Some(Some(gpu)) => gpu.flush(),
+        _ => Ok(()),
+    }
+}
@@ -0,0 +1,116 @@
+//! Virtio network device
+//!
+//! Only the transmit queue is used, so the device sends frames but never
+//! receives any, which is enough for [`crate::remote_log`].
+
+use super::{find, Queue, Transport};
+use crate::{
+    devices::{self, State},
+    drivers::pci::Device,
+    net::{self, NetDevice},
+    remote_log, Init,
+};
+use alloc::boxed::Box;
+use common::boot::offset;
+use core::ptr;
+use x86_64::structures::paging::{FrameAllocator, PhysFrame};
+
+/// Virtio device type of network devices
+const DEVICE_TYPE: u16 = 1;
+/// Transmit queue of the first queue pair
+const TRANSMIT_QUEUE: u16 = 1;
+/// Feature bit indicating the device configuration holds a MAC address
+const F_MAC: u64 = 1 << 5;
+/// Size of the header preceding every frame; it is all zeros, as no offloads
+/// are negotiated
+const HEADER_SIZE: usize = 12;
+/// Address used if the device does not provide one, a locally administered
+/// unicast address
+const DEFAULT_MAC: [u8; 6] = [0x52, 0x54, 0x00, 0x12, 0x34, 0x56];
+
+struct VirtioNet {
+    transport: Transport,
+    transmit: Queue,
+    /// Frame holding the header and Ethernet frame of a request
+    buffer: PhysFrame,
+    mac: [u8; 6],
+}
+
+impl NetDevice for VirtioNet {
+    fn mac(&self) -> [u8; 6] {
+        self.mac
+    }
+
+    fn send(&mut self, frame: &[u8]) {
+        let addr = self.buffer.start_address();
+        let buffer = offset::phys_to_virt(addr).as_mut_ptr::<u8>();
+        unsafe {
+            ptr::write_bytes(buffer, 0, HEADER_SIZE);
+            ptr::copy_nonoverlapping(frame.as_ptr(), buffer.add(HEADER_SIZE), frame.len());
+            // The buffer frame is only used for this request
+            self.transmit
+                .submit(&[(addr, (HEADER_SIZE + frame.len()) as u32, false)]);
+        }
+    }
+
+    fn stop(&mut self) {
+        self.transport.reset();
+    }
+}
+
+fn setup(init: &mut Init, device: &Device) -> Result<(), &'static str> {
+    let mut transport = Transport::new(device)?;
+    let features = transport.init(F_MAC)?;
+    let mac = match transport.device_config::<[u8; 6]>() {
+        Some(config) if features & F_MAC != 0 => unsafe { ptr::read_volatile(config) },
+        _ => DEFAULT_MAC,
+    };
+    let transmit = transport.queue(TRANSMIT_QUEUE, &mut init.frame_allocator)?;
+    transport.finish_init();
+    let buffer = init
+        .frame_allocator
+        .allocate_frame()
+        .ok_or("No frame for virtio network output")?;
+    net::set_device(Some(Box::new(VirtioNet {
+        transport,
+        transmit,
+        buffer,
+        mac,
+    })));
+    Ok(())
+}
+
+crate::initcall!(Device, init);
+
+/// Set up the first virtio network device, if any, as the network device, and
+/// send the log to it if configured
+pub fn init(init: &mut Init) {
+    let device = match find(DEVICE_TYPE).next() {
+        Some(device) => device,
+        None => {
+            if remote_log::enabled() {
+                log::warn!("No virtio network device, not sending the log");
+            }
+            return;
+        }
+    };
+    log::info!("Virtio network device at {}", device.address);
+    match setup(init, device) {
+        Ok(()) => {
+            devices::bind_pci(device.address, "virtio-net", State::Bound);
+            devices::on_quiesce(device.address, quiesce);
+            remote_log::start();
+        }
+        Err(e) => {
+            log::warn!("Virtio network device unavailable: {}", e);
+            devices::bind_pci(device.address, "virtio-net", State::Failed);
+        }
+    }
+}
+
+/// Stop the network device, which stops sending the log
+fn quiesce() {
+    if let Some(mut device) = net::set_device(None) {
+        device.stop();
+    }
+}
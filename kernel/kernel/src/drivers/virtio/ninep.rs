@@ -0,0 +1,491 @@
+//! Virtio 9P transport with a 9P2000.L client
+//!
+//! A directory shared by the host (`-virtfs` in QEMU) is attached at boot and
+//! files in it can be read by path with [`read`], which is enough to give
+//! programs test assets without rebuilding the image. If the host shares the
+//! directory writable, files can be replaced with [`write`]. Every read or
+//! write walks to the file, opens it, transfers the data and clunks it again,
+//! so no state is kept between them apart from the attached root. Only a
+//! single request is in flight at a time, so every message uses the same tag.
+
+use super::{find, Queue, Transport};
+use crate::{
+    devices::{self, State},
+    drivers::pci::Device,
+    Init,
+};
+use alloc::{string::String, vec::Vec};
+use common::boot::offset;
+use core::{convert::TryInto, ptr, slice};
+use spin::Mutex;
+use x86_64::structures::paging::{FrameAllocator, PhysFrame};
+
+/// Virtio device type of 9P transports
+const DEVICE_TYPE: u16 = 9;
+const REQUEST_QUEUE: u16 = 0;
+/// Feature bit of the mount tag in the device configuration
+const F_MOUNT_TAG: u64 = 1 << 0;
+
+/// Maximum message size, as requests and responses each get a frame
+const MSIZE: u32 = 4096;
+const VERSION: &str = "9P2000.L";
+/// Tag of every message
+const TAG: u16 = 0;
+const NOFID: u32 = !0;
+/// Fid of the attached root directory
+const ROOT_FID: u32 = 0;
+/// Fid of the file being read or written
+const FILE_FID: u32 = 1;
+/// Maximum number of path components of a walk
+const MAX_WALK: usize = 16;
+/// Size of the header of a read response: size, type, tag and count
+const READ_HEADER: u32 = 4 + 1 + 2 + 4;
+/// Size of the header of a write request: size, type, tag, fid, offset and
+/// count
+const WRITE_HEADER: u32 = 4 + 1 + 2 + 4 + 8 + 4;
+/// Open flags, as on Linux
+const O_RDONLY: u32 = 0;
+const O_WRONLY: u32 = 0o1;
+const O_CREAT: u32 = 0o100;
+const O_TRUNC: u32 = 0o1000;
+/// Permissions of created files
+const CREATE_MODE: u32 = 0o644;
+
+/// Message types
+mod ty {
+    pub const RLERROR: u8 = 7;
+    pub const TLOPEN: u8 = 12;
+    pub const RLOPEN: u8 = 13;
+    pub const TLCREATE: u8 = 14;
+    pub const RLCREATE: u8 = 15;
+    pub const TVERSION: u8 = 100;
+    pub const RVERSION: u8 = 101;
+    pub const TATTACH: u8 = 104;
+    pub const RATTACH: u8 = 105;
+    pub const TWALK: u8 = 110;
+    pub const RWALK: u8 = 111;
+    pub const TREAD: u8 = 116;
+    pub const RREAD: u8 = 117;
+    pub const TWRITE: u8 = 118;
+    pub const RWRITE: u8 = 119;
+    pub const TCLUNK: u8 = 120;
+    pub const RCLUNK: u8 = 121;
+}
+
+static HOST_FS: Mutex<Option<HostFs>> = Mutex::new(None);
+
+/// Request message, built by appending fields
+struct Message(Vec<u8>);
+
+impl Message {
+    /// Message of type `kind` without fields
+    fn new(kind: u8) -> Self {
+        let mut message = Self(Vec::new());
+        // The size is filled in by `finish`
+        message.u32(0).u8(kind).u16(TAG);
+        message
+    }
+
+    fn u8(&mut self, value: u8) -> &mut Self {
+        self.0.push(value);
+        self
+    }
+
+    fn u16(&mut self, value: u16) -> &mut Self {
+        self.0.extend_from_slice(&value.to_le_bytes());
+        self
+    }
+
+    fn u32(&mut self, value: u32) -> &mut Self {
+        self.0.extend_from_slice(&value.to_le_bytes());
+        self
+    }
+
+    fn u64(&mut self, value: u64) -> &mut Self {
+        self.0.extend_from_slice(&value.to_le_bytes());
+        self
+    }
+
+    /// String prefixed by its length; `s` should be shorter than 64 KiB
+    fn str(&mut self, s: &str) -> &mut Self {
+        self.u16(s.len() as u16);
+        self.0.extend_from_slice(s.as_bytes());
+        self
+    }
+
+    /// Encoded message with its size filled in
+    fn finish(mut self) -> Vec<u8> {
+        let size = self.0.len() as u32;
+        self.0[..4].copy_from_slice(&size.to_le_bytes());
+        self.0
+    }
+}
+
+/// Fields of a response message, read in order
+struct Reader<'a>(&'a [u8]);
+
+impl<'a> Reader<'a> {
+    fn take(&mut self, len: usize) -> Result<&'a [u8], &'static str> {
+        if self.0.len() < len {
+            return Err("Truncated 9P message");
+        }
+        let (bytes, rest) = self.0.split_at(len);
+        self.0 = rest;
+        Ok(bytes)
+    }
+
+    fn u8(&mut self) -> Result<u8, &'static str> {
+        Ok(self.take(1)?[0])
+    }
+
+    fn u16(&mut self) -> Result<u16, &'static str> {
+        Ok(u16::from_le_bytes(self.take(2)?.try_into().unwrap()))
+    }
+
+    fn u32(&mut self) -> Result<u32, &'static str> {
+        Ok(u32::from_le_bytes(self.take(4)?.try_into().unwrap()))
+    }
+
+    fn str(&mut self) -> Result<&'a [u8], &'static str> {
+        let len = self.u16()? as usize;
+        self.take(len)
+    }
+
+    /// Check the header of a response of type `expected`, returning the
+    /// fields after it
+    fn response(bytes: &'a [u8], expected: u8) -> Result<Self, &'static str> {
+        let mut header = Self(bytes);
+        let size = header.u32()? as usize;
+        let mut reader = Self(bytes.get(..size).ok_or("Truncated 9P message")?);
+        reader.take(4)?;
+        let kind = reader.u8()?;
+        reader.u16()?;
+        match kind {
+            ty::RLERROR => Err(errno(reader.u32()?)),
+            kind if kind == expected => Ok(reader),
+            _ => Err("Unexpected 9P response"),
+        }
+    }
+}
+
+/// Description of a Linux error number returned by the host
+fn errno(code: u32) -> &'static str {
+    match code {
+        1 | 13 => "Permission denied",
+        2 => "No such file or directory",
+        20 => "Not a directory",
+        21 => "Is a directory",
+        28 => "No space left on device",
+        30 => "Read-only file system",
+        _ => "Host file system error",
+    }
+}
+
+struct HostFs {
+    transport: Transport,
+    requests: Queue,
+    /// Frame holding the request message
+    request: PhysFrame,
+    /// Frame the device writes the response message to
+    response: PhysFrame,
+    /// Maximum message size agreed on with the host
+    msize: u32,
+}
+
+impl HostFs {
+    /// Send `message` and wait for the response of type `expected`
+    fn transact(&mut self, message: Message, expected: u8) -> Result<Reader, &'static str> {
+        let bytes = message.finish();
+        if bytes.len() > self.msize as usize {
+            return Err("9P message too long");
+        }
+        let request = self.request.start_address();
+        let response = self.response.start_address();
+        unsafe {
+            let buffer = offset::phys_to_virt(request).as_mut_ptr::<u8>();
+            ptr::copy_nonoverlapping(bytes.as_ptr(), buffer, bytes.len());
+        }
+        // The frames are only used for this request
+        let written = unsafe {
+            self.requests.submit(&[
+                (request, bytes.len() as u32, false),
+                (response, self.msize, true),
+            ])
+        };
+        let buffer = offset::phys_to_virt(response).as_ptr::<u8>();
+        let bytes = unsafe { slice::from_raw_parts(buffer, written.min(self.msize) as usize) };
+        Reader::response(bytes, expected)
+    }
+
+    /// Agree on the protocol version and message size
+    fn version(&mut self) -> Result<(), &'static str> {
+        let mut message = Message::new(ty::TVERSION);
+        message.u32(MSIZE).str(VERSION);
+        let mut reader = self.transact(message, ty::RVERSION)?;
+        let msize = reader.u32()?;
+        if reader.str()? != VERSION.as_bytes() {
+            return Err("Host does not support 9P2000.L");
+        }
+        // Reads and writes need room for data besides their headers
+        if msize <= READ_HEADER.max(WRITE_HEADER) {
+            return Err("Message size too small");
+        }
+        self.msize = msize.min(MSIZE);
+        Ok(())
+    }
+
+    /// Attach [`ROOT_FID`] to the root of the shared directory as root user
+    fn attach(&mut self) -> Result<(), &'static str> {
+        let mut message = Message::new(ty::TATTACH);
+        message.u32(ROOT_FID).u32(NOFID).str("").str("").u32(0);
+        self.transact(message, ty::RATTACH)?;
+        Ok(())
+    }
+
+    /// Walk from the root to `path`, giving the file fid `fid`
+    fn walk(&mut self, fid: u32, path: &str) -> Result<(), &'static str> {
+        let names = path.split('/').filter(|name| !name.is_empty());
+        let count = names.clone().count();
+        if count > MAX_WALK {
+            return Err("Path too deep");
+        }
+        let mut message = Message::new(ty::TWALK);
+        message.u32(ROOT_FID).u32(fid).u16(count as u16);
+        for name in names {
+            message.str(name);
+        }
+        let mut reader = self.transact(message, ty::RWALK)?;
+        // A partial walk returns the qids of the names found, without fid
+        if reader.u16()? as usize != count {
+            return Err(errno(2));
+        }
+        Ok(())
+    }
+
+    fn open(&mut self, fid: u32, flags: u32) -> Result<(), &'static str> {
+        let mut message = Message::new(ty::TLOPEN);
+        message.u32(fid).u32(flags);
+        self.transact(message, ty::RLOPEN)?;
+        Ok(())
+    }
+
+    /// Create and open file `name` for writing in the directory of `fid`,
+    /// which then refers to the file
+    fn create(&mut self, fid: u32, name: &str) -> Result<(), &'static str> {
+        let mut message = Message::new(ty::TLCREATE);
+        message
+            .u32(fid)
+            .str(name)
+            .u32(O_WRONLY | O_CREAT | O_TRUNC)
+            .u32(CREATE_MODE)
+            .u32(0);
+        self.transact(message, ty::RLCREATE)?;
+        Ok(())
+    }
+
+    /// Read from `offset` into `buf`, returning the number of bytes read
+    fn read_at(&mut self, fid: u32, offset: u64, buf: &mut [u8]) -> Result<usize, &'static str> {
+        let mut read = 0;
+        while read < buf.len() {
+            let count = (buf.len() - read).min((self.msize - READ_HEADER) as usize);
+            let mut message = Message::new(ty::TREAD);
+            message.u32(fid).u64(offset + read as u64).u32(count as u32);
+            let mut reader = self.transact(message, ty::RREAD)?;
+            let len = reader.u32()? as usize;
+            let data = reader.take(len.min(count))?;
+            if data.is_empty() {
+                break;
+            }
+            buf[read..read + data.len()].copy_from_slice(data);
+            read += data.len();
+        }
+        Ok(read)
+    }
+
+    /// Write all of `data` from `offset`
+    fn write_at(&mut self, fid: u32, offset: u64, data: &[u8]) -> Result<(), &'static str> {
+        let mut written = 0;
+        while written < data.len() {
+            let count = (data.len() - written).min((self.msize - WRITE_HEADER) as usize);
+            let mut message = Message::new(ty::TWRITE);
+            message
+                .u32(fid)
+                .u64(offset + written as u64)
+                .u32(count as u32);
+            message.0.extend_from_slice(&data[written..written + count]);
+            let mut reader = self.transact(message, ty::RWRITE)?;
+            let len = reader.u32()? as usize;
+            if len == 0 {
+                return Err("Host file system error");
+            }
+            written += len.min(count);
+        }
+        Ok(())
+    }
+
+    fn clunk(&mut self, fid: u32) -> Result<(), &'static str> {
+        let mut message = Message::new(ty::TCLUNK);
+        message.u32(fid);
+        self.transact(message, ty::RCLUNK)?;
+        Ok(())
+    }
+
+    /// Mount tag of the shared directory, if offered
+    fn tag(&self, features: u64) -> Option<String> {
+        if features & F_MOUNT_TAG == 0 {
+            return None;
+        }
+        let config = self.transport.device_config::<u8>()?;
+        let len = unsafe { (config as *mut u16).read_volatile() } as usize;
+        let tag = (0..len.min(64))
+            .map(|i| unsafe { config.add(2 + i).read_volatile() } as char)
+            .collect();
+        Some(tag)
+    }
+}
+
+fn setup(init: &mut Init, device: &Device) -> Result<(), &'static str> {
+    let mut transport = Transport::new(device)?;
+    let features = transport.init(F_MOUNT_TAG)?;
+    let requests = transport.queue(REQUEST_QUEUE, &mut init.frame_allocator)?;
+    transport.finish_init();
+    let mut frame = || {
+        init.frame_allocator
+            .allocate_frame()
+            .ok_or("No frame for 9P messages")
+    };
+    let request = frame()?;
+    let response = frame()?;
+    let mut fs = HostFs {
+        transport,
+        requests,
+        request,
+        response,
+        msize: MSIZE,
+    };
+    fs.version()?;
+    fs.attach()?;
+    match fs.tag(features) {
+        Some(tag) => log::info!("Attached host directory {:?}", tag),
+        None => log::info!("Attached host directory"),
+    }
+    *HOST_FS.lock() = Some(fs);
+    Ok(())
+}
+
+crate::initcall!(Device, init);
+
+/// Attach the directory shared through the first virtio 9P transport, if any
+pub fn init(init: &mut Init) {
+    let device = match find(DEVICE_TYPE).next() {
+        Some(device) => device,
+        None => return,
+    };
+    log::info!("Virtio 9P transport at {}", device.address);
+    match setup(init, device) {
+        Ok(()) => {
+            devices::bind_pci(device.address, "virtio-9p", State::Bound);
+            devices::on_quiesce(device.address, quiesce);
+        }
+        Err(e) => {
+            log::warn!("Host directory unavailable: {}", e);
+            devices::bind_pci(device.address, "virtio-9p", State::Failed);
+        }
+    }
+}
+
+/// Reset the virtio 9P transport, if any
+fn quiesce() {
+    if let Some(mut fs) = HOST_FS.lock().take() {
+        fs.transport.reset();
+    }
+}
+
+/// Read the file at `path`, relative to the shared directory, from `offset`
+/// into `buf`
+///
+/// Returns the number of bytes read, which is only less than the length of
+/// `buf` at the end of the file.
+pub fn read(path: &str, offset: u64, buf: &mut [u8]) -> Result<usize, &'static str> {
+    let mut guard = HOST_FS.lock();
+    let fs = guard.as_mut().ok_or("No host directory")?;
+    fs.walk(FILE_FID, path)?;
+    let result = fs
+        .open(FILE_FID, O_RDONLY)
+        .and_then(|()| fs.read_at(FILE_FID, offset, buf));
+    fs.clunk(FILE_FID)?;
+    result
+}
+
+/// Replace the contents of the file at `path`, relative to the shared
+/// directory, with `data`, creating the file if it does not exist
+pub fn write(path: &str, data: &[u8]) -> Result<(), &'static str> {
+    let (dir, name) = path.rsplit_once('/').unwrap_or(("", path));
+    if name.is_empty() {
+        return Err("Invalid path");
+    }
+    let mut guard = HOST_FS.lock();
+    let fs = guard.as_mut().ok_or("No host directory")?;
+    let opened = match fs.walk(FILE_FID, path) {
+        Ok(()) => fs.open(FILE_FID, O_WRONLY | O_TRUNC),
+        Err(e) if e == errno(2) => {
+            fs.walk(FILE_FID, dir)?;
+            fs.create(FILE_FID, name)
+        }
+        Err(e) => return Err(e),
+    };
+    let result = opened.and_then(|()| fs.write_at(FILE_FID, 0, data));
+    fs.clunk(FILE_FID)?;
+    result
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test_case]
+    fn encode_walk() {
+        let mut message = Message::new(ty::TWALK);
+        message.u32(ROOT_FID).u32(FILE_FID).u16(1).str("a");
+        assert_eq!(
+            message.finish(),
+            [
+                20,
+                0,
+                0,
+                0,
+                ty::TWALK,
+                0,
+                0,
+                0,
+                0,
+                0,
+                0,
+                1,
+                0,
+                0,
+                0,
+                1,
+                0,
+                1,
+                0,
+                b'a'
+            ]
+        );
+    }
+
+    #[test_case]
+    fn decode_response() {
+        let read = [13, 0, 0, 0, ty::RREAD, 0, 0, 2, 0, 0, 0, b'h', b'i', 0xff];
+        let mut reader = Reader::response(&read, ty::RREAD).unwrap();
+        assert_eq!(reader.u32(), Ok(2));
+        assert_eq!(reader.take(2), Ok(&b"hi"[..]));
+        assert!(reader.u8().is_err());
+
+        let error = [11, 0, 0, 0, ty::RLERROR, 0, 0, 2, 0, 0, 0];
+        assert_eq!(
+            Reader::response(&error, ty::RREAD).err(),
+            Some("No such file or directory")
+        );
+    }
+}
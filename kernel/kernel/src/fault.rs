@@ -0,0 +1,226 @@
+//! Exceptions caused by user processes
+//!
+//! An exception in user mode terminates the process instead of the kernel. The
+//! exception handler records the fault and returns to
+//! [`threads::fault_handler`] in kernel mode rather than to the process. With
+//! the registers of the process still loaded, it saves them and continues the
+//! system call loop with [`CODE`], which logs a [`Report`] of the fault and
+//! terminates the process.
+//!
+//! [`threads::fault_handler`]: crate::threads::fault_handler
+
+use crate::vm::AddressSpace;
+use core::fmt;
+use spin::Mutex;
+use x86_64::{
+    structures::paging::{Page, PageTableFlags},
+    VirtAddr,
+};
+
+/// Code with which the system call loop is continued after a fault
+pub const CODE: u64 = u64::MAX;
+
+/// Number of words of the user stack included in a report
+const STACK_WORDS: usize = 8;
+
+/// Exception a user process can cause
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub enum Exception {
+    DivideError,
+    InvalidOpcode,
+    GeneralProtection,
+    PageFault,
+}
+
+/// State of the process recorded by the exception handler
+#[derive(Copy, Clone, Debug)]
+pub struct Fault {
+    pub exception: Exception,
+    pub error_code: Option<u64>,
+    /// Address accessed by a page fault
+    pub address: Option<VirtAddr>,
+    pub rip: u64,
+    pub rsp: u64,
+    pub rflags: u64,
+}
+
+/// General purpose registers of the process, in the order they are saved
+#[derive(Copy, Clone, Debug)]
+#[repr(C)]
+pub struct Registers {
+    pub rax: u64,
+    pub rbx: u64,
+    pub rcx: u64,
+    pub rdx: u64,
+    pub rsi: u64,
+    pub rdi: u64,
+    pub rbp: u64,
+    pub r8: u64,
+    pub r9: u64,
+    pub r10: u64,
+    pub r11: u64,
+    pub r12: u64,
+    pub r13: u64,
+    pub r14: u64,
+    pub r15: u64,
+}
+
+static FAULT: Mutex<Option<Fault>> = Mutex::new(None);
+
+/// Registers saved by [`threads::fault_handler`]
+///
+/// [`threads::fault_handler`]: crate::threads::fault_handler
+pub static mut REGISTERS: Registers = Registers {
+    rax: 0,
+    rbx: 0,
+    rcx: 0,
+    rdx: 0,
+    rsi: 0,
+    rdi: 0,
+    rbp: 0,
+    r8: 0,
+    r9: 0,
+    r10: 0,
+    r11: 0,
+    r12: 0,
+    r13: 0,
+    r14: 0,
+    r15: 0,
+};
+
+/// Record `fault`, to be reported once the registers are saved
+pub fn record(fault: Fault) {
+    *FAULT.lock() = Some(fault);
+}
+
+/// Everything known about the fault of a process
+pub struct Report {
+    pub pid: u64,
+    pub fault: Fault,
+    pub registers: Registers,
+    /// Words at the top of the stack, if mapped
+    pub stack: [Option<u64>; STACK_WORDS],
+}
+
+impl Report {
+    /// Take the recorded fault of process `pid`, reading its stack through
+    /// the mappings of `address_space`
+    ///
+    /// # Safety
+    /// Must only be called after [`threads::fault_handler`] saved the
+    /// registers.
+    ///
+    /// [`threads::fault_handler`]: crate::threads::fault_handler
+    pub unsafe fn take(pid: u64, address_space: &AddressSpace) -> Option<Self> {
+        let fault = FAULT.lock().take()?;
+        let readable = |addr: u64| {
+            let page = match VirtAddr::try_new(addr) {
+                Ok(addr) => Page::containing_address(addr),
+                Err(_) => return false,
+            };
+            let flags = PageTableFlags::PRESENT | PageTableFlags::USER_ACCESSIBLE;
+            address_space.mappings().any(|mapping| {
+                mapping.pid == Some(pid)
+                    && mapping.flags.contains(flags)
+                    && mapping.pages.start <= page
+                    && page < mapping.pages.end
+            })
+        };
+        let mut stack = [None; STACK_WORDS];
+        for (i, word) in stack.iter_mut().enumerate() {
+            let addr = fault.rsp.wrapping_add(i as u64 * 8);
+            if addr % 8 == 0 && readable(addr) {
+                *word = Some((addr as *const u64).read_volatile());
+            }
+        }
+        Some(Self {
+            pid,
+            fault,
+            registers: REGISTERS,
+            stack,
+        })
+    }
+}
+
+impl fmt::Display for Report {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let fault = &self.fault;
+        write!(f, "Process {} caused {:?}", self.pid, fault.exception)?;
+        if let Some(error_code) = fault.error_code {
+            write!(f, " (error code {:#x})", error_code)?;
+        }
+        if let Some(address) = fault.address {
+            write!(f, " accessing {:#x}", address.as_u64())?;
+        }
+        writeln!(f)?;
+        writeln!(
+            f,
+            "rip {:016x} rsp {:016x} rflags {:016x}",
+            fault.rip, fault.rsp, fault.rflags
+        )?;
+        let r = &self.registers;
+        let registers = [
+            ("rax", r.rax),
+            ("rbx", r.rbx),
+            ("rcx", r.rcx),
+            ("rdx", r.rdx),
+            ("rsi", r.rsi),
+            ("rdi", r.rdi),
+            ("rbp", r.rbp),
+            ("r8", r.r8),
+            ("r9", r.r9),
+            ("r10", r.r10),
+            ("r11", r.r11),
+            ("r12", r.r12),
+            ("r13", r.r13),
+            ("r14", r.r14),
+            ("r15", r.r15),
+        ];
+        for line in registers.chunks(3) {
+            for (i, (name, value)) in line.iter().enumerate() {
+                let separator = if i == 0 { "" } else { " " };
+                write!(f, "{}{:<3} {:016x}", separator, name, value)?;
+            }
+            writeln!(f)?;
+        }
+        write!(f, "Stack:")?;
+        for (i, word) in self.stack.iter().enumerate() {
+            let addr = fault.rsp.wrapping_add(i as u64 * 8);
+            match word {
+                Some(word) => write!(f, "\n{:016x}: {:016x}", addr, word)?,
+                None => write!(f, "\n{:016x}: unmapped", addr)?,
+            }
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use alloc::format;
+
+    #[test_case]
+    fn report() {
+        let report = Report {
+            pid: 3,
+            fault: Fault {
+                exception: Exception::PageFault,
+                error_code: Some(0x6),
+                address: Some(VirtAddr::new(0xdead_0000)),
+                rip: 0x20_1000,
+                rsp: 0x2ff8,
+                rflags: 0x202,
+            },
+            registers: unsafe { REGISTERS },
+            stack: [Some(0x20_1234), None, None, None, None, None, None, None],
+        };
+        let text = format!("{}", report);
+        assert!(
+            text.starts_with("Process 3 caused PageFault (error code 0x6) accessing 0xdead0000\n")
+        );
+        assert!(text.contains("\n0000000000002ff8: 0000000000201234\n"));
+        assert!(text.ends_with("0000000000003030: unmapped"));
+        assert_eq!(text.lines().count(), 2 + 5 + 1 + 8);
+    }
+}
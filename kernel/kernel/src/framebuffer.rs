@@ -0,0 +1,308 @@
+//! Frame buffer access for user processes
+//!
+//! The frame buffer is granted to processes either exclusively or shared with
+//! other processes requesting shared access. Grants are mapped at a virtual
+//! address chosen by the kernel and revoked on release or when the owning
+//! process exits.
+//!
+//! There is no vertical blank interrupt, so vertical blanks are emulated using
+//! the timer at an assumed refresh rate to allow clients to pace their frames.
+//!
+//! While no process has access, the kernel shows its [`console`] on the frame
+//! buffer.
+//!
+//! If a virtio GPU is available its frame buffer is used instead of the one
+//! set up by UEFI. Its mode can be changed at runtime, but clients need to
+//! [`present`] their changes for them to be displayed.
+
+pub mod cursor;
+pub mod screenshot;
+
+use crate::{
+    console,
+    devices::{self, Resource, State},
+    drivers::virtio::gpu,
+    idle, interrupts,
+    sync::SeqLock,
+    vm::Backing,
+    Init,
+};
+use alloc::{vec, vec::Vec};
+use common::boot::{self, FramebufferInfo};
+use core::mem;
+use spin::Mutex;
+use sys::{FrameBuffer, FrameBufferAccess};
+use x86_64::{
+    structures::paging::{page::PageRange, Page, PageTableFlags, PhysFrame, Size4KiB},
+    VirtAddr,
+};
+
+/// Lowest virtual address at which the frame buffer is mapped
+const MAP_START: VirtAddr = VirtAddr::new_truncate(0x7000000);
+/// Virtual address below which the frame buffer mapping has to end
+const MAP_END: VirtAddr = VirtAddr::new_truncate(0x40000000);
+
+/// Assumed refresh rate of the display, as UEFI does not report it
+const REFRESH_RATE: u64 = 60;
+
+/// Frame buffer mapping handed out to a process
+struct Grant {
+    pid: u64,
+    access: FrameBufferAccess,
+    /// Address of the first byte of the frame buffer
+    start: VirtAddr,
+    pages: PageRange,
+}
+
+static GRANTS: Mutex<Vec<Grant>> = Mutex::new(Vec::new());
+
+/// Frame buffer handed out to processes
+static CURRENT: SeqLock<Option<FramebufferInfo>> = SeqLock::new(None);
+
+/// Select the frame buffer and initialize the overlays drawn by the kernel on
+/// top of it
+///
+/// Should be called after [`crate::initcall::run`].
+pub fn init(init: &mut Init) {
+    let gpu = gpu::init(init);
+    if let Some(boot) = init.boot_info.fb {
+        let resources = vec![Resource::Memory(boot.phys_addr, boot.size as u64)];
+        let id = devices::add("UEFI frame buffer".into(), resources);
+        if gpu.is_none() {
+            devices::bind(id, "framebuffer", State::Bound);
+        }
+    }
+    let fb = gpu.or(init.boot_info.fb);
+    if let Some(fb) = &fb {
+        cursor::init(fb);
+    }
+    CURRENT.write(fb);
+}
+
+/// Frame buffer that is currently in use
+pub fn current() -> Option<FramebufferInfo> {
+    CURRENT.read()
+}
+
+/// Grant process `pid` access to the frame buffer and map it
+///
+/// Requesting the frame buffer again with the same access returns the existing
+/// mapping. [`None`] is returned if there is no (supported) frame buffer or if
+/// the requested access conflicts with existing grants.
+pub fn request(init: &mut Init, pid: u64, access: FrameBufferAccess) -> Option<FrameBuffer> {
+    let fb = current()?;
+    let format = match fb.format {
+        boot::PixelFormat::Rgb => sys::PixelFormat::Rgb,
+        boot::PixelFormat::Bgr => sys::PixelFormat::Bgr,
+        format => {
+            log::warn!("Unsupported pixel format {:?}", format);
+            return None;
+        }
+    };
+    let describe = |start: VirtAddr| FrameBuffer {
+        ptr: start.as_mut_ptr(),
+        size: fb.size,
+        shape: fb.resolution,
+        stride: fb.stride,
+        format,
+    };
+
+    let mut grants = GRANTS.lock();
+    if let Some(grant) = grants.iter().find(|grant| grant.pid == pid) {
+        if grant.access != access {
+            log::warn!("Process {} already has {:?} access", pid, grant.access);
+            return None;
+        }
+        return Some(describe(grant.start));
+    }
+    if let Some(grant) = grants.iter().find(|grant| {
+        access == FrameBufferAccess::Exclusive || grant.access == FrameBufferAccess::Exclusive
+    }) {
+        log::warn!(
+            "Frame buffer already granted to process {} ({:?})",
+            grant.pid,
+            grant.access
+        );
+        return None;
+    }
+
+    let phys_start = fb.phys_addr;
+    let frames = PhysFrame::<Size4KiB>::range_inclusive(
+        PhysFrame::containing_address(phys_start),
+        PhysFrame::containing_address(phys_start + (fb.size - 1)),
+    );
+    let count = frames.end - frames.start + 1;
+    let first_page = match init.address_space.find_free(MAP_START, MAP_END, count) {
+        Some(page) => page,
+        None => {
+            log::warn!("No virtual memory available to map frame buffer");
+            return None;
+        }
+    };
+    let flags =
+        PageTableFlags::PRESENT | PageTableFlags::WRITABLE | PageTableFlags::USER_ACCESSIBLE;
+    let pages = Page::range(first_page, first_page + count);
+    let mapped = unsafe {
+        init.address_space.map_frames(
+            pages,
+            frames.start,
+            flags,
+            Backing::FrameBuffer,
+            Some(pid),
+            &mut init.frame_allocator,
+        )
+    };
+    if let Err(e) = mapped {
+        log::warn!("Failed to map frame buffer: {}", e);
+        return None;
+    }
+    let start = first_page.start_address() + (phys_start - frames.start.start_address());
+    log::info!(
+        "Granted {:?} frame buffer access to process {} at {:?}",
+        access,
+        pid,
+        start
+    );
+    console::hide();
+    grants.push(Grant {
+        pid,
+        access,
+        start,
+        pages,
+    });
+    Some(describe(start))
+}
+
+/// Revoke the frame buffer grant of process `pid` and unmap it
+///
+/// Returns whether the process had been granted access. The console is shown
+/// again once no process has access.
+pub fn release(init: &mut Init, pid: u64) -> bool {
+    if !revoke(init, pid) {
+        return false;
+    }
+    if GRANTS.lock().is_empty() {
+        console::show();
+    }
+    true
+}
+
+/// Revoke the frame buffer grant of process `pid` and unmap it, leaving the
+/// console hidden
+fn revoke(init: &mut Init, pid: u64) -> bool {
+    let mut grants = GRANTS.lock();
+    let grant = match grants.iter().position(|grant| grant.pid == pid) {
+        Some(i) => grants.swap_remove(i),
+        None => return false,
+    };
+    log::info!("Revoking frame buffer access of process {}", pid);
+    init.address_space
+        .unmap(grant.pages.start, &mut init.frame_allocator)
+        .unwrap();
+    true
+}
+
+/// Free unused capacity of the grant list, returning the number of bytes freed
+///
+/// Does nothing if the grant list is in use.
+pub fn reclaim() -> usize {
+    let mut grants = match GRANTS.try_lock() {
+        Some(grants) => grants,
+        None => return 0,
+    };
+    let capacity = grants.capacity();
+    grants.shrink_to_fit();
+    (capacity - grants.capacity()) * mem::size_of::<Grant>()
+}
+
+/// Change the resolution of the frame buffer granted exclusively to `pid`
+///
+/// The grant is moved to the new frame buffer, which is returned. [`None`] is
+/// returned if the process has no exclusive access, if mode setting is
+/// unsupported, which requires a virtio GPU, or if the mode could not be set,
+/// in which case the grant to the old frame buffer is kept as it was.
+pub fn set_mode(init: &mut Init, pid: u64, resolution: (u32, u32)) -> Option<FrameBuffer> {
+    let exclusive = GRANTS
+        .lock()
+        .iter()
+        .any(|grant| grant.pid == pid && grant.access == FrameBufferAccess::Exclusive);
+    if !exclusive {
+        log::warn!("Process {} needs exclusive access to set mode", pid);
+        return None;
+    }
+    if !gpu::available() {
+        log::warn!("Mode setting requires a virtio GPU");
+        return None;
+    }
+    cursor::remove();
+    let fb = match gpu::set_mode(init, resolution) {
+        Ok(fb) => fb,
+        Err(e) => {
+            log::warn!("Failed to set mode: {}", e);
+            if let Some(fb) = current() {
+                cursor::init(&fb);
+            }
+            return None;
+        }
+    };
+    // The old frame buffer is freed, so it may no longer be mapped; the
+    // console stays hidden as it would draw on it
+    revoke(init, pid);
+    CURRENT.write(Some(fb));
+    cursor::init(&fb);
+    let granted = request(init, pid, FrameBufferAccess::Exclusive);
+    if granted.is_none() {
+        console::show();
+    }
+    granted
+}
+
+/// Display changes made to the frame buffer by process `pid`
+///
+/// Returns `false` if the process has no frame buffer access or the changes
+/// could not be displayed.
+pub fn present(pid: u64) -> bool {
+    if !GRANTS.lock().iter().any(|grant| grant.pid == pid) {
+        return false;
+    }
+    match gpu::flush() {
+        Ok(()) => true,
+        Err(e) => {
+            log::warn!("Failed to present frame buffer: {}", e);
+            false
+        }
+    }
+}
+
+/// Print a screenshot of the frame buffer to the serial port
+///
+/// The cursor is hidden so screenshots only contain what clients drew. Returns
+/// `false` if process `pid` has no frame buffer access or the frame buffer
+/// format is not supported.
+pub fn screenshot(pid: u64) -> bool {
+    if !GRANTS.lock().iter().any(|grant| grant.pid == pid) {
+        return false;
+    }
+    let fb = match current() {
+        Some(fb) => fb,
+        None => return false,
+    };
+    cursor::hide();
+    screenshot::capture(&fb)
+}
+
+/// Block until the next emulated vertical blank
+///
+/// Returns `false` without blocking if process `pid` has no frame buffer
+/// access.
+pub fn wait_vsync(pid: u64) -> bool {
+    if !GRANTS.lock().iter().any(|grant| grant.pid == pid) {
+        return false;
+    }
+    let frame = |ticks: u64| ticks * REFRESH_RATE / interrupts::TIMER_FREQUENCY as u64;
+    let current = frame(interrupts::ticks());
+    while frame(interrupts::ticks()) == current {
+        idle::wait();
+    }
+    true
+}
@@ -0,0 +1,159 @@
+//! Software cursor drawn on top of the frame buffer
+//!
+//! The pixels covered by the cursor are saved when it is drawn and restored
+//! when it moves, so clients don't need to redraw anything. Clients drawing
+//! underneath the cursor do overwrite it until it moves again.
+
+use common::boot::{offset, FramebufferInfo, PixelFormat};
+use core::ptr;
+use spin::Mutex;
+use x86_64::instructions::interrupts;
+
+/// Cursor image: `#` is the outline, `.` the fill and spaces are transparent
+const IMAGE: [&[u8; WIDTH]; HEIGHT] = [
+    b"#          ",
+    b"##         ",
+    b"#.#        ",
+    b"#..#       ",
+    b"#...#      ",
+    b"#....#     ",
+    b"#.....#    ",
+    b"#......#   ",
+    b"#.......#  ",
+    b"#........# ",
+    b"#.....#####",
+    b"#..#..#    ",
+    b"#.# #..#   ",
+    b"##  #..#   ",
+    b"#    #..#  ",
+    b"     ###   ",
+];
+const WIDTH: usize = 11;
+const HEIGHT: usize = 16;
+
+/// Colors used for the cursor, which are the same for RGB and BGR pixels
+const OUTLINE: u32 = 0x000000;
+const FILL: u32 = 0xffffff;
+
+struct Cursor {
+    /// Start of the frame buffer in the offset mapping
+    base: *mut u32,
+    resolution: (usize, usize),
+    stride: usize,
+    /// Screen coordinates of the tip of the cursor
+    position: (usize, usize),
+    visible: bool,
+    /// Frame buffer contents covered by the cursor
+    saved: [u32; WIDTH * HEIGHT],
+}
+
+// Safe because the frame buffer is only accessed while holding the lock
+unsafe impl Send for Cursor {}
+
+static CURSOR: Mutex<Option<Cursor>> = Mutex::new(None);
+
+impl Cursor {
+    /// Pointer to the frame buffer pixel at offset `(dx, dy)` in the image, if
+    /// it is on screen
+    fn pixel(&self, dx: usize, dy: usize) -> Option<*mut u32> {
+        let (x, y) = (self.position.0 + dx, self.position.1 + dy);
+        if x < self.resolution.0 && y < self.resolution.1 {
+            Some(self.base.wrapping_add(y * self.stride + x))
+        } else {
+            None
+        }
+    }
+
+    fn draw(&mut self) {
+        for (dy, row) in IMAGE.iter().enumerate() {
+            for (dx, byte) in row.iter().enumerate() {
+                let color = match byte {
+                    b'#' => OUTLINE,
+                    b'.' => FILL,
+                    _ => continue,
+                };
+                if let Some(pixel) = self.pixel(dx, dy) {
+                    unsafe {
+                        self.saved[dy * WIDTH + dx] = ptr::read_volatile(pixel);
+                        ptr::write_volatile(pixel, color);
+                    }
+                }
+            }
+        }
+        self.visible = true;
+    }
+
+    fn erase(&mut self) {
+        if !self.visible {
+            return;
+        }
+        for (dy, row) in IMAGE.iter().enumerate() {
+            for (dx, byte) in row.iter().enumerate() {
+                if *byte == b' ' {
+                    continue;
+                }
+                if let Some(pixel) = self.pixel(dx, dy) {
+                    unsafe { ptr::write_volatile(pixel, self.saved[dy * WIDTH + dx]) };
+                }
+            }
+        }
+        self.visible = false;
+    }
+}
+
+/// Set up the (initially hidden) cursor in the middle of the frame buffer
+///
+/// Replaces the cursor of a previous frame buffer without touching its memory.
+pub fn init(fb: &FramebufferInfo) {
+    if fb.format == PixelFormat::Bitmask {
+        log::warn!("Cursor not supported for bitmask pixel format");
+        remove();
+        return;
+    }
+    let cursor = Cursor {
+        base: offset::phys_to_virt(fb.phys_addr).as_mut_ptr(),
+        resolution: fb.resolution,
+        stride: fb.stride,
+        position: (fb.resolution.0 / 2, fb.resolution.1 / 2),
+        visible: false,
+        saved: [0; WIDTH * HEIGHT],
+    };
+    interrupts::without_interrupts(|| *CURSOR.lock() = Some(cursor));
+}
+
+/// Remove the cursor without touching the frame buffer, e.g. because the frame
+/// buffer is about to be freed
+pub fn remove() {
+    interrupts::without_interrupts(|| *CURSOR.lock() = None);
+}
+
+/// Run `f` on the cursor, if there is one
+///
+/// Interrupts are disabled so the cursor can also be used from interrupt
+/// handlers.
+fn with_cursor<F: FnOnce(&mut Cursor) -> T, T>(f: F) -> Option<T> {
+    interrupts::without_interrupts(|| CURSOR.lock().as_mut().map(f))
+}
+
+/// Move the cursor by a relative amount, clamped to the screen, and show it
+pub fn move_by(dx: isize, dy: isize) {
+    with_cursor(|cursor| {
+        let (x, y) = cursor.position;
+        let (w, h) = cursor.resolution;
+        let x = (x as isize + dx).clamp(0, w as isize - 1) as usize;
+        let y = (y as isize + dy).clamp(0, h as isize - 1) as usize;
+        cursor.erase();
+        cursor.position = (x, y);
+        cursor.draw();
+    });
+}
+
+/// Current screen coordinates of the tip of the cursor
+pub fn position() -> Option<(usize, usize)> {
+    with_cursor(|cursor| cursor.position)
+}
+
+/// Remove the cursor from the screen until it is moved again
+pub fn hide() {
+    with_cursor(Cursor::erase);
+}
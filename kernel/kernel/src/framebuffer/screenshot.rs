@@ -0,0 +1,46 @@
+//! Screenshots of the frame buffer sent over the serial port
+//!
+//! The frame buffer is encoded as binary PPM, which is in turn encoded as
+//! base64 and printed between [`BEGIN`] and [`END`] marker lines. The xtask
+//! runner extracts these blocks from the serial output and saves them.
+
+use crate::base64::Encoder;
+use common::{
+    boot::{offset, FramebufferInfo, PixelFormat},
+    println,
+};
+use core::{fmt, ptr};
+
+/// Line preceding the encoded screenshot
+pub const BEGIN: &str = "-----BEGIN SCREENSHOT-----";
+/// Line following the encoded screenshot
+pub const END: &str = "-----END SCREENSHOT-----";
+
+/// Print a screenshot of frame buffer `fb` to the serial port
+///
+/// Returns `false` if the pixel format is not supported.
+pub fn capture(fb: &FramebufferInfo) -> bool {
+    use fmt::Write;
+
+    let rgb: fn(u32) -> [u8; 3] = match fb.format {
+        PixelFormat::Rgb => |pixel: u32| [pixel as u8, (pixel >> 8) as u8, (pixel >> 16) as u8],
+        PixelFormat::Bgr => |pixel: u32| [(pixel >> 16) as u8, (pixel >> 8) as u8, pixel as u8],
+        PixelFormat::Bitmask => return false,
+    };
+    let base = offset::phys_to_virt(fb.phys_addr).as_ptr::<u32>();
+    let (width, height) = fb.resolution;
+    log::info!("Taking {}x{} screenshot", width, height);
+
+    println!("{}", BEGIN);
+    let mut encoder = Encoder::new();
+    write!(encoder, "P6\n{} {}\n255\n", width, height).unwrap();
+    for y in 0..height {
+        for x in 0..width {
+            let pixel = unsafe { ptr::read_volatile(base.add(y * fb.stride + x)) };
+            encoder.extend(&rgb(pixel));
+        }
+    }
+    encoder.finish();
+    println!("{}", END);
+    true
+}
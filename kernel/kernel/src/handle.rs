@@ -0,0 +1,190 @@
+//! Handles to kernel objects held by processes
+//!
+//! Devices shared between processes, such as the frame buffer, are only
+//! accessible to processes holding a handle to them with the rights required
+//! by the system call, see [`SyscallCode::required_rights`]. Processes receive
+//! their handles when spawned and can only restrict or close them, so access
+//! is decided by whoever spawns a process.
+//!
+//! Only the first process, init, is spawned with handles to all devices. Other
+//! processes get the handles their spawning process granted them, so init (or
+//! a process it delegated to) decides which process may access which device.
+//!
+//! [`SyscallCode::required_rights`]: sys::SyscallCode::required_rights
+
+use alloc::vec::Vec;
+use spin::Mutex;
+use sys::{ObjectKind, Rights};
+
+/// Maximum number of handles per process
+const MAX_HANDLES: usize = 16;
+
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub struct Handle {
+    pub kind: ObjectKind,
+    pub rights: Rights,
+}
+
+/// Handles of a process, identified by their index
+#[derive(Clone, Debug)]
+pub struct HandleTable {
+    handles: [Option<Handle>; MAX_HANDLES],
+}
+
+impl HandleTable {
+    /// Table without handles
+    pub const fn new() -> Self {
+        Self {
+            handles: [None; MAX_HANDLES],
+        }
+    }
+
+    /// Table with all rights to every device, the audit log, the power state
+    /// and the tracer, given to init
+    pub fn devices() -> Self {
+        let mut table = Self::new();
+        for &kind in &[
+            ObjectKind::FrameBuffer,
+            ObjectKind::Audio,
+            ObjectKind::Input,
+            ObjectKind::HostFs,
+            ObjectKind::AuditLog,
+            ObjectKind::Power,
+            ObjectKind::Trace,
+        ] {
+            table.insert(kind, Rights::ALL).unwrap();
+        }
+        table
+    }
+
+    /// Add a handle to an object of `kind` with `rights`
+    pub fn insert(&mut self, kind: ObjectKind, rights: Rights) -> Result<u64, &'static str> {
+        let (index, slot) = self
+            .handles
+            .iter_mut()
+            .enumerate()
+            .find(|(_, slot)| slot.is_none())
+            .ok_or("Too many handles")?;
+        *slot = Some(Handle { kind, rights });
+        Ok(index as u64)
+    }
+
+    /// Number of handles in the table
+    pub fn count(&self) -> usize {
+        self.handles.iter().flatten().count()
+    }
+
+    pub fn get(&self, handle: u64) -> Option<Handle> {
+        *self.handles.get(handle as usize)?
+    }
+
+    /// First handle to an object of `kind`
+    pub fn find(&self, kind: ObjectKind) -> Option<u64> {
+        self.handles
+            .iter()
+            .position(|slot| slot.map_or(false, |handle| handle.kind == kind))
+            .map(|index| index as u64)
+    }
+
+    /// Whether any handle grants `rights` to an object of `kind`
+    pub fn allows(&self, kind: ObjectKind, rights: Rights) -> bool {
+        self.handles
+            .iter()
+            .flatten()
+            .any(|handle| handle.kind == kind && handle.rights.contains(rights))
+    }
+
+    /// Keep only `rights` of `handle`
+    pub fn restrict(&mut self, handle: u64, rights: Rights) -> Result<(), &'static str> {
+        let handle = self
+            .handles
+            .get_mut(handle as usize)
+            .and_then(Option::as_mut)
+            .ok_or("Invalid handle")?;
+        handle.rights = handle.rights & rights;
+        Ok(())
+    }
+
+    pub fn close(&mut self, handle: u64) -> Result<(), &'static str> {
+        self.handles
+            .get_mut(handle as usize)
+            .and_then(Option::take)
+            .map(|_| ())
+            .ok_or("Invalid handle")
+    }
+}
+
+/// Handles granted by processes to the next process they spawn, by the
+/// identifier of the granting process
+static GRANTS: Mutex<Vec<(u64, HandleTable)>> = Mutex::new(Vec::new());
+
+/// Grant a copy of `handle` from `table` of process `pid` with only `rights`
+/// to the next process it spawns
+///
+/// Rights not held by the handle cannot be granted.
+pub fn grant(
+    pid: u64,
+    table: &HandleTable,
+    handle: u64,
+    rights: Rights,
+) -> Result<(), &'static str> {
+    let handle = table.get(handle).ok_or("Invalid handle")?;
+    let mut grants = GRANTS.lock();
+    let i = match grants.iter().position(|(other, _)| *other == pid) {
+        Some(i) => i,
+        None => {
+            grants.push((pid, HandleTable::new()));
+            grants.len() - 1
+        }
+    };
+    grants[i].1.insert(handle.kind, handle.rights & rights)?;
+    Ok(())
+}
+
+/// Take the handles process `pid` granted to the next process it spawns
+///
+/// Also called when the process exits, dropping the grants it did not pass on.
+pub fn take_grants(pid: u64) -> HandleTable {
+    let mut grants = GRANTS.lock();
+    match grants.iter().position(|(other, _)| *other == pid) {
+        Some(i) => grants.swap_remove(i).1,
+        None => HandleTable::new(),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test_case]
+    fn restrict_and_close() {
+        let mut table = HandleTable::new();
+        let handle = table.insert(ObjectKind::FrameBuffer, Rights::ALL).unwrap();
+        assert!(table.allows(ObjectKind::FrameBuffer, Rights::MAP | Rights::WRITE));
+        assert!(!table.allows(ObjectKind::Audio, Rights::WRITE));
+        table.restrict(handle, Rights::READ).unwrap();
+        assert!(!table.allows(ObjectKind::FrameBuffer, Rights::WRITE));
+        table.restrict(handle, Rights::ALL).unwrap();
+        assert_eq!(table.get(handle).unwrap().rights, Rights::READ);
+        table.close(handle).unwrap();
+        assert_eq!(table.find(ObjectKind::FrameBuffer), None);
+        assert!(table.close(handle).is_err());
+    }
+
+    #[test_case]
+    fn grant_subset() {
+        // Processes that never run
+        const PID: u64 = u64::MAX;
+        const OTHER: u64 = u64::MAX - 1;
+        let mut table = HandleTable::new();
+        let handle = table.insert(ObjectKind::Input, Rights::READ).unwrap();
+        grant(PID, &table, handle, Rights::ALL).unwrap();
+        assert!(grant(PID, &table, handle + 1, Rights::ALL).is_err());
+        // Grants only go to processes spawned by the granting one
+        assert_eq!(take_grants(OTHER).find(ObjectKind::Input), None);
+        let granted = take_grants(PID);
+        assert!(granted.allows(ObjectKind::Input, Rights::READ));
+        assert!(!granted.allows(ObjectKind::Input, Rights::WRITE));
+        assert_eq!(take_grants(PID).find(ObjectKind::Input), None);
+    }
+}
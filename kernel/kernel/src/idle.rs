@@ -0,0 +1,125 @@
+//! Waiting for interrupts while there is nothing to do
+//!
+//! If the CPU supports MONITOR/MWAIT, [`wait`] requests the deepest C-state
+//! it enumerates; otherwise it halts. QEMU only exposes MWAIT to guests with
+//! `-overcommit cpu-pm=on`. The time stamp counter cycles spent waiting are
+//! counted per C-state, halting counting as C1.
+
+use crate::{interrupts, trace};
+use core::{
+    arch::x86_64::{__cpuid, __get_cpuid_max},
+    sync::atomic::{AtomicU64, Ordering},
+};
+use spin::Once;
+
+/// Number of C-states that can be requested with MWAIT
+const C_STATES: usize = 8;
+
+/// MWAIT hint of the C-state requested, or [`None`] to halt instead
+static HINT: Once<Option<u32>> = Once::new();
+/// Time stamp counter when idle accounting started
+static START: AtomicU64 = AtomicU64::new(0);
+/// Address monitored while waiting; never written, as only interrupts wake up
+static MONITOR: AtomicU64 = AtomicU64::new(0);
+
+static RESIDENCY: [Residency; C_STATES] = {
+    const NEW: Residency = Residency::new();
+    [NEW; C_STATES]
+};
+
+struct Residency {
+    waits: AtomicU64,
+    cycles: AtomicU64,
+}
+
+impl Residency {
+    const fn new() -> Self {
+        Self {
+            waits: AtomicU64::new(0),
+            cycles: AtomicU64::new(0),
+        }
+    }
+}
+
+/// MWAIT hint of the deepest C-state and its deepest sub-state, if supported
+fn detect() -> Option<u32> {
+    if unsafe { __cpuid(1) }.ecx & (1 << 3) == 0 || unsafe { __get_cpuid_max(0) }.0 < 5 {
+        return None;
+    }
+    let leaf = unsafe { __cpuid(5) };
+    // Without the extensions the sub-states are not enumerated
+    if leaf.ecx & 1 == 0 {
+        return Some(0);
+    }
+    (1..C_STATES as u32)
+        .rev()
+        .map(|state| (state, (leaf.edx >> (4 * state)) & 0xf))
+        .find(|&(_, sub_states)| sub_states != 0)
+        .map(|(state, sub_states)| ((state - 1) << 4) | (sub_states - 1))
+}
+
+/// Choose how to wait and start counting idle time
+pub fn init() {
+    let hint = *HINT.call_once(detect);
+    START.store(trace::timestamp(), Ordering::Relaxed);
+    match hint {
+        Some(hint) => log::info!("Idling with MWAIT in C{}", c_state(hint)),
+        None => log::info!("Idling with HLT"),
+    }
+}
+
+/// C-state requested by MWAIT `hint`
+fn c_state(hint: u32) -> usize {
+    ((hint >> 4) & 0xf) as usize + 1
+}
+
+/// Wait for the next interrupt
+///
+/// Interrupts should be enabled.
+pub fn wait() {
+    let start = trace::timestamp();
+    let state = match HINT.get().copied().flatten() {
+        Some(hint) => {
+            unsafe {
+                asm!(
+                    "monitor",
+                    in("rax") &MONITOR as *const AtomicU64,
+                    in("ecx") 0,
+                    in("edx") 0,
+                    options(nostack)
+                );
+                asm!("mwait", in("eax") hint, in("ecx") 0, options(nostack));
+            }
+            c_state(hint)
+        }
+        None => {
+            x86_64::instructions::hlt();
+            1
+        }
+    };
+    let residency = &RESIDENCY[state.min(C_STATES) - 1];
+    residency.waits.fetch_add(1, Ordering::Relaxed);
+    residency
+        .cycles
+        .fetch_add(trace::timestamp() - start, Ordering::Relaxed);
+}
+
+/// Log the time spent in each C-state
+pub fn report() {
+    let total = trace::timestamp() - START.load(Ordering::Relaxed);
+    for (i, residency) in RESIDENCY.iter().enumerate() {
+        let waits = residency.waits.load(Ordering::Relaxed);
+        if waits == 0 {
+            continue;
+        }
+        let cycles = residency.cycles.load(Ordering::Relaxed);
+        log::info!(
+            "CPU {} idle in C{}: {} waits, {} cycles ({}%)",
+            interrupts::cpu_id(),
+            i + 1,
+            waits,
+            cycles,
+            cycles * 100 / total.max(1)
+        );
+    }
+}
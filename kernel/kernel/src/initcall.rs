@@ -0,0 +1,121 @@
+//! Registration of functions run during boot
+//!
+//! Drivers and subsystems register an init function with [`initcall!`] next to
+//! their definition instead of being called from `main.rs`. Registrations are
+//! collected by the linker in the `initcall` section, which [`run`] walks once
+//! the [`Init`] structure exists. Functions run level by level; within a level
+//! the order is link order, which is unspecified, so a function that depends
+//! on another one should be registered at a later level. Device drivers only
+//! touch their own device, so their order does not matter, apart from PS/2
+//! coming before USB (see [`Level::Platform`]).
+//!
+//! [`initcall!`]: crate::initcall
+
+use crate::{config, interrupts, Init};
+use core::slice;
+
+/// Stage of boot at which an init function runs, in order
+#[derive(Copy, Clone, Debug, PartialEq, Eq, PartialOrd, Ord)]
+pub enum Level {
+    /// Set up of kernel facilities other functions rely on
+    Early,
+    /// Set up of CPU and platform features
+    Arch,
+    /// Discovery of buses devices are attached to
+    Bus,
+    /// Set up of devices at fixed I/O ports, such as the PS/2 controller,
+    /// before USB controllers are reset, which may end the firmware's PS/2
+    /// emulation of USB devices
+    Platform,
+    /// Set up of devices found on buses
+    Device,
+    /// Set up of file systems on devices
+    Fs,
+}
+
+impl Level {
+    pub const ALL: [Self; 6] = [
+        Self::Early,
+        Self::Arch,
+        Self::Bus,
+        Self::Platform,
+        Self::Device,
+        Self::Fs,
+    ];
+}
+
+/// Init function registered with [`initcall!`]
+///
+/// [`initcall!`]: crate::initcall
+pub struct InitCall {
+    pub level: Level,
+    /// Module the function was registered in
+    pub name: &'static str,
+    pub function: fn(&mut Init),
+}
+
+/// Register `$function` to run at boot at level `$level`
+///
+/// The function takes `&mut Init`.
+#[macro_export]
+macro_rules! initcall {
+    ($level:ident, $function:expr) => {
+        const _: () = {
+            // Kept by the linker through the start and stop symbols
+            #[link_section = "initcall"]
+            #[used]
+            static INITCALL: $crate::initcall::InitCall = $crate::initcall::InitCall {
+                level: $crate::initcall::Level::$level,
+                name: module_path!(),
+                function: $function,
+            };
+        };
+    };
+}
+
+extern "C" {
+    static __start_initcall: InitCall;
+    static __stop_initcall: InitCall;
+}
+
+/// All registered init functions, in link order
+fn all() -> &'static [InitCall] {
+    unsafe {
+        let start = &__start_initcall as *const InitCall;
+        let stop = &__stop_initcall as *const InitCall;
+        slice::from_raw_parts(start, stop.offset_from(start) as usize)
+    }
+}
+
+/// Run all registered init functions, level by level
+///
+/// With [`config::deterministic`] device interrupts are held back until all
+/// functions ran, so they cannot interleave with setting up devices.
+pub fn run(init: &mut Init) {
+    let mut run_all = || {
+        for level in Level::ALL {
+            for call in all().iter().filter(|call| call.level == level) {
+                log::debug!("Init {:?} {}", level, call.name);
+                (call.function)(init);
+            }
+        }
+    };
+    if config::deterministic() {
+        interrupts::without_device_interrupts(run_all);
+    } else {
+        run_all();
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test_case]
+    fn registered() {
+        let calls = all();
+        assert!(calls.iter().any(|call| call.level == Level::Bus));
+        assert!(calls.iter().any(|call| call.level == Level::Platform));
+        assert!(calls.iter().any(|call| call.level == Level::Device));
+    }
+}
@@ -0,0 +1,117 @@
+//! Fault injection
+//!
+//! To exercise error paths, every Nth frame allocation, heap allocation or
+//! system call can be made to fail, with N set by the `fail-frame`,
+//! `fail-heap` and `fail-syscall` options of the kernel configuration; zero,
+//! the default, injects nothing. An injected frame allocation returns no frame
+//! and an injected system call returns an error code without running. Heap
+//! allocations are infallible in most of the kernel, so an injected heap
+//! failure usually ends in the allocation error handler. The numbers of calls
+//! and injected failures are logged by [`report`].
+
+use crate::config;
+use core::sync::atomic::{AtomicU64, Ordering};
+
+/// Place where failures can be injected
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub enum Site {
+    Frame,
+    Heap,
+    Syscall,
+}
+
+impl Site {
+    const ALL: [Self; 3] = [Self::Frame, Self::Heap, Self::Syscall];
+
+    /// Interval of injected failures, or zero if disabled
+    fn every(self) -> u64 {
+        #[cfg(test)]
+        {
+            let forced = FORCED[self as usize].load(Ordering::Relaxed);
+            if forced != 0 {
+                return forced;
+            }
+        }
+        match self {
+            Site::Frame => config::fail_frame(),
+            Site::Heap => config::fail_heap(),
+            Site::Syscall => config::fail_syscall(),
+        }
+    }
+}
+
+static COUNTERS: [Counter; 3] = [Counter::new(), Counter::new(), Counter::new()];
+
+/// Intervals set by tests, used instead of the configured ones unless zero
+#[cfg(test)]
+static FORCED: [AtomicU64; 3] = [AtomicU64::new(0), AtomicU64::new(0), AtomicU64::new(0)];
+
+struct Counter {
+    calls: AtomicU64,
+    injected: AtomicU64,
+}
+
+impl Counter {
+    const fn new() -> Self {
+        Self {
+            calls: AtomicU64::new(0),
+            injected: AtomicU64::new(0),
+        }
+    }
+
+    /// Count a call, returning whether it is the `every`th one
+    fn hit(&self, every: u64) -> bool {
+        let calls = self.calls.fetch_add(1, Ordering::Relaxed) + 1;
+        if calls % every != 0 {
+            return false;
+        }
+        self.injected.fetch_add(1, Ordering::Relaxed);
+        true
+    }
+}
+
+/// Whether the current call at `site` should fail
+pub fn fail(site: Site) -> bool {
+    match site.every() {
+        0 => false,
+        every => COUNTERS[site as usize].hit(every),
+    }
+}
+
+/// Fail every `every`th call at `site` from now on, regardless of the
+/// configuration, or go back to the configured interval if zero
+#[cfg(test)]
+pub fn force(site: Site, every: u64) {
+    FORCED[site as usize].store(every, Ordering::Relaxed);
+    COUNTERS[site as usize].calls.store(0, Ordering::Relaxed);
+}
+
+/// Log the number of calls and injected failures of all enabled sites
+pub fn report() {
+    for &site in &Site::ALL {
+        if site.every() == 0 {
+            continue;
+        }
+        let counter = &COUNTERS[site as usize];
+        log::info!(
+            "{:?} fault injection: {} of {} calls failed",
+            site,
+            counter.injected.load(Ordering::Relaxed),
+            counter.calls.load(Ordering::Relaxed)
+        );
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test_case]
+    fn every_nth() {
+        let counter = Counter::new();
+        let hits: usize = (0..9).filter(|_| counter.hit(3)).count();
+        assert_eq!(hits, 3);
+        assert_eq!(counter.calls.load(Ordering::Relaxed), 9);
+        assert!(!counter.hit(3));
+    }
+}
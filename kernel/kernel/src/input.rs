@@ -0,0 +1,376 @@
+//! Routing of input events to user processes
+//!
+//! Events are pushed by device drivers, usually from interrupt handlers, so
+//! queues have a fixed capacity and nothing is allocated. Every process reading
+//! input gets its own queue, and events are only delivered to the queue of the
+//! focused process. The first process reading input is focused automatically.
+//!
+//! Processes can also read key events as characters of the US keyboard layout
+//! with [`read_key`], or whole lines echoed to the console with [`read_line`].
+
+use crate::{console, framebuffer::cursor, idle, jobs, shutdown, watchdog};
+use spin::Mutex;
+use sys::{InputEvent, MouseButton};
+use x86_64::instructions::interrupts;
+
+/// Maximum number of pending events per process; newer events are dropped
+/// when full
+const CAPACITY: usize = 256;
+/// Maximum number of processes with an input queue
+const MAX_CLIENTS: usize = 8;
+
+/// Usage id of the left control key; modifier bits map to consecutive ids
+const MODIFIER_USAGE: u8 = 0xe0;
+/// Modifier bits of the control and shift keys on either side
+const CTRL: u8 = 1 << 0 | 1 << 4;
+const SHIFT: u8 = 1 << 1 | 1 << 5;
+
+/// Characters of the keys with usage ids from 0x2d, without and with shift,
+/// or zero for keys without one
+const SYMBOLS: [&[u8; 12]; 2] = [b"-=[]\\\0;'`,./", b"_+{}|\0:\"~<>?"];
+/// Characters of the digit keys without and with shift
+const DIGITS: [&[u8; 10]; 2] = [b"1234567890", b"!@#$%^&*()"];
+
+/// Mouse buttons in the order used by [`Mouse::report`]
+const BUTTONS: [MouseButton; 3] = [MouseButton::Left, MouseButton::Right, MouseButton::Middle];
+
+static ROUTER: Mutex<Router> = Mutex::new(Router::new());
+
+/// Ring buffer of events
+struct Queue {
+    events: [Option<InputEvent>; CAPACITY],
+    start: usize,
+    len: usize,
+}
+
+impl Queue {
+    const fn new() -> Self {
+        Self {
+            events: [None; CAPACITY],
+            start: 0,
+            len: 0,
+        }
+    }
+
+    fn push(&mut self, event: InputEvent) -> bool {
+        if self.len == CAPACITY {
+            return false;
+        }
+        let i = (self.start + self.len) % CAPACITY;
+        self.events[i] = Some(event);
+        self.len += 1;
+        true
+    }
+
+    fn pop(&mut self) -> Option<InputEvent> {
+        if self.len == 0 {
+            return None;
+        }
+        let i = self.start;
+        self.start = (i + 1) % CAPACITY;
+        self.len -= 1;
+        self.events[i].take()
+    }
+}
+
+struct Client {
+    pid: u64,
+    queue: Queue,
+    /// Modifier keys held according to the events taken, in the bit order of
+    /// HID reports
+    modifiers: u8,
+}
+
+impl Client {
+    /// Take the oldest event, keeping track of modifier keys
+    fn pop(&mut self) -> Option<InputEvent> {
+        let event = self.queue.pop()?;
+        if let InputEvent::Key { usage, pressed } = event {
+            if let Some(bit) = usage.checked_sub(MODIFIER_USAGE).filter(|bit| *bit < 8) {
+                if pressed {
+                    self.modifiers |= 1 << bit;
+                } else {
+                    self.modifiers &= !(1 << bit);
+                }
+            }
+        }
+        Some(event)
+    }
+}
+
+struct Router {
+    focus: Option<u64>,
+    clients: [Option<Client>; MAX_CLIENTS],
+}
+
+impl Router {
+    const fn new() -> Self {
+        const NONE: Option<Client> = None;
+        Self {
+            focus: None,
+            clients: [NONE; MAX_CLIENTS],
+        }
+    }
+
+    fn client(&mut self, pid: u64) -> Option<&mut Client> {
+        self.clients
+            .iter_mut()
+            .flatten()
+            .find(|client| client.pid == pid)
+    }
+
+    /// Queue of process `pid`, which is created if necessary
+    fn register(&mut self, pid: u64) -> Option<&mut Client> {
+        if self.client(pid).is_none() {
+            let slot = self.clients.iter_mut().find(|slot| slot.is_none())?;
+            *slot = Some(Client {
+                pid,
+                queue: Queue::new(),
+                modifiers: 0,
+            });
+            log::debug!("Created input queue for process {}", pid);
+            if self.focus.is_none() {
+                log::info!("Focusing process {}", pid);
+                self.focus = Some(pid);
+            }
+        }
+        self.client(pid)
+    }
+}
+
+/// Run `f` on the router with interrupts disabled, as drivers push events from
+/// interrupt handlers
+fn with_router<F: FnOnce(&mut Router) -> T, T>(f: F) -> T {
+    interrupts::without_interrupts(|| f(&mut ROUTER.lock()))
+}
+
+/// Deliver an event to the focused process
+///
+/// The event is dropped if no process is focused or if it is a key used by the
+/// console.
+pub fn push(event: InputEvent) {
+    if let InputEvent::Key { usage, pressed } = event {
+        if console::key(usage, pressed) {
+            return;
+        }
+    }
+    with_router(|router| {
+        let focus = match router.focus {
+            Some(pid) => pid,
+            None => return,
+        };
+        if let Some(client) = router.client(focus) {
+            if !client.queue.push(event) {
+                log::trace!("Input queue of process {} full", focus);
+            }
+        }
+    });
+}
+
+/// Take the oldest event from the queue of process `pid`
+pub fn pop(pid: u64) -> Option<InputEvent> {
+    with_router(|router| match router.register(pid) {
+        Some(client) => client.pop(),
+        None => {
+            log::warn!("No input queue available for process {}", pid);
+            None
+        }
+    })
+}
+
+/// Character typed by pressing the key with `usage` while holding `modifiers`
+/// on a US keyboard
+///
+/// Control with a letter gives the corresponding control character.
+fn character(usage: u8, modifiers: u8) -> Option<u8> {
+    let shift = (modifiers & SHIFT != 0) as usize;
+    let c = match usage {
+        0x04..=0x1d => {
+            let letter = b'a' + usage - 0x04;
+            if modifiers & CTRL != 0 {
+                letter - b'a' + 1
+            } else if shift == 1 {
+                letter.to_ascii_uppercase()
+            } else {
+                letter
+            }
+        }
+        0x1e..=0x27 => DIGITS[shift][(usage - 0x1e) as usize],
+        0x28 | 0x58 => b'\n',
+        0x29 => 0x1b,
+        0x2a => 0x08,
+        0x2b => b'\t',
+        0x2c => b' ',
+        0x2d..=0x38 => SYMBOLS[shift][(usage - 0x2d) as usize],
+        _ => 0,
+    };
+    Some(c).filter(|&c| c != 0)
+}
+
+/// Take the next character typed for process `pid`, dropping other events
+/// before it
+pub fn read_key(pid: u64) -> Option<char> {
+    with_router(|router| {
+        let client = router.register(pid)?;
+        while let Some(event) = client.pop() {
+            if let InputEvent::Key {
+                usage,
+                pressed: true,
+            } = event
+            {
+                if let Some(c) = character(usage, client.modifiers) {
+                    return Some(char::from(c));
+                }
+            }
+        }
+        None
+    })
+}
+
+/// Read a line typed for process `pid` into `buf`, echoing it to the terminal
+/// of user processes
+///
+/// Blocks until Enter is pressed, in which case the line ends with a newline,
+/// or `buf` is full, and returns the length of the line. Backspace removes the
+/// last character; other control characters are ignored. Returns [`None`]
+/// early if the process is interrupted or a shutdown is requested.
+pub fn read_line(pid: u64, buf: &mut [u8]) -> Option<usize> {
+    let mut len = 0;
+    while len < buf.len() {
+        if jobs::interrupted(pid) || shutdown::requested() {
+            return None;
+        }
+        let c = match read_key(pid) {
+            Some(c) => c,
+            None => {
+                watchdog::touch();
+                idle::wait();
+                continue;
+            }
+        };
+        match c {
+            '\x08' if len > 0 => len -= 1,
+            c if c.is_ascii_control() && c != '\n' && c != '\t' => continue,
+            c => {
+                buf[len] = c as u8;
+                len += 1;
+            }
+        }
+        console::echo(c.encode_utf8(&mut [0; 4]));
+        if c == '\n' {
+            break;
+        }
+    }
+    Some(len)
+}
+
+/// Process that input is currently delivered to
+pub fn focus() -> Option<u64> {
+    with_router(|router| router.focus)
+}
+
+/// Deliver input to process `target` on behalf of process `pid`
+///
+/// Only the focused process may pass on focus, unless no process is focused.
+/// The target should have read input before. Returns whether focus changed.
+pub fn set_focus(pid: u64, target: u64) -> bool {
+    with_router(|router| {
+        if router.focus.map_or(false, |focus| focus != pid) {
+            log::warn!("Process {} cannot change focus as it is not focused", pid);
+            return false;
+        }
+        if router.client(target).is_none() {
+            log::warn!("Process {} has no input queue to focus", target);
+            return false;
+        }
+        log::info!("Focusing process {}", target);
+        router.focus = Some(target);
+        true
+    })
+}
+
+/// Remove the queue of process `pid`, e.g. when it exits
+///
+/// If the process was focused, focus moves to another process with a queue.
+pub fn remove(pid: u64) {
+    with_router(|router| {
+        if let Some(slot) = router
+            .clients
+            .iter_mut()
+            .find(|slot| slot.as_ref().map_or(false, |client| client.pid == pid))
+        {
+            *slot = None;
+        }
+        if router.focus == Some(pid) {
+            router.focus = router
+                .clients
+                .iter()
+                .flatten()
+                .next()
+                .map(|client| client.pid);
+        }
+    });
+}
+
+/// State of a pointing device, used by drivers to turn reports into events
+pub struct Mouse {
+    buttons: u8,
+}
+
+impl Mouse {
+    pub const fn new() -> Self {
+        Self { buttons: 0 }
+    }
+
+    /// Handle a report of a pointing device
+    ///
+    /// Movement has positive `dy` pointing down and moves the cursor. The
+    /// lowest bits of `buttons` are the left, right and middle button, and
+    /// positive `scroll` points towards the user.
+    pub fn report(&mut self, dx: i32, dy: i32, buttons: u8, scroll: i32) {
+        if dx != 0 || dy != 0 {
+            cursor::move_by(dx as isize, dy as isize);
+            let (x, y) = cursor::position().unwrap_or((0, 0));
+            push(InputEvent::MouseMove {
+                x: x as u32,
+                y: y as u32,
+                dx,
+                dy,
+            });
+        }
+        for (i, &button) in BUTTONS.iter().enumerate() {
+            let mask = 1 << i;
+            if (buttons ^ self.buttons) & mask != 0 {
+                push(InputEvent::MouseButton {
+                    button,
+                    pressed: buttons & mask != 0,
+                });
+            }
+        }
+        self.buttons = buttons;
+        if scroll != 0 {
+            push(InputEvent::MouseScroll { delta: scroll });
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test_case]
+    fn characters() {
+        let left_shift = 1 << 1;
+        let right_ctrl = 1 << 4;
+        assert_eq!(character(0x04, 0), Some(b'a'));
+        assert_eq!(character(0x1d, left_shift), Some(b'Z'));
+        assert_eq!(character(0x06, right_ctrl), Some(0x03));
+        assert_eq!(character(0x1e, 0), Some(b'1'));
+        assert_eq!(character(0x27, left_shift), Some(b')'));
+        assert_eq!(character(0x34, left_shift), Some(b'"'));
+        assert_eq!(character(0x28, 0), Some(b'\n'));
+        // Non-US # and F1
+        assert_eq!(character(0x32, 0), None);
+        assert_eq!(character(0x3a, 0), None);
+    }
+}
@@ -1,12 +1,25 @@
-use core::sync::atomic::{AtomicUsize, Ordering};
+use crate::{
+    acpi, config, crash_dump,
+    devices::{self, Resource, State},
+    drivers,
+    fault::{self, Exception, Fault},
+    latency,
+    physmap::KernelPageTable,
+    profile, shutdown, stack, telemetry, threads, time, trace, watchdog,
+};
+use alloc::vec;
+use core::sync::atomic::{AtomicBool, AtomicU16, AtomicU32, AtomicU64, Ordering};
 use spin::Once;
+use sys::{LatencySource, TraceKind};
 use x86_64::{
     instructions::interrupts,
-    registers::control::Cr2,
+    registers::control::{Cr2, Cr3},
     structures::idt::{InterruptDescriptorTable, InterruptStackFrame, PageFaultErrorCode},
+    VirtAddr,
 };
 
 mod gdt {
+    use core::ops::Range;
     use spin::Once;
     use x86_64::{
         instructions::{segmentation, tables},
@@ -14,6 +27,7 @@ mod gdt {
         structures::{
             gdt::{Descriptor, GlobalDescriptorTable, SegmentSelector},
             tss::TaskStateSegment,
+            DescriptorTablePointer,
         },
         VirtAddr,
     };
@@ -30,6 +44,24 @@ mod gdt {
 
     pub const DOUBLE_FAULT_IST_INDEX: u16 = 0;
     pub const GENERAL_IST_INDEX: u16 = 1;
+    pub const NMI_IST_INDEX: u16 = 2;
+    pub const MACHINE_CHECK_IST_INDEX: u16 = 3;
+    pub const PAGE_FAULT_IST_INDEX: u16 = 4;
+
+    /// Size of each stack of the interrupt stack table
+    const IST_STACK_SIZE: usize = 4096 * 5;
+
+    /// Top of a new stack for the interrupt stack table
+    ///
+    /// Every expansion has its own stack, which is not thread-safe.
+    macro_rules! ist_stack {
+        () => {{
+            static mut STACK: [u8; IST_STACK_SIZE] = [0; IST_STACK_SIZE];
+
+            let stack_start = VirtAddr::from_ptr(unsafe { &STACK });
+            stack_start + IST_STACK_SIZE
+        }};
+    }
 
     static GDT: Once<Gdt> = Once::new();
     static TSS: Once<TaskStateSegment> = Once::new();
@@ -37,7 +69,7 @@ mod gdt {
     /// Initialize everything related to the GDT
     ///
     /// This includes, specifically:
-    /// - Set up double fault stack in task state segment
+    /// - Set up interrupt stacks in task state segment
     /// - Initialize and load global descriptor table
     /// - Reset nonsensical segment registers
     /// - Set up code and task state segment selectors
@@ -45,23 +77,13 @@ mod gdt {
     pub fn init() {
         let tss = TSS.call_once(|| {
             let mut tss = TaskStateSegment::new();
-            // Set up stack for double fault handler
-            tss.interrupt_stack_table[DOUBLE_FAULT_IST_INDEX as usize] = {
-                const STACK_SIZE: usize = 4096 * 5;
-                // Not thread-safe
-                static mut STACK: [u8; STACK_SIZE] = [0; STACK_SIZE];
-
-                let stack_start = VirtAddr::from_ptr(unsafe { &STACK });
-                stack_start + STACK_SIZE
-            };
-            tss.interrupt_stack_table[GENERAL_IST_INDEX as usize] = {
-                const STACK_SIZE: usize = 4096 * 5;
-                // Not thread-safe
-                static mut STACK: [u8; STACK_SIZE] = [0; STACK_SIZE];
-
-                let stack_start = VirtAddr::from_ptr(unsafe { &STACK });
-                stack_start + STACK_SIZE
-            };
+            // Exceptions that can occur while handling another interrupt get
+            // their own stack, so they don't overwrite the stack in use
+            tss.interrupt_stack_table[DOUBLE_FAULT_IST_INDEX as usize] = ist_stack!();
+            tss.interrupt_stack_table[GENERAL_IST_INDEX as usize] = ist_stack!();
+            tss.interrupt_stack_table[NMI_IST_INDEX as usize] = ist_stack!();
+            tss.interrupt_stack_table[MACHINE_CHECK_IST_INDEX as usize] = ist_stack!();
+            tss.interrupt_stack_table[PAGE_FAULT_IST_INDEX as usize] = ist_stack!();
             tss
         });
         let gdt = GDT.call_once(|| {
@@ -99,19 +121,66 @@ mod gdt {
         )
         .unwrap();
     }
+
+    /// Load the global descriptor table and segments again after a sleep state,
+    /// which loses them
+    pub fn resume() {
+        // Type bit set when the task state segment is loaded
+        const TSS_BUSY: u64 = 1 << 41;
+        let gdt = GDT.get().expect("GDT not initialized");
+        gdt.gdt.load();
+        unsafe {
+            let mut gdtr = DescriptorTablePointer {
+                limit: 0,
+                base: VirtAddr::zero(),
+            };
+            asm!("sgdt [{}]", in(reg) &mut gdtr, options(nostack));
+            // Loading fails if the descriptor is still marked busy
+            let tss = gdtr.base + gdt.tss_selector.index() as u64 * 8;
+            *tss.as_mut_ptr::<u64>() &= !TSS_BUSY;
+            segmentation::set_cs(gdt.kernel_code_selector);
+            segmentation::load_ss(gdt.kernel_data_selector);
+            segmentation::load_ds(gdt.kernel_data_selector);
+            segmentation::load_es(gdt.kernel_data_selector);
+            tables::load_tss(gdt.tss_selector);
+        }
+    }
+
+    /// Bounds of the stack of the interrupt stack table containing `addr`, if
+    /// any
+    pub fn interrupt_stack(addr: u64) -> Option<Range<u64>> {
+        TSS.get()?
+            .interrupt_stack_table
+            .iter()
+            .map(|top| top.as_u64() - IST_STACK_SIZE as u64..top.as_u64())
+            .find(|stack| stack.contains(&addr))
+    }
+
+    /// Kernel code and data segment selectors
+    pub fn kernel_selectors() -> (SegmentSelector, SegmentSelector) {
+        let gdt = GDT.get().expect("GDT not initialized");
+        (gdt.kernel_code_selector, gdt.kernel_data_selector)
+    }
 }
 
 mod pic {
+    use crate::ports::Ports;
     use pic8259::ChainedPics;
     use spin::Mutex;
 
     pub const PIC_1_OFFSET: u8 = 0x20;
     pub const PIC_2_OFFSET: u8 = PIC_1_OFFSET + 8;
 
+    /// Command and data ports of the primary and secondary PIC
+    const PORTS: [Ports; 2] = [Ports::new("pic", 0x20, 2), Ports::new("pic", 0xa0, 2)];
+
     pub static PICS: Mutex<ChainedPics> =
         Mutex::new(unsafe { ChainedPics::new(PIC_1_OFFSET, PIC_2_OFFSET) });
 
     pub fn init() {
+        for ports in &PORTS {
+            ports.claim().expect("PIC ports claimed");
+        }
         let mut pics = PICS.lock();
         unsafe {
             // UEFI masks all interrupt, so unmask at least the ones we want
@@ -119,22 +188,423 @@ mod pic {
             pics.initialize();
         }
     }
+
+    /// In-service and interrupt request registers of the primary and secondary
+    /// PIC, for diagnostics
+    pub fn registers() -> [(u8, u8); 2] {
+        const READ_IRR: u8 = 0x0a;
+        const READ_ISR: u8 = 0x0b;
+        let read = |ports: &Ports| unsafe {
+            ports.write(0, READ_ISR);
+            let isr = ports.read(0);
+            ports.write(0, READ_IRR);
+            (isr, ports.read(0))
+        };
+        [read(&PORTS[0]), read(&PORTS[1])]
+    }
+
+    /// Interrupt masks of the primary and secondary PIC
+    pub fn masks() -> [u8; 2] {
+        unsafe { [PORTS[0].read(1), PORTS[1].read(1)] }
+    }
+
+    /// Initialize again with masks `masks` after a sleep state, which resets
+    /// the PICs
+    pub fn resume(masks: [u8; 2]) {
+        let mut pics = PICS.lock();
+        unsafe {
+            pics.write_masks(masks[0], masks[1]);
+            pics.initialize();
+        }
+    }
+
+    /// Set the interrupt masks of the primary and secondary PIC
+    pub fn set_masks(masks: [u8; 2]) {
+        unsafe { PICS.lock().write_masks(masks[0], masks[1]) };
+    }
+
+    /// Unmask interrupt request line `irq`
+    pub fn unmask(irq: u8) {
+        let ports = &PORTS[irq as usize / 8];
+        unsafe {
+            let mask: u8 = ports.read(1);
+            ports.write(1, mask & !(1 << (irq % 8)));
+        }
+    }
+
+    /// Whether the interrupt with `vector` is not actually in service, which
+    /// happens for spurious interrupts on the lowest priority line of a PIC
+    pub fn is_spurious(vector: u8) -> bool {
+        let irq = vector - PIC_1_OFFSET;
+        let [(isr1, _), (isr2, _)] = registers();
+        let isr = if irq < 8 { isr1 } else { isr2 };
+        isr & (1 << (irq % 8)) == 0
+    }
+
+    /// Signal end of interrupt for a spurious interrupt with `vector`
+    ///
+    /// Only the primary PIC is notified, as the secondary PIC did not actually
+    /// raise an interrupt but the primary PIC did see its cascade line.
+    pub fn end_of_spurious_interrupt(vector: u8) {
+        const END_OF_INTERRUPT: u8 = 0x20;
+        if vector >= PIC_2_OFFSET {
+            unsafe { PORTS[0].write(0, END_OF_INTERRUPT) };
+        }
+    }
 }
 
+mod pit {
+    use crate::ports::Ports;
+
+    /// Frequency of the oscillator driving the PIT
+    const BASE_FREQUENCY: u32 = 1_193_182;
+    /// Frequency of timer interrupts
+    pub const FREQUENCY: u32 = 1000;
+
+    /// Channel data ports followed by the command port
+    const PORTS: Ports = Ports::new("pit", 0x40, 4);
+    const CHANNEL_0: u16 = 0;
+    const COMMAND: u16 = 3;
+
+    /// Program channel 0 to generate interrupts at [`FREQUENCY`]
+    pub fn init() {
+        PORTS.claim().expect("PIT ports claimed");
+        let divisor = BASE_FREQUENCY / FREQUENCY;
+        unsafe {
+            // Channel 0, lobyte/hibyte access, rate generator
+            PORTS.write(COMMAND, 0b00_11_010_0u8);
+            PORTS.write(CHANNEL_0, divisor as u8);
+            PORTS.write(CHANNEL_0, (divisor >> 8) as u8);
+        }
+    }
+}
+
+mod lapic {
+    use common::boot::offset;
+    use x86_64::{registers::model_specific::Msr, PhysAddr};
+
+    /// Model specific register containing the base address of the local APIC
+    const BASE_MSR: u32 = 0x1b;
+
+    fn register(offset: usize) -> *mut u32 {
+        let base = unsafe { Msr::new(BASE_MSR).read() } & 0x000f_ffff_ffff_f000;
+        offset::phys_to_virt(PhysAddr::new(base + offset as u64)).as_mut_ptr()
+    }
+
+    /// Local APIC id of the current CPU
+    pub fn id() -> u8 {
+        (unsafe { register(0x20).read_volatile() } >> 24) as u8
+    }
+
+    /// Signal end of interrupt for interrupts delivered through the local APIC,
+    /// such as message signaled interrupts
+    pub fn end_of_interrupt() {
+        unsafe { register(0xb0).write_volatile(0) };
+    }
+
+    /// Deliver spurious interrupts with `vector`
+    pub fn set_spurious_vector(vector: u8) {
+        unsafe {
+            let spurious = register(timer::SPURIOUS_VECTOR);
+            spurious.write_volatile((spurious.read_volatile() & !0xff) | vector as u32);
+        }
+    }
+
+    /// Timer registers
+    mod timer {
+        pub const SPURIOUS_VECTOR: usize = 0xf0;
+        pub const LVT: usize = 0x320;
+        pub const INITIAL_COUNT: usize = 0x380;
+        pub const CURRENT_COUNT: usize = 0x390;
+        pub const DIVIDE: usize = 0x3e0;
+
+        pub const APIC_ENABLE: u32 = 1 << 8;
+        pub const MASKED: u32 = 1 << 16;
+        pub const PERIODIC: u32 = 1 << 17;
+        pub const DIVIDE_BY_16: u32 = 0b0011;
+    }
+
+    /// Number of timer interrupts of the PIT to calibrate against
+    const CALIBRATION_TICKS: u64 = 10;
+
+    /// Measure the number of local APIC timer ticks per timer interrupt of the
+    /// PIT, which should be running
+    fn calibrate_timer() -> u32 {
+        let wait_ticks = |ticks| {
+            let start = super::ticks();
+            while super::ticks() < start + ticks {
+                x86_64::instructions::hlt();
+            }
+        };
+        unsafe {
+            register(timer::DIVIDE).write_volatile(timer::DIVIDE_BY_16);
+            register(timer::LVT).write_volatile(timer::MASKED);
+            // Start right after a timer interrupt
+            wait_ticks(1);
+            register(timer::INITIAL_COUNT).write_volatile(u32::MAX);
+            wait_ticks(CALIBRATION_TICKS);
+            let elapsed = u32::MAX - register(timer::CURRENT_COUNT).read_volatile();
+            register(timer::INITIAL_COUNT).write_volatile(0);
+            elapsed / CALIBRATION_TICKS as u32
+        }
+    }
+
+    /// Interrupt periodically with vector `vector` at `frequency`
+    pub fn start_timer(vector: u8, frequency: u32) {
+        unsafe {
+            let spurious = register(timer::SPURIOUS_VECTOR);
+            spurious.write_volatile(spurious.read_volatile() | timer::APIC_ENABLE);
+        }
+        let count = (calibrate_timer() as u64 * super::pit::FREQUENCY as u64 / frequency as u64)
+            .max(1) as u32;
+        unsafe {
+            register(timer::LVT).write_volatile(timer::PERIODIC | vector as u32);
+            register(timer::INITIAL_COUNT).write_volatile(count);
+        }
+    }
+}
+
+pub use gdt::interrupt_stack;
+pub use lapic::id as cpu_id;
+pub use pic::registers as pic_registers;
+pub use pit::FREQUENCY as TIMER_FREQUENCY;
+
 const TIMER_INTERRUPT_ID: u8 = pic::PIC_1_OFFSET;
+const KEYBOARD_INTERRUPT_ID: u8 = pic::PIC_1_OFFSET + 1;
+const MOUSE_INTERRUPT_ID: u8 = pic::PIC_2_OFFSET + 4;
+/// Interrupt request line of the ACPI system control interrupt; other lines
+/// are not supported
+pub const SCI_IRQ: u8 = 9;
+const SCI_INTERRUPT_ID: u8 = pic::PIC_1_OFFSET + SCI_IRQ;
+/// Vector of the message signaled interrupt of the USB host controller
+pub const USB_INTERRUPT_ID: u8 = 0x30;
+/// Vector of the local APIC timer, used for profiling and the watchdog
+const APIC_TIMER_INTERRUPT_ID: u8 = 0x31;
+/// Vector of spurious interrupts of the local APIC
+const APIC_SPURIOUS_INTERRUPT_ID: u8 = 0xff;
+
+/// Whether [`without_device_interrupts`] is running, which defers unmasking
+static DEFERRING: AtomicBool = AtomicBool::new(false);
+/// Interrupt request lines unmasked while [`DEFERRING`], one bit per line
+static DEFERRED_UNMASKS: AtomicU16 = AtomicU16::new(0);
+
+/// Unmask interrupt request line `irq` of the PIC
+///
+/// Inside [`without_device_interrupts`] the line is only unmasked once it
+/// returns.
+pub fn unmask_irq(irq: u8) {
+    if DEFERRING.load(Ordering::Relaxed) {
+        DEFERRED_UNMASKS.fetch_or(1 << irq, Ordering::Relaxed);
+    } else {
+        pic::unmask(irq);
+    }
+}
+
+/// Run `f` with all interrupt request lines except the timer masked, which
+/// delays device interrupts until it returns
+///
+/// Lines unmasked with [`unmask_irq`] by `f` are unmasked afterwards, in
+/// addition to the ones that were unmasked before.
+pub fn without_device_interrupts<R>(f: impl FnOnce() -> R) -> R {
+    let masks = pic::masks();
+    pic::set_masks([!1, 0xff]);
+    DEFERRING.store(true, Ordering::Relaxed);
+    let result = f();
+    DEFERRING.store(false, Ordering::Relaxed);
+    let unmasks = DEFERRED_UNMASKS.swap(0, Ordering::Relaxed);
+    pic::set_masks([masks[0] & !unmasks as u8, masks[1] & !(unmasks >> 8) as u8]);
+    result
+}
+
+/// Address and data of a message signaled interrupt with vector `vector`,
+/// delivered to the current CPU
+pub fn msi_message(vector: u8) -> (u64, u32) {
+    (0xfee0_0000 | (lapic::id() as u64) << 12, vector as u32)
+}
+
+/// Frequency of local APIC timer interrupts, or zero if not running
+static APIC_TIMER_FREQUENCY: AtomicU32 = AtomicU32::new(0);
+
+/// Interrupt at least at `frequency` with the local APIC timer, which takes
+/// samples for [`profile`] and runs the [`watchdog`]
+///
+/// The timer is calibrated against the PIT, which should be running. With
+/// [`config::deterministic`] the timer is not started, so the PIT is the only
+/// source of timer interrupts.
+pub fn start_apic_timer(frequency: u32) {
+    if config::deterministic() {
+        log::info!("Local APIC timer disabled for deterministic boot");
+        return;
+    }
+    if APIC_TIMER_FREQUENCY.load(Ordering::Relaxed) >= frequency {
+        return;
+    }
+    lapic::start_timer(APIC_TIMER_INTERRUPT_ID, frequency);
+    APIC_TIMER_FREQUENCY.store(frequency, Ordering::Relaxed);
+}
+
+/// Frequency of local APIC timer interrupts, or zero if not running
+pub fn apic_timer_frequency() -> u32 {
+    APIC_TIMER_FREQUENCY.load(Ordering::Relaxed)
+}
+
+/// Number of timer interrupts since initialization
+static TICKS: AtomicU64 = AtomicU64::new(0);
+
+/// Number of timer interrupts since initialization, occurring at
+/// [`TIMER_FREQUENCY`]
+pub fn ticks() -> u64 {
+    TICKS.load(Ordering::Relaxed)
+}
 
 static IDT: Once<InterruptDescriptorTable> = Once::new();
 
+/// Number of unexpected or spurious interrupts per vector
+static UNEXPECTED: [AtomicU64; 256] = {
+    const ZERO: AtomicU64 = AtomicU64::new(0);
+    [ZERO; 256]
+};
+
+/// Count an unexpected or spurious interrupt with `vector` and log it, with
+/// exponentially decreasing frequency
+fn report_unexpected(vector: u8, stack_frame: &InterruptStackFrame, kind: &str) {
+    let count = UNEXPECTED[vector as usize].fetch_add(1, Ordering::Relaxed) + 1;
+    if count.is_power_of_two() {
+        log::warn!(
+            "{} interrupt {:#x} at {:?} ({} so far)",
+            kind,
+            vector,
+            stack_frame.instruction_pointer,
+            count
+        );
+    }
+}
+
+/// Install [`unexpected_handler`] for all vectors `$hi * 16 + $lo`
+macro_rules! set_unexpected_handlers {
+    ($idt:ident, [$($hi:literal)*], $lo:tt) => {
+        $(set_unexpected_handlers!(@row $idt, $hi, $lo);)*
+    };
+    (@row $idt:ident, $hi:literal, [$($lo:literal)*]) => {
+        $(
+            $idt[$hi * 16 + $lo]
+                .set_handler_fn(unexpected_handler::<{ $hi * 16 + $lo }>)
+                .set_stack_index(gdt::GENERAL_IST_INDEX);
+        )*
+    };
+}
+
+/// Return to [`threads::fault_handler`] instead of the user process that caused
+/// `exception`, see [`fault`]
+///
+/// Returns `false` if the exception did not occur in user mode.
+fn user_fault(
+    stack_frame: &mut InterruptStackFrame,
+    exception: Exception,
+    error_code: Option<u64>,
+    address: Option<VirtAddr>,
+) -> bool {
+    if stack_frame.code_segment & 3 != 3 {
+        return false;
+    }
+    fault::record(Fault {
+        exception,
+        error_code,
+        address,
+        rip: stack_frame.instruction_pointer.as_u64(),
+        rsp: stack_frame.stack_pointer.as_u64(),
+        rflags: stack_frame.cpu_flags,
+    });
+    enter_fault_handler(stack_frame);
+    true
+}
+
+/// Return to [`threads::fault_handler`] in kernel mode instead of to the user
+/// process interrupted with `stack_frame`
+fn enter_fault_handler(stack_frame: &mut InterruptStackFrame) {
+    let (code_selector, data_selector) = gdt::kernel_selectors();
+    unsafe {
+        stack_frame.as_mut().update(|frame| {
+            frame.instruction_pointer = VirtAddr::from_ptr(threads::fault_handler as *const ());
+            frame.code_segment = code_selector.0 as u64;
+            // Interrupts enabled, like in the system call loop
+            frame.cpu_flags = 0x0202;
+            frame.stack_pointer = VirtAddr::new(threads::kernel_stack());
+            frame.stack_segment = data_selector.0 as u64;
+        });
+    }
+}
+
+extern "x86-interrupt" fn divide_error_handler(mut stack_frame: InterruptStackFrame) {
+    let _page_table = KernelPageTable::enter();
+    if !user_fault(&mut stack_frame, Exception::DivideError, None, None) {
+        panic!("divide error in {:#?}", stack_frame);
+    }
+}
+
+extern "x86-interrupt" fn invalid_opcode_handler(mut stack_frame: InterruptStackFrame) {
+    let _page_table = KernelPageTable::enter();
+    if !user_fault(&mut stack_frame, Exception::InvalidOpcode, None, None) {
+        panic!("invalid opcode in {:#?}", stack_frame);
+    }
+}
+
+extern "x86-interrupt" fn general_protection_fault_handler(
+    mut stack_frame: InterruptStackFrame,
+    error_code: u64,
+) {
+    let _page_table = KernelPageTable::enter();
+    if let Some(rip) = telemetry::fixup(stack_frame.instruction_pointer) {
+        unsafe {
+            stack_frame
+                .as_mut()
+                .update(|frame| frame.instruction_pointer = rip)
+        };
+        return;
+    }
+    let exception = Exception::GeneralProtection;
+    if !user_fault(&mut stack_frame, exception, Some(error_code), None) {
+        panic!(
+            "general protection fault ({:#x}) in {:#?}",
+            error_code, stack_frame
+        );
+    }
+}
+
 extern "x86-interrupt" fn breakpoint_handler(stack_frame: InterruptStackFrame) {
+    let _page_table = KernelPageTable::enter();
     log::warn!("Breakpoint in {:#?}", stack_frame);
 }
 
 extern "x86-interrupt" fn page_fault_handler(
-    stack_frame: InterruptStackFrame,
+    mut stack_frame: InterruptStackFrame,
     error_code: PageFaultErrorCode,
 ) {
+    let _page_table = KernelPageTable::enter();
     let address = Cr2::read();
 
+    // Pages of executables are mapped when first accessed and copied when
+    // first written
+    let write = error_code.contains(PageFaultErrorCode::CAUSED_BY_WRITE);
+    if unsafe { threads::populate(address, write) } {
+        return;
+    }
+
+    let exception = Exception::PageFault;
+    let code = Some(error_code.bits());
+    if user_fault(&mut stack_frame, exception, code, Some(address)) {
+        return;
+    }
+
+    if stack::is_guard(address) {
+        log::error!(
+            "Kernel stack overflow accessing {:?} in {:#?}",
+            address,
+            stack_frame
+        );
+        panic!("kernel stack overflow");
+    }
+
     log::error!(
         "Page fault {:?} at {:?} in {:#?}",
         error_code,
@@ -150,57 +620,323 @@ extern "x86-interrupt" fn double_fault_handler(
     stack_frame: InterruptStackFrame,
     _error_code: u64,
 ) -> ! {
+    let _page_table = KernelPageTable::enter();
     log::error!("Double fault in {:#?}", stack_frame);
 
     // We can't recover, so we remain looping
+    if stack::is_guard(stack_frame.stack_pointer) {
+        panic!("double fault caused by kernel stack overflow");
+    }
     panic!("double fault");
 }
 
-extern "x86-interrupt" fn timer_interrupt_handler(_stack_frame: InterruptStackFrame) {
-    static COUNT: AtomicUsize = AtomicUsize::new(0);
-    let count = COUNT.fetch_add(1, Ordering::Relaxed);
-    if count % 1000 == 0 {
+/// Number of recent trace events logged on a non-maskable interrupt
+const NMI_TRACE_EVENTS: usize = 16;
+
+/// Print a line from the NMI handler, which must not take the logger's or the
+/// console's locks: the interrupted code may hold them
+macro_rules! nmi_println {
+    ($($arg:tt)*) => {
+        common::serial::print_unlocked(format_args!("{}\n", format_args!($($arg)*)))
+    };
+}
+
+/// Print the interrupted context, which allows breaking into a hung system by
+/// injecting a non-maskable interrupt (see `cargo xtask nmi`)
+extern "x86-interrupt" fn nmi_handler(stack_frame: InterruptStackFrame) {
+    let _page_table = KernelPageTable::enter();
+    let rbp: u64;
+    unsafe { asm!("mov {}, rbp", out(reg) rbp) };
+    nmi_println!("Non-maskable interrupt in {:#?}", stack_frame);
+    nmi_println!(
+        "Process {:?}, CR2 {:?}, CR3 {:?}",
+        threads::current_pid(),
+        Cr2::read(),
+        Cr3::read().0
+    );
+    // The saved frame pointer is that of the interrupted code, which can only
+    // be followed if it was running in the kernel
+    if stack_frame.code_segment & 0b11 == 0 {
+        let rsp = stack_frame.stack_pointer.as_u64();
+        let (addrs, len) = crash_dump::backtrace(rsp, unsafe { *(rbp as *const u64) });
+        nmi_println!("Backtrace:");
+        for addr in &addrs[..len] {
+            nmi_println!("  {:#x}", addr);
+        }
+    }
+    nmi_println!("Recent trace events:");
+    trace::recent(NMI_TRACE_EVENTS, |record| {
+        nmi_println!(
+            "  {} {:?} {:#x} on CPU {}",
+            record.timestamp,
+            record.kind,
+            record.arg,
+            record.cpu
+        );
+    });
+}
+
+extern "x86-interrupt" fn machine_check_handler(stack_frame: InterruptStackFrame) -> ! {
+    let _page_table = KernelPageTable::enter();
+    log::error!("Machine check in {:#?}", stack_frame);
+    panic!("machine check");
+}
+
+extern "x86-interrupt" fn timer_interrupt_handler(mut stack_frame: InterruptStackFrame) {
+    let _page_table = KernelPageTable::enter();
+    let start = latency::start();
+    trace::record(TraceKind::IrqEnter, TIMER_INTERRUPT_ID as u64);
+    let count = TICKS.fetch_add(1, Ordering::Relaxed);
+    time::tick();
+    if count % TIMER_FREQUENCY as u64 == 0 {
+        telemetry::tick();
+    }
+    if count % (60 * TIMER_FREQUENCY as u64) == 0 {
         log::info!("Handling timer interrupt #{}", count);
     }
+    // A process that does not make system calls is killed here once the
+    // shutdown grace period is over, without a recorded fault
+    if stack_frame.code_segment & 3 == 3 && shutdown::expired() {
+        enter_fault_handler(&mut stack_frame);
+    }
     unsafe { pic::PICS.lock().notify_end_of_interrupt(TIMER_INTERRUPT_ID) };
+    trace::record(TraceKind::IrqExit, TIMER_INTERRUPT_ID as u64);
+    latency::finish(LatencySource::Interrupt, start);
+}
+
+extern "x86-interrupt" fn keyboard_interrupt_handler(_stack_frame: InterruptStackFrame) {
+    let _page_table = KernelPageTable::enter();
+    let start = latency::start();
+    trace::record(TraceKind::IrqEnter, KEYBOARD_INTERRUPT_ID as u64);
+    drivers::ps2::keyboard::interrupt();
+    unsafe {
+        pic::PICS
+            .lock()
+            .notify_end_of_interrupt(KEYBOARD_INTERRUPT_ID)
+    };
+    trace::record(TraceKind::IrqExit, KEYBOARD_INTERRUPT_ID as u64);
+    latency::finish(LatencySource::Interrupt, start);
+}
+
+extern "x86-interrupt" fn mouse_interrupt_handler(_stack_frame: InterruptStackFrame) {
+    let _page_table = KernelPageTable::enter();
+    let start = latency::start();
+    trace::record(TraceKind::IrqEnter, MOUSE_INTERRUPT_ID as u64);
+    drivers::ps2::mouse::interrupt();
+    unsafe { pic::PICS.lock().notify_end_of_interrupt(MOUSE_INTERRUPT_ID) };
+    trace::record(TraceKind::IrqExit, MOUSE_INTERRUPT_ID as u64);
+    latency::finish(LatencySource::Interrupt, start);
+}
+
+extern "x86-interrupt" fn sci_interrupt_handler(_stack_frame: InterruptStackFrame) {
+    let _page_table = KernelPageTable::enter();
+    let start = latency::start();
+    trace::record(TraceKind::IrqEnter, SCI_INTERRUPT_ID as u64);
+    acpi::interrupt();
+    unsafe { pic::PICS.lock().notify_end_of_interrupt(SCI_INTERRUPT_ID) };
+    trace::record(TraceKind::IrqExit, SCI_INTERRUPT_ID as u64);
+    latency::finish(LatencySource::Interrupt, start);
+}
+
+extern "x86-interrupt" fn usb_interrupt_handler(_stack_frame: InterruptStackFrame) {
+    let _page_table = KernelPageTable::enter();
+    let start = latency::start();
+    trace::record(TraceKind::IrqEnter, USB_INTERRUPT_ID as u64);
+    drivers::usb::xhci::interrupt();
+    lapic::end_of_interrupt();
+    trace::record(TraceKind::IrqExit, USB_INTERRUPT_ID as u64);
+    latency::finish(LatencySource::Interrupt, start);
+}
+
+/// Handler of vectors that are not otherwise assigned
+extern "x86-interrupt" fn unexpected_handler<const VECTOR: u8>(stack_frame: InterruptStackFrame) {
+    let _page_table = KernelPageTable::enter();
+    report_unexpected(VECTOR, &stack_frame, "Unexpected");
+    // Acknowledge the interrupt, as it might have come from either controller
+    if (pic::PIC_1_OFFSET..pic::PIC_2_OFFSET + 8).contains(&VECTOR) {
+        unsafe { pic::PICS.lock().notify_end_of_interrupt(VECTOR) };
+    } else {
+        lapic::end_of_interrupt();
+    }
+}
+
+/// Handler of the lowest priority line of a PIC, which is unused but raised
+/// for spurious interrupts
+extern "x86-interrupt" fn pic_spurious_handler<const VECTOR: u8>(stack_frame: InterruptStackFrame) {
+    let _page_table = KernelPageTable::enter();
+    if pic::is_spurious(VECTOR) {
+        report_unexpected(VECTOR, &stack_frame, "Spurious");
+        pic::end_of_spurious_interrupt(VECTOR);
+    } else {
+        report_unexpected(VECTOR, &stack_frame, "Unexpected");
+        unsafe { pic::PICS.lock().notify_end_of_interrupt(VECTOR) };
+    }
+}
+
+/// Handler of spurious interrupts of the local APIC, which must not be
+/// acknowledged
+extern "x86-interrupt" fn apic_spurious_handler(stack_frame: InterruptStackFrame) {
+    let _page_table = KernelPageTable::enter();
+    report_unexpected(APIC_SPURIOUS_INTERRUPT_ID, &stack_frame, "Spurious");
+}
+
+extern "x86-interrupt" fn apic_timer_interrupt_handler(stack_frame: InterruptStackFrame) {
+    let _page_table = KernelPageTable::enter();
+    profile::sample(&stack_frame);
+    watchdog::check(&stack_frame);
+    #[cfg(test)]
+    crate::test::background();
+    lapic::end_of_interrupt();
+}
+
+crate::initcall!(Arch, |_| register_devices());
+
+/// Record the legacy devices set up by [`init`]
+fn register_devices() {
+    let legacy = [
+        (
+            "8259 PIC",
+            vec![Resource::Ports(0x20, 2), Resource::Ports(0xa0, 2)],
+            "pic",
+        ),
+        (
+            "8254 PIT",
+            vec![Resource::Ports(0x40, 4), Resource::Irq(0)],
+            "pit",
+        ),
+    ];
+    for (name, resources, driver) in legacy {
+        let id = devices::add(name.into(), resources);
+        devices::bind(id, driver, State::Bound);
+    }
 }
 
 /// Initialize everything related to interrupts; should be called only once
 ///
 /// This includes, specifically:
 /// - Everything related to the global descriptor table (see [`gdt::init`])
-/// - Initialize and load the interrupt descriptor table
+/// - Initialize and load the interrupt descriptor table, reporting unexpected
+///   and spurious interrupts
+/// - Program the timer to interrupt at [`TIMER_FREQUENCY`]
 pub fn init() {
     gdt::init();
     let idt = IDT.call_once(|| {
         let mut idt = InterruptDescriptorTable::new();
         unsafe {
+            // Specific handlers below override these
+            set_unexpected_handlers!(
+                idt,
+                [2 3 4 5 6 7 8 9 10 11 12 13 14 15],
+                [0 1 2 3 4 5 6 7 8 9 10 11 12 13 14 15]
+            );
+            idt[pic::PIC_1_OFFSET as usize + 7]
+                .set_handler_fn(pic_spurious_handler::<{ pic::PIC_1_OFFSET + 7 }>)
+                .set_stack_index(gdt::GENERAL_IST_INDEX);
+            idt[pic::PIC_2_OFFSET as usize + 7]
+                .set_handler_fn(pic_spurious_handler::<{ pic::PIC_2_OFFSET + 7 }>)
+                .set_stack_index(gdt::GENERAL_IST_INDEX);
+            idt[APIC_SPURIOUS_INTERRUPT_ID as usize]
+                .set_handler_fn(apic_spurious_handler)
+                .set_stack_index(gdt::GENERAL_IST_INDEX);
+            idt.divide_error
+                .set_handler_fn(divide_error_handler)
+                .set_stack_index(gdt::GENERAL_IST_INDEX);
+            idt.invalid_opcode
+                .set_handler_fn(invalid_opcode_handler)
+                .set_stack_index(gdt::GENERAL_IST_INDEX);
+            idt.general_protection_fault
+                .set_handler_fn(general_protection_fault_handler)
+                .set_stack_index(gdt::GENERAL_IST_INDEX);
             idt.breakpoint
                 .set_handler_fn(breakpoint_handler)
                 .set_stack_index(gdt::GENERAL_IST_INDEX);
+            idt.non_maskable_interrupt
+                .set_handler_fn(nmi_handler)
+                .set_stack_index(gdt::NMI_IST_INDEX);
             idt.page_fault
                 .set_handler_fn(page_fault_handler)
-                .set_stack_index(gdt::GENERAL_IST_INDEX);
+                .set_stack_index(gdt::PAGE_FAULT_IST_INDEX);
             idt.double_fault
                 .set_handler_fn(double_fault_handler)
                 .set_stack_index(gdt::DOUBLE_FAULT_IST_INDEX);
+            idt.machine_check
+                .set_handler_fn(machine_check_handler)
+                .set_stack_index(gdt::MACHINE_CHECK_IST_INDEX);
             idt[TIMER_INTERRUPT_ID as usize]
                 .set_handler_fn(timer_interrupt_handler)
                 .set_stack_index(gdt::GENERAL_IST_INDEX);
+            idt[KEYBOARD_INTERRUPT_ID as usize]
+                .set_handler_fn(keyboard_interrupt_handler)
+                .set_stack_index(gdt::GENERAL_IST_INDEX);
+            idt[MOUSE_INTERRUPT_ID as usize]
+                .set_handler_fn(mouse_interrupt_handler)
+                .set_stack_index(gdt::GENERAL_IST_INDEX);
+            idt[SCI_INTERRUPT_ID as usize]
+                .set_handler_fn(sci_interrupt_handler)
+                .set_stack_index(gdt::GENERAL_IST_INDEX);
+            idt[USB_INTERRUPT_ID as usize]
+                .set_handler_fn(usb_interrupt_handler)
+                .set_stack_index(gdt::GENERAL_IST_INDEX);
+            idt[APIC_TIMER_INTERRUPT_ID as usize]
+                .set_handler_fn(apic_timer_interrupt_handler)
+                .set_stack_index(gdt::GENERAL_IST_INDEX);
         }
         idt
     });
     idt.load();
+    lapic::set_spurious_vector(APIC_SPURIOUS_INTERRUPT_ID);
     pic::init();
+    pit::init();
+    interrupts::enable();
+}
+
+/// Interrupt controller state saved by [`suspend`]
+pub struct Suspended {
+    pic_masks: [u8; 2],
+}
+
+/// Save the state lost in a sleep state; interrupts should be disabled
+pub fn suspend() -> Suspended {
+    Suspended {
+        pic_masks: pic::masks(),
+    }
+}
+
+/// Restore the state lost in a sleep state and enable interrupts again
+///
+/// Control registers and model specific registers should already be restored.
+pub fn resume(suspended: Suspended) {
+    gdt::resume();
+    IDT.get().expect("IDT not initialized").load();
+    lapic::set_spurious_vector(APIC_SPURIOUS_INTERRUPT_ID);
+    pic::resume(suspended.pic_masks);
+    pit::init();
     interrupts::enable();
+    // Calibrated against the PIT again, which needs interrupts
+    let frequency = APIC_TIMER_FREQUENCY.load(Ordering::Relaxed);
+    if frequency != 0 {
+        lapic::start_timer(APIC_TIMER_INTERRUPT_ID, frequency);
+    }
 }
 
 #[cfg(test)]
 mod tests {
+    use super::{pic, unmask_irq, without_device_interrupts};
     use x86_64::instructions::interrupts;
 
     #[test_case]
     fn int3() {
         interrupts::int3();
     }
+
+    #[test_case]
+    fn deferred_unmask() {
+        let masks = pic::masks();
+        without_device_interrupts(|| {
+            unmask_irq(1);
+            assert_eq!(pic::masks(), [!1, 0xff]);
+        });
+        assert_eq!(pic::masks(), [masks[0] & !0b10, masks[1]]);
+        pic::set_masks(masks);
+    }
 }
@@ -0,0 +1,315 @@
+//! Process groups and job control
+//!
+//! Every process belongs to a process group, identified by the id of the
+//! process that created it. Processes start in a group of their own and can
+//! join another group with [`SyscallCode::SetProcessGroup`], so a shell can
+//! treat the processes of a pipeline as one job. Each virtual terminal of the
+//! [`console`] has a foreground group, which is sent [`Signal::Terminate`]
+//! when Ctrl+C is typed on the terminal. Like other signals, it is delivered
+//! when a process returns from its next system call.
+//!
+//! Processes belong to the terminal set by the `user-vt` option. They can
+//! change its foreground group with [`SyscallCode::SetForeground`] if their
+//! group is in the foreground or none is, and a process started while no group
+//! is in the foreground is put there.
+//!
+//! Processes can be stopped with [`SyscallCode::Stop`] and continued with
+//! [`SyscallCode::Continue`], e.g. to suspend background work without killing
+//! it, but only by processes of their own group. A stopped process is parked
+//! when it returns from a system call, so it keeps what it holds. Ctrl+C also
+//! continues the processes it interrupts, so they can handle
+//! [`Signal::Terminate`]; without a terminal a process cannot stop itself, as
+//! it might never be continued.
+//!
+//! [`SyscallCode::SetProcessGroup`]: sys::SyscallCode::SetProcessGroup
+//! [`SyscallCode::SetForeground`]: sys::SyscallCode::SetForeground
+//! [`SyscallCode::Stop`]: sys::SyscallCode::Stop
+//! [`SyscallCode::Continue`]: sys::SyscallCode::Continue
+//! [`Signal::Terminate`]: sys::Signal::Terminate
+
+use crate::{config, console};
+use spin::Mutex;
+use x86_64::instructions::interrupts;
+
+/// Maximum number of processes in process groups
+const MAX_MEMBERS: usize = 16;
+
+static JOBS: Mutex<Jobs> = Mutex::new(Jobs::new());
+
+#[derive(Copy, Clone, Debug)]
+struct Member {
+    pid: u64,
+    pgid: u64,
+    /// Whether Ctrl+C was typed since the process last checked
+    interrupted: bool,
+    /// Whether the process should be parked until it is continued
+    stopped: bool,
+}
+
+struct Jobs {
+    members: [Option<Member>; MAX_MEMBERS],
+    /// Foreground group of every virtual terminal
+    foreground: [Option<u64>; console::COUNT],
+}
+
+impl Jobs {
+    const fn new() -> Self {
+        Self {
+            members: [None; MAX_MEMBERS],
+            foreground: [None; console::COUNT],
+        }
+    }
+
+    fn member(&mut self, pid: u64) -> Option<&mut Member> {
+        self.members
+            .iter_mut()
+            .flatten()
+            .find(|member| member.pid == pid)
+    }
+
+    fn group_exists(&self, pgid: u64) -> bool {
+        self.members
+            .iter()
+            .flatten()
+            .any(|member| member.pgid == pgid)
+    }
+
+    /// Add process `pid` in a group of its own, in the foreground of `vt` if
+    /// that has none
+    fn register(&mut self, pid: u64, vt: Option<usize>) -> Result<(), &'static str> {
+        let slot = self
+            .members
+            .iter_mut()
+            .find(|slot| slot.is_none())
+            .ok_or("Too many processes in process groups")?;
+        *slot = Some(Member {
+            pid,
+            pgid: pid,
+            interrupted: false,
+            stopped: false,
+        });
+        if let Some(foreground) = vt.map(|vt| &mut self.foreground[vt]) {
+            foreground.get_or_insert(pid);
+        }
+        Ok(())
+    }
+
+    /// Remove process `pid`, and its group from the foreground if it was the
+    /// last member
+    fn remove(&mut self, pid: u64) {
+        let slot = self
+            .members
+            .iter_mut()
+            .find(|slot| slot.as_ref().map_or(false, |member| member.pid == pid));
+        let pgid = match slot.and_then(Option::take) {
+            Some(member) => member.pgid,
+            None => return,
+        };
+        if !self.group_exists(pgid) {
+            for foreground in &mut self.foreground {
+                if *foreground == Some(pgid) {
+                    *foreground = None;
+                }
+            }
+        }
+    }
+
+    /// Move process `pid` to group `pgid`, or a new group of its own if zero
+    fn join(&mut self, pid: u64, pgid: u64) -> Result<(), &'static str> {
+        let pgid = if pgid == 0 { pid } else { pgid };
+        if pgid != pid && !self.group_exists(pgid) {
+            return Err("No such process group");
+        }
+        self.member(pid).ok_or("Process not in a group")?.pgid = pgid;
+        Ok(())
+    }
+
+    /// Put group `pgid`, or that of process `pid` if zero, in the foreground
+    /// of `vt` on behalf of `pid`
+    fn set_foreground(&mut self, pid: u64, pgid: u64, vt: usize) -> Result<(), &'static str> {
+        let own = self.member(pid).ok_or("Process not in a group")?.pgid;
+        let pgid = if pgid == 0 { own } else { pgid };
+        if self.foreground[vt].map_or(false, |foreground| foreground != own) {
+            return Err("Process group is not in the foreground");
+        }
+        if !self.group_exists(pgid) {
+            return Err("No such process group");
+        }
+        self.foreground[vt] = Some(pgid);
+        Ok(())
+    }
+
+    /// Interrupt the foreground group of `vt`, returning its id if there is one
+    fn interrupt(&mut self, vt: usize) -> Option<u64> {
+        let pgid = self.foreground[vt]?;
+        for member in self.members.iter_mut().flatten() {
+            if member.pgid == pgid {
+                member.interrupted = true;
+                member.stopped = false;
+            }
+        }
+        Some(pgid)
+    }
+
+    fn take_interrupt(&mut self, pid: u64) -> bool {
+        self.member(pid)
+            .map_or(false, |member| core::mem::take(&mut member.interrupted))
+    }
+
+    fn interrupted(&mut self, pid: u64) -> bool {
+        self.member(pid).map_or(false, |member| member.interrupted)
+    }
+
+    /// Stop or continue process `pid` on behalf of process `caller`, which
+    /// should be in the same group
+    fn set_stopped(&mut self, caller: u64, pid: u64, stopped: bool) -> Result<(), &'static str> {
+        let own = self.member(caller).ok_or("Process not in a group")?.pgid;
+        let member = self.member(pid).ok_or("No such process")?;
+        if member.pgid != own {
+            return Err("Process is in another group");
+        }
+        member.stopped = stopped;
+        Ok(())
+    }
+
+    fn stopped(&mut self, pid: u64) -> bool {
+        self.member(pid).map_or(false, |member| member.stopped)
+    }
+}
+
+/// Run `f` on the process groups with interrupts disabled, as the keyboard
+/// interrupts them from interrupt handlers
+fn with_jobs<F: FnOnce(&mut Jobs) -> T, T>(f: F) -> T {
+    interrupts::without_interrupts(|| f(&mut JOBS.lock()))
+}
+
+/// Terminal of user processes, if they have one
+fn user_vt() -> Option<usize> {
+    console::index(config::user_vt())
+}
+
+/// Put newly started process `pid` in a group of its own
+pub fn register(pid: u64) -> Result<(), &'static str> {
+    with_jobs(|jobs| jobs.register(pid, user_vt()))
+}
+
+/// Remove process `pid` from its group, e.g. when it exits
+pub fn remove(pid: u64) {
+    with_jobs(|jobs| jobs.remove(pid))
+}
+
+/// Move process `pid` to group `pgid`, or a new group of its own if zero
+pub fn join(pid: u64, pgid: u64) -> Result<(), &'static str> {
+    with_jobs(|jobs| jobs.join(pid, pgid))
+}
+
+/// Put group `pgid`, or that of process `pid` if zero, in the foreground of the
+/// terminal of user processes on behalf of `pid`
+pub fn set_foreground(pid: u64, pgid: u64) -> Result<(), &'static str> {
+    let vt = user_vt().ok_or("Processes have no terminal")?;
+    with_jobs(|jobs| jobs.set_foreground(pid, pgid, vt))
+}
+
+/// Send a keyboard interrupt to the foreground group of terminal `vt`, indexed
+/// from zero
+///
+/// Returns whether the terminal has a foreground group. Nothing is logged, as
+/// the console calls this while it is locked.
+pub fn interrupt(vt: usize) -> bool {
+    with_jobs(|jobs| jobs.interrupt(vt)).is_some()
+}
+
+/// Whether process `pid` was interrupted since the last call
+pub fn take_interrupt(pid: u64) -> bool {
+    with_jobs(|jobs| jobs.take_interrupt(pid))
+}
+
+/// Whether process `pid` was interrupted, without taking the interrupt, so
+/// blocking system calls can return before it is delivered
+pub fn interrupted(pid: u64) -> bool {
+    with_jobs(|jobs| jobs.interrupted(pid))
+}
+
+/// Stop process `pid` of the group of process `caller` until it is continued
+/// with [`resume`]
+pub fn stop(caller: u64, pid: u64) -> Result<(), &'static str> {
+    if pid == caller && user_vt().is_none() {
+        return Err("Process could not be continued");
+    }
+    with_jobs(|jobs| jobs.set_stopped(caller, pid, true))
+}
+
+/// Continue process `pid` of the group of process `caller` stopped with
+/// [`stop`]
+pub fn resume(caller: u64, pid: u64) -> Result<(), &'static str> {
+    with_jobs(|jobs| jobs.set_stopped(caller, pid, false))
+}
+
+/// Whether process `pid` is stopped
+pub fn stopped(pid: u64) -> bool {
+    with_jobs(|jobs| jobs.stopped(pid))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test_case]
+    fn foreground() {
+        let mut jobs = Jobs::new();
+        jobs.register(1, Some(0)).unwrap();
+        jobs.register(2, Some(0)).unwrap();
+        assert_eq!(jobs.foreground[0], Some(1));
+        // Only the foreground group can change the foreground
+        assert!(jobs.set_foreground(2, 0, 0).is_err());
+        assert!(jobs.set_foreground(1, 3, 0).is_err());
+        jobs.set_foreground(1, 2, 0).unwrap();
+        assert_eq!(jobs.foreground[0], Some(2));
+        // The foreground is cleared once its group is empty
+        jobs.remove(2);
+        assert_eq!(jobs.foreground[0], None);
+        jobs.set_foreground(1, 0, 1).unwrap();
+        assert_eq!(jobs.foreground[1], Some(1));
+    }
+
+    #[test_case]
+    fn interrupt() {
+        let mut jobs = Jobs::new();
+        for pid in 1..=3 {
+            jobs.register(pid, Some(0)).unwrap();
+        }
+        assert!(jobs.join(2, 4).is_err());
+        jobs.join(2, 3).unwrap();
+        jobs.set_foreground(1, 3, 0).unwrap();
+        assert_eq!(jobs.interrupt(0), Some(3));
+        assert!(!jobs.take_interrupt(1));
+        assert!(jobs.interrupted(2));
+        assert!(jobs.take_interrupt(2));
+        assert!(!jobs.interrupted(2));
+        assert!(!jobs.take_interrupt(2));
+        assert!(jobs.take_interrupt(3));
+        assert_eq!(jobs.interrupt(1), None);
+    }
+
+    #[test_case]
+    fn stop() {
+        let mut jobs = Jobs::new();
+        jobs.register(1, Some(0)).unwrap();
+        jobs.register(2, Some(0)).unwrap();
+        jobs.register(3, Some(0)).unwrap();
+        jobs.join(2, 1).unwrap();
+        assert!(jobs.set_stopped(1, 4, true).is_err());
+        // Only processes of the same group can be stopped and continued
+        assert!(jobs.set_stopped(1, 3, true).is_err());
+        assert!(jobs.set_stopped(3, 1, true).is_err());
+        jobs.set_stopped(1, 1, true).unwrap();
+        jobs.set_stopped(1, 2, true).unwrap();
+        assert!(jobs.stopped(1) && jobs.stopped(2));
+        jobs.set_stopped(1, 2, false).unwrap();
+        assert!(!jobs.stopped(2));
+        // Interrupting continues the process so it can handle the signal
+        assert_eq!(jobs.interrupt(0), Some(1));
+        assert!(!jobs.stopped(1));
+        assert!(jobs.take_interrupt(1));
+    }
+}
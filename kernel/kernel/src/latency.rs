@@ -0,0 +1,105 @@
+//! Latency histograms of interrupt handlers and system calls
+//!
+//! Latencies are measured with the time stamp counter from entry until the
+//! handler completes, and counted in buckets of powers of two. Measurement is
+//! enabled with the `latency` option of the kernel configuration; otherwise
+//! nothing is measured.
+
+use crate::{config, trace};
+use core::sync::atomic::{AtomicU64, Ordering};
+use sys::{LatencyHistogram, LatencySource, LATENCY_BUCKETS};
+
+static HISTOGRAMS: [Histogram; 2] = [Histogram::new(), Histogram::new()];
+
+struct Histogram {
+    buckets: [AtomicU64; LATENCY_BUCKETS],
+    max: AtomicU64,
+}
+
+impl Histogram {
+    const fn new() -> Self {
+        const ZERO: AtomicU64 = AtomicU64::new(0);
+        Self {
+            buckets: [ZERO; LATENCY_BUCKETS],
+            max: ZERO,
+        }
+    }
+
+    fn record(&self, cycles: u64) {
+        let bucket = (64 - cycles.leading_zeros() as usize).min(LATENCY_BUCKETS - 1);
+        self.buckets[bucket].fetch_add(1, Ordering::Relaxed);
+        self.max.fetch_max(cycles, Ordering::Relaxed);
+    }
+
+    fn snapshot(&self) -> LatencyHistogram {
+        let mut histogram = LatencyHistogram {
+            buckets: [0; LATENCY_BUCKETS],
+            max: self.max.load(Ordering::Relaxed),
+        };
+        for (count, bucket) in histogram.buckets.iter_mut().zip(&self.buckets) {
+            *count = bucket.load(Ordering::Relaxed);
+        }
+        histogram
+    }
+}
+
+/// Start of a measurement, to be passed to [`finish`]
+pub fn start() -> u64 {
+    if !config::latency() {
+        return 0;
+    }
+    trace::timestamp()
+}
+
+/// Count the latency since `start` in the histogram of `source`
+pub fn finish(source: LatencySource, start: u64) {
+    if !config::latency() {
+        return;
+    }
+    HISTOGRAMS[source as usize].record(trace::timestamp().wrapping_sub(start));
+}
+
+/// Histogram of `source`, or [`None`] if measurement is disabled
+pub fn histogram(source: LatencySource) -> Option<LatencyHistogram> {
+    if !config::latency() {
+        return None;
+    }
+    Some(HISTOGRAMS[source as usize].snapshot())
+}
+
+/// Log the histograms of all sources
+pub fn report() {
+    for &source in &[LatencySource::Interrupt, LatencySource::Syscall] {
+        let histogram = match histogram(source) {
+            Some(histogram) => histogram,
+            None => return,
+        };
+        let count: u64 = histogram.buckets.iter().sum();
+        log::info!(
+            "{:?} latency: {} samples, max {} cycles",
+            source,
+            count,
+            histogram.max
+        );
+        for (i, &n) in histogram.buckets.iter().enumerate().filter(|(_, &n)| n > 0) {
+            log::info!("  < 2^{:<2} cycles: {}", i, n);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test_case]
+    fn buckets() {
+        let histogram = Histogram::new();
+        for &cycles in &[0, 1, 2, 3, 4, u64::MAX] {
+            histogram.record(cycles);
+        }
+        let snapshot = histogram.snapshot();
+        assert_eq!(&snapshot.buckets[..4], &[1, 1, 2, 1]);
+        assert_eq!(snapshot.buckets[LATENCY_BUCKETS - 1], 1);
+        assert_eq!(snapshot.max, u64::MAX);
+    }
+}
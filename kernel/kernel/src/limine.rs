@@ -0,0 +1,496 @@
+//! Entry through the Limine boot protocol
+//!
+//! Besides the UEFI stub, the kernel can be booted by any bootloader that
+//! implements the [Limine protocol], such as Limine itself. The bootloader
+//! fills in the responses to the requests below and enters the kernel at
+//! [`entry`]. Its responses are translated into a [`BootInfo`], and the page
+//! tables that the stub would set up are created before continuing at
+//! [`_start`](crate::_start) like the stub does.
+//!
+//! The bootloader loads the kernel contiguously in physical memory, and maps
+//! and relocates it at an address of its choice in the higher half. The kernel
+//! is mapped and relocated again at the address the stub uses, after which the
+//! higher half of the bootloader is dropped.
+//!
+//! This entry is experimental and untested: unlike the stub, the test runner
+//! does not boot through it.
+//!
+//! [Limine protocol]: https://github.com/limine-bootloader/limine/blob/trunk/PROTOCOL.md
+
+use crate::trace;
+use common::{
+    boot::{offset, BootInfo, BootTimes, CommandLine, FramebufferInfo, MemoryMap, PixelFormat},
+    boot_error,
+    elf::ElfInfo,
+    error::BootError,
+    paging, println, serial,
+};
+use core::{
+    mem, slice, str,
+    sync::atomic::{AtomicPtr, AtomicU64, Ordering},
+};
+use uefi::table::boot::{MemoryDescriptor, MemoryType};
+use x86_64::{
+    instructions::{hlt, tlb},
+    registers::control::{Cr3, Cr3Flags},
+    structures::paging::{FrameAllocator, Mapper, OffsetPageTable, PageTable, PhysFrame, Size4KiB},
+    PhysAddr, VirtAddr,
+};
+
+/// Shared first half of the identifiers of all requests
+const COMMON_MAGIC: [u64; 2] = [0xc7b1_dd30_df4c_8b88, 0x0a82_e883_a194_f07b];
+
+/// Request for a feature of the bootloader, answered by setting `response`
+///
+/// Fields of this and the other protocol structures that only the bootloader
+/// reads, or that are only there for the layout, allow dead code.
+#[repr(C)]
+struct Request<T> {
+    #[allow(dead_code)]
+    id: [u64; 4],
+    #[allow(dead_code)]
+    revision: u64,
+    response: AtomicPtr<T>,
+}
+
+impl<T> Request<T> {
+    const fn new(id: [u64; 2]) -> Self {
+        Self {
+            id: [COMMON_MAGIC[0], COMMON_MAGIC[1], id[0], id[1]],
+            revision: 0,
+            response: AtomicPtr::new(core::ptr::null_mut()),
+        }
+    }
+
+    /// Response of the bootloader, if it supports the feature
+    fn response(&self) -> Option<&'static T> {
+        unsafe { self.response.load(Ordering::Relaxed).as_ref() }
+    }
+}
+
+/// Response without contents other than its revision
+#[repr(C)]
+struct Empty {
+    #[allow(dead_code)]
+    revision: u64,
+}
+
+#[repr(C)]
+struct EntryPointRequest {
+    request: Request<Empty>,
+    #[allow(dead_code)]
+    entry: unsafe extern "C" fn() -> !,
+}
+
+#[repr(C)]
+struct HhdmResponse {
+    #[allow(dead_code)]
+    revision: u64,
+    offset: u64,
+}
+
+#[repr(C)]
+struct MemmapResponse {
+    #[allow(dead_code)]
+    revision: u64,
+    entry_count: u64,
+    entries: *const &'static MemmapEntry,
+}
+
+#[repr(C)]
+struct MemmapEntry {
+    base: u64,
+    length: u64,
+    kind: u64,
+}
+
+/// Memory map entry types
+mod kind {
+    pub const USABLE: u64 = 0;
+    pub const ACPI_RECLAIMABLE: u64 = 2;
+    pub const ACPI_NVS: u64 = 3;
+    pub const BAD_MEMORY: u64 = 4;
+    pub const BOOTLOADER_RECLAIMABLE: u64 = 5;
+    pub const EXECUTABLE_AND_MODULES: u64 = 6;
+}
+
+#[repr(C)]
+struct ExecutableAddressResponse {
+    #[allow(dead_code)]
+    revision: u64,
+    physical_base: u64,
+    virtual_base: u64,
+}
+
+#[repr(C)]
+struct ExecutableFileResponse {
+    #[allow(dead_code)]
+    revision: u64,
+    file: &'static File,
+}
+
+/// File loaded by the bootloader; only the fields used are included
+#[repr(C)]
+struct File {
+    #[allow(dead_code)]
+    revision: u64,
+    address: *const u8,
+    size: u64,
+    #[allow(dead_code)]
+    path: *const u8,
+    cmdline: *const u8,
+}
+
+#[repr(C)]
+struct FramebufferResponse {
+    #[allow(dead_code)]
+    revision: u64,
+    framebuffer_count: u64,
+    framebuffers: *const &'static Framebuffer,
+}
+
+/// Frame buffer set up by the bootloader; only the fields used are included
+#[repr(C)]
+struct Framebuffer {
+    address: u64,
+    width: u64,
+    height: u64,
+    pitch: u64,
+    bpp: u16,
+    memory_model: u8,
+    #[allow(dead_code)]
+    red_mask_size: u8,
+    red_mask_shift: u8,
+    #[allow(dead_code)]
+    green_mask_size: u8,
+    green_mask_shift: u8,
+    #[allow(dead_code)]
+    blue_mask_size: u8,
+    blue_mask_shift: u8,
+}
+
+#[repr(C)]
+struct RsdpResponse {
+    #[allow(dead_code)]
+    revision: u64,
+    address: u64,
+}
+
+#[repr(C)]
+struct SmbiosResponse {
+    #[allow(dead_code)]
+    revision: u64,
+    entry_32: u64,
+    entry_64: u64,
+}
+
+/// Base revision of the protocol; the last element is set to zero by a
+/// bootloader that supports it
+static BASE_REVISION: [AtomicU64; 3] = [
+    AtomicU64::new(0xf956_2b2d_5c95_a6c8),
+    AtomicU64::new(0x6a7b_3849_4453_6bdc),
+    AtomicU64::new(1),
+];
+
+static ENTRY_POINT: EntryPointRequest = EntryPointRequest {
+    request: Request::new([0x13d8_6c03_5a1c_d3e1, 0x2b0c_aa89_d8f3_026a]),
+    entry,
+};
+static HHDM: Request<HhdmResponse> = Request::new([0x48dc_f1cb_8ad2_b852, 0x6398_4e95_9a98_244b]);
+static MEMMAP: Request<MemmapResponse> =
+    Request::new([0x67cf_3d9d_378a_806f, 0xe304_acdf_c50c_3c62]);
+static EXECUTABLE_ADDRESS: Request<ExecutableAddressResponse> =
+    Request::new([0x71ba_7686_3cc5_5f63, 0xb264_4a48_c516_a487]);
+static EXECUTABLE_FILE: Request<ExecutableFileResponse> =
+    Request::new([0xad97_e90e_83f1_ed67, 0x31eb_5d1c_5ff2_3b69]);
+static FRAMEBUFFER: Request<FramebufferResponse> =
+    Request::new([0x9d58_27dc_d881_dd75, 0xa314_8604_f6fa_b11b]);
+static RSDP: Request<RsdpResponse> = Request::new([0xc5e7_7b6b_397e_7b43, 0x2763_7845_accd_cf3c]);
+static SMBIOS: Request<SmbiosResponse> =
+    Request::new([0x9e90_46f1_1e09_5391, 0xaa4a_520f_efbd_e5ee]);
+
+/// Number of pages of the stack the kernel is entered with, as with the stub
+const STACK_PAGES: u64 = 16;
+/// Number of pages holding the translated memory map
+const MEMORY_MAP_PAGES: u64 = 3;
+
+/// Whether the kernel was booted through the Limine protocol
+pub fn booted() -> bool {
+    ENTRY_POINT.request.response().is_some()
+}
+
+/// Entry point requested from the bootloader, running at the address it chose
+unsafe extern "C" fn entry() -> ! {
+    serial::init();
+    if let Err(e) = boot() {
+        println!("Booting through the Limine protocol failed: {}", e);
+    }
+    loop {
+        hlt();
+    }
+}
+
+/// Frames for the data handed to the kernel, taken from the start of the
+/// largest usable region, which is reported as loader data in the memory map
+struct Frames {
+    hhdm: u64,
+    /// Index of the memory map entry the frames are taken from
+    entry: usize,
+    start: u64,
+    next: u64,
+    end: u64,
+}
+
+impl Frames {
+    fn new(entries: &[&MemmapEntry], hhdm: u64) -> Result<Self, &'static str> {
+        let (entry, region) = entries
+            .iter()
+            .enumerate()
+            .filter(|(_, region)| region.kind == kind::USABLE)
+            .max_by_key(|(_, region)| region.length)
+            .ok_or("No usable memory")?;
+        Ok(Self {
+            hhdm,
+            entry,
+            start: region.base,
+            next: region.base,
+            end: region.base + region.length,
+        })
+    }
+
+    /// Allocate `count` contiguous zeroed frames, returning the address of
+    /// the first
+    fn allocate(&mut self, count: u64) -> Result<PhysAddr, &'static str> {
+        let addr = self.next;
+        if addr + count * 4096 > self.end {
+            return Err("Out of memory for boot data");
+        }
+        self.next += count * 4096;
+        unsafe { ((self.hhdm + addr) as *mut u8).write_bytes(0, (count * 4096) as usize) };
+        Ok(PhysAddr::new(addr))
+    }
+
+    /// Memory in `addr` for the bootloader
+    fn virt(&self, addr: PhysAddr) -> VirtAddr {
+        VirtAddr::new(self.hhdm + addr.as_u64())
+    }
+}
+
+unsafe impl FrameAllocator<Size4KiB> for Frames {
+    fn allocate_frame(&mut self) -> Option<PhysFrame> {
+        self.allocate(1).ok().map(PhysFrame::containing_address)
+    }
+}
+
+/// Type of UEFI memory corresponding to a memory map entry type
+fn memory_type(kind: u64) -> MemoryType {
+    match kind {
+        kind::USABLE => MemoryType::CONVENTIONAL,
+        kind::ACPI_RECLAIMABLE => MemoryType::ACPI_RECLAIM,
+        kind::ACPI_NVS => MemoryType::ACPI_NON_VOLATILE,
+        kind::BAD_MEMORY => MemoryType::UNUSABLE,
+        // Bootloader memory holds the stack and structures in use until the
+        // kernel is entered, so it is never reclaimed
+        kind::BOOTLOADER_RECLAIMABLE | kind::EXECUTABLE_AND_MODULES => MemoryType::LOADER_DATA,
+        _ => MemoryType::RESERVED,
+    }
+}
+
+/// Translate the memory map into UEFI memory descriptors at `descriptors`,
+/// splitting off the frames taken by `frames`
+fn memory_map(
+    entries: &[&MemmapEntry],
+    frames: &Frames,
+    descriptors: PhysAddr,
+) -> Result<MemoryMap, &'static str> {
+    let capacity = (MEMORY_MAP_PAGES * 4096) as usize / mem::size_of::<MemoryDescriptor>();
+    if entries.len() + 1 > capacity {
+        return Err("Too many memory map entries");
+    }
+    let ptr = frames.virt(descriptors).as_mut_ptr::<MemoryDescriptor>();
+    let mut len = 0;
+    let mut push = |ty, start: u64, end: u64| {
+        if start < end {
+            let mut descriptor = MemoryDescriptor::default();
+            descriptor.ty = ty;
+            descriptor.phys_start = start;
+            descriptor.page_count = (end - start) / 4096;
+            unsafe { ptr.add(len).write(descriptor) };
+            len += 1;
+        }
+    };
+    for (i, entry) in entries.iter().enumerate() {
+        let end = entry.base + entry.length;
+        if i == frames.entry {
+            push(MemoryType::LOADER_DATA, frames.start, frames.next);
+            push(MemoryType::CONVENTIONAL, frames.next, end);
+        } else {
+            push(memory_type(entry.kind), entry.base, end);
+        }
+    }
+    let ptr = offset::phys_to_virt(descriptors).as_ptr();
+    let size = mem::size_of::<MemoryDescriptor>();
+    Ok(unsafe { MemoryMap::new(ptr, size, len) })
+}
+
+/// Physical address of a table the bootloader located, which depending on the
+/// base revision is given in the higher half direct map
+fn table_address(addr: u64, hhdm: u64) -> Option<PhysAddr> {
+    match addr {
+        0 => None,
+        addr if addr >= hhdm => Some(PhysAddr::new(addr - hhdm)),
+        addr => Some(PhysAddr::new(addr)),
+    }
+}
+
+/// First frame buffer, if any
+fn framebuffer(hhdm: u64) -> Option<FramebufferInfo> {
+    let response = FRAMEBUFFER.response()?;
+    if response.framebuffer_count == 0 {
+        return None;
+    }
+    let fb = unsafe { *response.framebuffers };
+    // Only 32-bit pixels are supported, like with UEFI
+    if fb.bpp != 32 {
+        return None;
+    }
+    let shifts = (fb.red_mask_shift, fb.green_mask_shift, fb.blue_mask_shift);
+    let format = match (fb.memory_model, shifts) {
+        (1, (0, 8, 16)) => PixelFormat::Rgb,
+        (1, (16, 8, 0)) => PixelFormat::Bgr,
+        _ => PixelFormat::Bitmask,
+    };
+    Some(FramebufferInfo {
+        phys_addr: PhysAddr::new(fb.address - hhdm),
+        size: (fb.pitch * fb.height) as usize,
+        resolution: (fb.width as usize, fb.height as usize),
+        stride: (fb.pitch / 4) as usize,
+        format,
+    })
+}
+
+/// Command line of the kernel as configured in the bootloader
+fn command_line(file: &File) -> CommandLine {
+    if file.cmdline.is_null() {
+        return CommandLine::new("");
+    }
+    let len = (0..)
+        .take_while(|&i| unsafe { *file.cmdline.add(i) } != 0)
+        .count();
+    let bytes = unsafe { slice::from_raw_parts(file.cmdline, len) };
+    CommandLine::new(str::from_utf8(bytes).unwrap_or(""))
+}
+
+/// Map the loaded kernel where the UEFI stub would, with the permissions of
+/// its segments, returning the lowest address it is mapped at
+fn map_kernel(
+    kernel: &ElfInfo,
+    physical_base: u64,
+    map: &mut OffsetPageTable,
+    frames: &mut Frames,
+) -> Result<VirtAddr, BootError> {
+    let base = kernel
+        .segments()
+        .map(|(pages, _)| pages.start)
+        .min()
+        .ok_or("Kernel without loadable segments")?;
+    for (pages, flags) in kernel.segments() {
+        for page in pages {
+            let addr = PhysAddr::new(physical_base + (page - base) * 4096);
+            let frame = PhysFrame::containing_address(addr);
+            unsafe { map.map_to(page, frame, flags, frames) }
+                .map_err(|_| boot_error!("Mapping error", "{:?} to {:?}", page, frame))?
+                .ignore();
+        }
+    }
+    Ok(base.start_address())
+}
+
+/// Translate the responses of the bootloader and enter the kernel
+unsafe fn boot() -> Result<(), BootError> {
+    let loader_entry = trace::timestamp();
+    if BASE_REVISION[2].load(Ordering::Relaxed) != 0 {
+        return Err("Base revision not supported".into());
+    }
+    if paging::la57_enabled() {
+        return Err("5-level paging not supported".into());
+    }
+    let hhdm = HHDM.response().ok_or("No higher half direct map")?.offset;
+    let address = EXECUTABLE_ADDRESS.response().ok_or("No kernel address")?;
+    let file = EXECUTABLE_FILE.response().ok_or("No kernel file")?.file;
+    let memmap = MEMMAP.response().ok_or("No memory map")?;
+    let entries = slice::from_raw_parts(memmap.entries, memmap.entry_count as usize);
+
+    let bytes = slice::from_raw_parts(file.address, file.size as usize);
+    let kernel = ElfInfo::new(bytes, false)?;
+    let mut frames = Frames::new(entries, hhdm)?;
+    let descriptors = frames.allocate(MEMORY_MAP_PAGES)?;
+    let boot_info = frames.allocate(1)?;
+    let stack = frames.allocate(STACK_PAGES)? + STACK_PAGES * 4096;
+
+    // Same page table as set up by the stub, plus the higher half of the
+    // bootloader in which this code and its stack run
+    let page_table = frames.allocate(1)?;
+    let pml4 = &mut *frames.virt(page_table).as_mut_ptr::<PageTable>();
+    paging::map_offset(pml4, hhdm, || {
+        frames.allocate(1).map(PhysFrame::containing_address)
+    })?;
+    let current = frames.virt(Cr3::read().0.start_address());
+    let current = &*current.as_ptr::<PageTable>();
+    for (entry, current) in pml4.iter_mut().zip(current.iter()).skip(256) {
+        *entry = current.clone();
+    }
+    let mut map = OffsetPageTable::new(pml4, VirtAddr::new(hhdm));
+    let kernel_base = map_kernel(&kernel, address.physical_base, &mut map, &mut frames)?;
+
+    // Nothing is allocated from here on, so the memory map is final
+    let info = BootInfo {
+        uefi_system_table: None,
+        memory_map: memory_map(entries, &frames, descriptors)?,
+        fb: framebuffer(hhdm),
+        cmdline: command_line(file),
+        smbios: SMBIOS.response().and_then(|smbios| {
+            // The kernel prefers the 64-bit entry point, like the stub does
+            table_address(smbios.entry_64, hhdm).or_else(|| table_address(smbios.entry_32, hhdm))
+        }),
+        rsdp: RSDP
+            .response()
+            .and_then(|rsdp| table_address(rsdp.address, hhdm)),
+        kernel_offset: kernel.offset(),
+        // The bootloader exited boot services before entering the kernel
+        times: BootTimes {
+            loader_entry,
+            ..BootTimes::default()
+        },
+    };
+    frames.virt(boot_info).as_mut_ptr::<BootInfo>().write(info);
+
+    let finish = finish as usize as u64 - address.virtual_base + kernel_base.as_u64();
+    Cr3::write(PhysFrame::containing_address(page_table), Cr3Flags::empty());
+    // Data now refers to the kernel as mapped by the new page table
+    kernel.relocate_with(|virt| {
+        let addr = hhdm + address.physical_base + (virt - kernel_base);
+        Ok(addr as *mut u64)
+    })?;
+    let info = frames.virt(boot_info).as_mut_ptr::<BootInfo>();
+    (*info).times.handoff = trace::timestamp();
+    asm!(
+        "mov rsp, {}; call {}",
+        in(reg) offset::phys_to_virt(stack).as_u64(),
+        in(reg) finish,
+        in("rdi") offset::phys_to_virt(boot_info).as_u64(),
+        options(noreturn)
+    );
+}
+
+/// Drop the higher half of the bootloader and enter the kernel, running where
+/// the UEFI stub would have mapped the kernel
+unsafe extern "C" fn finish(boot_info: &'static BootInfo) -> ! {
+    let pml4 = offset::phys_to_virt(Cr3::read().0.start_address());
+    let pml4 = &mut *pml4.as_mut_ptr::<PageTable>();
+    for entry in pml4.iter_mut().skip(256) {
+        entry.set_unused();
+    }
+    tlb::flush_all();
+    crate::_start(boot_info);
+}
@@ -5,7 +5,8 @@
     alloc_error_handler,
     asm,
     const_mut_refs,
-    custom_test_frameworks
+    custom_test_frameworks,
+    global_asm
 )]
 #![allow(clippy::inconsistent_digit_grouping)]
 #![test_runner(test::test_runner)]
@@ -13,56 +14,117 @@
 
 extern crate alloc;
 
+mod acpi;
 mod allocator;
+mod audit;
+mod base64;
+mod boot_profile;
+mod config;
+mod console;
+mod core_dump;
+mod crash_dump;
+mod devices;
+mod drivers;
+mod fault;
+mod framebuffer;
+mod handle;
+mod hmac;
+mod idle;
+mod initcall;
+mod inject;
+mod input;
 mod interrupts;
+mod jobs;
+mod latency;
+mod limine;
+mod mmap;
+mod net;
+mod oom;
+mod physmap;
+mod ports;
+mod profile;
+mod programs;
+mod ptrace;
+mod remote_log;
+mod shutdown;
+mod signal;
+mod smbios;
+mod stack;
+mod sync;
+mod telemetry;
 #[cfg(test)]
 mod test;
 mod threads;
+mod time;
+mod trace;
+mod user;
+mod vm;
+mod watchdog;
 
 use allocator::{RegionFrameAllocator, UserFrameAllocator};
 use common::{
     boot::{offset, BootInfo, KernelMain},
     elf::Elf,
+    paging,
 };
 use core::alloc::Layout;
-use x86_64::{
-    registers::control::Cr3,
-    structures::paging::{OffsetPageTable, PageTable},
-};
-
-mod config {
-    include!(concat!(env!("XTASK_OUT_DIR"), "/cfg_kernel.rs"));
-}
-
-const USER_SIZE: usize = include_bytes!(env!("USER_PATH")).len();
-const USER_BYTES: [u8; USER_SIZE] = *include_bytes!(env!("USER_PATH"));
-
-/// Put userspace ELF in memory
-static USER: Elf<USER_SIZE> = Elf::new(USER_BYTES);
+use log::LevelFilter;
+use vm::AddressSpace;
+use x86_64::structures::paging::{OffsetPageTable, PageTable};
 
 // Type-check of kernel entry point
 const _: KernelMain = _start;
 
 pub struct Init {
     boot_info: &'static BootInfo,
-    page_table: OffsetPageTable<'static>,
+    address_space: AddressSpace,
     frame_allocator: UserFrameAllocator<RegionFrameAllocator>,
 }
 
 fn init(boot_info: &'static BootInfo) -> Init {
-    common::init(config::LOG_LEVEL).unwrap();
-    let page_table_addr = offset::VIRT_ADDR + Cr3::read().0.start_address().as_u64();
+    boot_profile::mark("Kernel entry");
+    // The log level is narrowed to the configured one right away
+    common::init(LevelFilter::Trace, config::LOG_FORMAT).unwrap();
+    config::init(boot_info.cmdline.as_str());
+    if limine::booted() {
+        log::info!("Booted through the Limine protocol");
+    }
+    crash_dump::init(boot_info);
+    boot_profile::mark("Logging and configuration");
+    let level_4_table = unsafe { paging::active_level_4_table(offset::VIRT_ADDR) };
+    let page_table_addr = offset::phys_to_virt(level_4_table.start_address());
     let page_table_ref = unsafe { &mut *page_table_addr.as_mut_ptr::<PageTable>() };
-    let mut page_table = unsafe { OffsetPageTable::new(page_table_ref, offset::VIRT_ADDR) };
+    let page_table = unsafe { OffsetPageTable::new(page_table_ref, offset::VIRT_ADDR) };
+    let mut address_space = AddressSpace::new(page_table);
     let mut frame_allocator = RegionFrameAllocator::new(boot_info.memory_map.clone());
-    allocator::init(&mut page_table, &mut frame_allocator).unwrap();
+    acpi::sleep::reserve(&mut frame_allocator);
+    allocator::init(&mut address_space, &mut frame_allocator).unwrap();
+    stack::init(&mut address_space, &mut frame_allocator).unwrap();
+    boot_profile::mark("Memory");
     interrupts::init();
+    boot_profile::mark("Interrupts");
+    common::logger::set_clock(|| interrupts::ticks() * 1000 / interrupts::TIMER_FREQUENCY as u64);
+    trace::init();
+    profile::init(boot_info);
+    watchdog::init();
+    time::init(&mut frame_allocator).unwrap();
+    idle::init();
+    physmap::init(&mut frame_allocator).unwrap();
+    boot_profile::mark("Timers and page table isolation");
     let frame_allocator = UserFrameAllocator::new(frame_allocator);
-    Init {
+    let mut init = Init {
         boot_info,
-        page_table,
+        address_space,
         frame_allocator,
-    }
+    };
+    initcall::run(&mut init);
+    boot_profile::mark("Init calls");
+    framebuffer::init(&mut init);
+    console::init();
+    boot_profile::mark("Frame buffer and console");
+    devices::dump();
+    boot_profile::report(&boot_info.times);
+    init
 }
 
 // Kernel entry point for tests
@@ -70,36 +132,75 @@ fn init(boot_info: &'static BootInfo) -> Init {
 #[no_mangle]
 pub unsafe extern "C" fn _start(boot_info: &'static BootInfo) -> ! {
     let init = init(boot_info);
-    test::run_tests(init);
+    stack::switch(init, test::run_tests);
 }
 
 /// Kernel entry point
 #[cfg(not(test))]
 #[no_mangle]
 pub unsafe extern "C" fn _start(boot_info: &'static BootInfo) -> ! {
-    let mut init = init(boot_info);
+    let init = init(boot_info);
+    stack::switch(init, kernel_main);
+}
 
+/// Kernel main function, running on the kernel stack
+#[cfg(not(test))]
+fn kernel_main(mut init: Init) -> ! {
     // Single line to prevent race condition with first timer interrupt
     common::println!("\n== ÅngstrÖS v{} ==\n", env!("CARGO_PKG_VERSION"));
 
     log::info!("Boot complete");
-    threads::spawn_user(&mut init, &USER.info(true).unwrap());
-    log::info!("Rerunning user process");
-    threads::spawn_user(&mut init, &USER.info(true).unwrap());
+    let limits = threads::Limits::default();
+    for run in 0..2 {
+        if run > 0 {
+            log::info!("Rerunning user process");
+        }
+        // The first process is init, later ones get no handles as grants are
+        // only passed on to processes spawned by the granting one
+        let handles = match run {
+            0 => handle::HandleTable::devices(),
+            _ => handle::HandleTable::new(),
+        };
+        if let Err(e) = unsafe { threads::spawn_user(&mut init, programs::INIT, limits, handles) } {
+            log::error!("Failed to run user process: {}", e);
+        }
+        if shutdown::requested() {
+            break;
+        }
+    }
+    trace::dump();
+    profile::dump();
+    latency::report();
+    idle::report();
+    inject::report();
+    if shutdown::requested() {
+        shutdown::run();
+    }
     log::info!("Going to halt");
 
     loop {
-        x86_64::instructions::hlt();
+        // The power button can still request a shutdown
+        if shutdown::requested() {
+            shutdown::run();
+        }
+        watchdog::touch();
+        drivers::virtio::balloon::poll(&mut init);
+        idle::wait();
     }
 }
 
 #[cfg(not(test))]
 #[panic_handler]
 fn panic(info: &core::panic::PanicInfo) -> ! {
+    remote_log::panic(info);
+    crash_dump::write(info);
     common::panic_handler(info);
 }
 
 #[alloc_error_handler]
 fn alloc_error(layout: Layout) -> ! {
-    panic!("Out of memory requesting {:#?}", layout);
+    panic!(
+        "Out of memory requesting {:#?} after growing heap and reclaiming caches",
+        layout
+    );
 }
@@ -0,0 +1,131 @@
+//! Memory allocated by processes with [`SyscallCode::Mmap`]
+//!
+//! Processes get fresh zeroed frames mapped at an address chosen by the
+//! kernel, above the frame buffer. The mappings are recorded in the address
+//! space like the stack, so they count towards the memory limit of the process
+//! and are unmapped with its other memory when it exits.
+//!
+//! [`SyscallCode::Mmap`]: sys::SyscallCode::Mmap
+
+use crate::{
+    vm::{Backing, Mapping},
+    Init,
+};
+use common::boot::offset;
+use core::ptr;
+use x86_64::{
+    structures::paging::{Page, PageTableFlags},
+    VirtAddr,
+};
+
+/// Lowest virtual address at which memory is mapped
+const MAP_START: VirtAddr = VirtAddr::new_truncate(0x4000_0000);
+/// Virtual address below which mappings have to end
+const MAP_END: VirtAddr = VirtAddr::new_truncate(0x8000_0000);
+
+/// Map `len` bytes, rounded up to whole pages, for process `pid`, returning
+/// their address
+pub fn map(init: &mut Init, pid: u64, len: u64) -> Result<u64, &'static str> {
+    if len == 0 {
+        return Err("Empty mapping");
+    }
+    let count = len.checked_add(4095).ok_or("Mapping too large")? / 4096;
+    let start = init
+        .address_space
+        .find_free(MAP_START, MAP_END, count)
+        .ok_or("No virtual memory available")?;
+    let flags =
+        PageTableFlags::PRESENT | PageTableFlags::WRITABLE | PageTableFlags::USER_ACCESSIBLE;
+    init.address_space.map_anonymous(
+        Page::range(start, start + count),
+        flags,
+        Some(pid),
+        &mut init.frame_allocator,
+    )?;
+    // Frames are reused without clearing them. Like other frames filled by the
+    // kernel, they are written through the offset mapping, not user addresses.
+    for page in Page::range(start, start + count) {
+        // Mapped right above
+        let frame = init.address_space.translate(page).unwrap();
+        let ptr = offset::phys_to_virt(frame.start_address()).as_mut_ptr::<u8>();
+        unsafe { ptr::write_bytes(ptr, 0, 4096) };
+    }
+    let addr = start.start_address();
+    log::debug!("Mapped {} pages at {:?} for process {}", count, addr, pid);
+    Ok(addr.as_u64())
+}
+
+/// Unmap the memory mapped by [`map`] for process `pid` at `addr`
+pub fn unmap(init: &mut Init, pid: u64, addr: u64) -> Result<(), &'static str> {
+    let addr = VirtAddr::try_new(addr).map_err(|_| "Invalid address")?;
+    let start = Page::from_start_address(addr).map_err(|_| "Misaligned address")?;
+    let owned = |mapping: &Mapping| {
+        mapping.pages.start == start
+            && mapping.pid == Some(pid)
+            && mapping.backing == Backing::Anonymous
+            && MAP_START <= addr
+            && addr < MAP_END
+    };
+    if !init.address_space.mappings().any(owned) {
+        return Err("No memory mapped at address");
+    }
+    init.address_space.unmap(start, &mut init.frame_allocator)?;
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::inject::{self, Site};
+
+    /// Process that never runs, owning the memory mapped by the tests
+    const PID: u64 = u64::MAX;
+
+    #[test_case]
+    fn map_and_unmap() {
+        let mut guard = crate::test::INIT.lock();
+        let init = guard.as_mut().unwrap();
+        let addr = map(init, PID, 4097).unwrap();
+        let memory = unsafe { core::slice::from_raw_parts_mut(addr as *mut u8, 8192) };
+        assert!(memory.iter().all(|&byte| byte == 0));
+        memory[8191] = 1;
+        let other = map(init, PID, 1).unwrap();
+        assert_eq!(other, addr + 8192);
+        // Only whole mappings of the process itself
+        assert!(unmap(init, PID, addr + 4096).is_err());
+        assert!(unmap(init, PID - 1, addr).is_err());
+        unmap(init, PID, addr).unwrap();
+        assert!(unmap(init, PID, addr).is_err());
+        assert!(map(init, PID, 0).is_err());
+        init.address_space
+            .unmap_process(PID, &mut init.frame_allocator);
+        assert!(init
+            .address_space
+            .mappings()
+            .all(|mapping| mapping.pid != Some(PID)));
+    }
+
+    /// A mapping that runs out of frames halfway leaves nothing behind
+    #[test_case]
+    fn map_without_frames() {
+        let mut guard = crate::test::INIT.lock();
+        let init = guard.as_mut().unwrap();
+        // Page tables that are kept after unmapping are allocated up front
+        let addr = map(init, PID, 4 * 4096).unwrap();
+        unmap(init, PID, addr).unwrap();
+        let free = init.frame_allocator.free_frames();
+        inject::force(Site::Frame, 2);
+        let result = map(init, PID, 4 * 4096);
+        inject::force(Site::Frame, 0);
+        assert!(result.is_err());
+        assert_eq!(init.frame_allocator.free_frames(), free);
+        assert_eq!(init.address_space.usage(PID), 0);
+        assert!(init
+            .address_space
+            .mappings()
+            .all(|mapping| mapping.pid != Some(PID)));
+        // The pages can be mapped again
+        assert_eq!(map(init, PID, 4 * 4096), Ok(addr));
+        unmap(init, PID, addr).unwrap();
+    }
+}
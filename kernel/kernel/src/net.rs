@@ -0,0 +1,303 @@
+//! Network devices and UDP datagrams
+//!
+//! The kernel uses a single [`NetDevice`] at a time: the first virtio network
+//! device, or a [`Loopback`](loopback::Loopback) device, e.g. in tests. Frames
+//! are sent synchronously and received frames are polled. Only UDP over IPv4
+//! without fragmentation is understood, which is enough for
+//! [`crate::remote_log`]; there is no ARP, so datagrams are sent to the
+//! Ethernet broadcast address.
+
+// Receiving frames is only used by tests yet
+#[cfg(test)]
+pub mod loopback;
+
+use alloc::boxed::Box;
+use core::{
+    fmt,
+    str::FromStr,
+    sync::atomic::{AtomicU16, Ordering},
+};
+use spin::Mutex;
+
+/// Maximum size of a frame without its checksum, for a 1500-byte MTU
+pub const MAX_FRAME: usize = 1514;
+
+const ETHERNET_SIZE: usize = 14;
+const IPV4_SIZE: usize = 20;
+const UDP_SIZE: usize = 8;
+/// Offset of the payload of a datagram sent by [`send_udp`]
+const PAYLOAD_OFFSET: usize = ETHERNET_SIZE + IPV4_SIZE + UDP_SIZE;
+/// Maximum size of the payload of a datagram
+pub const MAX_PAYLOAD: usize = MAX_FRAME - PAYLOAD_OFFSET;
+
+const ETHERTYPE_IPV4: u16 = 0x0800;
+const PROTOCOL_UDP: u8 = 17;
+
+/// Device sending and receiving Ethernet frames
+pub trait NetDevice: Send {
+    /// MAC address of the device
+    fn mac(&self) -> [u8; 6];
+    /// Send `frame`, which is at most [`MAX_FRAME`] bytes
+    fn send(&mut self, frame: &[u8]);
+    /// Copy the next received frame, if any, to `buf`, returning its size
+    #[cfg(test)]
+    fn receive(&mut self, _buf: &mut [u8; MAX_FRAME]) -> Option<usize> {
+        None
+    }
+    /// Stop using the device, e.g. before the machine is handed over
+    fn stop(&mut self) {}
+}
+
+static DEVICE: Mutex<Option<Box<dyn NetDevice>>> = Mutex::new(None);
+
+/// Identification field of the next IPv4 packet
+static IDENTIFICATION: AtomicU16 = AtomicU16::new(0);
+
+/// Use `device` from now on, returning the previous device
+pub fn set_device(device: Option<Box<dyn NetDevice>>) -> Option<Box<dyn NetDevice>> {
+    core::mem::replace(&mut *DEVICE.lock(), device)
+}
+
+/// Copy the next frame received by the network device, if any, to `buf`,
+/// returning its size
+#[cfg(test)]
+pub fn receive(buf: &mut [u8; MAX_FRAME]) -> Option<usize> {
+    DEVICE.lock().as_mut()?.receive(buf)
+}
+
+/// IPv4 address and UDP port
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub struct Endpoint {
+    pub addr: [u8; 4],
+    pub port: u16,
+}
+
+impl FromStr for Endpoint {
+    type Err = &'static str;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let (addr, port) = s.split_once(':').ok_or("Expected address:port")?;
+        Ok(Self {
+            addr: parse_addr(addr)?,
+            port: port.parse().map_err(|_| "Invalid port")?,
+        })
+    }
+}
+
+impl fmt::Display for Endpoint {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let [a, b, c, d] = self.addr;
+        write!(f, "{}.{}.{}.{}:{}", a, b, c, d, self.port)
+    }
+}
+
+/// Parse an IPv4 address in dotted decimal notation
+pub fn parse_addr(s: &str) -> Result<[u8; 4], &'static str> {
+    let mut addr = [0; 4];
+    let mut parts = s.split('.');
+    for byte in &mut addr {
+        *byte = parts
+            .next()
+            .and_then(|part| part.parse().ok())
+            .ok_or("Invalid IPv4 address")?;
+    }
+    match parts.next() {
+        Some(_) => Err("Invalid IPv4 address"),
+        None => Ok(addr),
+    }
+}
+
+/// Internet checksum of `bytes`, which has an even length
+fn checksum(bytes: &[u8]) -> u16 {
+    let mut sum = bytes
+        .chunks_exact(2)
+        .map(|word| u16::from_be_bytes([word[0], word[1]]) as u32)
+        .sum::<u32>();
+    while sum > 0xffff {
+        sum = (sum & 0xffff) + (sum >> 16);
+    }
+    !(sum as u16)
+}
+
+/// Write the Ethernet, IPv4 and UDP headers of a datagram with a payload of
+/// `len` bytes to the start of `frame`
+fn headers(frame: &mut [u8], mac: [u8; 6], source: Endpoint, destination: Endpoint, len: usize) {
+    let (ethernet, rest) = frame.split_at_mut(ETHERNET_SIZE);
+    ethernet[..6].copy_from_slice(&[0xff; 6]);
+    ethernet[6..12].copy_from_slice(&mac);
+    ethernet[12..].copy_from_slice(&ETHERTYPE_IPV4.to_be_bytes());
+
+    let (ip, udp) = rest.split_at_mut(IPV4_SIZE);
+    let ip_len = (IPV4_SIZE + UDP_SIZE + len) as u16;
+    let id = IDENTIFICATION.fetch_add(1, Ordering::Relaxed);
+    // Version 4 without options, don't fragment, time to live 64
+    ip[..2].copy_from_slice(&[0x45, 0]);
+    ip[2..4].copy_from_slice(&ip_len.to_be_bytes());
+    ip[4..6].copy_from_slice(&id.to_be_bytes());
+    ip[6..12].copy_from_slice(&[0x40, 0, 64, PROTOCOL_UDP, 0, 0]);
+    ip[12..16].copy_from_slice(&source.addr);
+    ip[16..20].copy_from_slice(&destination.addr);
+    let sum = checksum(ip);
+    ip[10..12].copy_from_slice(&sum.to_be_bytes());
+
+    // The UDP checksum is optional over IPv4 and left zero
+    udp[..2].copy_from_slice(&source.port.to_be_bytes());
+    udp[2..4].copy_from_slice(&destination.port.to_be_bytes());
+    udp[4..6].copy_from_slice(&((UDP_SIZE + len) as u16).to_be_bytes());
+    udp[6..8].copy_from_slice(&[0, 0]);
+}
+
+/// Send a UDP datagram holding `payload`, which is at most [`MAX_PAYLOAD`]
+/// bytes, through the network device
+///
+/// Fails if there is no network device, or if it is in use, e.g. by the code
+/// that was interrupted to send this datagram. Nothing is allocated, so this
+/// can be used while panicking.
+pub fn send_udp(
+    source: Endpoint,
+    destination: Endpoint,
+    payload: &[u8],
+) -> Result<(), &'static str> {
+    assert!(payload.len() <= MAX_PAYLOAD);
+    let mut device = DEVICE.try_lock().ok_or("Network device busy")?;
+    let device = device.as_mut().ok_or("No network device")?;
+    let mut frame = [0; MAX_FRAME];
+    headers(&mut frame, device.mac(), source, destination, payload.len());
+    let size = PAYLOAD_OFFSET + payload.len();
+    frame[PAYLOAD_OFFSET..size].copy_from_slice(payload);
+    device.send(&frame[..size]);
+    Ok(())
+}
+
+/// UDP datagram found in a frame by [`parse_udp`]
+#[cfg(test)]
+#[derive(Debug, PartialEq, Eq)]
+pub struct Datagram<'a> {
+    pub source: Endpoint,
+    pub destination: Endpoint,
+    pub payload: &'a [u8],
+}
+
+/// Find the UDP datagram in Ethernet `frame`, if it holds an intact,
+/// unfragmented one
+#[cfg(test)]
+pub fn parse_udp(frame: &[u8]) -> Option<Datagram<'_>> {
+    let ethertype = frame.get(12..ETHERNET_SIZE)?;
+    if u16::from_be_bytes([ethertype[0], ethertype[1]]) != ETHERTYPE_IPV4 {
+        return None;
+    }
+    let packet = &frame[ETHERNET_SIZE..];
+    let header_len = 4 * (*packet.first()? & 0xf) as usize;
+    if packet[0] >> 4 != 4 || header_len < IPV4_SIZE || packet.len() < header_len {
+        return None;
+    }
+    let ip = &packet[..header_len];
+    let total_len = u16::from_be_bytes([ip[2], ip[3]]) as usize;
+    // More fragments flag or fragment offset
+    let fragmented = u16::from_be_bytes([ip[6], ip[7]]) & 0x3fff != 0;
+    if checksum(ip) != 0 || ip[9] != PROTOCOL_UDP || fragmented {
+        return None;
+    }
+    let udp = packet.get(header_len..total_len)?;
+    let udp_len = u16::from_be_bytes([*udp.get(4)?, *udp.get(5)?]) as usize;
+    let endpoint = |addr: &[u8], port: &[u8]| Endpoint {
+        addr: [addr[0], addr[1], addr[2], addr[3]],
+        port: u16::from_be_bytes([port[0], port[1]]),
+    };
+    Some(Datagram {
+        source: endpoint(&ip[12..16], &udp[..2]),
+        destination: endpoint(&ip[16..20], &udp[2..4]),
+        payload: udp.get(UDP_SIZE..udp_len)?,
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{loopback::Loopback, *};
+
+    const SOURCE: Endpoint = Endpoint {
+        addr: [127, 0, 0, 1],
+        port: 1234,
+    };
+    const DESTINATION: Endpoint = Endpoint {
+        addr: [127, 0, 0, 1],
+        port: 5140,
+    };
+
+    /// Run `f` with a loopback device as the network device
+    fn with_loopback<F: FnOnce()>(f: F) {
+        let previous = set_device(Some(Box::new(Loopback::new())));
+        f();
+        set_device(previous);
+    }
+
+    #[test_case]
+    fn parse_endpoint() {
+        let endpoint = "10.0.2.2:5140".parse::<Endpoint>().unwrap();
+        assert_eq!(endpoint.addr, [10, 0, 2, 2]);
+        assert_eq!(endpoint.port, 5140);
+        assert!("10.0.2:5140".parse::<Endpoint>().is_err());
+        assert!("10.0.2.2.1:5140".parse::<Endpoint>().is_err());
+        assert!("10.0.2.256:5140".parse::<Endpoint>().is_err());
+        assert!("10.0.2.2".parse::<Endpoint>().is_err());
+    }
+
+    #[test_case]
+    fn ipv4_checksum() {
+        let mut header = [
+            0x45, 0x00, 0x00, 0x73, 0x00, 0x00, 0x40, 0x00, 0x40, 0x11, 0x00, 0x00, 0xc0, 0xa8,
+            0x00, 0x01, 0xc0, 0xa8, 0x00, 0xc7,
+        ];
+        assert_eq!(checksum(&header), 0xb861);
+        header[10..12].copy_from_slice(&0xb861u16.to_be_bytes());
+        assert_eq!(checksum(&header), 0);
+    }
+
+    #[test_case]
+    fn udp_over_loopback() {
+        with_loopback(|| {
+            send_udp(SOURCE, DESTINATION, b"first").unwrap();
+            send_udp(DESTINATION, SOURCE, b"").unwrap();
+            let mut buf = [0; MAX_FRAME];
+            let len = receive(&mut buf).unwrap();
+            assert_eq!(
+                parse_udp(&buf[..len]),
+                Some(Datagram {
+                    source: SOURCE,
+                    destination: DESTINATION,
+                    payload: b"first",
+                })
+            );
+            let len = receive(&mut buf).unwrap();
+            let datagram = parse_udp(&buf[..len]).unwrap();
+            assert_eq!(datagram.destination, SOURCE);
+            assert!(datagram.payload.is_empty());
+            assert!(receive(&mut buf).is_none());
+        });
+    }
+
+    #[test_case]
+    fn parse_rejects_damaged() {
+        with_loopback(|| {
+            send_udp(SOURCE, DESTINATION, b"payload").unwrap();
+            let mut buf = [0; MAX_FRAME];
+            let len = receive(&mut buf).unwrap();
+            assert!(parse_udp(&buf[..len]).is_some());
+            assert!(parse_udp(&buf[..len - 1]).is_none());
+            assert!(parse_udp(&buf[..ETHERNET_SIZE + 10]).is_none());
+            // Changing the address breaks the header checksum
+            buf[ETHERNET_SIZE + 19] ^= 1;
+            assert!(parse_udp(&buf[..len]).is_none());
+            buf[ETHERNET_SIZE + 19] ^= 1;
+            buf[12] = 0x86;
+            assert!(parse_udp(&buf[..len]).is_none());
+        });
+    }
+
+    #[test_case]
+    fn send_without_device() {
+        let previous = set_device(None);
+        assert!(send_udp(SOURCE, DESTINATION, b"lost").is_err());
+        set_device(previous);
+    }
+}
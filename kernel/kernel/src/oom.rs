@@ -0,0 +1,58 @@
+//! Handling of kernel heap exhaustion
+//!
+//! When an allocation fails, the heap is first grown into its reserve and then
+//! caches are reclaimed, after which the allocation is retried. Only if both
+//! fail the allocation fails as well, which panics in the allocation error
+//! handler. Each step is logged so the decision path ends up in crash dumps.
+//!
+//! Two things are not done. The reserve is not mapped lazily: it is mapped to
+//! frames at boot, so growing saves no physical memory (see
+//! [`allocator::HEAP_GROWTH_STEPS`]). No user process is killed either: they
+//! run to completion one at a time and do not own memory on the kernel heap,
+//! so killing one could not satisfy a failed allocation.
+
+use crate::{allocator, framebuffer};
+use core::{
+    alloc::Layout,
+    sync::atomic::{AtomicBool, Ordering},
+};
+
+/// Caches that can be dropped under memory pressure, each returning the number
+/// of bytes it freed
+const RECLAIMERS: &[(&str, fn() -> usize)] = &[("frame buffer grants", framebuffer::reclaim)];
+
+/// Whether a failed allocation is being handled, as reclaiming may allocate
+static HANDLING: AtomicBool = AtomicBool::new(false);
+
+/// Try to make memory available after an allocation of `layout` failed
+///
+/// Returns whether the allocation should be retried.
+pub fn handle(layout: Layout) -> bool {
+    if HANDLING.swap(true, Ordering::Acquire) {
+        return false;
+    }
+    let retry = free_memory(layout);
+    HANDLING.store(false, Ordering::Release);
+    retry
+}
+
+fn free_memory(layout: Layout) -> bool {
+    log::warn!("Kernel heap exhausted allocating {} bytes", layout.size());
+    if let Some(size) = allocator::grow() {
+        log::warn!("Grew kernel heap to {} KiB", size / 1024);
+        return true;
+    }
+    log::warn!("Kernel heap reserve exhausted, reclaiming caches");
+    let mut freed = 0;
+    for (name, reclaim) in RECLAIMERS {
+        let bytes = reclaim();
+        if bytes != 0 {
+            log::warn!("Reclaimed {} bytes from {}", bytes, name);
+            freed += bytes;
+        }
+    }
+    if freed == 0 {
+        log::error!("Nothing to reclaim, failing allocation");
+    }
+    freed != 0
+}
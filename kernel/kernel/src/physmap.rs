@@ -0,0 +1,139 @@
+//! Hiding the offset mapping of physical memory from user processes
+//!
+//! When enabled with the `hide-physmap` option, processes run on a copy of the
+//! kernel page table without the offset mapping of physical memory, so
+//! speculative reads from user mode cannot reach arbitrary memory through it.
+//! This is not kernel page table isolation: the kernel image, heap and stacks
+//! share the first top-level entry with user space, so they stay mapped (but
+//! not user accessible) while a process runs, and speculative reads of kernel
+//! data there are not prevented.
+//!
+//! System call entry and exit switch page tables in assembly, and interrupt
+//! handlers switch to the kernel page table with [`KernelPageTable`] for as
+//! long as they run. An indirect branch prediction barrier is issued when
+//! switching between processes and the kernel, if the CPU supports it.
+//!
+//! With 5-level paging, processes get a level 5 page table of their own as well
+//! that refers to the copy.
+//!
+//! The option exists to measure the cost of switching page tables; without a
+//! process context identifier every switch flushes the TLB.
+
+use crate::config;
+use common::{boot::offset, paging};
+use core::{
+    arch::x86_64::__cpuid_count,
+    sync::atomic::{AtomicBool, AtomicU64, Ordering},
+};
+use spin::Once;
+use x86_64::{
+    registers::{
+        control::{Cr3, Cr3Flags},
+        model_specific::Msr,
+    },
+    structures::paging::{FrameAllocator, PageTable, PhysFrame, Size4KiB},
+    PhysAddr,
+};
+
+/// Prediction command register, which issues a barrier when written
+const IA32_PRED_CMD: u32 = 0x49;
+
+/// Physical address of the kernel page table, or zero if the offset mapping is
+/// not hidden
+///
+/// Read by the system call entry in [`crate::threads`].
+pub static KERNEL_CR3: AtomicU64 = AtomicU64::new(0);
+
+/// Level 4 page table processes run on, and the level 5 page table referring
+/// to it if 5-level paging is enabled
+static USER_TABLES: Once<(PhysFrame, Option<PhysFrame>)> = Once::new();
+
+/// Whether the indirect branch prediction barrier is supported
+static IBPB: AtomicBool = AtomicBool::new(false);
+
+/// Set up the page table for processes if the offset mapping is hidden
+pub fn init<A>(all: &mut A) -> Result<(), &'static str>
+where
+    A: FrameAllocator<Size4KiB>,
+{
+    if !config::hide_physmap() {
+        return Ok(());
+    }
+    let mut allocate = || all.allocate_frame().ok_or("No frame allocated");
+    let pml4 = allocate()?;
+    let mut pml5 = None;
+    if paging::la57_enabled() {
+        let frame = allocate()?;
+        let table = offset::phys_to_virt(frame.start_address()).as_mut_ptr::<PageTable>();
+        paging::wrap_level_4_table(unsafe { &mut *table }, pml4);
+        pml5 = Some(frame);
+    }
+    USER_TABLES.call_once(|| (pml4, pml5));
+    let ibpb = unsafe { __cpuid_count(7, 0) }.edx & (1 << 26) != 0;
+    IBPB.store(ibpb, Ordering::Relaxed);
+    KERNEL_CR3.store(Cr3::read().0.start_address().as_u64(), Ordering::Relaxed);
+    log::info!(
+        "Offset mapping hidden from processes, branch prediction barrier {}",
+        if ibpb { "supported" } else { "unsupported" }
+    );
+    Ok(())
+}
+
+/// Value to load into CR3 when running a process, or zero if the offset
+/// mapping is not hidden
+///
+/// The page table is refreshed from the kernel page table, so top-level
+/// entries added since the last call are included.
+pub fn user_cr3() -> u64 {
+    let (pml4, pml5) = match USER_TABLES.get() {
+        Some(&tables) => tables,
+        None => return 0,
+    };
+    unsafe {
+        let kernel = paging::active_level_4_table(offset::VIRT_ADDR).start_address();
+        let kernel = &*offset::phys_to_virt(kernel).as_ptr::<PageTable>();
+        let user = &mut *offset::phys_to_virt(pml4.start_address()).as_mut_ptr::<PageTable>();
+        *user = kernel.clone();
+        let entries =
+            offset::PAGE_TABLE_INDEX..offset::PAGE_TABLE_INDEX + offset::PAGE_TABLE_ENTRIES;
+        for entry in entries {
+            user[entry].set_unused();
+        }
+    }
+    pml5.unwrap_or(pml4).start_address().as_u64()
+}
+
+/// Keep branch predictions made before a switch between a process and the
+/// kernel from affecting code after it
+pub fn barrier() {
+    if IBPB.load(Ordering::Relaxed) {
+        unsafe { Msr::new(IA32_PRED_CMD).write(1) };
+    }
+}
+
+/// Kernel page table loaded for as long as this lives, if a process page table
+/// was active
+pub struct KernelPageTable(Option<(PhysFrame, Cr3Flags)>);
+
+impl KernelPageTable {
+    pub fn enter() -> Self {
+        let kernel = KERNEL_CR3.load(Ordering::Relaxed);
+        if kernel == 0 {
+            return Self(None);
+        }
+        let (frame, flags) = Cr3::read();
+        if frame.start_address().as_u64() == kernel {
+            return Self(None);
+        }
+        unsafe { Cr3::write(PhysFrame::containing_address(PhysAddr::new(kernel)), flags) };
+        Self(Some((frame, flags)))
+    }
+}
+
+impl Drop for KernelPageTable {
+    fn drop(&mut self) {
+        if let Some((frame, flags)) = self.0 {
+            unsafe { Cr3::write(frame, flags) };
+        }
+    }
+}
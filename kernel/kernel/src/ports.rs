@@ -0,0 +1,150 @@
+//! Ownership of I/O ports
+//!
+//! A driver describes the ports of a device with a [`Ports`] range under its
+//! own name and claims it with [`Ports::claim`] before using it, which fails if
+//! another driver already claimed one of the ports. Ports are accessed by
+//! offset in the range, and accesses outside it are logged with the name of
+//! the driver instead of reaching whatever device is there.
+//!
+//! Claims are kept in a fixed table rather than on the heap, so interrupt
+//! controllers and timers can claim their ports early during boot.
+
+use core::{fmt, mem::size_of};
+use spin::Mutex;
+use x86_64::instructions::port::{Port, PortRead, PortWrite};
+
+/// Maximum number of claimed ranges
+const MAX_CLAIMS: usize = 32;
+
+static CLAIMS: Mutex<[Option<Ports>; MAX_CLAIMS]> = Mutex::new([None; MAX_CLAIMS]);
+
+/// Range of I/O ports used by a driver
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub struct Ports {
+    owner: &'static str,
+    start: u16,
+    count: u16,
+}
+
+impl fmt::Display for Ports {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let end = self.start + (self.count - 1);
+        write!(f, "ports {:#x}-{:#x} of {}", self.start, end, self.owner)
+    }
+}
+
+impl Ports {
+    /// `count` ports from `start`, used by driver `owner`
+    pub const fn new(owner: &'static str, start: u16, count: u16) -> Self {
+        Self {
+            owner,
+            start,
+            count,
+        }
+    }
+
+    fn overlaps(&self, other: &Self) -> bool {
+        let end = self.start as u32 + self.count as u32;
+        let other_end = other.start as u32 + other.count as u32;
+        (self.start as u32) < other_end && (other.start as u32) < end
+    }
+
+    /// Record that the owner uses these ports
+    ///
+    /// Claiming the same range again, e.g. when setting a device up again
+    /// after a sleep state, succeeds.
+    pub fn claim(&self) -> Result<(), &'static str> {
+        self.claim_in(&mut CLAIMS.lock()[..])
+    }
+
+    /// Record the claim in `claims`, see [`Ports::claim`]
+    fn claim_in(&self, claims: &mut [Option<Ports>]) -> Result<(), &'static str> {
+        let mut free = None;
+        for (i, claim) in claims.iter().enumerate() {
+            match claim {
+                Some(claim) if claim == self => return Ok(()),
+                Some(claim) if claim.overlaps(self) => {
+                    log::error!("{} cannot claim {}", self.owner, claim);
+                    return Err("I/O ports already claimed");
+                }
+                Some(_) => {}
+                None => free = free.or(Some(i)),
+            }
+        }
+        let free = free.ok_or("Too many I/O port claims")?;
+        claims[free] = Some(*self);
+        Ok(())
+    }
+
+    /// Port at `offset` if a `T` there lies in the range, otherwise log the
+    /// attempt to `access` it
+    fn port<T>(&self, offset: u16, access: fmt::Arguments) -> Option<Port<T>> {
+        if offset as usize + size_of::<T>() <= self.count as usize {
+            Some(Port::new(self.start + offset))
+        } else {
+            log::error!(
+                "{} tried to {} port {:#x} outside its {}",
+                self.owner,
+                access,
+                self.start as u32 + offset as u32,
+                self
+            );
+            None
+        }
+    }
+
+    /// Read the port at `offset`, or the default if it is out of range
+    ///
+    /// # Safety
+    /// Reading the port should not have unsafe side effects.
+    pub unsafe fn read<T: PortRead + Default>(&self, offset: u16) -> T {
+        self.port(offset, format_args!("read"))
+            .map_or_else(T::default, |mut port| port.read())
+    }
+
+    /// Write `value` to the port at `offset`, unless it is out of range
+    ///
+    /// # Safety
+    /// Writing the port should not have unsafe side effects.
+    pub unsafe fn write<T: PortWrite + fmt::LowerHex>(&self, offset: u16, value: T) {
+        if let Some(mut port) = self.port(offset, format_args!("write {:#x} to", value)) {
+            port.write(value);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test_case]
+    fn overlap() {
+        let ports = Ports::new("a", 0x60, 4);
+        assert!(ports.overlaps(&Ports::new("b", 0x63, 1)));
+        assert!(ports.overlaps(&Ports::new("b", 0x5f, 2)));
+        assert!(!ports.overlaps(&Ports::new("b", 0x64, 1)));
+        assert!(!ports.overlaps(&Ports::new("b", 0x5c, 4)));
+        assert!(Ports::new("a", 0xfffe, 2).overlaps(&Ports::new("b", 0xffff, 1)));
+    }
+
+    #[test_case]
+    fn claim_conflict() {
+        // A table of its own, as claims in the global one are permanent
+        let mut claims = [None; 2];
+        let ports = Ports::new("test", 0xff00, 4);
+        assert_eq!(ports.claim_in(&mut claims), Ok(()));
+        assert_eq!(ports.claim_in(&mut claims), Ok(()));
+        assert!(Ports::new("other", 0xff02, 4)
+            .claim_in(&mut claims)
+            .is_err());
+        assert!(Ports::new("test", 0xff02, 4).claim_in(&mut claims).is_err());
+        assert_eq!(Ports::new("other", 0xff04, 1).claim_in(&mut claims), Ok(()));
+        assert!(Ports::new("full", 0xff08, 1).claim_in(&mut claims).is_err());
+    }
+
+    #[test_case]
+    fn display() {
+        let ports = Ports::new("ps2", 0x60, 1);
+        assert_eq!(alloc::format!("{}", ports), "ports 0x60-0x60 of ps2");
+    }
+}
@@ -0,0 +1,130 @@
+//! Sampling profiler driven by the local APIC timer
+//!
+//! Every sample records the interrupted instruction pointer and the running
+//! process. Profiling is enabled with the `profile` option of the kernel
+//! configuration. Samples are printed over the serial port by [`dump`], which
+//! the xtask runner symbolizes for flame graphs.
+
+use crate::{base64::Encoder, config, interrupts, threads};
+use common::{boot::BootInfo, println};
+use core::{
+    mem,
+    sync::atomic::{AtomicU64, Ordering},
+};
+use spin::Mutex;
+use x86_64::{instructions::interrupts::without_interrupts, structures::idt::InterruptStackFrame};
+
+/// Line preceding the encoded dump
+pub const BEGIN: &str = "-----BEGIN PROFILE-----";
+/// Line following the encoded dump
+pub const END: &str = "-----END PROFILE-----";
+
+/// Start of a dump, identifying its format
+const MAGIC: &[u8; 4] = b"APRF";
+/// Version of the dump format
+const VERSION: u32 = 2;
+
+/// Number of samples per second
+const FREQUENCY: u32 = 1000;
+/// Maximum number of samples; later samples are dropped
+const CAPACITY: usize = 8192;
+
+static SAMPLES: Mutex<Samples> = Mutex::new(Samples::new());
+/// Offset the kernel is loaded at, to symbolize its instruction pointers
+static KERNEL_OFFSET: AtomicU64 = AtomicU64::new(0);
+
+#[derive(Copy, Clone)]
+struct Sample {
+    rip: u64,
+    /// Process identifier, or zero if no process was running
+    pid: u32,
+    /// Whether the process itself was running rather than the kernel
+    user: bool,
+}
+
+struct Samples {
+    samples: [Sample; CAPACITY],
+    len: usize,
+    dropped: u64,
+}
+
+impl Samples {
+    const fn new() -> Self {
+        const EMPTY: Sample = Sample {
+            rip: 0,
+            pid: 0,
+            user: false,
+        };
+        Self {
+            samples: [EMPTY; CAPACITY],
+            len: 0,
+            dropped: 0,
+        }
+    }
+}
+
+/// Start taking samples; the timer should be running for calibration
+pub fn init(boot_info: &BootInfo) {
+    if !config::profile() {
+        return;
+    }
+    KERNEL_OFFSET.store(boot_info.kernel_offset, Ordering::Relaxed);
+    interrupts::start_apic_timer(FREQUENCY);
+    log::info!("Profiling at {} Hz", FREQUENCY);
+}
+
+/// Record a sample of the code interrupted by the local APIC timer
+pub fn sample(stack_frame: &InterruptStackFrame) {
+    if !config::profile() {
+        return;
+    }
+    let mut samples = SAMPLES.lock();
+    if samples.len == CAPACITY {
+        samples.dropped += 1;
+        return;
+    }
+    let i = samples.len;
+    samples.samples[i] = Sample {
+        rip: stack_frame.instruction_pointer.as_u64(),
+        pid: threads::current_pid().unwrap_or(0) as u32,
+        // Requested privilege level of the code segment
+        user: stack_frame.code_segment & 0b11 == 3,
+    };
+    samples.len += 1;
+}
+
+/// Move samples to the serial port
+///
+/// The dump starts with [`MAGIC`] and [`VERSION`] as `u32` and the offset
+/// the kernel is loaded at as `u64`, followed by samples consisting of the instruction pointer as `u64`, the process
+/// identifier as `u32` and whether the process itself was running as `u32`;
+/// all in little endian. It is encoded as base64 and printed between [`BEGIN`]
+/// and [`END`] marker lines. Samples taken while printing are discarded.
+pub fn dump() {
+    if !config::profile() {
+        return;
+    }
+    println!("{}", BEGIN);
+    let mut encoder = Encoder::new();
+    encoder.extend(MAGIC);
+    encoder.extend(&VERSION.to_le_bytes());
+    encoder.extend(&KERNEL_OFFSET.load(Ordering::Relaxed).to_le_bytes());
+    let len = without_interrupts(|| SAMPLES.lock().len);
+    for i in 0..len {
+        // Lock per sample to not block the sampling interrupt while printing
+        let sample = without_interrupts(|| SAMPLES.lock().samples[i]);
+        encoder.extend(&sample.rip.to_le_bytes());
+        encoder.extend(&sample.pid.to_le_bytes());
+        encoder.extend(&(sample.user as u32).to_le_bytes());
+    }
+    encoder.finish();
+    println!("{}", END);
+    let dropped = without_interrupts(|| {
+        let mut samples = SAMPLES.lock();
+        samples.len = 0;
+        mem::take(&mut samples.dropped)
+    });
+    if dropped > 0 {
+        log::warn!("Dropped {} samples as the buffer was full", dropped);
+    }
+}
@@ -0,0 +1,41 @@
+//! User programs embedded in the kernel
+//!
+//! Until there is a file system to load programs from, xtask builds the init
+//! program and the other programs listed in the build configuration and
+//! generates a table of them by name, which is linked into the kernel.
+
+use common::{elf::ElfInfo, error::BootError};
+
+/// Table generated by xtask
+mod table {
+    include!(concat!(env!("XTASK_OUT_DIR"), "/programs.rs"));
+}
+
+/// Name of the first program to run
+pub use table::INIT;
+
+/// Parsed ELF of the program called `name`
+pub fn get(name: &str) -> Result<ElfInfo<'static>, &'static str> {
+    let (_, elf) = table::PROGRAMS
+        .iter()
+        .find(|(program, _)| *program == name)
+        .ok_or("No such program")?;
+    elf.info(true).map_err(BootError::logged)
+}
+
+/// Names of all embedded programs
+pub fn names() -> impl Iterator<Item = &'static str> {
+    table::PROGRAMS.iter().map(|(name, _)| *name)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test_case]
+    fn lookup() {
+        assert!(names().any(|name| name == INIT));
+        assert!(get(INIT).is_ok());
+        assert!(get("").is_err());
+    }
+}
@@ -0,0 +1,180 @@
+//! Tracing of user processes
+//!
+//! A [`Tracer`] attached to a process by [`threads::spawn_traced`] sees each of
+//! its system calls before it is handled, and its fault before it is
+//! terminated. Through the [`Tracee`] it can read and write the memory of the
+//! process and the registers restored when it continues, change the arguments
+//! of the system call, skip it or kill the process.
+//!
+//! Only one process runs at a time and processes spawned by another only run
+//! once it has exited, so tracers run in the kernel rather than in a parent
+//! process. For the same reason a fault still terminates the process, and
+//! single-stepping is not supported. The `strace` option of the kernel
+//! configuration attaches [`Strace`] to every process.
+//!
+//! [`threads::spawn_traced`]: crate::threads::spawn_traced
+
+use crate::{fault::Report, signal::Context, user::UserSlice, Init};
+
+/// Number of bytes of code at the faulting instruction logged by [`Strace`]
+const CODE_BYTES: usize = 16;
+
+/// System call about to be handled
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub struct Syscall {
+    pub code: u64,
+    pub rsi: u64,
+    pub rdx: u64,
+}
+
+/// What to do with a system call after the tracer has seen it
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub enum Action {
+    /// Handle the system call
+    Continue,
+    /// Return to the process right away with the return code in the context
+    Skip,
+    /// Terminate the process
+    Kill,
+}
+
+/// Traced process, stopped while the tracer runs
+pub struct Tracee<'a> {
+    pid: u64,
+    context: &'a mut Context,
+    init: &'a mut Init,
+}
+
+impl<'a> Tracee<'a> {
+    pub fn new(pid: u64, context: &'a mut Context, init: &'a mut Init) -> Self {
+        Self { pid, context, init }
+    }
+
+    pub fn pid(&self) -> u64 {
+        self.pid
+    }
+
+    /// Registers restored when the process continues
+    ///
+    /// Registers not in the context are not preserved across system calls.
+    pub fn context(&mut self) -> &mut Context {
+        self.context
+    }
+
+    /// Copy memory of the process at `addr` to `buf`
+    pub fn read_memory(&mut self, addr: u64, buf: &mut [u8]) -> Result<(), &'static str> {
+        let memory = UserSlice::new(addr, buf.len());
+        buf.copy_from_slice(memory.get(self.init, self.pid)?);
+        Ok(())
+    }
+
+    /// Copy `data` to memory of the process at `addr`
+    ///
+    /// Only memory the process can write itself is written.
+    pub fn write_memory(&mut self, addr: u64, data: &[u8]) -> Result<(), &'static str> {
+        let mut memory = UserSlice::new(addr, data.len());
+        memory.get_mut(self.init, self.pid)?.copy_from_slice(data);
+        Ok(())
+    }
+}
+
+/// Observer of a traced process
+pub trait Tracer {
+    /// Called before `syscall` is handled; changes to it are handled instead
+    fn syscall(&mut self, _tracee: &mut Tracee, _syscall: &mut Syscall) -> Action {
+        Action::Continue
+    }
+
+    /// Called when the process faulted, before it is terminated
+    fn fault(&mut self, _tracee: &mut Tracee, _report: &Report) {}
+}
+
+/// Tracer logging system calls and the code at faults
+pub struct Strace;
+
+impl Tracer for Strace {
+    fn syscall(&mut self, tracee: &mut Tracee, syscall: &mut Syscall) -> Action {
+        log::info!(
+            "Process {} syscall {}({:#x}, {:#x})",
+            tracee.pid(),
+            syscall.code,
+            syscall.rsi,
+            syscall.rdx
+        );
+        Action::Continue
+    }
+
+    fn fault(&mut self, tracee: &mut Tracee, report: &Report) {
+        let mut code = [0; CODE_BYTES];
+        match tracee.read_memory(report.fault.rip, &mut code) {
+            Ok(()) => log::info!("Code at {:#x}: {:02x?}", report.fault.rip, code),
+            Err(e) => log::info!("Code at {:#x} not readable: {}", report.fault.rip, e),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{
+        handle::HandleTable,
+        programs,
+        threads::{self, Limits},
+    };
+    use alloc::{string::String, vec::Vec};
+    use sys::SyscallCode;
+
+    /// Tracer recording system calls and the messages logged
+    #[derive(Default)]
+    struct Recorder {
+        codes: Vec<u64>,
+        messages: Vec<String>,
+        kill_after: Option<usize>,
+    }
+
+    impl Tracer for Recorder {
+        fn syscall(&mut self, tracee: &mut Tracee, syscall: &mut Syscall) -> Action {
+            self.codes.push(syscall.code);
+            if syscall.code == SyscallCode::Log as u64 {
+                let mut message = alloc::vec![0; syscall.rdx as usize];
+                tracee.read_memory(syscall.rsi, &mut message).unwrap();
+                self.messages.push(String::from_utf8(message).unwrap());
+            }
+            match self.kill_after {
+                Some(count) if self.codes.len() >= count => Action::Kill,
+                _ => Action::Continue,
+            }
+        }
+    }
+
+    fn spawn(recorder: &mut Recorder) {
+        let mut guard = crate::test::INIT.lock();
+        let init = guard.as_mut().unwrap();
+        let handles = HandleTable::devices();
+        let tracer = Some(recorder as &mut dyn Tracer);
+        unsafe { threads::spawn_traced(init, programs::INIT, Limits::default(), handles, tracer) }
+            .unwrap();
+    }
+
+    #[test_case]
+    fn record() {
+        let mut recorder = Recorder::default();
+        spawn(&mut recorder);
+        assert_eq!(recorder.codes.last(), Some(&(SyscallCode::Exit as u64)));
+        let logs = recorder
+            .codes
+            .iter()
+            .filter(|&&code| code == SyscallCode::Log as u64);
+        assert_eq!(logs.count(), recorder.messages.len());
+    }
+
+    #[test_case]
+    fn kill() {
+        let mut recorder = Recorder {
+            kill_after: Some(1),
+            ..Default::default()
+        };
+        spawn(&mut recorder);
+        assert_eq!(recorder.codes.len(), 1);
+    }
+}
@@ -0,0 +1,143 @@
+//! Log messages and panic reports sent over UDP
+//!
+//! If the `remote-log` option of the kernel configuration is set to an IPv4
+//! address and port, every log message is sent as a UDP datagram through the
+//! network device (see [`crate::net`]) from the address in
+//! `remote-log-source`. Frames go to the Ethernet broadcast address, which
+//! QEMU's user network and host bridges deliver like any other frame. `cargo
+//! xtask remote-log` receives and verifies the records.
+//!
+//! A datagram holds a single record: [`MAGIC`], a big-endian sequence number,
+//! a [`Kind`] byte and the message, which is truncated to fit, followed by the
+//! HMAC-SHA256 tag of all of these, keyed with the `remote-log-key` of the build
+//! configuration. The sequence number starts at zero on boot and counts records
+//! that could not be sent as well, so gaps show lost records.
+
+use crate::{
+    config,
+    hmac::{Hmac, TAG_SIZE},
+    net::{self, Endpoint, MAX_PAYLOAD},
+};
+use common::logger;
+use core::{
+    fmt::{self, Arguments, Write},
+    panic::PanicInfo,
+    sync::atomic::{AtomicU64, Ordering},
+};
+use x86_64::instructions::interrupts;
+
+/// Start of a record, identifying its format
+const MAGIC: &[u8; 4] = b"ARLG";
+/// Size of the magic, sequence number and kind of a record
+const HEADER_SIZE: usize = 13;
+/// Maximum length of a message in bytes
+const MESSAGE_SIZE: usize = MAX_PAYLOAD - HEADER_SIZE - TAG_SIZE;
+
+/// Kind of message in a record
+#[derive(Copy, Clone)]
+#[repr(u8)]
+enum Kind {
+    Log = 1,
+    Panic = 2,
+}
+
+/// Sequence number of the next record
+static SEQUENCE: AtomicU64 = AtomicU64::new(0);
+
+/// Buffer of fixed size, truncating what is written to it
+struct Message {
+    buf: [u8; MESSAGE_SIZE],
+    len: usize,
+}
+
+impl Write for Message {
+    fn write_str(&mut self, s: &str) -> fmt::Result {
+        let len = s.len().min(MESSAGE_SIZE - self.len);
+        self.buf[self.len..self.len + len].copy_from_slice(&s.as_bytes()[..len]);
+        self.len += len;
+        Ok(())
+    }
+}
+
+/// Write the record of `kind` holding `message` to `buf`, returning its size
+fn record(buf: &mut [u8; MAX_PAYLOAD], sequence: u64, kind: Kind, message: &[u8]) -> usize {
+    buf[..4].copy_from_slice(MAGIC);
+    buf[4..12].copy_from_slice(&sequence.to_be_bytes());
+    buf[12] = kind as u8;
+    let len = HEADER_SIZE + message.len();
+    buf[HEADER_SIZE..len].copy_from_slice(message);
+    let mut hmac = Hmac::new(config::REMOTE_LOG_KEY.as_bytes());
+    hmac.update(&buf[..len]);
+    buf[len..len + TAG_SIZE].copy_from_slice(&hmac.finish());
+    len + TAG_SIZE
+}
+
+/// Send a record of `kind` holding `message`
+fn send(kind: Kind, message: &[u8]) {
+    let destination = match config::remote_log() {
+        Some(destination) => destination,
+        None => return,
+    };
+    let source = Endpoint {
+        addr: config::remote_log_source(),
+        port: destination.port,
+    };
+    let sequence = SEQUENCE.fetch_add(1, Ordering::Relaxed);
+    let mut buf = [0; MAX_PAYLOAD];
+    let len = record(&mut buf, sequence, kind, message);
+    // Nothing can be logged about a failure without sending another record
+    let _ = net::send_udp(source, destination, &buf[..len]);
+}
+
+/// Send a record of `kind` holding `args`, formatted and truncated to fit
+fn send_fmt(kind: Kind, args: Arguments) {
+    let mut message = Message {
+        buf: [0; MESSAGE_SIZE],
+        len: 0,
+    };
+    let _ = message.write_fmt(args);
+    interrupts::without_interrupts(|| send(kind, &message.buf[..message.len]));
+}
+
+fn log(args: Arguments) {
+    send_fmt(Kind::Log, args);
+}
+
+/// Whether the log should be sent over the network
+pub fn enabled() -> bool {
+    config::remote_log().is_some()
+}
+
+/// Send log messages from now on, if enabled; called once the network device
+/// is set up
+pub fn start() {
+    if let Some(destination) = config::remote_log() {
+        logger::set_remote(log);
+        log::info!("Sending the log to {}", destination);
+        if config::REMOTE_LOG_KEY.is_empty() {
+            log::warn!("The remote log key is empty, so records can be forged");
+        }
+    }
+}
+
+/// Send a report of the panic described by `info`, if enabled
+pub fn panic(info: &PanicInfo) {
+    send_fmt(Kind::Panic, format_args!("{}", info));
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test_case]
+    fn record_layout() {
+        let mut buf = [0; MAX_PAYLOAD];
+        let len = record(&mut buf, 0x0102, Kind::Panic, b"oops");
+        assert_eq!(len, HEADER_SIZE + 4 + TAG_SIZE);
+        assert_eq!(&buf[..HEADER_SIZE], b"ARLG\0\0\0\0\0\0\x01\x02\x02");
+        assert_eq!(&buf[HEADER_SIZE..HEADER_SIZE + 4], b"oops");
+        let mut hmac = Hmac::new(config::REMOTE_LOG_KEY.as_bytes());
+        hmac.update(&buf[..HEADER_SIZE + 4]);
+        assert_eq!(&buf[HEADER_SIZE + 4..len], &hmac.finish());
+    }
+}
@@ -0,0 +1,70 @@
+//! Orderly shutdown
+//!
+//! A process requests a shutdown with [`SyscallCode::Shutdown`], or the power
+//! button does through the ACPI system control interrupt. The running
+//! process is sent [`Signal::Terminate`] and killed once [`GRACE_PERIOD`] has
+//! passed, by the system call loop or, if it makes no system calls, by the
+//! timer interrupt. Once it is gone [`run`] stops the devices and powers off.
+//!
+//! There are no file systems or block caches to flush yet. Power off uses the
+//! soft off state described by the ACPI tables; without them it assumes the
+//! PM1a control register is at the port OVMF sets up on QEMU, and elsewhere
+//! the system just halts.
+//!
+//! [`SyscallCode::Shutdown`]: sys::SyscallCode::Shutdown
+//! [`Signal::Terminate`]: sys::Signal::Terminate
+
+use crate::{acpi, devices, interrupts};
+use spin::Once;
+use x86_64::instructions::{self, port::Port};
+
+/// Time processes get to exit after a shutdown is requested, in milliseconds
+const GRACE_PERIOD: u64 = 2000;
+
+/// PM1a control register of the ACPI power management block set up by OVMF
+const PM1A_CONTROL: u16 = 0x604;
+/// Sleep enable bit and sleep type of the S5 (soft off) state on QEMU
+const SLEEP_S5: u16 = 1 << 13;
+
+/// Time after which processes are killed, in milliseconds since boot; set
+/// once a shutdown is requested
+static DEADLINE: Once<u64> = Once::new();
+
+/// Milliseconds since boot
+fn now() -> u64 {
+    interrupts::ticks() * 1000 / interrupts::TIMER_FREQUENCY as u64
+}
+
+/// Request a shutdown
+pub fn request() {
+    DEADLINE.call_once(|| {
+        log::info!("Shutdown requested");
+        now() + GRACE_PERIOD
+    });
+}
+
+/// Whether a shutdown was requested
+pub fn requested() -> bool {
+    DEADLINE.get().is_some()
+}
+
+/// Whether processes had their chance to exit after a shutdown request
+pub fn expired() -> bool {
+    DEADLINE.get().map_or(false, |&deadline| now() >= deadline)
+}
+
+/// Stop all devices and power off
+///
+/// Should only be called when no process runs anymore.
+pub fn run() -> ! {
+    log::info!("Stopping devices");
+    devices::quiesce();
+    instructions::interrupts::disable();
+    log::info!("Powering off");
+    acpi::power_off();
+    unsafe { Port::<u16>::new(PM1A_CONTROL).write(SLEEP_S5) };
+    log::warn!("Power off failed, halting");
+    loop {
+        instructions::hlt();
+    }
+}
@@ -0,0 +1,134 @@
+//! Signals sent to processes
+//!
+//! Processes only run until their next system call, so signals are delivered
+//! when returning from one. A signal that can be handled interrupts the process
+//! by switching to its handler on the stack it registered; the handler returns
+//! to the interrupted code with [`SyscallCode::SignalReturn`]. Handlers do not
+//! nest, so signals arriving meanwhile wait for the running handler to return.
+//!
+//! [`SyscallCode::SignalReturn`]: sys::SyscallCode::SignalReturn
+
+use crate::vm::USER_END;
+use sys::Signal;
+
+/// User registers restored when returning from a system call
+#[derive(Copy, Clone, Debug)]
+pub struct Context {
+    pub rip: u64,
+    pub rsp: u64,
+    pub rax: u64,
+}
+
+#[derive(Copy, Clone, Debug)]
+struct Handler {
+    entry: u64,
+    /// Top of the stack the handler runs on
+    stack: u64,
+}
+
+/// Signal state of a process
+#[derive(Debug, Default)]
+pub struct Signals {
+    handler: Option<Handler>,
+    pending: Option<Signal>,
+    /// Context interrupted by the running handler
+    interrupted: Option<Context>,
+}
+
+impl Signals {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Handle signals at `entry` on the stack ending at `stack`, or terminate
+    /// on them if `entry` is zero
+    ///
+    /// Both have to lie in user memory: `sysretq` faults in the kernel when
+    /// returning to a non-canonical address.
+    pub fn set_handler(&mut self, entry: u64, stack: u64) -> Result<(), &'static str> {
+        self.handler = match entry {
+            0 => None,
+            _ if entry >= USER_END => return Err("Handler outside of user memory"),
+            _ if stack < 16 || stack > USER_END => return Err("Stack outside of user memory"),
+            _ => Some(Handler { entry, stack }),
+        };
+        Ok(())
+    }
+
+    /// Send `signal` to the process
+    ///
+    /// Returns whether the process should be terminated right away.
+    pub fn send(&mut self, signal: Signal) -> bool {
+        match (signal, self.handler) {
+            (Signal::Terminate, Some(_)) => {
+                self.pending = Some(signal);
+                false
+            }
+            _ => true,
+        }
+    }
+
+    /// Switch `context` to the handler of a pending signal, if any
+    ///
+    /// Returns the argument to pass to the handler.
+    pub fn deliver(&mut self, context: &mut Context) -> Option<u64> {
+        if self.interrupted.is_some() {
+            return None;
+        }
+        let signal = self.pending.take()?;
+        let handler = self.handler?;
+        log::debug!("Delivering {:?} to handler at {:#x}", signal, handler.entry);
+        self.interrupted = Some(*context);
+        *context = Context {
+            rip: handler.entry,
+            // Aligned as if the handler was called
+            rsp: (handler.stack & !0xf) - 8,
+            rax: 0,
+        };
+        Some(signal as u64)
+    }
+
+    /// Restore `context` interrupted by the running handler
+    ///
+    /// Returns `false` if no handler is running.
+    pub fn restore(&mut self, context: &mut Context) -> bool {
+        match self.interrupted.take() {
+            Some(interrupted) => {
+                *context = interrupted;
+                true
+            }
+            None => false,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test_case]
+    fn handled_signal_round_trip() {
+        let mut signals = Signals::new();
+        assert!(signals.send(Signal::Terminate));
+        assert!(signals.set_handler(0x8000_0000_0000_1000, 0x3008).is_err());
+        assert!(signals.set_handler(0x1000, 0xffff_8000_0000_3008).is_err());
+        signals.set_handler(0x1000, 0x3008).unwrap();
+        assert!(signals.send(Signal::Kill));
+        assert!(!signals.send(Signal::Terminate));
+        let mut context = Context {
+            rip: 0x2000,
+            rsp: 0x5000,
+            rax: 7,
+        };
+        assert_eq!(
+            signals.deliver(&mut context),
+            Some(Signal::Terminate as u64)
+        );
+        assert_eq!((context.rip, context.rsp), (0x1000, 0x2ff8));
+        assert!(!signals.send(Signal::Terminate));
+        assert_eq!(signals.deliver(&mut context), None);
+        assert!(signals.restore(&mut context));
+        assert_eq!((context.rip, context.rsp, context.rax), (0x2000, 0x5000, 7));
+        assert!(!signals.restore(&mut context));
+    }
+}
@@ -0,0 +1,294 @@
+//! Hardware identification from the SMBIOS tables
+//!
+//! The bootloader passes the address of the SMBIOS entry point, which locates
+//! the structure table. The BIOS, system, baseboard and memory device
+//! structures are summarized at boot, so logs from real machines show what
+//! they ran on.
+
+use crate::Init;
+use alloc::{
+    string::{String, ToString},
+    vec::Vec,
+};
+use common::boot::offset;
+use core::{fmt, slice};
+use x86_64::PhysAddr;
+
+/// Structure types
+mod ty {
+    pub const BIOS: u8 = 0;
+    pub const SYSTEM: u8 = 1;
+    pub const BASEBOARD: u8 = 2;
+    pub const MEMORY_DEVICE: u8 = 17;
+    pub const END: u8 = 127;
+}
+
+/// Structure in the table
+struct Structure<'a> {
+    ty: u8,
+    /// Formatted area, including the header
+    formatted: &'a [u8],
+    /// Strings following the formatted area
+    strings: &'a [u8],
+}
+
+impl<'a> Structure<'a> {
+    fn byte(&self, offset: usize) -> Option<u8> {
+        self.formatted.get(offset).copied()
+    }
+
+    fn word(&self, offset: usize) -> Option<u16> {
+        let bytes = self.formatted.get(offset..offset + 2)?;
+        Some(u16::from_le_bytes([bytes[0], bytes[1]]))
+    }
+
+    fn dword(&self, offset: usize) -> Option<u32> {
+        let bytes = self.formatted.get(offset..offset + 4)?;
+        Some(u32::from_le_bytes([bytes[0], bytes[1], bytes[2], bytes[3]]))
+    }
+
+    /// String referred to by the byte at `offset`
+    fn string(&self, offset: usize) -> Option<String> {
+        let index = self.byte(offset)? as usize;
+        if index == 0 {
+            return None;
+        }
+        let string = self.strings.split(|&b| b == 0).nth(index - 1)?;
+        let string = String::from_utf8_lossy(string);
+        let string = string.trim();
+        if string.is_empty() {
+            None
+        } else {
+            Some(string.to_string())
+        }
+    }
+}
+
+/// Iterator over the structures of a table
+struct Structures<'a>(&'a [u8]);
+
+impl<'a> Iterator for Structures<'a> {
+    type Item = Structure<'a>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        let table = self.0;
+        let length = *table.get(1)? as usize;
+        if length < 4 || table.len() < length {
+            return None;
+        }
+        let (formatted, rest) = table.split_at(length);
+        // Strings end with an empty string, which is doubled if there are none
+        let end = rest.windows(2).position(|w| w == [0, 0])?;
+        self.0 = &rest[end + 2..];
+        if formatted[0] == ty::END {
+            self.0 = &[];
+        }
+        Some(Structure {
+            ty: formatted[0],
+            formatted,
+            strings: &rest[..end],
+        })
+    }
+}
+
+#[derive(Clone, Debug, Default, PartialEq, Eq)]
+pub struct MemoryDevice {
+    pub locator: Option<String>,
+    pub size_mib: u64,
+    /// Speed in MT/s, if known
+    pub speed: Option<u16>,
+    pub manufacturer: Option<String>,
+}
+
+/// Summary of the hardware described by SMBIOS
+#[derive(Clone, Debug, Default, PartialEq, Eq)]
+pub struct Hardware {
+    pub version: (u8, u8),
+    pub bios_vendor: Option<String>,
+    pub bios_version: Option<String>,
+    pub bios_date: Option<String>,
+    pub system_manufacturer: Option<String>,
+    pub system_product: Option<String>,
+    pub system_version: Option<String>,
+    pub board_manufacturer: Option<String>,
+    pub board_product: Option<String>,
+    /// Populated memory slots
+    pub memory: Vec<MemoryDevice>,
+}
+
+impl Hardware {
+    /// Summarize the structures in `table`
+    fn parse(version: (u8, u8), table: &[u8]) -> Self {
+        let mut hardware = Self {
+            version,
+            ..Self::default()
+        };
+        for structure in Structures(table) {
+            match structure.ty {
+                ty::BIOS => {
+                    hardware.bios_vendor = structure.string(0x04);
+                    hardware.bios_version = structure.string(0x05);
+                    hardware.bios_date = structure.string(0x08);
+                }
+                ty::SYSTEM => {
+                    hardware.system_manufacturer = structure.string(0x04);
+                    hardware.system_product = structure.string(0x05);
+                    hardware.system_version = structure.string(0x06);
+                }
+                ty::BASEBOARD => {
+                    hardware.board_manufacturer = structure.string(0x04);
+                    hardware.board_product = structure.string(0x05);
+                }
+                ty::MEMORY_DEVICE => {
+                    let size_mib = match structure.word(0x0c) {
+                        None | Some(0) | Some(0xffff) => continue,
+                        Some(0x7fff) => match structure.dword(0x1c) {
+                            Some(size) => (size & 0x7fff_ffff) as u64,
+                            None => continue,
+                        },
+                        // Size in KiB
+                        Some(size) if size & 0x8000 != 0 => (size & 0x7fff) as u64 / 1024,
+                        Some(size) => size as u64,
+                    };
+                    hardware.memory.push(MemoryDevice {
+                        locator: structure.string(0x10),
+                        size_mib,
+                        speed: structure.word(0x15).filter(|&speed| speed != 0),
+                        manufacturer: structure.string(0x17),
+                    });
+                }
+                _ => {}
+            }
+        }
+        hardware
+    }
+}
+
+impl fmt::Display for Hardware {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let unknown = |s: &Option<String>| s.clone().unwrap_or_else(|| "unknown".to_string());
+        writeln!(f, "SMBIOS {}.{}", self.version.0, self.version.1)?;
+        writeln!(
+            f,
+            "BIOS: {} {} ({})",
+            unknown(&self.bios_vendor),
+            unknown(&self.bios_version),
+            unknown(&self.bios_date)
+        )?;
+        writeln!(
+            f,
+            "System: {} {} {}",
+            unknown(&self.system_manufacturer),
+            unknown(&self.system_product),
+            unknown(&self.system_version)
+        )?;
+        write!(
+            f,
+            "Baseboard: {} {}",
+            unknown(&self.board_manufacturer),
+            unknown(&self.board_product)
+        )?;
+        for device in &self.memory {
+            write!(
+                f,
+                "\nMemory: {} MiB in {}",
+                device.size_mib,
+                unknown(&device.locator)
+            )?;
+            if let Some(speed) = device.speed {
+                write!(f, " at {} MT/s", speed)?;
+            }
+            if let Some(manufacturer) = &device.manufacturer {
+                write!(f, " by {}", manufacturer)?;
+            }
+        }
+        Ok(())
+    }
+}
+
+/// Version and physical location and size of the structure table described by
+/// the entry point at `addr`
+fn entry_point(addr: PhysAddr) -> Result<((u8, u8), PhysAddr, usize), &'static str> {
+    let ptr = offset::phys_to_virt(addr).as_ptr::<u8>();
+    let anchor = unsafe { slice::from_raw_parts(ptr, 5) };
+    let (length, sm3) = match anchor {
+        b"_SM3_" => (unsafe { *ptr.add(6) } as usize, true),
+        [b'_', b'S', b'M', b'_', _] => (unsafe { *ptr.add(5) } as usize, false),
+        _ => return Err("Invalid SMBIOS entry point anchor"),
+    };
+    let entry = unsafe { slice::from_raw_parts(ptr, length) };
+    if length < 0x18 || entry.iter().fold(0u8, |sum, &b| sum.wrapping_add(b)) != 0 {
+        return Err("Invalid SMBIOS entry point checksum");
+    }
+    let (version, table, size) = if sm3 {
+        let mut addr = [0; 8];
+        addr.copy_from_slice(&entry[0x10..0x18]);
+        let mut size = [0; 4];
+        size.copy_from_slice(&entry[0x0c..0x10]);
+        let size = u32::from_le_bytes(size) as usize;
+        ((entry[7], entry[8]), u64::from_le_bytes(addr), size)
+    } else {
+        if length < 0x1f {
+            return Err("SMBIOS entry point too short");
+        }
+        let mut addr = [0; 4];
+        addr.copy_from_slice(&entry[0x18..0x1c]);
+        let size = u16::from_le_bytes([entry[0x16], entry[0x17]]) as usize;
+        ((entry[6], entry[7]), u32::from_le_bytes(addr) as u64, size)
+    };
+    if table + size as u64 > offset::MAX_SIZE {
+        return Err("SMBIOS table outside of mapped memory");
+    }
+    Ok((version, PhysAddr::new(table), size))
+}
+
+crate::initcall!(Early, init);
+
+/// Parse and log the SMBIOS tables, if the firmware provided them
+pub fn init(init: &mut Init) {
+    let addr = match init.boot_info.smbios {
+        Some(addr) => addr,
+        None => return,
+    };
+    let (version, table, size) = match entry_point(addr) {
+        Ok(found) => found,
+        Err(e) => {
+            log::warn!("{}", e);
+            return;
+        }
+    };
+    let ptr = offset::phys_to_virt(table).as_ptr::<u8>();
+    let table = unsafe { slice::from_raw_parts(ptr, size) };
+    let hardware = Hardware::parse(version, table);
+    for line in hardware.to_string().lines() {
+        log::info!("{}", line);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test_case]
+    fn parse() {
+        let mut table = Vec::new();
+        // System information with two strings
+        table.extend_from_slice(&[ty::SYSTEM, 0x08, 0x01, 0x00, 1, 2, 0, 0]);
+        table.extend_from_slice(b"QEMU\0Standard PC\0\0");
+        // Memory device of 2 GiB without strings
+        let mut memory = [0; 0x1b];
+        memory[0] = ty::MEMORY_DEVICE;
+        memory[1] = memory.len() as u8;
+        memory[0x0c..0x0e].copy_from_slice(&2048u16.to_le_bytes());
+        table.extend_from_slice(&memory);
+        table.extend_from_slice(&[0, 0]);
+        table.extend_from_slice(&[ty::END, 0x04, 0x02, 0x00, 0, 0]);
+        let hardware = Hardware::parse((3, 0), &table);
+        assert_eq!(hardware.system_manufacturer.as_deref(), Some("QEMU"));
+        assert_eq!(hardware.system_product.as_deref(), Some("Standard PC"));
+        assert_eq!(hardware.system_version, None);
+        assert_eq!(hardware.memory.len(), 1);
+        assert_eq!(hardware.memory[0].size_mib, 2048);
+        assert_eq!(hardware.memory[0].speed, None);
+    }
+}
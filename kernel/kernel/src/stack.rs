@@ -0,0 +1,98 @@
+//! Kernel stack with a guard page
+//!
+//! The stack handed over by the UEFI stub is not protected against overflows,
+//! so the kernel switches to a stack of its own with an unmapped guard page
+//! below it. Overflowing the stack then causes a page fault, which is handled
+//! on a separate stack and reported as a stack overflow.
+//!
+//! Overflows of buffers on the stack are caught by the stack protector the
+//! kernel is built with: functions with such buffers place [`__stack_chk_guard`]
+//! below their return address and call [`__stack_chk_fail`] if it was
+//! overwritten when they return.
+
+use crate::{interrupts, vm::AddressSpace, Init};
+use core::{mem::ManuallyDrop, ops::Range, ptr};
+use x86_64::{
+    structures::paging::{FrameAllocator, FrameDeallocator, Page, PageTableFlags, Size4KiB},
+    VirtAddr,
+};
+
+/// Start of the guard page, which is directly followed by the stack
+const GUARD: VirtAddr = VirtAddr::new_truncate(0o2_000_000_0000);
+/// Size of the stack in pages
+const PAGES: u64 = 16;
+
+/// Arguments passed to [`trampoline`] on the new stack
+struct Start {
+    init: Init,
+    f: fn(Init) -> !,
+}
+
+/// Canary checked by functions protected by the stack protector
+///
+/// It cannot be changed once protected functions are running, so it is fixed
+/// at compile time. This catches accidental overflows, not deliberate ones.
+#[no_mangle]
+#[allow(non_upper_case_globals)]
+pub static __stack_chk_guard: u64 = 0x595e_9fbd_94fd_a700;
+
+/// Called by a protected function that overwrote its canary
+#[no_mangle]
+pub extern "C" fn __stack_chk_fail() -> ! {
+    // The return address lies in the corrupted function; frame pointers are
+    // kept by the target specification
+    let addr: u64;
+    unsafe { asm!("mov {}, [rbp + 8]", out(reg) addr) };
+    panic!("stack smashing detected at {:#x}", addr);
+}
+
+/// Map the kernel stack and its guard page
+pub fn init<A>(address_space: &mut AddressSpace, all: &mut A) -> Result<(), &'static str>
+where
+    A: FrameAllocator<Size4KiB> + FrameDeallocator<Size4KiB>,
+{
+    let guard = Page::containing_address(GUARD);
+    address_space.map_guard(Page::range(guard, guard + 1), None)?;
+    let flags = PageTableFlags::PRESENT | PageTableFlags::WRITABLE;
+    address_space.map_anonymous(Page::range(guard + 1, guard + 1 + PAGES), flags, None, all)
+}
+
+/// Whether `addr` lies in the guard page of the kernel stack
+pub fn is_guard(addr: VirtAddr) -> bool {
+    (GUARD..GUARD + 4096u64).contains(&addr)
+}
+
+/// Bounds of the known stack containing `addr`: the kernel stack or one of the
+/// interrupt stacks
+pub fn bounds(addr: u64) -> Option<Range<u64>> {
+    let start = (GUARD + 4096u64).as_u64();
+    let kernel = start..start + PAGES * 4096;
+    if kernel.contains(&addr) {
+        return Some(kernel);
+    }
+    interrupts::interrupt_stack(addr)
+}
+
+/// Continue in `f` on the kernel stack, abandoning the current stack
+///
+/// # Safety
+/// The kernel stack should have been mapped by [`init`].
+pub unsafe fn switch(init: Init, f: fn(Init) -> !) -> ! {
+    let mut start = ManuallyDrop::new(Start { init, f });
+    let top = GUARD + (PAGES + 1) * 4096;
+    log::debug!("Switching to kernel stack ending at {:?}", top);
+    asm!(
+        "mov rsp, {}",
+        "call {}",
+        in(reg) top.as_u64(),
+        in(reg) trampoline as usize,
+        in("rdi") &mut *start as *mut Start as usize,
+        options(noreturn),
+    );
+}
+
+/// Move the arguments to the new stack and call the function
+extern "C" fn trampoline(start: usize) -> ! {
+    let Start { init, f } = unsafe { ptr::read(start as *const Start) };
+    f(init)
+}
@@ -0,0 +1,267 @@
+//! Synchronization for read-mostly data
+//!
+//! A [`Mutex`] makes readers wait for each other, and readers in interrupt
+//! handlers deadlock on a lock held by the code they interrupted. The
+//! primitives here never make readers wait for other readers, and only make
+//! them wait for writers briefly or not at all:
+//!
+//! - [`SeqLock`] holds small [`Copy`] values. Readers copy the value and retry
+//!   if a write happened meanwhile.
+//! - [`Rcu`] holds values of any size behind a pointer. Readers borrow the
+//!   current value without waiting; writers replace it with a modified copy
+//!   and free the old one once no reader can still be using it.
+//!
+//! Writers are serialized by a lock. A writer waits for readers of an [`Rcu`]
+//! to drop their guards, so a guard must not be held while writing. Interrupt
+//! handlers may read an [`Rcu`], but a [`SeqLock`] only if its writers disable
+//! interrupts, as the handler would retry forever after interrupting a write.
+//! Neither may be written by interrupt handlers, which could end up waiting
+//! for the code they interrupted.
+
+use alloc::boxed::Box;
+use core::{
+    cell::UnsafeCell,
+    hint::spin_loop,
+    ops::Deref,
+    ptr,
+    sync::atomic::{fence, AtomicPtr, AtomicU64, AtomicUsize, Ordering},
+};
+use spin::Mutex;
+
+/// Sequence lock for small [`Copy`] values
+///
+/// The sequence number is odd while a write is in progress. Readers retry
+/// until they read the same even number before and after copying the value.
+pub struct SeqLock<T> {
+    sequence: AtomicU64,
+    writer: Mutex<()>,
+    value: UnsafeCell<T>,
+}
+
+// Readers only obtain copies, so sharing is sound for Send values
+unsafe impl<T: Copy + Send> Sync for SeqLock<T> {}
+
+impl<T: Copy> SeqLock<T> {
+    pub const fn new(value: T) -> Self {
+        Self {
+            sequence: AtomicU64::new(0),
+            writer: Mutex::new(()),
+            value: UnsafeCell::new(value),
+        }
+    }
+
+    /// Copy of the value, not torn by concurrent writes
+    pub fn read(&self) -> T {
+        loop {
+            let before = self.sequence.load(Ordering::Acquire);
+            if before % 2 == 0 {
+                // May race with a writer, in which case the copy is discarded
+                let value = unsafe { ptr::read_volatile(self.value.get()) };
+                fence(Ordering::Acquire);
+                if self.sequence.load(Ordering::Relaxed) == before {
+                    return value;
+                }
+            }
+            spin_loop();
+        }
+    }
+
+    /// Replace the value
+    pub fn write(&self, value: T) {
+        self.update(|current| *current = value);
+    }
+
+    /// Modify the value in place with `f`, returning its result
+    pub fn update<R>(&self, f: impl FnOnce(&mut T) -> R) -> R {
+        let _writer = self.writer.lock();
+        let sequence = self.sequence.load(Ordering::Relaxed);
+        self.sequence.store(sequence + 1, Ordering::Relaxed);
+        fence(Ordering::Release);
+        let result = f(unsafe { &mut *self.value.get() });
+        self.sequence.store(sequence + 2, Ordering::Release);
+        result
+    }
+}
+
+/// Read-copy-update cell, empty until a value is published
+///
+/// Readers announce themselves in the counter of the current epoch. A writer
+/// publishes the new value, moves to the next epoch and waits for the readers
+/// of the previous one to leave before freeing the old value. Readers arriving
+/// after the move see the new value, so the wait ends as soon as the readers
+/// that were already there are done.
+pub struct Rcu<T> {
+    current: AtomicPtr<T>,
+    epoch: AtomicUsize,
+    /// Number of readers per parity of the epoch they started in
+    readers: [AtomicUsize; 2],
+    writer: Mutex<()>,
+}
+
+unsafe impl<T: Send + Sync> Sync for Rcu<T> {}
+unsafe impl<T: Send> Send for Rcu<T> {}
+
+/// Value of an [`Rcu`] borrowed by a reader
+pub struct RcuGuard<'a, T> {
+    rcu: &'a Rcu<T>,
+    value: &'a T,
+    slot: usize,
+}
+
+impl<T> Deref for RcuGuard<'_, T> {
+    type Target = T;
+
+    fn deref(&self) -> &T {
+        self.value
+    }
+}
+
+impl<T> Drop for RcuGuard<'_, T> {
+    fn drop(&mut self) {
+        self.rcu.readers[self.slot].fetch_sub(1, Ordering::Release);
+    }
+}
+
+impl<T> Rcu<T> {
+    pub const fn new() -> Self {
+        Self {
+            current: AtomicPtr::new(ptr::null_mut()),
+            epoch: AtomicUsize::new(0),
+            readers: [AtomicUsize::new(0), AtomicUsize::new(0)],
+            writer: Mutex::new(()),
+        }
+    }
+
+    /// Borrow the current value, if any; writers wait for the guard to be
+    /// dropped before freeing it
+    pub fn read(&self) -> Option<RcuGuard<T>> {
+        let slot = loop {
+            let epoch = self.epoch.load(Ordering::SeqCst);
+            self.readers[epoch % 2].fetch_add(1, Ordering::SeqCst);
+            // A writer that advanced the epoch in between waits for the other
+            // slot, and a later one could free the value read
+            if self.epoch.load(Ordering::SeqCst) == epoch {
+                break epoch % 2;
+            }
+            self.readers[epoch % 2].fetch_sub(1, Ordering::Release);
+        };
+        let value = unsafe { self.current.load(Ordering::SeqCst).as_ref() };
+        if value.is_none() {
+            self.readers[slot].fetch_sub(1, Ordering::Release);
+        }
+        value.map(|value| RcuGuard {
+            rcu: self,
+            value,
+            slot,
+        })
+    }
+
+    /// Replace the value, waiting for readers of the old one
+    pub fn replace(&self, value: T) {
+        let _writer = self.writer.lock();
+        self.publish(value);
+    }
+
+    /// Modify a copy of the value, or of the default if there is none, with
+    /// `f` and replace the value with it, returning the result of `f`
+    pub fn update<R>(&self, f: impl FnOnce(&mut T) -> R) -> R
+    where
+        T: Clone + Default,
+    {
+        let _writer = self.writer.lock();
+        // No other writer can free the current value while the lock is held
+        let current = unsafe { self.current.load(Ordering::SeqCst).as_ref() };
+        let mut value = current.cloned().unwrap_or_default();
+        let result = f(&mut value);
+        self.publish(value);
+        result
+    }
+
+    /// Swap in `value` and free the old one once its readers are gone; the
+    /// writer lock should be held
+    fn publish(&self, value: T) {
+        let old = self
+            .current
+            .swap(Box::into_raw(Box::new(value)), Ordering::SeqCst);
+        let epoch = self.epoch.fetch_add(1, Ordering::SeqCst);
+        while self.readers[epoch % 2].load(Ordering::SeqCst) != 0 {
+            spin_loop();
+        }
+        if !old.is_null() {
+            drop(unsafe { Box::from_raw(old) });
+        }
+    }
+}
+
+impl<T> Drop for Rcu<T> {
+    fn drop(&mut self) {
+        let current = *self.current.get_mut();
+        if !current.is_null() {
+            drop(unsafe { Box::from_raw(current) });
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use alloc::vec::Vec;
+    use core::sync::atomic::AtomicBool;
+
+    #[test_case]
+    fn seqlock_update() {
+        let lock = SeqLock::new((1u64, 2u64));
+        assert_eq!(lock.read(), (1, 2));
+        lock.write((3, 4));
+        let sum = lock.update(|(a, b)| {
+            *a += 1;
+            *b += 1;
+            *a + *b
+        });
+        assert_eq!(sum, 9);
+        assert_eq!(lock.read(), (4, 5));
+    }
+
+    /// Value recording whether it was dropped
+    struct Tracked<'a>(u64, &'a AtomicBool);
+
+    impl Drop for Tracked<'_> {
+        fn drop(&mut self) {
+            self.1.store(true, Ordering::Relaxed);
+        }
+    }
+
+    #[test_case]
+    fn rcu_frees_after_readers() {
+        let (first, second) = (AtomicBool::new(false), AtomicBool::new(false));
+        let rcu = Rcu::new();
+        assert!(rcu.read().is_none());
+        rcu.replace(Tracked(1, &first));
+        let guard = rcu.read().unwrap();
+        assert_eq!(guard.0, 1);
+        drop(guard);
+        rcu.replace(Tracked(2, &second));
+        assert!(first.load(Ordering::Relaxed));
+        assert_eq!(rcu.read().unwrap().0, 2);
+        // Readers only count while they hold a guard
+        assert_eq!(rcu.readers[0].load(Ordering::Relaxed), 0);
+        assert_eq!(rcu.readers[1].load(Ordering::Relaxed), 0);
+        drop(rcu);
+        assert!(second.load(Ordering::Relaxed));
+    }
+
+    #[test_case]
+    fn rcu_update_copies() {
+        let rcu = Rcu::<Vec<u64>>::new();
+        rcu.update(|v| v.push(1));
+        let before = rcu.read().unwrap().as_ptr();
+        let len = rcu.update(|v| {
+            v.push(2);
+            v.len()
+        });
+        assert_eq!(len, 2);
+        let after = rcu.read().unwrap();
+        assert_eq!(*after, [1, 2]);
+        assert_ne!(after.as_ptr(), before);
+    }
+}
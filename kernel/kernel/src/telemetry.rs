@@ -0,0 +1,183 @@
+//! Package temperature and energy readings from model specific registers
+//!
+//! Intel CPUs report the package temperature as an offset below the maximum
+//! junction temperature. Energy counters (RAPL) exist on Intel and AMD CPUs,
+//! but are not always enumerated, so the registers are probed: a read of a
+//! register that does not exist is skipped by the general protection fault
+//! handler through [`fixup`]. The 32-bit energy counter wraps within minutes
+//! under load, so it is accumulated every second by [`tick`].
+
+use core::sync::atomic::{AtomicBool, Ordering};
+use spin::{Mutex, Once};
+use sys::Telemetry;
+use x86_64::{instructions::interrupts::without_interrupts, VirtAddr};
+
+mod msr {
+    pub const TEMPERATURE_TARGET: u32 = 0x1a2;
+    pub const PACKAGE_THERM_STATUS: u32 = 0x1b1;
+    pub const INTEL_POWER_UNIT: u32 = 0x606;
+    pub const INTEL_PACKAGE_ENERGY: u32 = 0x611;
+    pub const AMD_POWER_UNIT: u32 = 0xc001_0299;
+    pub const AMD_PACKAGE_ENERGY: u32 = 0xc001_029b;
+}
+
+/// Set while [`read_probe`] reads a register that may not exist
+static PROBING: AtomicBool = AtomicBool::new(false);
+/// Set by [`fixup`] if the register did not exist
+static FAULTED: AtomicBool = AtomicBool::new(false);
+
+/// Registers found by [`init`]
+static SOURCES: Once<Sources> = Once::new();
+
+static ENERGY: Mutex<Energy> = Mutex::new(Energy { last: 0, total: 0 });
+
+struct Sources {
+    /// Maximum junction temperature in degrees Celsius, if the package
+    /// temperature can be read
+    tj_max: Option<u32>,
+    /// Package energy counter and the number of microjoules per 2^32 of its
+    /// increments
+    energy: Option<(u32, u64)>,
+}
+
+struct Energy {
+    /// Last value of the counter
+    last: u32,
+    /// Accumulated increments of the counter
+    total: u64,
+}
+
+/// Read model specific register `msr`
+///
+/// # Safety
+/// The register should exist, unless [`PROBING`] is set.
+unsafe fn read(msr: u32) -> u64 {
+    let (high, low): (u32, u32);
+    // Not marked as not accessing memory, so it is ordered with PROBING
+    asm!("rdmsr", in("ecx") msr, out("eax") low, out("edx") high, options(nostack));
+    (high as u64) << 32 | low as u64
+}
+
+/// Read model specific register `msr`, or [`None`] if it does not exist
+fn read_probe(msr: u32) -> Option<u64> {
+    FAULTED.store(false, Ordering::SeqCst);
+    PROBING.store(true, Ordering::SeqCst);
+    let value = unsafe { read(msr) };
+    PROBING.store(false, Ordering::SeqCst);
+    if FAULTED.load(Ordering::SeqCst) {
+        None
+    } else {
+        Some(value)
+    }
+}
+
+/// Address to continue at after a general protection fault at `rip`, if it
+/// was caused by [`read_probe`]
+pub fn fixup(rip: VirtAddr) -> Option<VirtAddr> {
+    if !PROBING.load(Ordering::SeqCst) {
+        return None;
+    }
+    const RDMSR: [u8; 2] = [0x0f, 0x32];
+    if unsafe { rip.as_ptr::<[u8; 2]>().read() } != RDMSR {
+        return None;
+    }
+    FAULTED.store(true, Ordering::SeqCst);
+    Some(rip + RDMSR.len() as u64)
+}
+
+fn vendor() -> [u8; 12] {
+    let leaf = unsafe { core::arch::x86_64::__cpuid(0) };
+    let mut vendor = [0; 12];
+    vendor[0..4].copy_from_slice(&leaf.ebx.to_le_bytes());
+    vendor[4..8].copy_from_slice(&leaf.edx.to_le_bytes());
+    vendor[8..12].copy_from_slice(&leaf.ecx.to_le_bytes());
+    vendor
+}
+
+fn probe() -> Sources {
+    let (unit, counter) = match &vendor() {
+        b"GenuineIntel" => (msr::INTEL_POWER_UNIT, msr::INTEL_PACKAGE_ENERGY),
+        b"AuthenticAMD" => (msr::AMD_POWER_UNIT, msr::AMD_PACKAGE_ENERGY),
+        _ => {
+            return Sources {
+                tj_max: None,
+                energy: None,
+            }
+        }
+    };
+    let tj_max = match (
+        read_probe(msr::TEMPERATURE_TARGET),
+        read_probe(msr::PACKAGE_THERM_STATUS),
+    ) {
+        (Some(target), Some(_)) => Some((target >> 16) as u32 & 0xff),
+        _ => None,
+    };
+    let energy = match (read_probe(unit), read_probe(counter)) {
+        // A unit is 1 / 2^n joules
+        (Some(unit), Some(_)) => Some((counter, (1_000_000u64 << 32) >> ((unit >> 8) & 0x1f))),
+        _ => None,
+    };
+    Sources { tj_max, energy }
+}
+
+crate::initcall!(Arch, |_| init());
+
+/// Probe for temperature and energy registers
+pub fn init() {
+    // The timer interrupt should not see the counter before its first value
+    let sources = without_interrupts(|| {
+        let sources = SOURCES.call_once(probe);
+        if let Some((counter, _)) = sources.energy {
+            ENERGY.lock().last = unsafe { read(counter) } as u32;
+        }
+        sources
+    });
+    log::info!(
+        "Package temperature {}, energy {}",
+        if sources.tj_max.is_some() {
+            "available"
+        } else {
+            "unavailable"
+        },
+        if sources.energy.is_some() {
+            "available"
+        } else {
+            "unavailable"
+        }
+    );
+}
+
+/// Accumulate the energy counter; called by the timer interrupt every second
+pub fn tick() {
+    let counter = match SOURCES.get().and_then(|sources| sources.energy) {
+        Some((counter, _)) => counter,
+        None => return,
+    };
+    let value = unsafe { read(counter) } as u32;
+    let mut energy = ENERGY.lock();
+    energy.total += value.wrapping_sub(energy.last) as u64;
+    energy.last = value;
+}
+
+/// Current readings
+pub fn readings() -> Telemetry {
+    let sources = match SOURCES.get() {
+        Some(sources) => sources,
+        None => return Telemetry::default(),
+    };
+    let temperature = sources.tj_max.map(|tj_max| {
+        let status = unsafe { read(msr::PACKAGE_THERM_STATUS) };
+        tj_max.saturating_sub((status >> 16) as u32 & 0x7f)
+    });
+    let energy = sources.energy.map(|(_, micro_joules)| {
+        let total = without_interrupts(|| {
+            tick();
+            ENERGY.lock().total
+        });
+        ((total as u128 * micro_joules as u128) >> 32) as u64
+    });
+    Telemetry {
+        temperature,
+        energy,
+    }
+}
@@ -1,17 +1,101 @@
-use crate::Init;
+//! Kernel test runner
+//!
+//! With the `test-stress` option the tests run under stress: the local APIC
+//! timer interrupts them at [`STRESS_FREQUENCY`], and every interrupt does
+//! [`background`] work touching state shared with the interrupted code and
+//! stays busy for a while. There are no kernel threads to run alongside the
+//! tests, so this is how code that is not safe against interrupts is caught.
+
+use crate::{
+    allocator, config, devices,
+    drivers::pci,
+    handle::HandleTable,
+    interrupts,
+    ports::Ports,
+    programs, telemetry,
+    threads::{self, Limits},
+    time, trace, Init,
+};
+use alloc::vec::Vec;
 use common::{print, println};
-use core::panic::PanicInfo;
+use core::{
+    hint::spin_loop,
+    panic::PanicInfo,
+    sync::atomic::{AtomicBool, AtomicU64, Ordering},
+};
 use owo_colors::OwoColorize;
 use spin::Mutex;
-use x86_64::instructions::port::Port;
 
 pub static INIT: Mutex<Option<Init>> = Mutex::new(None);
 
+/// Port of QEMU's `isa-debug-exit` device, see [`exit`]
+const EXIT_PORT: Ports = Ports::new("qemu-exit", 0xf4, 4);
+
+/// Passed and failed tests reported by the running userspace test suite
+static REPORT: Mutex<Option<(u64, u64)>> = Mutex::new(None);
+
+/// Program spawned repeatedly by [`run_process_stress`] instead of being run
+/// as a test suite
+const STRESS_PROGRAM: &str = "stress";
+/// Time during which [`run_process_stress`] keeps spawning processes
+const STRESS_PROCESS_SECONDS: u64 = 5;
+
+/// Frequency of local APIC timer interrupts under stress
+const STRESS_FREQUENCY: u32 = 2000;
+/// Time every interrupt under stress stays busy, widening the window in which
+/// the interrupted code is suspended
+const STRESS_BUSY_NS: u64 = 20_000;
+
+/// Whether the tests run under stress
+static STRESS: AtomicBool = AtomicBool::new(false);
+/// Number of background runs under stress
+static BACKGROUND_RUNS: AtomicU64 = AtomicU64::new(0);
+
+/// Interrupt the tests at a high rate, see the module documentation
+fn start_stress() {
+    interrupts::start_apic_timer(STRESS_FREQUENCY);
+    if interrupts::apic_timer_frequency() < STRESS_FREQUENCY {
+        println!("not running under stress: local APIC timer unavailable");
+        return;
+    }
+    STRESS.store(true, Ordering::Relaxed);
+    println!(
+        "running under stress: interrupts at {} Hz, busy for {} us each",
+        STRESS_FREQUENCY,
+        STRESS_BUSY_NS / 1000
+    );
+}
+
+/// Work done by the local APIC timer interrupt under stress, doing what
+/// interrupt handlers may do with state the tests also use
+pub fn background() {
+    if !STRESS.load(Ordering::Relaxed) {
+        return;
+    }
+    let start = trace::timestamp();
+    let run = BACKGROUND_RUNS.fetch_add(1, Ordering::Relaxed);
+    // Read-mostly data may be read while the interrupted code updates it
+    if let Some(device) = pci::devices().first() {
+        devices::pci(device.address);
+    }
+    telemetry::readings();
+    if run % STRESS_FREQUENCY as u64 == 0 {
+        log::debug!("Background run #{} under stress", run);
+    }
+    while time::nanoseconds(trace::timestamp() - start) < STRESS_BUSY_NS {
+        spin_loop();
+    }
+}
+
 /// Run tests and exits
 ///
 /// Calls `test_main` (and thus `test_runner`) internally.
 pub fn run_tests(init: Init) -> ! {
+    EXIT_PORT.claim().expect("QEMU exit port claimed");
     *INIT.lock() = Some(init);
+    if config::test_stress() {
+        start_stress();
+    }
     crate::test_main();
     panic!("Should have exited QEMU with appropriate error code...");
 }
@@ -34,8 +118,190 @@ enum ExitCode {
 /// # Safety
 /// Port should exist (the case if QEMU is used)
 fn exit(exit_code: ExitCode) {
-    let mut port = Port::<u32>::new(0xf4);
-    unsafe { port.write(exit_code as u32) };
+    unsafe { EXIT_PORT.write(0, exit_code as u32) };
+}
+
+/// Deterministic xorshift pseudorandom numbers for randomized tests
+pub struct Rng(u64);
+
+impl Rng {
+    /// Generator for `seed`, which is scrambled so small seeds are fine
+    pub const fn new(seed: u64) -> Self {
+        // The state may not be zero, which an odd multiplier preserves
+        Self(seed.wrapping_add(1).wrapping_mul(0x9e37_79b9_7f4a_7c15))
+    }
+
+    pub fn next(&mut self) -> u64 {
+        self.0 ^= self.0 << 13;
+        self.0 ^= self.0 >> 7;
+        self.0 ^= self.0 << 17;
+        self.0
+    }
+
+    /// Number in `0..n`
+    pub fn below(&mut self, n: u64) -> u64 {
+        self.next() % n
+    }
+}
+
+/// Record the outcome of a userspace test suite, see
+/// [`sys::SyscallCode::TestReport`]
+pub fn report(passed: u64, failed: u64) {
+    *REPORT.lock() = Some((passed, failed));
+}
+
+/// Run every embedded program other than init as a userspace test suite
+///
+/// Returns the number of passed and failed tests. A suite that fails to run
+/// or exits without reporting counts as a single failed test.
+fn run_user_suites() -> (u64, u64) {
+    let mut guard = INIT.lock();
+    let init = guard.as_mut().unwrap();
+    let (mut passed, mut failed) = (0, 0);
+    let suites = programs::names().filter(|&name| name != programs::INIT && name != STRESS_PROGRAM);
+    for name in suites {
+        print!("suite {} ... ", name);
+        REPORT.lock().take();
+        let limits = Limits::default();
+        let result = unsafe { threads::spawn_user(init, name, limits, HandleTable::devices()) };
+        match (result, REPORT.lock().take()) {
+            (Ok(()), Some((ok, 0))) => {
+                println!("{} ({} passed)", "ok".green(), ok);
+                passed += ok;
+            }
+            (Ok(()), Some((ok, bad))) => {
+                println!("{} ({} passed; {} failed)", "FAILED".red(), ok, bad);
+                passed += ok;
+                failed += bad;
+            }
+            (Ok(()), None) => {
+                println!("{} (no report)", "FAILED".red());
+                failed += 1;
+            }
+            (Err(e), _) => {
+                println!("{} ({})", "FAILED".red(), e);
+                failed += 1;
+            }
+        }
+    }
+    (passed, failed)
+}
+
+/// Spawn [`STRESS_PROGRAM`], if embedded, until [`STRESS_PROCESS_SECONDS`]
+/// have passed, checking that every process succeeds and that processes do not
+/// leak heap memory or frames
+///
+/// Memory use is compared with that after the first process, which may
+/// initialize things lazily. Processes run one at a time, as there is no
+/// scheduler yet. Returns whether all checks passed, or [`None`] if the program
+/// is not embedded.
+fn run_process_stress() -> Option<bool> {
+    programs::names().find(|&name| name == STRESS_PROGRAM)?;
+    print!("processes {} ... ", STRESS_PROGRAM);
+    let spawn = || {
+        REPORT.lock().take();
+        let mut guard = INIT.lock();
+        let init = guard.as_mut().unwrap();
+        let limits = Limits::default();
+        let result =
+            unsafe { threads::spawn_user(init, STRESS_PROGRAM, limits, HandleTable::devices()) };
+        match (result, REPORT.lock().take()) {
+            (Ok(()), Some((_, 0))) => Ok(()),
+            (Ok(()), Some(_)) => Err("checks failed"),
+            (Ok(()), None) => Err("no report"),
+            (Err(e), _) => Err(e),
+        }
+    };
+    let result = spawn().and_then(|()| {
+        let (heap, free) = memory();
+        let start = trace::timestamp();
+        let duration = STRESS_PROCESS_SECONDS * 1_000_000_000;
+        let mut count = 1;
+        while time::nanoseconds(trace::timestamp() - start) < duration {
+            spawn()?;
+            count += 1;
+        }
+        let (heap_after, free_after) = memory();
+        Ok((count, heap_after - heap, free - free_after))
+    });
+    match result {
+        Ok((count, 0, 0)) => {
+            println!("{} ({} processes)", "ok".green(), count);
+            Some(true)
+        }
+        Ok((count, heap, frames)) => {
+            println!(
+                "{} ({} processes kept {} heap bytes and {} frames)",
+                "FAILED".red(),
+                count,
+                heap,
+                frames
+            );
+            Some(false)
+        }
+        Err(e) => {
+            println!("{} ({})", "FAILED".red(), e);
+            Some(false)
+        }
+    }
+}
+
+/// Heap bytes in use and free frames
+fn memory() -> (i64, i64) {
+    let guard = INIT.lock();
+    let stats = allocator::memory_stats(&guard.as_ref().unwrap().frame_allocator);
+    (stats.heap_used as i64, (stats.free / 4096) as i64)
+}
+
+/// Resources used by a passed test
+struct Measurement {
+    name: &'static str,
+    nanoseconds: u64,
+    /// Change in heap bytes in use
+    heap: i64,
+    /// Change in frames in use
+    frames: i64,
+}
+
+impl Measurement {
+    /// Whether the test kept memory allocated, which is not necessarily a leak
+    /// as it may have initialized something lazily
+    fn leaky(&self) -> bool {
+        self.heap > 0 || self.frames > 0
+    }
+}
+
+/// Print the time and memory used by every test, flagging those that kept
+/// memory allocated
+fn print_measurements(measurements: &[Measurement]) {
+    let width = measurements.iter().map(|m| m.name.len()).max().unwrap_or(0);
+    println!();
+    println!(
+        "{:width$} {:>10} {:>10} {:>7}",
+        "test",
+        "time (us)",
+        "heap (B)",
+        "frames",
+        width = width
+    );
+    for m in measurements {
+        print!(
+            "{:width$} {:>10} {:>+10} {:>+7}",
+            m.name,
+            m.nanoseconds / 1000,
+            m.heap,
+            m.frames,
+            width = width
+        );
+        if m.leaky() {
+            print!(" {}", "kept memory".yellow());
+        }
+        println!();
+    }
+    let leaky = measurements.iter().filter(|m| m.leaky()).count();
+    if leaky > 0 {
+        println!("{} tests kept memory allocated", leaky);
+    }
 }
 
 pub fn test_runner(tests: &[&dyn Test]) {
@@ -46,19 +312,57 @@ pub fn test_runner(tests: &[&dyn Test]) {
         if tests.len() == 1 { "" } else { "s" }
     );
 
+    // Allocated up front so measurements do not disturb themselves
+    let mut measurements = Vec::with_capacity(tests.len());
     for test in tests {
+        print!("test {} ... ", test.name());
+        let (heap, free) = memory();
+        let start = trace::timestamp();
         test.run();
+        let cycles = trace::timestamp() - start;
+        let (heap_after, free_after) = memory();
+        println!("{}", "ok".green());
+        measurements.push(Measurement {
+            name: test.name(),
+            nanoseconds: time::nanoseconds(cycles),
+            heap: heap_after - heap,
+            frames: free - free_after,
+        });
+    }
+    print_measurements(&measurements);
+    let (mut user_passed, mut failed) = run_user_suites();
+    match run_process_stress() {
+        Some(true) => user_passed += 1,
+        Some(false) => failed += 1,
+        None => {}
+    }
+    let passed = tests.len() as u64 + user_passed;
+    if STRESS.load(Ordering::Relaxed) {
+        println!();
+        println!(
+            "{} background runs under stress",
+            BACKGROUND_RUNS.load(Ordering::Relaxed)
+        );
     }
 
     println!();
-    println!(
-        "test result: {}. {} passed; 0 failed",
-        "ok".green(),
-        tests.len()
-    );
+    if failed == 0 {
+        println!("test result: {}. {} passed; 0 failed", "ok".green(), passed);
+    } else {
+        println!(
+            "test result: {}. {} passed; {} failed",
+            "FAILED".red(),
+            passed,
+            failed
+        );
+    }
     println!();
 
-    exit(ExitCode::Success);
+    exit(if failed == 0 {
+        ExitCode::Success
+    } else {
+        ExitCode::Failure
+    });
 }
 
 #[cfg(test)]
@@ -71,13 +375,16 @@ fn panic(info: &PanicInfo) -> ! {
 }
 
 pub trait Test {
+    fn name(&self) -> &'static str;
     fn run(&self);
 }
 
 impl<F: Fn()> Test for F {
+    fn name(&self) -> &'static str {
+        core::any::type_name::<F>()
+    }
+
     fn run(&self) {
-        print!("test {} ... ", core::any::type_name::<F>());
         self();
-        println!("{}", "ok".green());
     }
 }
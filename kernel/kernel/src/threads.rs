@@ -1,73 +1,449 @@
-use crate::Init;
-use common::{boot::offset, elf::ElfInfo};
-use core::{slice, str};
-use sys::{FrameBuffer, SyscallCode};
-use uefi::proto::console::gop;
+use crate::{
+    acpi, allocator, audit, boot_profile, config, console, core_dump, drivers,
+    fault::{self, Report},
+    framebuffer,
+    handle::{self, HandleTable},
+    idle,
+    inject::{self, Site},
+    input, jobs, latency, mmap, physmap, programs,
+    ptrace::{Action, Strace, Syscall, Tracee, Tracer},
+    shutdown,
+    signal::{Context, Signals},
+    telemetry, time, trace,
+    user::{UserPtr, UserSlice},
+    watchdog, Init,
+};
+use alloc::vec::Vec;
+use common::elf::ElfInfo;
+use core::{
+    ptr, str,
+    sync::atomic::{AtomicPtr, AtomicU64, Ordering},
+};
+use spin::Mutex;
+use sys::{
+    AuditKind, AuditRecord, FrameBufferAccess, HostRead, HostWrite, LatencySource, ObjectKind,
+    ProcessInfo, Rights, Signal, SyscallCode, TraceKind, TraceRecord, PROCESS_NAME_LEN,
+};
 use x86_64::{
     registers::model_specific::LStar,
-    structures::paging::{
-        FrameAllocator, FrameDeallocator, Mapper, Page, PageTableFlags, PhysFrame, Size4KiB,
-        Translate,
-    },
-    PhysAddr, VirtAddr,
+    structures::paging::{Page, PageTableFlags},
+    VirtAddr,
 };
 
 static mut STACK: u64 = 0;
 
+/// Stack pointer of the system call loop while a process runs
+pub fn kernel_stack() -> u64 {
+    unsafe { STACK }
+}
+
+/// Process identifier handed out to the next spawned process
+static NEXT_PID: AtomicU64 = AtomicU64::new(1);
+/// Process identifier of the running process, or zero if none
+static CURRENT_PID: AtomicU64 = AtomicU64::new(0);
+
+/// Process identifier of the running process, if any
+pub fn current_pid() -> Option<u64> {
+    match CURRENT_PID.load(Ordering::Relaxed) {
+        0 => None,
+        pid => Some(pid),
+    }
+}
+
+/// Maximum number of spawned processes waiting to run
+const MAX_PENDING: usize = 8;
+/// Maximum length in bytes of paths in the directory shared by the host
+const MAX_HOST_PATH: usize = 256;
+
+/// Process spawned with [`SyscallCode::Spawn`], waiting for the running
+/// process to exit
+struct Pending {
+    pid: u64,
+    name: &'static str,
+    handles: HandleTable,
+}
+
+/// Spawned processes in the order they run
+static PENDING: Mutex<Vec<Pending>> = Mutex::new(Vec::new());
+
+/// Kernel state lent to the page fault handler while a process runs, to map
+/// the pages of its executable on demand
+static RUNNING: AtomicPtr<Init> = AtomicPtr::new(ptr::null_mut());
+
+/// Handle a page fault at `addr` in the executable of the running process,
+/// caused by a `write` or not, returning whether the access can be retried;
+/// called by the page fault handler
+///
+/// # Safety
+/// Must only be called by the page fault handler, for a fault outside of
+/// [`vm`](crate::vm) such as the process or a system call accessing its memory.
+pub unsafe fn populate(addr: VirtAddr, write: bool) -> bool {
+    let (pid, init) = match (current_pid(), RUNNING.load(Ordering::Relaxed).as_mut()) {
+        (Some(pid), Some(init)) => (pid, init),
+        _ => return false,
+    };
+    init.address_space
+        .populate(pid, addr, write, &mut init.frame_allocator)
+        .unwrap_or(false)
+}
+
+/// Resources a process may use
+#[derive(Copy, Clone, Debug)]
+pub struct Limits {
+    /// Pages mapped to fresh frames, including the executable and stack
+    pub pages: u64,
+}
+
+impl Default for Limits {
+    fn default() -> Self {
+        Self { pages: 0o10_000 }
+    }
+}
+
+/// System calls that need a handle, see [`SyscallCode::required_rights`]
+const GUARDED: [SyscallCode; 17] = [
+    SyscallCode::FrameBuffer,
+    SyscallCode::FbWaitVsync,
+    SyscallCode::FbSetMode,
+    SyscallCode::FbPresent,
+    SyscallCode::Screenshot,
+    SyscallCode::InputEvent,
+    SyscallCode::InputFocus,
+    SyscallCode::InputSetFocus,
+    SyscallCode::ReadKey,
+    SyscallCode::ReadLine,
+    SyscallCode::AudioSubmit,
+    SyscallCode::TraceDrain,
+    SyscallCode::HostRead,
+    SyscallCode::HostWrite,
+    SyscallCode::AuditDrain,
+    SyscallCode::Shutdown,
+    SyscallCode::Suspend,
+];
+
+/// Whether `handles` allow process `pid` system call `code`, if guarded
+///
+/// Denials are audited, as is the first allowed call on each kind of object,
+/// which is tracked in the bits of `used`.
+fn allowed(pid: u64, handles: &HandleTable, code: u64, used: &mut u32) -> bool {
+    let required = GUARDED
+        .iter()
+        .find(|&&guarded| guarded as u64 == code)
+        .and_then(|guarded| guarded.required_rights());
+    let (kind, rights) = match required {
+        Some(required) => required,
+        None => return true,
+    };
+    let allowed = handles.allows(kind, rights);
+    let bit = 1 << kind as u32;
+    if !allowed || *used & bit == 0 {
+        let arg = (kind as u64) << 32 | rights.0 as u64;
+        audit::record(AuditKind::DeviceAccess, pid, arg, allowed);
+    }
+    if allowed {
+        *used |= bit;
+    } else {
+        common::log_rate_limited!(
+            10,
+            log::Level::Warn,
+            "Process {} lacks {:?} on {:?} for syscall {}",
+            pid,
+            rights,
+            kind,
+            code
+        );
+    }
+    allowed
+}
+
 /// Simple test of user space
 ///
-/// Blocks until userspace thread returns, does not clean up ELF mappings.
-pub unsafe fn spawn_user(init: &mut Init, elf: &ElfInfo) {
-    elf.setup_mappings(&mut init.page_table, &mut init.frame_allocator)
-        .unwrap();
+/// Runs the embedded program called `name`, see [`programs`], and blocks
+/// until it returns. Fails without running the process if it cannot be
+/// mapped within `limits`. The process can only use the devices it has a
+/// handle to in `handles`. It is traced by [`Strace`] if configured.
+pub unsafe fn spawn_user(
+    init: &mut Init,
+    name: &str,
+    limits: Limits,
+    handles: HandleTable,
+) -> Result<(), &'static str> {
+    let mut strace = Strace;
+    let tracer = if config::strace() {
+        Some(&mut strace as &mut dyn Tracer)
+    } else {
+        None
+    };
+    spawn_traced(init, name, limits, handles, tracer)
+}
+
+/// Run a process like [`spawn_user`], traced by `tracer` if any, see
+/// [`ptrace`](crate::ptrace)
+///
+/// The processes it spawns run after it, within the same `limits` and traced
+/// by the same tracer.
+pub unsafe fn spawn_traced(
+    init: &mut Init,
+    name: &str,
+    limits: Limits,
+    handles: HandleTable,
+    mut tracer: Option<&mut dyn Tracer>,
+) -> Result<(), &'static str> {
+    let pid = NEXT_PID.fetch_add(1, Ordering::Relaxed);
+    let result = run(init, pid, name, limits, handles, reborrow(&mut tracer));
+    while let Some(next) = take_pending() {
+        if shutdown::requested() {
+            log::info!("Process {} ({}) not run for shutdown", next.pid, next.name);
+            continue;
+        }
+        let tracer = reborrow(&mut tracer);
+        if let Err(e) = run(init, next.pid, next.name, limits, next.handles, tracer) {
+            log::warn!("Failed to run process {} ({}): {}", next.pid, next.name, e);
+        }
+    }
+    result
+}
+
+/// Reborrow `tracer` to trace one of several processes
+fn reborrow<'a>(tracer: &'a mut Option<&mut dyn Tracer>) -> Option<&'a mut dyn Tracer> {
+    tracer
+        .as_mut()
+        .map(|tracer| &mut **tracer as &mut dyn Tracer)
+}
+
+/// Queue the embedded program called `name` to run once process `parent`
+/// exits, with the handles `parent` granted to it, returning its process
+/// identifier
+///
+/// The grants are only taken once the process is queued, so they are kept for
+/// a later attempt if spawning fails.
+fn spawn_pending(parent: u64, name: &str) -> Result<u64, &'static str> {
+    let name = programs::names()
+        .find(|&program| program == name)
+        .ok_or("No such program")?;
+    let mut pending = PENDING.lock();
+    if pending.len() == MAX_PENDING {
+        return Err("Too many processes waiting to run");
+    }
+    let pid = NEXT_PID.fetch_add(1, Ordering::Relaxed);
+    log::info!("Process {} ({}) waits to run", pid, name);
+    pending.push(Pending {
+        pid,
+        name,
+        handles: handle::take_grants(parent),
+    });
+    Ok(pid)
+}
+
+/// Oldest spawned process waiting to run
+fn take_pending() -> Option<Pending> {
+    let mut pending = PENDING.lock();
+    if pending.is_empty() {
+        return None;
+    }
+    Some(pending.remove(0))
+}
+
+/// Run process `pid` of program `name` until it exits
+unsafe fn run(
+    init: &mut Init,
+    pid: u64,
+    name: &str,
+    limits: Limits,
+    handles: HandleTable,
+    tracer: Option<&mut dyn Tracer>,
+) -> Result<(), &'static str> {
+    let elf = &programs::get(name)?;
+    log::info!("Spawning process {} ({}) with {:?}", pid, name, limits);
+    jobs::register(pid)?;
     let stack_start = 0x2000;
     let stack_length = 1;
-    let stack_start_page = Page::containing_address(VirtAddr::new(stack_start));
-    let stack_pages = Page::range(stack_start_page, stack_start_page + stack_length);
-    for page in stack_pages {
-        let frame = init.frame_allocator.allocate_frame().unwrap();
-        let flags =
-            PageTableFlags::PRESENT | PageTableFlags::WRITABLE | PageTableFlags::USER_ACCESSIBLE;
-        init.page_table
-            .map_to(page, frame, flags, &mut init.frame_allocator)
-            .unwrap()
-            .flush();
+    let result = map_process(init, elf, pid, limits, stack_start, stack_length);
+    audit::record(
+        AuditKind::Spawn,
+        pid,
+        handles.count() as u64,
+        result.is_ok(),
+    );
+    if let Err(e) = result {
+        jobs::remove(pid);
+        init.address_space
+            .unmap_process(pid, &mut init.frame_allocator);
+        init.address_space.unmap_elf(pid, &mut init.frame_allocator);
+        return Err(e);
     }
+    init.address_space.log_maps(Some(pid));
     LStar::write(VirtAddr::from_ptr(syscall_handler as *const ()));
     log::info!("Switching to userspace");
-    syscall_loop(init, elf.entry_point(), stack_start + stack_length * 0x1000);
+    trace::record(TraceKind::ContextSwitch, pid);
+    physmap::barrier();
+    CURRENT_PID.store(pid, Ordering::Relaxed);
+    RUNNING.store(init, Ordering::Relaxed);
+    let mut info = ProcessInfo {
+        pid,
+        page_limit: limits.pages,
+        ..Default::default()
+    };
+    let len = name.len().min(PROCESS_NAME_LEN);
+    info.name[..len].copy_from_slice(&name.as_bytes()[..len]);
+    syscall_loop(
+        init,
+        info,
+        handles,
+        tracer,
+        elf.entry_point(),
+        stack_start + stack_length * 0x1000,
+    );
+    RUNNING.store(ptr::null_mut(), Ordering::Relaxed);
+    CURRENT_PID.store(0, Ordering::Relaxed);
+    physmap::barrier();
+    trace::record(TraceKind::ContextSwitch, 0);
     log::info!("Back in kernelspace");
-    for page in stack_pages {
-        let (frame, flush) = init.page_table.unmap(page).unwrap();
-        flush.flush();
-        init.frame_allocator.deallocate_frame(frame);
+    framebuffer::release(init, pid);
+    input::remove(pid);
+    jobs::remove(pid);
+    // Grants not passed on to a spawned process are dropped
+    handle::take_grants(pid);
+    init.address_space
+        .unmap_process(pid, &mut init.frame_allocator);
+    init.address_space.unmap_elf(pid, &mut init.frame_allocator);
+    Ok(())
+}
+
+/// Map the stack, time page and executable of process `pid`
+///
+/// The executable is mapped last, as it is only mapped if all of it fits
+/// within `limits`.
+fn map_process(
+    init: &mut Init,
+    elf: &ElfInfo<'static>,
+    pid: u64,
+    limits: Limits,
+    stack_start: u64,
+    stack_length: u64,
+) -> Result<(), &'static str> {
+    init.address_space.set_limit(pid, limits.pages)?;
+    let stack_start_page = Page::containing_address(VirtAddr::new(stack_start));
+    let stack_pages = Page::range(stack_start_page, stack_start_page + stack_length);
+    let flags =
+        PageTableFlags::PRESENT | PageTableFlags::WRITABLE | PageTableFlags::USER_ACCESSIBLE;
+    init.address_space
+        .map_anonymous(stack_pages, flags, Some(pid), &mut init.frame_allocator)?;
+    time::map(&mut init.address_space, pid, &mut init.frame_allocator)?;
+    init.address_space
+        .map_elf(elf, pid, &mut init.frame_allocator)
+}
+
+/// Wait while process `pid` is stopped, see [`jobs::stop`]
+///
+/// A shutdown ends the wait, so the process can be terminated.
+fn park(pid: u64) {
+    log::info!("Process {} stopped", pid);
+    while jobs::stopped(pid) && !shutdown::requested() {
+        watchdog::touch();
+        idle::wait();
     }
-    elf.remove_mappings(&mut init.page_table, &mut init.frame_allocator)
-        .unwrap();
+    log::info!("Process {} continued", pid);
+}
+
+/// Value of `result`, a [`UserPtr`] or [`UserSlice`] access of process `pid`,
+/// or else fail the system call with `context`
+macro_rules! user {
+    ($context:ident, $pid:expr, $result:expr) => {
+        match $result {
+            Ok(value) => value,
+            Err(e) => {
+                common::log_rate_limited!(
+                    10,
+                    log::Level::Warn,
+                    "Process {} passed invalid memory: {}",
+                    $pid,
+                    e
+                );
+                $context.rax = 1;
+                continue;
+            }
+        }
+    };
 }
 
-/// Loop while handling syscalls
-unsafe fn syscall_loop(init: &mut Init, entry_point: u64, stack_end: u64) {
-    let mut rip = entry_point;
-    let mut rsp = stack_end;
-    let mut rax = 0u64;
+/// Loop while handling syscalls of the process described by `info`, which is
+/// kept up to date
+///
+/// Memory passed by the process is only accessed through [`UserPtr`] and
+/// [`UserSlice`], so invalid addresses fail the system call.
+unsafe fn syscall_loop(
+    init: &mut Init,
+    mut info: ProcessInfo,
+    mut handles: HandleTable,
+    mut tracer: Option<&mut dyn Tracer>,
+    entry_point: u64,
+    stack_end: u64,
+) {
+    let pid = info.pid;
+    let mut context = Context {
+        rip: entry_point,
+        rsp: stack_end,
+        rax: 0,
+    };
+    let mut signals = Signals::new();
+    // Page table to run the process on, if isolated
+    let user_cr3 = physmap::user_cr3();
+    // Start of the syscall being returned from, if any
+    let mut syscall_start = None;
+    // Whether the process was asked to terminate for a shutdown
+    let mut shutdown_sent = false;
+    // Kinds of objects used, see `allowed`
+    let mut used = 0;
+    boot_profile::user_entry();
     loop {
+        if let Some(start) = syscall_start {
+            latency::finish(LatencySource::Syscall, start);
+            trace::record(TraceKind::SyscallExit, context.rax);
+        }
+        if jobs::stopped(pid) {
+            park(pid);
+        }
+        if shutdown::requested() && !shutdown_sent {
+            shutdown_sent = true;
+            if signals.send(Signal::Terminate) {
+                log::info!("Process {} terminated for shutdown", pid);
+                return;
+            }
+        }
+        if jobs::take_interrupt(pid) && signals.send(Signal::Terminate) {
+            log::info!("Process {} terminated by keyboard interrupt", pid);
+            return;
+        }
+        if shutdown::expired() {
+            log::warn!("Process {} killed after shutdown grace period", pid);
+            return;
+        }
+        // Argument of a signal handler switched to
+        let arg = signals.deliver(&mut context).unwrap_or(0);
         let code: u64;
         let rsi: u64;
         let rdx: u64;
+        let entered = trace::timestamp();
         asm!(
-            "mov [{}], rsp; mov rsp, {}; sysretq; return_syscall:",
+            "mov [{}], rsp",
+            "test {cr3}, {cr3}",
+            "jz 2f",
+            "mov cr3, {cr3}",
+            "2:",
+            "mov rsp, {}; sysretq; return_syscall:",
             in(reg) &STACK,
-            in(reg) rsp,
+            in(reg) context.rsp,
+            cr3 = in(reg) user_cr3,
             // rip is read from rcx
-            inout("rcx") rip,
+            inout("rcx") context.rip,
             // rflags is read from r11
             inlateout("r11") 0x0212 => _,
             // The rest is not preserved
-            inlateout("rax") rax => rsp,
+            inlateout("rax") context.rax => context.rsp,
             lateout("rdx") rdx,
             lateout("rsi") rsi,
-            lateout("rdi") code,
+            inlateout("rdi") arg => code,
             lateout("r8") _,
             lateout("r9") _,
             lateout("r10") _,
@@ -76,69 +452,412 @@ unsafe fn syscall_loop(init: &mut Init, entry_point: u64, stack_end: u64) {
             lateout("r14") _,
             lateout("r15") _,
         );
-        rax = 0;
+        info.cpu_time_ns += time::nanoseconds(trace::timestamp() - entered);
+        watchdog::touch();
+        if code == fault::CODE {
+            match Report::take(pid, &init.address_space) {
+                Some(report) => {
+                    log::error!("{}", report);
+                    if let Some(tracer) = &mut tracer {
+                        tracer.fault(&mut Tracee::new(pid, &mut context, init), &report);
+                    }
+                    core_dump::write(init, &report);
+                }
+                None if shutdown::expired() => {
+                    log::warn!("Process {} killed after shutdown grace period", pid)
+                }
+                None => log::error!("Process {} faulted", pid),
+            }
+            return;
+        }
+        info.syscalls += 1;
+        syscall_start = Some(latency::start());
+        trace::record(TraceKind::SyscallEnter, code);
+        context.rax = 0;
+        let mut syscall = Syscall { code, rsi, rdx };
+        if let Some(tracer) = &mut tracer {
+            match tracer.syscall(&mut Tracee::new(pid, &mut context, init), &mut syscall) {
+                Action::Continue => {}
+                Action::Skip => continue,
+                Action::Kill => {
+                    log::info!("Process {} killed by its tracer", pid);
+                    return;
+                }
+            }
+        }
+        let Syscall { code, rsi, rdx } = syscall;
+        let exempt = [SyscallCode::Exit as u64, SyscallCode::SignalReturn as u64];
+        if !exempt.contains(&code) && inject::fail(Site::Syscall) {
+            context.rax = 1;
+            continue;
+        }
+        if !allowed(pid, &handles, code, &mut used) {
+            context.rax = 1;
+            continue;
+        }
         match code {
             x if x == SyscallCode::Exit as u64 => {
                 log::info!("User exited with code {}", rsi);
                 return;
             }
             x if x == SyscallCode::Log as u64 => {
-                // TODO add checks for pointer and length
-                let s = slice::from_raw_parts(rsi as _, rdx as _);
-                match str::from_utf8(s) {
-                    Ok(s) => log::info!("User message: {}", s),
+                let message = UserSlice::new(rsi, rdx as usize);
+                match str::from_utf8(user!(context, pid, message.get(init, pid))) {
+                    Ok(s) => {
+                        log::info!("User message: {}", s);
+                        console::user_message(s);
+                    }
                     Err(_) => {
                         log::warn!("User message not valid UTF-8");
-                        rax = 1;
+                        context.rax = 1;
                     }
                 }
             }
             x if x == SyscallCode::FrameBuffer as u64 => {
-                if let Some(fb) = &init.boot_info.fb {
-                    if let Some(format) = match fb.info.pixel_format() {
-                        gop::PixelFormat::Rgb => Some(sys::PixelFormat::Rgb),
-                        gop::PixelFormat::Bgr => Some(sys::PixelFormat::Bgr),
-                        _ => None,
-                    } {
-                        let start = PhysAddr::new((fb.ptr as usize - offset::USIZE) as u64);
-                        let start_frame = PhysFrame::<Size4KiB>::containing_address(start);
-                        let virt_start =
-                            VirtAddr::new(0x7000000 + (start - start_frame.start_address()));
-                        if init.page_table.translate_addr(virt_start).is_none() {
-                            for (i, frame) in PhysFrame::range_inclusive(
-                                start_frame,
-                                PhysFrame::containing_address(start + (fb.size - 1)),
-                            )
-                            .enumerate()
-                            {
-                                let page = Page::containing_address(virt_start) + i as u64;
-                                let flags = PageTableFlags::PRESENT
-                                    | PageTableFlags::WRITABLE
-                                    | PageTableFlags::USER_ACCESSIBLE;
-                                log::trace!("Mapping {:?} to {:?}", page, frame);
-                                init.page_table
-                                    .map_to(page, frame, flags, &mut init.frame_allocator)
-                                    .unwrap()
-                                    .flush();
-                            }
+                let access = match rdx {
+                    x if x == FrameBufferAccess::Exclusive as u64 => FrameBufferAccess::Exclusive,
+                    x if x == FrameBufferAccess::Shared as u64 => FrameBufferAccess::Shared,
+                    _ => {
+                        log::warn!("Invalid frame buffer access {}", rdx);
+                        context.rax = 1;
+                        continue;
+                    }
+                };
+                match framebuffer::request(init, pid, access) {
+                    Some(fb) => user!(context, pid, UserPtr::new(rsi).write(init, pid, fb)),
+                    None => context.rax = 1,
+                }
+            }
+            x if x == SyscallCode::FrameBufferRelease as u64 => {
+                if !framebuffer::release(init, pid) {
+                    context.rax = 1;
+                }
+            }
+            x if x == SyscallCode::FbWaitVsync as u64 => {
+                if !framebuffer::wait_vsync(pid) {
+                    context.rax = 1;
+                }
+            }
+            x if x == SyscallCode::FbSetMode as u64 => {
+                let resolution = ((rdx >> 32) as u32, rdx as u32);
+                match framebuffer::set_mode(init, pid, resolution) {
+                    Some(fb) => user!(context, pid, UserPtr::new(rsi).write(init, pid, fb)),
+                    None => context.rax = 1,
+                }
+            }
+            x if x == SyscallCode::FbPresent as u64 => {
+                if !framebuffer::present(pid) {
+                    context.rax = 1;
+                }
+            }
+            x if x == SyscallCode::Screenshot as u64 => {
+                if !framebuffer::screenshot(pid) {
+                    context.rax = 1;
+                }
+            }
+            x if x == SyscallCode::InputEvent as u64 => match input::pop(pid) {
+                Some(event) => user!(context, pid, UserPtr::new(rsi).write(init, pid, event)),
+                None => context.rax = 1,
+            },
+            x if x == SyscallCode::InputFocus as u64 => {
+                let focus = input::focus().unwrap_or(0);
+                user!(context, pid, UserPtr::new(rsi).write(init, pid, focus));
+            }
+            x if x == SyscallCode::InputSetFocus as u64 => {
+                let target = if rsi == 0 { pid } else { rsi };
+                if !input::set_focus(pid, target) {
+                    context.rax = 1;
+                }
+            }
+            x if x == SyscallCode::ReadKey as u64 => match input::read_key(pid) {
+                Some(c) => user!(context, pid, UserPtr::new(rsi).write(init, pid, c as u32)),
+                None => context.rax = 1,
+            },
+            x if x == SyscallCode::ReadLine as u64 => {
+                let len = UserPtr::new(rdx);
+                let mut buf = UserSlice::<u8>::new(rsi, user!(context, pid, len.read(init, pid)));
+                match input::read_line(pid, user!(context, pid, buf.get_mut(init, pid))) {
+                    Some(read) => user!(context, pid, len.write(init, pid, read)),
+                    None => context.rax = 1,
+                }
+            }
+            x if x == SyscallCode::AudioSubmit as u64 => {
+                let samples = UserSlice::<i16>::new(rsi, rdx as usize);
+                if !drivers::ac97::submit(user!(context, pid, samples.get(init, pid))) {
+                    context.rax = 1;
+                }
+            }
+            x if x == SyscallCode::TraceDrain as u64 => {
+                let len = UserPtr::new(rdx);
+                let mut buf =
+                    UserSlice::<TraceRecord>::new(rsi, user!(context, pid, len.read(init, pid)));
+                match trace::drain(user!(context, pid, buf.get_uninit(init, pid))) {
+                    Some(count) => user!(context, pid, len.write(init, pid, count)),
+                    None => context.rax = 1,
+                }
+            }
+            x if x == SyscallCode::AuditDrain as u64 => {
+                let len = UserPtr::new(rdx);
+                let mut buf =
+                    UserSlice::<AuditRecord>::new(rsi, user!(context, pid, len.read(init, pid)));
+                let count = audit::drain(user!(context, pid, buf.get_uninit(init, pid)));
+                user!(context, pid, len.write(init, pid, count));
+            }
+            x if x == SyscallCode::Latency as u64 => {
+                let source = match rsi {
+                    x if x == LatencySource::Interrupt as u64 => LatencySource::Interrupt,
+                    x if x == LatencySource::Syscall as u64 => LatencySource::Syscall,
+                    _ => {
+                        log::warn!("Invalid latency source {}", rsi);
+                        context.rax = 1;
+                        continue;
+                    }
+                };
+                match latency::histogram(source) {
+                    Some(histogram) => {
+                        user!(context, pid, UserPtr::new(rdx).write(init, pid, histogram))
+                    }
+                    None => context.rax = 1,
+                }
+            }
+            x if x == SyscallCode::Kill as u64 => {
+                let signal = match rdx {
+                    x if x == Signal::Kill as u64 => Signal::Kill,
+                    x if x == Signal::Terminate as u64 => Signal::Terminate,
+                    _ => {
+                        log::warn!("Invalid signal {}", rdx);
+                        context.rax = 1;
+                        continue;
+                    }
+                };
+                // Only the calling process is running
+                let target = if rsi == 0 { pid } else { rsi };
+                audit::record(AuditKind::Kill, pid, target, target == pid);
+                if target != pid {
+                    log::warn!("No process {} to send {:?} to", target, signal);
+                    context.rax = 1;
+                    continue;
+                }
+                if signals.send(signal) {
+                    log::info!("Process {} terminated by {:?}", pid, signal);
+                    return;
+                }
+            }
+            x if x == SyscallCode::SignalHandler as u64 => {
+                if let Err(e) = signals.set_handler(rsi, rdx) {
+                    log::warn!("Process {} passed invalid signal handler: {}", pid, e);
+                    context.rax = 1;
+                }
+            }
+            x if x == SyscallCode::SignalReturn as u64 => {
+                if !signals.restore(&mut context) {
+                    log::warn!("Signal return outside of signal handler");
+                    context.rax = 1;
+                }
+            }
+            x if x == SyscallCode::HandleFind as u64 => {
+                let kind = match rsi {
+                    x if x == ObjectKind::FrameBuffer as u64 => ObjectKind::FrameBuffer,
+                    x if x == ObjectKind::Audio as u64 => ObjectKind::Audio,
+                    x if x == ObjectKind::Input as u64 => ObjectKind::Input,
+                    x if x == ObjectKind::HostFs as u64 => ObjectKind::HostFs,
+                    x if x == ObjectKind::AuditLog as u64 => ObjectKind::AuditLog,
+                    x if x == ObjectKind::Power as u64 => ObjectKind::Power,
+                    x if x == ObjectKind::Trace as u64 => ObjectKind::Trace,
+                    _ => {
+                        log::warn!("Invalid object kind {}", rsi);
+                        context.rax = 1;
+                        continue;
+                    }
+                };
+                match handles.find(kind) {
+                    Some(handle) => user!(context, pid, UserPtr::new(rdx).write(init, pid, handle)),
+                    None => context.rax = 1,
+                }
+            }
+            x if x == SyscallCode::HandleRights as u64 => match handles.get(rsi) {
+                Some(handle) => user!(
+                    context,
+                    pid,
+                    UserPtr::new(rdx).write(init, pid, handle.rights)
+                ),
+                None => context.rax = 1,
+            },
+            x if x == SyscallCode::HandleRestrict as u64 => {
+                if let Err(e) = handles.restrict(rsi, Rights(rdx as u32)) {
+                    log::warn!("{}", e);
+                    context.rax = 1;
+                }
+            }
+            x if x == SyscallCode::HandleGrant as u64 => {
+                let result = handle::grant(pid, &handles, rsi, Rights(rdx as u32));
+                let arg = rsi << 32 | rdx as u32 as u64;
+                audit::record(AuditKind::Grant, pid, arg, result.is_ok());
+                if let Err(e) = result {
+                    log::warn!("Failed to grant handle {}: {}", rsi, e);
+                    context.rax = 1;
+                }
+            }
+            x if x == SyscallCode::HandleClose as u64 => {
+                if let Err(e) = handles.close(rsi) {
+                    log::warn!("{}", e);
+                    context.rax = 1;
+                }
+            }
+            x if x == SyscallCode::Shutdown as u64 => shutdown::request(),
+            x if x == SyscallCode::Telemetry as u64 => {
+                user!(
+                    context,
+                    pid,
+                    UserPtr::new(rsi).write(init, pid, telemetry::readings())
+                )
+            }
+            x if x == SyscallCode::Suspend as u64 => {
+                if let Err(e) = acpi::sleep::suspend() {
+                    log::warn!("{}", e);
+                    context.rax = 1;
+                }
+            }
+            x if x == SyscallCode::HostRead as u64 => {
+                let request: HostRead = user!(context, pid, UserPtr::new(rsi).read(init, pid));
+                let path = UserSlice::new(request.path as u64, request.path_len);
+                let path = user!(context, pid, path.get(init, pid));
+                // Copied before the buffer is borrowed, which it may overlap
+                let mut path_copy = [0; MAX_HOST_PATH];
+                let path = match path_copy.get_mut(..path.len()) {
+                    Some(copy) => {
+                        copy.copy_from_slice(path);
+                        &*copy
+                    }
+                    None => {
+                        log::warn!("Host path of process {} too long", pid);
+                        context.rax = 1;
+                        continue;
+                    }
+                };
+                let mut buf = UserSlice::new(request.buf as u64, request.buf_len);
+                let buf = user!(context, pid, buf.get_mut(init, pid));
+                let result = str::from_utf8(path)
+                    .map_err(|_| "Path not valid UTF-8")
+                    .and_then(|path| drivers::virtio::ninep::read(path, request.offset, buf));
+                match result {
+                    Ok(read) => user!(context, pid, UserPtr::new(rdx).write(init, pid, read)),
+                    Err(e) => {
+                        log::warn!("Failed to read host file: {}", e);
+                        context.rax = 1;
+                    }
+                }
+            }
+            x if x == SyscallCode::TestReport as u64 => {
+                log::info!("Process {} passed {} tests, failed {}", pid, rsi, rdx);
+                #[cfg(test)]
+                crate::test::report(rsi, rdx);
+            }
+            x if x == SyscallCode::ProcessList as u64 => {
+                // Only the calling process is running
+                let len = UserPtr::new(rdx);
+                let mut buf =
+                    UserSlice::<ProcessInfo>::new(rsi, user!(context, pid, len.read(init, pid)));
+                let buf = user!(context, pid, buf.get_uninit(init, pid));
+                info.pages = init.address_space.usage(pid);
+                if let Some(first) = buf.first_mut() {
+                    first.write(info);
+                }
+                let count = buf.len().min(1);
+                user!(context, pid, len.write(init, pid, count));
+            }
+            x if x == SyscallCode::MemoryStats as u64 => {
+                let stats = allocator::memory_stats(&init.frame_allocator);
+                user!(context, pid, UserPtr::new(rsi).write(init, pid, stats));
+            }
+            x if x == SyscallCode::HostWrite as u64 => {
+                let request: HostWrite = user!(context, pid, UserPtr::new(rsi).read(init, pid));
+                let path = UserSlice::new(request.path as u64, request.path_len);
+                let path = user!(context, pid, path.get(init, pid));
+                let data = UserSlice::new(request.buf as u64, request.buf_len);
+                let data = user!(context, pid, data.get(init, pid));
+                let result = str::from_utf8(path)
+                    .map_err(|_| "Path not valid UTF-8")
+                    .and_then(|path| drivers::virtio::ninep::write(path, data));
+                if let Err(e) = result {
+                    log::warn!("Failed to write host file: {}", e);
+                    context.rax = 1;
+                }
+            }
+            x if x == SyscallCode::SetProcessGroup as u64 => {
+                if let Err(e) = jobs::join(pid, rsi) {
+                    log::warn!("Failed to set process group: {}", e);
+                    context.rax = 1;
+                }
+            }
+            x if x == SyscallCode::SetForeground as u64 => {
+                if let Err(e) = jobs::set_foreground(pid, rsi) {
+                    log::warn!("Failed to set foreground process group: {}", e);
+                    context.rax = 1;
+                }
+            }
+            x if x == SyscallCode::Stop as u64 => {
+                let target = if rsi == 0 { pid } else { rsi };
+                if let Err(e) = jobs::stop(pid, target) {
+                    log::warn!("Failed to stop process {}: {}", target, e);
+                    context.rax = 1;
+                }
+            }
+            x if x == SyscallCode::Continue as u64 => {
+                if let Err(e) = jobs::resume(pid, rsi) {
+                    log::warn!("Failed to continue process {}: {}", rsi, e);
+                    context.rax = 1;
+                }
+            }
+            x if x == SyscallCode::Mmap as u64 => match mmap::map(init, pid, rsi) {
+                Ok(addr) => {
+                    let written = UserPtr::new(rdx).write(init, pid, addr);
+                    if written.is_err() {
+                        // The process could not unmap memory it does not know of
+                        if let Err(e) = mmap::unmap(init, pid, addr) {
+                            log::error!("Failed to unmap memory of process {}: {}", pid, e);
                         }
-                        (rsi as *mut FrameBuffer).write(FrameBuffer {
-                            ptr: virt_start.as_mut_ptr(),
-                            size: fb.size,
-                            shape: fb.info.resolution(),
-                            stride: fb.info.stride(),
-                            format,
-                        });
-                    } else {
-                        rax = 1;
                     }
-                } else {
-                    rax = 1;
+                    user!(context, pid, written)
+                }
+                Err(e) => {
+                    log::warn!("Failed to map memory for process {}: {}", pid, e);
+                    context.rax = 1;
+                }
+            },
+            x if x == SyscallCode::Munmap as u64 => {
+                if let Err(e) = mmap::unmap(init, pid, rsi) {
+                    log::warn!("Failed to unmap memory of process {}: {}", pid, e);
+                    context.rax = 1;
+                }
+            }
+            x if x == SyscallCode::Spawn as u64 => {
+                let len = UserPtr::<u64>::new(rdx);
+                let name = UserSlice::new(rsi, user!(context, pid, len.read(init, pid)) as usize);
+                let result = str::from_utf8(user!(context, pid, name.get(init, pid)))
+                    .map_err(|_| "Name not valid UTF-8")
+                    .and_then(|name| spawn_pending(pid, name));
+                match result {
+                    Ok(child) => user!(context, pid, len.write(init, pid, child)),
+                    Err(e) => {
+                        log::warn!("Process {} failed to spawn: {}", pid, e);
+                        context.rax = 1;
+                    }
+                }
+            }
+            x if x == SyscallCode::Sleep as u64 => {
+                if !time::sleep(rsi) {
+                    context.rax = 1;
                 }
             }
             _ => {
-                log::warn!("Ignoring unknown syscall {}", code as u64);
-                rax = 1
+                common::log_rate_limited!(
+                    10,
+                    log::Level::Warn,
+                    "Ignoring unknown syscall {}",
+                    code as u64
+                );
+                context.rax = 1
             }
         }
     }
@@ -146,7 +865,15 @@ unsafe fn syscall_loop(init: &mut Init, entry_point: u64, stack_end: u64) {
 
 unsafe extern "C" fn syscall_handler() {
     asm!(
-        "pop rax; mov rax, rsp; mov rsp, [{}]; jmp return_syscall",
+        "pop rax",
+        // Switch to the kernel page table if the process runs on its own
+        "mov r8, [rip + {}]",
+        "test r8, r8",
+        "jz 2f",
+        "mov cr3, r8",
+        "2:",
+        "mov rax, rsp; mov rsp, [{}]; jmp return_syscall",
+        sym physmap::KERNEL_CR3,
         in(reg) &STACK,
         // The pop is just to realign the stack since this function isn't naked
         out("rax") _,
@@ -154,19 +881,159 @@ unsafe extern "C" fn syscall_handler() {
         out("rdx") _,
         out("rsi") _,
         out("rdi") _,
+        out("r8") _,
+    );
+}
+
+/// Entered in kernel mode on the stack of the system call loop instead of
+/// returning to a process that caused an exception, see [`fault`]
+///
+/// Saves the registers of the process and continues the system call loop with
+/// [`fault::CODE`].
+pub unsafe extern "C" fn fault_handler() {
+    asm!(
+        "mov [rip + {regs}], rax",
+        "mov [rip + {regs} + 0x08], rbx",
+        "mov [rip + {regs} + 0x10], rcx",
+        "mov [rip + {regs} + 0x18], rdx",
+        "mov [rip + {regs} + 0x20], rsi",
+        "mov [rip + {regs} + 0x28], rdi",
+        "mov [rip + {regs} + 0x30], rbp",
+        "mov [rip + {regs} + 0x38], r8",
+        "mov [rip + {regs} + 0x40], r9",
+        "mov [rip + {regs} + 0x48], r10",
+        "mov [rip + {regs} + 0x50], r11",
+        "mov [rip + {regs} + 0x58], r12",
+        "mov [rip + {regs} + 0x60], r13",
+        "mov [rip + {regs} + 0x68], r14",
+        "mov [rip + {regs} + 0x70], r15",
+        "mov rax, [rip + {kernel_cr3}]",
+        "test rax, rax",
+        "jz 2f",
+        "mov cr3, rax",
+        "2:",
+        "mov rsp, [rip + {stack}]",
+        // fault::CODE
+        "mov rdi, -1",
+        "jmp return_syscall",
+        regs = sym fault::REGISTERS,
+        kernel_cr3 = sym physmap::KERNEL_CR3,
+        stack = sym STACK,
+        options(noreturn),
     );
 }
 
 #[cfg(test)]
 mod tests {
     use super::*;
+    use core::mem::MaybeUninit;
 
     #[test_case]
     fn dummy() {
         let mut guard = crate::test::INIT.lock();
         let init = guard.as_mut().unwrap();
         for _ in 0..10 {
-            unsafe { spawn_user(init, &crate::USER.info(true).unwrap()) };
+            let handles = HandleTable::devices();
+            unsafe { spawn_user(init, programs::INIT, Limits::default(), handles) }.unwrap();
+        }
+    }
+
+    #[test_case]
+    fn memory_limit() {
+        let mut guard = crate::test::INIT.lock();
+        let init = guard.as_mut().unwrap();
+        let limits = Limits { pages: 1 };
+        let handles = HandleTable::devices();
+        assert!(unsafe { spawn_user(init, programs::INIT, limits, handles.clone()) }.is_err());
+        // Nothing is left mapped
+        unsafe { spawn_user(init, programs::INIT, Limits::default(), handles) }.unwrap();
+    }
+
+    #[test_case]
+    fn without_handles() {
+        let mut guard = crate::test::INIT.lock();
+        let init = guard.as_mut().unwrap();
+        let handles = HandleTable::new();
+        unsafe { spawn_user(init, programs::INIT, Limits::default(), handles) }.unwrap();
+    }
+
+    #[test_case]
+    fn spawn_after_exit() {
+        let mut guard = crate::test::INIT.lock();
+        let init = guard.as_mut().unwrap();
+        // Grants are kept when spawning fails and go to the spawned process
+        let mut table = HandleTable::new();
+        let handle = table.insert(ObjectKind::Input, Rights::READ).unwrap();
+        handle::grant(0, &table, handle, Rights::READ).unwrap();
+        assert!(spawn_pending(0, "").is_err());
+        let pid = spawn_pending(0, programs::INIT).unwrap();
+        assert!(PENDING.lock()[0]
+            .handles
+            .allows(ObjectKind::Input, Rights::READ));
+        let mut buf = alloc::vec![MaybeUninit::uninit(); 256];
+        while audit::drain(&mut buf) > 0 {}
+        let handles = HandleTable::devices();
+        unsafe { spawn_user(init, programs::INIT, Limits::default(), handles) }.unwrap();
+        assert!(PENDING.lock().is_empty());
+        // The spawned process ran after the spawning one
+        let count = audit::drain(&mut buf);
+        let spawns: Vec<_> = buf[..count]
+            .iter()
+            .map(|record| unsafe { record.assume_init() })
+            .filter(|record| record.kind == AuditKind::Spawn)
+            .map(|record| (record.pid, record.allowed))
+            .collect();
+        assert_eq!(spawns, [(pid + 1, true), (pid, true)]);
+
+        // Only a few processes can wait to run
+        for _ in 0..MAX_PENDING {
+            spawn_pending(0, programs::INIT).unwrap();
         }
+        assert!(spawn_pending(0, programs::INIT).is_err());
+        PENDING.lock().clear();
+    }
+
+    #[test_case]
+    fn audit_device_access() {
+        // Process that never runs, to tell its records apart
+        const PID: u64 = u64::MAX;
+        let mut buf = [MaybeUninit::uninit(); 16];
+        while audit::drain(&mut buf) > 0 {}
+        let mut handles = HandleTable::new();
+        handles.insert(ObjectKind::Input, Rights::READ).unwrap();
+        let mut used = 0;
+        let read = SyscallCode::InputEvent as u64;
+        assert!(allowed(PID, &handles, read, &mut used));
+        assert!(allowed(PID, &handles, read, &mut used));
+        let write = SyscallCode::InputSetFocus as u64;
+        assert!(!allowed(PID, &handles, write, &mut used));
+        assert!(allowed(PID, &handles, SyscallCode::Log as u64, &mut used));
+        // Only init can shut down or suspend the system
+        let shutdown = SyscallCode::Shutdown as u64;
+        assert!(!allowed(PID, &handles, shutdown, &mut used));
+        assert!(allowed(PID, &HandleTable::devices(), shutdown, &mut used));
+        let suspend = SyscallCode::Suspend as u64;
+        assert!(!allowed(PID, &handles, suspend, &mut used));
+        let count = audit::drain(&mut buf);
+        let records: Vec<_> = buf[..count]
+            .iter()
+            .map(|record| unsafe { record.assume_init() })
+            .filter(|record| record.pid == PID)
+            .map(|record| (record.kind, record.allowed, record.arg))
+            .collect();
+        let input = (ObjectKind::Input as u64) << 32;
+        let power = (ObjectKind::Power as u64) << 32 | Rights::WRITE.0 as u64;
+        let expected = [
+            (AuditKind::DeviceAccess, true, input | Rights::READ.0 as u64),
+            (
+                AuditKind::DeviceAccess,
+                false,
+                input | Rights::WRITE.0 as u64,
+            ),
+            (AuditKind::DeviceAccess, false, power),
+            (AuditKind::DeviceAccess, true, power),
+            (AuditKind::DeviceAccess, false, power),
+        ];
+        assert_eq!(records, expected);
     }
 }
@@ -0,0 +1,184 @@
+//! Time shared with user processes
+//!
+//! The time stamp counter is calibrated against the PIT, and a page describing
+//! it is mapped read-only into every process at [`TIME_PAGE_ADDR`], so processes
+//! can read the time without a system call. The timer interrupt moves the
+//! epoch of the page forward with [`tick`], so processes only convert short
+//! intervals of the time stamp counter.
+
+use crate::{
+    idle, interrupts, shutdown, trace,
+    vm::{AddressSpace, Backing},
+    watchdog,
+};
+use common::boot::offset;
+use core::{
+    ptr,
+    sync::atomic::{compiler_fence, Ordering},
+};
+use spin::Once;
+use sys::{TimePage, TIME_PAGE_ADDR};
+use x86_64::{
+    structures::paging::{FrameAllocator, Page, PageTableFlags, PhysFrame, Size4KiB},
+    VirtAddr,
+};
+
+/// Number of timer interrupts to calibrate the time stamp counter against
+const CALIBRATION_TICKS: u64 = 10;
+
+/// Frame holding the [`TimePage`]
+static FRAME: Once<PhysFrame> = Once::new();
+
+fn page() -> Option<*mut TimePage> {
+    let frame = FRAME.get()?;
+    Some(offset::phys_to_virt(frame.start_address()).as_mut_ptr())
+}
+
+/// Calibrate the time stamp counter and set up the time page
+///
+/// The timer should be running.
+pub fn init<A>(all: &mut A) -> Result<(), &'static str>
+where
+    A: FrameAllocator<Size4KiB>,
+{
+    let wait_ticks = |ticks| {
+        let start = interrupts::ticks();
+        while interrupts::ticks() < start + ticks {
+            x86_64::instructions::hlt();
+        }
+    };
+    // Start right after a timer interrupt
+    wait_ticks(1);
+    let start = trace::timestamp();
+    wait_ticks(CALIBRATION_TICKS);
+    let tsc = trace::timestamp();
+    let tsc_frequency = (tsc - start) * interrupts::TIMER_FREQUENCY as u64 / CALIBRATION_TICKS;
+    log::info!("Time stamp counter runs at {} kHz", tsc_frequency / 1000);
+
+    let frame = all.allocate_frame().ok_or("No frame allocated")?;
+    let time = TimePage {
+        sequence: 0,
+        tsc_frequency,
+        epoch_tsc: tsc,
+        epoch_ns: 0,
+    };
+    let ptr = offset::phys_to_virt(frame.start_address()).as_mut_ptr::<u8>();
+    unsafe {
+        ptr.write_bytes(0, 0x1000);
+        ptr.cast::<TimePage>().write_volatile(time);
+    }
+    FRAME.call_once(|| frame);
+    Ok(())
+}
+
+/// Move the epoch of the time page to now; called by the timer interrupt
+pub fn tick() {
+    let page = match page() {
+        Some(page) => page,
+        None => return,
+    };
+    let tsc = trace::timestamp();
+    // Only the timer interrupt writes, so the page can't change meanwhile
+    let mut time = unsafe { page.read_volatile() };
+    let elapsed = (tsc - time.epoch_tsc) as u128 * 1_000_000_000 / time.tsc_frequency as u128;
+    let sequence = unsafe { ptr::addr_of_mut!((*page).sequence) };
+    unsafe { sequence.write_volatile(time.sequence + 1) };
+    compiler_fence(Ordering::Release);
+    time.epoch_ns += elapsed as u64;
+    time.epoch_tsc = tsc;
+    time.sequence += 1;
+    unsafe { page.write_volatile(time) };
+    compiler_fence(Ordering::Release);
+    unsafe { sequence.write_volatile(time.sequence + 1) };
+}
+
+/// Continue the time page from the current time stamp counter after a sleep
+/// state, which resets it; time spent asleep is not counted
+pub fn resume() {
+    let page = match page() {
+        Some(page) => page,
+        None => return,
+    };
+    let mut time = unsafe { page.read_volatile() };
+    let sequence = unsafe { ptr::addr_of_mut!((*page).sequence) };
+    unsafe { sequence.write_volatile(time.sequence + 1) };
+    compiler_fence(Ordering::Release);
+    time.epoch_tsc = trace::timestamp();
+    time.sequence += 2;
+    unsafe { page.write_volatile(time) };
+}
+
+/// Convert a number of cycles of the time stamp counter to nanoseconds, or
+/// zero if it is not calibrated
+pub fn nanoseconds(cycles: u64) -> u64 {
+    match page() {
+        Some(page) => {
+            let frequency = unsafe { ptr::addr_of!((*page).tsc_frequency).read_volatile() };
+            (cycles as u128 * 1_000_000_000 / frequency as u128) as u64
+        }
+        None => 0,
+    }
+}
+
+/// Wait for at least `ns` nanoseconds, idling between interrupts
+///
+/// Returns `false` early if a shutdown is requested.
+pub fn sleep(ns: u64) -> bool {
+    let start = trace::timestamp();
+    while nanoseconds(trace::timestamp() - start) < ns {
+        if shutdown::requested() {
+            return false;
+        }
+        watchdog::touch();
+        idle::wait();
+    }
+    true
+}
+
+/// Map the time page read-only for process `pid`
+pub fn map<A>(address_space: &mut AddressSpace, pid: u64, all: &mut A) -> Result<(), &'static str>
+where
+    A: FrameAllocator<Size4KiB>,
+{
+    let frame = *FRAME.get().ok_or("Time page not initialized")?;
+    let page = Page::containing_address(VirtAddr::new(TIME_PAGE_ADDR));
+    let flags = PageTableFlags::PRESENT | PageTableFlags::USER_ACCESSIBLE;
+    // The frame is only written by the kernel, through the offset mapping
+    unsafe {
+        address_space.map_frames(
+            Page::range(page, page + 1),
+            frame,
+            flags,
+            Backing::Time,
+            Some(pid),
+            all,
+        )
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test_case]
+    fn epoch_advances() {
+        let page = page().unwrap();
+        let before = unsafe { page.read_volatile() };
+        let start = interrupts::ticks();
+        while interrupts::ticks() < start + 2 {
+            x86_64::instructions::hlt();
+        }
+        let after = unsafe { page.read_volatile() };
+        assert!(after.epoch_ns > before.epoch_ns);
+        assert!(after.epoch_tsc > before.epoch_tsc);
+        assert_eq!(after.sequence % 2, 0);
+    }
+
+    #[test_case]
+    fn sleep() {
+        let start = trace::timestamp();
+        assert!(super::sleep(2_000_000));
+        assert!(nanoseconds(trace::timestamp() - start) >= 2_000_000);
+        assert!(super::sleep(0));
+    }
+}
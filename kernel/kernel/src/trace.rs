@@ -0,0 +1,234 @@
+//! Tracing of kernel events into per-CPU ring buffers
+//!
+//! Tracepoints record a [`TraceRecord`] stamped with the time stamp counter in
+//! the buffer of the current CPU, overwriting the oldest record when full.
+//! Tracing is enabled with the `trace` option of the kernel configuration;
+//! otherwise tracepoints do nothing. Records are drained by user processes or
+//! printed over the serial port by [`dump`].
+
+use crate::{base64::Encoder, config, interrupts};
+use common::println;
+use core::{
+    arch::x86_64::_rdtsc,
+    mem::{self, MaybeUninit},
+    sync::atomic::{AtomicU64, Ordering},
+};
+use spin::Mutex;
+use sys::{TraceKind, TraceRecord};
+use x86_64::instructions::interrupts::without_interrupts;
+
+/// Line preceding the encoded dump
+pub const BEGIN: &str = "-----BEGIN TRACE-----";
+/// Line following the encoded dump
+pub const END: &str = "-----END TRACE-----";
+
+/// Start of a dump, identifying its format
+const MAGIC: &[u8; 4] = b"ATRC";
+/// Version of the dump format
+const VERSION: u32 = 1;
+
+/// Number of records per CPU
+const CAPACITY: usize = 4096;
+/// Number of CPUs with a buffer; events on other CPUs are not recorded
+const MAX_CPUS: usize = 4;
+
+static BUFFERS: [Mutex<Ring>; MAX_CPUS] = {
+    const EMPTY: Mutex<Ring> = Mutex::new(Ring::new());
+    [EMPTY; MAX_CPUS]
+};
+
+/// Timer ticks and time stamp counter at initialization, used for calibration
+static START_TICKS: AtomicU64 = AtomicU64::new(0);
+static START_TSC: AtomicU64 = AtomicU64::new(0);
+
+/// Ring buffer of records
+struct Ring {
+    records: [Option<TraceRecord>; CAPACITY],
+    start: usize,
+    len: usize,
+    /// Number of records overwritten since the last dump
+    dropped: u64,
+}
+
+impl Ring {
+    const fn new() -> Self {
+        Self {
+            records: [None; CAPACITY],
+            start: 0,
+            len: 0,
+            dropped: 0,
+        }
+    }
+
+    fn push(&mut self, record: TraceRecord) {
+        if self.len == CAPACITY {
+            self.records[self.start] = Some(record);
+            self.start = (self.start + 1) % CAPACITY;
+            self.dropped += 1;
+            return;
+        }
+        self.records[(self.start + self.len) % CAPACITY] = Some(record);
+        self.len += 1;
+    }
+
+    fn pop(&mut self) -> Option<TraceRecord> {
+        if self.len == 0 {
+            return None;
+        }
+        let i = self.start;
+        self.start = (i + 1) % CAPACITY;
+        self.len -= 1;
+        self.records[i].take()
+    }
+}
+
+/// Current value of the time stamp counter
+pub fn timestamp() -> u64 {
+    unsafe { _rdtsc() }
+}
+
+/// Start calibrating the time stamp counter against the timer, which should
+/// be running
+pub fn init() {
+    if !config::trace() {
+        return;
+    }
+    START_TICKS.store(interrupts::ticks(), Ordering::Relaxed);
+    START_TSC.store(timestamp(), Ordering::Relaxed);
+    log::info!("Tracing enabled");
+}
+
+/// Record an event on the current CPU
+pub fn record(kind: TraceKind, arg: u64) {
+    if !config::trace() {
+        return;
+    }
+    let cpu = interrupts::cpu_id();
+    let record = TraceRecord {
+        timestamp: timestamp(),
+        kind,
+        cpu: cpu as u32,
+        arg,
+    };
+    // Tracepoints are hit in interrupt handlers as well
+    if let Some(buffer) = BUFFERS.get(cpu as usize) {
+        without_interrupts(|| buffer.lock().push(record));
+    }
+}
+
+/// Call `f` with up to `count` of the most recent events on the current CPU,
+/// oldest first, without removing them
+///
+/// Does nothing if the buffer is in use, so it can be called from any context,
+/// including non-maskable interrupts.
+pub fn recent<F: FnMut(&TraceRecord)>(count: usize, mut f: F) {
+    if !config::trace() {
+        return;
+    }
+    let buffer = BUFFERS.get(interrupts::cpu_id() as usize);
+    let ring = match buffer.and_then(|buffer| buffer.try_lock()) {
+        Some(ring) => ring,
+        None => return,
+    };
+    for i in ring.len.saturating_sub(count)..ring.len {
+        if let Some(record) = &ring.records[(ring.start + i) % CAPACITY] {
+            f(record);
+        }
+    }
+}
+
+/// Move recorded events into `buf`, ordered per CPU
+///
+/// Returns the number of events moved, or [`None`] if tracing is disabled.
+pub fn drain(buf: &mut [MaybeUninit<TraceRecord>]) -> Option<usize> {
+    if !config::trace() {
+        return None;
+    }
+    let mut len = 0;
+    for buffer in &BUFFERS {
+        without_interrupts(|| {
+            let mut ring = buffer.lock();
+            while len < buf.len() {
+                match ring.pop() {
+                    Some(record) => {
+                        buf[len].write(record);
+                    }
+                    None => break,
+                }
+                len += 1;
+            }
+        });
+    }
+    Some(len)
+}
+
+/// Move recorded events to the serial port
+///
+/// The dump starts with [`MAGIC`], [`VERSION`] as `u32` and the number of time
+/// stamp counter increments per millisecond as `u64`, followed by the records
+/// laid out as [`TraceRecord`]; all in little endian. It is encoded as base64
+/// and printed between [`BEGIN`] and [`END`] marker lines, which the xtask
+/// runner converts to the Chrome trace event format.
+pub fn dump() {
+    if !config::trace() {
+        return;
+    }
+    let ticks = interrupts::ticks() - START_TICKS.load(Ordering::Relaxed);
+    let tsc = timestamp() - START_TSC.load(Ordering::Relaxed);
+    let tsc_per_ms = (tsc * interrupts::TIMER_FREQUENCY as u64)
+        .checked_div(ticks * 1000)
+        .unwrap_or(0);
+
+    println!("{}", BEGIN);
+    let mut encoder = Encoder::new();
+    encoder.extend(MAGIC);
+    encoder.extend(&VERSION.to_le_bytes());
+    encoder.extend(&tsc_per_ms.to_le_bytes());
+    let mut dropped = 0;
+    for buffer in &BUFFERS {
+        // Lock per record to not block interrupt handlers while printing
+        while let Some(record) = without_interrupts(|| buffer.lock().pop()) {
+            encoder.extend(&record.timestamp.to_le_bytes());
+            encoder.extend(&(record.kind as u32).to_le_bytes());
+            encoder.extend(&record.cpu.to_le_bytes());
+            encoder.extend(&record.arg.to_le_bytes());
+        }
+        dropped += without_interrupts(|| mem::take(&mut buffer.lock().dropped));
+    }
+    encoder.finish();
+    println!("{}", END);
+    if dropped > 0 {
+        log::warn!("Dropped {} trace events as buffers were full", dropped);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn record(arg: u64) -> TraceRecord {
+        TraceRecord {
+            timestamp: arg,
+            kind: TraceKind::ContextSwitch,
+            cpu: 0,
+            arg,
+        }
+    }
+
+    #[test_case]
+    fn ring_overwrites_oldest() {
+        // Too large for the stack
+        static RING: Mutex<Ring> = Mutex::new(Ring::new());
+        let mut ring = RING.lock();
+        for i in 0..CAPACITY as u64 + 2 {
+            ring.push(record(i));
+        }
+        assert_eq!(ring.dropped, 2);
+        assert_eq!(ring.pop(), Some(record(2)));
+        let mut last = None;
+        while let Some(record) = ring.pop() {
+            last = Some(record);
+        }
+        assert_eq!(last, Some(record(CAPACITY as u64 + 1)));
+    }
+}
@@ -0,0 +1,207 @@
+//! Memory of the running process accessed by system calls
+//!
+//! Addresses passed to a system call are wrapped in a [`UserPtr`] or
+//! [`UserSlice`], which check with [`AddressSpace::check_user`] that the
+//! process itself could access the memory before the kernel touches it. An
+//! invalid address thus fails the system call with an error instead of
+//! faulting in the kernel.
+//!
+//! [`AddressSpace::check_user`]: crate::vm::AddressSpace::check_user
+
+use crate::Init;
+use core::{
+    marker::PhantomData,
+    mem::{align_of, size_of, MaybeUninit},
+    slice,
+};
+use sys::{HostRead, HostWrite};
+
+/// Type for which any bytes are a valid value, so it can be read from memory
+/// of a process
+///
+/// # Safety
+/// The type should have no padding, invalid bit patterns or references, which
+/// rules out `bool`, enums and most structures with such fields.
+pub unsafe trait Pod: Copy {}
+
+unsafe impl Pod for u8 {}
+unsafe impl Pod for u64 {}
+unsafe impl Pod for i16 {}
+unsafe impl Pod for usize {}
+unsafe impl Pod for HostRead {}
+unsafe impl Pod for HostWrite {}
+
+/// Check that process `pid` can access `len` values of `T` at `addr`, and
+/// `write` them if asked
+fn check<T>(
+    init: &mut Init,
+    pid: u64,
+    addr: u64,
+    len: usize,
+    write: bool,
+) -> Result<(), &'static str> {
+    if addr % align_of::<T>() as u64 != 0 {
+        return Err("Misaligned pointer");
+    }
+    let size = len.checked_mul(size_of::<T>()).ok_or("Range too large")?;
+    init.address_space
+        .check_user(pid, addr, size as u64, write, &mut init.frame_allocator)
+}
+
+/// Address of a `T` in memory of the running process
+///
+/// Only [`Pod`] values can be read; any value can be written.
+pub struct UserPtr<T> {
+    addr: u64,
+    _marker: PhantomData<*mut T>,
+}
+
+impl<T> UserPtr<T> {
+    pub fn new(addr: u64) -> Self {
+        Self {
+            addr,
+            _marker: PhantomData,
+        }
+    }
+
+    /// Copy `value` to memory of process `pid`
+    pub fn write(&self, init: &mut Init, pid: u64, value: T) -> Result<(), &'static str> {
+        check::<T>(init, pid, self.addr, 1, true)?;
+        unsafe { (self.addr as *mut T).write(value) };
+        Ok(())
+    }
+}
+
+impl<T: Pod> UserPtr<T> {
+    /// Copy the value from memory of process `pid`
+    pub fn read(&self, init: &mut Init, pid: u64) -> Result<T, &'static str> {
+        check::<T>(init, pid, self.addr, 1, false)?;
+        Ok(unsafe { (self.addr as *const T).read() })
+    }
+}
+
+/// Address and length of consecutive values of `T` in memory of the running
+/// process
+///
+/// Only [`Pod`] values can be read; other values can only be written through
+/// [`UserSlice::get_uninit`]. The slices borrowed from it should not outlive
+/// the system call.
+pub struct UserSlice<T> {
+    addr: u64,
+    len: usize,
+    _marker: PhantomData<*mut T>,
+}
+
+impl<T> UserSlice<T> {
+    pub fn new(addr: u64, len: usize) -> Self {
+        Self {
+            addr,
+            len,
+            _marker: PhantomData,
+        }
+    }
+
+    /// Space for the values to be written to, if process `pid` can write it
+    pub fn get_uninit<'a>(
+        &'a mut self,
+        init: &mut Init,
+        pid: u64,
+    ) -> Result<&'a mut [MaybeUninit<T>], &'static str> {
+        if self.len == 0 {
+            return Ok(&mut []);
+        }
+        check::<T>(init, pid, self.addr, self.len, true)?;
+        Ok(unsafe { slice::from_raw_parts_mut(self.addr as *mut MaybeUninit<T>, self.len) })
+    }
+}
+
+impl<T: Pod> UserSlice<T> {
+    /// The values, if process `pid` can read them
+    pub fn get<'a>(&'a self, init: &mut Init, pid: u64) -> Result<&'a [T], &'static str> {
+        if self.len == 0 {
+            return Ok(&[]);
+        }
+        check::<T>(init, pid, self.addr, self.len, false)?;
+        Ok(unsafe { slice::from_raw_parts(self.addr as *const T, self.len) })
+    }
+
+    /// The values, if process `pid` can write them
+    pub fn get_mut<'a>(
+        &'a mut self,
+        init: &mut Init,
+        pid: u64,
+    ) -> Result<&'a mut [T], &'static str> {
+        if self.len == 0 {
+            return Ok(&mut []);
+        }
+        check::<T>(init, pid, self.addr, self.len, true)?;
+        Ok(unsafe { slice::from_raw_parts_mut(self.addr as *mut T, self.len) })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use x86_64::{
+        structures::paging::{Page, PageTableFlags},
+        VirtAddr,
+    };
+
+    /// Process that never runs, owning the pages mapped by [`with_page`]
+    const PID: u64 = u64::MAX;
+
+    /// Run `f` with the address of a page mapped for [`PID`] with `flags`
+    fn with_page<F: FnOnce(&mut Init, u64)>(flags: PageTableFlags, f: F) {
+        let mut guard = crate::test::INIT.lock();
+        let init = guard.as_mut().unwrap();
+        let page = init
+            .address_space
+            .find_free(VirtAddr::new(0x4000_0000), VirtAddr::new(0x8000_0000), 1)
+            .unwrap();
+        init.address_space
+            .map_anonymous(
+                Page::range(page, page + 1),
+                flags | PageTableFlags::PRESENT,
+                Some(PID),
+                &mut init.frame_allocator,
+            )
+            .unwrap();
+        f(init, page.start_address().as_u64());
+        init.address_space
+            .unmap_process(PID, &mut init.frame_allocator);
+    }
+
+    #[test_case]
+    fn writable() {
+        let flags = PageTableFlags::WRITABLE | PageTableFlags::USER_ACCESSIBLE;
+        with_page(flags, |init, addr| {
+            UserPtr::new(addr + 8).write(init, PID, 42u64).unwrap();
+            assert_eq!(UserPtr::<u64>::new(addr + 8).read(init, PID), Ok(42));
+            let mut buf = UserSlice::<u8>::new(addr, 4096);
+            assert_eq!(buf.get_mut(init, PID).map(|buf| buf[8]), Ok(42));
+            assert!(UserSlice::<u8>::new(addr, 4097).get(init, PID).is_err());
+            assert!(UserPtr::<u64>::new(addr + 4).read(init, PID).is_err());
+        });
+    }
+
+    #[test_case]
+    fn read_only() {
+        with_page(PageTableFlags::USER_ACCESSIBLE, |init, addr| {
+            assert!(UserPtr::<u64>::new(addr).read(init, PID).is_ok());
+            assert!(UserPtr::new(addr).write(init, PID, 0u64).is_err());
+            assert!(UserSlice::<u8>::new(addr, 1).get_mut(init, PID).is_err());
+        });
+    }
+
+    #[test_case]
+    fn kernel_only() {
+        with_page(PageTableFlags::WRITABLE, |init, addr| {
+            assert!(UserPtr::<u64>::new(addr).read(init, PID).is_err());
+            let kernel = VirtAddr::from_ptr(&PID).as_u64();
+            assert!(UserPtr::<u64>::new(kernel).read(init, PID).is_err());
+            assert!(UserSlice::<u8>::new(u64::MAX, 2).get(init, PID).is_err());
+            assert!(UserSlice::<u64>::new(0, usize::MAX).get(init, PID).is_err());
+            assert_eq!(UserSlice::<u8>::new(0, 0).get(init, PID), Ok(&[][..]));
+        });
+    }
+}
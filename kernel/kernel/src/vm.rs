@@ -0,0 +1,652 @@
+//! Virtual memory management
+//!
+//! All mappings made by the kernel go through an [`AddressSpace`], which
+//! records what backs each of them and who owns it. This allows checking new
+//! mappings for overlap, unmapping everything a process owns in one go and
+//! listing the mappings for debugging. Segments of executables are mapped on
+//! demand: their descriptors are recorded and [`AddressSpace::populate`] maps
+//! a page once it is first accessed. Pages holding only file contents are
+//! shared with the executable embedded in the kernel and, for writable
+//! segments, copied when first written. Mappings made by the UEFI stub, such as
+//! the kernel itself and the offset mapping of physical memory through which
+//! device registers are accessed, are not recorded.
+
+use crate::audit;
+use common::{
+    boot::offset,
+    elf::{ElfInfo, Segment},
+    error::BootError,
+    tlb::Shootdown,
+};
+use core::{fmt, slice};
+use sys::AuditKind;
+use x86_64::{
+    registers::control::{Cr0, Cr0Flags},
+    structures::paging::{
+        page::PageRange, FrameAllocator, FrameDeallocator, Mapper, OffsetPageTable, Page,
+        PageTableFlags, PhysFrame, Size4KiB, Translate, TranslateResult,
+    },
+    VirtAddr,
+};
+
+/// Maximum number of recorded mappings
+const MAX_MAPPINGS: usize = 64;
+/// Maximum number of processes with a memory limit
+const MAX_LIMITS: usize = 8;
+/// Maximum number of executable segments mapped on demand
+const MAX_SEGMENTS: usize = 16;
+/// End of the lower half of the address space, which processes use
+pub const USER_END: u64 = 0x0000_8000_0000_0000;
+
+/// What backs a mapping
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub enum Backing {
+    /// Segment of an ELF executable, mapped on demand
+    Elf,
+    /// Fresh frames owned by the mapping
+    Anonymous,
+    /// Frame buffer memory
+    FrameBuffer,
+    /// Page shared by the kernel describing the time
+    Time,
+    /// Unmapped pages that catch overflows of an adjacent stack
+    Guard,
+}
+
+/// Recorded mapping of a range of pages
+#[derive(Copy, Clone, Debug)]
+pub struct Mapping {
+    pub pages: PageRange,
+    pub flags: PageTableFlags,
+    pub backing: Backing,
+    /// Process owning the mapping, or [`None`] for the kernel
+    pub pid: Option<u64>,
+}
+
+impl Mapping {
+    fn overlaps(&self, pages: PageRange) -> bool {
+        self.pages.start < pages.end && pages.start < self.pages.end
+    }
+
+    /// Whether the mapping takes frames from the frame allocator
+    fn allocates(&self) -> bool {
+        matches!(self.backing, Backing::Elf | Backing::Anonymous)
+    }
+
+    fn executable(&self) -> bool {
+        self.flags.contains(PageTableFlags::PRESENT)
+            && !self.flags.contains(PageTableFlags::NO_EXECUTE)
+    }
+}
+
+/// Formats like a line of `/proc/<pid>/maps`
+impl fmt::Display for Mapping {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let flag = |flag, c| if self.flags.contains(flag) { c } else { '-' };
+        write!(
+            f,
+            "{:012x}-{:012x} {}{}{}{} {:?}",
+            self.pages.start.start_address().as_u64(),
+            self.pages.end.start_address().as_u64(),
+            flag(PageTableFlags::PRESENT, 'r'),
+            flag(PageTableFlags::WRITABLE, 'w'),
+            if self.executable() { 'x' } else { '-' },
+            flag(PageTableFlags::USER_ACCESSIBLE, 'u'),
+            self.backing,
+        )
+    }
+}
+
+/// Page table with bookkeeping of the mappings in it
+pub struct AddressSpace {
+    page_table: OffsetPageTable<'static>,
+    mappings: [Option<Mapping>; MAX_MAPPINGS],
+    /// Process identifiers and the number of pages they may allocate
+    limits: [Option<(u64, u64)>; MAX_LIMITS],
+    /// Executable segments and the processes they are mapped for
+    segments: [Option<(u64, Segment<'static>)>; MAX_SEGMENTS],
+}
+
+impl AddressSpace {
+    /// Manage the mappings in `page_table`
+    ///
+    /// Write protection is enabled for the kernel as well, so its writes to
+    /// memory of processes copy pages on write like those of the processes.
+    pub fn new(page_table: OffsetPageTable<'static>) -> Self {
+        unsafe { Cr0::update(|flags| flags.insert(Cr0Flags::WRITE_PROTECT)) };
+        Self {
+            page_table,
+            mappings: [None; MAX_MAPPINGS],
+            limits: [None; MAX_LIMITS],
+            segments: [None; MAX_SEGMENTS],
+        }
+    }
+
+    /// Limit the number of pages mapped to fresh frames for process `pid`
+    ///
+    /// The limit is removed by [`AddressSpace::unmap_process`].
+    pub fn set_limit(&mut self, pid: u64, pages: u64) -> Result<(), &'static str> {
+        let slot = self
+            .limits
+            .iter_mut()
+            .find(|slot| slot.map_or(true, |(other, _)| other == pid))
+            .ok_or("Too many processes with a memory limit")?;
+        *slot = Some((pid, pages));
+        Ok(())
+    }
+
+    /// Number of pages mapped to fresh frames for process `pid`
+    pub fn usage(&self, pid: u64) -> u64 {
+        self.mappings()
+            .filter(|mapping| mapping.pid == Some(pid) && mapping.allocates())
+            .map(|mapping| mapping.pages.end - mapping.pages.start)
+            .sum()
+    }
+
+    /// Check that `pages` more pages can be mapped to fresh frames for `pid`
+    fn check_limit(&self, pid: Option<u64>, pages: u64) -> Result<(), &'static str> {
+        let pid = match pid {
+            Some(pid) => pid,
+            None => return Ok(()),
+        };
+        let limit = self
+            .limits
+            .iter()
+            .flatten()
+            .find(|(other, _)| *other == pid);
+        if let Some(&(_, limit)) = limit {
+            let usage = self.usage(pid);
+            if usage + pages > limit {
+                log::warn!(
+                    "Process {} exceeds its limit of {} pages mapping {} pages on top of {}",
+                    pid,
+                    limit,
+                    pages,
+                    usage
+                );
+                return Err("Memory limit exceeded");
+            }
+        }
+        Ok(())
+    }
+
+    /// Recorded mappings, in no particular order
+    pub fn mappings(&self) -> impl Iterator<Item = &Mapping> {
+        self.mappings.iter().flatten()
+    }
+
+    /// Check that `pages` is unmapped and record it as a new mapping
+    fn record(&mut self, mapping: Mapping) -> Result<(), &'static str> {
+        if let Some(other) = self.mappings().find(|other| other.overlaps(mapping.pages)) {
+            log::warn!("Mapping {} overlaps {}", mapping, other);
+            return Err("Overlapping mapping");
+        }
+        let slot = self
+            .mappings
+            .iter_mut()
+            .find(|slot| slot.is_none())
+            .ok_or("Too many mappings")?;
+        *slot = Some(mapping);
+        let user = mapping.flags.contains(PageTableFlags::USER_ACCESSIBLE);
+        if let Some(pid) = mapping.pid.filter(|_| user && mapping.executable()) {
+            let addr = mapping.pages.start.start_address().as_u64();
+            audit::record(AuditKind::ExecMap, pid, addr, true);
+        }
+        Ok(())
+    }
+
+    /// Map `pages` to consecutive frames starting at `frame`
+    ///
+    /// The frames are not owned by the mapping and not deallocated on unmap.
+    ///
+    /// # Safety
+    /// The frames should be safe to access through the mapping.
+    pub unsafe fn map_frames<A>(
+        &mut self,
+        pages: PageRange,
+        frame: PhysFrame,
+        flags: PageTableFlags,
+        backing: Backing,
+        pid: Option<u64>,
+        all: &mut A,
+    ) -> Result<(), &'static str>
+    where
+        A: FrameAllocator<Size4KiB>,
+    {
+        self.record(Mapping {
+            pages,
+            flags,
+            backing,
+            pid,
+        })?;
+        for (i, page) in pages.enumerate() {
+            let frame = frame + i as u64;
+            log::trace!("Mapping {:?} to {:?}", page, frame);
+            match self.page_table.map_to(page, frame, flags, all) {
+                Ok(flush) => flush.flush(),
+                Err(e) => {
+                    log::error!("{:?}", e);
+                    // The frames are not owned, so they are not deallocated
+                    self.roll_back(pages, page, |_| ());
+                    return Err("Mapping error");
+                }
+            }
+        }
+        Ok(())
+    }
+
+    /// Map `pages` to fresh frames, which are deallocated on unmap
+    ///
+    /// If a frame cannot be allocated or mapped, the pages mapped so far are
+    /// unmapped and their frames deallocated again.
+    pub fn map_anonymous<A>(
+        &mut self,
+        pages: PageRange,
+        flags: PageTableFlags,
+        pid: Option<u64>,
+        all: &mut A,
+    ) -> Result<(), &'static str>
+    where
+        A: FrameAllocator<Size4KiB> + FrameDeallocator<Size4KiB>,
+    {
+        self.check_limit(pid, pages.end - pages.start)?;
+        self.record(Mapping {
+            pages,
+            flags,
+            backing: Backing::Anonymous,
+            pid,
+        })?;
+        for page in pages {
+            let frame = match all.allocate_frame() {
+                Some(frame) => frame,
+                None => {
+                    self.roll_back(pages, page, |frame| unsafe { all.deallocate_frame(frame) });
+                    return Err("No frame allocated");
+                }
+            };
+            match unsafe { self.page_table.map_to(page, frame, flags, all) } {
+                Ok(flush) => flush.flush(),
+                Err(e) => {
+                    log::error!("{:?}", e);
+                    unsafe { all.deallocate_frame(frame) };
+                    self.roll_back(pages, page, |frame| unsafe { all.deallocate_frame(frame) });
+                    return Err("Mapping error");
+                }
+            }
+        }
+        Ok(())
+    }
+
+    /// Undo the recorded mapping of `pages` after mapping `failed` failed,
+    /// unmapping the pages before it and passing their frames to `free`
+    ///
+    /// Pages are unmapped last to first, so a bump allocator gets its frames
+    /// back.
+    fn roll_back<F: FnMut(PhysFrame)>(&mut self, pages: PageRange, failed: Page, mut free: F) {
+        let mut shootdown = Shootdown::new();
+        let mut page = failed;
+        while page > pages.start {
+            page -= 1;
+            if let Ok((frame, flush)) = self.page_table.unmap(page) {
+                shootdown.push(page, flush);
+                free(frame);
+            }
+        }
+        shootdown.finish();
+        self.forget(|mapping| mapping.pages == pages);
+    }
+
+    /// Reserve `pages` as guard pages, which are left unmapped
+    pub fn map_guard(&mut self, pages: PageRange, pid: Option<u64>) -> Result<(), &'static str> {
+        self.record(Mapping {
+            pages,
+            flags: PageTableFlags::empty(),
+            backing: Backing::Guard,
+            pid,
+        })
+    }
+
+    /// Map the loadable segments of `elf` for process `pid`
+    ///
+    /// Pages are only mapped by [`AddressSpace::populate`] when first
+    /// accessed, except those relocated right away. All of them count towards
+    /// the memory limit of the process, so it cannot run out of frames later.
+    pub fn map_elf<A>(
+        &mut self,
+        elf: &ElfInfo<'static>,
+        pid: u64,
+        all: &mut A,
+    ) -> Result<(), &'static str>
+    where
+        A: FrameAllocator<Size4KiB>,
+    {
+        let count = elf
+            .segments()
+            .map(|(pages, _)| pages.end - pages.start + 1)
+            .sum();
+        self.check_limit(Some(pid), count)?;
+        for segment in elf.segment_descriptors() {
+            let segment = segment.map_err(BootError::logged)?;
+            self.record(Mapping {
+                pages: Page::range(segment.pages.start, segment.pages.end + 1),
+                flags: segment.flags,
+                backing: Backing::Elf,
+                pid: Some(pid),
+            })?;
+            let slot = self
+                .segments
+                .iter_mut()
+                .find(|slot| slot.is_none())
+                .ok_or("Too many executable segments")?;
+            *slot = Some((pid, segment));
+        }
+        elf.relocate_with(|virt| {
+            let page = Page::containing_address(virt);
+            let segment = self.segment(pid, page).ok_or("Relocation not mapped")?;
+            // Relocated in a private frame, as the executable embedded in the
+            // kernel is immutable and shared by processes
+            let frame = match self.page_table.translate_page(page) {
+                Ok(frame) if Some(frame) != self.shared_frame(&segment, page) => frame,
+                _ => self.map_private(&segment, page, all)?,
+            };
+            let addr = offset::phys_to_virt(frame.start_address()) + virt.as_u64() % 4096;
+            Ok(addr.as_mut_ptr())
+        })
+        .map_err(BootError::logged)
+    }
+
+    /// Segment of process `pid` containing `page`
+    fn segment(&self, pid: u64, page: Page) -> Option<Segment<'static>> {
+        self.segments
+            .iter()
+            .flatten()
+            .find(|(other, segment)| {
+                *other == pid && segment.pages.start <= page && page <= segment.pages.end
+            })
+            .map(|&(_, segment)| segment)
+    }
+
+    /// Frame of the executable itself holding `page` of `segment`, if the page
+    /// holds nothing but contents of the file
+    fn shared_frame(&self, segment: &Segment, page: Page) -> Option<PhysFrame> {
+        let phys = self.page_table.translate_addr(segment.shared(page)?)?;
+        Some(PhysFrame::containing_address(phys))
+    }
+
+    /// Handle a page fault at `addr` in an executable segment of process `pid`,
+    /// returning whether the access can be retried
+    ///
+    /// Pages are mapped on first access. Pages holding only contents of the
+    /// file are mapped to the frames of the executable, which processes share.
+    /// Those of writable segments are mapped read-only at first and copied to
+    /// a fresh frame on the first `write`. Called from the page fault handler,
+    /// so it does not log.
+    pub fn populate<A>(
+        &mut self,
+        pid: u64,
+        addr: VirtAddr,
+        write: bool,
+        all: &mut A,
+    ) -> Result<bool, &'static str>
+    where
+        A: FrameAllocator<Size4KiB>,
+    {
+        let page = Page::containing_address(addr);
+        let segment = match self.segment(pid, page) {
+            Some(segment) => segment,
+            None => return Ok(false),
+        };
+        let write = write && segment.flags.contains(PageTableFlags::WRITABLE);
+        let shared = self.shared_frame(&segment, page);
+        match self.page_table.translate_page(page) {
+            Err(_) => {}
+            // Copied on write
+            Ok(frame) if write && Some(frame) == shared => {}
+            // Faults on other mapped pages are real
+            Ok(_) => return Ok(false),
+        }
+        match (shared, write) {
+            (Some(shared), false) => {
+                // Copied on write
+                let mut flags = segment.flags;
+                flags.remove(PageTableFlags::WRITABLE);
+                unsafe { self.page_table.map_to(page, shared, flags, all) }
+                    .map_err(|_| "Mapping error")?
+                    .flush();
+            }
+            _ => {
+                self.map_private(&segment, page, all)?;
+            }
+        }
+        Ok(true)
+    }
+
+    /// Map `page` of `segment` to a fresh frame holding its contents, in place
+    /// of the frame of the executable if that is mapped
+    fn map_private<A>(
+        &mut self,
+        segment: &Segment,
+        page: Page,
+        all: &mut A,
+    ) -> Result<PhysFrame, &'static str>
+    where
+        A: FrameAllocator<Size4KiB>,
+    {
+        let frame = all.allocate_frame().ok_or("No frame allocated")?;
+        let ptr = offset::phys_to_virt(frame.start_address()).as_mut_ptr::<u8>();
+        let bytes = unsafe { slice::from_raw_parts_mut(ptr, 4096) };
+        match self.shared_frame(segment, page) {
+            Some(shared) => {
+                let src = offset::phys_to_virt(shared.start_address()).as_ptr::<u8>();
+                bytes.copy_from_slice(unsafe { slice::from_raw_parts(src, 4096) });
+            }
+            None => segment.fill(page, bytes),
+        }
+        if self.page_table.translate_page(page).is_ok() {
+            let (_, flush) = self.page_table.unmap(page).map_err(|_| "Mapping error")?;
+            flush.flush();
+        }
+        unsafe { self.page_table.map_to(page, frame, segment.flags, all) }
+            .map_err(|_| "Mapping error")?
+            .flush();
+        Ok(frame)
+    }
+
+    /// Check that process `pid` can access the `len` bytes from `start`, and
+    /// `write` them if asked, before the kernel does so on its behalf
+    ///
+    /// Pages of its executable are populated as if the process accessed them,
+    /// so the kernel does not fault on them and does not write to pages shared
+    /// with the executable.
+    pub fn check_user<A>(
+        &mut self,
+        pid: u64,
+        start: u64,
+        len: u64,
+        write: bool,
+        all: &mut A,
+    ) -> Result<(), &'static str>
+    where
+        A: FrameAllocator<Size4KiB>,
+    {
+        if len == 0 {
+            return Ok(());
+        }
+        let end = start
+            .checked_add(len)
+            .filter(|&end| end <= USER_END)
+            .ok_or("Range outside of user memory")?;
+        let first = Page::containing_address(VirtAddr::new(start));
+        let last = Page::containing_address(VirtAddr::new(end - 1));
+        for page in Page::range_inclusive(first, last) {
+            if !self.user_accessible(page, write)
+                && !(self.populate(pid, page.start_address(), write, all)?
+                    && self.user_accessible(page, write))
+            {
+                return Err("Memory not accessible to the process");
+            }
+        }
+        Ok(())
+    }
+
+    /// Whether `page` is mapped for processes to access, and `write` if asked
+    fn user_accessible(&self, page: Page, write: bool) -> bool {
+        let mut required = PageTableFlags::PRESENT | PageTableFlags::USER_ACCESSIBLE;
+        if write {
+            required |= PageTableFlags::WRITABLE;
+        }
+        match self.page_table.translate(page.start_address()) {
+            TranslateResult::Mapped { flags, .. } => flags.contains(required),
+            _ => false,
+        }
+    }
+
+    /// Unmap the loadable segments mapped for process `pid`, deallocating the
+    /// fresh frames of their pages
+    pub fn unmap_elf<A>(&mut self, pid: u64, all: &mut A)
+    where
+        A: FrameDeallocator<Size4KiB>,
+    {
+        let mut shootdown = Shootdown::new();
+        for i in 0..MAX_SEGMENTS {
+            let segment = match self.segments[i] {
+                Some((other, segment)) if other == pid => segment,
+                _ => continue,
+            };
+            self.segments[i] = None;
+            for page in segment.pages {
+                let shared = self.shared_frame(&segment, page);
+                // Pages that were never accessed are not mapped
+                let (frame, flush) = match self.page_table.unmap(page) {
+                    Ok(unmapped) => unmapped,
+                    Err(_) => continue,
+                };
+                shootdown.push(page, flush);
+                if Some(frame) != shared {
+                    // Reused only after the shootdown, see `unmap_where`
+                    unsafe { all.deallocate_frame(frame) };
+                }
+            }
+        }
+        shootdown.finish();
+        self.forget(|mapping| mapping.backing == Backing::Elf && mapping.pid == Some(pid));
+    }
+
+    /// Unmap the mapping starting at `start`, deallocating its frames if it
+    /// owns them
+    pub fn unmap<A>(&mut self, start: Page, all: &mut A) -> Result<Mapping, &'static str>
+    where
+        A: FrameDeallocator<Size4KiB>,
+    {
+        let mut shootdown = Shootdown::new();
+        let mapping = self.unmap_where(|mapping| mapping.pages.start == start, all, &mut shootdown);
+        shootdown.finish();
+        mapping.ok_or("No mapping at address")
+    }
+
+    /// Unmap all mappings of process `pid` except for ELF segments, which
+    /// should be unmapped with [`AddressSpace::unmap_elf`]
+    pub fn unmap_process<A>(&mut self, pid: u64, all: &mut A)
+    where
+        A: FrameDeallocator<Size4KiB>,
+    {
+        let mut shootdown = Shootdown::new();
+        let owned = |mapping: &Mapping| mapping.pid == Some(pid) && mapping.backing != Backing::Elf;
+        while self.unmap_where(owned, all, &mut shootdown).is_some() {}
+        shootdown.finish();
+        for slot in self.limits.iter_mut() {
+            if slot.map_or(false, |(other, _)| other == pid) {
+                *slot = None;
+            }
+        }
+    }
+
+    /// Unmap the first mapping matching `f`, queueing invalidations in
+    /// `shootdown`
+    fn unmap_where<F, A>(&mut self, f: F, all: &mut A, shootdown: &mut Shootdown) -> Option<Mapping>
+    where
+        F: Fn(&Mapping) -> bool,
+        A: FrameDeallocator<Size4KiB>,
+    {
+        let mapping = self
+            .mappings
+            .iter_mut()
+            .find(|slot| slot.as_ref().map_or(false, &f))?
+            .take()?;
+        log::trace!("Unmapping {}", mapping);
+        if mapping.backing == Backing::Guard {
+            return Some(mapping);
+        }
+        for page in mapping.pages {
+            // Skipped rather than panicking, so the mapping is still forgotten
+            let (frame, flush) = match self.page_table.unmap(page) {
+                Ok(unmapped) => unmapped,
+                Err(e) => {
+                    log::warn!("Page {:?} of {} not mapped: {:?}", page, mapping, e);
+                    continue;
+                }
+            };
+            shootdown.push(page, flush);
+            if mapping.backing == Backing::Anonymous {
+                // The frame is only reused after the shootdown, which the caller
+                // finishes while `all` is still borrowed
+                unsafe { all.deallocate_frame(frame) };
+            }
+        }
+        Some(mapping)
+    }
+
+    /// Forget recorded mappings matching `f` without unmapping them
+    fn forget<F: Fn(&Mapping) -> bool>(&mut self, f: F) {
+        for slot in self.mappings.iter_mut() {
+            if slot.as_ref().map_or(false, &f) {
+                *slot = None;
+            }
+        }
+    }
+
+    /// Frame that `page` is mapped to, if it is mapped
+    pub fn translate(&self, page: Page) -> Option<PhysFrame> {
+        self.page_table.translate_page(page).ok()
+    }
+
+    /// Find `count` consecutive unmapped pages in `start..end`
+    pub fn find_free(&self, start: VirtAddr, end: VirtAddr, count: u64) -> Option<Page> {
+        let end = Page::containing_address(end);
+        let mut start = Page::containing_address(start);
+        let mut page = start;
+        while page < end {
+            let pages = Page::range(page, page + 1);
+            if self.page_table.translate_page(page).is_ok()
+                || self.mappings().any(|mapping| mapping.overlaps(pages))
+            {
+                start = page + 1;
+            } else if page - start + 1 == count {
+                return Some(start);
+            }
+            page += 1;
+        }
+        None
+    }
+
+    /// Log mappings of process `pid`, or of the kernel if [`None`], in the
+    /// format of `/proc/<pid>/maps`
+    pub fn log_maps(&self, pid: Option<u64>) {
+        match pid {
+            Some(pid) => log::debug!("Mappings of process {}:", pid),
+            None => log::debug!("Mappings of the kernel:"),
+        }
+        let mut mappings = [None; MAX_MAPPINGS];
+        for (slot, mapping) in mappings
+            .iter_mut()
+            .zip(self.mappings().filter(|mapping| mapping.pid == pid))
+        {
+            *slot = Some(mapping);
+        }
+        let mappings = &mut mappings[..];
+        mappings.sort_unstable_by_key(|mapping| mapping.map(|mapping| mapping.pages.start));
+        for mapping in mappings.iter().flatten() {
+            log::debug!("{}", mapping);
+        }
+    }
+}
@@ -0,0 +1,78 @@
+//! Detection of stalled timer interrupts and soft lockups
+//!
+//! The local APIC timer, which is independent of the PIT and its interrupt
+//! controller, periodically checks that timer interrupts keep arriving and that
+//! the kernel makes progress. If timer interrupts stall, e.g. because a handler
+//! did not signal the end of interrupt, diagnostics are logged and the kernel
+//! panics. Not handling system calls nor idling for [`SOFT_LOCKUP_SECONDS`] is
+//! reported as a soft lockup. Lockups with interrupts disabled, such as
+//! spinning on a lock in an interrupt handler, cannot be detected.
+
+use crate::{interrupts, threads};
+use core::sync::atomic::{AtomicBool, AtomicU64, Ordering};
+use x86_64::structures::idt::InterruptStackFrame;
+
+/// Minimum number of checks per second
+const FREQUENCY: u32 = 10;
+/// Time without timer interrupts after which the kernel panics
+const TIMER_STALL_SECONDS: u64 = 1;
+/// Time without progress after which a soft lockup is reported
+const SOFT_LOCKUP_SECONDS: u64 = 10;
+
+/// Number of checks performed
+static CHECKS: AtomicU64 = AtomicU64::new(0);
+/// Timer interrupt count at the last check
+static LAST_TICKS: AtomicU64 = AtomicU64::new(0);
+/// Check at which the timer interrupt count last changed
+static LAST_TICK_CHECK: AtomicU64 = AtomicU64::new(0);
+/// Check at which the kernel last made progress
+static LAST_PROGRESS_CHECK: AtomicU64 = AtomicU64::new(0);
+/// Whether the current soft lockup has been reported
+static REPORTED: AtomicBool = AtomicBool::new(false);
+
+/// Start checking; the timer should be running for calibration
+pub fn init() {
+    interrupts::start_apic_timer(FREQUENCY);
+    log::info!("Watchdog enabled");
+}
+
+/// Note that the kernel is making progress
+pub fn touch() {
+    LAST_PROGRESS_CHECK.store(CHECKS.load(Ordering::Relaxed), Ordering::Relaxed);
+    REPORTED.store(false, Ordering::Relaxed);
+}
+
+/// Check timer interrupts and progress, called by the local APIC timer
+pub fn check(stack_frame: &InterruptStackFrame) {
+    let frequency = interrupts::apic_timer_frequency() as u64;
+    let check = CHECKS.fetch_add(1, Ordering::Relaxed) + 1;
+    let rip = stack_frame.instruction_pointer;
+    let pid = threads::current_pid();
+
+    let ticks = interrupts::ticks();
+    if LAST_TICKS.swap(ticks, Ordering::Relaxed) != ticks {
+        LAST_TICK_CHECK.store(check, Ordering::Relaxed);
+    } else if check - LAST_TICK_CHECK.load(Ordering::Relaxed) > TIMER_STALL_SECONDS * frequency {
+        let [(isr1, irr1), (isr2, irr2)] = interrupts::pic_registers();
+        log::error!("Timer interrupts stalled at tick {}", ticks);
+        log::error!(
+            "PIC in service {:#010b} {:#010b}, requested {:#010b} {:#010b}",
+            isr1,
+            isr2,
+            irr1,
+            irr2
+        );
+        log::error!("Interrupted {:?} in process {:?}", rip, pid);
+        panic!("timer interrupts stalled, is an end of interrupt missing?");
+    }
+
+    let since_progress = check - LAST_PROGRESS_CHECK.load(Ordering::Relaxed);
+    if since_progress > SOFT_LOCKUP_SECONDS * frequency && !REPORTED.swap(true, Ordering::Relaxed) {
+        log::warn!(
+            "Soft lockup: no progress for {}s, interrupted {:?} in process {:?}",
+            since_progress / frequency,
+            rip,
+            pid
+        );
+    }
+}
@@ -0,0 +1,224 @@
+//! Minimal kernel to test the UEFI stub
+//!
+//! Instead of the kernel, `cargo xtask test --stub` embeds this in the stub.
+//! It checks the [`BootInfo`] and the environment the stub hands over, and
+//! exits QEMU through the debug exit device like the kernel tests.
+
+#![no_std]
+#![no_main]
+#![feature(global_asm)]
+
+use common::{
+    boot::{self, offset, BootInfo, KernelMain},
+    paging, print, println, serial,
+};
+use core::{mem, panic::PanicInfo};
+use owo_colors::OwoColorize;
+use uefi::table::boot::MemoryType;
+use x86_64::{
+    instructions::{hlt, port::Port},
+    structures::paging::{
+        mapper::TranslateResult, OffsetPageTable, PageTable, PageTableFlags, Translate,
+    },
+    VirtAddr,
+};
+
+// Type-check of kernel entry point
+const _: KernelMain = _start;
+
+extern "C" {
+    fn _start(boot_info: &'static BootInfo) -> !;
+}
+
+// Pass the stack pointer as set by the stub, before a prologue changes it
+global_asm!(
+    ".global _start",
+    "_start:",
+    "mov rsi, rsp",
+    "jmp stub_test_main",
+);
+
+/// Exit code to pass to QEMU, see the kernel tests
+#[repr(u32)]
+enum ExitCode {
+    Success = 0x10,
+    Failure = 0x11,
+}
+
+fn exit(exit_code: ExitCode) -> ! {
+    let mut port = Port::<u32>::new(0xf4);
+    unsafe { port.write(exit_code as u32) };
+    loop {
+        hlt();
+    }
+}
+
+/// Page table flags of the page containing `addr`
+fn flags(addr: VirtAddr) -> Result<PageTableFlags, &'static str> {
+    let frame = unsafe { paging::active_level_4_table(offset::VIRT_ADDR) };
+    let table = offset::phys_to_virt(frame.start_address()).as_mut_ptr::<PageTable>();
+    let page_table = unsafe { OffsetPageTable::new(&mut *table, offset::VIRT_ADDR) };
+    match page_table.translate(addr) {
+        TranslateResult::Mapped { flags, .. } => Ok(flags),
+        _ => Err("Address not mapped"),
+    }
+}
+
+/// Whether the page containing `addr` has all flags in `set` and none in
+/// `clear`
+fn has_flags(
+    addr: VirtAddr,
+    set: PageTableFlags,
+    clear: PageTableFlags,
+) -> Result<bool, &'static str> {
+    let flags = flags(addr)?;
+    Ok(flags.contains(set) && !flags.intersects(clear))
+}
+
+/// Regions are page-aligned and disjoint, and include conventional memory
+fn memory_map(boot_info: &BootInfo) -> Result<(), &'static str> {
+    let map = &boot_info.memory_map;
+    if map.len() == 0 {
+        return Err("Empty memory map");
+    }
+    let range = |start: u64, pages: u64| start..start + pages * 4096;
+    for (i, region) in map.clone().enumerate() {
+        if region.phys_start % 4096 != 0 {
+            return Err("Region not page-aligned");
+        }
+        let a = range(region.phys_start, region.page_count);
+        for other in map.clone().skip(i + 1) {
+            let b = range(other.phys_start, other.page_count);
+            if a.start < b.end && b.start < a.end {
+                return Err("Regions overlap");
+            }
+        }
+    }
+    let conventional: u64 = map
+        .clone()
+        .filter(|region| region.ty == MemoryType::CONVENTIONAL)
+        .map(|region| region.page_count)
+        .sum();
+    if conventional < 256 {
+        return Err("Less than 1 MiB of conventional memory");
+    }
+    Ok(())
+}
+
+static RODATA: [u8; 8] = *b"readonly";
+static mut DATA: u64 = 1;
+
+/// Kernel segments are mapped with the permissions of the ELF, and physical
+/// memory at the offset only for the kernel
+fn mappings() -> Result<(), &'static str> {
+    use PageTableFlags as F;
+    let code = VirtAddr::new(stub_test_main as usize as u64);
+    if !has_flags(code, F::PRESENT, F::WRITABLE | F::NO_EXECUTE)? {
+        return Err("Code writable or not executable");
+    }
+    let rodata = VirtAddr::from_ptr(&RODATA);
+    if !has_flags(rodata, F::PRESENT | F::NO_EXECUTE, F::WRITABLE)? {
+        return Err("Read-only data writable or executable");
+    }
+    let data = VirtAddr::from_ptr(unsafe { &DATA });
+    if !has_flags(data, F::PRESENT | F::WRITABLE | F::NO_EXECUTE, F::empty())? {
+        return Err("Data not writable or executable");
+    }
+    let phys = offset::VIRT_ADDR;
+    if !has_flags(phys, F::PRESENT | F::WRITABLE, F::USER_ACCESSIBLE)? {
+        return Err("Offset mapping not writable or user accessible");
+    }
+    Ok(())
+}
+
+/// The stack is aligned as if the entry point was called and lies in the
+/// offset mapping, as does the boot information
+fn stack(boot_info: &BootInfo, entry_rsp: u64) -> Result<(), &'static str> {
+    // The return address misaligns the 16-byte aligned stack by 8 bytes
+    if entry_rsp % 16 != 8 {
+        return Err("Stack misaligned at entry");
+    }
+    let rsp = VirtAddr::new(entry_rsp);
+    if rsp < offset::VIRT_ADDR {
+        return Err("Stack not in offset mapping");
+    }
+    let flags = PageTableFlags::PRESENT | PageTableFlags::WRITABLE;
+    if !has_flags(rsp - 8u64, flags, PageTableFlags::empty())? {
+        return Err("Stack not writable");
+    }
+    let boot_info = VirtAddr::from_ptr(boot_info);
+    if boot_info < offset::VIRT_ADDR || !boot_info.is_aligned(mem::align_of::<BootInfo>() as u64) {
+        return Err("Boot information misplaced");
+    }
+    Ok(())
+}
+
+/// Check the boot phases were timed in order, before the kernel was entered
+fn times(boot_info: &BootInfo) -> Result<(), &'static str> {
+    let times = &boot_info.times;
+    let stamps = [
+        times.loader_entry,
+        times.exit_boot_services,
+        times.handoff,
+        boot::timestamp(),
+    ];
+    if stamps.contains(&0) {
+        return Err("Boot phase not timed");
+    }
+    if stamps.windows(2).any(|pair| pair[0] > pair[1]) {
+        return Err("Boot phases timed out of order");
+    }
+    Ok(())
+}
+
+#[no_mangle]
+extern "C" fn stub_test_main(boot_info: &'static BootInfo, entry_rsp: u64) -> ! {
+    serial::init();
+    let tests: [(&str, &dyn Fn() -> Result<(), &'static str>); 4] = [
+        ("memory_map", &|| memory_map(boot_info)),
+        ("mappings", &mappings),
+        ("stack", &|| stack(boot_info, entry_rsp)),
+        ("times", &|| times(boot_info)),
+    ];
+
+    println!();
+    println!("running {} stub tests", tests.len());
+    let mut failed = 0;
+    for (name, test) in tests.iter() {
+        print!("{} ... ", name);
+        match test() {
+            Ok(()) => println!("{}", "ok".green()),
+            Err(e) => {
+                println!("{} ({})", "FAILED".red(), e);
+                failed += 1;
+            }
+        }
+    }
+
+    println!();
+    if failed == 0 {
+        println!(
+            "test result: {}. {} passed; 0 failed",
+            "ok".green(),
+            tests.len()
+        );
+        println!();
+        exit(ExitCode::Success);
+    } else {
+        println!(
+            "test result: {}. {} passed; {} failed",
+            "FAILED".red(),
+            tests.len() - failed,
+            failed
+        );
+        println!();
+        exit(ExitCode::Failure);
+    }
+}
+
+#[panic_handler]
+fn panic(info: &PanicInfo) -> ! {
+    println!("{}\n", "failed".red());
+    println!("{}", info);
+    exit(ExitCode::Failure);
+}
@@ -1,5 +1,6 @@
 //! Convenience wrappers for allocations
 
+use crate::failure::SetupError;
 use uefi::{
     prelude::*,
     table::boot::{AllocateType, MemoryType},
@@ -21,23 +22,23 @@ impl<'a> BootAllocator<'a> {
     /// Allocate from pool
     ///
     /// Convenience function for [`BootServices::allocate_pool`]. Log any
-    /// warnings and use a static string as error message.
-    pub fn allocate_pool(&self, count: usize) -> Result<*mut u8, &'static str> {
+    /// warnings and keep the status of errors.
+    pub fn allocate_pool(&self, count: usize) -> Result<*mut u8, SetupError> {
         self.0
             .allocate_pool(MemoryType::LOADER_DATA, count)
             .log_warning()
-            .map_err(|_| "Failed to allocate pool")
+            .map_err(|e| SetupError::firmware("Failed to allocate pool", e.status()))
     }
 
     /// Allocate pages
     ///
     /// Convenience function for [`BootServices::allocate_pages`]. Log any
-    /// warnings and use a static string as error message.
-    pub fn allocate_pages(&self, count: usize) -> Result<u64, &'static str> {
+    /// warnings and keep the status of errors.
+    pub fn allocate_pages(&self, count: usize) -> Result<u64, SetupError> {
         self.0
             .allocate_pages(AllocateType::AnyPages, MemoryType::LOADER_DATA, count)
             .log_warning()
-            .map_err(|_| "Failed to allocate pages")
+            .map_err(|e| SetupError::firmware("Failed to allocate pages", e.status()))
     }
 }
 
@@ -0,0 +1,59 @@
+//! Saving the log to the ESP
+//!
+//! On machines without a serial port the log is otherwise lost, so it is
+//! written to [`PATH`] on the file system the stub was loaded from before
+//! exiting boot services. Only the log of the stub is saved, as the kernel has
+//! no file system access.
+
+use crate::{esp, failure::SetupError};
+use common::logger;
+use uefi::{
+    prelude::*,
+    proto::media::file::{Directory, File, FileAttribute, FileMode, FileType, RegularFile},
+    Handle,
+};
+
+/// Directory on the ESP containing [`PATH`]
+const DIR: &str = "angstros";
+/// Path of the log file on the ESP
+const PATH: &str = "angstros\\boot.log";
+
+/// Create or truncate the file at `path` in `root`
+fn create(root: &mut Directory, path: &str) -> Result<RegularFile, &'static str> {
+    // There is no truncating open mode, so delete any old file first
+    if let Ok(file) = root
+        .open(path, FileMode::ReadWrite, FileAttribute::empty())
+        .log_warning()
+    {
+        file.delete()
+            .log_warning()
+            .map_err(|_| "Failed to delete old log file")?;
+    }
+    let file = root
+        .open(path, FileMode::CreateReadWrite, FileAttribute::empty())
+        .log_warning()
+        .map_err(|_| "Failed to create log file")?;
+    match file.into_type().log_warning() {
+        Ok(FileType::Regular(file)) => Ok(file),
+        _ => Err("Log file is not a regular file"),
+    }
+}
+
+/// Write the log history to [`PATH`] on the ESP the stub with handle `image`
+/// was loaded from
+pub fn write(boot_serv: &BootServices, image: Handle) -> Result<(), SetupError> {
+    let mut root = esp::root(boot_serv, image)?;
+    root.open(DIR, FileMode::CreateReadWrite, FileAttribute::DIRECTORY)
+        .log_warning()
+        .map_err(|_| "Failed to create log directory")?;
+    let mut file = create(&mut root, PATH)?;
+    logger::with_history(|a, b| {
+        file.write(a)
+            .and_then(|_| file.write(b))
+            .log_warning()
+            .map_err(|_| "Failed to write log file")
+    })
+    .ok_or("Log history in use")??;
+    log::info!("Saved log to \\{}", PATH);
+    Ok(())
+}
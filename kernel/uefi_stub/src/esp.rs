@@ -0,0 +1,73 @@
+//! Files on the ESP
+//!
+//! The ESP is the file system on the device the stub was loaded from, which
+//! need not be the first one the firmware finds.
+
+use crate::{allocator::BootAllocator, failure::SetupError};
+use core::slice;
+use uefi::{
+    prelude::*,
+    proto::{
+        loaded_image::LoadedImage,
+        media::{
+            file::{Directory, File, FileAttribute, FileMode, FileType, RegularFile},
+            fs::SimpleFileSystem,
+        },
+    },
+    Handle,
+};
+
+/// Open the root directory of the ESP the stub with handle `image` was loaded
+/// from
+pub fn root(boot_serv: &BootServices, image: Handle) -> Result<Directory, SetupError> {
+    let loaded_image = boot_serv
+        .handle_protocol::<LoadedImage>(image)
+        .log_warning()
+        .map_err(|e| SetupError::firmware("Failed to open loaded image", e.status()))?;
+    let device = unsafe { &*loaded_image.get() }.device();
+    let fs = boot_serv
+        .handle_protocol::<SimpleFileSystem>(device)
+        .log_warning()
+        .map_err(|e| SetupError::firmware("Failed to open file system", e.status()))?;
+    let fs = unsafe { &mut *fs.get() };
+    fs.open_volume()
+        .log_warning()
+        .map_err(|e| SetupError::firmware("Failed to open ESP", e.status()))
+}
+
+/// Read the file at `path` in `root` into newly allocated pages, so it is
+/// aligned like [`Elf`](common::elf::Elf) does
+pub fn read(
+    root: &mut Directory,
+    path: &str,
+    boot_alloc: &BootAllocator,
+) -> Result<&'static [u8], SetupError> {
+    let file = root
+        .open(path, FileMode::Read, FileAttribute::empty())
+        .log_warning()
+        .map_err(|e| SetupError::firmware("Failed to open file", e.status()))?;
+    let mut file = match file.into_type().log_warning() {
+        Ok(FileType::Regular(file)) => file,
+        Ok(FileType::Dir(_)) => return Err("Not a regular file".into()),
+        Err(e) => return Err(SetupError::firmware("Failed to open file", e.status())),
+    };
+    let seek_error = |e: uefi::Error| SetupError::firmware("Failed to seek file", e.status());
+    file.set_position(RegularFile::END_OF_FILE)
+        .log_warning()
+        .map_err(seek_error)?;
+    let size = file.get_position().log_warning().map_err(seek_error)? as usize;
+    file.set_position(0).log_warning().map_err(seek_error)?;
+    let addr = boot_alloc.allocate_pages(((size + 4095) / 4096).max(1))?;
+    // Creating a &[u8] containing uninitialized memory is UB
+    unsafe { (addr as *mut u8).write_bytes(0, size) };
+    let buf = unsafe { slice::from_raw_parts_mut(addr as *mut u8, size) };
+    let mut read = 0;
+    while read < size {
+        match file.read(&mut buf[read..]).log_warning() {
+            Ok(0) => return Err("File shorter than its size".into()),
+            Ok(len) => read += len,
+            Err(e) => return Err(SetupError::firmware("Failed to read file", e.status())),
+        }
+    }
+    Ok(buf)
+}
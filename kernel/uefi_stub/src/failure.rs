@@ -0,0 +1,133 @@
+//! Diagnostics of a failed boot
+//!
+//! Many machines lack the serial port the log goes to, so if setting up the
+//! boot fails, the phase it failed in, the status returned by the firmware and
+//! a hint on what to try are shown on the UEFI console before shutting down.
+
+use crate::progress::Phase;
+use common::error::BootError;
+use core::fmt::{self, Write};
+use uefi::prelude::*;
+
+/// Time the failure is shown before shutting down in seconds
+const SHOW_TIME: usize = 30;
+/// Interval at which the keyboard is polled in microseconds
+const POLL_INTERVAL: usize = 10_000;
+
+/// Reason setting up the boot failed
+#[derive(Clone, Debug)]
+pub enum SetupError {
+    /// Request the firmware failed with `status`
+    Firmware {
+        message: &'static str,
+        status: Status,
+    },
+    /// Failure of the stub itself, such as an invalid kernel ELF
+    Stub(BootError),
+}
+
+impl SetupError {
+    /// Error of a request described by `message` that the firmware failed
+    /// with `status`
+    pub fn firmware(message: &'static str, status: Status) -> Self {
+        Self::Firmware { message, status }
+    }
+
+    /// Status the firmware failed with, if it did
+    pub fn status(&self) -> Option<Status> {
+        match self {
+            Self::Firmware { status, .. } => Some(*status),
+            Self::Stub(_) => None,
+        }
+    }
+}
+
+impl From<BootError> for SetupError {
+    fn from(error: BootError) -> Self {
+        Self::Stub(error)
+    }
+}
+
+impl From<&'static str> for SetupError {
+    fn from(message: &'static str) -> Self {
+        Self::Stub(BootError::new(message))
+    }
+}
+
+impl fmt::Display for SetupError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            Self::Firmware { message, status } => write!(f, "{}: {:?}", message, status),
+            Self::Stub(error) => write!(f, "{}", error),
+        }
+    }
+}
+
+/// Failure to set up the boot during `phase`
+pub struct Failure {
+    pub phase: Phase,
+    pub error: SetupError,
+}
+
+impl Failure {
+    /// What the user could try to boot successfully
+    fn hint(&self) -> &'static str {
+        match (&self.error, self.phase) {
+            (SetupError::Firmware { status, .. }, _) if *status == Status::OUT_OF_RESOURCES => {
+                "The firmware ran out of memory. Try again with more memory."
+            }
+            (SetupError::Firmware { status, .. }, Phase::Kernel)
+                if *status == Status::NOT_FOUND =>
+            {
+                "Check the path of the kernel in angstros\\menu.conf on the ESP."
+            }
+            (SetupError::Firmware { .. }, _) => {
+                "The firmware refused a request. Updating or resetting it may help."
+            }
+            (SetupError::Stub(_), Phase::Kernel) => {
+                "The kernel is not a valid position-independent ELF. Rebuild it."
+            }
+            (SetupError::Stub(_), _) => {
+                "This is likely a bug in the stub; its log has more details."
+            }
+        }
+    }
+
+    /// Log the failure and show it on the UEFI console until a key is pressed
+    /// or [`SHOW_TIME`] has passed
+    pub fn show(&self, system_table: &SystemTable<Boot>) {
+        log::error!("Boot failed in phase {:?}: {}", self.phase, self.error);
+        let stdout = system_table.stdout();
+        let _ = stdout.clear().log_warning();
+        let _ = writeln!(stdout, "ÅngstrÖS failed to boot\n");
+        let _ = writeln!(
+            stdout,
+            "  Stage:  {} ({}/{})",
+            self.phase.description(),
+            self.phase as usize + 1,
+            Phase::COUNT
+        );
+        let _ = match self.error.status() {
+            Some(status) => writeln!(stdout, "  Status: {:?}", status),
+            None => writeln!(stdout, "  Status: -"),
+        };
+        let _ = match &self.error {
+            SetupError::Firmware { message, .. } => writeln!(stdout, "  Error:  {}", message),
+            SetupError::Stub(error) => writeln!(stdout, "  Error:  {}", error),
+        };
+        let _ = writeln!(stdout, "  Hint:   {}\n", self.hint());
+        let _ = writeln!(
+            stdout,
+            "Shutting down in {} s, or press any key to shut down now",
+            SHOW_TIME
+        );
+
+        let boot_serv = system_table.boot_services();
+        for _ in 0..SHOW_TIME * 1_000_000 / POLL_INTERVAL {
+            if let Ok(Some(_)) = system_table.stdin().read_key().log_warning() {
+                break;
+            }
+            boot_serv.stall(POLL_INTERVAL);
+        }
+    }
+}
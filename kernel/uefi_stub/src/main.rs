@@ -3,22 +3,31 @@
 #![feature(abi_efiapi, asm)]
 
 mod allocator;
+mod boot_log;
+mod esp;
+mod failure;
+mod menu;
+mod placement;
+mod progress;
 
 use allocator::BootAllocator;
 use common::{
-    boot::{offset, BootInfo, FrameBuffer, MemoryMap},
-    elf::Elf,
-    println,
+    boot::{self, offset, BootInfo, BootTimes, CommandLine, FramebufferInfo, MemoryMap},
+    boot_error,
+    elf::{Elf, ElfInfo},
+    paging, println,
 };
 use core::{mem, panic::PanicInfo, slice};
+use failure::{Failure, SetupError};
+use menu::Entry;
+use progress::{Phase, Progress};
 use uefi::{
     prelude::*,
-    proto::console::gop::GraphicsOutput,
-    table::{boot::MemoryDescriptor, runtime::ResetType},
-    Handle,
+    proto::{console::gop::GraphicsOutput, loaded_image::LoadedImage},
+    table::{boot::MemoryDescriptor, cfg, runtime::ResetType},
+    Guid, Handle,
 };
 use x86_64::{
-    registers::control::Cr3,
     structures::paging::{Mapper, OffsetPageTable, PageTable, PageTableFlags, PhysFrame, Size4KiB},
     PhysAddr, VirtAddr,
 };
@@ -38,31 +47,76 @@ fn shutdown(system_table: SystemTable<Boot>) -> ! {
     rt.reset(ResetType::Shutdown, Status::SUCCESS, None);
 }
 
+/// Options the stub was loaded with, without the path of the stub itself
+fn command_line(boot_serv: &BootServices, image: Handle) -> CommandLine {
+    let mut buf = [0; 512];
+    let options = boot_serv
+        .handle_protocol::<LoadedImage>(image)
+        .log_warning()
+        .ok()
+        .and_then(|loaded_image| unsafe { &*loaded_image.get() }.load_options(&mut buf).ok())
+        .unwrap_or("");
+    let mut options = options.trim();
+    // The UEFI shell passes the path of the image as the first option
+    let first = options.split(' ').next().unwrap_or("").as_bytes();
+    if first.len() >= 4 && first[first.len() - 4..].eq_ignore_ascii_case(b".efi") {
+        options = options[first.len()..].trim_start();
+    }
+    log::info!("Kernel command line: {:?}", options);
+    CommandLine::new(options)
+}
+
 struct Setup {
-    kernel_page_table: &'static PageTable,
+    /// Physical address of the top-level page table of the kernel
+    page_table: u64,
     stack: u64,
     entry_point: u64,
+    kernel_offset: u64,
     boot_info: *mut BootInfo,
     mmap: &'static mut [u8],
+    cmdline: CommandLine,
+    smbios: Option<PhysAddr>,
+    rsdp: Option<PhysAddr>,
+}
+
+/// Find the first entry of the configuration table with one of `guids`, in
+/// order of preference
+fn config_table_entry(system_table: &SystemTable<Boot>, guids: &[Guid]) -> Option<PhysAddr> {
+    let config_table = system_table.config_table();
+    guids
+        .iter()
+        .find_map(|&guid| config_table.iter().find(|entry| entry.guid == guid))
+        .map(|entry| PhysAddr::new(entry.address as u64))
 }
 
 fn setup_boot(
+    image: Handle,
     system_table: &SystemTable<Boot>,
-) -> Result<(Setup, Option<FrameBuffer>), &'static str> {
-    common::init(config::LOG_LEVEL)?;
-
-    // Reset UEFI text and background colors and print newline
-    println!("\x1b[0m");
-    println!(
-        "== ÅngstrÖS UEFI boot stub v{} ==",
-        env!("CARGO_PKG_VERSION")
-    );
-    println!();
-
+    progress: &Progress,
+    entry: Entry,
+) -> Result<(Setup, Option<FramebufferInfo>), SetupError> {
+    progress.report(system_table, Phase::Tables);
     let boot_serv = system_table.boot_services();
     let mut boot_alloc = BootAllocator::new(&boot_serv);
+    let cmdline = match entry.cmdline {
+        Some(cmdline) => {
+            log::info!("Kernel command line: {:?}", cmdline);
+            CommandLine::new(cmdline)
+        }
+        None => command_line(&boot_serv, image),
+    };
+    // Prefer the 64-bit SMBIOS 3 entry point and the ACPI 2.0 RSDP
+    let smbios = config_table_entry(system_table, &[cfg::SMBIOS3_GUID, cfg::SMBIOS_GUID]);
+    if smbios.is_none() {
+        log::warn!("No SMBIOS entry point in configuration table");
+    }
+    let rsdp = config_table_entry(system_table, &[cfg::ACPI2_GUID, cfg::ACPI_GUID]);
+    if rsdp.is_none() {
+        log::warn!("No ACPI RSDP in configuration table");
+    }
 
     // Setup graphics protocol and frame buffer
+    progress.report(system_table, Phase::Graphics);
     let fb = boot_serv
         .locate_protocol::<GraphicsOutput>()
         .log_warning()
@@ -71,24 +125,50 @@ fn setup_boot(
                 log::error!("Failed to locate graphics output: {:?}", e.status());
                 None
             },
-            |gop| Some(FrameBuffer::new(unsafe { &mut *gop.get() }, offset::USIZE)),
+            |gop| {
+                let fb = FramebufferInfo::new(unsafe { &mut *gop.get() });
+                if fb.is_none() {
+                    log::warn!("Graphics output has no frame buffer");
+                }
+                fb
+            },
         );
 
     // Setup basic mappings for kernel
-    let uefi_page_table = {
-        let phys_addr = Cr3::read().0.start_address();
-        let virt_addr = VirtAddr::new(phys_addr.as_u64());
-        unsafe { virt_addr.as_mut_ptr::<PageTable>().as_mut() }.unwrap()
-    };
+    progress.report(system_table, Phase::PageTables);
+    let la57 = paging::la57_enabled();
+    if la57 {
+        log::info!("5-level paging enabled by firmware");
+    } else if paging::la57_supported() {
+        log::debug!("5-level paging supported but not enabled");
+    }
     let kernel_page_table = {
         let virt_addr = VirtAddr::new(boot_alloc.allocate_pages(1)?);
         let ptr: *mut PageTable = virt_addr.as_mut_ptr();
         unsafe { ptr.write(PageTable::new()) };
         unsafe { ptr.as_mut() }.unwrap()
     };
-    kernel_page_table[offset::PAGE_TABLE_INDEX] = uefi_page_table[0].clone();
+    paging::map_offset(kernel_page_table, 0, || {
+        boot_alloc
+            .allocate_pages(1)
+            .map(|addr| PhysFrame::containing_address(PhysAddr::new(addr)))
+    })?;
     let mut offset_kpt = unsafe { OffsetPageTable::new(kernel_page_table, VirtAddr::new(0)) };
-    let kernel_info = KERNEL.info(false)?;
+    progress.report(system_table, Phase::Kernel);
+    let kernel_info = match entry.kernel {
+        Some(path) => {
+            log::info!("Loading kernel from \\{}", path);
+            let bytes = esp::read(&mut esp::root(&boot_serv, image)?, path, &boot_alloc)?;
+            ElfInfo::new(bytes, false)?
+        }
+        None => KERNEL.info(false)?,
+    };
+    // Keep the kernel clear of the pages identity mapped below
+    let addr = PhysAddr::new(VirtAddr::from_ptr(switch_to_kernel as *const ()).as_u64());
+    let frame = PhysFrame::<Size4KiB>::containing_address(addr);
+    let identity = frame.start_address().as_u64()..(frame + 2).start_address().as_u64();
+    let kernel_offset = placement::kernel_offset(&kernel_info, identity)?;
+    let kernel_info = kernel_info.with_offset(kernel_offset)?;
     kernel_info.setup_mappings(&mut offset_kpt, &mut boot_alloc)?;
 
     // Map pages around context switch
@@ -96,15 +176,23 @@ fn setup_boot(
         "Identity mapping around kernel context switch at {:?}",
         switch_to_kernel as *const ()
     );
-    let addr = PhysAddr::new(VirtAddr::from_ptr(switch_to_kernel as *const ()).as_u64());
-    let frame = PhysFrame::<Size4KiB>::containing_address(addr);
     for frame in PhysFrame::range_inclusive(frame, frame + 1) {
         log::debug!("Identity mapping {:?} to be sure", frame);
         unsafe { offset_kpt.identity_map(frame, PageTableFlags::PRESENT, &mut boot_alloc) }
-            .map_err(|_| "Mapping error")?
+            .map_err(|_| boot_error!("Mapping error", "identity mapping {:?}", frame))?
             .ignore();
     }
 
+    let mut page_table = kernel_page_table as *const PageTable as u64;
+    if la57 {
+        let addr = boot_alloc.allocate_pages(1)?;
+        let pml5 = unsafe { &mut *(addr as *mut PageTable) };
+        let frame = PhysFrame::containing_address(PhysAddr::new(page_table));
+        paging::wrap_level_4_table(pml5, frame);
+        page_table = addr;
+    }
+
+    progress.report(system_table, Phase::BootInfo);
     let stack = boot_alloc.allocate_pages(16)? + 15 * 0x1000;
     let boot_info = {
         let size = mem::size_of::<BootInfo>();
@@ -124,11 +212,15 @@ fn setup_boot(
 
     Ok((
         Setup {
-            kernel_page_table,
+            page_table,
             stack,
             entry_point: kernel_info.entry_point(),
+            kernel_offset,
             boot_info,
             mmap,
+            cmdline,
+            smbios,
+            rsdp,
         },
         fb,
     ))
@@ -136,19 +228,44 @@ fn setup_boot(
 
 #[entry]
 fn efi_main(image_handler: Handle, system_table: SystemTable<Boot>) -> Status {
-    let (setup, fb) = match setup_boot(&system_table) {
+    let loader_entry = boot::timestamp();
+    common::init(config::LOG_LEVEL, config::LOG_FORMAT).unwrap();
+
+    // Reset UEFI text and background colors and print newline
+    println!("\x1b[0m");
+    println!(
+        "== ÅngstrÖS UEFI boot stub v{} ==",
+        env!("CARGO_PKG_VERSION")
+    );
+    println!();
+
+    let entry = menu::select(&system_table, image_handler);
+    let progress = Progress::new(&system_table, config::PROGRESS);
+    let (setup, fb) = match setup_boot(image_handler, &system_table, &progress, entry) {
         Ok(s) => s,
-        Err(s) => {
-            log::error!("{}", s);
+        Err(error) => {
+            let failure = Failure {
+                phase: progress.phase(),
+                error,
+            };
+            failure.show(&system_table);
             shutdown(system_table);
         }
     };
 
+    if config::LOG_FILE {
+        if let Err(e) = boot_log::write(system_table.boot_services(), image_handler) {
+            log::warn!("{}", e);
+        }
+    }
+
     log::info!("Exiting boot services and performing final setup");
+    progress.report(&system_table, Phase::Exit);
 
     let (uefi_system_table, mut mmap_iter) = system_table
         .exit_boot_services(image_handler, setup.mmap)?
         .log();
+    let exit_boot_services = boot::timestamp();
 
     // Figure out distance between elements in memory descriptor slice
     let size = if let (Some(fst), Some(snd)) = (mmap_iter.next(), mmap_iter.next()) {
@@ -165,25 +282,37 @@ fn efi_main(image_handler: Handle, system_table: SystemTable<Boot>) -> Status {
     let ptr = setup.mmap.as_ptr().wrapping_add(offset::USIZE).cast();
     let memory_map = unsafe { MemoryMap::new(ptr, size, len) };
 
+    log::info!("Switching to kernel");
+
     unsafe {
         setup.boot_info.write(BootInfo {
-            uefi_system_table,
+            uefi_system_table: Some(uefi_system_table),
             memory_map,
             fb,
+            cmdline: setup.cmdline,
+            smbios: setup.smbios,
+            rsdp: setup.rsdp,
+            kernel_offset: setup.kernel_offset,
+            times: BootTimes {
+                loader_entry,
+                exit_boot_services,
+                handoff: boot::timestamp(),
+            },
         })
     };
-
-    log::info!("Switching to kernel");
-
     switch_to_kernel(setup);
 }
 
+/// Jump to the kernel entry point on the kernel stack
+///
+/// The entry point is called so it finds the stack aligned as the ABI expects;
+/// it never returns.
 #[inline(never)]
 fn switch_to_kernel(setup: Setup) -> ! {
     unsafe {
         asm!(
-            "mov cr3, {}; mov rsp, {}; jmp {}",
-            in(reg) setup.kernel_page_table as *const _ as usize,
+            "mov cr3, {}; mov rsp, {}; call {}",
+            in(reg) setup.page_table,
             in(reg) setup.stack as usize + offset::USIZE,
             in(reg) setup.entry_point,
             in("rdi") setup.boot_info as usize + offset::USIZE,
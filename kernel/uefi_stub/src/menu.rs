@@ -0,0 +1,177 @@
+//! Boot menu
+//!
+//! Besides the kernel embedded in the stub, kernels on the ESP can be booted,
+//! for instance a debug and a release build side by side. They are listed in
+//! [`PATH`], one entry per line: a title, the path of the kernel ELF on the ESP
+//! and its command line, separated by `|`. Empty lines and lines starting with
+//! `#` are ignored, e.g.:
+//!
+//! ```text
+//! Debug kernel|angstros\kernel-debug.elf|log=trace
+//! ```
+//!
+//! If the file exists, a menu on the UEFI console lets the user pick an entry
+//! with the arrow or number keys and Enter. The highlighted entry, initially
+//! the embedded kernel, is booted once the configured timeout expires without
+//! a key being pressed.
+
+use crate::{allocator::BootAllocator, config, esp};
+use core::{fmt::Write, str};
+use uefi::{
+    prelude::*,
+    proto::console::text::{Key, ScanCode},
+    Handle,
+};
+
+/// Path of the menu on the ESP
+const PATH: &str = "angstros\\menu.conf";
+/// Maximum number of entries, so each can be selected with a number key
+const MAX_ENTRIES: usize = 9;
+/// Interval at which the keyboard is polled in microseconds
+const POLL_INTERVAL: usize = 10_000;
+
+/// Kernel to boot
+#[derive(Copy, Clone, Debug)]
+pub struct Entry {
+    pub title: &'static str,
+    /// Path of the kernel on the ESP, or `None` for the embedded kernel
+    pub kernel: Option<&'static str>,
+    /// Command line, or `None` to use the options the stub was loaded with
+    pub cmdline: Option<&'static str>,
+}
+
+const EMBEDDED: Entry = Entry {
+    title: "Embedded kernel",
+    kernel: None,
+    cmdline: None,
+};
+
+impl Entry {
+    fn parse(line: &'static str) -> Option<Self> {
+        let mut fields = line.splitn(3, '|').map(str::trim);
+        Some(Self {
+            title: fields.next()?,
+            kernel: Some(fields.next().filter(|path| !path.is_empty())?),
+            cmdline: Some(fields.next().unwrap_or("")),
+        })
+    }
+}
+
+/// Entries of the menu; the first one is the embedded kernel
+struct Menu {
+    entries: [Entry; MAX_ENTRIES],
+    len: usize,
+}
+
+impl Menu {
+    fn parse(text: &'static str) -> Self {
+        let mut menu = Self {
+            entries: [EMBEDDED; MAX_ENTRIES],
+            len: 1,
+        };
+        let lines = text
+            .lines()
+            .map(str::trim)
+            .filter(|line| !line.is_empty() && !line.starts_with('#'));
+        for line in lines {
+            if menu.len == MAX_ENTRIES {
+                log::warn!("Boot menu has more than {} entries", MAX_ENTRIES);
+                break;
+            }
+            match Entry::parse(line) {
+                Some(entry) => {
+                    menu.entries[menu.len] = entry;
+                    menu.len += 1;
+                }
+                None => log::warn!("Invalid boot menu entry {:?}", line),
+            }
+        }
+        menu
+    }
+
+    fn draw(&self, system_table: &SystemTable<Boot>, selected: usize, timeout: Option<usize>) {
+        let stdout = system_table.stdout();
+        let _ = stdout.clear().log_warning();
+        let _ = writeln!(stdout, "ÅngstrÖS boot menu\n");
+        for (i, entry) in self.entries[..self.len].iter().enumerate() {
+            let marker = if i == selected { '>' } else { ' ' };
+            let _ = writeln!(stdout, "{} {}. {}", marker, i + 1, entry.title);
+        }
+        let _ = writeln!(stdout);
+        if let Some(timeout) = timeout {
+            let _ = writeln!(stdout, "Booting highlighted entry in {} s", timeout);
+        } else {
+            let _ = writeln!(stdout, "Press Enter to boot highlighted entry");
+        }
+    }
+
+    /// Let the user pick an entry
+    fn run(&self, system_table: &SystemTable<Boot>) -> Entry {
+        let boot_serv = system_table.boot_services();
+        let polls_per_second = 1_000_000 / POLL_INTERVAL;
+        let mut polls = config::MENU_TIMEOUT as usize * polls_per_second;
+        let mut timeout = true;
+        let mut selected = 0;
+        let mut redraw = true;
+        loop {
+            if redraw {
+                let remaining = (polls + polls_per_second - 1) / polls_per_second;
+                self.draw(system_table, selected, Some(remaining).filter(|_| timeout));
+                redraw = false;
+            }
+            let key = system_table.stdin().read_key().log_warning();
+            if let Ok(Some(key)) = key {
+                timeout = false;
+                redraw = true;
+                match key {
+                    Key::Printable(c) => match char::from(c) {
+                        '\r' => break,
+                        c => {
+                            let digit = c.to_digit(10).unwrap_or(0) as usize;
+                            if (1..=self.len).contains(&digit) {
+                                selected = digit - 1;
+                            }
+                        }
+                    },
+                    Key::Special(ScanCode::UP) => selected = selected.saturating_sub(1),
+                    Key::Special(ScanCode::DOWN) => selected = (selected + 1).min(self.len - 1),
+                    Key::Special(_) => {}
+                }
+                continue;
+            }
+            if timeout {
+                if polls == 0 {
+                    break;
+                }
+                polls -= 1;
+                redraw = polls % polls_per_second == 0;
+            }
+            boot_serv.stall(POLL_INTERVAL);
+        }
+        let _ = system_table.stdout().clear().log_warning();
+        self.entries[selected]
+    }
+}
+
+/// Select the kernel to boot, using the menu if there is one on the ESP the
+/// stub with handle `image` was loaded from
+pub fn select(system_table: &SystemTable<Boot>, image: Handle) -> Entry {
+    let boot_serv = system_table.boot_services();
+    let boot_alloc = BootAllocator::new(boot_serv);
+    let text = esp::root(boot_serv, image)
+        .and_then(|mut root| esp::read(&mut root, PATH, &boot_alloc))
+        .and_then(|bytes| str::from_utf8(bytes).map_err(|_| "Boot menu is not UTF-8".into()));
+    let menu = match text {
+        Ok(text) => Menu::parse(text),
+        Err(e) => {
+            log::debug!("No boot menu: {}", e);
+            return EMBEDDED;
+        }
+    };
+    if menu.len == 1 {
+        return EMBEDDED;
+    }
+    let entry = menu.run(system_table);
+    log::info!("Booting {:?}", entry.title);
+    entry
+}
@@ -0,0 +1,64 @@
+//! Placement of the kernel in its address space
+//!
+//! The kernel is a position-independent executable, so it can be loaded at any
+//! page-aligned offset from its linked addresses. It is normally loaded at the
+//! default offset of [`ElfInfo::offset`], but is moved up if that would
+//! overlap pages the stub maps itself. With the `kaslr` option of the stub
+//! configuration the offset is instead chosen randomly from [`KASLR_RANGE`].
+
+use crate::config;
+use common::elf::ElfInfo;
+use core::{arch::x86_64::_rdtsc, ops::Range};
+use x86_64::instructions::random::RdRand;
+
+/// Addresses the kernel is loaded in when randomized, clear of the kernel's
+/// heap, stacks and frame buffer mappings and below the offset mapping
+const KASLR_RANGE: Range<u64> = 0o20_000_000_0000..0o40_000_000_0000;
+/// Alignment of load offsets, so the kernel could be mapped with large pages
+const ALIGN: u64 = 0x200000;
+/// Number of offsets tried above the default one
+const TRIES: u64 = 16;
+
+/// Random number from RDRAND, or the time stamp counter without it
+fn random() -> u64 {
+    RdRand::new()
+        .and_then(|rdrand| rdrand.get_u64())
+        .unwrap_or_else(|| unsafe { _rdtsc() })
+}
+
+/// Choose the offset to load `kernel` at, such that it doesn't overlap
+/// `avoid`
+pub fn kernel_offset(kernel: &ElfInfo, avoid: Range<u64>) -> Result<u64, &'static str> {
+    // Addresses spanned by the kernel relative to the offset
+    let default = kernel.offset();
+    let start = kernel
+        .segments()
+        .map(|(pages, _)| pages.start.start_address().as_u64())
+        .min()
+        .ok_or("Kernel without loadable segments")?
+        - default;
+    let end = kernel
+        .segments()
+        .map(|(pages, _)| pages.end.start_address().as_u64() + 4096)
+        .max()
+        .ok_or("Kernel without loadable segments")?
+        - default;
+    let fits = |offset: u64| offset + end <= avoid.start || avoid.end <= offset + start;
+
+    let offset = if config::KASLR {
+        let slots = (KASLR_RANGE.end - KASLR_RANGE.start - end) / ALIGN;
+        let first = random() % slots;
+        (0..slots)
+            .map(|i| KASLR_RANGE.start + (first + i) % slots * ALIGN)
+            .find(|&offset| fits(offset))
+    } else {
+        (0..TRIES)
+            .map(|i| default + i * ALIGN)
+            .find(|&offset| fits(offset))
+    };
+    let offset = offset.ok_or("No room to load kernel")?;
+    if offset != default {
+        log::info!("Loading kernel at offset {:#x}", offset);
+    }
+    Ok(offset)
+}
@@ -0,0 +1,157 @@
+//! Boot progress on screen
+//!
+//! The log of the stub goes to the serial port, which many real machines lack,
+//! so the phases of the boot are shown on screen as well to tell where it
+//! stalls. With a frame buffer a splash screen shows the current phase above a
+//! progress bar; otherwise, or if configured, each phase is printed as a line
+//! on the UEFI console.
+
+use core::{cell::Cell, fmt::Write};
+use uefi::{
+    prelude::*,
+    proto::console::gop::{BltOp, BltPixel, GraphicsOutput},
+};
+
+/// How progress is shown
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub enum Mode {
+    /// Splash screen with a progress bar, or text without a frame buffer
+    Splash,
+    /// Line of text per phase
+    Text,
+    Off,
+}
+
+/// Phases of the boot, in order
+#[derive(Copy, Clone, Debug)]
+pub enum Phase {
+    Tables,
+    Graphics,
+    PageTables,
+    Kernel,
+    BootInfo,
+    Exit,
+}
+
+impl Phase {
+    /// Number of phases
+    pub const COUNT: usize = 6;
+
+    pub fn description(self) -> &'static str {
+        match self {
+            Phase::Tables => "Reading firmware tables",
+            Phase::Graphics => "Setting up graphics",
+            Phase::PageTables => "Creating page tables",
+            Phase::Kernel => "Mapping kernel",
+            Phase::BootInfo => "Preparing boot information",
+            Phase::Exit => "Starting kernel",
+        }
+    }
+}
+
+/// Row of the UEFI console showing the current phase on the splash screen
+const STATUS_ROW: usize = 2;
+/// Height of the progress bar in pixels
+const BAR_HEIGHT: usize = 8;
+/// Color of the part of the progress bar still to go, in RGB
+const BAR_BACKGROUND: (u8, u8, u8) = (0x30, 0x30, 0x30);
+/// Color of the part of the progress bar that is done, in RGB
+const BAR_FOREGROUND: (u8, u8, u8) = (0xe0, 0xe0, 0xe0);
+
+pub struct Progress {
+    mode: Mode,
+    /// Phase reported last
+    phase: Cell<Phase>,
+}
+
+impl Progress {
+    /// Prepare the screen for progress in the configured mode
+    pub fn new(system_table: &SystemTable<Boot>, mode: Mode) -> Self {
+        let mode = match mode {
+            Mode::Splash if gop(system_table).is_none() => Mode::Text,
+            mode => mode,
+        };
+        let progress = Self {
+            mode,
+            phase: Cell::new(Phase::Tables),
+        };
+        let stdout = system_table.stdout();
+        match mode {
+            Mode::Splash => {
+                let _ = stdout.clear().log_warning();
+                let _ = stdout.enable_cursor(false).log_warning();
+                let _ = writeln!(stdout, "ÅngstrÖS");
+                progress.fill(system_table, BAR_BACKGROUND, Phase::COUNT);
+            }
+            Mode::Text => {
+                let _ = writeln!(stdout, "ÅngstrÖS is booting");
+            }
+            Mode::Off => {}
+        }
+        progress
+    }
+
+    /// Show that `phase` started
+    pub fn report(&self, system_table: &SystemTable<Boot>, phase: Phase) {
+        self.phase.set(phase);
+        let stdout = system_table.stdout();
+        let step = phase as usize + 1;
+        match self.mode {
+            Mode::Splash => {
+                if stdout
+                    .set_cursor_position(0, STATUS_ROW)
+                    .log_warning()
+                    .is_ok()
+                {
+                    let _ = write!(stdout, "{:<40}", phase.description());
+                }
+                self.fill(system_table, BAR_FOREGROUND, step);
+            }
+            Mode::Text => {
+                let _ = writeln!(
+                    stdout,
+                    "[{}/{}] {}",
+                    step,
+                    Phase::COUNT,
+                    phase.description()
+                );
+            }
+            Mode::Off => {}
+        }
+    }
+
+    /// Phase the boot is in
+    pub fn phase(&self) -> Phase {
+        self.phase.get()
+    }
+
+    /// Fill `steps` out of all steps of the progress bar with `color`
+    fn fill(&self, system_table: &SystemTable<Boot>, color: (u8, u8, u8), steps: usize) {
+        let gop = match gop(system_table) {
+            Some(gop) => gop,
+            None => return,
+        };
+        let (width, height) = gop.current_mode_info().resolution();
+        let bar_width = width / 2;
+        let op = BltOp::VideoFill {
+            color: BltPixel::new(color.0, color.1, color.2),
+            dest: (width / 4, height * 2 / 3),
+            dims: (
+                bar_width * steps.min(Phase::COUNT) / Phase::COUNT,
+                BAR_HEIGHT,
+            ),
+        };
+        if gop.blt(op).log_warning().is_err() {
+            log::debug!("Failed to draw progress bar");
+        }
+    }
+}
+
+fn gop(system_table: &SystemTable<Boot>) -> Option<&mut GraphicsOutput> {
+    let gop = system_table
+        .boot_services()
+        .locate_protocol::<GraphicsOutput>()
+        .log_warning()
+        .ok()?;
+    Some(unsafe { &mut *gop.get() })
+}
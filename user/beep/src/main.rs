@@ -0,0 +1,45 @@
+#![no_std]
+#![no_main]
+#![feature(asm)]
+
+use core::panic::PanicInfo;
+use os::audio;
+
+/// Frequency of the tone in Hz
+const FREQUENCY: u32 = 440;
+/// Duration of the tone in milliseconds
+const DURATION: u32 = 500;
+const AMPLITUDE: i16 = 4000;
+
+#[no_mangle]
+extern "C" fn _start() {
+    let period = audio::SAMPLE_RATE / FREQUENCY;
+    let mut samples = [0; 2 * 1024];
+    let mut t = 0;
+    let total = audio::SAMPLE_RATE * DURATION / 1000;
+    while t < total {
+        let frames = samples.chunks_exact_mut(audio::CHANNELS);
+        let mut len = 0;
+        for frame in frames.take((total - t) as usize) {
+            // Square wave
+            let value = if t % period < period / 2 {
+                AMPLITUDE
+            } else {
+                -AMPLITUDE
+            };
+            frame.fill(value);
+            t += 1;
+            len += audio::CHANNELS;
+        }
+        if !audio::submit(&samples[..len]) {
+            os::log("No audio device available");
+            os::exit(1);
+        }
+    }
+    os::exit(0);
+}
+
+#[panic_handler]
+fn panic(_info: &PanicInfo) -> ! {
+    loop {}
+}
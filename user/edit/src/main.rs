@@ -0,0 +1,382 @@
+#![no_std]
+#![no_main]
+
+use core::{mem, panic::PanicInfo, ptr, slice};
+use font8x8::legacy::BASIC_LEGACY;
+use os::sys::{FrameBufferAccess, InputEvent};
+
+/// File edited, relative to the directory shared by the host
+const PATH: &str = "edit.txt";
+/// Maximum size of the file in bytes
+const CAPACITY: usize = 0x4000;
+/// Width and height of a character cell
+const CELL: usize = 8;
+/// White and black look the same in every pixel format
+const FOREGROUND: u32 = 0x00ff_ffff;
+const BACKGROUND: u32 = 0;
+
+/// Characters typed with the keys from `-` to `/` on a US keyboard, without
+/// and with shift; zero for the key that US keyboards lack
+const SYMBOLS: [&[u8; 12]; 2] = [b"-=[]\\\0;'`,./", b"_+{}|\0:\"~<>?"];
+const DIGITS: [&[u8; 10]; 2] = [b"1234567890", b"!@#$%^&*()"];
+
+/// Too large for the stack
+static mut BUFFER: [u8; CAPACITY] = [0; CAPACITY];
+
+/// Text being edited, with the cursor as byte offset
+struct Text {
+    buf: &'static mut [u8; CAPACITY],
+    len: usize,
+    cursor: usize,
+}
+
+impl Text {
+    fn as_bytes(&self) -> &[u8] {
+        &self.buf[..self.len]
+    }
+
+    /// Start of the line containing offset `at`
+    fn line_start(&self, at: usize) -> usize {
+        self.buf[..at]
+            .iter()
+            .rposition(|&b| b == b'\n')
+            .map_or(0, |i| i + 1)
+    }
+
+    /// End of the line containing offset `at`, before the newline
+    fn line_end(&self, at: usize) -> usize {
+        self.buf[at..self.len]
+            .iter()
+            .position(|&b| b == b'\n')
+            .map_or(self.len, |i| at + i)
+    }
+
+    /// Insert `byte` before the cursor, unless the text is full
+    fn insert(&mut self, byte: u8) -> bool {
+        if self.len == CAPACITY {
+            return false;
+        }
+        self.buf.copy_within(self.cursor..self.len, self.cursor + 1);
+        self.buf[self.cursor] = byte;
+        self.len += 1;
+        self.cursor += 1;
+        true
+    }
+
+    /// Remove the byte after the cursor, if any
+    fn delete(&mut self) -> bool {
+        if self.cursor == self.len {
+            return false;
+        }
+        self.buf.copy_within(self.cursor + 1..self.len, self.cursor);
+        self.len -= 1;
+        true
+    }
+
+    /// Remove the byte before the cursor, if any
+    fn backspace(&mut self) -> bool {
+        if self.cursor == 0 {
+            return false;
+        }
+        self.cursor -= 1;
+        self.delete()
+    }
+
+    /// Move the cursor to its column on the previous or next line, or the end
+    /// of that line if it is shorter
+    fn move_vertically(&mut self, up: bool) {
+        let start = self.line_start(self.cursor);
+        let column = self.cursor - start;
+        let target = if up {
+            if start == 0 {
+                return;
+            }
+            self.line_start(start - 1)
+        } else {
+            let end = self.line_end(self.cursor);
+            if end == self.len {
+                return;
+            }
+            end + 1
+        };
+        self.cursor = (target + column).min(self.line_end(target));
+    }
+}
+
+/// Key pressed while editing
+enum Key {
+    Char(u8),
+    Backspace,
+    Delete,
+    Left,
+    Right,
+    Up,
+    Down,
+    Home,
+    End,
+    Save,
+    Quit,
+}
+
+/// Key with USB HID usage id `usage`, on a US keyboard
+fn key(usage: u8, shift: bool, ctrl: bool) -> Option<Key> {
+    let key = match usage {
+        0x16 if ctrl => Key::Save,
+        0x14 if ctrl => Key::Quit,
+        _ if ctrl => return None,
+        0x04..=0x1d if shift => Key::Char(b'A' + usage - 0x04),
+        0x04..=0x1d => Key::Char(b'a' + usage - 0x04),
+        0x1e..=0x27 => Key::Char(DIGITS[shift as usize][(usage - 0x1e) as usize]),
+        0x28 => Key::Char(b'\n'),
+        0x2a => Key::Backspace,
+        0x2c => Key::Char(b' '),
+        0x2d..=0x38 => match SYMBOLS[shift as usize][(usage - 0x2d) as usize] {
+            0 => return None,
+            c => Key::Char(c),
+        },
+        0x4a => Key::Home,
+        0x4c => Key::Delete,
+        0x4d => Key::End,
+        0x4f => Key::Right,
+        0x50 => Key::Left,
+        0x51 => Key::Down,
+        0x52 => Key::Up,
+        _ => return None,
+    };
+    Some(key)
+}
+
+/// Frame buffer drawn on in character cells
+struct Screen {
+    buf: &'static mut [u32],
+    stride: usize,
+    columns: usize,
+    rows: usize,
+}
+
+impl Screen {
+    /// Draw `c` in the cell at `column` and `row`, with the colors swapped if
+    /// `inverted`; non-ASCII bytes are drawn as `?`
+    fn draw(&mut self, column: usize, row: usize, c: u8, inverted: bool) {
+        if column >= self.columns || row >= self.rows {
+            return;
+        }
+        let glyph = BASIC_LEGACY
+            .get(c as usize)
+            .unwrap_or(&BASIC_LEGACY[b'?' as usize]);
+        let (fg, bg) = if inverted {
+            (BACKGROUND, FOREGROUND)
+        } else {
+            (FOREGROUND, BACKGROUND)
+        };
+        for (y, bits) in glyph.iter().enumerate() {
+            let start = (row * CELL + y) * self.stride + column * CELL;
+            for (x, pixel) in self.buf[start..start + CELL].iter_mut().enumerate() {
+                let color = if bits & 1 << x != 0 { fg } else { bg };
+                unsafe { ptr::write_volatile(pixel, color) };
+            }
+        }
+    }
+
+    /// Draw `s` from `column` on `row`, returning the column after it
+    fn draw_str(&mut self, column: usize, row: usize, s: &[u8], inverted: bool) -> usize {
+        for (i, &c) in s.iter().enumerate() {
+            self.draw(column + i, row, c, inverted);
+        }
+        column + s.len()
+    }
+}
+
+/// Editor state besides the text
+struct Editor {
+    text: Text,
+    /// Line shown at the top of the screen
+    top: usize,
+    modified: bool,
+    /// Message shown in the status line
+    status: &'static str,
+    /// Whether quitting with unsaved changes was requested once
+    quit_requested: bool,
+}
+
+impl Editor {
+    /// Handle `key`, returning whether to quit
+    fn handle(&mut self, key: Key) -> bool {
+        let text = &mut self.text;
+        let changed = match key {
+            Key::Char(c) => {
+                let inserted = text.insert(c);
+                if !inserted {
+                    self.status = "File is full";
+                }
+                inserted
+            }
+            Key::Backspace => text.backspace(),
+            Key::Delete => text.delete(),
+            Key::Left => {
+                text.cursor = text.cursor.saturating_sub(1);
+                false
+            }
+            Key::Right => {
+                text.cursor = (text.cursor + 1).min(text.len);
+                false
+            }
+            Key::Up => {
+                text.move_vertically(true);
+                false
+            }
+            Key::Down => {
+                text.move_vertically(false);
+                false
+            }
+            Key::Home => {
+                text.cursor = text.line_start(text.cursor);
+                false
+            }
+            Key::End => {
+                text.cursor = text.line_end(text.cursor);
+                false
+            }
+            Key::Save => {
+                if os::host_write(PATH, text.as_bytes()) {
+                    self.modified = false;
+                    self.status = "Saved";
+                } else {
+                    self.status = "Could not save";
+                }
+                false
+            }
+            Key::Quit => {
+                if !self.modified || self.quit_requested {
+                    return true;
+                }
+                self.quit_requested = true;
+                self.status = "Unsaved changes, press Ctrl+Q again to quit";
+                return false;
+            }
+        };
+        if changed {
+            self.modified = true;
+        }
+        self.quit_requested = false;
+        false
+    }
+
+    /// Draw the visible lines and the status line
+    fn render(&mut self, screen: &mut Screen) {
+        let text = &self.text;
+        let rows = screen.rows - 1;
+        let line_start = text.line_start(text.cursor);
+        let cursor_row = text.buf[..line_start]
+            .iter()
+            .filter(|&&b| b == b'\n')
+            .count();
+        let cursor_column = (text.cursor - line_start).min(screen.columns - 1);
+        if cursor_row < self.top {
+            self.top = cursor_row;
+        } else if cursor_row >= self.top + rows {
+            self.top = cursor_row + 1 - rows;
+        }
+        let mut lines = text.as_bytes().split(|&b| b == b'\n').skip(self.top);
+        for row in 0..rows {
+            let line = lines.next().unwrap_or(&[]);
+            let end = screen.draw_str(0, row, &line[..line.len().min(screen.columns)], false);
+            for column in end..screen.columns {
+                screen.draw(column, row, b' ', false);
+            }
+        }
+        let under_cursor = match text.as_bytes().get(text.cursor) {
+            Some(b'\n') | None => b' ',
+            Some(&c) => c,
+        };
+        screen.draw(cursor_column, cursor_row - self.top, under_cursor, true);
+
+        let mut column = screen.draw_str(0, rows, PATH.as_bytes(), true);
+        if self.modified {
+            column = screen.draw_str(column, rows, b" [modified]", true);
+        }
+        column = screen.draw_str(column, rows, b" - Ctrl+S save, Ctrl+Q quit - ", true);
+        column = screen.draw_str(column, rows, self.status.as_bytes(), true);
+        for column in column..screen.columns {
+            screen.draw(column, rows, b' ', true);
+        }
+    }
+}
+
+#[no_mangle]
+extern "C" fn _start() {
+    let fb = match os::frame_buffer(FrameBufferAccess::Exclusive) {
+        Some(fb) => fb,
+        None => {
+            os::log("Screen access not granted");
+            os::exit(2);
+        }
+    };
+    let mut screen = Screen {
+        buf: unsafe {
+            slice::from_raw_parts_mut(fb.ptr as *mut u32, fb.size / mem::size_of::<u32>())
+        },
+        stride: fb.stride,
+        columns: fb.shape.0 / CELL,
+        rows: fb.shape.1 / CELL,
+    };
+    if screen.columns == 0 || screen.rows < 2 {
+        os::log("Screen too small");
+        os::exit(2);
+    }
+
+    // Only this function takes the buffer
+    let buf = unsafe { &mut BUFFER };
+    let (len, status) = match os::host_read(PATH, 0, buf) {
+        Some(CAPACITY) => (CAPACITY, "File too large, truncated"),
+        Some(len) => (len, "Opened"),
+        None => (0, "New file"),
+    };
+    let mut editor = Editor {
+        text: Text {
+            buf,
+            len,
+            cursor: 0,
+        },
+        top: 0,
+        modified: false,
+        status,
+        quit_requested: false,
+    };
+
+    let (mut shift, mut ctrl) = (false, false);
+    let mut dirty = true;
+    loop {
+        while let Some(event) = os::input_event() {
+            let (usage, pressed) = match event {
+                InputEvent::Key { usage, pressed } => (usage, pressed),
+                _ => continue,
+            };
+            match usage {
+                0xe0 | 0xe4 => ctrl = pressed,
+                0xe1 | 0xe5 => shift = pressed,
+                _ if pressed => {
+                    if let Some(key) = key(usage, shift, ctrl) {
+                        if editor.handle(key) {
+                            os::exit(0);
+                        }
+                        dirty = true;
+                    }
+                }
+                _ => {}
+            }
+        }
+        if dirty {
+            editor.render(&mut screen);
+            os::present();
+            dirty = false;
+        }
+        os::wait_vsync();
+    }
+}
+
+#[panic_handler]
+fn panic(_info: &PanicInfo) -> ! {
+    os::log("panic!");
+    os::exit(1);
+}
@@ -1,9 +1,14 @@
 #![no_std]
+#![cfg_attr(feature = "heap", feature(alloc_error_handler))]
 
 pub use sys;
+pub use ustd;
 
-use core::mem::{self, MaybeUninit};
-use sys::{syscall, FrameBuffer, SyscallCode};
+use core::mem::MaybeUninit;
+use sys::{
+    syscall, AuditRecord, FrameBuffer, FrameBufferAccess, HostRead, HostWrite, InputEvent,
+    LatencyHistogram, LatencySource, MemoryStats, ProcessInfo, SyscallCode, Telemetry, TraceRecord,
+};
 
 /// Exit with specified exit code
 pub fn exit(code: u64) -> ! {
@@ -19,14 +24,74 @@ pub fn log(msg: &str) {
     debug_assert_eq!(code, 0);
 }
 
+/// Shut down the system
+///
+/// The calling process is sent [`signal::Signal::Terminate`] like all others,
+/// so it should exit soon after. Returns `false` without power access.
+pub fn shutdown() -> bool {
+    unsafe { syscall(SyscallCode::Shutdown, 0, 0) == 0 }
+}
+
+/// Suspend to RAM until a wake event, such as the power button
+///
+/// Experimental; returns whether the system slept, right away if suspending
+/// is not supported or without power access.
+pub fn suspend() -> bool {
+    unsafe { syscall(SyscallCode::Suspend, 0, 0) == 0 }
+}
+
+/// Map at least `len` bytes of fresh zeroed memory
+///
+/// The memory stays mapped until [`munmap`] is called or the process exits.
+/// Returns [`None`] if the memory limit of the process would be exceeded.
+pub fn mmap(len: usize) -> Option<*mut u8> {
+    let mut addr = 0u64;
+    let code = unsafe { syscall(SyscallCode::Mmap, len as u64, &mut addr as *mut _ as u64) };
+    if code != 0 {
+        return None;
+    }
+    Some(addr as *mut u8)
+}
+
+/// Unmap the memory at `ptr` returned by [`mmap`]
+///
+/// # Safety
+/// No references into the memory may be used afterwards.
+pub unsafe fn munmap(ptr: *mut u8) -> bool {
+    syscall(SyscallCode::Munmap, ptr as u64, 0) == 0
+}
+
+/// Spawn a process running the embedded program called `name`
+///
+/// The process runs once the calling process has exited, with the handles
+/// granted to it, see [`handle::grant`]. Returns its process identifier, or
+/// [`None`] if there is no such program.
+pub fn spawn(name: &str) -> Option<u64> {
+    let mut pid = name.len() as u64;
+    let code = unsafe {
+        syscall(
+            SyscallCode::Spawn,
+            name.as_ptr() as u64,
+            &mut pid as *mut _ as u64,
+        )
+    };
+    if code != 0 {
+        return None;
+    }
+    Some(pid)
+}
+
 /// Obtain frame buffer
-pub fn frame_buffer() -> Option<FrameBuffer> {
+///
+/// The frame buffer stays mapped until [`release_frame_buffer`] is called or
+/// the process exits.
+pub fn frame_buffer(access: FrameBufferAccess) -> Option<FrameBuffer> {
     let fb = MaybeUninit::<FrameBuffer>::uninit();
     let code = unsafe {
         syscall(
             SyscallCode::FrameBuffer,
             &fb as *const _ as u64,
-            mem::size_of::<FrameBuffer>() as u64,
+            access as u64,
         )
     };
     if code != 0 {
@@ -34,3 +99,553 @@ pub fn frame_buffer() -> Option<FrameBuffer> {
     }
     Some(unsafe { fb.assume_init() })
 }
+
+/// Release frame buffer obtained by [`frame_buffer`]
+///
+/// Returns whether a frame buffer was released.
+///
+/// # Safety
+/// The memory of the frame buffer is unmapped, so it should not be accessed
+/// anymore.
+pub unsafe fn release_frame_buffer() -> bool {
+    syscall(SyscallCode::FrameBufferRelease, 0, 0) == 0
+}
+
+/// Wait for the next vertical blank of the frame buffer
+///
+/// Returns `false` immediately if no frame buffer access was obtained.
+pub fn wait_vsync() -> bool {
+    unsafe { syscall(SyscallCode::FbWaitVsync, 0, 0) == 0 }
+}
+
+/// Change the resolution of the frame buffer
+///
+/// Requires exclusive access obtained by [`frame_buffer`]. Returns the
+/// remapped frame buffer, whose resolution may be clamped to what the device
+/// supports. If the mode could not be set, [`None`] is returned and the
+/// previous frame buffer stays mapped.
+///
+/// # Safety
+/// If the mode is set, the previous frame buffer is unmapped, so it should not
+/// be accessed anymore.
+pub unsafe fn set_mode(width: u32, height: u32) -> Option<FrameBuffer> {
+    let fb = MaybeUninit::<FrameBuffer>::uninit();
+    let resolution = (width as u64) << 32 | height as u64;
+    let code = syscall(SyscallCode::FbSetMode, &fb as *const _ as u64, resolution);
+    if code != 0 {
+        return None;
+    }
+    Some(fb.assume_init())
+}
+
+/// Display changes made to the frame buffer
+///
+/// This is required for some frame buffers before changes become visible.
+/// Returns `false` if no frame buffer access was obtained.
+pub fn present() -> bool {
+    unsafe { syscall(SyscallCode::FbPresent, 0, 0) == 0 }
+}
+
+/// Print a screenshot of the frame buffer to the serial port
+///
+/// Returns `false` if no frame buffer access was obtained.
+pub fn screenshot() -> bool {
+    unsafe { syscall(SyscallCode::Screenshot, 0, 0) == 0 }
+}
+
+/// Take the oldest pending input event, if any
+///
+/// Events are only delivered to the focused process. If no process is focused,
+/// the first process calling this function gains focus.
+pub fn input_event() -> Option<InputEvent> {
+    let event = MaybeUninit::<InputEvent>::uninit();
+    let code = unsafe { syscall(SyscallCode::InputEvent, &event as *const _ as u64, 0) };
+    if code != 0 {
+        return None;
+    }
+    Some(unsafe { event.assume_init() })
+}
+
+/// Process id of the process receiving input, if any
+pub fn input_focus() -> Option<u64> {
+    let mut pid = 0u64;
+    unsafe { syscall(SyscallCode::InputFocus, &mut pid as *mut _ as u64, 0) };
+    if pid == 0 {
+        None
+    } else {
+        Some(pid)
+    }
+}
+
+/// Deliver input to process `pid`, or the calling process if [`None`]
+///
+/// Returns whether focus changed, which is only allowed for the focused
+/// process or if no process is focused.
+pub fn set_input_focus(pid: Option<u64>) -> bool {
+    unsafe { syscall(SyscallCode::InputSetFocus, pid.unwrap_or(0), 0) == 0 }
+}
+
+/// Next character typed on the keyboard, if any, without blocking
+///
+/// Other input events pending for the process are dropped.
+pub fn read_key() -> Option<char> {
+    let mut c = 0u32;
+    let code = unsafe { syscall(SyscallCode::ReadKey, &mut c as *mut _ as u64, 0) };
+    if code != 0 {
+        return None;
+    }
+    core::char::from_u32(c)
+}
+
+/// Read a line typed on the keyboard into `buf`, echoing it to the terminal
+///
+/// Blocks until Enter is pressed, in which case the line ends with a newline,
+/// or `buf` is full. Returns [`None`] on Ctrl+C or a shutdown.
+pub fn read_line(buf: &mut [u8]) -> Option<&str> {
+    let mut len = buf.len();
+    let code = unsafe {
+        syscall(
+            SyscallCode::ReadLine,
+            buf.as_mut_ptr() as u64,
+            &mut len as *mut _ as u64,
+        )
+    };
+    if code != 0 {
+        return None;
+    }
+    core::str::from_utf8(&buf[..len]).ok()
+}
+
+/// Move events recorded by the kernel tracer into `buf`
+///
+/// Returns the number of events stored, or [`None`] if tracing is disabled or
+/// without trace access.
+pub fn drain_trace(buf: &mut [TraceRecord]) -> Option<usize> {
+    let mut len = buf.len();
+    let code = unsafe {
+        syscall(
+            SyscallCode::TraceDrain,
+            buf.as_mut_ptr() as u64,
+            &mut len as *mut _ as u64,
+        )
+    };
+    if code != 0 {
+        return None;
+    }
+    Some(len)
+}
+
+/// Move records of the kernel audit log into `buf`
+///
+/// Returns the number of records stored, or [`None`] without a handle to the
+/// audit log.
+pub fn drain_audit(buf: &mut [AuditRecord]) -> Option<usize> {
+    let mut len = buf.len();
+    let code = unsafe {
+        syscall(
+            SyscallCode::AuditDrain,
+            buf.as_mut_ptr() as u64,
+            &mut len as *mut _ as u64,
+        )
+    };
+    if code != 0 {
+        return None;
+    }
+    Some(len)
+}
+
+/// Histogram of latencies of `source` measured by the kernel
+///
+/// Returns [`None`] if latency measurement is disabled.
+pub fn latency_histogram(source: LatencySource) -> Option<LatencyHistogram> {
+    let histogram = MaybeUninit::<LatencyHistogram>::uninit();
+    let code = unsafe {
+        syscall(
+            SyscallCode::Latency,
+            source as u64,
+            &histogram as *const _ as u64,
+        )
+    };
+    if code != 0 {
+        return None;
+    }
+    Some(unsafe { histogram.assume_init() })
+}
+
+/// Temperature and energy use of the CPU package
+pub fn telemetry() -> Telemetry {
+    let telemetry = MaybeUninit::<Telemetry>::uninit();
+    unsafe { syscall(SyscallCode::Telemetry, &telemetry as *const _ as u64, 0) };
+    unsafe { telemetry.assume_init() }
+}
+
+/// Read the file at `path` in the directory shared by the host from `offset`
+/// into `buf`
+///
+/// Returns the number of bytes read, which is less than the length of `buf`
+/// only at the end of the file, or `None` if there is no shared directory or
+/// the file cannot be read.
+pub fn host_read(path: &str, offset: u64, buf: &mut [u8]) -> Option<usize> {
+    let request = HostRead {
+        path: path.as_ptr(),
+        path_len: path.len(),
+        offset,
+        buf: buf.as_mut_ptr(),
+        buf_len: buf.len(),
+    };
+    let mut read = 0usize;
+    let code = unsafe {
+        syscall(
+            SyscallCode::HostRead,
+            &request as *const _ as u64,
+            &mut read as *mut _ as u64,
+        )
+    };
+    if code != 0 {
+        return None;
+    }
+    Some(read)
+}
+
+/// Replace the contents of the file at `path` in the directory shared by the
+/// host with `data`, creating the file if it does not exist
+///
+/// Returns `false` if there is no shared directory, it is read-only or the
+/// file cannot be written.
+pub fn host_write(path: &str, data: &[u8]) -> bool {
+    let request = HostWrite {
+        path: path.as_ptr(),
+        path_len: path.len(),
+        buf: data.as_ptr(),
+        buf_len: data.len(),
+    };
+    unsafe { syscall(SyscallCode::HostWrite, &request as *const _ as u64, 0) == 0 }
+}
+
+/// Store information on running processes in `buf`
+///
+/// Returns the number of processes stored.
+pub fn processes(buf: &mut [ProcessInfo]) -> usize {
+    let mut len = buf.len();
+    unsafe {
+        syscall(
+            SyscallCode::ProcessList,
+            buf.as_mut_ptr() as u64,
+            &mut len as *mut _ as u64,
+        )
+    };
+    len
+}
+
+/// Use of physical memory and the kernel heap
+pub fn memory_stats() -> MemoryStats {
+    let stats = MaybeUninit::<MemoryStats>::uninit();
+    unsafe { syscall(SyscallCode::MemoryStats, &stats as *const _ as u64, 0) };
+    unsafe { stats.assume_init() }
+}
+
+/// Report the outcome of a test suite to the kernel, see
+/// [`SyscallCode::TestReport`]
+pub fn test_report(passed: u64, failed: u64) {
+    unsafe { syscall(SyscallCode::TestReport, passed, failed) };
+}
+
+/// Global allocator backed by [`mmap`], enabled by the `heap` feature
+///
+/// Programs can then use `Vec`, `Box` and `String` after `extern crate alloc`.
+/// Memory is mapped in chunks of at least [`CHUNK`] bytes and never unmapped.
+/// The heap is not reentrant: allocating in a signal handler that interrupted
+/// an allocation fails, and freeing there leaks the memory.
+#[cfg(feature = "heap")]
+pub mod heap {
+    use super::*;
+    use core::{
+        alloc::{GlobalAlloc, Layout},
+        cell::UnsafeCell,
+        ptr::{self, NonNull},
+        sync::atomic::{AtomicBool, Ordering},
+    };
+    use ustd::heap::FreeList;
+
+    /// Minimum number of bytes mapped at a time
+    pub const CHUNK: usize = 64 * 1024;
+
+    #[global_allocator]
+    static HEAP: Heap = Heap {
+        list: UnsafeCell::new(FreeList::new()),
+        busy: AtomicBool::new(false),
+    };
+
+    struct Heap {
+        list: UnsafeCell<FreeList>,
+        /// Whether the list is in use, possibly by code a signal handler
+        /// interrupted
+        busy: AtomicBool,
+    }
+
+    // The list is only accessed while holding `busy`
+    unsafe impl Sync for Heap {}
+
+    impl Heap {
+        /// Run `f` on the list, unless it is in use
+        fn with_list<F: FnOnce(&mut FreeList) -> T, T>(&self, f: F) -> Option<T> {
+            if self.busy.swap(true, Ordering::Acquire) {
+                return None;
+            }
+            let result = f(unsafe { &mut *self.list.get() });
+            self.busy.store(false, Ordering::Release);
+            Some(result)
+        }
+    }
+
+    /// Map memory in which `layout` fits and add it to `list`
+    fn grow(list: &mut FreeList, layout: Layout) -> Option<()> {
+        let len = (layout.size() + layout.align()).max(CHUNK);
+        // Whole pages are mapped anyway
+        let len = (len + 4095) / 4096 * 4096;
+        let start = mmap(len)?;
+        unsafe { list.add(start, len) };
+        Some(())
+    }
+
+    unsafe impl GlobalAlloc for Heap {
+        unsafe fn alloc(&self, layout: Layout) -> *mut u8 {
+            let ptr = self.with_list(|list| {
+                list.allocate(layout).or_else(|| {
+                    grow(list, layout)?;
+                    list.allocate(layout)
+                })
+            });
+            ptr.flatten().map_or(ptr::null_mut(), NonNull::as_ptr)
+        }
+
+        unsafe fn dealloc(&self, ptr: *mut u8, layout: Layout) {
+            if let Some(ptr) = NonNull::new(ptr) {
+                self.with_list(|list| list.deallocate(ptr, layout));
+            }
+        }
+    }
+
+    #[alloc_error_handler]
+    fn alloc_error(_layout: Layout) -> ! {
+        log("Out of memory");
+        exit(1);
+    }
+}
+
+/// Audio playback
+pub mod audio {
+    use super::*;
+
+    pub use sys::{AUDIO_CHANNELS as CHANNELS, AUDIO_SAMPLE_RATE as SAMPLE_RATE};
+
+    /// Play interleaved stereo samples at [`SAMPLE_RATE`]
+    ///
+    /// Blocks until all samples are queued. Returns `false` if no audio device
+    /// is available.
+    pub fn submit(samples: &[i16]) -> bool {
+        unsafe {
+            syscall(
+                SyscallCode::AudioSubmit,
+                samples.as_ptr() as u64,
+                samples.len() as u64,
+            ) == 0
+        }
+    }
+}
+
+/// Signals sent to processes
+pub mod signal {
+    use super::*;
+    use core::sync::atomic::{AtomicUsize, Ordering};
+
+    pub use sys::Signal;
+
+    /// Size of the stack signal handlers run on
+    const STACK_SIZE: usize = 4096;
+
+    #[repr(align(16))]
+    struct Stack([u8; STACK_SIZE]);
+
+    static mut STACK: Stack = Stack([0; STACK_SIZE]);
+    /// Handler registered with [`set_handler`]
+    static HANDLER: AtomicUsize = AtomicUsize::new(0);
+
+    /// Send `signal` to process `pid`, or the calling process if [`None`]
+    ///
+    /// Returns `false` if there is no such process.
+    pub fn kill(pid: Option<u64>, signal: Signal) -> bool {
+        unsafe { syscall(SyscallCode::Kill, pid.unwrap_or(0), signal as u64) == 0 }
+    }
+
+    /// Call `handler` on a dedicated stack for signals that can be handled,
+    /// or terminate on them if [`None`]
+    ///
+    /// Execution continues where it was interrupted when the handler returns.
+    pub fn set_handler(handler: Option<fn(Signal)>) {
+        HANDLER.store(
+            handler.map_or(0, |handler| handler as usize),
+            Ordering::Relaxed,
+        );
+        let (entry, stack) = match handler {
+            Some(_) => (
+                trampoline as u64,
+                unsafe { STACK.0.as_ptr() } as u64 + STACK_SIZE as u64,
+            ),
+            None => (0, 0),
+        };
+        unsafe { syscall(SyscallCode::SignalHandler, entry, stack) };
+    }
+
+    /// Entry point of signal handlers, called by the kernel
+    extern "C" fn trampoline(signal: u64) -> ! {
+        let signal = match signal {
+            x if x == Signal::Terminate as u64 => Signal::Terminate,
+            _ => Signal::Kill,
+        };
+        let handler = HANDLER.load(Ordering::Relaxed);
+        if handler != 0 {
+            let handler: fn(Signal) = unsafe { core::mem::transmute(handler) };
+            handler(signal);
+        }
+        unsafe { syscall(SyscallCode::SignalReturn, 0, 0) };
+        unreachable!("Kernel should have returned from signal handler");
+    }
+}
+
+/// Time without system calls
+pub mod time {
+    use core::{
+        arch::x86_64::_rdtsc,
+        ptr,
+        sync::atomic::{fence, Ordering},
+        time::Duration,
+    };
+    use sys::{syscall, SyscallCode, TimePage, TIME_PAGE_ADDR};
+
+    /// Block for at least `duration`
+    ///
+    /// Returns `false` early if a shutdown is requested.
+    pub fn sleep(duration: Duration) -> bool {
+        let ns = duration.as_nanos().min(u64::MAX as u128) as u64;
+        unsafe { syscall(SyscallCode::Sleep, ns, 0) == 0 }
+    }
+
+    /// Time since boot, read from the [`TimePage`] mapped by the kernel
+    pub fn monotonic() -> Duration {
+        let page = TIME_PAGE_ADDR as *const TimePage;
+        let sequence = || unsafe { ptr::addr_of!((*page).sequence).read_volatile() };
+        loop {
+            let start = sequence();
+            fence(Ordering::Acquire);
+            let time = unsafe { page.read_volatile() };
+            let tsc = unsafe { _rdtsc() };
+            fence(Ordering::Acquire);
+            if start % 2 != 0 || sequence() != start {
+                continue;
+            }
+            let elapsed = tsc.saturating_sub(time.epoch_tsc) as u128 * 1_000_000_000
+                / time.tsc_frequency.max(1) as u128;
+            return Duration::from_nanos(time.epoch_ns + elapsed as u64);
+        }
+    }
+}
+
+/// Process groups, for job control
+pub mod jobs {
+    use sys::{syscall, SyscallCode};
+
+    /// Move the calling process to process group `pgid`, or to a new group of
+    /// its own if [`None`]
+    ///
+    /// Returns `false` if the group has no processes.
+    pub fn set_process_group(pgid: Option<u64>) -> bool {
+        unsafe { syscall(SyscallCode::SetProcessGroup, pgid.unwrap_or(0), 0) == 0 }
+    }
+
+    /// Put process group `pgid`, or that of the calling process if [`None`], in
+    /// the foreground of the terminal, so Ctrl+C terminates its processes
+    ///
+    /// Returns `false` if the group of the calling process is not in the
+    /// foreground while another group is, or if `pgid` has no processes.
+    pub fn set_foreground(pgid: Option<u64>) -> bool {
+        unsafe { syscall(SyscallCode::SetForeground, pgid.unwrap_or(0), 0) == 0 }
+    }
+
+    /// Stop process `pid`, or the calling process if [`None`], until it is
+    /// continued with [`resume`]
+    ///
+    /// Returns `false` if there is no such process in the group of the calling
+    /// process, or if it would stop itself without a terminal to continue it.
+    pub fn stop(pid: Option<u64>) -> bool {
+        unsafe { syscall(SyscallCode::Stop, pid.unwrap_or(0), 0) == 0 }
+    }
+
+    /// Continue process `pid` stopped with [`stop`]
+    ///
+    /// Returns `false` if there is no such process in the group of the calling
+    /// process.
+    pub fn resume(pid: u64) -> bool {
+        unsafe { syscall(SyscallCode::Continue, pid, 0) == 0 }
+    }
+}
+
+/// Handles to kernel objects
+pub mod handle {
+    use core::mem::MaybeUninit;
+    use sys::{syscall, ObjectKind, Rights, SyscallCode};
+
+    /// Handle to an object of `kind`, if the process holds one
+    pub fn find(kind: ObjectKind) -> Option<u64> {
+        let handle = MaybeUninit::<u64>::uninit();
+        let code = unsafe {
+            syscall(
+                SyscallCode::HandleFind,
+                kind as u64,
+                &handle as *const _ as u64,
+            )
+        };
+        if code != 0 {
+            return None;
+        }
+        Some(unsafe { handle.assume_init() })
+    }
+
+    /// Rights granted by `handle`, or [`None`] if it is invalid
+    pub fn rights(handle: u64) -> Option<Rights> {
+        let rights = MaybeUninit::<Rights>::uninit();
+        let code = unsafe {
+            syscall(
+                SyscallCode::HandleRights,
+                handle,
+                &rights as *const _ as u64,
+            )
+        };
+        if code != 0 {
+            return None;
+        }
+        Some(unsafe { rights.assume_init() })
+    }
+
+    /// Drop all rights of `handle` not in `rights`
+    ///
+    /// Returns `false` if the handle is invalid.
+    pub fn restrict(handle: u64, rights: Rights) -> bool {
+        unsafe { syscall(SyscallCode::HandleRestrict, handle, rights.0 as u64) == 0 }
+    }
+
+    /// Grant a copy of `handle` with only `rights` to the next process spawned
+    /// with [`spawn`](crate::spawn)
+    ///
+    /// Returns `false` if the handle is invalid or too many handles are granted.
+    pub fn grant(handle: u64, rights: Rights) -> bool {
+        unsafe { syscall(SyscallCode::HandleGrant, handle, rights.0 as u64) == 0 }
+    }
+
+    /// Close `handle`
+    ///
+    /// Returns `false` if the handle is invalid.
+    pub fn close(handle: u64) -> bool {
+        unsafe { syscall(SyscallCode::HandleClose, handle, 0) == 0 }
+    }
+}
@@ -1,10 +1,23 @@
 #![no_std]
 #![no_main]
 
-use core::{mem, panic::PanicInfo, slice};
-use os::sys::PixelFormat;
+use core::{fmt::Write, mem, panic::PanicInfo, slice, time::Duration};
+use os::{
+    sys::{FrameBufferAccess, PixelFormat},
+    time,
+    ustd::LineWriter,
+};
 use volatile::Volatile;
 
+/// Frames drawn per second at most
+const FRAME_RATE: u32 = 60;
+/// Number of frames drawn before reporting the timing and exiting
+const FRAMES: u32 = 600;
+/// Side of the bouncing square in pixels
+const SQUARE: usize = 64;
+/// Distance moved per frame by the square and the gradient, in pixels
+const SPEED: usize = 4;
+
 #[derive(Copy, Clone, Debug, PartialEq, Eq)]
 #[repr(C, align(4))]
 pub struct Pixel {
@@ -29,10 +42,111 @@ pub struct FrameBuffer {
     format: PixelFormat,
 }
 
+impl FrameBuffer {
+    /// Draw a gradient shifted by `offset` pixels, with a white square at
+    /// `square`
+    fn draw(&mut self, offset: usize, square: (usize, usize)) {
+        let (w, h) = self.shape;
+        let (sx, sy) = square;
+        for y in 0..h {
+            for x in 0..w {
+                let in_square = (sx..sx + SQUARE).contains(&x) && (sy..sy + SQUARE).contains(&y);
+                let pixel = if in_square {
+                    Pixel::new(0xff, 0xff, 0xff, self.format)
+                } else {
+                    let r = 0xff * ((x + offset) % w) / w;
+                    let g = 0xff * y / h;
+                    Pixel::new(r as u8, g as u8, 0xff, self.format)
+                };
+                self.buf.index_mut(y * self.stride + x).write(pixel);
+            }
+        }
+    }
+}
+
+/// Position along an axis that moves back and forth between its ends
+struct Bounce {
+    pos: usize,
+    forward: bool,
+}
+
+impl Bounce {
+    /// Move by [`SPEED`] along an axis of `len` pixels
+    fn step(&mut self, len: usize) {
+        let max = len.saturating_sub(SQUARE);
+        if self.forward {
+            self.pos = (self.pos + SPEED).min(max);
+            self.forward = self.pos < max;
+        } else {
+            self.pos = self.pos.saturating_sub(SPEED);
+            self.forward = self.pos == 0;
+        }
+    }
+}
+
+/// Time taken to draw and present frames
+#[derive(Default)]
+struct Stats {
+    frames: u32,
+    total: Duration,
+    max: Duration,
+    /// Frames that took longer than the frame period
+    late: u32,
+}
+
+impl Stats {
+    fn record(&mut self, frame: Duration, period: Duration) {
+        self.frames += 1;
+        self.total += frame;
+        self.max = self.max.max(frame);
+        if frame > period {
+            self.late += 1;
+        }
+    }
+}
+
+/// Animate until [`FRAMES`] frames are drawn or a shutdown is requested,
+/// sleeping between frames to keep to [`FRAME_RATE`]
+fn animate(fb: &mut FrameBuffer) -> (Stats, Duration) {
+    let period = Duration::from_secs(1) / FRAME_RATE;
+    let (w, h) = fb.shape;
+    let mut x = Bounce {
+        pos: 0,
+        forward: true,
+    };
+    let mut y = Bounce {
+        pos: h / 3,
+        forward: true,
+    };
+    let mut stats = Stats::default();
+    let start = time::monotonic();
+    let mut deadline = start;
+    for frame in 0..FRAMES as usize {
+        let begin = time::monotonic();
+        fb.draw(frame * SPEED, (x.pos, y.pos));
+        os::present();
+        stats.record(time::monotonic() - begin, period);
+        x.step(w);
+        y.step(h);
+
+        deadline += period;
+        let now = time::monotonic();
+        if now < deadline {
+            if !time::sleep(deadline - now) {
+                break;
+            }
+        } else {
+            // Late frames are not caught up on
+            deadline = now;
+        }
+    }
+    (stats, time::monotonic() - start)
+}
+
 #[no_mangle]
 extern "C" fn _start() {
     os::log("Obtaining screen access...");
-    let fb = os::frame_buffer();
+    let fb = os::frame_buffer(FrameBufferAccess::Exclusive);
     if let Some(fb) = fb {
         os::log("Screen access obtained!");
         let buf = unsafe {
@@ -44,17 +158,26 @@ extern "C" fn _start() {
             stride: fb.stride,
             format: fb.format,
         };
-        let (w, h) = fb.shape;
-        for y in 0..h {
-            for x in 0..w {
-                let r = 0xff * x / w;
-                let g = 0xff * y / h;
-                let b = 0xff;
-                fb.buf
-                    .index_mut(y * fb.stride + x)
-                    .write(Pixel::new(r as u8, g as u8, b, fb.format));
-            }
-        }
+        let (stats, elapsed) = animate(&mut fb);
+        let frames = stats.frames.max(1);
+        let mut out = LineWriter::<_, 128>::new(os::log);
+        let _ = writeln!(
+            out,
+            "Drew {} frames of {}x{} in {} ms, {} frames per second",
+            stats.frames,
+            fb.shape.0,
+            fb.shape.1,
+            elapsed.as_millis(),
+            stats.frames as u128 * 1000 / elapsed.as_millis().max(1)
+        );
+        let _ = writeln!(
+            out,
+            "Frame time average {} us, max {} us, {} over the {} us budget",
+            (stats.total / frames).as_micros(),
+            stats.max.as_micros(),
+            stats.late,
+            (Duration::from_secs(1) / FRAME_RATE).as_micros()
+        );
     } else {
         os::log("Screen access not granted");
         os::exit(2);
@@ -0,0 +1,118 @@
+//! Userspace test suite, run by `cargo xtask test` after the kernel tests
+//!
+//! Tests return an error message instead of panicking, as there is no
+//! unwinding; the counts are reported with [`os::test_report`]. A panic exits
+//! without reporting, which the kernel counts as a failure.
+
+#![no_std]
+#![no_main]
+#![feature(asm)]
+
+extern crate alloc;
+
+use alloc::{boxed::Box, string::String, vec, vec::Vec};
+use core::panic::PanicInfo;
+use os::{
+    handle,
+    sys::{ObjectKind, Rights},
+    time,
+};
+
+type Result = core::result::Result<(), &'static str>;
+
+const TESTS: [(&str, fn() -> Result); 6] = [
+    ("monotonic", monotonic),
+    ("handle_restrict", handle_restrict),
+    ("invalid_handle", invalid_handle),
+    ("missing_host_file", missing_host_file),
+    ("mmap", mmap),
+    ("heap", heap),
+];
+
+fn monotonic() -> Result {
+    let start = time::monotonic();
+    if time::monotonic() < start {
+        return Err("Time went backwards");
+    }
+    Ok(())
+}
+
+fn handle_restrict() -> Result {
+    let handle = handle::find(ObjectKind::Input).ok_or("No input handle")?;
+    if !handle::restrict(handle, Rights::READ) {
+        return Err("Restricting failed");
+    }
+    match handle::rights(handle) {
+        Some(Rights::READ) => Ok(()),
+        _ => Err("Rights not restricted"),
+    }
+}
+
+fn invalid_handle() -> Result {
+    if handle::rights(u64::MAX).is_some() || handle::close(u64::MAX) {
+        return Err("Invalid handle accepted");
+    }
+    Ok(())
+}
+
+fn missing_host_file() -> Result {
+    let mut buf = [0; 16];
+    match os::host_read("selftest/missing", 0, &mut buf) {
+        Some(_) => Err("Read missing file"),
+        None => Ok(()),
+    }
+}
+
+fn mmap() -> Result {
+    let ptr = os::mmap(8192).ok_or("Mapping failed")?;
+    let memory = unsafe { core::slice::from_raw_parts_mut(ptr, 8192) };
+    if memory.iter().any(|&byte| byte != 0) {
+        return Err("Memory not zeroed");
+    }
+    memory[8191] = 1;
+    if !unsafe { os::munmap(ptr) } {
+        return Err("Unmapping failed");
+    }
+    if unsafe { os::munmap(ptr) } {
+        return Err("Unmapped twice");
+    }
+    Ok(())
+}
+
+fn heap() -> Result {
+    let mut v: Vec<u64> = (0..10_000).collect();
+    v.retain(|x| x % 2 == 0);
+    if v.iter().sum::<u64>() != 24_995_000 {
+        return Err("Vector corrupted");
+    }
+    let boxed = Box::new(7u64);
+    let large = vec![7u8; 100_000];
+    let mut s = String::new();
+    for _ in 0..100 {
+        s.push_str("heap");
+    }
+    if s.len() != 400 || *boxed != 7 || large.iter().any(|&byte| byte != 7) {
+        return Err("Allocations overlap");
+    }
+    Ok(())
+}
+
+#[no_mangle]
+extern "C" fn _start() {
+    let mut failed = 0;
+    for (name, test) in TESTS.iter() {
+        if let Err(e) = test() {
+            os::log(name);
+            os::log(e);
+            failed += 1;
+        }
+    }
+    os::test_report(TESTS.len() as u64 - failed, failed);
+    os::exit(failed);
+}
+
+#[panic_handler]
+fn panic(_info: &PanicInfo) -> ! {
+    os::log("Test suite panicked");
+    os::exit(1);
+}
@@ -0,0 +1,95 @@
+//! Process stress program, spawned repeatedly by `cargo xtask test` after the
+//! user test suites
+//!
+//! Every run makes a mix of system calls that create and drop kernel state
+//! for the process and checks their results like a test suite, reporting the
+//! counts with [`os::test_report`]. The kernel checks that its memory use is
+//! the same after all runs as after the first.
+
+#![no_std]
+#![no_main]
+#![feature(asm)]
+
+use core::{panic::PanicInfo, time::Duration};
+use os::{
+    handle, jobs,
+    sys::{ObjectKind, ProcessInfo, Rights},
+    time,
+};
+
+type Result = core::result::Result<(), &'static str>;
+
+const STEPS: [(&str, fn() -> Result); 5] = [
+    ("log", log),
+    ("memory_stats", memory_stats),
+    ("process_list", process_list),
+    ("handles", handles),
+    ("sleep", sleep),
+];
+
+fn log() -> Result {
+    os::log("Stress process running");
+    Ok(())
+}
+
+fn memory_stats() -> Result {
+    let stats = os::memory_stats();
+    if stats.free > stats.total || stats.heap_used > stats.heap_size {
+        return Err("Inconsistent memory statistics");
+    }
+    Ok(())
+}
+
+fn process_list() -> Result {
+    let mut buf = [ProcessInfo::default(); 8];
+    match os::processes(&mut buf) {
+        0 => Err("No processes listed"),
+        len if buf[..len].iter().all(|info| info.syscalls == 0) => {
+            Err("Running process made no system calls")
+        }
+        _ => Ok(()),
+    }
+}
+
+fn handles() -> Result {
+    let handle = handle::find(ObjectKind::Input).ok_or("No input handle")?;
+    if !handle::restrict(handle, Rights::READ) || !handle::close(handle) {
+        return Err("Handle operations failed");
+    }
+    if !jobs::set_process_group(None) {
+        return Err("Could not create a process group");
+    }
+    Ok(())
+}
+
+fn sleep() -> Result {
+    let start = time::monotonic();
+    let duration = Duration::from_millis(1);
+    if !time::sleep(duration) {
+        return Err("Sleep interrupted");
+    }
+    if time::monotonic() - start < duration {
+        return Err("Woke up early");
+    }
+    Ok(())
+}
+
+#[no_mangle]
+extern "C" fn _start() {
+    let mut failed = 0;
+    for (name, step) in STEPS.iter() {
+        if let Err(e) = step() {
+            os::log(name);
+            os::log(e);
+            failed += 1;
+        }
+    }
+    os::test_report(STEPS.len() as u64 - failed, failed);
+    os::exit(failed);
+}
+
+#[panic_handler]
+fn panic(_info: &PanicInfo) -> ! {
+    os::log("Stress process panicked");
+    os::exit(1);
+}
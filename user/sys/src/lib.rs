@@ -7,6 +7,304 @@ pub enum PixelFormat {
     Rgb,
 }
 
+/// Kind of access requested to the frame buffer
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub enum FrameBufferAccess {
+    /// No other process may access the frame buffer at the same time
+    Exclusive = 0,
+    /// Access is shared with other processes that requested shared access
+    Shared = 1,
+}
+
+/// Button of a mouse
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+#[repr(u8)]
+pub enum MouseButton {
+    Left,
+    Right,
+    Middle,
+}
+
+/// Event generated by an input device
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+#[repr(C)]
+pub enum InputEvent {
+    /// Relative mouse movement, with positive `dy` pointing down, and the
+    /// resulting cursor position on screen
+    MouseMove {
+        x: u32,
+        y: u32,
+        dx: i32,
+        dy: i32,
+    },
+    MouseButton {
+        button: MouseButton,
+        pressed: bool,
+    },
+    /// Scroll wheel movement, with positive `delta` pointing towards the user
+    MouseScroll {
+        delta: i32,
+    },
+    /// Key press or release, identified by its usage id on the keyboard page
+    /// of the USB HID usage tables
+    Key {
+        usage: u8,
+        pressed: bool,
+    },
+}
+
+/// Kind of event recorded by the kernel tracer
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+#[repr(u32)]
+pub enum TraceKind {
+    /// Switch to the process with id in `arg`, or to the kernel if zero
+    ContextSwitch = 0,
+    /// Start of system call with code in `arg`
+    SyscallEnter = 1,
+    /// End of system call with return code in `arg`
+    SyscallExit = 2,
+    /// Start of interrupt handler for vector in `arg`
+    IrqEnter = 3,
+    /// End of interrupt handler for vector in `arg`
+    IrqExit = 4,
+    /// Heap allocation of `arg` bytes
+    Alloc = 5,
+    /// Heap deallocation of `arg` bytes
+    Free = 6,
+}
+
+/// Event recorded by the kernel tracer
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+#[repr(C)]
+pub struct TraceRecord {
+    /// Value of the time stamp counter when the event occurred
+    pub timestamp: u64,
+    pub kind: TraceKind,
+    /// Local APIC id of the CPU the event occurred on
+    pub cpu: u32,
+    pub arg: u64,
+}
+
+/// Kind of security-relevant operation recorded in the kernel audit log
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+#[repr(u32)]
+pub enum AuditKind {
+    /// Process spawned with the number of handles in `arg`
+    Spawn = 0,
+    /// Handle with index in the upper half of `arg` granted with the
+    /// [`Rights`] in the lower half, see [`SyscallCode::HandleGrant`]
+    Grant = 1,
+    /// Signal sent to the process with id in `arg`
+    Kill = 2,
+    /// Executable memory mapped at the address in `arg`
+    ExecMap = 3,
+    /// System call on an object of the [`ObjectKind`] in the upper half of
+    /// `arg` needing the [`Rights`] in the lower half. Only denials and the
+    /// first allowed call per kind of object are recorded for each process.
+    DeviceAccess = 4,
+}
+
+/// Operation recorded in the kernel audit log
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+#[repr(C)]
+pub struct AuditRecord {
+    /// Value of the time stamp counter when the operation occurred
+    pub timestamp: u64,
+    pub kind: AuditKind,
+    /// Whether the operation was allowed and performed
+    pub allowed: bool,
+    /// Process performing the operation, or zero for the kernel
+    pub pid: u64,
+    pub arg: u64,
+}
+
+/// Number of buckets of a [`LatencyHistogram`]
+pub const LATENCY_BUCKETS: usize = 64;
+
+/// Kind of code whose latency is measured by the kernel
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub enum LatencySource {
+    /// Interrupt handlers
+    Interrupt = 0,
+    /// System calls, excluding [`SyscallCode::Exit`]
+    Syscall = 1,
+}
+
+/// Kind of kernel object a handle refers to
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub enum ObjectKind {
+    FrameBuffer = 0,
+    Audio = 1,
+    Input = 2,
+    /// Directory shared by the host, see [`SyscallCode::HostRead`] and
+    /// [`SyscallCode::HostWrite`]
+    HostFs = 3,
+    /// Audit log of the kernel, see [`SyscallCode::AuditDrain`]
+    AuditLog = 4,
+    /// Power state of the system, see [`SyscallCode::Shutdown`] and
+    /// [`SyscallCode::Suspend`]
+    Power = 5,
+    /// Events recorded by the kernel tracer, see [`SyscallCode::TraceDrain`]
+    Trace = 6,
+}
+
+/// Rights a handle grants on its object
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+#[repr(transparent)]
+pub struct Rights(pub u32);
+
+impl Rights {
+    pub const NONE: Self = Self(0);
+    /// Read state or events of the object
+    pub const READ: Self = Self(1 << 0);
+    /// Change the object or the output it produces
+    pub const WRITE: Self = Self(1 << 1);
+    /// Map memory of the object
+    pub const MAP: Self = Self(1 << 2);
+    pub const ALL: Self = Self(Self::READ.0 | Self::WRITE.0 | Self::MAP.0);
+
+    /// Whether all rights in `other` are included
+    pub const fn contains(self, other: Self) -> bool {
+        self.0 & other.0 == other.0
+    }
+}
+
+impl core::ops::BitOr for Rights {
+    type Output = Self;
+
+    fn bitor(self, other: Self) -> Self {
+        Self(self.0 | other.0)
+    }
+}
+
+impl core::ops::BitAnd for Rights {
+    type Output = Self;
+
+    fn bitand(self, other: Self) -> Self {
+        Self(self.0 & other.0)
+    }
+}
+
+/// Signal sent to a process with [`SyscallCode::Kill`]
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub enum Signal {
+    /// Terminate the process immediately; cannot be handled
+    Kill = 0,
+    /// Request the process to terminate, which it does unless it registered a
+    /// handler with [`SyscallCode::SignalHandler`]
+    Terminate = 1,
+}
+
+/// Histogram of latencies measured by the kernel
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+#[repr(C)]
+pub struct LatencyHistogram {
+    /// Bucket `i` counts latencies of less than `2^i` but at least `2^(i - 1)`
+    /// cycles of the time stamp counter; the last bucket has no upper bound
+    pub buckets: [u64; LATENCY_BUCKETS],
+    /// Largest latency in cycles
+    pub max: u64,
+}
+
+/// Thermal and power readings of the CPU package, see
+/// [`SyscallCode::Telemetry`]
+#[derive(Copy, Clone, Debug, Default, PartialEq, Eq)]
+#[repr(C)]
+pub struct Telemetry {
+    /// Temperature in degrees Celsius, if available
+    pub temperature: Option<u32>,
+    /// Energy used in microjoules since boot, if available
+    pub energy: Option<u64>,
+}
+
+/// Sample rate of audio passed to [`SyscallCode::AudioSubmit`], in Hz
+pub const AUDIO_SAMPLE_RATE: u32 = 48000;
+/// Number of interleaved channels of audio passed to
+/// [`SyscallCode::AudioSubmit`]
+pub const AUDIO_CHANNELS: usize = 2;
+
+/// Address at which the [`TimePage`] is mapped read-only in every process
+pub const TIME_PAGE_ADDR: u64 = 0x1000;
+
+/// Time shared by the kernel with every process, see [`TIME_PAGE_ADDR`]
+///
+/// The time since boot is the epoch plus the time stamp counter increments
+/// since then. The kernel moves the epoch forward regularly; `sequence` is odd
+/// while it does, and readers should retry if it is odd or changes while
+/// reading.
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+#[repr(C)]
+pub struct TimePage {
+    pub sequence: u64,
+    /// Frequency of the time stamp counter in Hz
+    pub tsc_frequency: u64,
+    /// Time stamp counter at the epoch
+    pub epoch_tsc: u64,
+    /// Nanoseconds since boot at the epoch
+    pub epoch_ns: u64,
+}
+
+/// File to read with [`SyscallCode::HostRead`]
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+#[repr(C)]
+pub struct HostRead {
+    /// UTF-8 path relative to the shared directory
+    pub path: *const u8,
+    pub path_len: usize,
+    /// Offset in the file to read from
+    pub offset: u64,
+    pub buf: *mut u8,
+    pub buf_len: usize,
+}
+
+/// File to replace with [`SyscallCode::HostWrite`]
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+#[repr(C)]
+pub struct HostWrite {
+    /// UTF-8 path relative to the shared directory
+    pub path: *const u8,
+    pub path_len: usize,
+    /// New contents of the file
+    pub buf: *const u8,
+    pub buf_len: usize,
+}
+
+/// Length of [`ProcessInfo::name`]
+pub const PROCESS_NAME_LEN: usize = 16;
+
+/// Running process, see [`SyscallCode::ProcessList`]
+#[derive(Copy, Clone, Debug, Default, PartialEq, Eq)]
+#[repr(C)]
+pub struct ProcessInfo {
+    pub pid: u64,
+    /// Name of the program, truncated or padded with zeros
+    pub name: [u8; PROCESS_NAME_LEN],
+    /// Time spent running the process in nanoseconds, including interrupts
+    /// handled meanwhile
+    pub cpu_time_ns: u64,
+    /// Number of system calls made
+    pub syscalls: u64,
+    /// Pages mapped to fresh frames for the process
+    pub pages: u64,
+    /// Maximum number of pages that may be mapped to fresh frames
+    pub page_limit: u64,
+}
+
+/// Use of physical memory and the kernel heap, see
+/// [`SyscallCode::MemoryStats`]
+#[derive(Copy, Clone, Debug, Default, PartialEq, Eq)]
+#[repr(C)]
+pub struct MemoryStats {
+    /// Physical memory available for allocation at boot, in bytes
+    pub total: u64,
+    /// Physical memory that is not allocated, in bytes
+    pub free: u64,
+    /// Size of the kernel heap in bytes
+    pub heap_size: u64,
+    /// Bytes allocated on the kernel heap
+    pub heap_used: u64,
+}
+
 pub struct FrameBuffer {
     pub ptr: *mut u8,
     pub size: usize,
@@ -16,6 +314,9 @@ pub struct FrameBuffer {
 }
 
 /// System call codes
+///
+/// A system call passed memory the process cannot access, or cannot write if
+/// the kernel stores a result there, returns an error code.
 #[derive(Copy, Clone, Debug, PartialEq, Eq)]
 pub enum SyscallCode {
     /// Exit with code in rsi
@@ -23,8 +324,193 @@ pub enum SyscallCode {
     /// Log message, raw parts of UTF-8 slice passed through rsi for the pointer
     /// and rdx for the length.
     Log = 1,
-    /// Get access to frame buffer. Pass pointer to [`FrameBuffer`] in rsi.
+    /// Get access to frame buffer. Pass pointer to [`FrameBuffer`] in rsi and
+    /// [`FrameBufferAccess`] in rdx. The frame buffer stays mapped until it is
+    /// released or the process exits.
     FrameBuffer = 2,
+    /// Release access to frame buffer obtained by [`SyscallCode::FrameBuffer`].
+    FrameBufferRelease = 3,
+    /// Wait for the next vertical blank of the frame buffer, which may be
+    /// emulated. Requires frame buffer access.
+    FbWaitVsync = 4,
+    /// Change the resolution of the frame buffer. Requires exclusive frame
+    /// buffer access. Pass pointer to [`FrameBuffer`] in rsi to store the
+    /// remapped frame buffer and the resolution in rdx as `width << 32 |
+    /// height`.
+    FbSetMode = 5,
+    /// Display changes made to the frame buffer. Requires frame buffer access.
+    FbPresent = 6,
+    /// Print a screenshot of the frame buffer to the serial port. Requires
+    /// frame buffer access.
+    Screenshot = 7,
+    /// Take the oldest pending input event without blocking. Pass pointer to
+    /// [`InputEvent`] in rsi. Returns an error code if there is no event.
+    /// Events are only delivered to the focused process.
+    InputEvent = 8,
+    /// Get the process id of the focused process, or zero if there is none.
+    /// Pass pointer to `u64` in rsi.
+    InputFocus = 9,
+    /// Focus process with id in rsi, or the calling process if zero. Only
+    /// allowed for the focused process or if no process is focused.
+    InputSetFocus = 10,
+    /// Play audio. Pass pointer to interleaved stereo samples in rsi and the
+    /// number of samples in rdx, see [`AUDIO_SAMPLE_RATE`]. Blocks until all
+    /// samples are queued for playback.
+    AudioSubmit = 11,
+    /// Move recorded trace events into a buffer of [`TraceRecord`]s. Pass
+    /// pointer to the buffer in rsi and pointer to `usize` in rdx, which holds
+    /// the capacity of the buffer and is overwritten with the number of
+    /// events stored. Tracing must be enabled in the kernel configuration.
+    /// Requires trace access.
+    TraceDrain = 12,
+    /// Get latency histogram. Pass [`LatencySource`] in rsi and pointer to
+    /// [`LatencyHistogram`] in rdx. Latency measurement must be enabled in the
+    /// kernel configuration.
+    Latency = 13,
+    /// Send [`Signal`] in rdx to the process with id in rsi, or the calling
+    /// process if zero. Signals are delivered when the process returns from a
+    /// system call.
+    Kill = 14,
+    /// Handle signals by jumping to the address in rsi with the [`Signal`] in
+    /// rdi and the stack pointer set to rdx, or restore the default of
+    /// terminating if rsi is zero. The handler should finish with
+    /// [`SyscallCode::SignalReturn`]. Returns an error code if the handler or
+    /// stack lies outside of user memory.
+    SignalHandler = 15,
+    /// Return from a signal handler to the code it interrupted.
+    SignalReturn = 16,
+    /// Find a handle to an object of [`ObjectKind`] in rsi. Pass pointer to
+    /// `u64` in rdx to store the handle. Returns an error code if the process
+    /// holds no such handle.
+    HandleFind = 17,
+    /// Get the [`Rights`] of the handle in rsi. Pass pointer to [`Rights`] in
+    /// rdx.
+    HandleRights = 18,
+    /// Drop all rights of the handle in rsi except for the [`Rights`] in rdx.
+    /// Rights can never be regained.
+    HandleRestrict = 19,
+    /// Close the handle in rsi.
+    HandleClose = 20,
+    /// Grant a copy of the handle in rsi with only the [`Rights`] in rdx to
+    /// the next process spawned by the caller with [`SyscallCode::Spawn`].
+    /// Rights not held cannot be granted. Grants not passed on are dropped
+    /// when the caller exits.
+    HandleGrant = 21,
+    /// Shut down the system. Running processes are sent
+    /// [`Signal::Terminate`] and killed if they make a system call after a
+    /// grace period. Requires power access, which only init holds unless it
+    /// grants it.
+    Shutdown = 22,
+    /// Read the temperature and energy use of the CPU package. Pass pointer
+    /// to [`Telemetry`] in rsi.
+    Telemetry = 23,
+    /// Suspend to RAM until a wake event, such as the power button.
+    /// Experimental; returns 1 if suspending is not supported or failed.
+    /// Requires power access.
+    Suspend = 24,
+    /// Read a file in the directory shared by the host. Pass pointer to
+    /// [`HostRead`] in rsi and pointer to `usize` in rdx to store the number
+    /// of bytes read, which is less than requested only at the end of the
+    /// file. Returns an error code if there is no shared directory, the path
+    /// is longer than 256 bytes or the file cannot be read.
+    HostRead = 25,
+    /// Report the outcome of a userspace test suite, with the number of
+    /// passed tests in rsi and of failed tests in rdx. Kernel test builds
+    /// combine it with the kernel tests; otherwise it is only logged.
+    TestReport = 26,
+    /// Get information on running processes. Pass pointer to a buffer of
+    /// [`ProcessInfo`] in rsi and pointer to `usize` in rdx, which holds the
+    /// capacity of the buffer and is overwritten with the number of processes
+    /// stored. Only the calling process runs at a time for now.
+    ProcessList = 27,
+    /// Get the use of physical memory and the kernel heap. Pass pointer to
+    /// [`MemoryStats`] in rsi.
+    MemoryStats = 28,
+    /// Block for at least the number of nanoseconds in rsi. Returns an error
+    /// code early if a shutdown is requested.
+    Sleep = 29,
+    /// Replace the contents of a file in the directory shared by the host,
+    /// creating it if needed. Pass pointer to [`HostWrite`] in rsi. Returns an
+    /// error code if there is no shared directory, it is read-only or the file
+    /// cannot be written.
+    HostWrite = 30,
+    /// Move the calling process to the process group with id in rsi, or to a
+    /// new group of its own if zero. Returns an error code if the group has no
+    /// processes.
+    SetProcessGroup = 31,
+    /// Put the process group with id in rsi, or that of the calling process if
+    /// zero, in the foreground of the terminal of user processes, so it is
+    /// sent [`Signal::Terminate`] on Ctrl+C. Returns an error code unless the
+    /// group of the calling process is in the foreground or none is.
+    SetForeground = 32,
+    /// Stop the process with id in rsi, or the calling process if zero. A
+    /// stopped process is parked when it returns from its current or next
+    /// system call, until it is continued with [`SyscallCode::Continue`] or
+    /// interrupted with Ctrl+C. Returns an error code if there is no such
+    /// process in the process group of the calling process, or if the calling
+    /// process would stop itself while user processes have no terminal.
+    Stop = 33,
+    /// Continue the process with id in rsi stopped by [`SyscallCode::Stop`].
+    /// Returns an error code if there is no such process in the process group
+    /// of the calling process.
+    Continue = 34,
+    /// Take the next character typed on the keyboard without blocking. Pass
+    /// pointer to `u32` in rsi to store the Unicode scalar value. Returns an
+    /// error code if no character is pending. Other input events are dropped;
+    /// characters are only delivered to the focused process.
+    ReadKey = 35,
+    /// Read a line typed on the keyboard, echoed to the terminal of user
+    /// processes. Pass pointer to a buffer in rsi and pointer to `usize` in
+    /// rdx, which holds the capacity of the buffer and is overwritten with the
+    /// length of the UTF-8 line stored, including the newline. Blocks until
+    /// Enter is pressed or the buffer is full, and returns an error code early
+    /// on Ctrl+C or a shutdown.
+    ReadLine = 36,
+    /// Move records of the audit log into a buffer of [`AuditRecord`]s. Pass
+    /// pointer to the buffer in rsi and pointer to `usize` in rdx, which holds
+    /// the capacity of the buffer and is overwritten with the number of
+    /// records stored. The oldest records are overwritten when the log is
+    /// full.
+    AuditDrain = 37,
+    /// Map fresh zeroed memory, readable and writable, at an address chosen by
+    /// the kernel. Pass the length in bytes in rsi, which is rounded up to
+    /// whole pages, and pointer to `u64` in rdx to store the address. The
+    /// memory counts towards the memory limit of the process and is unmapped
+    /// when it exits. Returns an error code if the limit would be exceeded.
+    Mmap = 38,
+    /// Unmap all memory mapped by a single [`SyscallCode::Mmap`] at the
+    /// address in rsi. Returns an error code if there is no such mapping.
+    Munmap = 39,
+    /// Spawn a process running the program embedded in the kernel with the
+    /// UTF-8 name in rsi. Pass pointer to `u64` in rdx, which holds the length
+    /// of the name and is overwritten with the id of the new process. Only one
+    /// process runs at a time for now, so it runs once the calling process has
+    /// exited, with the handles it granted by [`SyscallCode::HandleGrant`] and
+    /// the same memory limit. Returns an error code if there is no such
+    /// program or too many processes are waiting to run, in which case the
+    /// grants are kept for the next attempt.
+    Spawn = 40,
+}
+
+impl SyscallCode {
+    /// Object and rights a process needs a handle to for this system call
+    pub fn required_rights(self) -> Option<(ObjectKind, Rights)> {
+        use SyscallCode::*;
+        match self {
+            FrameBuffer => Some((ObjectKind::FrameBuffer, Rights::MAP | Rights::WRITE)),
+            FbWaitVsync | Screenshot => Some((ObjectKind::FrameBuffer, Rights::READ)),
+            FbSetMode | FbPresent => Some((ObjectKind::FrameBuffer, Rights::WRITE)),
+            InputEvent | InputFocus | ReadKey | ReadLine => Some((ObjectKind::Input, Rights::READ)),
+            InputSetFocus => Some((ObjectKind::Input, Rights::WRITE)),
+            AudioSubmit => Some((ObjectKind::Audio, Rights::WRITE)),
+            HostRead => Some((ObjectKind::HostFs, Rights::READ)),
+            HostWrite => Some((ObjectKind::HostFs, Rights::WRITE)),
+            AuditDrain => Some((ObjectKind::AuditLog, Rights::READ)),
+            TraceDrain => Some((ObjectKind::Trace, Rights::READ)),
+            Shutdown | Suspend => Some((ObjectKind::Power, Rights::WRITE)),
+            _ => None,
+        }
+    }
 }
 
 /// Perform a system call
@@ -34,7 +520,52 @@ pub enum SyscallCode {
 /// # Safety
 /// - [`SyscallCode::Exit`]: always safe
 /// - [`SyscallCode::Log`]: valid pointer and length should be supplied
-/// - [`SyscallCode::Framebuffer`]: valid pointer to store [`FrameBuffer`]
+/// - [`SyscallCode::FrameBuffer`]: valid pointer to store [`FrameBuffer`]
+/// - [`SyscallCode::FrameBufferRelease`]: no references into the frame buffer
+///   may be used afterwards
+/// - [`SyscallCode::FbWaitVsync`]: always safe
+/// - [`SyscallCode::FbSetMode`]: valid pointer to store [`FrameBuffer`]; no
+///   references into the previous frame buffer may be used afterwards
+/// - [`SyscallCode::FbPresent`]: always safe
+/// - [`SyscallCode::Screenshot`]: always safe
+/// - [`SyscallCode::InputEvent`]: valid pointer to store [`InputEvent`]
+/// - [`SyscallCode::InputFocus`]: valid pointer to store `u64`
+/// - [`SyscallCode::InputSetFocus`]: always safe
+/// - [`SyscallCode::AudioSubmit`]: valid pointer and length should be supplied
+/// - [`SyscallCode::TraceDrain`]: valid pointers to buffer and its capacity
+/// - [`SyscallCode::Latency`]: valid pointer to store [`LatencyHistogram`]
+/// - [`SyscallCode::Kill`]: always safe, but may not return
+/// - [`SyscallCode::SignalHandler`]: valid handler and stack should be supplied
+/// - [`SyscallCode::SignalReturn`]: only from a signal handler
+/// - [`SyscallCode::HandleFind`]: valid pointer to store `u64`
+/// - [`SyscallCode::HandleRights`]: valid pointer to store [`Rights`]
+/// - [`SyscallCode::HandleRestrict`]: always safe
+/// - [`SyscallCode::HandleClose`]: always safe
+/// - [`SyscallCode::HandleGrant`]: always safe
+/// - [`SyscallCode::Shutdown`]: always safe; denied without a handle to
+///   [`ObjectKind::Power`], as it terminates every process
+/// - [`SyscallCode::Telemetry`]: valid pointer to store [`Telemetry`]
+/// - [`SyscallCode::Suspend`]: always safe; denied without a handle to
+///   [`ObjectKind::Power`], as it stops every process
+/// - [`SyscallCode::HostRead`]: valid pointer to [`HostRead`] with valid
+///   pointers and lengths, and valid pointer to store `usize`
+/// - [`SyscallCode::TestReport`]: always safe
+/// - [`SyscallCode::ProcessList`]: valid pointers to buffer and its capacity
+/// - [`SyscallCode::MemoryStats`]: valid pointer to store [`MemoryStats`]
+/// - [`SyscallCode::Sleep`]: always safe
+/// - [`SyscallCode::HostWrite`]: valid pointer to [`HostWrite`] with valid
+///   pointers and lengths
+/// - [`SyscallCode::SetProcessGroup`]: always safe
+/// - [`SyscallCode::SetForeground`]: always safe
+/// - [`SyscallCode::Stop`]: always safe, but may not return for a while
+/// - [`SyscallCode::Continue`]: always safe
+/// - [`SyscallCode::ReadKey`]: valid pointer to store `u32`
+/// - [`SyscallCode::ReadLine`]: valid pointers to buffer and its capacity
+/// - [`SyscallCode::AuditDrain`]: valid pointers to buffer and its capacity
+/// - [`SyscallCode::Mmap`]: valid pointer to store `u64`
+/// - [`SyscallCode::Munmap`]: no references into the memory may be used
+///   afterwards
+/// - [`SyscallCode::Spawn`]: valid pointer to name and to its length
 pub unsafe fn syscall(code: SyscallCode, rsi: u64, rdx: u64) -> u64 {
     let rax: u64;
     asm!(
@@ -0,0 +1,128 @@
+#![no_std]
+#![no_main]
+
+use core::{
+    fmt::{self, Write},
+    panic::PanicInfo,
+    str,
+    time::Duration,
+};
+use os::{
+    sys::{InputEvent, ObjectKind, ProcessInfo},
+    time,
+    ustd::LineWriter,
+};
+
+/// Time between refreshes of the table
+const INTERVAL: Duration = Duration::from_secs(1);
+/// Maximum number of processes shown
+const MAX_PROCESSES: usize = 16;
+/// Usage id of the Q key, which quits
+const KEY_Q: u8 = 0x14;
+/// Longest line logged, longer ones are split
+const LINE_LEN: usize = 80;
+
+/// Share of CPU time in tenths of a percent, unknown for new processes
+struct Cpu(Option<u128>);
+
+impl fmt::Display for Cpu {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self.0 {
+            Some(cpu) => write!(f, "{:>4}.{}", cpu / 10, cpu % 10),
+            None => write!(f, "{:>6}", "-"),
+        }
+    }
+}
+
+/// Name of a process, up to the first zero
+fn name(info: &ProcessInfo) -> &str {
+    let len = info
+        .name
+        .iter()
+        .position(|&b| b == 0)
+        .unwrap_or(info.name.len());
+    str::from_utf8(&info.name[..len]).unwrap_or("?")
+}
+
+/// Whether Q was pressed since the last refresh
+fn quit_pressed() -> bool {
+    while let Some(event) = os::input_event() {
+        if let InputEvent::Key {
+            usage: KEY_Q,
+            pressed: true,
+        } = event
+        {
+            return true;
+        }
+    }
+    false
+}
+
+#[no_mangle]
+extern "C" fn _start() {
+    let input = os::handle::find(ObjectKind::Input).is_some();
+    let mut out = LineWriter::<_, LINE_LEN>::new(os::log);
+    let mut previous = [ProcessInfo::default(); MAX_PROCESSES];
+    let mut previous_len = 0;
+    let mut previous_time = time::monotonic();
+    loop {
+        let mut processes = [ProcessInfo::default(); MAX_PROCESSES];
+        let len = os::processes(&mut processes);
+        let now = time::monotonic();
+        let elapsed = (now - previous_time).as_nanos().max(1);
+        let memory = os::memory_stats();
+
+        let uptime = now.as_secs();
+        let _ = writeln!(
+            out,
+            "top - up {}:{:02}:{:02}, {} processes{}",
+            uptime / 3600,
+            uptime / 60 % 60,
+            uptime % 60,
+            len,
+            if input { ", press Q to quit" } else { "" }
+        );
+        let _ = writeln!(
+            out,
+            "Mem: {} KiB total, {} KiB free; heap: {} KiB used of {} KiB",
+            memory.total / 1024,
+            memory.free / 1024,
+            memory.heap_used / 1024,
+            memory.heap_size / 1024
+        );
+        let _ = writeln!(
+            out,
+            "{:>5} {:<16} {:>6} {:>10} {:>9} {:>9}",
+            "PID", "NAME", "CPU%", "SYSCALLS", "PAGES", "LIMIT"
+        );
+        for info in &processes[..len] {
+            let cpu = previous[..previous_len]
+                .iter()
+                .find(|other| other.pid == info.pid)
+                .map(|other| (info.cpu_time_ns - other.cpu_time_ns) as u128 * 1000 / elapsed);
+            let _ = writeln!(
+                out,
+                "{:>5} {:<16} {} {:>10} {:>9} {:>9}",
+                info.pid,
+                name(info),
+                Cpu(cpu),
+                info.syscalls,
+                info.pages,
+                info.page_limit
+            );
+        }
+
+        previous = processes;
+        previous_len = len;
+        previous_time = now;
+        if !time::sleep(INTERVAL) || (input && quit_pressed()) {
+            break;
+        }
+    }
+    os::exit(0);
+}
+
+#[panic_handler]
+fn panic(_info: &PanicInfo) -> ! {
+    loop {}
+}
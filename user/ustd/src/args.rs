@@ -0,0 +1,70 @@
+//! Parsing of command lines in the style of the kernel command line
+//!
+//! Arguments are separated by whitespace and are either options written as
+//! `key=value` or plain words. Processes do not receive a command line from
+//! the kernel yet, but programs can parse lines read from elsewhere, such as
+//! a file in the directory shared by the host.
+
+use crate::error::Result;
+use core::str::FromStr;
+
+/// Argument on a command line
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub enum Arg<'a> {
+    Option { key: &'a str, value: &'a str },
+    Word(&'a str),
+}
+
+/// Arguments on `line`, in order
+pub fn parse<'a>(line: &'a str) -> impl Iterator<Item = Arg<'a>> {
+    line.split_whitespace()
+        .map(|arg| match arg.split_once('=') {
+            Some((key, value)) => Arg::Option { key, value },
+            None => Arg::Word(arg),
+        })
+}
+
+/// Value of the last option called `key` on `line`, if any
+pub fn option<'a>(line: &'a str, key: &str) -> Option<&'a str> {
+    parse(line)
+        .filter_map(|arg| match arg {
+            Arg::Option { key: other, value } if other == key => Some(value),
+            _ => None,
+        })
+        .last()
+}
+
+/// Parse the value of an option
+pub fn value<T: FromStr>(value: &str) -> Result<T> {
+    value.parse().map_err(|_| "Invalid value")
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::vec::Vec;
+
+    #[test]
+    fn words_and_options() {
+        let args: Vec<_> = parse(" edit  path=a=b\tverbose ").collect();
+        assert_eq!(
+            args,
+            [
+                Arg::Word("edit"),
+                Arg::Option {
+                    key: "path",
+                    value: "a=b"
+                },
+                Arg::Word("verbose")
+            ]
+        );
+    }
+
+    #[test]
+    fn last_option() {
+        let line = "interval=2 quiet interval=5";
+        assert_eq!(option(line, "interval").map(value), Some(Ok(5u32)));
+        assert_eq!(option(line, "quiet"), None);
+        assert_eq!(value::<u32>("x"), Err("Invalid value"));
+    }
+}
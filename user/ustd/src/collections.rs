@@ -0,0 +1,264 @@
+//! Collections with a fixed capacity
+
+use core::{
+    fmt,
+    mem::MaybeUninit,
+    ops::{Deref, DerefMut},
+    ptr, slice, str,
+};
+
+/// Vector holding up to `N` elements inline
+pub struct ArrayVec<T, const N: usize> {
+    items: [MaybeUninit<T>; N],
+    len: usize,
+}
+
+impl<T, const N: usize> ArrayVec<T, N> {
+    pub fn new() -> Self {
+        Self {
+            // An array of uninitialized values needs no initialization
+            items: unsafe { MaybeUninit::uninit().assume_init() },
+            len: 0,
+        }
+    }
+
+    pub const fn capacity(&self) -> usize {
+        N
+    }
+
+    pub fn is_full(&self) -> bool {
+        self.len == N
+    }
+
+    /// Append `item`, or return it if the vector is full
+    pub fn push(&mut self, item: T) -> Result<(), T> {
+        if self.is_full() {
+            return Err(item);
+        }
+        self.items[self.len] = MaybeUninit::new(item);
+        self.len += 1;
+        Ok(())
+    }
+
+    pub fn pop(&mut self) -> Option<T> {
+        if self.len == 0 {
+            return None;
+        }
+        self.len -= 1;
+        Some(unsafe { self.items[self.len].as_ptr().read() })
+    }
+
+    /// Insert `item` at `index`, shifting the elements after it, or return it
+    /// if the vector is full
+    ///
+    /// # Panics
+    /// If `index` is greater than the length.
+    pub fn insert(&mut self, index: usize, item: T) -> Result<(), T> {
+        assert!(index <= self.len, "Insertion index out of bounds");
+        if self.is_full() {
+            return Err(item);
+        }
+        unsafe {
+            let at = self.items.as_mut_ptr().add(index);
+            ptr::copy(at, at.add(1), self.len - index);
+            at.write(MaybeUninit::new(item));
+        }
+        self.len += 1;
+        Ok(())
+    }
+
+    /// Remove the element at `index`, shifting the elements after it
+    ///
+    /// # Panics
+    /// If `index` is out of bounds.
+    pub fn remove(&mut self, index: usize) -> T {
+        assert!(index < self.len, "Removal index out of bounds");
+        unsafe {
+            let at = self.items.as_mut_ptr().add(index);
+            let item = at.read().assume_init();
+            ptr::copy(at.add(1), at, self.len - index - 1);
+            self.len -= 1;
+            item
+        }
+    }
+
+    /// Drop the elements from `len` on
+    pub fn truncate(&mut self, len: usize) {
+        while self.len > len {
+            self.pop();
+        }
+    }
+
+    pub fn clear(&mut self) {
+        self.truncate(0);
+    }
+}
+
+impl<T, const N: usize> Default for ArrayVec<T, N> {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl<T, const N: usize> Deref for ArrayVec<T, N> {
+    type Target = [T];
+
+    fn deref(&self) -> &[T] {
+        unsafe { slice::from_raw_parts(self.items.as_ptr().cast(), self.len) }
+    }
+}
+
+impl<T, const N: usize> DerefMut for ArrayVec<T, N> {
+    fn deref_mut(&mut self) -> &mut [T] {
+        unsafe { slice::from_raw_parts_mut(self.items.as_mut_ptr().cast(), self.len) }
+    }
+}
+
+impl<T, const N: usize> Drop for ArrayVec<T, N> {
+    fn drop(&mut self) {
+        self.clear();
+    }
+}
+
+impl<T: Clone, const N: usize> Clone for ArrayVec<T, N> {
+    fn clone(&self) -> Self {
+        let mut clone = Self::new();
+        for item in self.iter() {
+            // Cannot exceed the capacity
+            let _ = clone.push(item.clone());
+        }
+        clone
+    }
+}
+
+impl<T: fmt::Debug, const N: usize> fmt::Debug for ArrayVec<T, N> {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.debug_list().entries(self.iter()).finish()
+    }
+}
+
+impl<T: PartialEq, const N: usize> PartialEq for ArrayVec<T, N> {
+    fn eq(&self, other: &Self) -> bool {
+        **self == **other
+    }
+}
+
+impl<T: Eq, const N: usize> Eq for ArrayVec<T, N> {}
+
+/// String holding up to `N` bytes inline
+#[derive(Clone, Default, PartialEq, Eq)]
+pub struct ArrayString<const N: usize> {
+    bytes: ArrayVec<u8, N>,
+}
+
+impl<const N: usize> ArrayString<N> {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    pub fn as_str(&self) -> &str {
+        // Only whole strings are appended
+        unsafe { str::from_utf8_unchecked(&self.bytes) }
+    }
+
+    /// Append `s`, or nothing if it does not fit
+    pub fn push_str(&mut self, s: &str) -> Result<(), &'static str> {
+        if self.bytes.len() + s.len() > N {
+            return Err("String is full");
+        }
+        for &b in s.as_bytes() {
+            // Cannot exceed the capacity
+            let _ = self.bytes.push(b);
+        }
+        Ok(())
+    }
+
+    pub fn push(&mut self, c: char) -> Result<(), &'static str> {
+        self.push_str(c.encode_utf8(&mut [0; 4]))
+    }
+
+    pub fn pop(&mut self) -> Option<char> {
+        let c = self.as_str().chars().next_back()?;
+        self.bytes.truncate(self.bytes.len() - c.len_utf8());
+        Some(c)
+    }
+
+    pub fn clear(&mut self) {
+        self.bytes.clear();
+    }
+}
+
+impl<const N: usize> Deref for ArrayString<N> {
+    type Target = str;
+
+    fn deref(&self) -> &str {
+        self.as_str()
+    }
+}
+
+impl<const N: usize> fmt::Write for ArrayString<N> {
+    fn write_str(&mut self, s: &str) -> fmt::Result {
+        self.push_str(s).map_err(|_| fmt::Error)
+    }
+}
+
+impl<const N: usize> fmt::Display for ArrayString<N> {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.pad(self.as_str())
+    }
+}
+
+impl<const N: usize> fmt::Debug for ArrayString<N> {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        fmt::Debug::fmt(self.as_str(), f)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use core::fmt::Write;
+    use std::rc::Rc;
+
+    #[test]
+    fn vec() {
+        let mut v = ArrayVec::<u32, 4>::new();
+        v.push(1).unwrap();
+        v.push(3).unwrap();
+        v.insert(1, 2).unwrap();
+        v.insert(0, 0).unwrap();
+        assert_eq!(v.push(4), Err(4));
+        assert_eq!(*v, [0, 1, 2, 3]);
+        assert_eq!(v.remove(1), 1);
+        assert_eq!(v.pop(), Some(3));
+        assert_eq!(*v, [0, 2]);
+        v[0] = 5;
+        assert_eq!(v.clone().iter().sum::<u32>(), 7);
+    }
+
+    #[test]
+    fn vec_drops() {
+        let rc = Rc::new(());
+        let mut v = ArrayVec::<_, 3>::new();
+        for _ in 0..3 {
+            v.push(rc.clone()).unwrap();
+        }
+        v.truncate(2);
+        assert_eq!(Rc::strong_count(&rc), 3);
+        drop(v.remove(0));
+        drop(v);
+        assert_eq!(Rc::strong_count(&rc), 1);
+    }
+
+    #[test]
+    fn string() {
+        let mut s = ArrayString::<8>::new();
+        write!(s, "{}-é", 12).unwrap();
+        assert_eq!(s.as_str(), "12-é");
+        assert!(write!(s, "long").is_err());
+        assert_eq!(&*s, "12-é");
+        assert_eq!(s.pop(), Some('é'));
+        s.push('!').unwrap();
+        assert_eq!(format!("{:>6}", s), "  12-!");
+    }
+}
@@ -0,0 +1,46 @@
+//! Errors, described by a static string as in the kernel
+
+/// Result with an error message
+pub type Result<T = ()> = core::result::Result<T, &'static str>;
+
+/// Conversion of failures reported by [`Option`] or [`bool`], as returned by
+/// most system call wrappers, to a [`Result`]
+pub trait Context {
+    type Output;
+
+    /// Error `msg` on failure
+    fn context(self, msg: &'static str) -> Result<Self::Output>;
+}
+
+impl<T> Context for Option<T> {
+    type Output = T;
+
+    fn context(self, msg: &'static str) -> Result<T> {
+        self.ok_or(msg)
+    }
+}
+
+impl Context for bool {
+    type Output = ();
+
+    fn context(self, msg: &'static str) -> Result {
+        if self {
+            Ok(())
+        } else {
+            Err(msg)
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn context() {
+        assert_eq!(Some(1).context("none"), Ok(1));
+        assert_eq!(None::<u8>.context("none"), Err("none"));
+        assert_eq!(true.context("false"), Ok(()));
+        assert_eq!(false.context("false"), Err("false"));
+    }
+}
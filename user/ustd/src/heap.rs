@@ -0,0 +1,210 @@
+//! First-fit allocation from a list of free memory blocks
+//!
+//! The list does not obtain memory itself: regions are added to it, e.g. after
+//! mapping them with a system call, and never returned. Free blocks are kept
+//! ordered by address and merged with their neighbours, so memory freed in any
+//! order can be allocated as a whole again.
+
+use core::{
+    alloc::Layout,
+    ptr::{self, NonNull},
+};
+
+/// Granularity of addresses and sizes of blocks, which fits a [`Block`]
+const UNIT: usize = 16;
+
+/// Header of a free block, stored at its start
+struct Block {
+    size: usize,
+    next: *mut Block,
+}
+
+/// Free list allocator
+pub struct FreeList {
+    /// Free block with the lowest address
+    head: *mut Block,
+}
+
+impl FreeList {
+    pub const fn new() -> Self {
+        Self {
+            head: ptr::null_mut(),
+        }
+    }
+
+    /// Make `size` bytes at `start` available for allocation
+    ///
+    /// # Safety
+    /// The memory should be valid for reads and writes, unused and not
+    /// overlap regions added before.
+    pub unsafe fn add(&mut self, start: *mut u8, size: usize) {
+        let addr = start as usize;
+        let aligned = round_up(addr, UNIT);
+        let size = size.saturating_sub(aligned - addr) / UNIT * UNIT;
+        if size > 0 {
+            self.insert(aligned, size);
+        }
+    }
+
+    /// Allocate memory fitting `layout`, if a free block is large enough
+    pub fn allocate(&mut self, layout: Layout) -> Option<NonNull<u8>> {
+        let (size, align) = fit(layout);
+        let mut prev: *mut Block = ptr::null_mut();
+        let mut block = self.head;
+        while !block.is_null() {
+            let addr = block as usize;
+            let (block_size, next) = unsafe { ((*block).size, (*block).next) };
+            let start = round_up(addr, align);
+            let end = start + size;
+            if end <= addr + block_size {
+                // Take the block out, returning what remains around the
+                // allocation
+                unsafe {
+                    match prev.as_mut() {
+                        Some(prev) => prev.next = next,
+                        None => self.head = next,
+                    }
+                    if start > addr {
+                        self.insert(addr, start - addr);
+                    }
+                    if end < addr + block_size {
+                        self.insert(end, addr + block_size - end);
+                    }
+                }
+                return NonNull::new(start as *mut u8);
+            }
+            prev = block;
+            block = next;
+        }
+        None
+    }
+
+    /// Free memory allocated with `layout`
+    ///
+    /// # Safety
+    /// `ptr` should have been returned by [`FreeList::allocate`] with the same
+    /// layout, and not be used afterwards.
+    pub unsafe fn deallocate(&mut self, ptr: NonNull<u8>, layout: Layout) {
+        let (size, _) = fit(layout);
+        self.insert(ptr.as_ptr() as usize, size);
+    }
+
+    /// Number of bytes that are free, in blocks of any size
+    pub fn free(&self) -> usize {
+        let mut free = 0;
+        let mut block = self.head;
+        while let Some(current) = unsafe { block.as_ref() } {
+            free += current.size;
+            block = current.next;
+        }
+        free
+    }
+
+    /// Insert the free block of `size` bytes at `addr` in the list, merging it
+    /// with adjacent blocks
+    unsafe fn insert(&mut self, addr: usize, mut size: usize) {
+        let mut prev: *mut Block = ptr::null_mut();
+        let mut next = self.head;
+        while !next.is_null() && (next as usize) < addr {
+            prev = next;
+            next = (*next).next;
+        }
+        if !next.is_null() && addr + size == next as usize {
+            size += (*next).size;
+            next = (*next).next;
+        }
+        if let Some(block) = prev.as_mut() {
+            if prev as usize + block.size == addr {
+                block.size += size;
+                block.next = next;
+                return;
+            }
+        }
+        let block = addr as *mut Block;
+        block.write(Block { size, next });
+        match prev.as_mut() {
+            Some(prev) => prev.next = block,
+            None => self.head = block,
+        }
+    }
+}
+
+impl Default for FreeList {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+/// Size and alignment of the block allocated for `layout`
+fn fit(layout: Layout) -> (usize, usize) {
+    let size = round_up(layout.size().max(1), UNIT);
+    (size, layout.align().max(UNIT))
+}
+
+/// Round `value` up to a multiple of `align`, which is a power of two
+fn round_up(value: usize, align: usize) -> usize {
+    (value + align - 1) & !(align - 1)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const SIZE: usize = 4096;
+
+    #[repr(align(4096))]
+    struct Arena([u8; SIZE]);
+
+    fn with_list<F: FnOnce(&mut FreeList, usize)>(f: F) {
+        let mut arena = Box::new(Arena([0; SIZE]));
+        let mut list = FreeList::new();
+        unsafe { list.add(arena.0.as_mut_ptr(), SIZE) };
+        f(&mut list, arena.0.as_ptr() as usize);
+    }
+
+    fn layout(size: usize, align: usize) -> Layout {
+        Layout::from_size_align(size, align).unwrap()
+    }
+
+    #[test]
+    fn allocate_and_free() {
+        with_list(|list, start| {
+            let a = list.allocate(layout(100, 8)).unwrap();
+            assert_eq!(a.as_ptr() as usize, start);
+            let b = list.allocate(layout(1, 1)).unwrap();
+            assert_eq!(b.as_ptr() as usize, start + 112);
+            assert_eq!(list.free(), SIZE - 128);
+            unsafe { list.deallocate(a, layout(100, 8)) };
+            // The freed block is reused first
+            let c = list.allocate(layout(64, 8)).unwrap();
+            assert_eq!(c, a);
+            assert!(list.allocate(layout(SIZE, 8)).is_none());
+        });
+    }
+
+    #[test]
+    fn alignment() {
+        with_list(|list, start| {
+            list.allocate(layout(16, 16)).unwrap();
+            let aligned = list.allocate(layout(32, 256)).unwrap();
+            assert_eq!(aligned.as_ptr() as usize, start + 256);
+            // The padding before it is still available
+            let small = list.allocate(layout(200, 8)).unwrap();
+            assert_eq!(small.as_ptr() as usize, start + 16);
+            assert_eq!(list.free(), SIZE - 16 - 32 - 208);
+        });
+    }
+
+    #[test]
+    fn merges_blocks() {
+        with_list(|list, _| {
+            let quarter = layout(SIZE / 4, 8);
+            let blocks: Vec<_> = (0..4).map(|_| list.allocate(quarter).unwrap()).collect();
+            assert_eq!(list.free(), 0);
+            for &i in &[2, 0, 3, 1] {
+                unsafe { list.deallocate(blocks[i], quarter) };
+            }
+            assert!(list.allocate(layout(SIZE, 8)).is_some());
+        });
+    }
+}
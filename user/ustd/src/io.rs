@@ -0,0 +1,95 @@
+//! Buffered output
+
+use core::{fmt, str};
+
+/// Writer passing complete lines to a sink, such as `os::log`
+///
+/// Lines are passed without their newline. Lines longer than `N` bytes are
+/// split, at a character boundary if possible. Text not followed by a newline
+/// is kept until [`LineWriter::flush`].
+pub struct LineWriter<F: FnMut(&str), const N: usize> {
+    sink: F,
+    buf: [u8; N],
+    len: usize,
+}
+
+impl<F: FnMut(&str), const N: usize> LineWriter<F, N> {
+    pub fn new(sink: F) -> Self {
+        Self {
+            sink,
+            buf: [0; N],
+            len: 0,
+        }
+    }
+
+    /// Pass the buffered text to the sink, even if it is empty
+    pub fn flush(&mut self) {
+        // Only whole characters are buffered
+        let s = str::from_utf8(&self.buf[..self.len]).unwrap_or_default();
+        (self.sink)(s);
+        self.len = 0;
+    }
+
+    fn push_str(&mut self, mut s: &str) {
+        while self.len + s.len() > N {
+            let mut split = N - self.len;
+            while !s.is_char_boundary(split) {
+                split -= 1;
+            }
+            if split == 0 && self.len == 0 {
+                // A character longer than the buffer is dropped
+                split = s.chars().next().map_or(s.len(), char::len_utf8);
+            } else {
+                self.buf[self.len..self.len + split].copy_from_slice(&s.as_bytes()[..split]);
+                self.len += split;
+            }
+            self.flush();
+            s = &s[split..];
+        }
+        self.buf[self.len..self.len + s.len()].copy_from_slice(s.as_bytes());
+        self.len += s.len();
+    }
+}
+
+impl<F: FnMut(&str), const N: usize> fmt::Write for LineWriter<F, N> {
+    fn write_str(&mut self, s: &str) -> fmt::Result {
+        let mut lines = s.split('\n');
+        self.push_str(lines.next().unwrap_or_default());
+        for line in lines {
+            self.flush();
+            self.push_str(line);
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use core::fmt::Write;
+    use std::{string::String, vec::Vec};
+
+    #[test]
+    fn lines() {
+        let mut lines = Vec::new();
+        {
+            let mut writer = LineWriter::<_, 8>::new(|s: &str| lines.push(String::from(s)));
+            writeln!(writer, "a{}", 1).unwrap();
+            write!(writer, "\nb").unwrap();
+            writer.flush();
+        }
+        assert_eq!(lines, ["a1", "", "b"]);
+    }
+
+    #[test]
+    fn long_lines_split() {
+        let mut lines = Vec::new();
+        {
+            let mut writer = LineWriter::<_, 4>::new(|s: &str| lines.push(String::from(s)));
+            writeln!(writer, "abcdef").unwrap();
+            // Two-byte characters are not split
+            writeln!(writer, "aééé").unwrap();
+        }
+        assert_eq!(lines, ["abcd", "ef", "aé", "éé"]);
+    }
+}
@@ -0,0 +1,18 @@
+//! Helpers shared by user programs, re-exported by `os`
+//!
+//! Nothing here makes system calls, so the crate is tested on the host. User
+//! programs have no heap unless they enable the `heap` feature of `os`, so the
+//! collections have a fixed capacity and can be placed on the stack or in
+//! statics.
+
+#![cfg_attr(not(any(test, feature = "std")), no_std)]
+
+pub mod args;
+pub mod collections;
+pub mod error;
+pub mod heap;
+pub mod io;
+
+pub use collections::{ArrayString, ArrayVec};
+pub use error::{Context, Result};
+pub use io::LineWriter;
@@ -0,0 +1,22 @@
+//! WebAssembly interpreter, to run sandboxed guest programs
+//!
+//! Modules are decoded from the binary format and interpreted directly. They
+//! are not validated up front: malformed code traps while it runs instead,
+//! and never gets access to more than its own linear memory. Only the integer
+//! subset of WebAssembly 1.0 is supported, with the sign extension operators
+//! and the bulk memory copy and fill, as user programs do not use floating
+//! point. Guests affect the system only through the host functions they
+//! import, which a [`Host`] provides.
+//!
+//! Nothing here makes system calls and nothing is allocated, so the crate is
+//! tested on the host. State too large for the stack of user programs is kept
+//! in [`Module`] and [`Store`], which can be placed in statics.
+
+#![cfg_attr(not(any(test, feature = "std")), no_std)]
+
+mod machine;
+mod module;
+mod reader;
+
+pub use machine::{Host, Machine, Store, PAGE_SIZE};
+pub use module::{FuncType, Module, ValType};
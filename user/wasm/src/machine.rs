@@ -0,0 +1,1071 @@
+//! Interpretation of instantiated modules
+//!
+//! Values are kept on the stack as 64-bit words, with 32-bit integers in the
+//! lower half, and the locals of a function are the stack slots its
+//! arguments were passed in followed by slots for its declared locals.
+//! Structured control flow is followed by looking for the end of a block when
+//! it is left, so code needs no preparation before it runs.
+
+use crate::{
+    module::{const_expr, FuncType, Module, MAX_GLOBALS, MAX_IMPORTS, MAX_PARAMS},
+    reader::Reader,
+};
+use ustd::Result;
+
+/// Size of a page of linear memory
+pub const PAGE_SIZE: usize = 0x10000;
+/// Capacity of the value stack, in values
+const STACK_SIZE: usize = 0x2000;
+/// Maximum depth of calls
+const MAX_FRAMES: usize = 256;
+/// Maximum number of blocks entered but not left, in all calls
+const MAX_LABELS: usize = 1024;
+/// Maximum size of the table of functions
+const MAX_TABLE: usize = 1024;
+
+/// Table element that refers to no function
+const NULL: usize = usize::MAX;
+
+/// Provider of the functions a module imports
+pub trait Host {
+    /// Index of the function `name` of `module`, which should have type `ty`
+    fn resolve(&mut self, module: &str, name: &str, ty: &FuncType) -> Result<usize>;
+
+    /// Call function `index` with `args`; the linear memory of the guest is
+    /// passed as `memory`
+    fn call(&mut self, index: usize, args: &[u64], memory: &mut [u8]) -> Result<Option<u64>>;
+}
+
+/// Function being executed
+#[derive(Copy, Clone)]
+struct Frame {
+    /// Position and end of the code of the caller to return to
+    pc: usize,
+    end: usize,
+    /// Stack slot of the first local and number of locals
+    locals: usize,
+    local_count: usize,
+    /// First label of the function
+    labels: usize,
+    /// Number of values returned
+    arity: usize,
+}
+
+#[derive(Copy, Clone, PartialEq, Eq)]
+enum LabelKind {
+    Block,
+    Loop,
+    If,
+}
+
+/// Block that is entered but not left
+#[derive(Copy, Clone)]
+struct Label {
+    kind: LabelKind,
+    /// Start of the code inside the block
+    start: usize,
+    /// Height of the stack below the parameters of the block
+    height: usize,
+    /// Number of values passed by a branch to the label
+    arity: usize,
+}
+
+/// State of a module instance besides its linear memory
+pub struct Store {
+    stack: [u64; STACK_SIZE],
+    frames: [Frame; MAX_FRAMES],
+    labels: [Label; MAX_LABELS],
+    globals: [u64; MAX_GLOBALS],
+    table: [usize; MAX_TABLE],
+    /// Host function index of every import
+    imports: [usize; MAX_IMPORTS],
+}
+
+impl Store {
+    pub const fn new() -> Self {
+        const FRAME: Frame = Frame {
+            pc: 0,
+            end: 0,
+            locals: 0,
+            local_count: 0,
+            labels: 0,
+            arity: 0,
+        };
+        const LABEL: Label = Label {
+            kind: LabelKind::Block,
+            start: 0,
+            height: 0,
+            arity: 0,
+        };
+        Self {
+            stack: [0; STACK_SIZE],
+            frames: [FRAME; MAX_FRAMES],
+            labels: [LABEL; MAX_LABELS],
+            globals: [0; MAX_GLOBALS],
+            table: [NULL; MAX_TABLE],
+            imports: [0; MAX_IMPORTS],
+        }
+    }
+}
+
+impl Default for Store {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+/// Instance of a module
+pub struct Machine<'a, H> {
+    module: &'a Module<'a>,
+    store: &'a mut Store,
+    memory: &'a mut [u8],
+    host: H,
+    /// Size of the linear memory, and the size it may grow to, in pages
+    pages: usize,
+    max_pages: usize,
+    table_len: usize,
+    /// Height of the value stack, the frame stack and the label stack
+    sp: usize,
+    fp: usize,
+    lp: usize,
+    /// Position of the next instruction and end of the current function
+    pc: usize,
+    end: usize,
+}
+
+/// Parameters and results of a block type
+fn block_type(module: &Module, code: &mut Reader) -> Result<(usize, usize)> {
+    match code.i33()? {
+        -64 => Ok((0, 0)),
+        -1 | -2 => Ok((0, 1)),
+        -3 | -4 => Err("Floating point is not supported"),
+        index if index >= 0 => {
+            let ty = module.ty(index as usize)?;
+            Ok((ty.params().len(), ty.result.is_some() as usize))
+        }
+        _ => Err("Invalid block type"),
+    }
+}
+
+/// Skip the immediates of the instruction with `opcode`
+fn skip_immediates(opcode: u8, code: &mut Reader) -> Result {
+    match opcode {
+        0x02..=0x04 => {
+            code.i33()?;
+        }
+        0x0c | 0x0d | 0x10 | 0x20..=0x24 | 0x3f | 0x40 => {
+            code.u32()?;
+        }
+        0x0e => {
+            for _ in 0..=code.u32()? {
+                code.u32()?;
+            }
+        }
+        0x11 | 0x28..=0x3e => {
+            code.u32()?;
+            code.u32()?;
+        }
+        0x1c => {
+            for _ in 0..code.u32()? {
+                code.u8()?;
+            }
+        }
+        0x41 => {
+            code.i32()?;
+        }
+        0x42 => {
+            code.i64()?;
+        }
+        0xfc => match code.u32()? {
+            10 => {
+                code.u8()?;
+                code.u8()?;
+            }
+            11 => {
+                code.u8()?;
+            }
+            _ => return Err("Unsupported instruction"),
+        },
+        _ => {}
+    }
+    Ok(())
+}
+
+/// Skip to the end of the block whose code starts at the position of `code`,
+/// returning whether the block continues with an else branch instead
+fn skip_block(code: &mut Reader) -> Result<bool> {
+    let mut depth = 0usize;
+    loop {
+        let opcode = code.u8()?;
+        match opcode {
+            0x02..=0x04 => depth += 1,
+            0x05 if depth == 0 => return Ok(true),
+            0x0b if depth == 0 => return Ok(false),
+            0x0b => depth -= 1,
+            _ => {}
+        }
+        skip_immediates(opcode, code)?;
+    }
+}
+
+const DIVISION_BY_ZERO: &str = "Integer division by zero";
+const OVERFLOW: &str = "Integer overflow";
+
+/// Integer operators of a width, numbered from the first operator of their
+/// kind, such as `i32.eqz`, `i32.clz` and `i32.add`
+macro_rules! integer_ops {
+    ($name:ident, $u:ty, $s:ty) => {
+        mod $name {
+            use super::{DIVISION_BY_ZERO, OVERFLOW};
+            use ustd::Result;
+
+            pub fn compare(op: u8, a: $u, b: $u) -> bool {
+                let (sa, sb) = (a as $s, b as $s);
+                match op {
+                    1 => a == b,
+                    2 => a != b,
+                    3 => sa < sb,
+                    4 => a < b,
+                    5 => sa > sb,
+                    6 => a > b,
+                    7 => sa <= sb,
+                    8 => a <= b,
+                    9 => sa >= sb,
+                    _ => a >= b,
+                }
+            }
+
+            pub fn unary(op: u8, a: $u) -> $u {
+                match op {
+                    0 => a.leading_zeros() as $u,
+                    1 => a.trailing_zeros() as $u,
+                    _ => a.count_ones() as $u,
+                }
+            }
+
+            pub fn binary(op: u8, a: $u, b: $u) -> Result<$u> {
+                let (sa, sb) = (a as $s, b as $s);
+                Ok(match op {
+                    0 => a.wrapping_add(b),
+                    1 => a.wrapping_sub(b),
+                    2 => a.wrapping_mul(b),
+                    3 if b == 0 => return Err(DIVISION_BY_ZERO),
+                    3 => sa.checked_div(sb).ok_or(OVERFLOW)? as $u,
+                    4 => a.checked_div(b).ok_or(DIVISION_BY_ZERO)?,
+                    5 if b == 0 => return Err(DIVISION_BY_ZERO),
+                    5 => sa.wrapping_rem(sb) as $u,
+                    6 => a.checked_rem(b).ok_or(DIVISION_BY_ZERO)?,
+                    7 => a & b,
+                    8 => a | b,
+                    9 => a ^ b,
+                    10 => a.wrapping_shl(b as u32),
+                    11 => sa.wrapping_shr(b as u32) as $u,
+                    12 => a.wrapping_shr(b as u32),
+                    13 => a.rotate_left(b as u32),
+                    _ => a.rotate_right(b as u32),
+                })
+            }
+        }
+    };
+}
+
+integer_ops!(i32_ops, u32, i32);
+integer_ops!(i64_ops, u64, i64);
+
+/// Size in bytes, signedness and whether the result is 64 bits wide of the
+/// load instruction with `opcode`
+fn load_kind(opcode: u8) -> Result<(usize, bool, bool)> {
+    Ok(match opcode {
+        0x28 => (4, false, false),
+        0x29 => (8, false, true),
+        0x2c => (1, true, false),
+        0x2d => (1, false, false),
+        0x2e => (2, true, false),
+        0x2f => (2, false, false),
+        0x30 => (1, true, true),
+        0x31 => (1, false, true),
+        0x32 => (2, true, true),
+        0x33 => (2, false, true),
+        0x34 => (4, true, true),
+        0x35 => (4, false, true),
+        _ => return Err("Floating point is not supported"),
+    })
+}
+
+/// Size in bytes stored by the store instruction with `opcode`
+fn store_size(opcode: u8) -> Result<usize> {
+    Ok(match opcode {
+        0x36 | 0x3e => 4,
+        0x37 => 8,
+        0x3a | 0x3c => 1,
+        0x3b | 0x3d => 2,
+        _ => return Err("Floating point is not supported"),
+    })
+}
+
+impl<'a, H: Host> Machine<'a, H> {
+    /// Instantiate `module`, with `memory` as the capacity of its linear
+    /// memory and `host` providing its imports, and run its start function
+    pub fn new(
+        module: &'a Module<'a>,
+        store: &'a mut Store,
+        memory: &'a mut [u8],
+        mut host: H,
+    ) -> Result<Self> {
+        for (i, import) in module.imports().iter().enumerate() {
+            let ty = module.ty(import.ty)?;
+            store.imports[i] = host.resolve(import.module, import.name, ty)?;
+        }
+        for (value, global) in store.globals.iter_mut().zip(module.globals()) {
+            *value = global.init;
+        }
+        let (pages, max_pages) = match module.memory {
+            Some(limits) => {
+                let capacity = memory.len() / PAGE_SIZE;
+                if limits.min > capacity {
+                    return Err("Not enough memory for module");
+                }
+                (limits.min, limits.max.unwrap_or(capacity).min(capacity))
+            }
+            None => (0, 0),
+        };
+        memory[..pages * PAGE_SIZE].fill(0);
+        let table_len = module.table.map_or(0, |limits| limits.min);
+        if table_len > MAX_TABLE {
+            return Err("Table too large");
+        }
+        store.table[..table_len].fill(NULL);
+        let mut machine = Self {
+            module,
+            store,
+            memory,
+            host,
+            pages,
+            max_pages,
+            table_len,
+            sp: 0,
+            fp: 0,
+            lp: 0,
+            pc: 0,
+            end: 0,
+        };
+        machine.init_table()?;
+        machine.init_memory()?;
+        if let Some(start) = module.start {
+            machine.call_function(start, &[])?;
+        }
+        Ok(machine)
+    }
+
+    pub fn host(&mut self) -> &mut H {
+        &mut self.host
+    }
+
+    /// Copy the active element segments into the table
+    fn init_table(&mut self) -> Result {
+        let mut reader = self.module.section(self.module.elements)?;
+        if reader.is_empty() {
+            return Ok(());
+        }
+        for _ in 0..reader.usize()? {
+            if reader.usize()? != 0 {
+                return Err("Unsupported element segment");
+            }
+            let offset = const_expr(&mut reader)? as u32 as usize;
+            let count = reader.usize()?;
+            let end = offset
+                .checked_add(count)
+                .ok_or("Element segment out of bounds")?;
+            if end > self.table_len {
+                return Err("Element segment out of bounds");
+            }
+            for slot in &mut self.store.table[offset..end] {
+                *slot = reader.usize()?;
+            }
+        }
+        Ok(())
+    }
+
+    /// Copy the active data segments into linear memory
+    fn init_memory(&mut self) -> Result {
+        let mut reader = self.module.section(self.module.data)?;
+        if reader.is_empty() {
+            return Ok(());
+        }
+        for _ in 0..reader.usize()? {
+            let offset = match reader.usize()? {
+                0 => Some(const_expr(&mut reader)?),
+                1 => None,
+                2 if reader.usize()? == 0 => Some(const_expr(&mut reader)?),
+                _ => return Err("Unsupported data segment"),
+            };
+            let len = reader.usize()?;
+            let bytes = reader.bytes(len)?;
+            if let Some(offset) = offset {
+                let start = offset as u32 as usize;
+                self.memory_slice(start, len)?.copy_from_slice(bytes);
+            }
+        }
+        Ok(())
+    }
+
+    /// Call the function exported as `name` with `args`
+    pub fn invoke(&mut self, name: &str, args: &[u64]) -> Result<Option<u64>> {
+        let index = self.module.export(name)?;
+        self.call_function(index, args)
+    }
+
+    fn call_function(&mut self, index: usize, args: &[u64]) -> Result<Option<u64>> {
+        let ty = self.module.func_type(index)?;
+        if args.len() != ty.params().len() {
+            return Err("Wrong number of arguments");
+        }
+        self.sp = 0;
+        self.fp = 0;
+        self.lp = 0;
+        for &arg in args {
+            self.push(arg)?;
+        }
+        self.call(index)?;
+        if self.fp > 0 {
+            self.run()?;
+        }
+        if ty.result.is_some() {
+            Ok(Some(self.pop()?))
+        } else {
+            Ok(None)
+        }
+    }
+
+    fn push(&mut self, value: u64) -> Result {
+        let slot = self.store.stack.get_mut(self.sp);
+        *slot.ok_or("Value stack exhausted")? = value;
+        self.sp += 1;
+        Ok(())
+    }
+
+    fn pop(&mut self) -> Result<u64> {
+        let locals = match self.fp {
+            0 => 0,
+            fp => {
+                let frame = &self.store.frames[fp - 1];
+                frame.locals + frame.local_count
+            }
+        };
+        if self.sp <= locals {
+            return Err("Value stack underflow");
+        }
+        self.sp -= 1;
+        Ok(self.store.stack[self.sp])
+    }
+
+    fn pop_u32(&mut self) -> Result<u32> {
+        Ok(self.pop()? as u32)
+    }
+
+    fn push_u32(&mut self, value: u32) -> Result {
+        self.push(value as u64)
+    }
+
+    /// Move the top `count` values down to `height`
+    fn unwind(&mut self, height: usize, count: usize) -> Result {
+        let top = self.sp.checked_sub(count).filter(|&top| top >= height);
+        let top = top.ok_or("Value stack underflow")?;
+        self.store.stack.copy_within(top..self.sp, height);
+        self.sp = height + count;
+        Ok(())
+    }
+
+    /// Call function `index`, with its arguments on the stack
+    fn call(&mut self, index: usize) -> Result {
+        let ty = *self.module.func_type(index)?;
+        let params = ty.params().len();
+        if let Some(&host_index) = self.store.imports[..self.module.imports().len()].get(index) {
+            let mut args = [0; MAX_PARAMS];
+            for arg in args[..params].iter_mut().rev() {
+                *arg = self.pop()?;
+            }
+            let memory = &mut self.memory[..self.pages * PAGE_SIZE];
+            let result = self.host.call(host_index, &args[..params], memory)?;
+            match (result, ty.result) {
+                (Some(value), Some(_)) => self.push(value)?,
+                (None, None) => {}
+                _ => return Err("Host function returned wrong number of values"),
+            }
+            return Ok(());
+        }
+        let function = *self
+            .module
+            .function(index)
+            .ok_or("Invalid function index")?;
+        let locals = self.sp.checked_sub(params).ok_or("Value stack underflow")?;
+        let frame = self
+            .store
+            .frames
+            .get_mut(self.fp)
+            .ok_or("Call stack exhausted")?;
+        *frame = Frame {
+            pc: self.pc,
+            end: self.end,
+            locals,
+            local_count: params + function.locals,
+            labels: self.lp,
+            arity: ty.result.is_some() as usize,
+        };
+        let sp = self.sp + function.locals;
+        let slots = self.store.stack.get_mut(self.sp..sp);
+        slots.ok_or("Value stack exhausted")?.fill(0);
+        self.fp += 1;
+        self.sp = sp;
+        self.pc = function.code.0;
+        self.end = function.code.1;
+        Ok(())
+    }
+
+    /// Return from the current function
+    fn ret(&mut self) -> Result {
+        let frame = self.store.frames[self.fp - 1];
+        let top = self.sp.checked_sub(frame.arity);
+        if top
+            .filter(|&top| top >= frame.locals + frame.local_count)
+            .is_none()
+        {
+            return Err("Value stack underflow");
+        }
+        self.unwind(frame.locals, frame.arity)?;
+        self.fp -= 1;
+        self.lp = frame.labels;
+        self.pc = frame.pc;
+        self.end = frame.end;
+        Ok(())
+    }
+
+    fn push_label(
+        &mut self,
+        kind: LabelKind,
+        start: usize,
+        (params, results): (usize, usize),
+    ) -> Result {
+        let height = self.sp.checked_sub(params).ok_or("Value stack underflow")?;
+        let arity = if kind == LabelKind::Loop {
+            params
+        } else {
+            results
+        };
+        let label = self
+            .store
+            .labels
+            .get_mut(self.lp)
+            .ok_or("Blocks nested too deeply")?;
+        *label = Label {
+            kind,
+            start,
+            height,
+            arity,
+        };
+        self.lp += 1;
+        Ok(())
+    }
+
+    /// Branch to the label `depth` blocks out
+    fn branch(&mut self, depth: usize) -> Result {
+        let labels = self.store.frames[self.fp - 1].labels;
+        let count = self.lp - labels;
+        if depth == count {
+            return self.ret();
+        }
+        let index = count.checked_sub(depth + 1).ok_or("Invalid branch depth")? + labels;
+        let label = self.store.labels[index];
+        self.unwind(label.height, label.arity)?;
+        if label.kind == LabelKind::Loop {
+            self.lp = index + 1;
+            self.pc = label.start;
+        } else {
+            let mut code = Reader::new(self.module.bytes(), label.start, self.end)?;
+            while skip_block(&mut code)? {}
+            self.lp = index;
+            self.pc = code.pos();
+        }
+        Ok(())
+    }
+
+    /// Range of `len` bytes of linear memory at `addr`
+    fn memory_slice(&mut self, addr: usize, len: usize) -> Result<&mut [u8]> {
+        let end = addr
+            .checked_add(len)
+            .filter(|&end| end <= self.pages * PAGE_SIZE);
+        let end = end.ok_or("Out of bounds memory access")?;
+        Ok(&mut self.memory[addr..end])
+    }
+
+    /// Effective address of a memory instruction
+    fn address(&mut self, code: &mut Reader) -> Result<usize> {
+        code.u32()?;
+        let offset = code.u32()?;
+        Ok(self.pop_u32()? as usize + offset as usize)
+    }
+
+    fn load(&mut self, opcode: u8, code: &mut Reader) -> Result {
+        let (size, signed, wide) = load_kind(opcode)?;
+        let addr = self.address(code)?;
+        let mut bytes = [0; 8];
+        bytes[..size].copy_from_slice(self.memory_slice(addr, size)?);
+        let mut value = u64::from_le_bytes(bytes);
+        if signed {
+            let shift = 64 - 8 * size as u32;
+            value = ((value << shift) as i64 >> shift) as u64;
+        }
+        if !wide {
+            value = value as u32 as u64;
+        }
+        self.push(value)
+    }
+
+    fn store(&mut self, opcode: u8, code: &mut Reader) -> Result {
+        let size = store_size(opcode)?;
+        let value = self.pop()?;
+        let addr = self.address(code)?;
+        let bytes = value.to_le_bytes();
+        self.memory_slice(addr, size)?
+            .copy_from_slice(&bytes[..size]);
+        Ok(())
+    }
+
+    fn grow(&mut self, delta: u32) -> Result {
+        let pages = self.pages;
+        match pages.checked_add(delta as usize) {
+            Some(new) if new <= self.max_pages => {
+                self.memory[pages * PAGE_SIZE..new * PAGE_SIZE].fill(0);
+                self.pages = new;
+                self.push_u32(pages as u32)
+            }
+            _ => self.push_u32(u32::MAX),
+        }
+    }
+
+    /// Bulk memory instructions, after the prefix
+    fn bulk(&mut self, code: &mut Reader) -> Result {
+        match code.u32()? {
+            10 => {
+                code.u8()?;
+                code.u8()?;
+                let len = self.pop_u32()? as usize;
+                let src = self.pop_u32()? as usize;
+                let dst = self.pop_u32()? as usize;
+                self.memory_slice(src, len)?;
+                self.memory_slice(dst, len)?;
+                self.memory.copy_within(src..src + len, dst);
+            }
+            11 => {
+                code.u8()?;
+                let len = self.pop_u32()? as usize;
+                let value = self.pop_u32()? as u8;
+                let dst = self.pop_u32()? as usize;
+                self.memory_slice(dst, len)?.fill(value);
+            }
+            _ => return Err("Unsupported instruction"),
+        }
+        Ok(())
+    }
+
+    fn local(&self, code: &mut Reader) -> Result<usize> {
+        let frame = &self.store.frames[self.fp - 1];
+        let index = code.usize()?;
+        if index >= frame.local_count {
+            return Err("Invalid local index");
+        }
+        Ok(frame.locals + index)
+    }
+
+    fn global(&self, code: &mut Reader) -> Result<usize> {
+        let index = code.usize()?;
+        if index >= self.module.globals().len() {
+            return Err("Invalid global index");
+        }
+        Ok(index)
+    }
+
+    /// Run until the outermost function returns
+    fn run(&mut self) -> Result {
+        while self.fp > 0 {
+            let mut code = Reader::new(self.module.bytes(), self.pc, self.end)?;
+            let opcode = code.u8()?;
+            self.step(opcode, &mut code)?;
+        }
+        Ok(())
+    }
+
+    /// Execute the instruction with `opcode`, whose immediates follow in
+    /// `code`
+    ///
+    /// Instructions that do not jump continue after their immediates.
+    fn step(&mut self, opcode: u8, code: &mut Reader) -> Result {
+        match opcode {
+            0x00 => return Err("Unreachable executed"),
+            0x01 => {}
+            0x02 | 0x03 => {
+                let ty = block_type(self.module, code)?;
+                let kind = if opcode == 0x02 {
+                    LabelKind::Block
+                } else {
+                    LabelKind::Loop
+                };
+                self.push_label(kind, code.pos(), ty)?;
+            }
+            0x04 => {
+                let ty = block_type(self.module, code)?;
+                let condition = self.pop_u32()?;
+                self.push_label(LabelKind::If, code.pos(), ty)?;
+                if condition == 0 && !skip_block(code)? {
+                    self.lp -= 1;
+                }
+            }
+            0x05 => {
+                // The then branch finished, so skip the else branch
+                skip_block(code)?;
+                self.lp -= 1;
+            }
+            0x0b => {
+                if self.lp > self.store.frames[self.fp - 1].labels {
+                    self.lp -= 1;
+                } else {
+                    return self.ret();
+                }
+            }
+            0x0c => return self.branch(code.usize()?),
+            0x0d => {
+                let depth = code.usize()?;
+                if self.pop_u32()? != 0 {
+                    return self.branch(depth);
+                }
+            }
+            0x0e => {
+                let count = code.usize()?;
+                let index = self.pop_u32()? as usize;
+                let mut depth = None;
+                for i in 0..=count {
+                    let target = code.usize()?;
+                    if i == index.min(count) {
+                        depth = Some(target);
+                    }
+                }
+                return self.branch(depth.unwrap());
+            }
+            0x0f => return self.ret(),
+            0x10 => {
+                let index = code.usize()?;
+                self.pc = code.pos();
+                return self.call(index);
+            }
+            0x11 => {
+                let ty = *self.module.ty(code.usize()?)?;
+                if code.u8()? != 0 {
+                    return Err("Invalid table index");
+                }
+                let element = self.pop_u32()? as usize;
+                if element >= self.table_len {
+                    return Err("Undefined table element");
+                }
+                let index = self.store.table[element];
+                if index == NULL {
+                    return Err("Uninitialized table element");
+                }
+                if *self.module.func_type(index)? != ty {
+                    return Err("Indirect call type mismatch");
+                }
+                self.pc = code.pos();
+                return self.call(index);
+            }
+            0x1a => {
+                self.pop()?;
+            }
+            0x1b | 0x1c => {
+                if opcode == 0x1c {
+                    for _ in 0..code.u32()? {
+                        code.u8()?;
+                    }
+                }
+                let condition = self.pop_u32()?;
+                let b = self.pop()?;
+                let a = self.pop()?;
+                self.push(if condition != 0 { a } else { b })?;
+            }
+            0x20 => {
+                let slot = self.local(code)?;
+                self.push(self.store.stack[slot])?;
+            }
+            0x21 => {
+                let slot = self.local(code)?;
+                self.store.stack[slot] = self.pop()?;
+            }
+            0x22 => {
+                let slot = self.local(code)?;
+                let value = self.pop()?;
+                self.store.stack[slot] = value;
+                self.push(value)?;
+            }
+            0x23 => {
+                let index = self.global(code)?;
+                self.push(self.store.globals[index])?;
+            }
+            0x24 => {
+                let index = self.global(code)?;
+                if !self.module.globals()[index].mutable {
+                    return Err("Global is immutable");
+                }
+                self.store.globals[index] = self.pop()?;
+            }
+            0x28..=0x35 => self.load(opcode, code)?,
+            0x36..=0x3e => self.store(opcode, code)?,
+            0x3f => {
+                code.u8()?;
+                self.push_u32(self.pages as u32)?;
+            }
+            0x40 => {
+                code.u8()?;
+                let delta = self.pop_u32()?;
+                self.grow(delta)?;
+            }
+            0x41 => self.push_u32(code.i32()? as u32)?,
+            0x42 => self.push(code.i64()? as u64)?,
+            0x45 => {
+                let a = self.pop_u32()?;
+                self.push_u32((a == 0) as u32)?;
+            }
+            0x46..=0x4f => {
+                let b = self.pop_u32()?;
+                let a = self.pop_u32()?;
+                self.push_u32(i32_ops::compare(opcode - 0x45, a, b) as u32)?;
+            }
+            0x50 => {
+                let a = self.pop()?;
+                self.push_u32((a == 0) as u32)?;
+            }
+            0x51..=0x5a => {
+                let b = self.pop()?;
+                let a = self.pop()?;
+                self.push_u32(i64_ops::compare(opcode - 0x50, a, b) as u32)?;
+            }
+            0x67..=0x69 => {
+                let a = self.pop_u32()?;
+                self.push_u32(i32_ops::unary(opcode - 0x67, a))?;
+            }
+            0x6a..=0x78 => {
+                let b = self.pop_u32()?;
+                let a = self.pop_u32()?;
+                self.push_u32(i32_ops::binary(opcode - 0x6a, a, b)?)?;
+            }
+            0x79..=0x7b => {
+                let a = self.pop()?;
+                self.push(i64_ops::unary(opcode - 0x79, a))?;
+            }
+            0x7c..=0x8a => {
+                let b = self.pop()?;
+                let a = self.pop()?;
+                self.push(i64_ops::binary(opcode - 0x7c, a, b)?)?;
+            }
+            0xa7 => {
+                let a = self.pop()?;
+                self.push_u32(a as u32)?;
+            }
+            0xac => {
+                let a = self.pop_u32()?;
+                self.push(a as i32 as i64 as u64)?;
+            }
+            0xad => {
+                let a = self.pop_u32()?;
+                self.push(a as u64)?;
+            }
+            0xc0 => {
+                let a = self.pop_u32()?;
+                self.push_u32(a as i8 as i32 as u32)?;
+            }
+            0xc1 => {
+                let a = self.pop_u32()?;
+                self.push_u32(a as i16 as i32 as u32)?;
+            }
+            0xc2..=0xc4 => {
+                let a = self.pop()?;
+                let value = match opcode {
+                    0xc2 => a as i8 as i64,
+                    0xc3 => a as i16 as i64,
+                    _ => a as i32 as i64,
+                };
+                self.push(value as u64)?;
+            }
+            0xfc => self.bulk(code)?,
+            0x43 | 0x44 | 0x5b..=0x66 | 0x8b..=0xa6 | 0xa8..=0xab | 0xae..=0xbf => {
+                return Err("Floating point is not supported")
+            }
+            _ => return Err("Unsupported instruction"),
+        }
+        self.pc = code.pos();
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::module::{
+        tests::{module, section},
+        ValType,
+    };
+    use std::{boxed::Box, vec, vec::Vec};
+
+    /// Host summing bytes of the guest's memory
+    struct Sum;
+
+    impl Host for Sum {
+        fn resolve(&mut self, module: &str, name: &str, ty: &FuncType) -> Result<usize> {
+            let sum = FuncType::new(&[ValType::I32, ValType::I32], Some(ValType::I32));
+            match (module, name) {
+                ("env", "sum") if *ty == sum => Ok(7),
+                _ => Err("Unknown import"),
+            }
+        }
+
+        fn call(&mut self, index: usize, args: &[u64], memory: &mut [u8]) -> Result<Option<u64>> {
+            assert_eq!(index, 7);
+            let bytes = &memory[args[0] as usize..][..args[1] as usize];
+            Ok(Some(bytes.iter().map(|&b| b as u64).sum()))
+        }
+    }
+
+    /// Code section with bodies of functions without locals
+    fn code(bodies: &[&[u8]]) -> Vec<u8> {
+        let mut contents = vec![bodies.len() as u8];
+        for body in bodies {
+            contents.extend_from_slice(&[body.len() as u8 + 1, 0]);
+            contents.extend_from_slice(body);
+        }
+        section(10, &contents)
+    }
+
+    /// Instantiate `bytes` and call its export `name` with `args`
+    fn run(bytes: &[u8], name: &str, args: &[u64]) -> Result<Option<u64>> {
+        let mut module = Box::new(Module::new());
+        module.parse(bytes)?;
+        let mut store = Box::new(Store::new());
+        let mut memory = vec![0; 2 * PAGE_SIZE];
+        let mut machine = Machine::new(&module, &mut store, &mut memory, Sum)?;
+        machine.invoke(name, args)
+    }
+
+    #[test]
+    fn recursion() {
+        let bytes = module(&[
+            section(1, &[1, 0x60, 1, 0x7e, 1, 0x7e]),
+            section(3, &[1, 0]),
+            section(7, &[1, 3, b'f', b'a', b'c', 0, 0]),
+            // if n == 0 { 1 } else { n * fac(n - 1) }
+            code(&[&[
+                0x20, 0, 0x50, 0x04, 0x7e, 0x42, 1, 0x05, 0x20, 0, 0x20, 0, 0x42, 1, 0x7d, 0x10, 0,
+                0x7e, 0x0b, 0x0b,
+            ]]),
+        ]);
+        assert_eq!(run(&bytes, "fac", &[0]), Ok(Some(1)));
+        assert_eq!(run(&bytes, "fac", &[20]), Ok(Some(2432902008176640000)));
+        assert_eq!(run(&bytes, "fac", &[1000]), Err("Call stack exhausted"));
+        assert_eq!(run(&bytes, "fac", &[]), Err("Wrong number of arguments"));
+    }
+
+    #[test]
+    fn loops() {
+        let bytes = module(&[
+            section(1, &[1, 0x60, 1, 0x7f, 1, 0x7f]),
+            section(3, &[1, 0]),
+            section(7, &[1, 3, b's', b'u', b'm', 0, 0]),
+            // Sum from 1 to n, with one local for the sum
+            section(
+                10,
+                &[
+                    1, 33, 1, 1, 0x7f, 0x02, 0x40, 0x03, 0x40, 0x20, 0, 0x45, 0x0d, 1, 0x20, 1,
+                    0x20, 0, 0x6a, 0x21, 1, 0x20, 0, 0x41, 1, 0x6b, 0x21, 0, 0x0c, 0, 0x0b, 0x0b,
+                    0x20, 1, 0x0b,
+                ],
+            ),
+        ]);
+        assert_eq!(run(&bytes, "sum", &[0]), Ok(Some(0)));
+        assert_eq!(run(&bytes, "sum", &[100]), Ok(Some(5050)));
+    }
+
+    #[test]
+    fn memory_and_host() {
+        let bytes = module(&[
+            section(1, &[2, 0x60, 2, 0x7f, 0x7f, 1, 0x7f, 0x60, 0, 1, 0x7f]),
+            section(2, &[1, 3, b'e', b'n', b'v', 3, b's', b'u', b'm', 0, 0]),
+            section(3, &[3, 1, 1, 1]),
+            section(5, &[1, 0, 1]),
+            section(
+                7,
+                &[2, 3, b'r', b'u', b'n', 0, 1, 3, b'o', b'o', b'b', 0, 2],
+            ),
+            code(&[
+                // Store the sum of three bytes at 0 and load it again
+                &[
+                    0x41, 0, 0x41, 8, 0x41, 3, 0x10, 0, 0x36, 2, 0, 0x41, 0, 0x28, 2, 0, 0x0b,
+                ],
+                &[0x41, 0xfd, 0xff, 0x03, 0x28, 2, 0, 0x0b],
+                // Grow memory by a page, then by more than it can
+                &[0x41, 1, 0x40, 0, 0x41, 1, 0x40, 0, 0x6a, 0x0b],
+            ]),
+            section(11, &[1, 0, 0x41, 8, 0x0b, 3, 1, 2, 3]),
+        ]);
+        assert_eq!(run(&bytes, "run", &[]), Ok(Some(6)));
+        assert_eq!(run(&bytes, "oob", &[]), Err("Out of bounds memory access"));
+        assert_eq!(run(&bytes, "sum", &[]), Err("Export not found"));
+
+        let mut grow = bytes.clone();
+        let exports = section(7, &[1, 4, b'g', b'r', b'o', b'w', 0, 3]);
+        let start = grow.windows(4).position(|w| w == [7, 13, 2, 3]).unwrap();
+        grow.splice(start..start + 15, exports);
+        // One page was added, then growing failed, wrapping the sum to zero
+        assert_eq!(run(&grow, "grow", &[]), Ok(Some(0)));
+    }
+
+    #[test]
+    fn traps() {
+        let bytes = module(&[
+            section(1, &[1, 0x60, 0, 1, 0x7f]),
+            section(3, &[3, 0, 0, 0]),
+            section(7, &[3, 1, b'd', 0, 0, 1, b'u', 0, 1, 1, b'o', 0, 2]),
+            code(&[
+                &[0x41, 1, 0x41, 0, 0x6e, 0x0b],
+                &[0x00, 0x0b],
+                &[0x41, 0x80, 0x80, 0x80, 0x80, 0x78, 0x41, 0x7f, 0x6d, 0x0b],
+            ]),
+        ]);
+        assert_eq!(run(&bytes, "d", &[]), Err("Integer division by zero"));
+        assert_eq!(run(&bytes, "u", &[]), Err("Unreachable executed"));
+        assert_eq!(run(&bytes, "o", &[]), Err("Integer overflow"));
+    }
+
+    #[test]
+    fn tables_and_branches() {
+        let bytes = module(&[
+            section(1, &[2, 0x60, 0, 1, 0x7f, 0x60, 1, 0x7f, 1, 0x7f]),
+            section(3, &[4, 0, 0, 1, 1]),
+            section(4, &[1, 0x70, 0, 3]),
+            section(7, &[2, 1, b'i', 0, 2, 1, b's', 0, 3]),
+            section(9, &[1, 0, 0x41, 0, 0x0b, 2, 0, 1]),
+            code(&[
+                &[0x41, 10, 0x0b],
+                &[0x41, 20, 0x0b],
+                &[0x20, 0, 0x11, 0, 0, 0x0b],
+                // Branch out of the inner block for zero, the outer otherwise
+                &[
+                    0x02, 0x40, 0x02, 0x40, 0x20, 0, 0x0e, 1, 0, 1, 0x0b, 0x41, 5, 0x0f, 0x0b,
+                    0x41, 7, 0x0b,
+                ],
+            ]),
+        ]);
+        assert_eq!(run(&bytes, "i", &[0]), Ok(Some(10)));
+        assert_eq!(run(&bytes, "i", &[1]), Ok(Some(20)));
+        assert_eq!(run(&bytes, "i", &[2]), Err("Uninitialized table element"));
+        assert_eq!(run(&bytes, "i", &[3]), Err("Undefined table element"));
+        assert_eq!(run(&bytes, "s", &[0]), Ok(Some(5)));
+        assert_eq!(run(&bytes, "s", &[1]), Ok(Some(7)));
+        assert_eq!(run(&bytes, "s", &[9]), Ok(Some(7)));
+    }
+}
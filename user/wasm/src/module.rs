@@ -0,0 +1,506 @@
+//! Decoding of modules
+//!
+//! Sections are decoded as far as the interpreter needs to look things up by
+//! index. Code is kept in the binary format, and the sections that are only
+//! used while instantiating are kept as ranges of the module to decode then.
+
+use crate::reader::Reader;
+use ustd::Result;
+
+/// Maximum number of function types, imported and defined functions, globals
+/// and parameters of a function
+pub const MAX_TYPES: usize = 64;
+pub const MAX_IMPORTS: usize = 32;
+pub const MAX_FUNCTIONS: usize = 256;
+pub const MAX_GLOBALS: usize = 64;
+pub const MAX_PARAMS: usize = 16;
+/// Maximum number of locals of a function, including its parameters
+pub const MAX_LOCALS: usize = 1024;
+
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub enum ValType {
+    I32,
+    I64,
+}
+
+impl ValType {
+    fn decode(byte: u8) -> Result<Self> {
+        match byte {
+            0x7f => Ok(Self::I32),
+            0x7e => Ok(Self::I64),
+            0x7d | 0x7c => Err("Floating point is not supported"),
+            _ => Err("Unknown value type"),
+        }
+    }
+}
+
+/// Signature of a function; functions return at most one value
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub struct FuncType {
+    params: [ValType; MAX_PARAMS],
+    param_count: usize,
+    pub result: Option<ValType>,
+}
+
+impl FuncType {
+    const EMPTY: Self = Self {
+        params: [ValType::I32; MAX_PARAMS],
+        param_count: 0,
+        result: None,
+    };
+
+    /// # Panics
+    /// If there are more than [`MAX_PARAMS`] parameters.
+    pub fn new(params: &[ValType], result: Option<ValType>) -> Self {
+        let mut ty = Self::EMPTY;
+        ty.params[..params.len()].copy_from_slice(params);
+        ty.param_count = params.len();
+        ty.result = result;
+        ty
+    }
+
+    pub fn params(&self) -> &[ValType] {
+        &self.params[..self.param_count]
+    }
+
+    fn decode(reader: &mut Reader) -> Result<Self> {
+        if reader.u8()? != 0x60 {
+            return Err("Invalid function type");
+        }
+        let mut ty = Self::EMPTY;
+        ty.param_count = reader.usize()?;
+        if ty.param_count > MAX_PARAMS {
+            return Err("Too many parameters");
+        }
+        for param in &mut ty.params[..ty.param_count] {
+            *param = ValType::decode(reader.u8()?)?;
+        }
+        ty.result = match reader.usize()? {
+            0 => None,
+            1 => Some(ValType::decode(reader.u8()?)?),
+            _ => return Err("Multiple results are not supported"),
+        };
+        Ok(ty)
+    }
+}
+
+/// Imported function
+#[derive(Copy, Clone, Debug)]
+pub struct Import<'a> {
+    pub module: &'a str,
+    pub name: &'a str,
+    pub ty: usize,
+}
+
+/// Function defined by the module
+#[derive(Copy, Clone, Debug)]
+pub struct Function {
+    pub ty: usize,
+    /// Number of locals besides the parameters
+    pub locals: usize,
+    /// Start and end of the code after the declarations of the locals
+    pub code: (usize, usize),
+}
+
+#[derive(Copy, Clone, Debug)]
+pub struct Global {
+    pub mutable: bool,
+    pub init: u64,
+}
+
+/// Minimum and maximum size of a memory or table
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub struct Limits {
+    pub min: usize,
+    pub max: Option<usize>,
+}
+
+impl Limits {
+    fn decode(reader: &mut Reader) -> Result<Self> {
+        let has_max = match reader.u8()? {
+            0 => false,
+            1 => true,
+            _ => return Err("Invalid limits"),
+        };
+        let min = reader.usize()?;
+        let max = if has_max { Some(reader.usize()?) } else { None };
+        Ok(Self { min, max })
+    }
+}
+
+/// Evaluate a constant expression
+pub fn const_expr(reader: &mut Reader) -> Result<u64> {
+    let value = match reader.u8()? {
+        0x41 => reader.i32()? as u32 as u64,
+        0x42 => reader.i64()? as u64,
+        0x23 => return Err("Imported globals are not supported"),
+        _ => return Err("Unsupported constant expression"),
+    };
+    if reader.u8()? != 0x0b {
+        return Err("Unsupported constant expression");
+    }
+    Ok(value)
+}
+
+/// Decoded module, borrowing its binary
+pub struct Module<'a> {
+    bytes: &'a [u8],
+    types: [FuncType; MAX_TYPES],
+    type_count: usize,
+    imports: [Import<'a>; MAX_IMPORTS],
+    import_count: usize,
+    functions: [Function; MAX_FUNCTIONS],
+    function_count: usize,
+    globals: [Global; MAX_GLOBALS],
+    global_count: usize,
+    pub memory: Option<Limits>,
+    pub table: Option<Limits>,
+    pub start: Option<usize>,
+    exports: (usize, usize),
+    pub elements: (usize, usize),
+    pub data: (usize, usize),
+}
+
+impl<'a> Module<'a> {
+    pub const fn new() -> Self {
+        const IMPORT: Import = Import {
+            module: "",
+            name: "",
+            ty: 0,
+        };
+        const FUNCTION: Function = Function {
+            ty: 0,
+            locals: 0,
+            code: (0, 0),
+        };
+        const GLOBAL: Global = Global {
+            mutable: false,
+            init: 0,
+        };
+        Self {
+            bytes: &[],
+            types: [FuncType::EMPTY; MAX_TYPES],
+            type_count: 0,
+            imports: [IMPORT; MAX_IMPORTS],
+            import_count: 0,
+            functions: [FUNCTION; MAX_FUNCTIONS],
+            function_count: 0,
+            globals: [GLOBAL; MAX_GLOBALS],
+            global_count: 0,
+            memory: None,
+            table: None,
+            start: None,
+            exports: (0, 0),
+            elements: (0, 0),
+            data: (0, 0),
+        }
+    }
+
+    /// Decode the module in `bytes`, replacing the previous one
+    ///
+    /// Decoding happens in place, as a module is too large for the stack of
+    /// user programs.
+    pub fn parse(&mut self, bytes: &'a [u8]) -> Result {
+        self.bytes = bytes;
+        self.type_count = 0;
+        self.import_count = 0;
+        self.function_count = 0;
+        self.global_count = 0;
+        self.memory = None;
+        self.table = None;
+        self.start = None;
+        self.exports = (0, 0);
+        self.elements = (0, 0);
+        self.data = (0, 0);
+
+        let mut reader = Reader::new(bytes, 0, bytes.len())?;
+        if reader.bytes(4) != Ok(b"\0asm") {
+            return Err("Not a WebAssembly module");
+        }
+        if reader.bytes(4)? != [1, 0, 0, 0] {
+            return Err("Unsupported WebAssembly version");
+        }
+        let mut declared = 0;
+        let mut defined = 0;
+        while !reader.is_empty() {
+            let id = reader.u8()?;
+            let len = reader.usize()?;
+            let mut section = reader.sub(len)?;
+            let range = (section.pos(), section.end());
+            match id {
+                0 => continue,
+                1 => self.parse_types(&mut section)?,
+                2 => self.parse_imports(&mut section)?,
+                3 => declared = self.parse_functions(&mut section)?,
+                4 => self.table = Self::parse_limits(&mut section, Some(0x70))?,
+                5 => self.memory = Self::parse_limits(&mut section, None)?,
+                6 => self.parse_globals(&mut section)?,
+                7 => self.exports = range,
+                8 => self.start = Some(section.usize()?),
+                9 => self.elements = range,
+                10 => defined = self.parse_code(&mut section)?,
+                11 => self.data = range,
+                12 => continue,
+                _ => return Err("Unknown section"),
+            }
+            if matches!(id, 1..=6 | 8 | 10) && !section.is_empty() {
+                return Err("Section has trailing bytes");
+            }
+        }
+        if declared != defined {
+            return Err("Function and code sections differ");
+        }
+        Ok(())
+    }
+
+    fn parse_types(&mut self, reader: &mut Reader) -> Result {
+        self.type_count = reader.usize()?;
+        if self.type_count > MAX_TYPES {
+            return Err("Too many types");
+        }
+        for ty in &mut self.types[..self.type_count] {
+            *ty = FuncType::decode(reader)?;
+        }
+        Ok(())
+    }
+
+    fn parse_imports(&mut self, reader: &mut Reader<'a>) -> Result {
+        self.import_count = reader.usize()?;
+        if self.import_count > MAX_IMPORTS {
+            return Err("Too many imports");
+        }
+        for i in 0..self.import_count {
+            let module = reader.name()?;
+            let name = reader.name()?;
+            if reader.u8()? != 0 {
+                return Err("Only functions can be imported");
+            }
+            let ty = reader.usize()?;
+            self.ty(ty)?;
+            self.imports[i] = Import { module, name, ty };
+        }
+        Ok(())
+    }
+
+    /// Decode the types of the defined functions, returning their number
+    fn parse_functions(&mut self, reader: &mut Reader) -> Result<usize> {
+        let count = reader.usize()?;
+        if self.import_count + count > MAX_FUNCTIONS {
+            return Err("Too many functions");
+        }
+        for i in 0..count {
+            let ty = reader.usize()?;
+            self.ty(ty)?;
+            self.functions[i].ty = ty;
+        }
+        self.function_count = count;
+        Ok(count)
+    }
+
+    /// Decode the limits of the only table or memory, with the element type
+    /// `element` for tables
+    fn parse_limits(reader: &mut Reader, element: Option<u8>) -> Result<Option<Limits>> {
+        match reader.usize()? {
+            0 => return Ok(None),
+            1 => {}
+            _ => return Err("Multiple memories or tables are not supported"),
+        }
+        if let Some(element) = element {
+            if reader.u8()? != element {
+                return Err("Unsupported table element type");
+            }
+        }
+        Limits::decode(reader).map(Some)
+    }
+
+    fn parse_globals(&mut self, reader: &mut Reader) -> Result {
+        self.global_count = reader.usize()?;
+        if self.global_count > MAX_GLOBALS {
+            return Err("Too many globals");
+        }
+        for global in &mut self.globals[..self.global_count] {
+            ValType::decode(reader.u8()?)?;
+            let mutable = match reader.u8()? {
+                0 => false,
+                1 => true,
+                _ => return Err("Invalid global mutability"),
+            };
+            let init = const_expr(reader)?;
+            *global = Global { mutable, init };
+        }
+        Ok(())
+    }
+
+    /// Decode the bodies of the defined functions, returning their number
+    fn parse_code(&mut self, reader: &mut Reader) -> Result<usize> {
+        let count = reader.usize()?;
+        if count > self.function_count {
+            return Err("Function and code sections differ");
+        }
+        for function in &mut self.functions[..count] {
+            let len = reader.usize()?;
+            let mut body = reader.sub(len)?;
+            let params = self.types[function.ty].param_count;
+            let mut locals = 0usize;
+            for _ in 0..body.usize()? {
+                locals = locals.saturating_add(body.usize()?);
+                ValType::decode(body.u8()?)?;
+            }
+            if params + locals > MAX_LOCALS {
+                return Err("Too many locals");
+            }
+            function.locals = locals;
+            function.code = (body.pos(), body.end());
+        }
+        Ok(count)
+    }
+
+    pub fn bytes(&self) -> &'a [u8] {
+        self.bytes
+    }
+
+    /// Reader of the section in `range`, which may be absent
+    pub fn section(&self, range: (usize, usize)) -> Result<Reader<'a>> {
+        Reader::new(self.bytes, range.0, range.1)
+    }
+
+    pub fn imports(&self) -> &[Import<'a>] {
+        &self.imports[..self.import_count]
+    }
+
+    /// Defined function with index `index` in the function index space, which
+    /// starts with the imports
+    pub fn function(&self, index: usize) -> Option<&Function> {
+        let index = index.checked_sub(self.import_count)?;
+        self.functions[..self.function_count].get(index)
+    }
+
+    pub fn function_count(&self) -> usize {
+        self.import_count + self.function_count
+    }
+
+    /// Type with index `index` in the type section
+    pub fn ty(&self, index: usize) -> Result<&FuncType> {
+        self.types[..self.type_count]
+            .get(index)
+            .ok_or("Invalid type index")
+    }
+
+    /// Type of the function with index `index`
+    pub fn func_type(&self, index: usize) -> Result<&FuncType> {
+        match self.imports().get(index) {
+            Some(import) => self.ty(import.ty),
+            None => self.ty(self.function(index).ok_or("Invalid function index")?.ty),
+        }
+    }
+
+    pub fn globals(&self) -> &[Global] {
+        &self.globals[..self.global_count]
+    }
+
+    /// Index of the function exported as `name`
+    pub fn export(&self, name: &str) -> Result<usize> {
+        let mut reader = self.section(self.exports)?;
+        if reader.is_empty() {
+            return Err("Export not found");
+        }
+        for _ in 0..reader.usize()? {
+            let export = reader.name()?;
+            let kind = reader.u8()?;
+            let index = reader.usize()?;
+            if export == name && kind == 0 {
+                return Ok(index);
+            }
+        }
+        Err("Export not found")
+    }
+}
+
+impl Default for Module<'_> {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+#[cfg(test)]
+pub mod tests {
+    use super::*;
+    use std::vec::Vec;
+
+    /// Section with `id` and `contents`, which should be shorter than 128
+    /// bytes
+    pub fn section(id: u8, contents: &[u8]) -> Vec<u8> {
+        let mut section = vec![id, contents.len() as u8];
+        section.extend_from_slice(contents);
+        section
+    }
+
+    /// Module consisting of `sections`
+    pub fn module(sections: &[Vec<u8>]) -> Vec<u8> {
+        let mut module = b"\0asm\x01\0\0\0".to_vec();
+        for section in sections {
+            module.extend_from_slice(section);
+        }
+        module
+    }
+
+    #[test]
+    fn parse() {
+        let bytes = module(&[
+            // (i32, i64) -> i32 and () -> ()
+            section(1, &[2, 0x60, 2, 0x7f, 0x7e, 1, 0x7f, 0x60, 0, 0]),
+            section(2, &[1, 1, b'm', 1, b'f', 0, 1]),
+            section(3, &[1, 0]),
+            section(5, &[1, 1, 1, 2]),
+            section(6, &[1, 0x7f, 1, 0x41, 0x7f, 0x0b]),
+            section(7, &[1, 3, b'r', b'u', b'n', 0, 1]),
+            // One i64 local and two i32 locals
+            section(10, &[1, 7, 2, 1, 0x7e, 2, 0x7f, 0x41, 0x0b]),
+        ]);
+        let mut module = Module::new();
+        module.parse(&bytes).unwrap();
+        assert_eq!(module.imports()[0].module, "m");
+        assert_eq!(module.imports()[0].name, "f");
+        assert_eq!(module.function_count(), 2);
+        let ty = module.func_type(1).unwrap();
+        assert_eq!(ty.params(), [ValType::I32, ValType::I64]);
+        assert_eq!(ty.result, Some(ValType::I32));
+        let function = module.function(1).unwrap();
+        assert_eq!(function.locals, 3);
+        assert_eq!(&bytes[function.code.0..function.code.1], [0x41, 0x0b]);
+        assert!(module.function(0).is_none());
+        assert_eq!(
+            module.memory,
+            Some(Limits {
+                min: 1,
+                max: Some(2)
+            })
+        );
+        assert_eq!(module.globals()[0].init, u32::MAX as u64);
+        assert!(module.globals()[0].mutable);
+        assert_eq!(module.export("run"), Ok(1));
+        assert_eq!(module.export("walk"), Err("Export not found"));
+    }
+
+    #[test]
+    fn unsupported() {
+        let mut module = Module::new();
+        assert_eq!(
+            module.parse(b"\0elf\x01\0\0\0"),
+            Err("Not a WebAssembly module")
+        );
+        let floats = self::module(&[section(1, &[1, 0x60, 1, 0x7d, 0])]);
+        assert_eq!(
+            module.parse(&floats),
+            Err("Floating point is not supported")
+        );
+        let missing_code = self::module(&[section(1, &[1, 0x60, 0, 0]), section(3, &[1, 0])]);
+        assert_eq!(
+            module.parse(&missing_code),
+            Err("Function and code sections differ")
+        );
+        let truncated = self::module(&[section(1, &[1, 0x60, 0, 0])]);
+        let truncated = &truncated[..truncated.len() - 1];
+        assert_eq!(module.parse(truncated), Err("Unexpected end of module"));
+    }
+}
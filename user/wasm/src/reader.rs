@@ -0,0 +1,141 @@
+//! Decoding of the values the binary format is built from
+
+use core::{convert::TryFrom, str};
+use ustd::Result;
+
+/// Reader of a module, keeping positions relative to its start
+#[derive(Clone)]
+pub struct Reader<'a> {
+    bytes: &'a [u8],
+    pos: usize,
+}
+
+impl<'a> Reader<'a> {
+    /// Reader of `bytes` up to `end`, starting at `pos`
+    pub fn new(bytes: &'a [u8], pos: usize, end: usize) -> Result<Self> {
+        let bytes = bytes.get(..end).ok_or("Unexpected end of module")?;
+        Ok(Self { bytes, pos })
+    }
+
+    pub fn pos(&self) -> usize {
+        self.pos
+    }
+
+    pub fn end(&self) -> usize {
+        self.bytes.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.pos >= self.bytes.len()
+    }
+
+    pub fn u8(&mut self) -> Result<u8> {
+        let byte = *self.bytes.get(self.pos).ok_or("Unexpected end of module")?;
+        self.pos += 1;
+        Ok(byte)
+    }
+
+    pub fn bytes(&mut self, len: usize) -> Result<&'a [u8]> {
+        let end = self
+            .pos
+            .checked_add(len)
+            .ok_or("Unexpected end of module")?;
+        let bytes = self
+            .bytes
+            .get(self.pos..end)
+            .ok_or("Unexpected end of module")?;
+        self.pos = end;
+        Ok(bytes)
+    }
+
+    /// Reader of the next `len` bytes, which are skipped here
+    pub fn sub(&mut self, len: usize) -> Result<Self> {
+        let pos = self.pos;
+        self.bytes(len)?;
+        Self::new(self.bytes, pos, self.pos)
+    }
+
+    /// Integer of at most `bits` bits in LEB128, sign-extended to 64 bits if
+    /// `signed`
+    fn leb128(&mut self, bits: u32, signed: bool) -> Result<u64> {
+        let mut value = 0;
+        let mut shift = 0;
+        loop {
+            let byte = self.u8()?;
+            value |= u64::from(byte & 0x7f) << shift;
+            shift += 7;
+            if byte & 0x80 == 0 {
+                if signed && shift < 64 && byte & 0x40 != 0 {
+                    value |= !0 << shift;
+                }
+                return Ok(value);
+            }
+            if shift >= bits {
+                return Err("Integer too long");
+            }
+        }
+    }
+
+    pub fn u32(&mut self) -> Result<u32> {
+        u32::try_from(self.leb128(32, false)?).map_err(|_| "Integer too large")
+    }
+
+    /// Count or index, which are encoded as `u32`
+    pub fn usize(&mut self) -> Result<usize> {
+        Ok(self.u32()? as usize)
+    }
+
+    pub fn i32(&mut self) -> Result<i32> {
+        i32::try_from(self.leb128(32, true)? as i64).map_err(|_| "Integer too large")
+    }
+
+    /// Signed 33-bit integer, as used by block types
+    pub fn i33(&mut self) -> Result<i64> {
+        let value = self.leb128(33, true)? as i64;
+        if !(-(1 << 32)..1 << 32).contains(&value) {
+            return Err("Integer too large");
+        }
+        Ok(value)
+    }
+
+    pub fn i64(&mut self) -> Result<i64> {
+        Ok(self.leb128(64, true)? as i64)
+    }
+
+    pub fn name(&mut self) -> Result<&'a str> {
+        let len = self.usize()?;
+        str::from_utf8(self.bytes(len)?).map_err(|_| "Name is not UTF-8")
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn reader(bytes: &[u8]) -> Reader<'_> {
+        Reader::new(bytes, 0, bytes.len()).unwrap()
+    }
+
+    #[test]
+    fn leb128() {
+        assert_eq!(reader(&[0xe5, 0x8e, 0x26]).u32(), Ok(624485));
+        assert_eq!(reader(&[0x7f]).i32(), Ok(-1));
+        assert_eq!(reader(&[0x80, 0x7f]).i64(), Ok(-128));
+        assert_eq!(reader(&[0x40]).i33(), Ok(-64));
+        assert_eq!(reader(&[0xff, 0xff, 0xff, 0xff, 0x0f]).u32(), Ok(u32::MAX));
+        assert!(reader(&[0xff, 0xff, 0xff, 0xff, 0x1f]).u32().is_err());
+        assert!(reader(&[0x80, 0x80, 0x80, 0x80, 0x80, 0x00]).u32().is_err());
+        assert_eq!(reader(&[0x80]).u32(), Err("Unexpected end of module"));
+    }
+
+    #[test]
+    fn sub() {
+        let mut outer = reader(&[2, b'h', b'i', 7]);
+        let mut inner = outer.sub(3).unwrap();
+        assert_eq!(inner.name(), Ok("hi"));
+        assert!(inner.is_empty());
+        assert_eq!(outer.pos(), 3);
+        assert_eq!(outer.u8(), Ok(7));
+        assert!(outer.sub(1).is_err());
+    }
+}
@@ -0,0 +1,179 @@
+//! Runs the WebAssembly module `guest.wasm` from the directory shared by the
+//! host, calling its `_start` export
+//!
+//! Guests can import the functions in [`FUNCTIONS`] from the `angstros`
+//! module, which wrap system calls. Before the guest runs, every handle it
+//! cannot use is closed, so neither the guest nor a bug in the interpreter
+//! can reach objects beyond what its imports expose.
+
+#![no_std]
+#![no_main]
+
+use core::{fmt::Write, panic::PanicInfo, str, time::Duration};
+use os::{
+    handle,
+    sys::{ObjectKind, Rights},
+    time,
+    ustd::{Context, LineWriter, Result},
+};
+use wasm::{FuncType, Host, Machine, Module, Store, ValType, PAGE_SIZE};
+
+/// Module run, relative to the directory shared by the host
+const PATH: &str = "guest.wasm";
+/// Maximum size of the module in bytes
+const CAPACITY: usize = 0x40000;
+/// Maximum size of the linear memory of the guest, in pages
+const MEMORY_PAGES: usize = 16;
+/// Longest path passed to `host_read`
+const MAX_PATH: usize = 256;
+
+/// Host functions, with their parameters and result
+const FUNCTIONS: [(&str, &[ValType], Option<ValType>); 5] = [
+    // Log the UTF-8 string at `ptr` of `len` bytes; returns 1 if it is invalid
+    ("log", &[ValType::I32, ValType::I32], Some(ValType::I32)),
+    // Exit with `code`
+    ("exit", &[ValType::I32], None),
+    // Sleep for `ns` nanoseconds; returns 1 if a shutdown is requested
+    ("sleep", &[ValType::I64], Some(ValType::I32)),
+    // Nanoseconds since boot
+    ("monotonic", &[], Some(ValType::I64)),
+    // Read from `offset` of the file at `path`, `path_len` in the shared
+    // directory into `buf` of `buf_len` bytes; returns the number of bytes
+    // read or -1
+    (
+        "host_read",
+        &[
+            ValType::I32,
+            ValType::I32,
+            ValType::I64,
+            ValType::I32,
+            ValType::I32,
+        ],
+        Some(ValType::I64),
+    ),
+];
+const HOST_READ: usize = 4;
+
+// Too large for the stack
+static mut BYTES: [u8; CAPACITY] = [0; CAPACITY];
+static mut MODULE: Module = Module::new();
+static mut STORE: Store = Store::new();
+static mut MEMORY: [u8; MEMORY_PAGES * PAGE_SIZE] = [0; MEMORY_PAGES * PAGE_SIZE];
+
+/// Slice of `len` bytes at `ptr` in the memory of the guest
+fn guest(memory: &mut [u8], ptr: u64, len: u64) -> Result<&mut [u8]> {
+    let start = ptr as u32 as usize;
+    let end = start + len as u32 as usize;
+    memory
+        .get_mut(start..end)
+        .context("Out of bounds memory access")
+}
+
+struct Angstros;
+
+impl Host for Angstros {
+    fn resolve(&mut self, module: &str, name: &str, ty: &FuncType) -> Result<usize> {
+        if module != "angstros" {
+            return Err("Unknown import module");
+        }
+        let index = FUNCTIONS.iter().position(|(other, _, _)| *other == name);
+        let index = index.context("Unknown host function")?;
+        let (_, params, result) = FUNCTIONS[index];
+        if *ty != FuncType::new(params, result) {
+            return Err("Host function imported with wrong type");
+        }
+        Ok(index)
+    }
+
+    fn call(&mut self, index: usize, args: &[u64], memory: &mut [u8]) -> Result<Option<u64>> {
+        let value = match index {
+            0 => {
+                let msg = guest(memory, args[0], args[1])?;
+                match str::from_utf8(msg) {
+                    Ok(msg) => {
+                        os::log(msg);
+                        0
+                    }
+                    Err(_) => 1,
+                }
+            }
+            1 => os::exit(args[0] as u32 as u64),
+            2 => !time::sleep(Duration::from_nanos(args[0])) as u64,
+            3 => time::monotonic().as_nanos() as u64,
+            HOST_READ => {
+                let mut path = [0; MAX_PATH];
+                let len = (args[1] as u32 as usize).min(MAX_PATH);
+                path[..len].copy_from_slice(guest(memory, args[0], len as u64)?);
+                let buf = guest(memory, args[3], args[4])?;
+                let read = match str::from_utf8(&path[..len]) {
+                    Ok(path) => os::host_read(path, args[2], buf),
+                    Err(_) => None,
+                };
+                read.map_or(u64::MAX, |read| read as u64)
+            }
+            _ => unreachable!(),
+        };
+        Ok(FUNCTIONS[index].2.map(|_| value))
+    }
+}
+
+/// Close the handles of `kind`, or restrict them to `rights` if given
+fn limit_handles(kind: ObjectKind, rights: Option<Rights>) {
+    while let Some(handle) = handle::find(kind) {
+        match rights {
+            Some(rights) if handle::restrict(handle, rights) => break,
+            _ => {
+                handle::close(handle);
+            }
+        }
+    }
+}
+
+fn run() -> Result {
+    // Only this function takes the statics
+    let bytes = unsafe { &mut BYTES };
+    let len = os::host_read(PATH, 0, bytes).context("Could not read guest.wasm")?;
+    if len == CAPACITY {
+        return Err("Module too large");
+    }
+    let bytes: &'static [u8] = &bytes[..len];
+    let module = unsafe { &mut MODULE };
+    module.parse(bytes)?;
+    let reads = module
+        .imports()
+        .iter()
+        .any(|import| import.module == "angstros" && import.name == "host_read");
+
+    limit_handles(ObjectKind::FrameBuffer, None);
+    limit_handles(ObjectKind::Audio, None);
+    limit_handles(ObjectKind::Input, None);
+    limit_handles(ObjectKind::AuditLog, None);
+    limit_handles(ObjectKind::Power, None);
+    limit_handles(ObjectKind::Trace, None);
+    limit_handles(
+        ObjectKind::HostFs,
+        if reads { Some(Rights::READ) } else { None },
+    );
+
+    let store = unsafe { &mut STORE };
+    let memory = unsafe { &mut MEMORY };
+    let mut machine = Machine::new(module, store, memory, Angstros)?;
+    machine.invoke("_start", &[])?;
+    Ok(())
+}
+
+#[no_mangle]
+extern "C" fn _start() {
+    if let Err(e) = run() {
+        let mut out = LineWriter::<_, 80>::new(os::log);
+        let _ = writeln!(out, "Guest failed: {}", e);
+        os::exit(1);
+    }
+    os::exit(0);
+}
+
+#[panic_handler]
+fn panic(_info: &PanicInfo) -> ! {
+    os::log("panic!");
+    os::exit(1);
+}
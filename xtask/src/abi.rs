@@ -0,0 +1,393 @@
+//! Description of the system call ABI for other languages
+//!
+//! There is no separate table of system calls: the `sys` crate is the source
+//! of truth, so its source is read for the codes, their documentation and
+//! safety requirements, and the rights they need. The result is written as a
+//! JSON description and as bindings for C and Zig, for runtimes that cannot
+//! use the `sys` crate.
+
+use crate::config::Info;
+use anyhow::{bail, Context, Result};
+use serde::Serialize;
+use std::{fmt::Write, fs, path::Path};
+
+/// Name of the C header
+const C_HEADER: &str = "angstros_syscall.h";
+
+/// Calling convention of `sys::syscall`
+const CONVENTION: Convention = Convention {
+    instruction: "syscall",
+    code: "rdi",
+    arguments: ["rsi", "rdx"],
+    result: "rax",
+    clobbered: [
+        "rdi", "rsi", "rdx", "rcx", "r8", "r9", "r10", "r11", "r12", "r13", "r14", "r15",
+    ],
+};
+
+#[derive(Serialize)]
+struct Convention {
+    instruction: &'static str,
+    /// Register holding the system call code
+    code: &'static str,
+    arguments: [&'static str; 2],
+    result: &'static str,
+    clobbered: [&'static str; 12],
+}
+
+#[derive(Serialize)]
+#[serde(rename_all = "camelCase")]
+struct Abi {
+    convention: Convention,
+    syscalls: Vec<Syscall>,
+    object_kinds: Vec<Constant>,
+    rights: Vec<Constant>,
+    signals: Vec<Constant>,
+}
+
+#[derive(Serialize)]
+struct Syscall {
+    name: String,
+    code: u64,
+    description: String,
+    safety: String,
+    requires: Option<Requirement>,
+}
+
+/// Handle a process needs for a system call
+#[derive(Serialize)]
+struct Requirement {
+    object: String,
+    rights: Vec<String>,
+}
+
+/// Variant of an enum or associated constant
+#[derive(Serialize)]
+struct Constant {
+    name: String,
+    value: u64,
+    description: String,
+}
+
+/// Documentation in `line`, without intra-doc links
+fn doc(line: &str) -> Option<String> {
+    let text = line.trim().strip_prefix("///")?;
+    Some(text.trim().replace("[`", "`").replace("`]", "`"))
+}
+
+/// Lines of the block in `source` opened by the line `start`, up to the first
+/// closing brace, with the documentation of each line
+fn block<'a>(source: &'a str, start: &str) -> Result<Vec<(Vec<String>, &'a str)>> {
+    let mut lines = source.lines().skip_while(|line| line.trim() != start);
+    if lines.next().is_none() {
+        bail!("No `{}` in sys crate", start);
+    }
+    let mut block = Vec::new();
+    let mut docs = Vec::new();
+    for line in lines.take_while(|line| line.trim() != "}") {
+        match doc(line) {
+            Some(text) => docs.push(text),
+            None => block.push((std::mem::take(&mut docs), line.trim())),
+        }
+    }
+    Ok(block)
+}
+
+/// Variants with explicit discriminants of enum `name`
+fn variants(source: &str, name: &str) -> Result<Vec<Constant>> {
+    let mut variants = Vec::new();
+    for (docs, line) in block(source, &format!("pub enum {} {{", name))? {
+        let (name, value) = match line.trim_end_matches(',').split_once(" = ") {
+            Some(variant) => variant,
+            None => continue,
+        };
+        let value = value
+            .parse()
+            .with_context(|| format!("Invalid discriminant of {}", name))?;
+        variants.push(Constant {
+            name: name.into(),
+            value,
+            description: docs.join(" "),
+        });
+    }
+    Ok(variants)
+}
+
+/// Single rights, leaving out combinations such as `Rights::ALL`
+fn rights(source: &str) -> Result<Vec<Constant>> {
+    let mut rights = Vec::new();
+    for (docs, line) in block(source, "impl Rights {")? {
+        let line = match line.strip_prefix("pub const ") {
+            Some(line) => line,
+            None => continue,
+        };
+        let (name, value) = match line.split_once(": Self = Self(") {
+            Some(right) => right,
+            None => continue,
+        };
+        let value = match value.trim_end_matches(");").split_once(" << ") {
+            Some(("1", shift)) => 1 << shift.parse::<u32>()?,
+            _ => continue,
+        };
+        rights.push(Constant {
+            name: name.into(),
+            value,
+            description: docs.join(" "),
+        });
+    }
+    Ok(rights)
+}
+
+/// Handles needed for system calls, from `SyscallCode::required_rights`
+fn requirements(source: &str) -> Result<Vec<(String, Requirement)>> {
+    let start = "pub fn required_rights(self) -> Option<(ObjectKind, Rights)> {";
+    let mut requirements = Vec::new();
+    for (_, line) in block(source, start)? {
+        let (codes, requirement) = match line.split_once(" => Some((") {
+            Some(arm) => arm,
+            None => continue,
+        };
+        let (object, rights) = requirement
+            .trim_end_matches(")),")
+            .split_once(", ")
+            .context("Invalid required rights")?;
+        let object = object.trim_start_matches("ObjectKind::");
+        let rights: Vec<_> = rights
+            .split(" | ")
+            .map(|right| right.trim_start_matches("Rights::").to_string())
+            .collect();
+        for code in codes.split(" | ") {
+            let requirement = Requirement {
+                object: object.into(),
+                rights: rights.clone(),
+            };
+            requirements.push((code.into(), requirement));
+        }
+    }
+    Ok(requirements)
+}
+
+/// Safety requirements of system calls, from the documentation of
+/// `sys::syscall`
+fn safety(source: &str) -> Vec<(String, String)> {
+    let mut safety: Vec<(String, String)> = Vec::new();
+    let docs = source
+        .lines()
+        .skip_while(|line| line.trim() != "/// # Safety")
+        .skip(1)
+        .take_while(|line| doc(line).is_some())
+        .filter_map(doc);
+    for text in docs {
+        match text.strip_prefix("- `SyscallCode::") {
+            Some(item) => match item.split_once("`: ") {
+                Some((code, text)) => safety.push((code.into(), text.into())),
+                None => continue,
+            },
+            None => {
+                if let Some((_, last)) = safety.last_mut() {
+                    last.push(' ');
+                    last.push_str(&text);
+                }
+            }
+        }
+    }
+    safety
+}
+
+/// Read the ABI from the source of the sys crate
+fn parse(source: &str) -> Result<Abi> {
+    let mut requirements = requirements(source)?;
+    let safety = safety(source);
+    let mut syscalls = Vec::new();
+    for code in variants(source, "SyscallCode")? {
+        let safety = match safety.iter().find(|(name, _)| *name == code.name) {
+            Some((_, text)) => text.clone(),
+            None => bail!("No safety requirements of {}", code.name),
+        };
+        let requires = requirements
+            .iter()
+            .position(|(name, _)| *name == code.name)
+            .map(|i| requirements.swap_remove(i).1);
+        syscalls.push(Syscall {
+            name: code.name,
+            code: code.value,
+            description: code.description,
+            safety,
+            requires,
+        });
+    }
+    if let Some((name, _)) = requirements.first() {
+        bail!("Rights required for unknown system call {}", name);
+    }
+    Ok(Abi {
+        convention: CONVENTION,
+        syscalls,
+        object_kinds: variants(source, "ObjectKind")?,
+        rights: rights(source)?,
+        signals: variants(source, "Signal")?,
+    })
+}
+
+/// Convert a name in upper camel case to snake case
+fn snake_case(name: &str) -> String {
+    let mut snake = String::new();
+    let mut after_lowercase = false;
+    for c in name.chars() {
+        if c.is_ascii_uppercase() && after_lowercase {
+            snake.push('_');
+        }
+        snake.push(c.to_ascii_lowercase());
+        after_lowercase = c.is_ascii_lowercase();
+    }
+    snake
+}
+
+/// Comment with `text` for C and Zig, indented by `indent`
+fn comment(out: &mut String, indent: &str, text: &str) {
+    let mut line = String::new();
+    for word in text.split_whitespace() {
+        if !line.is_empty() && indent.len() + line.len() + word.len() > 76 {
+            writeln!(out, "{}// {}", indent, line).unwrap();
+            line.clear();
+        }
+        if !line.is_empty() {
+            line.push(' ');
+        }
+        line.push_str(word);
+    }
+    if !line.is_empty() {
+        writeln!(out, "{}// {}", indent, line).unwrap();
+    }
+}
+
+/// Name, value and description of constants
+type Entries<'a> = Vec<(&'a str, u64, &'a str)>;
+
+fn entries(constants: &[Constant]) -> Entries<'_> {
+    let entries = constants.iter();
+    entries
+        .map(|c| (&*c.name, c.value, &*c.description))
+        .collect()
+}
+
+fn syscall_entries(abi: &Abi) -> Entries<'_> {
+    let entries = abi.syscalls.iter();
+    entries
+        .map(|s| (&*s.name, s.code, &*s.description))
+        .collect()
+}
+
+/// Registers clobbered by a system call, quoted for inline assembly
+fn clobbered(skip: usize) -> String {
+    let registers = CONVENTION.clobbered[skip..].iter();
+    let quoted: Vec<_> = registers.map(|reg| format!("\"{}\"", reg)).collect();
+    quoted.join(", ")
+}
+
+/// C header with enums and an inline function performing system calls
+fn c_header(abi: &Abi) -> String {
+    let mut out = String::new();
+    out.push_str("// System call ABI of ÅngstrÖS, generated by `cargo xtask abi`\n");
+    out.push_str("#ifndef ANGSTROS_SYSCALL_H\n#define ANGSTROS_SYSCALL_H\n\n");
+    out.push_str("#include <stdint.h>\n");
+    let groups = [
+        ("syscall", "SYS", syscall_entries(abi)),
+        ("object_kind", "OBJECT", entries(&abi.object_kinds)),
+        ("right", "RIGHT", entries(&abi.rights)),
+        ("signal", "SIGNAL", entries(&abi.signals)),
+    ];
+    for (group, prefix, entries) in &groups {
+        writeln!(out, "\nenum angstros_{} {{", group).unwrap();
+        for &(name, value, description) in entries {
+            comment(&mut out, "    ", description);
+            let name = snake_case(name).to_ascii_uppercase();
+            writeln!(out, "    ANGSTROS_{}_{} = {},", prefix, name, value).unwrap();
+        }
+        out.push_str("};\n");
+    }
+    // The code and arguments are inputs and outputs, so skip them here
+    let function = r#"
+// Perform a system call and return its raw return code
+static inline uint64_t angstros_syscall(uint64_t code, uint64_t rsi, uint64_t rdx) {
+    uint64_t rax;
+    __asm__ volatile("syscall"
+                     : "=a"(rax), "+D"(code), "+S"(rsi), "+d"(rdx)
+                     :
+                     : CLOBBERED, "memory");
+    return rax;
+}
+
+#endif
+"#;
+    out.push_str(&function.replace("CLOBBERED", &clobbered(3)));
+    out
+}
+
+/// Zig module with enums and a function performing system calls
+fn zig_module(abi: &Abi) -> String {
+    let mut out = String::new();
+    out.push_str("// System call ABI of ÅngstrÖS, generated by `cargo xtask abi`\n");
+    let groups = [
+        ("Syscall", syscall_entries(abi)),
+        ("ObjectKind", entries(&abi.object_kinds)),
+        ("Signal", entries(&abi.signals)),
+    ];
+    for (group, entries) in &groups {
+        writeln!(out, "\npub const {} = enum(u64) {{", group).unwrap();
+        for &(name, value, description) in entries {
+            comment(&mut out, "    ", description);
+            writeln!(out, "    {} = {},", snake_case(name), value).unwrap();
+        }
+        out.push_str("};\n");
+    }
+    out.push_str("\npub const Rights = struct {\n");
+    for right in &abi.rights {
+        comment(&mut out, "    ", &right.description);
+        writeln!(out, "    pub const {}: u32 = {};", right.name, right.value).unwrap();
+    }
+    out.push_str("};\n");
+    let function = r#"
+/// Perform a system call and return its raw return code
+pub fn syscall(code: Syscall, rsi: u64, rdx: u64) u64 {
+    return asm volatile ("syscall"
+        : [ret] "={rax}" (-> u64),
+        : [code] "{rdi}" (@enumToInt(code)),
+          [rsi] "{rsi}" (rsi),
+          [rdx] "{rdx}" (rdx),
+        : CLOBBERED, "memory"
+    );
+}
+"#;
+    out.push_str(&function.replace("CLOBBERED", &clobbered(0)));
+    out
+}
+
+/// Read the ABI from the sys crate
+fn read(info: &Info) -> Result<Abi> {
+    let path = info.sys_dir().join("src/lib.rs");
+    let source =
+        fs::read_to_string(&path).with_context(|| format!("Could not read {}", path.display()))?;
+    parse(&source)
+}
+
+/// Write the C header to `dir`, for building C programs
+pub fn write_c_header(info: &Info, dir: &Path) -> Result<()> {
+    fs::write(dir.join(C_HEADER), c_header(&read(info)?))?;
+    Ok(())
+}
+
+/// Write the ABI description and bindings to the ABI directory
+pub fn generate(info: &Info) -> Result<()> {
+    let abi = read(info)?;
+    let dir = info.abi_dir();
+    fs::create_dir_all(&dir)?;
+    fs::write(dir.join("abi.json"), serde_json::to_string_pretty(&abi)?)?;
+    fs::write(dir.join(C_HEADER), c_header(&abi))?;
+    fs::write(dir.join("angstros_syscall.zig"), zig_module(&abi))?;
+    println!(
+        "Described {} system calls in {}",
+        abi.syscalls.len(),
+        dir.display()
+    );
+    Ok(())
+}
@@ -1,8 +1,10 @@
 use crate::{
+    c,
     command::Cargo,
-    config::{self, BuildConfig, Info, RunInfo},
+    config::{self, BuildConfig, Info, RunInfo, SubCommand},
+    image,
 };
-use anyhow::Result;
+use anyhow::{anyhow, Result};
 use std::{
     fs,
     path::{Path, PathBuf},
@@ -11,11 +13,41 @@ use std::{
 pub fn build(info: &Info) -> Result<RunInfo> {
     let cfg = handle_config(info)?;
     let user = build_user(info, &cfg.user)?;
-    let kernel = build_kernel(info, &user)?;
+    let mut programs = vec![(cfg.user.as_str(), user.clone())];
+    for name in &cfg.programs {
+        if programs.iter().all(|(n, _)| n != name) {
+            programs.push((name.as_str(), build_user(info, name)?));
+        }
+    }
+    write_programs(info, &programs)?;
+    let kernel = build_kernel(info)?;
+    let efi_stub = build_stub(info, &kernel)?;
+    build_efidir(info, &efi_stub)?;
+    let info = RunInfo {
+        info,
+        user,
+        kernel,
+        efi_stub,
+    };
+    if info.info.bios {
+        image::build(&info)?;
+    }
+    Ok(info)
+}
+
+/// Build the UEFI stub with the stub test kernel embedded instead of the kernel
+pub fn build_stub_test(info: &Info) -> Result<RunInfo> {
+    if info.bios {
+        return Err(anyhow!("The UEFI stub cannot be tested on legacy BIOS"));
+    }
+    handle_config(info)?;
+    let kernel = build_stub_test_kernel(info)?;
     let efi_stub = build_stub(info, &kernel)?;
     build_efidir(info, &efi_stub)?;
     Ok(RunInfo {
         info,
+        // There is no user program, nor profiles to symbolize with it
+        user: kernel.clone(),
         kernel,
         efi_stub,
     })
@@ -27,7 +59,12 @@ fn handle_config(info: &Info) -> Result<BuildConfig> {
     } else {
         "build.toml"
     };
-    let cfg: BuildConfig = config::parse(info, file)?;
+    let mut cfg: BuildConfig = config::parse(info, file)?;
+    cfg.kernel.deterministic |= info.deterministic;
+    cfg.kernel.sanitize_heap |= info.sanitize;
+    cfg.kernel.test_stress |= matches!(info.cmd, SubCommand::Test { stress: true, .. });
+    // A random load address would make the boot irreproducible
+    cfg.uefi_stub.kaslr &= !cfg.kernel.deterministic;
     let out = info.out_dir();
     xshell::mkdir_p(&out)?;
     fs::write(out.clone().join("cfg_kernel.rs"), format!("{}", cfg.kernel))?;
@@ -35,19 +72,47 @@ fn handle_config(info: &Info) -> Result<BuildConfig> {
     Ok(cfg)
 }
 
+/// Generate the table of user programs embedded in the kernel; the first one
+/// is init
+fn write_programs(info: &Info, programs: &[(&str, PathBuf)]) -> Result<()> {
+    let mut table = String::new();
+    for (i, (_, path)) in programs.iter().enumerate() {
+        table += &format!(
+            "static PROGRAM_{}: common::elf::Elf<{{ include_bytes!({:?}).len() }}> = \
+             common::elf::Elf::new(*include_bytes!({:?}));\n",
+            i, path, path
+        );
+    }
+    table += &format!(
+        "pub static PROGRAMS: [(&str, &dyn common::elf::AnyElf); {}] = [\n",
+        programs.len()
+    );
+    for (i, (name, _)) in programs.iter().enumerate() {
+        table += &format!("    ({:?}, &PROGRAM_{}),\n", name, i);
+    }
+    table += "];\n";
+    table += &format!("pub const INIT: &str = {:?};\n", programs[0].0);
+    fs::write(info.out_dir().join("programs.rs"), table)?;
+    Ok(())
+}
+
 fn build_user(info: &Info, user: &str) -> Result<PathBuf> {
+    if c::is_c_program(user) {
+        return c::build(info, user);
+    }
     println!("Building userspace...");
     Cargo::new("build")
         .with_info(info)
         .package(user)
         .env("RUST_TARGET_PATH", info.targetspec_dir())
         .target("x86_64-unknown-angstros")
-        .z("build-std=core")
+        // Programs can use alloc with the heap of the os crate
+        .z("build-std=core,alloc")
         .z("build-std-features=compiler-builtins-mem")
         .single_executable()
 }
 
-fn build_kernel(info: &Info, user: &Path) -> Result<PathBuf> {
+fn build_kernel(info: &Info) -> Result<PathBuf> {
     println!("Building kernel...");
     let mut cargo = Cargo::new(if info.test() { "test" } else { "build" });
     if info.test() {
@@ -60,11 +125,28 @@ fn build_kernel(info: &Info, user: &Path) -> Result<PathBuf> {
         .target("x86_64-unknown-angstros")
         .z("build-std=core,alloc")
         .z("build-std-features=compiler-builtins-mem")
-        .env("USER_PATH", user)
+        // Canary handling is implemented by the kernel stack module, and init
+        // functions are only referenced through their section's bounds
+        .env(
+            "RUSTFLAGS",
+            "-Z stack-protector=strong -C link-arg=-znostart-stop-gc",
+        )
         .env("XTASK_OUT_DIR", info.out_dir())
         .single_executable()
 }
 
+fn build_stub_test_kernel(info: &Info) -> Result<PathBuf> {
+    println!("Building stub test kernel...");
+    Cargo::new("build")
+        .with_info(info)
+        .package("stub_test")
+        .env("RUST_TARGET_PATH", info.targetspec_dir())
+        .target("x86_64-unknown-angstros")
+        .z("build-std=core")
+        .z("build-std-features=compiler-builtins-mem")
+        .single_executable()
+}
+
 fn build_stub(info: &Info, kernel: &Path) -> Result<PathBuf> {
     println!("Building UEFI stub...");
     Cargo::new("build")
@@ -0,0 +1,85 @@
+//! Userspace programs written in C
+//!
+//! Programs named after a file in `data/c` ending in `.c` are compiled with the
+//! C compiler in the `CC` environment variable, or `cc`, and linked by
+//! `rust-lld` with the startup code and linker script in that directory. They
+//! include `angstros_syscall.h`, generated from the sys crate, to make system
+//! calls. There is no C library, so programs should avoid constructs the
+//! compiler turns into calls to `memcpy` and friends.
+
+use crate::{abi, command::CommandResultExt, config::Info};
+use anyhow::{Context, Result};
+use std::{
+    env,
+    path::{Path, PathBuf},
+    process::Command,
+    str,
+};
+
+/// Flags matching the userspace target of Rust programs
+const CFLAGS: &[&str] = &[
+    "-std=c11",
+    "-O2",
+    "-Wall",
+    "-ffreestanding",
+    "-nostdlib",
+    "-fPIE",
+    "-mno-red-zone",
+    "-mgeneral-regs-only",
+    "-fno-stack-protector",
+];
+
+/// Whether `name` refers to a C program instead of a Cargo package
+pub fn is_c_program(name: &str) -> bool {
+    name.ends_with(".c")
+}
+
+/// Path to `rust-lld`, which is shipped with the Rust toolchain
+fn rust_lld() -> Result<PathBuf> {
+    let output = Command::new("rustc")
+        .args(&["--print", "target-libdir"])
+        .output()
+        .check_status("rustc")?;
+    let libdir = str::from_utf8(&output.stdout).context("Invalid rustc output")?;
+    Ok(Path::new(libdir.trim())
+        .with_file_name("bin")
+        .join("rust-lld"))
+}
+
+fn compile(source: &Path, object: &Path, include: &Path) -> Result<()> {
+    let cc = env::var_os("CC").unwrap_or_else(|| "cc".into());
+    Command::new(cc)
+        .args(CFLAGS)
+        .arg("-I")
+        .arg(include)
+        .arg("-c")
+        .arg(source)
+        .arg("-o")
+        .arg(object)
+        .status()
+        .check_status("C compiler")
+}
+
+/// Build C program `name`, returning the path to the executable
+pub fn build(info: &Info, name: &str) -> Result<PathBuf> {
+    println!("Building C program {}...", name);
+    let source = info.c_dir();
+    let out = info.out_dir().join("c");
+    xshell::mkdir_p(&out)?;
+    abi::write_c_header(info, &out)?;
+    let crt0 = out.join("crt0.o");
+    compile(&source.join("crt0.S"), &crt0, &out)?;
+    let object = out.join(name).with_extension("o");
+    compile(&source.join(name), &object, &out)?;
+    let executable = out.join(name).with_extension("");
+    Command::new(rust_lld()?)
+        .args(&["-flavor", "gnu", "-pie", "--no-dynamic-linker", "-T"])
+        .arg(source.join("user.ld"))
+        .arg(&crt0)
+        .arg(&object)
+        .arg("-o")
+        .arg(&executable)
+        .status()
+        .check_status("rust-lld")?;
+    Ok(executable)
+}
@@ -0,0 +1,23 @@
+//! Unit tests of shared crates, run on the host instead of in QEMU
+
+use crate::{command::Cargo, config::Info};
+use anyhow::Result;
+
+/// Crates with logic that does not depend on running in the kernel
+const PACKAGES: &[&str] = &["common", "ustd", "wasm"];
+
+/// Run the unit tests of shared crates on the host
+///
+/// These crates are `no_std`, unless built with their `std` feature.
+pub fn check(info: &Info) -> Result<()> {
+    for package in PACKAGES {
+        println!("Testing {} on the host...", package);
+        Cargo::plain("test")
+            .with_info(info)
+            .package(package)
+            .arg("--features")
+            .arg("std")
+            .run()?;
+    }
+    Ok(())
+}
@@ -62,9 +62,15 @@ pub struct Cargo(Command);
 
 impl Cargo {
     pub fn new<S: AsRef<OsStr>>(cmd: S) -> Self {
+        let mut c = Self::plain(cmd);
+        c.arg("--message-format=json-render-diagnostics");
+        c
+    }
+
+    /// Cargo command with human-readable output, which is not parsed
+    pub fn plain<S: AsRef<OsStr>>(cmd: S) -> Self {
         let mut c = env::var_os("CARGO").map_or_else(|| Command::new(env!("CARGO")), Command::new);
         c.arg(cmd);
-        c.arg("--message-format=json-render-diagnostics");
         c.stderr(Stdio::inherit());
         Self(c)
     }
@@ -98,6 +104,10 @@ impl Cargo {
         self
     }
 
+    pub fn run(&mut self) -> Result<()> {
+        self.0.status().check_status("Cargo")
+    }
+
     fn output(&mut self) -> Result<Output> {
         self.0.output().check_status("Cargo")
     }
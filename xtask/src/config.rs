@@ -3,6 +3,7 @@ use clap::Clap;
 use serde::{de::DeserializeOwned, Deserialize};
 use std::{
     fmt, fs,
+    net::{Ipv4Addr, SocketAddrV4},
     path::{Path, PathBuf},
 };
 
@@ -23,13 +24,25 @@ pub struct Info {
     /// Build in release mode with optimizations
     #[clap(long)]
     pub release: bool,
+    /// Boot deterministically to reproduce failures, with the kernel's
+    /// deterministic option and QEMU counting instructions as its clock
+    #[clap(long)]
+    pub deterministic: bool,
+    /// Build the kernel with its heap sanitizer, which catches heap buffer
+    /// overflows and uses after free at the cost of speed and memory
+    #[clap(long)]
+    pub sanitize: bool,
+    /// Boot the hybrid image with the legacy BIOS of QEMU, which loads the
+    /// kernel through Limine instead of the UEFI stub (experimental)
+    #[clap(long)]
+    pub bios: bool,
     #[clap(subcommand)]
     pub cmd: SubCommand,
 }
 
 impl Info {
     pub fn test(&self) -> bool {
-        self.cmd == SubCommand::Test
+        matches!(self.cmd, SubCommand::Test { .. })
     }
 
     pub fn targetspec_dir(&self) -> PathBuf {
@@ -44,6 +57,46 @@ impl Info {
         self.base_dir.join("target/xtask/esp")
     }
 
+    pub fn image_dir(&self) -> PathBuf {
+        self.base_dir.join("target/xtask/image")
+    }
+
+    pub fn image(&self) -> PathBuf {
+        self.base_dir.join("target/xtask/angstros.iso")
+    }
+
+    pub fn screenshot_dir(&self) -> PathBuf {
+        self.base_dir.join("target/xtask/screenshots")
+    }
+
+    pub fn trace_dir(&self) -> PathBuf {
+        self.base_dir.join("target/xtask/traces")
+    }
+
+    pub fn profile_dir(&self) -> PathBuf {
+        self.base_dir.join("target/xtask/profiles")
+    }
+
+    pub fn crash_dump_dir(&self) -> PathBuf {
+        self.base_dir.join("target/xtask/crash-dumps")
+    }
+
+    pub fn qmp_socket(&self) -> PathBuf {
+        self.base_dir.join("target/xtask/qmp.sock")
+    }
+
+    pub fn sys_dir(&self) -> PathBuf {
+        self.base_dir.join("user/sys")
+    }
+
+    pub fn c_dir(&self) -> PathBuf {
+        self.base_dir.join("data/c")
+    }
+
+    pub fn abi_dir(&self) -> PathBuf {
+        self.base_dir.join("target/xtask/abi")
+    }
+
     pub fn config_dir(&self) -> PathBuf {
         self.config_dir
             .clone()
@@ -60,11 +113,44 @@ pub enum SubCommand {
     /// Run kernel in QEMU
     Run,
     /// Run kernel tests in QEMU
-    Test,
+    Test {
+        /// Instead test the UEFI stub, with a minimal kernel checking what it
+        /// hands over
+        #[clap(long)]
+        stub: bool,
+        /// Run the kernel tests under stress: the local APIC timer interrupts
+        /// them at a high rate and does background work
+        #[clap(long)]
+        stress: bool,
+    },
+    /// Run unit tests of shared code on the host
+    Check,
+    /// Build a hybrid image booting on legacy BIOS and UEFI firmware through
+    /// Limine, in target/xtask/angstros.iso (experimental)
+    Image,
+    /// Inject a non-maskable interrupt into the running kernel
+    Nmi,
+    /// Decode crash dump saved while running the kernel
+    CrashDump {
+        /// Path to the crash dump
+        #[clap(parse(from_os_str))]
+        dump: PathBuf,
+    },
+    /// Receive the log sent by the kernel over UDP, verifying each record with
+    /// the remote log key of the build configuration
+    RemoteLog {
+        /// UDP port to listen on
+        #[clap(default_value = "5140")]
+        port: u16,
+    },
+    /// Describe the system call ABI in JSON and generate bindings for C and
+    /// Zig, in target/xtask/abi
+    Abi,
 }
 
 pub struct RunInfo<'a> {
     pub info: &'a Info,
+    pub user: PathBuf,
     pub kernel: PathBuf,
     pub efi_stub: PathBuf,
 }
@@ -83,14 +169,75 @@ fn camel_case(s: &str) -> String {
 #[serde(rename_all = "kebab-case")]
 pub struct BuildConfig {
     pub user: String,
+    #[serde(default)]
+    pub programs: Vec<String>,
     pub uefi_stub: StubConfig,
     pub kernel: KernelConfig,
 }
 
+fn default_true() -> bool {
+    true
+}
+
+fn default_menu_timeout() -> u64 {
+    3
+}
+
+fn default_progress() -> String {
+    "splash".into()
+}
+
+fn default_console() -> String {
+    "com1".into()
+}
+
+fn default_log_vt() -> u64 {
+    1
+}
+
+fn default_user_vt() -> u64 {
+    2
+}
+
+/// Address of the guest on QEMU's user network
+fn default_remote_log_source() -> Ipv4Addr {
+    Ipv4Addr::new(10, 0, 2, 15)
+}
+
+/// Formatting of log messages, shared by the UEFI stub and kernel
+#[derive(Deserialize)]
+#[serde(rename_all = "kebab-case")]
+struct LogFormat {
+    #[serde(default = "default_true")]
+    log_color: bool,
+    #[serde(default = "default_true")]
+    log_target: bool,
+}
+
+impl fmt::Display for LogFormat {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        writeln!(
+            f,
+            "pub const LOG_FORMAT: common::logger::Format = common::logger::Format {{ color: {}, target: {} }};",
+            self.log_color, self.log_target
+        )
+    }
+}
+
 #[derive(Deserialize)]
 #[serde(rename_all = "kebab-case")]
 pub struct StubConfig {
     log_level: String,
+    #[serde(flatten)]
+    log_format: LogFormat,
+    #[serde(default)]
+    log_file: bool,
+    #[serde(default = "default_progress")]
+    progress: String,
+    #[serde(default = "default_menu_timeout")]
+    menu_timeout: u64,
+    #[serde(default)]
+    pub kaslr: bool,
 }
 
 impl fmt::Display for StubConfig {
@@ -100,6 +247,15 @@ impl fmt::Display for StubConfig {
             "pub const LOG_LEVEL: log::LevelFilter = log::LevelFilter::{};",
             camel_case(&self.log_level)
         )?;
+        write!(f, "{}", self.log_format)?;
+        writeln!(f, "pub const LOG_FILE: bool = {};", self.log_file)?;
+        writeln!(
+            f,
+            "pub const PROGRESS: crate::progress::Mode = crate::progress::Mode::{};",
+            camel_case(&self.progress)
+        )?;
+        writeln!(f, "pub const MENU_TIMEOUT: u64 = {};", self.menu_timeout)?;
+        writeln!(f, "pub const KASLR: bool = {};", self.kaslr)?;
         Ok(())
     }
 }
@@ -108,7 +264,47 @@ impl fmt::Display for StubConfig {
 #[serde(rename_all = "kebab-case")]
 pub struct KernelConfig {
     log_level: String,
+    #[serde(flatten)]
+    log_format: LogFormat,
     allocator: String,
+    #[serde(default)]
+    trace: bool,
+    #[serde(default)]
+    profile: bool,
+    #[serde(default)]
+    latency: bool,
+    #[serde(default)]
+    crash_dump: bool,
+    #[serde(default)]
+    core_dump: bool,
+    #[serde(default)]
+    hide_physmap: bool,
+    #[serde(default)]
+    strace: bool,
+    #[serde(default)]
+    pub sanitize_heap: bool,
+    #[serde(default = "default_console")]
+    console: String,
+    #[serde(default = "default_log_vt")]
+    log_vt: u64,
+    #[serde(default = "default_user_vt")]
+    user_vt: u64,
+    #[serde(default)]
+    pub deterministic: bool,
+    #[serde(default)]
+    fail_frame: u64,
+    #[serde(default)]
+    fail_heap: u64,
+    #[serde(default)]
+    fail_syscall: u64,
+    #[serde(default)]
+    remote_log: Option<SocketAddrV4>,
+    #[serde(default = "default_remote_log_source")]
+    remote_log_source: Ipv4Addr,
+    #[serde(default)]
+    pub remote_log_key: String,
+    #[serde(default)]
+    pub test_stress: bool,
 }
 
 impl fmt::Display for KernelConfig {
@@ -118,11 +314,55 @@ impl fmt::Display for KernelConfig {
             "pub const LOG_LEVEL: log::LevelFilter = log::LevelFilter::{};",
             camel_case(&self.log_level)
         )?;
+        write!(f, "{}", self.log_format)?;
         writeln!(
             f,
-            "pub type Allocator = crate::allocator::{}Allocator;",
+            "pub const ALLOCATOR: crate::allocator::Kind = crate::allocator::Kind::{};",
             camel_case(&self.allocator)
         )?;
+        writeln!(f, "pub const TRACE: bool = {};", self.trace)?;
+        writeln!(f, "pub const PROFILE: bool = {};", self.profile)?;
+        writeln!(f, "pub const LATENCY: bool = {};", self.latency)?;
+        writeln!(f, "pub const CRASH_DUMP: bool = {};", self.crash_dump)?;
+        writeln!(f, "pub const CORE_DUMP: bool = {};", self.core_dump)?;
+        writeln!(f, "pub const HIDE_PHYSMAP: bool = {};", self.hide_physmap)?;
+        writeln!(f, "pub const STRACE: bool = {};", self.strace)?;
+        writeln!(f, "pub const SANITIZE_HEAP: bool = {};", self.sanitize_heap)?;
+        writeln!(
+            f,
+            "pub const CONSOLE: common::serial::Console = common::serial::Console::{};",
+            camel_case(&self.console)
+        )?;
+        writeln!(f, "pub const LOG_VT: u64 = {};", self.log_vt)?;
+        writeln!(f, "pub const USER_VT: u64 = {};", self.user_vt)?;
+        writeln!(f, "pub const DETERMINISTIC: bool = {};", self.deterministic)?;
+        writeln!(f, "pub const FAIL_FRAME: u64 = {};", self.fail_frame)?;
+        writeln!(f, "pub const FAIL_HEAP: u64 = {};", self.fail_heap)?;
+        writeln!(f, "pub const FAIL_SYSCALL: u64 = {};", self.fail_syscall)?;
+        let remote_log = match self.remote_log {
+            Some(addr) => format!(
+                "Some(crate::net::Endpoint {{ addr: {:?}, port: {} }})",
+                addr.ip().octets(),
+                addr.port()
+            ),
+            None => "None".into(),
+        };
+        writeln!(
+            f,
+            "pub const REMOTE_LOG: Option<crate::net::Endpoint> = {};",
+            remote_log
+        )?;
+        writeln!(
+            f,
+            "pub const REMOTE_LOG_SOURCE: [u8; 4] = {:?};",
+            self.remote_log_source.octets()
+        )?;
+        writeln!(
+            f,
+            "pub const REMOTE_LOG_KEY: &str = {:?};",
+            self.remote_log_key
+        )?;
+        writeln!(f, "pub const TEST_STRESS: bool = {};", self.test_stress)?;
         Ok(())
     }
 }
@@ -132,6 +372,19 @@ impl fmt::Display for KernelConfig {
 pub struct RunConfig {
     pub ovmf_dir: PathBuf,
     pub qemu_args: Vec<String>,
+    /// Host directory shared with the kernel over virtio-9p
+    #[serde(default)]
+    pub share_dir: Option<PathBuf>,
+    /// Let the kernel write to the shared directory
+    #[serde(default)]
+    pub share_writable: bool,
+    /// Ports of QEMU's user network forwarded from the host, as
+    /// `protocol:host->guest`
+    #[serde(default)]
+    pub forward: Vec<String>,
+    /// Directory containing the Limine BIOS and UEFI boot files
+    #[serde(default)]
+    pub limine_dir: Option<PathBuf>,
 }
 
 /// Convenience method to deserialize struct directly from a file since the
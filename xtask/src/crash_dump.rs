@@ -0,0 +1,116 @@
+//! Decoding of crash dumps printed by the kernel on panic
+//!
+//! The dump format is described in the kernel's `crash_dump` module.
+
+use crate::{
+    bytes::{u32_at, u64_at},
+    config::RunInfo,
+    symbols::{self, Symbols},
+};
+use anyhow::{bail, Context, Result};
+use std::{fmt::Write, fs, path::Path};
+
+const MAGIC: &[u8; 4] = b"ACRD";
+const VERSION: u32 = 1;
+const HEADER_SIZE: usize = 8;
+
+/// Names of the registers in the registers section
+const REGISTERS: [&str; 7] = ["rsp", "rbp", "rflags", "cr0", "cr2", "cr3", "cr4"];
+
+/// Names of UEFI memory types
+const MEMORY_TYPES: [&str; 15] = [
+    "reserved",
+    "loader code",
+    "loader data",
+    "boot services code",
+    "boot services data",
+    "runtime services code",
+    "runtime services data",
+    "conventional",
+    "unusable",
+    "ACPI reclaim",
+    "ACPI non-volatile",
+    "MMIO",
+    "MMIO port space",
+    "PAL code",
+    "persistent",
+];
+
+/// Decode `dump`, symbolizing it with the `kernel` executable
+fn decode(dump: &[u8], kernel: &Path) -> Result<String> {
+    if dump.len() < HEADER_SIZE || &dump[..4] != MAGIC {
+        bail!("Not a crash dump");
+    }
+    let version = u32_at(dump, 4);
+    if version != VERSION {
+        bail!("Unsupported crash dump version {}", version);
+    }
+    let mut out = String::new();
+    // Dumps of kernels that could only be loaded at the default offset lack it
+    let mut offset = symbols::KERNEL_OFFSET;
+    let mut rest = &dump[HEADER_SIZE..];
+    while !rest.is_empty() {
+        let (tag, len) = match rest.get(..8) {
+            Some(header) => (u32_at(header, 0), u32_at(header, 4) as usize),
+            None => bail!("Crash dump is truncated"),
+        };
+        let data = rest.get(8..8 + len).context("Crash dump is truncated")?;
+        rest = &rest[8 + len..];
+        match tag {
+            1 => writeln!(out, "{}\n", String::from_utf8_lossy(data))?,
+            2 => {
+                writeln!(out, "Registers:")?;
+                for (name, value) in REGISTERS.iter().zip(data.chunks_exact(8)) {
+                    writeln!(out, "  {:<6} {:#018x}", name, u64_at(value, 0))?;
+                }
+                writeln!(out)?;
+            }
+            3 => {
+                writeln!(out, "Backtrace:")?;
+                let symbols = Symbols::load(kernel, offset)?;
+                for (i, addr) in data.chunks_exact(8).enumerate() {
+                    let addr = u64_at(addr, 0);
+                    // Look up the call instruction rather than the return address
+                    let function = symbols.lookup(addr - 1).unwrap_or("??");
+                    writeln!(out, "  #{:<2} {:#018x} in {}", i, addr, function)?;
+                }
+                writeln!(out)?;
+            }
+            4 => writeln!(
+                out,
+                "Recent log messages:\n{}",
+                String::from_utf8_lossy(data)
+            )?,
+            5 => {
+                writeln!(out, "Memory map:")?;
+                for descriptor in data.chunks_exact(24) {
+                    let start = u64_at(descriptor, 0);
+                    let end = start + u64_at(descriptor, 8) * 0x1000;
+                    let ty = u32_at(descriptor, 16);
+                    match MEMORY_TYPES.get(ty as usize) {
+                        Some(ty) => writeln!(out, "  {:#014x}..{:#014x} {}", start, end, ty)?,
+                        None => writeln!(out, "  {:#014x}..{:#014x} {:#x}", start, end, ty)?,
+                    }
+                }
+                writeln!(out)?;
+            }
+            6 => match u64_at(data, 0) {
+                0 => writeln!(out, "No process running")?,
+                pid => writeln!(out, "Process {} running", pid)?,
+            },
+            7 => {
+                offset = u64_at(data, 0);
+                writeln!(out, "Kernel loaded at offset {:#x}\n", offset)?;
+            }
+            _ => writeln!(out, "Unknown section {} of {} bytes\n", tag, len)?,
+        }
+    }
+    Ok(out)
+}
+
+/// Print crash dump `dump`, symbolized with the current kernel executable
+pub fn print(info: &RunInfo, dump: &Path) -> Result<()> {
+    let data = fs::read(dump).with_context(|| format!("Could not read {}", dump.display()))?;
+    print!("{}", decode(&data, &info.kernel)?);
+    Ok(())
+}
@@ -0,0 +1,69 @@
+use crate::{
+    command::CommandResultExt,
+    config::{self, RunConfig, RunInfo},
+};
+use anyhow::{anyhow, Result};
+use std::{fs, path::PathBuf, process::Command};
+
+/// Limine files needed on the image, relative to the configured directory
+const LIMINE_FILES: &[&str] = &[
+    "limine-bios.sys",
+    "limine-bios-cd.bin",
+    "limine-uefi-cd.bin",
+];
+
+/// Boot menu of the image; the kernel is booted through the Limine protocol,
+/// and on UEFI firmware the stub can be chainloaded as well
+const LIMINE_CONF: &str = "\
+timeout: 3
+
+/ÅngstrÖS
+    protocol: limine
+    path: boot():/kernel.elf
+
+/ÅngstrÖS (UEFI stub)
+    protocol: efi
+    path: boot():/stub.efi
+";
+
+/// Build an ISO image that boots on both legacy BIOS and UEFI firmware
+pub fn build(info: &RunInfo) -> Result<PathBuf> {
+    println!("Building hybrid image...");
+    let config: RunConfig = config::parse(info.info, "run.toml")?;
+    let limine_dir = config
+        .limine_dir
+        .ok_or_else(|| anyhow!("Set limine-dir in run.toml to build an image"))?;
+    let root = info.info.image_dir();
+    if root.exists() {
+        fs::remove_dir_all(&root)?;
+    }
+    xshell::mkdir_p(&root)?;
+    for file in LIMINE_FILES {
+        xshell::cp(limine_dir.join(file), root.join(file))?;
+    }
+    xshell::cp(&info.kernel, root.join("kernel.elf"))?;
+    xshell::cp(&info.efi_stub, root.join("stub.efi"))?;
+    fs::write(root.join("limine.conf"), LIMINE_CONF)?;
+
+    let image = info.info.image();
+    Command::new("xorriso")
+        .args(&["-as", "mkisofs", "-R", "-r", "-J"])
+        .args(&["-b", "limine-bios-cd.bin", "-no-emul-boot"])
+        .args(&["-boot-load-size", "4", "-boot-info-table"])
+        .args(&["-hfsplus", "-apm-block-size", "2048"])
+        .args(&["--efi-boot", "limine-uefi-cd.bin", "-efi-boot-part"])
+        .args(&["--efi-boot-image", "--protective-msdos-label"])
+        .arg(&root)
+        .arg("-o")
+        .arg(&image)
+        .status()
+        .check_status("xorriso")?;
+    // Install the first BIOS stage into the boot sector of the image, which
+    // makes it bootable from a disk as well as from a CD
+    Command::new("limine")
+        .arg("bios-install")
+        .arg(&image)
+        .status()
+        .check_status("Limine")?;
+    Ok(image)
+}
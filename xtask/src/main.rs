@@ -2,14 +2,26 @@ use anyhow::Result;
 use clap::Clap;
 use config::{Info, SubCommand};
 
+mod abi;
 mod build;
+mod bytes;
+mod c;
+mod check;
 mod command;
 mod config;
+mod crash_dump;
+mod image;
+mod output;
+mod profile;
+mod qmp;
+mod remote_log;
 mod run;
+mod symbols;
+mod trace;
 
 fn main() -> Result<()> {
     let info = Info::parse();
-    match info.cmd {
+    match &info.cmd {
         SubCommand::Build => {
             build::build(&info)?;
         }
@@ -21,10 +33,37 @@ fn main() -> Result<()> {
             let info = build::build(&info)?;
             run::run(&info)?;
         }
-        SubCommand::Test => {
+        SubCommand::Test { stub: false, .. } => {
             let info = build::build(&info)?;
             run::test(&info)?;
         }
+        SubCommand::Test { stub: true, .. } => {
+            let info = build::build_stub_test(&info)?;
+            run::test(&info)?;
+        }
+        SubCommand::Image => {
+            let info = build::build(&info)?;
+            // Building with --bios already includes the image
+            if !info.info.bios {
+                image::build(&info)?;
+            }
+        }
+        SubCommand::Check => {
+            check::check(&info)?;
+        }
+        SubCommand::Nmi => {
+            qmp::inject_nmi(&info)?;
+        }
+        SubCommand::CrashDump { dump } => {
+            let info = build::build(&info)?;
+            crash_dump::print(&info, dump)?;
+        }
+        SubCommand::RemoteLog { port } => {
+            remote_log::receive(&info, *port)?;
+        }
+        SubCommand::Abi => {
+            abi::generate(&info)?;
+        }
     }
     Ok(())
 }
@@ -0,0 +1,180 @@
+//! Extraction of binary data from the serial output of the kernel
+//!
+//! The kernel prints binary data encoded as base64 between marker lines, see
+//! its `base64` module.
+
+use crate::{profile, trace};
+use std::{
+    fs,
+    io::{self, BufRead, BufReader, Read},
+    path::PathBuf,
+};
+
+/// Kind of binary data printed by the kernel
+#[derive(Copy, Clone)]
+enum Block {
+    /// Binary PPM image, see the kernel's `framebuffer::screenshot` module
+    Screenshot,
+    /// Trace dump, see the kernel's `trace` module
+    Trace,
+    /// Profile dump, see the kernel's `profile` module
+    Profile,
+    /// Crash dump, see the kernel's `crash_dump` module
+    CrashDump,
+}
+
+impl Block {
+    const ALL: [Block; 4] = [
+        Block::Screenshot,
+        Block::Trace,
+        Block::Profile,
+        Block::CrashDump,
+    ];
+
+    fn name(self) -> &'static str {
+        match self {
+            Block::Screenshot => "SCREENSHOT",
+            Block::Trace => "TRACE",
+            Block::Profile => "PROFILE",
+            Block::CrashDump => "CRASH DUMP",
+        }
+    }
+
+    fn begin(self) -> String {
+        format!("-----BEGIN {}-----", self.name())
+    }
+
+    fn end(self) -> String {
+        format!("-----END {}-----", self.name())
+    }
+}
+
+/// Directories to save extracted data to, and executables to symbolize
+/// profiles with
+pub struct Dirs {
+    pub screenshots: PathBuf,
+    pub traces: PathBuf,
+    pub profiles: PathBuf,
+    pub crash_dumps: PathBuf,
+    pub kernel: PathBuf,
+    pub user: PathBuf,
+}
+
+fn decode_char(c: u8) -> Option<u32> {
+    let value = match c {
+        b'A'..=b'Z' => c - b'A',
+        b'a'..=b'z' => c - b'a' + 26,
+        b'0'..=b'9' => c - b'0' + 52,
+        b'+' => 62,
+        b'/' => 63,
+        _ => return None,
+    };
+    Some(value as u32)
+}
+
+/// Decode a line of base64, returning [`None`] if it is not valid base64
+fn decode_line(line: &str) -> Option<Vec<u8>> {
+    let line = line.as_bytes();
+    if line.is_empty() || line.len() % 4 != 0 {
+        return None;
+    }
+    let mut out = Vec::with_capacity(line.len() / 4 * 3);
+    for chunk in line.chunks(4) {
+        let padding = chunk.iter().rev().take_while(|&&c| c == b'=').count();
+        if padding > 2 {
+            return None;
+        }
+        let mut n = 0;
+        for &c in &chunk[..4 - padding] {
+            n = n << 6 | decode_char(c)?;
+        }
+        n <<= 6 * padding;
+        out.extend_from_slice(&n.to_be_bytes()[1..4 - padding]);
+    }
+    Some(out)
+}
+
+/// Save a block of data as the `count`th block of its kind
+fn save(block: Block, data: &[u8], count: usize, dirs: &Dirs) -> io::Result<()> {
+    let path = match block {
+        Block::Screenshot => {
+            fs::create_dir_all(&dirs.screenshots)?;
+            let path = dirs.screenshots.join(format!("screenshot-{}.ppm", count));
+            fs::write(&path, data)?;
+            path
+        }
+        Block::Trace => {
+            let json = match trace::to_chrome_json(data) {
+                Ok(json) => json,
+                Err(e) => {
+                    println!("Could not convert trace: {:#}", e);
+                    return Ok(());
+                }
+            };
+            fs::create_dir_all(&dirs.traces)?;
+            let path = dirs.traces.join(format!("trace-{}.json", count));
+            fs::write(&path, json)?;
+            path
+        }
+        Block::Profile => {
+            let folded = match profile::to_folded(data, &dirs.kernel, &dirs.user) {
+                Ok(folded) => folded,
+                Err(e) => {
+                    println!("Could not symbolize profile: {:#}", e);
+                    return Ok(());
+                }
+            };
+            fs::create_dir_all(&dirs.profiles)?;
+            let path = dirs.profiles.join(format!("profile-{}.folded", count));
+            fs::write(&path, folded)?;
+            path
+        }
+        Block::CrashDump => {
+            fs::create_dir_all(&dirs.crash_dumps)?;
+            let path = dirs.crash_dumps.join(format!("crash-dump-{}.bin", count));
+            fs::write(&path, data)?;
+            path
+        }
+    };
+    println!(
+        "Saved {} to {}",
+        block.name().to_lowercase(),
+        path.display()
+    );
+    Ok(())
+}
+
+/// Forward `output` to stdout while saving the blocks of binary data to `dirs`
+///
+/// Blocks are numbered per kind in order of appearance. Lines inside a block
+/// that are not base64 (e.g. interleaved log messages) are forwarded as well.
+pub fn forward_output<R: Read>(output: R, dirs: &Dirs) -> io::Result<()> {
+    let mut reader = BufReader::new(output);
+    let mut buf = Vec::new();
+    let mut current: Option<(Block, Vec<u8>)> = None;
+    let mut counts = [0; Block::ALL.len()];
+    loop {
+        buf.clear();
+        if reader.read_until(b'\n', &mut buf)? == 0 {
+            return Ok(());
+        }
+        let line = String::from_utf8_lossy(&buf);
+        let trimmed = line.trim_end();
+        match &mut current {
+            None => match Block::ALL.iter().find(|block| trimmed == block.begin()) {
+                Some(&block) => current = Some((block, Vec::new())),
+                None => print!("{}", line),
+            },
+            Some((block, data)) if trimmed == block.end() => {
+                let count = &mut counts[*block as usize];
+                *count += 1;
+                save(*block, data, *count, dirs)?;
+                current = None;
+            }
+            Some((_, data)) => match decode_line(trimmed) {
+                Some(bytes) => data.extend(bytes),
+                None => print!("{}", line),
+            },
+        }
+    }
+}
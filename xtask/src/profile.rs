@@ -0,0 +1,60 @@
+//! Symbolization of kernel profiles into the folded stack format
+//!
+//! Every output line is a stack of frames separated by semicolons followed by
+//! the number of samples, as consumed by `flamegraph.pl` or `inferno`. Only
+//! the sampled function is known, so stacks consist of the process, whether
+//! the kernel was running and the function. The dump format is described in
+//! the kernel's `profile` module.
+
+use crate::{
+    bytes::{u32_at, u64_at},
+    symbols::{self, Symbols},
+};
+use anyhow::{bail, Result};
+use std::{collections::BTreeMap, path::Path};
+
+const MAGIC: &[u8; 4] = b"APRF";
+const VERSION: u32 = 2;
+const HEADER_SIZE: usize = 16;
+const SAMPLE_SIZE: usize = 16;
+
+/// Convert a profile dump to the folded stack format, using the symbols of the
+/// `kernel` and `user` executables
+pub fn to_folded(dump: &[u8], kernel: &Path, user: &Path) -> Result<String> {
+    if dump.len() < HEADER_SIZE || &dump[..4] != MAGIC {
+        bail!("Not a profile dump");
+    }
+    let version = u32_at(dump, 4);
+    if version != VERSION {
+        bail!("Unsupported profile dump version {}", version);
+    }
+    let samples = &dump[HEADER_SIZE..];
+    if samples.len() % SAMPLE_SIZE != 0 {
+        bail!("Profile dump is truncated");
+    }
+    let kernel = Symbols::load(kernel, u64_at(dump, 8))?;
+    let user = Symbols::load(user, symbols::USER_OFFSET)?;
+
+    let mut stacks = BTreeMap::<String, u64>::new();
+    for sample in samples.chunks_exact(SAMPLE_SIZE) {
+        let rip = u64_at(sample, 0);
+        let pid = u32_at(sample, 8);
+        let in_user = u32_at(sample, 12) != 0;
+        let symbols = if in_user { &user } else { &kernel };
+        let function = symbols
+            .lookup(rip)
+            .map_or_else(|| format!("{:#x}", rip), str::to_string)
+            // Semicolons separate frames
+            .replace(';', ":");
+        let stack = match (pid, in_user) {
+            (0, _) => format!("kernel;{}", function),
+            (pid, true) => format!("process {};{}", pid, function),
+            (pid, false) => format!("process {};kernel;{}", pid, function),
+        };
+        *stacks.entry(stack).or_default() += 1;
+    }
+    Ok(stacks
+        .iter()
+        .map(|(stack, count)| format!("{} {}\n", stack, count))
+        .collect())
+}
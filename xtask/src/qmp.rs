@@ -0,0 +1,60 @@
+//! Control of a running QEMU instance over the QEMU machine protocol
+
+use crate::config::Info;
+use anyhow::{anyhow, Context, Result};
+use serde_json::{json, Value};
+use std::{
+    io::{BufRead, BufReader, Write},
+    os::unix::net::UnixStream,
+};
+
+/// Connection to the QMP socket of a running QEMU instance
+struct Qmp {
+    reader: BufReader<UnixStream>,
+    writer: UnixStream,
+}
+
+impl Qmp {
+    fn connect(info: &Info) -> Result<Self> {
+        let path = info.qmp_socket();
+        let stream = UnixStream::connect(&path)
+            .with_context(|| format!("Could not connect to {}", path.display()))
+            .context("Is the kernel running?")?;
+        let mut qmp = Self {
+            reader: BufReader::new(stream.try_clone()?),
+            writer: stream,
+        };
+        // Skip greeting and leave capabilities negotiation mode
+        qmp.read()?;
+        qmp.execute("qmp_capabilities")?;
+        Ok(qmp)
+    }
+
+    fn read(&mut self) -> Result<Value> {
+        let mut line = String::new();
+        if self.reader.read_line(&mut line)? == 0 {
+            return Err(anyhow!("QMP connection closed"));
+        }
+        Ok(serde_json::from_str(&line)?)
+    }
+
+    /// Execute `command` and return its result, skipping asynchronous events
+    fn execute(&mut self, command: &str) -> Result<Value> {
+        writeln!(self.writer, "{}", json!({ "execute": command }))?;
+        loop {
+            let mut reply = self.read()?;
+            if let Some(value) = reply.get_mut("return") {
+                return Ok(value.take());
+            }
+            if let Some(error) = reply.get("error") {
+                return Err(anyhow!("QMP command {} failed: {}", command, error));
+            }
+        }
+    }
+}
+
+pub fn inject_nmi(info: &Info) -> Result<()> {
+    Qmp::connect(info)?.execute("inject-nmi")?;
+    println!("Injected non-maskable interrupt");
+    Ok(())
+}
@@ -0,0 +1,89 @@
+//! Receiving the log the kernel sends over UDP
+//!
+//! The record format is described in the kernel's `remote_log` module. Kernels
+//! are told apart by the address their records come from, so several can send
+//! to the same port.
+
+use crate::config::{self, BuildConfig, Info};
+use anyhow::{Context, Result};
+use hmac::{Hmac, Mac, NewMac};
+use sha2::Sha256;
+use std::{
+    collections::HashMap,
+    convert::TryInto,
+    net::{SocketAddr, UdpSocket},
+};
+
+const MAGIC: &[u8; 4] = b"ARLG";
+/// Size of the magic, sequence number and kind of a record
+const HEADER_SIZE: usize = 13;
+const TAG_SIZE: usize = 32;
+/// Kind of records holding a panic report
+const KIND_PANIC: u8 = 2;
+
+struct Record<'a> {
+    sequence: u64,
+    kind: u8,
+    message: &'a [u8],
+}
+
+/// Check that `datagram` is a record authenticated with `key`
+fn verify<'a>(datagram: &'a [u8], key: &[u8]) -> Result<Record<'a>, &'static str> {
+    if datagram.len() < HEADER_SIZE + TAG_SIZE || &datagram[..4] != MAGIC {
+        return Err("Not a log record");
+    }
+    let (data, tag) = datagram.split_at(datagram.len() - TAG_SIZE);
+    let mut mac = Hmac::<Sha256>::new_from_slice(key).expect("HMAC takes keys of any length");
+    mac.update(data);
+    mac.verify(tag).map_err(|_| "Invalid tag")?;
+    Ok(Record {
+        sequence: u64::from_be_bytes(data[4..12].try_into().unwrap()),
+        kind: data[12],
+        message: &data[HEADER_SIZE..],
+    })
+}
+
+/// Print the records received on UDP `port` until interrupted
+pub fn receive(info: &Info, port: u16) -> Result<()> {
+    let config: BuildConfig = config::parse(info, "build.toml")?;
+    let key = config.kernel.remote_log_key.as_bytes();
+    let socket = UdpSocket::bind(("0.0.0.0", port))
+        .with_context(|| format!("Could not listen on UDP port {}", port))?;
+    println!("Receiving the kernel log on UDP port {}...", port);
+    // Sequence number of the next record expected from each sender
+    let mut next = HashMap::<SocketAddr, u64>::new();
+    let mut buf = [0; 2048];
+    loop {
+        let (len, from) = socket.recv_from(&mut buf)?;
+        let record = match verify(&buf[..len], key) {
+            Ok(record) => record,
+            Err(e) => {
+                eprintln!("[{}] Ignoring datagram: {}", from, e);
+                continue;
+            }
+        };
+        let expected = next.entry(from).or_insert(0);
+        if record.sequence == 0 && *expected != 0 {
+            println!("[{}] --- Kernel restarted ---", from);
+        } else if record.sequence < *expected {
+            eprintln!(
+                "[{}] Ignoring record {}, which was sent before the last one",
+                from, record.sequence
+            );
+            continue;
+        } else if record.sequence > *expected {
+            println!(
+                "[{}] --- {} records lost ---",
+                from,
+                record.sequence - *expected
+            );
+        }
+        *expected = record.sequence + 1;
+        let message = String::from_utf8_lossy(record.message);
+        if record.kind == KIND_PANIC {
+            println!("[{}] KERNEL PANIC: {}", from, message);
+        } else {
+            println!("[{}] {}", from, message);
+        }
+    }
+}
@@ -1,31 +1,47 @@
 use crate::{
     command::CommandResultExt,
-    config::{self, Info, RunConfig, RunInfo},
+    config::{self, RunConfig, RunInfo},
+    output::{self, Dirs},
 };
-use anyhow::{anyhow, Result};
+use anyhow::{anyhow, Context, Result};
 use std::{
-    io::ErrorKind,
+    io::{self, ErrorKind},
     net::{Shutdown, TcpStream},
     path::Path,
-    process::{Child, Command, Stdio},
-    thread,
+    process::{Child, Command, ExitStatus, Stdio},
+    thread::{self, JoinHandle},
     time::Duration,
 };
 
+/// Running QEMU instance whose serial output is being forwarded
+struct Qemu {
+    child: Child,
+    output: JoinHandle<io::Result<()>>,
+}
+
+impl Qemu {
+    /// Wait for QEMU to exit and all of its output to be forwarded
+    fn wait(mut self) -> io::Result<ExitStatus> {
+        let status = self.child.wait()?;
+        self.output.join().expect("Output thread panicked")?;
+        Ok(status)
+    }
+}
+
 pub fn debug(info: &RunInfo) -> Result<()> {
-    let mut qemu = run_qemu(info.info, &["-s", "-S"])?;
+    let mut qemu = run_qemu(info, &["-s", "-S"])?;
     let gdb = run_gdb(&info.kernel);
-    qemu.kill()?;
+    qemu.child.kill()?;
     gdb
 }
 
 pub fn run(info: &RunInfo) -> Result<()> {
-    run_qemu(info.info, &[])?.wait().check_status("QEMU")
+    run_qemu(info, &[])?.wait().check_status("QEMU")
 }
 
 pub fn test(info: &RunInfo) -> Result<()> {
     let args = &["-device", "isa-debug-exit,iobase=0xf4,iosize=0x04"];
-    run_qemu(info.info, args)?
+    run_qemu(info, args)?
         .wait()
         .map(|status| match status.code() {
             // This is the mangled kernel::test::ExitCode::Success
@@ -60,30 +76,92 @@ fn run_gdb(kernel: &Path) -> Result<()> {
         .check_status("GDB")
 }
 
-fn run_qemu(info: &Info, extra_args: &[&str]) -> Result<Child> {
+/// Translate forwarding rule `rule`, e.g. `tcp:8080->80`, to a `hostfwd`
+/// option of QEMU's user network
+fn hostfwd(rule: &str) -> Result<String> {
+    let parse = || {
+        let (protocol, ports) = rule.split_once(':')?;
+        let (host, guest) = ports.split_once("->")?;
+        let (host, guest) = (host.parse::<u16>().ok()?, guest.parse::<u16>().ok()?);
+        match protocol {
+            "tcp" | "udp" => Some(format!("hostfwd={}::{}-:{}", protocol, host, guest)),
+            _ => None,
+        }
+    };
+    parse().with_context(|| format!("Invalid forwarding rule {:?}", rule))
+}
+
+fn run_qemu(run_info: &RunInfo, extra_args: &[&str]) -> Result<Qemu> {
     println!("Running kernel with QEMU...");
+    let info = run_info.info;
     let config: RunConfig = config::parse(info, "run.toml")?;
-    Command::new("qemu-system-x86_64")
+    let mut command = Command::new("qemu-system-x86_64");
+    if info.deterministic {
+        // Time advances with executed instructions only, from a fixed date
+        command.args(&["-icount", "shift=0,sleep=off"]);
+        command.args(&["-rtc", "base=2000-01-01T00:00:00,clock=vm"]);
+    }
+    if let Some(dir) = &config.share_dir {
+        let readonly = if config.share_writable { "off" } else { "on" };
+        command.arg("-virtfs").arg(format!(
+            "local,path={},mount_tag=host,security_model=none,readonly={}",
+            dir.display(),
+            readonly
+        ));
+    }
+    if !config.forward.is_empty() {
+        let mut netdev = String::from("user,id=net");
+        for rule in &config.forward {
+            netdev += ",";
+            netdev += &hostfwd(rule)?;
+        }
+        command.arg("-netdev").arg(netdev);
+        command.args(&["-device", "virtio-net-pci,netdev=net"]);
+    }
+    if info.bios {
+        // Without firmware flash QEMU runs SeaBIOS, which boots the image
+        command.arg("-cdrom").arg(info.image());
+    } else {
+        command
+            .arg("-drive")
+            .arg(format!(
+                "if=pflash,format=raw,file={},readonly",
+                config.ovmf_dir.join("OVMF_CODE.fd").display()
+            ))
+            .arg("-drive")
+            .arg(format!(
+                "if=pflash,format=raw,file={},readonly",
+                config.ovmf_dir.join("OVMF_VARS.fd").display()
+            ))
+            .arg("-drive")
+            .arg(format!(
+                "format=raw,file=fat:rw:{}",
+                info.esp_dir().display()
+            ));
+    }
+    let mut child = command
         .arg("-nodefaults")
         .args(config.qemu_args)
         .args(&["-serial", "stdio", "-vga", "std"])
-        .arg("-drive")
+        .arg("-qmp")
         .arg(format!(
-            "if=pflash,format=raw,file={},readonly",
-            config.ovmf_dir.join("OVMF_CODE.fd").display()
-        ))
-        .arg("-drive")
-        .arg(format!(
-            "if=pflash,format=raw,file={},readonly",
-            config.ovmf_dir.join("OVMF_VARS.fd").display()
-        ))
-        .arg("-drive")
-        .arg(format!(
-            "format=raw,file=fat:rw:{}",
-            info.esp_dir().display()
+            "unix:{},server,nowait",
+            info.qmp_socket().display()
         ))
         .args(extra_args)
         .stdin(Stdio::null())
+        .stdout(Stdio::piped())
         .spawn()
-        .check_status("QEMU")
+        .check_status("QEMU")?;
+    let stdout = child.stdout.take().unwrap();
+    let dirs = Dirs {
+        screenshots: info.screenshot_dir(),
+        traces: info.trace_dir(),
+        profiles: info.profile_dir(),
+        crash_dumps: info.crash_dump_dir(),
+        kernel: run_info.kernel.clone(),
+        user: run_info.user.clone(),
+    };
+    let output = thread::spawn(move || output::forward_output(stdout, &dirs));
+    Ok(Qemu { child, output })
 }
@@ -0,0 +1,161 @@
+//! Function symbols of ELF executables
+
+use crate::bytes::{u16_at, u32_at, u64_at};
+use anyhow::{bail, Context, Result};
+use std::{fs, path::Path};
+
+/// Load offsets of position-independent executables, see `ElfInfo::offset` in
+/// the common crate
+pub const KERNEL_OFFSET: u64 = 0x200000;
+pub const USER_OFFSET: u64 = 0x100000;
+
+/// ELF type of position-independent executables
+const ET_DYN: u16 = 3;
+/// ELF section type of symbol tables
+const SHT_SYMTAB: u32 = 2;
+/// ELF symbol type of functions
+const STT_FUNC: u8 = 2;
+
+/// Replacements of escape sequences in Rust's legacy symbol mangling
+const ESCAPES: &[(&str, &str)] = &[
+    ("..", "::"),
+    ("$LT$", "<"),
+    ("$GT$", ">"),
+    ("$RF$", "&"),
+    ("$BP$", "*"),
+    ("$C$", ","),
+    ("$SP$", "@"),
+    ("$u20$", " "),
+    ("$u27$", "'"),
+    ("$u5b$", "["),
+    ("$u5d$", "]"),
+    ("$u7b$", "{"),
+    ("$u7d$", "}"),
+    ("$u7e$", "~"),
+];
+
+/// Demangle a name mangled with Rust's legacy scheme, dropping the hash
+///
+/// Other names are returned unchanged.
+fn demangle(name: &str) -> String {
+    let mut rest = match name.strip_prefix("_ZN") {
+        Some(rest) => rest,
+        None => return name.to_string(),
+    };
+    let mut parts = Vec::new();
+    while !rest.starts_with('E') {
+        let digits = rest.bytes().take_while(u8::is_ascii_digit).count();
+        let len: usize = match rest[..digits].parse() {
+            Ok(len) => len,
+            Err(_) => return name.to_string(),
+        };
+        rest = &rest[digits..];
+        match (rest.get(..len), rest.get(len..)) {
+            (Some(part), Some(next)) => {
+                parts.push(part);
+                rest = next;
+            }
+            _ => return name.to_string(),
+        }
+    }
+    if parts
+        .last()
+        .map_or(false, |part| part.len() == 17 && part.starts_with('h'))
+    {
+        parts.pop();
+    }
+    parts
+        .iter()
+        .map(|part| {
+            // Leading dollar signs are escaped with an underscore
+            let part = if part.starts_with("_$") {
+                &part[1..]
+            } else {
+                part
+            };
+            ESCAPES
+                .iter()
+                .fold(part.to_string(), |part, (from, to)| part.replace(from, to))
+        })
+        .collect::<Vec<_>>()
+        .join("::")
+}
+
+struct Symbol {
+    start: u64,
+    end: u64,
+    name: String,
+}
+
+/// Function symbols of an ELF executable, sorted by address
+pub struct Symbols(Vec<Symbol>);
+
+impl Symbols {
+    pub fn load(path: &Path, pie_offset: u64) -> Result<Self> {
+        let elf = fs::read(path).with_context(|| format!("Could not read {}", path.display()))?;
+        Self::parse(&elf, pie_offset)
+            .with_context(|| format!("Could not parse symbols of {}", path.display()))
+    }
+
+    fn parse(elf: &[u8], pie_offset: u64) -> Result<Self> {
+        if elf.len() < 64 || &elf[..4] != b"\x7fELF" || elf[4] != 2 || elf[5] != 1 {
+            bail!("Not a 64-bit little-endian ELF file");
+        }
+        let offset = if u16_at(elf, 0x10) == ET_DYN {
+            pie_offset
+        } else {
+            0
+        };
+        let (sh_offset, sh_size, sh_count) = (
+            u64_at(elf, 0x28) as usize,
+            u16_at(elf, 0x3a) as usize,
+            u16_at(elf, 0x3c) as usize,
+        );
+        let section = |i: usize| {
+            elf.get(sh_offset + i * sh_size..sh_offset + (i + 1) * sh_size)
+                .context("Section header out of bounds")
+        };
+        let contents = |header: &[u8]| {
+            let start = u64_at(header, 0x18) as usize;
+            elf.get(start..start + u64_at(header, 0x20) as usize)
+                .context("Section out of bounds")
+        };
+
+        let mut symbols = Vec::new();
+        for i in 0..sh_count {
+            let header = section(i)?;
+            if u32_at(header, 0x04) != SHT_SYMTAB {
+                continue;
+            }
+            let strings = contents(section(u32_at(header, 0x28) as usize)?)?;
+            for symbol in contents(header)?.chunks_exact(24) {
+                if symbol[4] & 0xf != STT_FUNC {
+                    continue;
+                }
+                let name = strings
+                    .get(u32_at(symbol, 0) as usize..)
+                    .and_then(|name| name.split(|&c| c == 0).next())
+                    .context("Symbol name out of bounds")?;
+                let start = u64_at(symbol, 0x08) + offset;
+                symbols.push(Symbol {
+                    start,
+                    end: start + u64_at(symbol, 0x10).max(1),
+                    name: demangle(&String::from_utf8_lossy(name)),
+                });
+            }
+        }
+        symbols.sort_by_key(|symbol| symbol.start);
+        Ok(Self(symbols))
+    }
+
+    /// Name of the function containing `addr`
+    pub fn lookup(&self, addr: u64) -> Option<&str> {
+        let i = self.0.partition_point(|symbol| symbol.start <= addr);
+        let symbol = self.0[..i].last()?;
+        if addr < symbol.end {
+            Some(&symbol.name)
+        } else {
+            None
+        }
+    }
+}
@@ -0,0 +1,103 @@
+//! Conversion of kernel trace dumps to the Chrome trace event format
+//!
+//! The result can be loaded in `chrome://tracing` or Perfetto. The dump format
+//! is described in the kernel's `trace` module.
+
+use crate::bytes::{u32_at, u64_at};
+use anyhow::{bail, Result};
+use serde::Serialize;
+use serde_json::{json, Value};
+
+const MAGIC: &[u8; 4] = b"ATRC";
+const VERSION: u32 = 1;
+const HEADER_SIZE: usize = 16;
+const RECORD_SIZE: usize = 24;
+
+/// Time stamp counter increments per millisecond assumed if the kernel could
+/// not calibrate it
+const DEFAULT_TSC_PER_MS: u64 = 1_000_000;
+
+#[derive(Serialize)]
+#[serde(rename_all = "camelCase")]
+struct Trace {
+    trace_events: Vec<Event>,
+    display_time_unit: &'static str,
+}
+
+#[derive(Serialize)]
+struct Event {
+    name: String,
+    /// Phase: beginning or end of a duration, or instant
+    ph: &'static str,
+    /// Timestamp in microseconds
+    ts: f64,
+    pid: u32,
+    tid: u32,
+    args: Value,
+}
+
+/// Name, phase and arguments of an event, see `sys::TraceKind`
+fn describe(kind: u32, arg: u64) -> (String, &'static str, Value) {
+    match kind {
+        0 if arg == 0 => ("switch to kernel".into(), "i", json!({})),
+        0 => ("switch to process".into(), "i", json!({ "pid": arg })),
+        1 => (format!("syscall {}", arg), "B", json!({ "code": arg })),
+        2 => ("syscall".into(), "E", json!({ "return": arg })),
+        3 => (format!("irq {:#x}", arg), "B", json!({ "vector": arg })),
+        4 => (format!("irq {:#x}", arg), "E", json!({})),
+        5 => ("alloc".into(), "i", json!({ "size": arg })),
+        6 => ("free".into(), "i", json!({ "size": arg })),
+        _ => (format!("unknown {}", kind), "i", json!({ "arg": arg })),
+    }
+}
+
+/// Convert a trace dump to JSON in the Chrome trace event format
+///
+/// Every CPU is shown as a separate thread, with timestamps relative to the
+/// first event.
+pub fn to_chrome_json(dump: &[u8]) -> Result<String> {
+    if dump.len() < HEADER_SIZE || &dump[..4] != MAGIC {
+        bail!("Not a trace dump");
+    }
+    let version = u32_at(dump, 4);
+    if version != VERSION {
+        bail!("Unsupported trace dump version {}", version);
+    }
+    let tsc_per_ms = match u64_at(dump, 8) {
+        0 => {
+            println!("Trace has no timing information, assuming 1 GHz clock");
+            DEFAULT_TSC_PER_MS
+        }
+        n => n,
+    };
+    let records = &dump[HEADER_SIZE..];
+    if records.len() % RECORD_SIZE != 0 {
+        bail!("Trace dump is truncated");
+    }
+    let records = records.chunks_exact(RECORD_SIZE);
+    let start = records
+        .clone()
+        .map(|record| u64_at(record, 0))
+        .min()
+        .unwrap_or(0);
+
+    let trace_events = records
+        .map(|record| {
+            let timestamp = u64_at(record, 0) - start;
+            let (name, ph, args) = describe(u32_at(record, 8), u64_at(record, 16));
+            Event {
+                name,
+                ph,
+                ts: timestamp as f64 * 1000.0 / tsc_per_ms as f64,
+                pid: 0,
+                tid: u32_at(record, 12),
+                args,
+            }
+        })
+        .collect();
+    let trace = Trace {
+        trace_events,
+        display_time_unit: "ns",
+    };
+    Ok(serde_json::to_string(&trace)?)
+}